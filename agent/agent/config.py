@@ -1,7 +1,7 @@
 """Configuration management for secure agent."""
 
 import os
-from typing import Optional
+from typing import Dict, List, Optional
 from pydantic_settings import BaseSettings
 
 
@@ -37,6 +37,43 @@ class AgentConfig(BaseSettings):
     # Queue settings
     queue_poll_interval: float = 1.0  # seconds
     task_timeout: int = 300  # seconds
+    queue_backend: str = "redis"  # or "nats", matching the gateway
+    nats_url: str = "nats://nats:4222"
+    nats_stream: str = "CLAW_TASKS"
+    nats_subject_prefix: str = "claw.tasks"
+    nats_ack_wait: Optional[int] = None  # seconds; defaults to task_timeout
+    nats_max_deliver: int = 5
+
+    # Gateway agent registry (disabled unless both URL and key are set)
+    gateway_url: Optional[str] = None
+    gateway_agent_key: Optional[str] = None
+    agent_id: Optional[str] = None  # defaults to the hostname
+    heartbeat_interval: float = 10.0  # seconds
+    # Comma-separated capabilities this agent serves, e.g. "code,search"
+    agent_capabilities: str = ""
+
+    # Record encryption, matching the gateway's [encryption] settings
+    encryption_secret: Optional[str] = None  # base64 AES-256 key
+    encryption_key_id: str = "1"
+    # Comma-separated "key_id:secret" pairs still read after a rotation
+    encryption_previous_secrets: str = ""
+
+    @property
+    def capabilities(self) -> List[str]:
+        """Advertised capabilities, in the order their queues are drained."""
+        return [c.strip() for c in self.agent_capabilities.split(",") if c.strip()]
+
+    @property
+    def encryption_keys(self) -> Dict[str, str]:
+        """Every configured encryption secret by key id, the current one included."""
+        keys = {}
+        for pair in self.encryption_previous_secrets.split(","):
+            key_id, _, secret = pair.strip().partition(":")
+            if key_id and secret:
+                keys[key_id] = secret
+        if self.encryption_secret:
+            keys[self.encryption_key_id] = self.encryption_secret
+        return keys
 
     class Config:
         env_file = ".env"
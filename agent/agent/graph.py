@@ -2,17 +2,42 @@
 
 import asyncio
 import operator
+import time
+from datetime import datetime
 from typing import Dict, Any, Optional, Annotated
 from langgraph.graph import StateGraph, END
 from loguru import logger
 
 from .llm import SecureLLM
 from .storage import SecureStorage
+from .task_queue import FINAL_STATUSES, create_task_queue
+from .registry import AgentRegistry
 from .memory import AgentMemory
 from .orchestration_agent import OrchestrationAgent, OrchestrationState
 from .skill_execution_agent import SkillExecutionAgent
 
 
+def _deadline(task_data: Dict[str, Any], queued: Optional[float] = None) -> Optional[float]:
+    """
+    Unix time after which nobody waits for a task.
+
+    The record's deadline wins over the one the task was queued with, since
+    the gateway moves it when it requeues the task.
+    """
+    deadline = task_data.get("deadline")
+    if deadline:
+        try:
+            return datetime.fromisoformat(deadline).timestamp()
+        except ValueError:
+            logger.warning(f"Unreadable task deadline {deadline!r}")
+    return queued
+
+
+async def _canary() -> Dict[str, Any]:
+    """Answer a gateway canary task, which checks the way through the fleet, not the model."""
+    return {"result": "ok", "error": None}
+
+
 class AgentState(dict):
     """State for LangGraph agent."""
 
@@ -45,6 +70,11 @@ class SecureAgent:
         self.skill_executor = SkillExecutionAgent()
         self.storage = SecureStorage()
         self.memory = AgentMemory()
+        self.registry = AgentRegistry()
+        # Only registered agents are sent conversation tasks of their own
+        self.queue = create_task_queue(
+            self.storage, self.registry.agent_id if self.registry.enabled else None
+        )
         self.graph = self._build_graph()
 
     async def initialize(self):
@@ -52,6 +82,14 @@ class SecureAgent:
         await self.orchestrator.initialize()
         await self.skill_executor.initialize()
         await self.storage.connect()
+        # Keep what is logged while working on a task in its execution log
+        logger.add(
+            self.storage.log_sink,
+            level="INFO",
+            filter=lambda record: "task_id" in record["extra"]
+            and record["extra"].get("task_log", True),
+        )
+        await self.queue.connect()
         await self.memory.initialize()
         logger.info("Secure agent initialized")
 
@@ -677,32 +715,73 @@ Focus on what future decisions need to know, not everything that happened.
                 "skill_results": [],
             }
 
-    async def process_task(self, task_id: str, task_data: Dict[str, Any]) -> Dict[str, Any]:
+    async def process_task(
+        self,
+        task_id: str,
+        task_data: Dict[str, Any],
+        queued_deadline: Optional[float] = None,
+    ) -> Dict[str, Any]:
         """
         Process a task from Redis queue.
 
+        Work still running at the task's deadline is abandoned, leaving the
+        gateway's watchdog to requeue or time out the task.
+
         Args:
             task_id: Task ID
             task_data: Task data including input and config
+            queued_deadline: Deadline the queue delivered the task with, if any
 
         Returns:
-            Processing result
+            Processing result, empty if the task could no longer be started
+            or was abandoned
         """
-        # Update status to processing
-        await self.storage.update_task_status(task_id, "processing")
-
-        # Run agent
-        result = await self.run(
-            input_data=task_data.get("input", ""),
-            config=task_data.get("config"),
-        )
-
-        # Update status
+        deadline = _deadline(task_data, queued_deadline)
+        if deadline is not None and deadline <= time.time():
+            logger.info(f"Skipping task {task_id}: its deadline has passed")
+            return {}
+
+        # Update status to processing, claiming the task when registered
+        agent_id = self.registry.agent_id if self.registry.enabled else None
+        if agent_id:
+            await self.storage.claim_task(task_id, agent_id)
+            affinity = task_data.get("affinity") or {}
+            if affinity.get("token"):
+                await self.storage.hold_affinity(
+                    affinity["token"], agent_id, int(affinity.get("ttl_secs", 0))
+                )
+        if not await self.storage.update_task_status(task_id, "processing", agent_id=agent_id):
+            # Settled, timed out or deleted since it was queued
+            if agent_id:
+                await self.storage.release_task(task_id, agent_id)
+            return {}
+
+        # Run agent, giving up once nobody waits for the answer
+        remaining = deadline - time.time() if deadline is not None else None
+        try:
+            work = (
+                _canary()
+                if task_data.get("canary")
+                else self.run(
+                    input_data=task_data.get("input", ""),
+                    config=task_data.get("config"),
+                )
+            )
+            result = await asyncio.wait_for(work, timeout=remaining)
+        except asyncio.TimeoutError:
+            logger.warning(f"Abandoned task {task_id}: its deadline passed")
+            if agent_id:
+                await self.storage.release_task(task_id, agent_id)
+            return {}
+
+        # Update status, dropping the outcome if the gateway gave up meanwhile
         if result.get("error"):
-            await self.storage.update_task_status(task_id, "failed", result)
-        else:
-            await self.storage.update_task_status(task_id, "completed", result)
+            if await self.storage.update_task_status(task_id, "failed", result):
+                await self.storage.store_error(task_id, str(result["error"]), code="agent_error")
+        elif await self.storage.update_task_status(task_id, "completed", result):
             await self.storage.store_result(task_id, result)
+        if agent_id:
+            await self.storage.release_task(task_id, agent_id)
 
         return result
 
@@ -713,19 +792,31 @@ Focus on what future decisions need to know, not everything that happened.
         while True:
             try:
                 # Get task from queue
-                task_id = await self.storage.pop_task_from_queue()
+                delivery = await self.queue.pop()
 
-                if task_id:
+                if delivery:
+                    task_id = delivery.task_id
                     logger.info(f"Processing task: {task_id}")
 
                     # Get task data
                     task_data = await self.storage.get_task(task_id)
 
-                    if task_data:
-                        # Process task
-                        await self.process_task(task_id, task_data)
+                    if task_data and task_data.get("status") in FINAL_STATUSES:
+                        # Redelivered or requeued after it was already settled
+                        logger.info(f"Skipping task {task_id}: {task_data['status']}")
+                    elif task_data:
+                        # Process task, tagging logs with the originating request
+                        # and the task, whose execution log they go to
+                        request_id = task_data.get("request_id") or "-"
+                        with logger.contextualize(request_id=request_id, task_id=task_id):
+                            try:
+                                await self.process_task(task_id, task_data, delivery.deadline)
+                            except Exception:
+                                await delivery.nak()
+                                raise
                     else:
                         logger.warning(f"Task data not found: {task_id}")
+                    await delivery.ack()
                 else:
                     # No tasks, sleep
                     await asyncio.sleep(0.1)
@@ -741,6 +832,8 @@ Focus on what future decisions need to know, not everything that happened.
         """Shutdown agent components."""
         await self.orchestrator.shutdown()
         await self.skill_executor.shutdown()
+        await self.queue.close()
+        await logger.complete()
         await self.storage.disconnect()
         # Note: mem0 doesn't have an explicit close method for FAISS
         logger.info("Agent shutdown complete")
@@ -12,8 +12,18 @@ from .config import get_config, validate_network_isolation
 async def main():
     """Main agent loop."""
     # Configure logging
+    # Tasks carry the gateway's X-Request-Id; include it on every line
     logger.remove()
-    logger.add(sys.stdout, level="DEBUG")
+    logger.configure(extra={"request_id": "-"})
+    logger.add(
+        sys.stdout,
+        level="DEBUG",
+        format=(
+            "<green>{time:YYYY-MM-DD HH:mm:ss.SSS}</green> | <level>{level: <8}</level> | "
+            "{extra[request_id]} | <cyan>{name}</cyan>:<cyan>{function}</cyan>:"
+            "<cyan>{line}</cyan> - <level>{message}</level>"
+        ),
+    )
 
     # Get configuration
     config = get_config()
@@ -43,18 +53,20 @@ async def main():
     signal.signal(signal.SIGINT, signal_handler)
     signal.signal(signal.SIGTERM, signal_handler)
 
-    # Run agent in background
+    # Run agent in background, heartbeating to the gateway registry
     run_task = asyncio.create_task(agent.run_forever())
+    registry_task = asyncio.create_task(agent.registry.run_forever())
 
     # Wait for shutdown
     await shutdown_event.wait()
 
-    # Cancel run task
-    run_task.cancel()
-    try:
-        await run_task
-    except asyncio.CancelledError:
-        pass
+    # Cancel background tasks
+    for task in (run_task, registry_task):
+        task.cancel()
+        try:
+            await task
+        except asyncio.CancelledError:
+            pass
 
     # Shutdown agent
     await agent.shutdown()
@@ -0,0 +1,74 @@
+"""Registration with the gateway's agent worker registry."""
+
+import asyncio
+import socket
+from typing import Optional
+
+import httpx
+from loguru import logger
+
+from .config import get_config
+
+
+class AgentRegistry:
+    """Registers this worker with the gateway and keeps its heartbeat alive.
+
+    The gateway marks tasks held by an agent whose heartbeat lapses as
+    orphaned, so registration is only enabled when both the gateway URL and
+    an API key with the ``agent`` role are configured.
+    """
+
+    def __init__(self):
+        self.config = get_config()
+        self.agent_id = self.config.agent_id or socket.gethostname()
+        self.enabled = bool(self.config.gateway_url and self.config.gateway_agent_key)
+        self.client: Optional[httpx.AsyncClient] = None
+
+    async def register(self):
+        """Register (or re-register) with the gateway."""
+        response = await self.client.post(
+            "/agents/register",
+            json={
+                "agent_id": self.agent_id,
+                "hostname": socket.gethostname(),
+                "version": "0.1.0",
+                "capabilities": self.config.capabilities,
+            },
+        )
+        response.raise_for_status()
+        logger.info(f"Registered with gateway as agent {self.agent_id}")
+
+    async def heartbeat(self):
+        """Refresh the registration, registering again if it lapsed."""
+        response = await self.client.post(f"/agents/{self.agent_id}/heartbeat")
+        if response.status_code == 404:
+            logger.warning("Agent registration expired, registering again")
+            await self.register()
+            return
+        response.raise_for_status()
+
+    async def run_forever(self):
+        """Register, then heartbeat until cancelled."""
+        if not self.enabled:
+            logger.info("Gateway URL or agent key not set, agent registry disabled")
+            return
+
+        self.client = httpx.AsyncClient(
+            base_url=self.config.gateway_url,
+            headers={"Authorization": f"Bearer {self.config.gateway_agent_key}"},
+            timeout=10.0,
+        )
+        registered = False
+        try:
+            while True:
+                try:
+                    if registered:
+                        await self.heartbeat()
+                    else:
+                        await self.register()
+                        registered = True
+                except httpx.HTTPError as e:
+                    logger.error(f"Gateway heartbeat failed: {e}")
+                await asyncio.sleep(self.config.heartbeat_interval)
+        finally:
+            await self.client.aclose()
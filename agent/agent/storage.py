@@ -1,12 +1,114 @@
 """Redis storage for secure agent."""
 
+import base64
 import json
+import os
 import re
+from datetime import datetime, timezone
 from typing import Optional, Any, Dict
 import redis.asyncio as redis
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
 from loguru import logger
 from .config import get_config
 
+# Newest storage envelope version understood by the agent; version 2 is
+# version 1 encrypted with AES-256-GCM, written when a key is configured
+ENVELOPE_VERSION = 2
+
+# Statuses each task status may move to; keep in step with the gateway's
+# TRANSITIONS in gateway/claw-core/src/task_state.rs
+TRANSITIONS = {
+    "waiting": {"pending", "failed", "deleted"},
+    "pending": {"pending", "processing", "completed", "failed", "timed_out", "deleted"},
+    "processing": {
+        "pending",
+        "processing",
+        "completed",
+        "failed",
+        "timed_out",
+        "orphaned",
+        "deleted",
+    },
+    "forwarded": {"completed", "failed", "deleted"},
+    "completed": {"deleted"},
+    "failed": {"pending", "deleted"},
+    "timed_out": {"pending", "deleted"},
+    "orphaned": {"pending", "processing", "deleted"},
+    "deleted": set(),
+}
+
+# Largest chunk of a raw result kept in one Redis key
+RAW_CHUNK_BYTES = 512 * 1024
+
+# Entries kept in a task's execution log, the stream logs:{task_id}; Redis
+# trims a little past this
+TASK_LOG_MAXLEN = 1000
+
+# Attempts at a status change before giving up on a record that keeps
+# changing underneath it
+MAX_TRANSITION_ATTEMPTS = 5
+
+# Replace KEYS[1] with ARGV[2] only if it still holds ARGV[1], so a status
+# change never overwrites one the gateway made meanwhile
+COMPARE_AND_SET_SCRIPT = """
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  redis.call('SET', KEYS[1], ARGV[2])
+  return 1
+end
+return 0
+"""
+
+
+def _unwrap(data: str) -> tuple[Any, Optional[int]]:
+    """
+    Decode a stored record, stripping its versioned envelope.
+
+    Returns the payload and the envelope version it was stored with, or
+    None for legacy bare-JSON records.
+    """
+    value = json.loads(data)
+    if not isinstance(value, dict) or not isinstance(value.get("v"), int):
+        return value, None
+    if set(value) == {"v", "payload"}:
+        if value["v"] != 1:
+            raise ValueError(f"unsupported envelope version {value['v']}")
+        return value["payload"], 1
+    if set(value) == {"v", "key_id", "nonce", "payload"}:
+        if value["v"] != 2:
+            raise ValueError(f"unsupported envelope version {value['v']}")
+        secret = get_config().encryption_keys.get(value["key_id"])
+        if secret is None:
+            raise ValueError(f"record encrypted with unknown key {value['key_id']}")
+        plain = AESGCM(base64.b64decode(secret)).decrypt(
+            base64.b64decode(value["nonce"]), base64.b64decode(value["payload"]), None
+        )
+        return json.loads(plain), 2
+    return value, None
+
+
+def _wrap(payload: Any, version: Optional[int] = ENVELOPE_VERSION) -> str:
+    """
+    Encode a record, in a versioned envelope unless version is None.
+
+    Version 2 encrypts the record when an encryption key is configured and
+    falls back to version 1 otherwise.
+    """
+    if version is None:
+        return json.dumps(payload)
+    config = get_config()
+    if version >= 2 and config.encryption_secret:
+        nonce = os.urandom(12)
+        sealed = AESGCM(base64.b64decode(config.encryption_secret)).encrypt(
+            nonce, json.dumps(payload).encode(), None
+        )
+        return json.dumps({
+            "v": 2,
+            "key_id": config.encryption_key_id,
+            "nonce": base64.b64encode(nonce).decode(),
+            "payload": base64.b64encode(sealed).decode(),
+        })
+    return json.dumps({"v": 1, "payload": payload})
+
 
 class SecureStorage:
     """Secure Redis storage with ACL-based access control."""
@@ -51,34 +153,140 @@ class SecureStorage:
         try:
             data = await self.redis.get(key)
             if data:
-                return json.loads(data)
+                return _unwrap(data)[0]
             return None
         except Exception as e:
             logger.error(f"Failed to get task {task_id}: {e}")
             return None
 
     async def update_task_status(
-        self, task_id: str, status: str, result: Optional[Any] = None
-    ):
+        self,
+        task_id: str,
+        status: str,
+        result: Optional[Any] = None,
+        agent_id: Optional[str] = None,
+    ) -> bool:
         """
         Update task status in Redis.
 
+        The change is refused if TRANSITIONS does not allow it, say because
+        the gateway timed the task out or it was deleted meanwhile.
+
         Args:
             task_id: Task ID
             status: New status (processing, completed, failed)
             result: Optional result data
+            agent_id: Registered agent holding the task, if any
+
+        Returns:
+            Whether the status was changed
         """
         key = f"task:{task_id}"
         try:
-            data = await self.redis.get(key)
-            if data:
-                task = json.loads(data)
+            for _ in range(MAX_TRANSITION_ATTEMPTS):
+                data = await self.redis.get(key)
+                if not data:
+                    logger.warning(f"Task {task_id} not found, not marked {status}")
+                    return False
+                # Keep the record in the format it was written in
+                task, version = _unwrap(data)
+                current = task.get("status", "unknown")
+                if status not in TRANSITIONS.get(current, set()):
+                    logger.warning(f"Task {task_id} is {current}, not marking it {status}")
+                    return False
                 task["status"] = status
+                if agent_id is not None:
+                    task["agent_id"] = agent_id
+                # The gateway bills agent time from these timestamps
+                now = datetime.now(timezone.utc).isoformat()
+                if status == "processing":
+                    task["started_at"] = now
+                elif status in ("completed", "failed"):
+                    task["completed_at"] = now
                 if result is not None:
                     task["result"] = result
-                await self.redis.set(key, json.dumps(task))
+                # Failed records carry their error message for the gateway
+                if status == "failed" and isinstance(result, dict) and result.get("error"):
+                    task["error"] = str(result["error"])
+                swapped = await self.redis.eval(
+                    COMPARE_AND_SET_SCRIPT, 1, key, data, _wrap(task, version)
+                )
+                if swapped:
+                    return True
+            logger.warning(f"Task {task_id} kept changing, not marked {status}")
         except Exception as e:
             logger.error(f"Failed to update task {task_id}: {e}")
+        return False
+
+    async def claim_task(self, task_id: str, agent_id: str):
+        """
+        Record that an agent is working on a task.
+
+        The gateway orphans claimed tasks whose agent stops heartbeating.
+
+        Args:
+            task_id: Task ID
+            agent_id: Registered agent ID
+        """
+        try:
+            await self.redis.sadd(f"agent:tasks:{agent_id}", task_id)
+        except Exception as e:
+            logger.error(f"Failed to claim task {task_id}: {e}")
+
+    async def hold_affinity(self, token: str, agent_id: str, ttl_secs: int):
+        """
+        Have the gateway send further tasks with an affinity token here.
+
+        Args:
+            token: Affinity token of the task, e.g. one per chat
+            agent_id: Registered agent ID
+            ttl_secs: How long the affinity lasts
+        """
+        if ttl_secs <= 0:
+            return
+        try:
+            await self.redis.set(f"affinity:{token}", agent_id, ex=ttl_secs)
+        except Exception as e:
+            logger.error(f"Failed to hold affinity {token}: {e}")
+
+    async def release_task(self, task_id: str, agent_id: str):
+        """
+        Drop a finished task from the agent's claimed set.
+
+        Args:
+            task_id: Task ID
+            agent_id: Registered agent ID
+        """
+        try:
+            await self.redis.srem(f"agent:tasks:{agent_id}", task_id)
+        except Exception as e:
+            logger.error(f"Failed to release task {task_id}: {e}")
+
+    async def log_sink(self, message):
+        """
+        Loguru sink appending records logged while working on a task to its
+        execution log, which `GET /task/:id/logs` on the gateway serves.
+
+        Args:
+            message: Loguru message, its record tagged with `task_id`
+        """
+        record = message.record
+        entry = {
+            "time": record["time"].astimezone(timezone.utc).isoformat(),
+            "level": record["level"].name,
+            "source": f"{record['name']}:{record['function']}:{record['line']}",
+            "message": record["message"],
+        }
+        try:
+            await self.redis.xadd(
+                f"logs:{record['extra']['task_id']}",
+                entry,
+                maxlen=TASK_LOG_MAXLEN,
+                approximate=True,
+            )
+        except Exception as e:
+            # Kept out of the task's log, which just failed
+            logger.bind(task_log=False).warning(f"Failed to append to task log: {e}")
 
     async def store_result(self, task_id: str, result: Any):
         """
@@ -90,11 +298,73 @@ class SecureStorage:
         """
         key = f"result:{task_id}"
         try:
-            await self.redis.set(key, json.dumps(result))
+            await self.redis.set(key, _wrap(result))
             logger.info(f"Stored result for task {task_id}")
         except Exception as e:
             logger.error(f"Failed to store result {task_id}: {e}")
 
+    async def store_raw_result(
+        self,
+        task_id: str,
+        data: bytes,
+        content_type: str = "application/octet-stream",
+        name: Optional[str] = None,
+    ):
+        """
+        Store a large or binary task result in Redis chunks.
+
+        The result record only refers to the chunks; the gateway streams
+        them from GET /task/<id>/result/raw.
+
+        Args:
+            task_id: Task ID
+            data: Result contents
+            content_type: Media type of the contents
+            name: Optional file name offered to the downloader
+        """
+        chunks = [
+            data[i:i + RAW_CHUNK_BYTES] for i in range(0, len(data), RAW_CHUNK_BYTES)
+        ]
+        reference = {
+            "chunks": len(chunks),
+            "content_type": content_type,
+            "size": len(data),
+        }
+        if name:
+            reference["name"] = name
+        try:
+            pipe = self.redis.pipeline()
+            for index, chunk in enumerate(chunks):
+                pipe.set(f"result:{task_id}:chunk:{index}", chunk)
+            await pipe.execute()
+            # The reference goes last, so readers never find chunks missing
+            await self.redis.set(f"result:{task_id}", _wrap({"raw": reference}))
+            logger.info(f"Stored raw result for task {task_id} in {len(chunks)} chunks")
+        except Exception as e:
+            logger.error(f"Failed to store raw result {task_id}: {e}")
+
+    async def store_error(self, task_id: str, message: str, code: Optional[str] = None):
+        """
+        Store the error report of a failed task in Redis.
+
+        The gateway answers the task with status failed and this report
+        instead of a result.
+
+        Args:
+            task_id: Task ID
+            message: What went wrong, readable by the submitter
+            code: Optional machine-readable error code
+        """
+        key = f"error:{task_id}"
+        report = {"error": message}
+        if code:
+            report["code"] = code
+        try:
+            await self.redis.set(key, _wrap(report))
+            logger.info(f"Stored error report for task {task_id}")
+        except Exception as e:
+            logger.error(f"Failed to store error report {task_id}: {e}")
+
     async def get_result(self, task_id: str) -> Optional[Any]:
         """
         Get task result from Redis.
@@ -109,21 +379,37 @@ class SecureStorage:
         try:
             data = await self.redis.get(key)
             if data:
-                return json.loads(data)
+                return _unwrap(data)[0]
             return None
         except Exception as e:
             logger.error(f"Failed to get result {task_id}: {e}")
             return None
 
-    async def pop_task_from_queue(self) -> Optional[str]:
+    async def pop_task_from_queue(self, agent_id: Optional[str] = None) -> Optional[str]:
         """
         Pop a task from the agent queue.
 
+        The agent's own queue, holding follow-ups of conversations it
+        handled, comes first. Priority lanes (operator/admin submissions)
+        are always drained before regular queues, and the queues of this
+        agent's capabilities before the general queue.
+
+        Args:
+            agent_id: Registered agent ID, if registered
+
         Returns:
             Task ID or None if queue is empty
         """
+        capabilities = self.config.capabilities
+        queues = (
+            ([f"agent:direct:{agent_id}"] if agent_id else [])
+            + ["agent:queue:priority"]
+            + [f"agent:queue:{c}:priority" for c in capabilities]
+            + [f"agent:queue:{c}" for c in capabilities]
+            + ["agent:queue"]
+        )
         try:
-            task_id = await self.redis.brpop("agent:queue", timeout=1)
+            task_id = await self.redis.brpop(queues, timeout=1)
             if task_id:
                 return task_id[1]  # brpop returns (key, value)
             return None
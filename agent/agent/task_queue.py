@@ -0,0 +1,134 @@
+"""Consumers for the gateway's task queue backends."""
+
+from typing import Any, List, Optional
+
+import nats
+from loguru import logger
+from nats.errors import TimeoutError as NatsTimeoutError
+from nats.js.api import ConsumerConfig
+
+from .config import get_config
+from .storage import SecureStorage
+
+# Statuses after which a delivered task is not processed again
+FINAL_STATUSES = ("completed", "failed", "timed_out", "deleted")
+
+
+# NATS header carrying a task's deadline in unix seconds
+DEADLINE_HEADER = "Claw-Deadline"
+
+
+class Delivery:
+    """A task id handed to this agent, acknowledged once processed."""
+
+    def __init__(self, task_id: str, msg: Any = None):
+        self.task_id = task_id
+        self._msg = msg
+        # Unix time after which nobody waits for the task, when the queue said
+        self.deadline: Optional[float] = None
+        headers = getattr(msg, "headers", None) or {}
+        if headers.get(DEADLINE_HEADER):
+            self.deadline = float(headers[DEADLINE_HEADER])
+
+    async def ack(self):
+        """Mark the task as processed so it is not delivered again."""
+        if self._msg is not None:
+            await self._msg.ack()
+
+    async def nak(self):
+        """Hand the task back for redelivery."""
+        if self._msg is not None:
+            await self._msg.nak()
+
+
+class RedisTaskQueue:
+    """Pops task ids from the ``agent:queue*`` lists.
+
+    A popped task is gone from the list, so acknowledgements are no-ops.
+    """
+
+    def __init__(self, storage: SecureStorage, agent_id: Optional[str] = None):
+        self.storage = storage
+        self.agent_id = agent_id
+
+    async def connect(self):
+        pass
+
+    async def pop(self) -> Optional[Delivery]:
+        task_id = await self.storage.pop_task_from_queue(self.agent_id)
+        return Delivery(task_id) if task_id else None
+
+    async def close(self):
+        pass
+
+
+class NatsTaskQueue:
+    """Pulls task ids from the gateway's JetStream work-queue stream.
+
+    Each lane (priority, capability queues, the general queue) has a durable
+    pull consumer shared by every agent, drained in the same order as the
+    Redis lists, after the lane of this agent's own tasks. A task not acknowledged within the ack wait, for instance
+    because this agent died, is redelivered up to ``nats_max_deliver`` times.
+    """
+
+    def __init__(self, agent_id: Optional[str] = None):
+        self.config = get_config()
+        self.agent_id = agent_id
+        self.nc = None
+        self.subscriptions: List[Any] = []
+
+    def _lanes(self) -> List[str]:
+        capabilities = self.config.capabilities
+        # Subjects of agent queues replace the dots in agent ids
+        own = [f"agent.{self.agent_id.replace('.', '_')}"] if self.agent_id else []
+        return (
+            own
+            + ["priority"]
+            + [f"capability.{c}.priority" for c in capabilities]
+            + [f"capability.{c}" for c in capabilities]
+            + ["queue"]
+        )
+
+    async def connect(self):
+        config = self.config
+        self.nc = await nats.connect(config.nats_url)
+        js = self.nc.jetstream()
+        ack_wait = config.nats_ack_wait or config.task_timeout
+        for lane in self._lanes():
+            subscription = await js.pull_subscribe(
+                f"{config.nats_subject_prefix}.{lane}",
+                durable=f"claw-{lane.replace('.', '-')}",
+                stream=config.nats_stream,
+                config=ConsumerConfig(ack_wait=ack_wait, max_deliver=config.nats_max_deliver),
+            )
+            self.subscriptions.append(subscription)
+        logger.info(f"Consuming tasks from NATS stream {config.nats_stream}")
+
+    async def pop(self) -> Optional[Delivery]:
+        # Empty lanes are skipped quickly; the last one waits like BRPOP
+        for i, subscription in enumerate(self.subscriptions):
+            timeout = 1 if i == len(self.subscriptions) - 1 else 0.05
+            try:
+                msgs = await subscription.fetch(1, timeout=timeout)
+            except NatsTimeoutError:
+                continue
+            if msgs:
+                return Delivery(msgs[0].data.decode(), msgs[0])
+        return None
+
+    async def close(self):
+        if self.nc is not None:
+            await self.nc.drain()
+
+
+def create_task_queue(storage: SecureStorage, agent_id: Optional[str] = None):
+    """The consumer for the configured ``queue_backend``.
+
+    ``agent_id`` adds the queue of tasks sent to this agent alone.
+    """
+    backend = get_config().queue_backend
+    if backend == "nats":
+        return NatsTaskQueue(agent_id)
+    if backend != "redis":
+        raise ValueError(f"unknown queue backend {backend!r}")
+    return RedisTaskQueue(storage, agent_id)
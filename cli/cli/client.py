@@ -1,7 +1,7 @@
 """HTTP client for interacting with the gateway."""
 
 import httpx
-from typing import Optional, Dict, Any
+from typing import Optional, Dict, Any, List
 from loguru import logger
 
 
@@ -32,6 +32,7 @@ class GatewayClient:
         task_id: Optional[str] = None,
         config: Optional[Dict] = None,
         auth_token: Optional[str] = None,
+        depends_on: Optional[List[str]] = None,
     ) -> Dict[str, Any]:
         """
         Submit a task to the agent.
@@ -41,6 +42,7 @@ class GatewayClient:
             task_id: Optional task ID
             config: Optional config
             auth_token: Optional auth token
+            depends_on: Tasks that must complete before this one runs
 
         Returns:
             Response from gateway
@@ -58,6 +60,8 @@ class GatewayClient:
             "input": {"text": input_data},
             "config": config,
         }
+        if depends_on:
+            payload["depends_on"] = depends_on
 
         try:
             response = await self.client.post(
@@ -104,6 +108,68 @@ class GatewayClient:
                 "error": str(e),
             }
 
+    async def support_bundle(self, auth_token: str) -> tuple[str, bytes]:
+        """
+        Download a support bundle from the gateway admin API.
+
+        Args:
+            auth_token: Admin bearer token
+
+        Returns:
+            Suggested file name and archive bytes
+        """
+        response = await self.client.post(
+            f"{self.base_url}/admin/support-bundle",
+            headers={"Authorization": f"Bearer {auth_token}"},
+        )
+        response.raise_for_status()
+
+        disposition = response.headers.get("content-disposition", "")
+        filename = "support-bundle.tar.gz"
+        if 'filename="' in disposition:
+            filename = disposition.split('filename="', 1)[1].rstrip('"')
+        return filename, response.content
+
+    async def default_config(
+        self,
+        auth_token: str,
+        config: Optional[dict] = None,
+        rollback: Optional[int] = None,
+    ) -> dict:
+        """
+        Show, replace or roll back the default agent config via the admin API.
+
+        Args:
+            auth_token: Admin bearer token
+            config: New config to publish
+            rollback: Earlier version to re-publish
+
+        Returns:
+            The resulting config version record
+        """
+        headers = {"Authorization": f"Bearer {auth_token}"}
+        url = f"{self.base_url}/admin/config/default"
+        if rollback is not None:
+            response = await self.client.post(
+                f"{url}/rollback", json={"version": rollback}, headers=headers
+            )
+        elif config is not None:
+            response = await self.client.put(url, json=config, headers=headers)
+        else:
+            response = await self.client.get(url, headers=headers)
+        response.raise_for_status()
+        return response.json()
+
+    async def default_config_history(self, auth_token: str, limit: int = 20) -> list[dict]:
+        """List recorded default config versions, newest first."""
+        response = await self.client.get(
+            f"{self.base_url}/admin/config/default/history",
+            params={"limit": limit},
+            headers={"Authorization": f"Bearer {auth_token}"},
+        )
+        response.raise_for_status()
+        return response.json()["versions"]
+
     async def watch_task(
         self,
         task_id: str,
@@ -4,7 +4,7 @@ import asyncio
 import os
 import sys
 from pathlib import Path
-from typing import Optional
+from typing import List, Optional
 
 import typer
 from rich.console import Console
@@ -30,6 +30,7 @@ REDIS_HOST = os.getenv("REDIS_HOST", "redis")
 REDIS_PORT = int(os.getenv("REDIS_PORT", "6379"))
 REDIS_PASSWORD = os.getenv("REDIS_PASSWORD", "")
 GATEWAY_URL = os.getenv("GATEWAY_URL", "http://gateway:8080")
+ADMIN_TOKEN = os.getenv("ADMIN_TOKEN", "")
 
 
 @app.command()
@@ -189,6 +190,9 @@ def submit(
     input: str = typer.Argument(..., help="Input text for the agent"),
     task_id: Optional[str] = typer.Option(None, "--task-id", "-t", help="Task ID"),
     config: Optional[str] = typer.Option(None, "--config", "-c", help="Config JSON"),
+    depends_on: Optional[List[str]] = typer.Option(
+        None, "--depends-on", "-d", help="Task that must complete first (repeatable)"
+    ),
 ):
     """Submit a task to the agent."""
     import asyncio
@@ -199,7 +203,7 @@ def submit(
     async def do_submit():
         client = GatewayClient(GATEWAY_URL)
         try:
-            result = await client.submit_task(input, task_id, cfg)
+            result = await client.submit_task(input, task_id, cfg, depends_on=depends_on)
             await client.close()
 
             console.print(f"[bold green]Task submitted:[/bold green]")
@@ -325,6 +329,80 @@ def queue(
         manager.disconnect()
 
 
+@app.command("support-bundle")
+def support_bundle(
+    output: Optional[Path] = typer.Option(
+        None, "--output", "-o", help="Where to write the archive (default: server-provided name)"
+    ),
+    token: str = typer.Option(ADMIN_TOKEN, "--token", help="Admin bearer token"),
+):
+    """Download a support bundle for attaching to bug reports."""
+    if not token:
+        console.print("[bold red]Error:[/bold red] an admin token is required (--token or ADMIN_TOKEN)")
+        sys.exit(1)
+
+    async def do_download():
+        client = GatewayClient(GATEWAY_URL)
+        try:
+            filename, archive = await client.support_bundle(token)
+        finally:
+            await client.close()
+
+        path = output or Path(filename)
+        path.write_bytes(archive)
+        console.print(f"[bold green]Support bundle written:[/bold green] {path} ({len(archive)} bytes)")
+
+    try:
+        asyncio.run(do_download())
+    except Exception as e:
+        console.print(f"[bold red]Error:[/bold red] {e}")
+        sys.exit(1)
+
+
+@app.command("default-config")
+def default_config(
+    value: Optional[str] = typer.Argument(None, help="New default config JSON"),
+    history: bool = typer.Option(False, "--history", help="List recorded versions"),
+    rollback: Optional[int] = typer.Option(None, "--rollback", help="Re-publish an earlier version"),
+    token: str = typer.Option(ADMIN_TOKEN, "--token", help="Admin bearer token"),
+):
+    """Show or change the versioned default agent config."""
+    import json
+
+    if not token:
+        console.print("[bold red]Error:[/bold red] an admin token is required (--token or ADMIN_TOKEN)")
+        sys.exit(1)
+
+    async def do_request():
+        client = GatewayClient(GATEWAY_URL)
+        try:
+            if history:
+                versions = await client.default_config_history(token)
+                table = Table(title="Default Config History")
+                table.add_column("Version", style="cyan")
+                table.add_column("Updated By", style="green")
+                table.add_column("Updated At")
+                table.add_column("Note")
+                for v in versions:
+                    note = f"rollback of v{v['rolled_back_from']}" if v.get("rolled_back_from") else ""
+                    table.add_row(str(v["version"]), v.get("updated_by") or "", v.get("updated_at") or "", note)
+                console.print(table)
+                return
+
+            config = json.loads(value) if value else None
+            record = await client.default_config(token, config=config, rollback=rollback)
+            console.print(f"[bold]Version:[/bold] {record['version']}")
+            console.print_json(json.dumps(record["config"]))
+        finally:
+            await client.close()
+
+    try:
+        asyncio.run(do_request())
+    except Exception as e:
+        console.print(f"[bold red]Error:[/bold red] {e}")
+        sys.exit(1)
+
+
 @app.command()
 def setup():
     """Setup environment files."""
@@ -62,6 +62,8 @@ class RedisManager:
         """Set config value."""
         try:
             self.client.set(f"config:{key}", json.dumps(value))
+            # Let watchers (e.g. the gateway's config:runtime) reload
+            self.client.publish(f"config:{key}", "updated")
             logger.info(f"Set config {key}")
         except Exception as e:
             logger.error(f"Failed to set config {key}: {e}")
@@ -81,7 +83,11 @@ class RedisManager:
         try:
             data = self.client.get(f"task:{task_id}")
             if data:
-                return json.loads(data)
+                task = json.loads(data)
+                # Unwrap versioned `{"v", "payload"}` envelopes
+                if isinstance(task, dict) and set(task) == {"v", "payload"}:
+                    return task["payload"]
+                return task
             return None
         except Exception as e:
             logger.error(f"Failed to get task {task_id}: {e}")
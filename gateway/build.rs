@@ -0,0 +1,70 @@
+//! Generates the gRPC service from `proto/gateway.proto` when the `grpc`
+//! feature is enabled. The schema is compiled in-process, so building does
+//! not need `protoc`.
+//!
+//! Also records what `GET /version` reports about the build: the commit as
+//! `BUILD_GIT_SHA` (`GIT_SHA` from the environment when set, as in container
+//! builds without `.git`, else what git reports, else `unknown`), the build
+//! time in Unix seconds as `BUILD_TIMESTAMP` (`SOURCE_DATE_EPOCH` when set,
+//! for reproducible builds) and the enabled features as `BUILD_FEATURES`.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Output of `git <args>`, if git ran and succeeded
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    let output = String::from_utf8(output.stdout)
+        .ok()
+        .filter(|_| output.status.success())?;
+    Some(output.trim().to_string()).filter(|o| !o.is_empty())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+        let descriptors = protox::compile(["proto/gateway.proto"], ["proto"])?;
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)?;
+    }
+
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    let sha = match std::env::var("GIT_SHA").ok().filter(|sha| !sha.is_empty()) {
+        Some(sha) => sha,
+        None => {
+            // Build again when a commit is made or checked out
+            if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+                for watched in ["HEAD", "refs", "packed-refs"] {
+                    let path = Path::new(&git_dir).join(watched);
+                    if path.exists() {
+                        println!("cargo:rerun-if-changed={}", path.display());
+                    }
+                }
+            }
+            git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string())
+        }
+    };
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+
+    // Cargo tells build scripts the enabled features as CARGO_FEATURE_<NAME>
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    Ok(())
+}
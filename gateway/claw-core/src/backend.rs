@@ -0,0 +1,165 @@
+//! Task storage as an agent sees it.
+//!
+//! [`TaskBackend`] is what an agent needs of the gateway's storage: queue
+//! entries to take, task records to move through their statuses, and result
+//! and error records to leave. [`RedisBackend`] is the real thing, over the
+//! layout in [`keys`](crate::keys); `claw-test` has an in-memory one, so an
+//! agent's handlers can be tested without Redis.
+
+use redis::aio::ConnectionLike;
+use serde_json::Value;
+use std::future::Future;
+
+use crate::store::{self, StoreError};
+use crate::task_state::{self, TransitionError};
+use crate::{Task, TaskStatus};
+
+pub trait TaskBackend: Send {
+    /// Take the next task id off the first of `queues` holding one
+    fn pop(
+        &mut self,
+        queues: &[String],
+    ) -> impl Future<Output = Result<Option<String>, StoreError>> + Send;
+
+    /// Queue `task_id` on `queue`, behind the tasks already there
+    fn push(
+        &mut self,
+        queue: &str,
+        task_id: &str,
+    ) -> impl Future<Output = Result<(), StoreError>> + Send;
+
+    fn get_task(
+        &mut self,
+        task_id: &str,
+    ) -> impl Future<Output = Result<Option<Task>, StoreError>> + Send;
+
+    /// Store a new task record, replacing any under `task_id`
+    fn put_task(
+        &mut self,
+        task_id: &str,
+        task: &Task,
+    ) -> impl Future<Output = Result<(), StoreError>> + Send;
+
+    /// Move `task_id` to `to` as [`task_state::transition`] does, letting
+    /// `update` edit the record first; returns the record as written
+    fn transition<F>(
+        &mut self,
+        task_id: &str,
+        to: TaskStatus,
+        update: F,
+    ) -> impl Future<Output = Result<Task, TransitionError>> + Send
+    where
+        F: FnMut(&mut Task) + Send;
+
+    fn get_result(
+        &mut self,
+        task_id: &str,
+    ) -> impl Future<Output = Result<Option<Value>, StoreError>> + Send;
+
+    fn set_result(
+        &mut self,
+        task_id: &str,
+        result: &Value,
+    ) -> impl Future<Output = Result<(), StoreError>> + Send;
+
+    fn get_error(
+        &mut self,
+        task_id: &str,
+    ) -> impl Future<Output = Result<Option<Value>, StoreError>> + Send;
+
+    fn set_error(
+        &mut self,
+        task_id: &str,
+        report: &Value,
+    ) -> impl Future<Output = Result<(), StoreError>> + Send;
+}
+
+/// [`TaskBackend`] over a Redis connection
+pub struct RedisBackend<C> {
+    conn: C,
+}
+
+impl<C> RedisBackend<C> {
+    pub fn new(conn: C) -> Self {
+        Self { conn }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.conn
+    }
+}
+
+impl<C: ConnectionLike + Send + Sync> TaskBackend for RedisBackend<C> {
+    async fn pop(&mut self, queues: &[String]) -> Result<Option<String>, StoreError> {
+        for queue in queues {
+            let task_id: Option<String> = redis::cmd("RPOP")
+                .arg(queue)
+                .query_async(&mut self.conn)
+                .await?;
+            if task_id.is_some() {
+                return Ok(task_id);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn push(&mut self, queue: &str, task_id: &str) -> Result<(), StoreError> {
+        redis::cmd("LPUSH")
+            .arg(queue)
+            .arg(task_id)
+            .query_async::<_, ()>(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_task(&mut self, task_id: &str) -> Result<Option<Task>, StoreError> {
+        store::get_task(&mut self.conn, task_id).await
+    }
+
+    async fn put_task(&mut self, task_id: &str, task: &Task) -> Result<(), StoreError> {
+        store::set_task(&mut self.conn, task_id, task).await
+    }
+
+    async fn transition<F>(
+        &mut self,
+        task_id: &str,
+        to: TaskStatus,
+        mut update: F,
+    ) -> Result<Task, TransitionError>
+    where
+        F: FnMut(&mut Task) + Send,
+    {
+        let mut decoded = Ok(());
+        let record = task_state::transition(&mut self.conn, task_id, to.as_str(), |record| {
+            let edited = Task::from_record(record.clone()).and_then(|mut task| {
+                update(&mut task);
+                task.to_record()
+            });
+            match edited {
+                Ok(edited) => *record = edited,
+                Err(e) => decoded = Err(e),
+            }
+            decoded.is_ok()
+        })
+        .await?;
+        decoded?;
+        let record = record.ok_or(TransitionError::NotFound)?;
+        Ok(Task::from_record(record)?)
+    }
+
+    async fn get_result(&mut self, task_id: &str) -> Result<Option<Value>, StoreError> {
+        store::get_result(&mut self.conn, task_id).await
+    }
+
+    async fn set_result(&mut self, task_id: &str, result: &Value) -> Result<(), StoreError> {
+        store::set_result(&mut self.conn, task_id, result).await
+    }
+
+    async fn get_error(&mut self, task_id: &str) -> Result<Option<Value>, StoreError> {
+        store::get_error(&mut self.conn, task_id).await
+    }
+
+    async fn set_error(&mut self, task_id: &str, report: &Value) -> Result<(), StoreError> {
+        store::set_error(&mut self.conn, task_id, report).await
+    }
+}
@@ -0,0 +1,233 @@
+//! Versioned envelopes for task and result records stored in Redis.
+//!
+//! Records are written as `{"v": <version>, "payload": <record>}`. Readers
+//! accept the current version and every older one, including the original
+//! bare-JSON records (version 0), so gateways and agents can be upgraded in
+//! any order. Writers emit `tasks.envelope_version` (default: current), which
+//! can be pinned to 0 while older agents are still being rolled out.
+//!
+//! Version 2 is version 1 encrypted, `{"v": 2, "key_id": "...", "nonce":
+//! "<base64>", "payload": "<base64>"}` with the payload's JSON sealed under
+//! AES-256-GCM, so records in a compromised Redis leak no task inputs,
+//! configs or results. It is written while the write version is 2 and an
+//! `encryption.secret` is set, and read with whichever configured key its
+//! `key_id` names; without a key, version 2 writes fall back to version 1.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+
+/// Newest envelope version this gateway understands
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Keys records are encrypted with
+struct Keyring {
+    /// Id of the key new records are written with
+    key_id: String,
+    keys: HashMap<String, LessSafeKey>,
+}
+
+/// Encryption keys, once configured
+static KEYRING: RwLock<Option<Keyring>> = RwLock::new(None);
+
+/// Version used when writing records
+static WRITE_VERSION: AtomicU32 = AtomicU32::new(CURRENT_VERSION);
+
+/// Set the version used for writes; validated against [`CURRENT_VERSION`]
+/// when the configuration is loaded
+pub fn set_write_version(version: u32) {
+    WRITE_VERSION.store(version.min(CURRENT_VERSION), Ordering::Relaxed);
+}
+
+/// The 32-byte AES-256 key a base64 `secret` holds
+pub fn parse_secret(secret: &str) -> Result<Vec<u8>, String> {
+    let key = STANDARD
+        .decode(secret.trim())
+        .map_err(|e| format!("not base64: {}", e))?;
+    if key.len() != 32 {
+        return Err(format!("{} bytes instead of 32", key.len()));
+    }
+    Ok(key)
+}
+
+/// Encrypt new records with `secret` under `key_id`, and read records
+/// written with it or any of the `previous` secrets, by key id
+pub fn set_keys(
+    key_id: &str,
+    secret: &str,
+    previous: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    let mut keys = HashMap::new();
+    let secrets = previous
+        .iter()
+        .map(|(id, secret)| (id.as_str(), secret.as_str()))
+        .chain([(key_id, secret)]);
+    for (id, secret) in secrets {
+        let bytes = parse_secret(secret).map_err(|e| format!("key {}: {}", id, e))?;
+        let key = UnboundKey::new(&aead::AES_256_GCM, &bytes)
+            .map_err(|_| format!("key {}: rejected", id))?;
+        keys.insert(id.to_string(), LessSafeKey::new(key));
+    }
+    let keyring = Keyring {
+        key_id: key_id.to_string(),
+        keys,
+    };
+    *KEYRING.write().unwrap_or_else(|e| e.into_inner()) = Some(keyring);
+    Ok(())
+}
+
+/// Seal `payload` under the write key, if one is configured
+fn encrypt(payload: &Value) -> serde_json::Result<Option<Value>> {
+    let keyring = KEYRING.read().unwrap_or_else(|e| e.into_inner());
+    let Some(keyring) = keyring.as_ref() else {
+        return Ok(None);
+    };
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| serde::ser::Error::custom("no randomness for a nonce"))?;
+    let mut sealed = serde_json::to_vec(payload)?;
+    keyring.keys[&keyring.key_id]
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .map_err(|_| serde::ser::Error::custom("encryption failed"))?;
+    Ok(Some(serde_json::json!({
+        "v": 2,
+        "key_id": keyring.key_id,
+        "nonce": STANDARD.encode(nonce),
+        "payload": STANDARD.encode(sealed),
+    })))
+}
+
+/// Open a version 2 envelope
+fn decrypt(map: &serde_json::Map<String, Value>) -> Result<Value, String> {
+    let field = |name: &str| {
+        map.get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("encrypted record without {}", name))
+    };
+    let key_id = field("key_id")?;
+    let nonce = STANDARD
+        .decode(field("nonce")?)
+        .ok()
+        .and_then(|n| Nonce::try_assume_unique_for_key(&n).ok())
+        .ok_or("encrypted record with a bad nonce")?;
+    let mut sealed = STANDARD
+        .decode(field("payload")?)
+        .map_err(|e| format!("encrypted payload is not base64: {}", e))?;
+
+    let keyring = KEYRING.read().unwrap_or_else(|e| e.into_inner());
+    let key = keyring
+        .as_ref()
+        .and_then(|k| k.keys.get(key_id))
+        .ok_or_else(|| format!("record encrypted with unknown key {}", key_id))?;
+    let plain = key
+        .open_in_place(nonce, Aad::empty(), &mut sealed)
+        .map_err(|_| format!("record does not decrypt with key {}", key_id))?;
+    serde_json::from_slice(plain).map_err(|e| e.to_string())
+}
+
+/// Serialize a record in the configured envelope version
+pub fn encode(payload: &Value) -> serde_json::Result<String> {
+    let version = WRITE_VERSION.load(Ordering::Relaxed);
+    if version >= 2 {
+        if let Some(sealed) = encrypt(payload)? {
+            return serde_json::to_string(&sealed);
+        }
+    }
+    match version {
+        0 => serde_json::to_string(payload),
+        _ => serde_json::to_string(&serde_json::json!({
+            "v": 1,
+            "payload": payload,
+        })),
+    }
+}
+
+/// Parse a stored record written in any supported envelope version
+pub fn decode(raw: &str) -> serde_json::Result<Value> {
+    let value: Value = serde_json::from_str(raw)?;
+    unwrap(value).map_err(serde::de::Error::custom)
+}
+
+/// Strip the envelope from an already-parsed record
+pub fn unwrap(value: Value) -> Result<Value, String> {
+    let Value::Object(mut map) = value else {
+        return Ok(value);
+    };
+
+    // Only `{v, payload}` with nothing else, or with the key id and nonce of
+    // an encrypted one, is an envelope; anything else is a legacy bare record
+    // that merely happens to be an object
+    let encrypted = map.len() == 4 && map.contains_key("key_id") && map.contains_key("nonce");
+    let is_envelope = (map.len() == 2 || encrypted)
+        && map.get("v").is_some_and(Value::is_u64)
+        && map.contains_key("payload");
+    if !is_envelope {
+        return Ok(Value::Object(map));
+    }
+
+    let version = map["v"].as_u64().unwrap_or_default();
+    match version {
+        1 if !encrypted => Ok(map.remove("payload").unwrap_or(Value::Null)),
+        2 if encrypted => decrypt(&map),
+        v => Err(format!("unsupported envelope version {}", v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_every_version() {
+        let record = json!({"input": "hello", "status": "pending"});
+        assert_eq!(decode(&record.to_string()).unwrap(), record);
+        let v1 = json!({"v": 1, "payload": record}).to_string();
+        assert_eq!(decode(&v1).unwrap(), record);
+        assert!(decode(&json!({"v": 9, "payload": record}).to_string()).is_err());
+        // Bare records that only resemble an envelope stay as they are
+        let lookalike = json!({"v": 1, "payload": "x", "status": "pending"});
+        assert_eq!(decode(&lookalike.to_string()).unwrap(), lookalike);
+    }
+
+    #[test]
+    fn encrypts_with_a_key_and_reads_rotated_ones() {
+        let old = STANDARD.encode([7u8; 32]);
+        let new = STANDARD.encode([9u8; 32]);
+        let record = json!({"input": "a secret conversation"});
+
+        set_keys("old", &old, &BTreeMap::new()).unwrap();
+        let sealed = encrypt(&record).unwrap().unwrap();
+        assert!(!sealed.to_string().contains("secret conversation"));
+        assert_eq!(decode(&sealed.to_string()).unwrap(), record);
+
+        let previous = BTreeMap::from([("old".to_string(), old)]);
+        set_keys("new", &new, &previous).unwrap();
+        assert_eq!(decode(&sealed.to_string()).unwrap(), record);
+        let resealed = encrypt(&record).unwrap().unwrap();
+        assert_eq!(resealed["key_id"], "new");
+
+        set_keys("new", &new, &BTreeMap::new()).unwrap();
+        assert!(decode(&sealed.to_string()).is_err());
+        let mut tampered = resealed;
+        tampered["nonce"] = STANDARD.encode([0u8; NONCE_LEN]).into();
+        assert!(decode(&tampered.to_string()).is_err());
+        *KEYRING.write().unwrap() = None;
+    }
+
+    #[test]
+    fn secrets_are_32_bytes() {
+        assert!(parse_secret(&STANDARD.encode([1u8; 32])).is_ok());
+        assert!(parse_secret(&STANDARD.encode([1u8; 16])).is_err());
+        assert!(parse_secret("not base64!").is_err());
+    }
+}
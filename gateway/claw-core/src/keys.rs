@@ -0,0 +1,67 @@
+//! Redis key names.
+//!
+//! Tasks are queued by pushing their id onto the left of a queue list and
+//! taken by popping from the right, an agent trying its
+//! [direct queue](direct_queue) before any other.
+
+/// General queue of tasks any agent may run
+pub const AGENT_QUEUE: &str = "agent:queue";
+/// Priority lane of the general queue
+pub const PRIORITY_QUEUE: &str = "agent:queue:priority";
+/// Prefix of the queues of agents with a capability
+pub const CAPABILITY_QUEUE_PREFIX: &str = "agent:queue:";
+/// Prefix of the queues of single agents
+pub const DIRECT_QUEUE_PREFIX: &str = "agent:direct:";
+/// Prefix of the sets of tasks each agent is processing
+pub const AGENT_TASKS_PREFIX: &str = "agent:tasks:";
+
+/// Task record, JSON in an [envelope](crate::envelope)
+pub fn task_key(task_id: &str) -> String {
+    format!("task:{}", task_id)
+}
+
+/// Result of a completed task
+pub fn result_key(task_id: &str) -> String {
+    format!("result:{}", task_id)
+}
+
+/// Error report of a failed task
+pub fn error_key(task_id: &str) -> String {
+    format!("error:{}", task_id)
+}
+
+/// Stream of a task's execution log
+pub fn logs_key(task_id: &str) -> String {
+    format!("logs:{}", task_id)
+}
+
+/// Set of the tasks `agent_id` is processing
+pub fn agent_tasks_key(agent_id: &str) -> String {
+    format!("{}{}", AGENT_TASKS_PREFIX, agent_id)
+}
+
+/// Queue only `agent_id` takes tasks from
+pub fn direct_queue(agent_id: &str) -> String {
+    format!("{}{}", DIRECT_QUEUE_PREFIX, agent_id)
+}
+
+/// Queues an agent with `capabilities` drains, first to last, as the
+/// Python agent does
+pub fn claim_queues(agent_id: Option<&str>, capabilities: &[String]) -> Vec<String> {
+    let mut queues: Vec<String> = agent_id.map(direct_queue).into_iter().collect();
+    queues.push(queue_for(None, true));
+    queues.extend(capabilities.iter().map(|c| queue_for(Some(c), true)));
+    queues.extend(capabilities.iter().map(|c| queue_for(Some(c), false)));
+    queues.push(queue_for(None, false));
+    queues
+}
+
+/// Queue for a task, with a priority lane per capability as well
+pub fn queue_for(capability: Option<&str>, priority: bool) -> String {
+    match (capability, priority) {
+        (None, false) => AGENT_QUEUE.to_string(),
+        (None, true) => PRIORITY_QUEUE.to_string(),
+        (Some(cap), false) => format!("{}{}", CAPABILITY_QUEUE_PREFIX, cap),
+        (Some(cap), true) => format!("{}{}:priority", CAPABILITY_QUEUE_PREFIX, cap),
+    }
+}
@@ -0,0 +1,32 @@
+//! Task records, wire types and Redis layout of the secure gateway.
+//!
+//! Agents written in Rust depend on this crate instead of repeating the
+//! gateway's JSON shapes and key formats, and the gateway builds on it too,
+//! so the two cannot drift apart:
+//!
+//! - [`AgentRequest`] and [`AgentResponse`] are the bodies of `POST /task`
+//!   and of its answers.
+//! - [`Task`] is the record under `task:{id}`, in the current
+//!   [`schema`], and [`TaskStatus`] its status, moving as
+//!   [`task_state`] allows.
+//! - [`keys`] names the Redis keys and queues, and [`store`] reads and
+//!   writes the records through their [`envelope`].
+//! - [`TaskBackend`] is all of that as an agent uses it, over Redis with
+//!   [`RedisBackend`].
+//!
+//! The Python agent keeps its own copy of the layout in `agent/agent/`;
+//! `config/redis/README.md` documents both.
+
+pub mod backend;
+pub mod envelope;
+pub mod keys;
+pub mod schema;
+pub mod store;
+pub mod task_state;
+
+mod task;
+mod wire;
+
+pub use backend::{RedisBackend, TaskBackend};
+pub use task::{Task, TaskStatus};
+pub use wire::{AgentRequest, AgentResponse, MapSpec, OnFailure, ResultSignature};
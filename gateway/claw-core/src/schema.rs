@@ -0,0 +1,106 @@
+//! Task record schema versions.
+//!
+//! Task records carry the version of their layout in `schema_version`,
+//! [`CURRENT_VERSION`] for records written now; records from before
+//! versioning have none and count as version 0. Readers go through
+//! [`decode_task`], which brings older records up to date in memory by
+//! running the [`MIGRATIONS`] from their version on, so a field that moved
+//! or changed meaning does not break `GET /task/:id` on tasks stored by an
+//! earlier gateway. A record is written back in the current layout the next
+//! time its status changes, and `claw-admin migrate` upgrades the rest in
+//! place. Records from a newer gateway are read as they are.
+//!
+//! A layout change adds a migration taking version `n` records to `n + 1`
+//! and bumps [`CURRENT_VERSION`]. Migrations only fill in and rename
+//! fields, leaving records that already have the new layout alone, since
+//! agents may write either until they are upgraded.
+
+use serde_json::Value;
+
+use crate::envelope;
+
+/// Layout version of the task records this gateway writes
+pub const CURRENT_VERSION: u64 = 1;
+
+/// Brings a record of one version to the next
+type Migration = fn(&mut Value);
+
+/// Migration from each version to the next, by the version it upgrades
+pub const MIGRATIONS: [(u64, Migration); 1] = [(0, failures_and_deadlines)];
+
+/// Version 0 to 1: failed records name their error, and every queued record
+/// has a deadline
+fn failures_and_deadlines(task: &mut Value) {
+    // Agents used to leave the output of a failed task on the record only
+    let failed = task["status"] == "failed";
+    if failed && task["error"].is_null() {
+        let message = ["error", "message"]
+            .iter()
+            .find_map(|field| task["result"][*field].as_str())
+            .map(str::to_string);
+        if let Some(message) = message {
+            task["error"] = message.into();
+        }
+    }
+
+    // Deadlines used to be worked out from the timeout when checked
+    if task["deadline"].is_null() {
+        let created_at = task["created_at"]
+            .as_str()
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+        if let (Some(created_at), Some(secs)) = (created_at, task["timeout_secs"].as_i64()) {
+            let deadline = created_at + chrono::Duration::seconds(secs);
+            task["deadline"] = deadline.to_rfc3339().into();
+        }
+    }
+}
+
+/// The layout version of `task`
+pub fn version(task: &Value) -> u64 {
+    task["schema_version"].as_u64().unwrap_or(0)
+}
+
+/// Bring `task` up to [`CURRENT_VERSION`], returning whether it was older
+pub fn upgrade(task: &mut Value) -> bool {
+    let from = version(task);
+    if from >= CURRENT_VERSION || !task.is_object() {
+        return false;
+    }
+    for (_, migrate) in MIGRATIONS.iter().filter(|(v, _)| *v >= from) {
+        migrate(task);
+    }
+    task["schema_version"] = CURRENT_VERSION.into();
+    true
+}
+
+/// Parse a stored task record, upgraded to the current layout
+pub fn decode_task(raw: &str) -> serde_json::Result<Value> {
+    let mut task = envelope::decode(raw)?;
+    upgrade(&mut task);
+    Ok(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn upgrades_unversioned_records() {
+        let mut task = json!({
+            "status": "failed",
+            "result": {"error": "model unavailable"},
+            "timeout_secs": 60,
+            "created_at": "2024-05-01T10:00:00+00:00",
+        });
+        assert!(upgrade(&mut task));
+        assert_eq!(task["schema_version"], CURRENT_VERSION);
+        assert_eq!(task["error"], "model unavailable");
+        assert_eq!(task["deadline"], "2024-05-01T10:01:00+00:00");
+        assert!(!upgrade(&mut task));
+
+        let mut newer = json!({"status": "pending", "schema_version": CURRENT_VERSION + 1});
+        assert!(!upgrade(&mut newer));
+        assert!(newer.get("deadline").is_none());
+    }
+}
@@ -0,0 +1,123 @@
+//! Reading and writing task records in Redis.
+//!
+//! Records go through their [envelope](crate::envelope), so the keys set
+//! with [`envelope::set_keys`](crate::envelope::set_keys) decrypt and encrypt
+//! them as the gateway does, and task records come back in the current
+//! [schema](crate::schema). Status changes go through
+//! [`task_state`](crate::task_state) instead.
+
+use redis::aio::ConnectionLike;
+use serde_json::Value;
+
+use crate::{envelope, keys, schema, Task};
+
+#[derive(Debug)]
+pub enum StoreError {
+    Redis(redis::RedisError),
+    Decode(serde_json::Error),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Redis(e) => write!(f, "{}", e),
+            StoreError::Decode(e) => write!(f, "unreadable record: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<redis::RedisError> for StoreError {
+    fn from(e: redis::RedisError) -> Self {
+        StoreError::Redis(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Decode(e)
+    }
+}
+
+async fn get<C>(conn: &mut C, key: &str) -> Result<Option<Value>, StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    let raw: Option<String> = redis::cmd("GET").arg(key).query_async(conn).await?;
+    Ok(raw.map(|raw| envelope::decode(&raw)).transpose()?)
+}
+
+async fn set<C>(conn: &mut C, key: &str, value: &Value) -> Result<(), StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    redis::cmd("SET")
+        .arg(key)
+        .arg(envelope::encode(value)?)
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+/// The record of `task_id` as JSON, in the current schema
+pub async fn get_record<C>(conn: &mut C, task_id: &str) -> Result<Option<Value>, StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    let raw: Option<String> = redis::cmd("GET")
+        .arg(keys::task_key(task_id))
+        .query_async(conn)
+        .await?;
+    Ok(raw.map(|raw| schema::decode_task(&raw)).transpose()?)
+}
+
+/// The record of `task_id`
+pub async fn get_task<C>(conn: &mut C, task_id: &str) -> Result<Option<Task>, StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    let record = get_record(conn, task_id).await?;
+    Ok(record.map(Task::from_record).transpose()?)
+}
+
+/// Store the record of `task_id`, replacing any there; status changes go
+/// through [`task_state`](crate::task_state)
+pub async fn set_task<C>(conn: &mut C, task_id: &str, task: &Task) -> Result<(), StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    set(conn, &keys::task_key(task_id), &task.to_record()?).await
+}
+
+/// The result of a completed task
+pub async fn get_result<C>(conn: &mut C, task_id: &str) -> Result<Option<Value>, StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    get(conn, &keys::result_key(task_id)).await
+}
+
+/// The error report of a failed task, `{"error", "code"}`
+pub async fn get_error<C>(conn: &mut C, task_id: &str) -> Result<Option<Value>, StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    get(conn, &keys::error_key(task_id)).await
+}
+
+/// Store the result of `task_id`
+pub async fn set_result<C>(conn: &mut C, task_id: &str, result: &Value) -> Result<(), StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    set(conn, &keys::result_key(task_id), result).await
+}
+
+/// Store the error report of `task_id`
+pub async fn set_error<C>(conn: &mut C, task_id: &str, report: &Value) -> Result<(), StoreError>
+where
+    C: ConnectionLike + Send,
+{
+    set(conn, &keys::error_key(task_id), report).await
+}
@@ -0,0 +1,194 @@
+//! Typed task records.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::{schema, task_state};
+
+/// Status of a task, as [`task_state::TRANSITIONS`] lets it move
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Held back until its dependencies settle
+    Waiting,
+    #[default]
+    Pending,
+    Processing,
+    /// Settled by the peer gateway it was forwarded to
+    Forwarded,
+    Completed,
+    Failed,
+    TimedOut,
+    /// Its agent died while processing it
+    Orphaned,
+    Deleted,
+}
+
+impl TaskStatus {
+    pub const ALL: [TaskStatus; 9] = [
+        TaskStatus::Waiting,
+        TaskStatus::Pending,
+        TaskStatus::Processing,
+        TaskStatus::Forwarded,
+        TaskStatus::Completed,
+        TaskStatus::Failed,
+        TaskStatus::TimedOut,
+        TaskStatus::Orphaned,
+        TaskStatus::Deleted,
+    ];
+
+    /// The status as stored in task records
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskStatus::Waiting => "waiting",
+            TaskStatus::Pending => "pending",
+            TaskStatus::Processing => "processing",
+            TaskStatus::Forwarded => "forwarded",
+            TaskStatus::Completed => "completed",
+            TaskStatus::Failed => "failed",
+            TaskStatus::TimedOut => "timed_out",
+            TaskStatus::Orphaned => "orphaned",
+            TaskStatus::Deleted => "deleted",
+        }
+    }
+
+    /// Whether a task may move from this status to `to`
+    pub fn can_become(self, to: TaskStatus) -> bool {
+        task_state::is_allowed(self.as_str(), to.as_str())
+    }
+
+    /// Whether the task has ended, with or without a result
+    pub fn is_settled(self) -> bool {
+        !matches!(
+            self,
+            TaskStatus::Waiting
+                | TaskStatus::Pending
+                | TaskStatus::Processing
+                | TaskStatus::Forwarded
+        )
+    }
+}
+
+impl std::fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for TaskStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TaskStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown task status {:?}", s))
+    }
+}
+
+/// The record under `task:{id}`.
+///
+/// Fields the gateway sets on some tasks only, such as fan-out or pipeline
+/// bookkeeping, are kept in `extra`, so a record read and written back
+/// through this type loses nothing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Task {
+    pub status: TaskStatus,
+    #[serde(default)]
+    pub input: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<Value>,
+    #[serde(default)]
+    pub priority: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capability: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Agent processing or last processing the task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Layout version, see [`schema`]
+    #[serde(default = "current_version")]
+    pub schema_version: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn current_version() -> u64 {
+    schema::CURRENT_VERSION
+}
+
+impl Task {
+    /// A pending task for `input`
+    pub fn new(input: Value) -> Self {
+        Task {
+            input,
+            created_at: Some(Utc::now()),
+            schema_version: schema::CURRENT_VERSION,
+            ..Task::default()
+        }
+    }
+
+    /// Read a record already brought up to the current [`schema`]
+    pub fn from_record(record: Value) -> serde_json::Result<Self> {
+        serde_json::from_value(record)
+    }
+
+    /// The record to store
+    pub fn to_record(&self) -> serde_json::Result<Value> {
+        serde_json::to_value(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn statuses_match_the_transition_table() {
+        let listed: Vec<&str> = task_state::TRANSITIONS.iter().map(|(s, _)| *s).collect();
+        let ours: Vec<&str> = TaskStatus::ALL.iter().map(|s| s.as_str()).collect();
+        assert_eq!(listed, ours);
+        for status in TaskStatus::ALL {
+            assert_eq!(status.as_str().parse(), Ok(status));
+            assert_eq!(json!(status), json!(status.as_str()));
+        }
+        assert!(TaskStatus::Processing.can_become(TaskStatus::Completed));
+        assert!(!TaskStatus::Completed.can_become(TaskStatus::Processing));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let record = json!({
+            "status": "timed_out",
+            "input": {"text": "hi"},
+            "priority": false,
+            "labels": {"team": "ops"},
+            "created_at": "2024-05-01T10:00:00Z",
+            "schema_version": 1,
+            "pipeline": {"id": "p1", "stage": 0},
+        });
+        let task = Task::from_record(record.clone()).unwrap();
+        assert_eq!(task.status, TaskStatus::TimedOut);
+        assert_eq!(task.labels["team"], "ops");
+        assert_eq!(task.extra["pipeline"]["stage"], 0);
+        assert_eq!(task.to_record().unwrap(), record);
+    }
+}
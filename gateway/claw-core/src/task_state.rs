@@ -0,0 +1,288 @@
+//! Task status transitions.
+//!
+//! The gateway and agents both rewrite the JSON record under `task:{id}`, so
+//! a plain read-modify-write lets one writer clobber another: a watchdog
+//! timeout landing on top of the agent's `completed`, say. Status changes go
+//! through [`transition`] instead, which checks the move against
+//! [`TRANSITIONS`] on the record as read and writes the new record with a
+//! compare-and-set script that only succeeds if nobody changed the record in
+//! the meantime, rereading and retrying when someone did. Moves the table does
+//! not list, such as `completed` → `processing`, are refused.
+//!
+//! Agents follow the same table and script; keep `agent/agent/storage.py` in
+//! step.
+
+use redis::aio::ConnectionLike;
+use serde_json::Value;
+
+use crate::{envelope, keys, schema};
+
+/// The statuses each status may move to; `deleted` is the end of the line
+pub const TRANSITIONS: [(&str, &[&str]); 9] = [
+    // Held back until its dependencies settle
+    ("waiting", &["pending", "failed", "deleted"]),
+    (
+        "pending",
+        &[
+            "pending",
+            "processing",
+            "completed",
+            "failed",
+            "timed_out",
+            "deleted",
+        ],
+    ),
+    // Processing and orphaned tasks may start over when redelivered after
+    // their agent died
+    (
+        "processing",
+        &[
+            "pending",
+            "processing",
+            "completed",
+            "failed",
+            "timed_out",
+            "orphaned",
+            "deleted",
+        ],
+    ),
+    // Settled by the peer gateway it was forwarded to
+    ("forwarded", &["completed", "failed", "deleted"]),
+    ("completed", &["deleted"]),
+    ("failed", &["pending", "deleted"]),
+    ("timed_out", &["pending", "deleted"]),
+    ("orphaned", &["pending", "processing", "deleted"]),
+    ("deleted", &[]),
+];
+
+/// Attempts at a transition before giving up on a record that keeps
+/// changing underneath it
+const MAX_ATTEMPTS: usize = 5;
+
+/// Replace `KEYS[1]` with `ARGV[2]` only if it still holds `ARGV[1]`
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  redis.call('SET', KEYS[1], ARGV[2])
+  return 1
+end
+return 0
+"#;
+
+/// Whether a task may move from `from` to `to`
+pub fn is_allowed(from: &str, to: &str) -> bool {
+    TRANSITIONS
+        .iter()
+        .find(|(status, _)| *status == from)
+        .is_some_and(|(_, next)| next.contains(&to))
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    NotFound,
+    /// The table does not allow the move
+    Illegal {
+        from: String,
+        to: String,
+    },
+    /// The record changed on every attempt
+    Contended,
+    Redis(redis::RedisError),
+    Decode(serde_json::Error),
+}
+
+impl std::fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionError::NotFound => write!(f, "task not found"),
+            TransitionError::Illegal { from, to } => {
+                write!(f, "a {} task cannot become {}", from, to)
+            }
+            TransitionError::Contended => write!(f, "task record kept changing"),
+            TransitionError::Redis(e) => write!(f, "{}", e),
+            TransitionError::Decode(e) => write!(f, "unreadable task record: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl From<redis::RedisError> for TransitionError {
+    fn from(e: redis::RedisError) -> Self {
+        TransitionError::Redis(e)
+    }
+}
+
+impl From<serde_json::Error> for TransitionError {
+    fn from(e: serde_json::Error) -> Self {
+        TransitionError::Decode(e)
+    }
+}
+
+/// Move `task_id` to status `to`, letting `update` edit the record first.
+///
+/// `update` sees the record as currently stored, status included, and may
+/// decline the move by returning `false`, giving `Ok(None)`, before the
+/// table is consulted; it runs again on every retry. Returns the record as
+/// written.
+pub async fn transition<C, F>(
+    conn: &mut C,
+    task_id: &str,
+    to: &str,
+    mut update: F,
+) -> Result<Option<Value>, TransitionError>
+where
+    C: ConnectionLike + Send,
+    F: FnMut(&mut Value) -> bool,
+{
+    let edit = |task: &mut Value| {
+        let from = task["status"].as_str().unwrap_or("unknown").to_string();
+        if !update(task) {
+            return Ok(false);
+        }
+        if !is_allowed(&from, to) {
+            return Err(TransitionError::Illegal {
+                from,
+                to: to.to_string(),
+            });
+        }
+        task["status"] = to.into();
+        Ok(true)
+    };
+    compare_and_set(conn, task_id, edit).await
+}
+
+/// Rewrite `task_id` as `update` edits it, keeping its status; `update` may
+/// decline by returning `false`, giving `Ok(None)`. Returns the record as
+/// written, in the current [schema](crate::schema) and envelope.
+pub async fn rewrite<C, F>(
+    conn: &mut C,
+    task_id: &str,
+    mut update: F,
+) -> Result<Option<Value>, TransitionError>
+where
+    C: ConnectionLike + Send,
+    F: FnMut(&mut Value) -> bool,
+{
+    compare_and_set(conn, task_id, |task| Ok(update(task))).await
+}
+
+/// Write the record `edit` makes of `task_id`, rereading and retrying when
+/// the record changes underneath it
+async fn compare_and_set<C, F>(
+    conn: &mut C,
+    task_id: &str,
+    mut edit: F,
+) -> Result<Option<Value>, TransitionError>
+where
+    C: ConnectionLike + Send,
+    F: FnMut(&mut Value) -> Result<bool, TransitionError>,
+{
+    let key = keys::task_key(task_id);
+    let script = redis::Script::new(COMPARE_AND_SET_SCRIPT);
+    for _ in 0..MAX_ATTEMPTS {
+        let stored: Option<String> = redis::cmd("GET").arg(&key).query_async(conn).await?;
+        let Some(stored) = stored else {
+            return Err(TransitionError::NotFound);
+        };
+        let mut task = schema::decode_task(&stored)?;
+        if !edit(&mut task)? {
+            return Ok(None);
+        }
+
+        let swapped: bool = script
+            .key(&key)
+            .arg(&stored)
+            .arg(envelope::encode(&task)?)
+            .invoke_async(conn)
+            .await?;
+        if swapped {
+            return Ok(Some(task));
+        }
+    }
+    Err(TransitionError::Contended)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    type Table = BTreeMap<String, BTreeSet<String>>;
+
+    fn rust_table() -> Table {
+        TRANSITIONS
+            .iter()
+            .map(|(from, to)| {
+                let to = to.iter().map(|s| s.to_string()).collect();
+                (from.to_string(), to)
+            })
+            .collect()
+    }
+
+    /// The `TRANSITIONS` dict of the agent, read from its source
+    fn python_table() -> Table {
+        let source = include_str!("../../../agent/agent/storage.py");
+        let start = source.find("TRANSITIONS = {").expect("no TRANSITIONS dict");
+        let body = &source[start + "TRANSITIONS = {".len()..];
+        let body = &body[..body.find("\n}").expect("unterminated TRANSITIONS dict")];
+        let quoted = |s: &str| -> Vec<String> {
+            s.split('"')
+                .skip(1)
+                .step_by(2)
+                .map(str::to_string)
+                .collect()
+        };
+
+        let mut table = Table::new();
+        let mut rest = body;
+        while let Some(colon) = rest.find("\":") {
+            let from = quoted(&rest[..colon + 1]).pop().expect("unquoted status");
+            rest = &rest[colon + 2..];
+            let end = match rest.find("},") {
+                Some(end) if !rest.trim_start().starts_with("set()") => end,
+                _ => rest.find("set()").expect("unreadable entry") + "set()".len(),
+            };
+            table.insert(from, quoted(&rest[..end]).into_iter().collect());
+            rest = &rest[end..];
+        }
+        table
+    }
+
+    #[test]
+    fn allows_the_listed_moves() {
+        assert!(is_allowed("waiting", "pending"));
+        assert!(is_allowed("pending", "processing"));
+        assert!(is_allowed("processing", "completed"));
+        assert!(is_allowed("failed", "pending"));
+        assert!(is_allowed("orphaned", "processing"));
+        assert!(is_allowed("completed", "deleted"));
+    }
+
+    #[test]
+    fn refuses_other_moves() {
+        assert!(!is_allowed("completed", "processing"));
+        assert!(!is_allowed("completed", "pending"));
+        assert!(!is_allowed("waiting", "processing"));
+        assert!(!is_allowed("forwarded", "pending"));
+        for to in ["pending", "processing", "completed", "deleted"] {
+            assert!(!is_allowed("deleted", to));
+        }
+        assert!(!is_allowed("unknown", "pending"));
+        assert!(!is_allowed("pending", "unknown"));
+    }
+
+    #[test]
+    fn every_target_is_a_status() {
+        let table = rust_table();
+        for (from, to) in &table {
+            for status in to {
+                assert!(table.contains_key(status), "{} -> unknown {}", from, status);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_the_agent_table() {
+        assert_eq!(python_table(), rust_table());
+    }
+}
@@ -0,0 +1,67 @@
+//! Bodies of the gateway's task API.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// A task submission, the body of `POST /task`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AgentRequest {
+    pub task_id: String,
+    pub input: Value,
+    pub config: Option<Value>,
+    /// Only agents advertising this capability may run the task
+    pub capability: Option<String>,
+    /// Seconds from submission before the task times out
+    pub timeout_seconds: Option<u64>,
+    /// Key/value tags the task can be found by in `GET /tasks?label=key:value`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Tasks that must complete before this one is queued
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Run once per element of an array `input` and aggregate the results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub map: Option<MapSpec>,
+    /// Task this one reruns, set by `POST /task/:id/rerun`
+    #[serde(skip)]
+    pub parent_task_id: Option<String>,
+}
+
+/// Where a task stands, as the task API answers
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AgentResponse {
+    pub task_id: String,
+    pub status: String,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    /// The gateway's signature over `result`, when results are signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ResultSignature>,
+}
+
+/// What a failed child does to its parent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    /// Fail the parent as soon as one child fails
+    #[default]
+    Fail,
+    /// Complete the parent with the children that succeeded
+    Partial,
+}
+
+/// The `map` field of a submission
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapSpec {
+    pub on_failure: OnFailure,
+}
+
+/// Signature attached to a task response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultSignature {
+    pub key_id: String,
+    pub algorithm: String,
+    pub value: String,
+}
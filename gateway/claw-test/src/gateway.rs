@@ -0,0 +1,278 @@
+//! Test double of the gateway's task API.
+//!
+//! Answers as the gateway does, with the [`claw_core`] bodies, but without
+//! authentication, validation or any of the gateway's middleware: it is
+//! for testing what is on the other end of the API, not the gateway. Its
+//! handlers are its own, not the gateway's; the gateway's are tested in its
+//! crate against [`FakeRedis`](crate::FakeRedis). Submissions answer
+//! `submitted` and reused ids `409` (`duplicate_task`), as there.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use claw_core::{keys, AgentRequest, AgentResponse, Task, TaskBackend, TaskStatus};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+
+use crate::MemoryBackend;
+
+/// A test gateway serving on a local port
+pub struct TestGateway {
+    /// Base URL, e.g. `http://127.0.0.1:40123`
+    pub url: String,
+    pub addr: SocketAddr,
+    /// Storage behind the API, shared with the server
+    pub backend: MemoryBackend,
+    server: JoinHandle<()>,
+}
+
+impl Drop for TestGateway {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Serve the task API over a fresh [`MemoryBackend`]
+pub async fn spawn_test_gateway() -> std::io::Result<TestGateway> {
+    let backend = MemoryBackend::new();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let app = Router::new()
+        .route(
+            "/health",
+            get(|| async { Json(json!({"status": "healthy"})) }),
+        )
+        .route("/task", post(submit))
+        .route("/task/:task_id", get(task))
+        .route("/internal/work/claim", post(claim))
+        .route("/internal/work/:task_id/complete", post(complete))
+        .with_state(backend.clone());
+    let server = tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+    Ok(TestGateway {
+        url: format!("http://{}", addr),
+        addr,
+        backend,
+        server,
+    })
+}
+
+fn error(status: StatusCode, code: &str, message: impl std::fmt::Display) -> Response {
+    let body = json!({"code": code, "message": message.to_string()});
+    (status, Json(body)).into_response()
+}
+
+fn response(task_id: String, task: &Task, backend: &MemoryBackend) -> AgentResponse {
+    let error = match task.status {
+        TaskStatus::Failed => backend
+            .error(&task_id)
+            .and_then(|e| e["error"].as_str().map(str::to_string))
+            .or_else(|| task.error.clone()),
+        _ => None,
+    };
+    AgentResponse {
+        result: backend.result(&task_id),
+        status: task.status.to_string(),
+        error,
+        signature: None,
+        task_id,
+    }
+}
+
+async fn submit(
+    State(mut backend): State<MemoryBackend>,
+    Json(req): Json<AgentRequest>,
+) -> Response {
+    if backend.task(&req.task_id).is_some() {
+        return error(
+            StatusCode::CONFLICT,
+            "duplicate_task",
+            format!("Task {} already exists", req.task_id),
+        );
+    }
+    let mut task = Task::new(req.input);
+    task.config = req.config;
+    task.capability = req.capability;
+    task.timeout_secs = req.timeout_seconds;
+    task.labels = req.labels;
+
+    let queue = keys::queue_for(task.capability.as_deref(), false);
+    let stored = async {
+        backend.put_task(&req.task_id, &task).await?;
+        backend.push(&queue, &req.task_id).await
+    };
+    if let Err(e) = stored.await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", e);
+    }
+    let mut submitted = response(req.task_id, &task, &backend);
+    submitted.status = "submitted".to_string();
+    Json(submitted).into_response()
+}
+
+async fn task(State(backend): State<MemoryBackend>, Path(task_id): Path<String>) -> Response {
+    match backend.task(&task_id) {
+        Some(task) => Json(response(task_id, &task, &backend)).into_response(),
+        None => error(StatusCode::NOT_FOUND, "not_found", "Task not found"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClaimRequest {
+    agent_id: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+/// Hand out the next task as `POST /internal/work/claim` does; leases never
+/// lapse here
+async fn claim(
+    State(mut backend): State<MemoryBackend>,
+    Json(req): Json<ClaimRequest>,
+) -> Response {
+    let queues = keys::claim_queues(req.agent_id.as_deref(), &req.capabilities);
+    while let Ok(Some(task_id)) = backend.pop(&queues).await {
+        let started = backend
+            .transition(&task_id, TaskStatus::Processing, |task| {
+                task.agent_id = req.agent_id.clone();
+                task.started_at = Some(chrono::Utc::now());
+            })
+            .await;
+        // Settled or deleted while it was queued
+        let Ok(task) = started else { continue };
+        let task = task.to_record().unwrap_or_default();
+        return Json(json!({
+            "task_id": task_id,
+            "lease_id": uuid::Uuid::new_v4().to_string(),
+            "lease_expires_at": null,
+            "task": task,
+        }))
+        .into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct CompleteRequest {
+    result: Option<Value>,
+    error: Option<String>,
+    code: Option<String>,
+}
+
+async fn complete(
+    State(mut backend): State<MemoryBackend>,
+    Path(task_id): Path<String>,
+    Json(req): Json<CompleteRequest>,
+) -> Response {
+    let (to, outcome) = match (req.result, req.error) {
+        (Some(result), None) => (TaskStatus::Completed, Ok(result)),
+        (None, Some(error)) => (TaskStatus::Failed, Err(error)),
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_outcome",
+                "Give either result or error",
+            )
+        }
+    };
+    let settled = backend
+        .transition(&task_id, to, |task| {
+            task.completed_at = Some(chrono::Utc::now());
+            match &outcome {
+                Ok(result) => task.result = Some(result.clone()),
+                Err(error) => task.error = Some(error.clone()),
+            }
+        })
+        .await;
+    if let Err(e) = settled {
+        return error(StatusCode::CONFLICT, "invalid_transition", e);
+    }
+
+    let stored = match &outcome {
+        Ok(result) => backend.set_result(&task_id, result).await,
+        Err(error) => {
+            let mut report = json!({"error": error});
+            if let Some(code) = req.code {
+                report["code"] = json!(code);
+            }
+            backend.set_error(&task_id, &report).await
+        }
+    };
+    if let Err(e) = stored {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", e);
+    }
+    Json(json!({"task_id": task_id, "status": to})).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn agents_take_and_settle_submitted_tasks() {
+        let gateway = spawn_test_gateway().await.unwrap();
+        let http = reqwest::Client::new();
+
+        let submitted: AgentResponse = http
+            .post(format!("{}/task", gateway.url))
+            .json(&json!({"task_id": "t1", "input": "2+2", "config": null}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(submitted.status, "submitted");
+        assert_eq!(gateway.backend.queued(keys::AGENT_QUEUE), ["t1"]);
+        let resubmitted = http
+            .post(format!("{}/task", gateway.url))
+            .json(&json!({"task_id": "t1", "input": "2+3", "config": null}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resubmitted.status(), reqwest::StatusCode::CONFLICT);
+        assert_eq!(gateway.backend.queued(keys::AGENT_QUEUE), ["t1"]);
+
+        let claim: Value = http
+            .post(format!("{}/internal/work/claim", gateway.url))
+            .json(&json!({"agent_id": "a1"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(claim["task"]["status"], "processing");
+        let completed = http
+            .post(format!("{}/internal/work/t1/complete", gateway.url))
+            .json(&json!({"lease_id": claim["lease_id"], "result": 4}))
+            .send()
+            .await
+            .unwrap();
+        assert!(completed.status().is_success());
+
+        let done: AgentResponse = http
+            .get(format!("{}/task/t1", gateway.url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(done.status, "completed");
+        assert_eq!(done.result, Some(json!(4)));
+        let none = http
+            .post(format!("{}/internal/work/claim", gateway.url))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(none.status().as_u16(), 204);
+    }
+}
@@ -0,0 +1,28 @@
+//! Test harness for the secure gateway and its agents.
+//!
+//! Tests of agents, adaptors, client integrations and the gateway's own
+//! Redis helpers get by without a live Redis or Telegram bot token:
+//!
+//! - [`MemoryBackend`] is a [`TaskBackend`](claw_core::TaskBackend) holding
+//!   queues and records in memory, with the gateway's transition rules.
+//! - [`FakeTelegram`] serves the Bot API calls the gateway makes on a local
+//!   port, answering `getUpdates` with messages a test sends and recording
+//!   the replies; point `telegram.api_base` at it.
+//! - [`spawn_test_gateway`] serves a stand-in for the task API agents and
+//!   clients use, `POST /task`, `GET /task/:id` and the `/internal/work`
+//!   claims, over a [`MemoryBackend`] the test can inspect. Its handlers are
+//!   written for the double, not the gateway's, so it tests the client side
+//!   of the API only.
+//! - [`FakeRedis`] stands in for a Redis connection in code generic over
+//!   `redis::aio::ConnectionLike`, for the handful of string commands the
+//!   gateway's helpers send.
+
+mod gateway;
+mod memory;
+mod redis;
+mod telegram;
+
+pub use gateway::{spawn_test_gateway, TestGateway};
+pub use memory::MemoryBackend;
+pub use redis::FakeRedis;
+pub use telegram::FakeTelegram;
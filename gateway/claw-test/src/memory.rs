@@ -0,0 +1,171 @@
+//! In-memory task storage.
+
+use claw_core::store::StoreError;
+use claw_core::task_state::TransitionError;
+use claw_core::{Task, TaskBackend, TaskStatus};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Default)]
+struct State {
+    /// Task ids by queue, the next to take at the back as in Redis
+    queues: HashMap<String, VecDeque<String>>,
+    tasks: HashMap<String, Task>,
+    results: HashMap<String, Value>,
+    errors: HashMap<String, Value>,
+}
+
+/// [`TaskBackend`] keeping everything in memory; clones share their contents
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Task ids on `queue`, the next to take first
+    pub fn queued(&self, queue: &str) -> Vec<String> {
+        let state = self.state();
+        let queued = state.queues.get(queue).into_iter().flatten().rev();
+        queued.cloned().collect()
+    }
+
+    pub fn task(&self, task_id: &str) -> Option<Task> {
+        self.state().tasks.get(task_id).cloned()
+    }
+
+    pub fn result(&self, task_id: &str) -> Option<Value> {
+        self.state().results.get(task_id).cloned()
+    }
+
+    pub fn error(&self, task_id: &str) -> Option<Value> {
+        self.state().errors.get(task_id).cloned()
+    }
+}
+
+impl TaskBackend for MemoryBackend {
+    async fn pop(&mut self, queues: &[String]) -> Result<Option<String>, StoreError> {
+        let mut state = self.state();
+        Ok(queues
+            .iter()
+            .find_map(|queue| state.queues.get_mut(queue)?.pop_back()))
+    }
+
+    async fn push(&mut self, queue: &str, task_id: &str) -> Result<(), StoreError> {
+        let mut state = self.state();
+        let queue = state.queues.entry(queue.to_string()).or_default();
+        queue.push_front(task_id.to_string());
+        Ok(())
+    }
+
+    async fn get_task(&mut self, task_id: &str) -> Result<Option<Task>, StoreError> {
+        Ok(self.task(task_id))
+    }
+
+    async fn put_task(&mut self, task_id: &str, task: &Task) -> Result<(), StoreError> {
+        self.state().tasks.insert(task_id.to_string(), task.clone());
+        Ok(())
+    }
+
+    async fn transition<F>(
+        &mut self,
+        task_id: &str,
+        to: TaskStatus,
+        mut update: F,
+    ) -> Result<Task, TransitionError>
+    where
+        F: FnMut(&mut Task) + Send,
+    {
+        let mut state = self.state();
+        let task = state
+            .tasks
+            .get_mut(task_id)
+            .ok_or(TransitionError::NotFound)?;
+        let mut edited = task.clone();
+        update(&mut edited);
+        if !task.status.can_become(to) {
+            return Err(TransitionError::Illegal {
+                from: task.status.to_string(),
+                to: to.to_string(),
+            });
+        }
+        edited.status = to;
+        *task = edited.clone();
+        Ok(edited)
+    }
+
+    async fn get_result(&mut self, task_id: &str) -> Result<Option<Value>, StoreError> {
+        Ok(self.result(task_id))
+    }
+
+    async fn set_result(&mut self, task_id: &str, result: &Value) -> Result<(), StoreError> {
+        self.state()
+            .results
+            .insert(task_id.to_string(), result.clone());
+        Ok(())
+    }
+
+    async fn get_error(&mut self, task_id: &str) -> Result<Option<Value>, StoreError> {
+        Ok(self.error(task_id))
+    }
+
+    async fn set_error(&mut self, task_id: &str, report: &Value) -> Result<(), StoreError> {
+        self.state()
+            .errors
+            .insert(task_id.to_string(), report.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_core::keys;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn follows_the_transition_table() {
+        let mut backend = MemoryBackend::new();
+        backend
+            .put_task("t1", &Task::new(json!("hi")))
+            .await
+            .unwrap();
+        backend.push(keys::AGENT_QUEUE, "t1").await.unwrap();
+        backend.push(keys::AGENT_QUEUE, "t2").await.unwrap();
+
+        let queues = keys::claim_queues(None, &[]);
+        assert_eq!(backend.pop(&queues).await.unwrap().as_deref(), Some("t1"));
+        let task = backend
+            .transition("t1", TaskStatus::Processing, |task| {
+                task.agent_id = Some("a1".to_string())
+            })
+            .await
+            .unwrap();
+        assert_eq!(task.agent_id.as_deref(), Some("a1"));
+        backend
+            .transition("t1", TaskStatus::Completed, |_| {})
+            .await
+            .unwrap();
+
+        let refused = backend.transition("t1", TaskStatus::Processing, |_| {});
+        assert!(matches!(
+            refused.await,
+            Err(TransitionError::Illegal { .. })
+        ));
+        assert!(matches!(
+            backend
+                .transition("t2", TaskStatus::Processing, |_| {})
+                .await,
+            Err(TransitionError::NotFound)
+        ));
+        assert_eq!(backend.queued(keys::AGENT_QUEUE), ["t2"]);
+    }
+}
@@ -0,0 +1,366 @@
+//! In-memory stand-in for a Redis connection.
+
+use redis::aio::ConnectionLike;
+use redis::{Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Default)]
+struct Entry {
+    value: Vec<u8>,
+    /// Seconds to live, as last set
+    ttl_secs: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    strings: HashMap<String, Entry>,
+    hashes: HashMap<String, HashMap<String, Vec<u8>>>,
+    /// Writes to each key so far, for `WATCH`
+    writes: HashMap<String, u64>,
+}
+
+impl State {
+    fn written(&mut self, key: &str) {
+        *self.writes.entry(key.to_string()).or_default() += 1;
+    }
+}
+
+/// [`ConnectionLike`] over keys in memory; clones share their contents but
+/// watch keys apart, like connections of their own.
+///
+/// It understands `GET`, `GETDEL`, `MGET`, `SET` (with `NX`, `XX` and `EX`), `SETEX`,
+/// `DEL`, `EXISTS`, `EXPIRE`, `HGET`, `HMGET`, `HSET`, `HDEL`, `HINCRBY`,
+/// `HGETALL`, `WATCH` and `UNWATCH`, sent alone or in pipelines, atomic or not. An atomic
+/// pipeline is aborted if a key watched before it was written meanwhile.
+/// Keys never actually expire; [`FakeRedis::ttl`] tells what they were
+/// given.
+#[derive(Debug, Clone, Default)]
+pub struct FakeRedis {
+    state: Arc<Mutex<State>>,
+    /// Watched keys and their write counts when watched
+    watched: HashMap<String, u64>,
+}
+
+impl FakeRedis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Store `value` under `key` without a TTL
+    pub fn set(&self, key: &str, value: &str) {
+        let entry = Entry {
+            value: value.as_bytes().to_vec(),
+            ttl_secs: None,
+        };
+        let mut state = self.state();
+        state.strings.insert(key.to_string(), entry);
+        state.written(key);
+    }
+
+    /// The value under `key`
+    pub fn get(&self, key: &str) -> Option<String> {
+        let state = self.state();
+        let entry = state.strings.get(key)?;
+        Some(String::from_utf8_lossy(&entry.value).into_owned())
+    }
+
+    /// The value of `field` in the hash under `key`
+    pub fn hget(&self, key: &str, field: &str) -> Option<String> {
+        let state = self.state();
+        let value = state.hashes.get(key)?.get(field)?;
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+
+    /// The TTL `key` was last given, if it exists and has one
+    pub fn ttl(&self, key: &str) -> Option<i64> {
+        self.state().strings.get(key)?.ttl_secs
+    }
+
+    fn execute(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        let args: Vec<Vec<u8>> = cmd
+            .args_iter()
+            .map(|arg| match arg {
+                Arg::Simple(bytes) => bytes.to_vec(),
+                Arg::Cursor => Vec::new(),
+            })
+            .collect();
+        let text = |i: usize| String::from_utf8_lossy(&args[i]).into_owned();
+        let name = text(0).to_ascii_uppercase();
+        let state = &mut *self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let reply = match name.as_str() {
+            "GET" => state
+                .strings
+                .get(&text(1))
+                .map_or(Value::Nil, |e| Value::Data(e.value.clone())),
+            "GETDEL" => match state.strings.remove(&text(1)) {
+                Some(entry) => {
+                    state.written(&text(1));
+                    Value::Data(entry.value)
+                }
+                None => Value::Nil,
+            },
+            "MGET" => Value::Bulk(
+                (1..args.len())
+                    .map(|i| {
+                        state
+                            .strings
+                            .get(&text(i))
+                            .map_or(Value::Nil, |e| Value::Data(e.value.clone()))
+                    })
+                    .collect(),
+            ),
+            "SET" => {
+                let key = text(1);
+                let mut ttl_secs = None;
+                let mut condition = None;
+                let mut i = 3;
+                while i < args.len() {
+                    match text(i).to_ascii_uppercase().as_str() {
+                        "EX" => {
+                            ttl_secs = text(i + 1).parse().ok();
+                            i += 1;
+                        }
+                        flag @ ("NX" | "XX") => condition = Some(flag.to_string()),
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                let exists = state.strings.contains_key(&key);
+                match condition.as_deref() {
+                    Some("NX") if exists => return Ok(Value::Nil),
+                    Some("XX") if !exists => return Ok(Value::Nil),
+                    _ => {}
+                }
+                let value = args[2].clone();
+                state.written(&key);
+                state.strings.insert(key, Entry { value, ttl_secs });
+                Value::Okay
+            }
+            "SETEX" => {
+                let entry = Entry {
+                    value: args[3].clone(),
+                    ttl_secs: text(2).parse().ok(),
+                };
+                state.written(&text(1));
+                state.strings.insert(text(1), entry);
+                Value::Okay
+            }
+            "DEL" | "EXISTS" => {
+                let mut count = 0;
+                for key in (1..args.len()).map(text) {
+                    let found = if name == "DEL" {
+                        let found = state.strings.remove(&key).is_some()
+                            | state.hashes.remove(&key).is_some();
+                        if found {
+                            state.written(&key);
+                        }
+                        found
+                    } else {
+                        state.strings.contains_key(&key) || state.hashes.contains_key(&key)
+                    };
+                    count += found as i64;
+                }
+                Value::Int(count)
+            }
+            "EXPIRE" => match state.strings.get_mut(&text(1)) {
+                Some(entry) => {
+                    entry.ttl_secs = text(2).parse().ok();
+                    state.written(&text(1));
+                    Value::Int(1)
+                }
+                None => Value::Int(0),
+            },
+            "HGET" => state
+                .hashes
+                .get(&text(1))
+                .and_then(|hash| hash.get(&text(2)))
+                .map_or(Value::Nil, |value| Value::Data(value.clone())),
+            "HMGET" => {
+                let hash = state.hashes.get(&text(1));
+                let values = (2..args.len()).map(|i| {
+                    hash.and_then(|hash| hash.get(&text(i)))
+                        .map_or(Value::Nil, |value| Value::Data(value.clone()))
+                });
+                Value::Bulk(values.collect())
+            }
+            "HGETALL" => {
+                let hash = state.hashes.get(&text(1)).into_iter().flatten();
+                let pairs = hash.flat_map(|(field, value)| {
+                    [
+                        Value::Data(field.as_bytes().to_vec()),
+                        Value::Data(value.clone()),
+                    ]
+                });
+                Value::Bulk(pairs.collect())
+            }
+            "HSET" => {
+                let hash = state.hashes.entry(text(1)).or_default();
+                let mut added = 0;
+                for i in (2..args.len()).step_by(2) {
+                    added += hash.insert(text(i), args[i + 1].clone()).is_none() as i64;
+                }
+                state.written(&text(1));
+                Value::Int(added)
+            }
+            "HDEL" => {
+                let mut removed = 0;
+                if let Some(hash) = state.hashes.get_mut(&text(1)) {
+                    for field in (2..args.len()).map(text) {
+                        removed += hash.remove(&field).is_some() as i64;
+                    }
+                }
+                if removed > 0 {
+                    state.written(&text(1));
+                }
+                Value::Int(removed)
+            }
+            "HINCRBY" => {
+                let hash = state.hashes.entry(text(1)).or_default();
+                let field = hash.entry(text(2)).or_default();
+                let current: i64 = String::from_utf8_lossy(field).parse().unwrap_or(0);
+                let count = current + text(3).parse::<i64>().unwrap_or(0);
+                *field = count.to_string().into_bytes();
+                state.written(&text(1));
+                Value::Int(count)
+            }
+            "WATCH" => {
+                for key in (1..args.len()).map(text) {
+                    let writes = state.writes.get(&key).copied().unwrap_or(0);
+                    self.watched.insert(key, writes);
+                }
+                Value::Okay
+            }
+            "UNWATCH" => {
+                self.watched.clear();
+                Value::Okay
+            }
+            other => {
+                let detail = format!("FakeRedis does not know {}", other);
+                return Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unsupported",
+                    detail,
+                )));
+            }
+        };
+        Ok(reply)
+    }
+
+    /// Whether a watched key has been written since it was watched
+    fn watch_broken(&self) -> bool {
+        let state = self.state();
+        self.watched
+            .iter()
+            .any(|(key, writes)| state.writes.get(key).copied().unwrap_or(0) != *writes)
+    }
+}
+
+impl ConnectionLike for FakeRedis {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let reply = self.execute(cmd);
+        Box::pin(async move { reply })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        // Transactions ask for the one `EXEC` reply after `MULTI` and the
+        // `QUEUED` of each command
+        let transaction = offset == pipeline.cmd_iter().count() + 1 && count == 1;
+        if transaction && self.watch_broken() {
+            self.watched.clear();
+            return Box::pin(async move { Ok(vec![Value::Nil]) });
+        }
+        let replies = pipeline
+            .cmd_iter()
+            .map(|cmd| self.execute(cmd))
+            .collect::<RedisResult<Vec<_>>>();
+        if transaction {
+            self.watched.clear();
+        }
+        let replies = replies.map(|replies| match transaction {
+            true => vec![Value::Bulk(replies)],
+            false => replies.into_iter().skip(offset).take(count).collect(),
+        });
+        Box::pin(async move { replies })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::AsyncCommands;
+
+    #[tokio::test]
+    async fn answers_like_redis() {
+        let mut redis = FakeRedis::new();
+        let first: Option<String> = redis::cmd("SET")
+            .arg("k")
+            .arg("v")
+            .arg("NX")
+            .arg("EX")
+            .arg(30)
+            .query_async(&mut redis)
+            .await
+            .unwrap();
+        assert!(first.is_some());
+        let again: Option<String> = redis::cmd("SET")
+            .arg("k")
+            .arg("w")
+            .arg("NX")
+            .query_async(&mut redis)
+            .await
+            .unwrap();
+        assert!(again.is_none());
+        assert_eq!(redis.ttl("k"), Some(30));
+
+        let values: Vec<Option<String>> = redis.mget(&["k", "missing"]).await.unwrap();
+        assert_eq!(values, [Some("v".to_string()), None]);
+
+        let (deleted, expired): (i64, i64) = redis::pipe()
+            .atomic()
+            .del("k")
+            .expire("missing", 5)
+            .query_async(&mut redis)
+            .await
+            .unwrap();
+        assert_eq!((deleted, expired), (1, 0));
+        assert_eq!(redis.get("k"), None);
+    }
+
+    #[tokio::test]
+    async fn transactions_fail_when_a_watched_key_changes() {
+        let mut redis = FakeRedis::new();
+        let mut other = redis.clone();
+        redis::cmd("WATCH")
+            .arg("h")
+            .query_async::<_, ()>(&mut redis)
+            .await
+            .unwrap();
+        other.hincr::<_, _, _, i64>("h", "n", 2).await.unwrap();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().hset("h", "n", 9).ignore();
+        let applied: Option<()> = pipe.query_async(&mut redis).await.unwrap();
+        assert!(applied.is_none());
+        assert_eq!(redis.hget("h", "n").as_deref(), Some("2"));
+
+        // The failed transaction let go of the watch
+        let applied: Option<()> = pipe.query_async(&mut redis).await.unwrap();
+        assert!(applied.is_some());
+        let all: HashMap<String, i64> = redis.hgetall("h").await.unwrap();
+        assert_eq!(all, HashMap::from([("n".to_string(), 9)]));
+    }
+}
@@ -0,0 +1,251 @@
+//! Fake Telegram Bot API.
+
+use axum::{
+    extract::{Path, Query, State},
+    routing::any,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Id and username the fake bot answers `getMe` with
+pub const BOT_ID: i64 = 1000;
+pub const BOT_USERNAME: &str = "claw_test_bot";
+
+/// Longest a `getUpdates` long poll is held open, whatever it asks for
+const MAX_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct Bot {
+    /// Updates not yet confirmed by a later offset
+    updates: Vec<Value>,
+    next_update_id: i64,
+    next_message_id: i64,
+    /// Every call but `getUpdates`, as `(method, body)`
+    calls: Vec<(String, Value)>,
+}
+
+#[derive(Default)]
+struct Shared {
+    bot: Mutex<Bot>,
+    /// Woken when an update arrives or a call is recorded
+    changed: Notify,
+}
+
+impl Shared {
+    fn bot(&self) -> MutexGuard<'_, Bot> {
+        self.bot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A Bot API server on a local port, for any bot token
+pub struct FakeTelegram {
+    api_base: String,
+    shared: Arc<Shared>,
+    server: JoinHandle<()>,
+}
+
+impl FakeTelegram {
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let api_base = format!("http://{}", listener.local_addr()?);
+        let shared = Arc::new(Shared::default());
+        let app = Router::new()
+            .route("/:bot/:method", any(call))
+            .with_state(shared.clone());
+        let server = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Ok(Self {
+            api_base,
+            shared,
+            server,
+        })
+    }
+
+    /// Base URL to set as `telegram.api_base`
+    pub fn api_base(&self) -> &str {
+        &self.api_base
+    }
+
+    /// Have a user send `text` to the bot in private chat `chat_id`,
+    /// returning the update id
+    pub fn send_text(&self, chat_id: i64, text: &str) -> i64 {
+        self.send_message(json!({
+            "chat": {"id": chat_id, "type": "private"},
+            "from": {"id": chat_id, "is_bot": false, "first_name": "Test"},
+            "text": text,
+        }))
+    }
+
+    /// Deliver `message` to the bot, given a message id and date if it has
+    /// none, returning the update id
+    pub fn send_message(&self, mut message: Value) -> i64 {
+        let id = {
+            let mut bot = self.shared.bot();
+            bot.next_update_id += 1;
+            bot.next_message_id += 1;
+            if message["message_id"].is_null() {
+                message["message_id"] = json!(bot.next_message_id);
+            }
+            if message["date"].is_null() {
+                message["date"] = json!(chrono::Utc::now().timestamp());
+            }
+            let id = bot.next_update_id;
+            bot.updates
+                .push(json!({"update_id": id, "message": message}));
+            id
+        };
+        self.shared.changed.notify_waiters();
+        id
+    }
+
+    /// Bodies of the calls to `method` so far
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        let bot = self.shared.bot();
+        let calls = bot.calls.iter().filter(|(m, _)| m == method);
+        calls.map(|(_, body)| body.clone()).collect()
+    }
+
+    /// Messages the bot sent, as their `sendMessage` bodies
+    pub fn sent(&self) -> Vec<Value> {
+        self.calls("sendMessage")
+    }
+
+    /// Wait up to `timeout` for the bot to have sent `count` messages,
+    /// returning those sent by then
+    pub async fn wait_for_sent(&self, count: usize, timeout: Duration) -> Vec<Value> {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                let changed = self.shared.changed.notified();
+                if self.sent().len() >= count {
+                    return;
+                }
+                changed.await;
+            }
+        })
+        .await;
+        self.sent()
+    }
+}
+
+impl Drop for FakeTelegram {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A Bot API call: `/bot{token}/{method}` with JSON or query parameters
+async fn call(
+    State(shared): State<Arc<Shared>>,
+    Path((_bot, method)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    body: Option<Json<Value>>,
+) -> Json<Value> {
+    let mut params = body.map(|Json(body)| body).unwrap_or_else(|| json!({}));
+    for (key, value) in query {
+        params[key.as_str()] = serde_json::from_str(&value).unwrap_or(json!(value));
+    }
+
+    match method.as_str() {
+        "getMe" => Json(json!({"ok": true, "result": bot_user()})),
+        "getUpdates" => Json(json!({"ok": true, "result": updates(&shared, &params).await})),
+        _ => {
+            let result = {
+                let mut bot = shared.bot();
+                bot.next_message_id += 1;
+                let result = json!({
+                    "message_id": bot.next_message_id,
+                    "from": bot_user(),
+                    "chat": {"id": params["chat_id"], "type": "private"},
+                    "date": chrono::Utc::now().timestamp(),
+                });
+                bot.calls.push((method, params));
+                result
+            };
+            shared.changed.notify_waiters();
+            Json(json!({"ok": true, "result": result}))
+        }
+    }
+}
+
+fn bot_user() -> Value {
+    json!({
+        "id": BOT_ID,
+        "is_bot": true,
+        "first_name": "Claw Test",
+        "username": BOT_USERNAME,
+    })
+}
+
+/// Updates from the requested offset on, waiting briefly for one if there
+/// are none
+async fn updates(shared: &Shared, params: &Value) -> Vec<Value> {
+    let offset = params["offset"].as_i64().unwrap_or(0);
+    let pending = |shared: &Shared| {
+        let mut bot = shared.bot();
+        bot.updates
+            .retain(|u| u["update_id"].as_i64().unwrap_or(0) >= offset);
+        bot.updates.clone()
+    };
+
+    let changed = shared.changed.notified();
+    let found = pending(shared);
+    if !found.is_empty() {
+        return found;
+    }
+    let wait = params["timeout"].as_u64().unwrap_or(0);
+    let _ = tokio::time::timeout(MAX_POLL.min(Duration::from_secs(wait)), changed).await;
+    pending(shared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_updates_and_records_replies() {
+        let telegram = FakeTelegram::start().await.unwrap();
+        let http = reqwest::Client::new();
+        let bot = format!("{}/bot123:abc", telegram.api_base());
+
+        let id = telegram.send_text(42, "hello");
+        let updates: Value = http
+            .get(format!("{}/getUpdates?offset=0&timeout=1", bot))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(updates["result"][0]["update_id"], id);
+        assert_eq!(updates["result"][0]["message"]["text"], "hello");
+
+        let confirmed: Value = http
+            .get(format!("{}/getUpdates?offset={}&timeout=0", bot, id + 1))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(confirmed["result"], json!([]));
+
+        let sent: Value = http
+            .post(format!("{}/sendMessage", bot))
+            .json(&json!({"chat_id": 42, "text": "hi there"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(sent["ok"], true);
+        let replies = telegram.wait_for_sent(1, Duration::from_secs(1)).await;
+        assert_eq!(replies, [json!({"chat_id": 42, "text": "hi there"})]);
+    }
+}
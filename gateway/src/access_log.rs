@@ -0,0 +1,233 @@
+//! HTTP access log.
+//!
+//! With `access_log.enabled`, every request outside `access_log.exclude_paths`
+//! is logged once its response is ready: method, path (without the query,
+//! which may carry tokens), status, latency to the response head, the API
+//! key id of authenticated callers and the request id. `format = "text"`
+//! makes it an ordinary log line; `format = "json"` writes one JSON object
+//! per line to stdout instead, for log shippers. A share `sample_rate` of
+//! requests is logged, and server errors always are.
+//!
+//! A share `body_sample_rate` of the logged requests has its request and
+//! response bodies captured as well, when they have a known length of at
+//! most `max_body_bytes`; streamed responses such as event streams never
+//! are. JSON and form-encoded bodies are logged with the values of
+//! `redact_fields` masked, `text/*` as it is and anything else by its size. `redaction.logs` redacts
+//! access log lines like any other.
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::auth::Principal;
+use crate::config::AccessLogFormat;
+use crate::error::ApiError;
+use crate::redaction::RedactingStdout;
+use crate::request_id::RequestId;
+use crate::AppState;
+
+/// One access log line
+#[derive(Debug, Serialize)]
+struct Entry {
+    timestamp: String,
+    method: String,
+    path: String,
+    status: u16,
+    latency_ms: f64,
+    key_id: Option<String>,
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_body: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_body: Option<Value>,
+}
+
+/// Mask the values of `fields` anywhere in `value`, ignoring case
+fn mask(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if fields.iter().any(|field| field.eq_ignore_ascii_case(key)) {
+                    *value = json!("[redacted]");
+                } else {
+                    mask(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| mask(v, fields)),
+        _ => {}
+    }
+}
+
+/// A captured body of `content_type` as logged: JSON or a form with `fields`
+/// masked, text, or its size
+fn captured(bytes: &[u8], content_type: Option<&str>, fields: &[String]) -> Value {
+    if bytes.is_empty() {
+        return Value::Null;
+    }
+    let content_type = content_type.unwrap_or_default();
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        let mut value = Value::Object(
+            form_urlencoded::parse(bytes)
+                .map(|(name, value)| (name.into_owned(), Value::String(value.into_owned())))
+                .collect(),
+        );
+        mask(&mut value, fields);
+        return value;
+    }
+    if let Ok(mut value) = serde_json::from_slice::<Value>(bytes) {
+        mask(&mut value, fields);
+        return value;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) if content_type.starts_with("text/") => Value::String(text.to_string()),
+        _ => Value::String(format!("[{} bytes]", bytes.len())),
+    }
+}
+
+/// The `Content-Type` of a request or response
+fn content_type(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::CONTENT_TYPE)?.to_str().ok()
+}
+
+fn write(entry: &Entry, format: AccessLogFormat) {
+    if format == AccessLogFormat::Json {
+        let mut line = serde_json::to_string(entry).unwrap_or_default();
+        line.push('\n');
+        if let Err(e) = RedactingStdout.write_all(line.as_bytes()) {
+            warn!("Failed to write the access log: {}", e);
+        }
+        return;
+    }
+    let mut bodies = String::new();
+    for (name, body) in [
+        ("request_body", &entry.request_body),
+        ("response_body", &entry.response_body),
+    ] {
+        if let Some(body) = body {
+            bodies.push_str(&format!(" {}={}", name, body));
+        }
+    }
+    info!(
+        "{} {} {} {:.1}ms key={} request_id={}{}",
+        entry.method,
+        entry.path,
+        entry.status,
+        entry.latency_ms,
+        entry.key_id.as_deref().unwrap_or("-"),
+        entry.request_id.as_deref().unwrap_or("-"),
+        bodies
+    );
+}
+
+/// Middleware writing the access log
+pub async fn log_requests(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let config = state.config.current();
+    let settings = &config.access_log;
+    let path = req.uri().path().to_string();
+    if !settings.enabled || settings.exclude_paths.contains(&path) {
+        return next.run(req).await;
+    }
+    let sampled = rand::random::<f64>() < settings.sample_rate;
+    let capture = sampled && rand::random::<f64>() < settings.body_sample_rate;
+
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    let mut request_body = None;
+    if capture && length.is_some_and(|len| len <= settings.max_body_bytes) {
+        let (parts, body) = req.into_parts();
+        let Ok(bytes) = axum::body::to_bytes(body, settings.max_body_bytes).await else {
+            return ApiError::bad_request("invalid_body", "Failed to read the request body")
+                .into_response();
+        };
+        let content_type = content_type(&parts.headers);
+        request_body = Some(captured(&bytes, content_type, &settings.redact_fields));
+        req = Request::from_parts(parts, Body::from(bytes));
+    }
+
+    let mut response = next.run(req).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let status = response.status();
+    if !sampled && !status.is_server_error() {
+        return response;
+    }
+
+    let mut response_body = None;
+    let size = response.body().size_hint().exact();
+    if capture && size.is_some_and(|size| size as usize <= settings.max_body_bytes) {
+        let (parts, body) = response.into_parts();
+        let bytes = match axum::body::to_bytes(body, settings.max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to read the response to {} {}: {}", method, path, e);
+                return ApiError::internal("Failed to send the response").into_response();
+            }
+        };
+        let content_type = content_type(&parts.headers);
+        response_body = Some(captured(&bytes, content_type, &settings.redact_fields));
+        response = Response::from_parts(parts, Body::from(bytes));
+    }
+
+    let entry = Entry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        method,
+        path,
+        status: status.as_u16(),
+        latency_ms,
+        key_id: response
+            .extensions()
+            .get::<Principal>()
+            .map(|p| p.key_id.clone()),
+        request_id,
+        request_body,
+        response_body,
+    };
+    write(&entry, settings.format);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured_bodies_mask_secrets() {
+        let fields = vec!["password".to_string(), "api_key".to_string()];
+        let json = Some("application/json");
+        let body = br#"{"user": "ann", "Password": "hunter2", "keys": [{"api_key": "k"}]}"#;
+        assert_eq!(
+            captured(body, json, &fields),
+            json!({"user": "ann", "Password": "[redacted]", "keys": [{"api_key": "[redacted]"}]})
+        );
+        let text = Some("text/plain; charset=utf-8");
+        assert_eq!(captured(b"plain text", text, &fields), json!("plain text"));
+        assert_eq!(captured(b"plain text", None, &fields), json!("[10 bytes]"));
+        assert_eq!(captured(&[0xff, 0xfe], text, &fields), json!("[2 bytes]"));
+        assert_eq!(captured(b"", json, &fields), Value::Null);
+    }
+
+    #[test]
+    fn captured_forms_mask_secrets() {
+        let fields = vec!["body".to_string(), "from".to_string()];
+        let form = Some("application/x-www-form-urlencoded");
+        let body = b"From=%2B15551234567&Body=my+password+is+hunter2&NumMedia=0";
+        assert_eq!(
+            captured(body, form, &fields),
+            json!({"From": "[redacted]", "Body": "[redacted]", "NumMedia": "0"})
+        );
+    }
+}
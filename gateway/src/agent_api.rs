@@ -0,0 +1,329 @@
+//! Task reporting API for agents.
+//!
+//! Agents may report on the tasks they take over HTTP instead of writing
+//! the Redis records themselves, which leaves the key formats, envelopes and
+//! [status transitions](crate::task_state) to the gateway:
+//!
+//! - `POST /internal/task/:id/start` with `{"agent_id"}` marks the task
+//!   `processing`; a registered agent also claims it in `agent:tasks:{id}`,
+//!   holds the task's affinity token, as described in
+//!   [`agent_registry`](crate::agent_registry), and has the version it
+//!   registered with recorded as the task's `agent_version`.
+//! - `POST /internal/task/:id/progress` with `{"message", "percent"}` keeps
+//!   the latest progress in the record and appends it to the task's
+//!   execution log.
+//! - `POST /internal/task/:id/result` with `{"result"}` completes the task.
+//! - `POST /internal/task/:id/error` with `{"error", "code"}` fails it.
+//!
+//! All need the agent or admin role. A report the task's status no longer
+//! allows, such as a result for a task the watchdog timed out, is refused
+//! with a 409 and changes nothing. Long polls, event tracking and
+//! subscriptions see the writes as they see an agent's own.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, Instrument};
+
+use claw_core::{keys, store};
+
+use crate::agent_registry::{self, require_role};
+use crate::auth::{Principal, Role};
+use crate::error::ApiError;
+use crate::task_state;
+use crate::validation::{self, ValidationError};
+use crate::{failures, redis_connection, task_logs, telemetry, watchdog, AppState};
+
+/// Entries kept in a task's execution log, as agents keep it
+const LOG_MAXLEN: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct StartReport {
+    /// Registered agent taking the task, if any
+    agent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProgressReport {
+    message: String,
+    /// Share of the work done, 0-100
+    percent: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResultReport {
+    result: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorReport {
+    error: String,
+    code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportResponse {
+    task_id: String,
+    status: String,
+}
+
+/// Refuse a malformed task id from the path
+pub fn checked_task_id(task_id: &str) -> Result<(), ApiError> {
+    if validation::is_valid_task_id(task_id) {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request("invalid_task_id", "Invalid task id"))
+    }
+}
+
+/// Refusal of a report on a task in the wrong status
+fn not_processing(task_id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "invalid_transition",
+        format!("Task {} is not being processed", task_id),
+    )
+}
+
+/// Take a task off the set of the agent that claimed it, and off the
+/// watchdog's list now that it is settled
+async fn release(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    task: &Value,
+) -> redis::RedisResult<()> {
+    if let Some(agent_id) = task["agent_id"].as_str() {
+        conn.srem::<_, _, ()>(agent_registry::tasks_key(agent_id), task_id)
+            .await?;
+    }
+    watchdog::untrack(conn, task_id).await
+}
+
+/// Mark `task_id` processing by `agent_id`, returning its record
+pub async fn start(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    agent_id: Option<&str>,
+) -> Result<Value, ApiError> {
+    let now = chrono::Utc::now().to_rfc3339();
+    // The version it registered with, for feedback on its results
+    let version = match agent_id {
+        Some(agent_id) => agent_registry::agent_version(conn, agent_id).await?,
+        None => None,
+    };
+    let task = task_state::transition(conn, task_id, "processing", |task| {
+        if let Some(agent_id) = agent_id {
+            task["agent_id"] = json!(agent_id);
+        }
+        if let Some(version) = &version {
+            task["agent_version"] = json!(version);
+        }
+        task["started_at"] = json!(now);
+        true
+    })
+    .await?
+    .unwrap_or_default();
+
+    if let Some(agent_id) = agent_id {
+        conn.sadd::<_, _, ()>(agent_registry::tasks_key(agent_id), task_id)
+            .await?;
+        let token = task["affinity"]["token"].as_str();
+        let ttl = task["affinity"]["ttl_secs"].as_u64().unwrap_or(0);
+        if let Some(token) = token.filter(|_| ttl > 0) {
+            agent_registry::hold_affinity(conn, token, agent_id, ttl).await?;
+        }
+    }
+    info!("Task {} started", task_id);
+    Ok(task)
+}
+
+/// Complete `task_id` with `result`
+pub async fn complete(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    result: &Value,
+) -> Result<(), ApiError> {
+    let now = chrono::Utc::now().to_rfc3339();
+    let task = task_state::transition(conn, task_id, "completed", |task| {
+        task["completed_at"] = json!(now);
+        task["result"] = result.clone();
+        true
+    })
+    .await?
+    .unwrap_or_default();
+
+    let key = keys::result_key(task_id);
+    store::set_result(conn, task_id, result)
+        .instrument(telemetry::redis_span("SET", &key))
+        .await?;
+    release(conn, task_id, &task).await?;
+    info!("Task {} completed", task_id);
+    Ok(())
+}
+
+/// Fail `task_id` with `error`
+pub async fn fail(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    error: &str,
+    code: Option<&str>,
+) -> Result<(), ApiError> {
+    let now = chrono::Utc::now().to_rfc3339();
+    let task = task_state::transition(conn, task_id, "failed", |task| {
+        task["completed_at"] = json!(now);
+        task["error"] = json!(error);
+        true
+    })
+    .await?
+    .unwrap_or_default();
+
+    let key = failures::error_key(task_id);
+    store::set_error(conn, task_id, &error_record(error, code))
+        .instrument(telemetry::redis_span("SET", &key))
+        .await?;
+    release(conn, task_id, &task).await?;
+    info!("Task {} failed: {}", task_id, error);
+    Ok(())
+}
+
+/// Mark a task processing (agent role)
+pub async fn start_task(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<StartReport>, JsonRejection>,
+) -> Result<Json<ReportResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    checked_task_id(&task_id)?;
+    let Json(report) = body.map_err(ValidationError::from)?;
+
+    let mut conn = redis_connection(&state).await?;
+    start(&mut conn, &task_id, report.agent_id.as_deref()).await?;
+    Ok(Json(ReportResponse {
+        task_id,
+        status: "processing".to_string(),
+    }))
+}
+
+/// Record how far a processing task has come (agent role)
+pub async fn report_progress(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<ProgressReport>, JsonRejection>,
+) -> Result<Json<ReportResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    checked_task_id(&task_id)?;
+    let Json(report) = body.map_err(ValidationError::from)?;
+    if report.percent.is_some_and(|p| !(0.0..=100.0).contains(&p)) {
+        return Err(ApiError::bad_request(
+            "invalid_percent",
+            "percent must be 0-100",
+        ));
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    let now = chrono::Utc::now().to_rfc3339();
+    let progress = json!({
+        "message": report.message,
+        "percent": report.percent,
+        "updated_at": now,
+    });
+    let updated = task_state::rewrite(&mut conn, &task_id, |task| {
+        task["progress"] = progress.clone();
+        task["status"] == "processing"
+    })
+    .await?;
+    if updated.is_none() {
+        return Err(not_processing(&task_id));
+    }
+
+    let key = task_logs::logs_key(&task_id);
+    redis::cmd("XADD")
+        .arg(&key)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(LOG_MAXLEN)
+        .arg("*")
+        .arg(&[
+            ("time", now.as_str()),
+            ("level", "INFO"),
+            ("source", "progress"),
+            ("message", report.message.as_str()),
+        ])
+        .query_async::<_, ()>(&mut conn)
+        .instrument(telemetry::redis_span("XADD", &key))
+        .await?;
+
+    Ok(Json(ReportResponse {
+        task_id,
+        status: "processing".to_string(),
+    }))
+}
+
+/// Complete a task with its result (agent role)
+pub async fn report_result(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<ResultReport>, JsonRejection>,
+) -> Result<Json<ReportResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    checked_task_id(&task_id)?;
+    let Json(report) = body.map_err(ValidationError::from)?;
+
+    let mut conn = redis_connection(&state).await?;
+    complete(&mut conn, &task_id, &report.result).await?;
+    Ok(Json(ReportResponse {
+        task_id,
+        status: "completed".to_string(),
+    }))
+}
+
+/// Fail a task with an error report (agent role)
+pub async fn report_error(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<ErrorReport>, JsonRejection>,
+) -> Result<Json<ReportResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    checked_task_id(&task_id)?;
+    let Json(report) = body.map_err(ValidationError::from)?;
+
+    let mut conn = redis_connection(&state).await?;
+    fail(&mut conn, &task_id, &report.error, report.code.as_deref()).await?;
+    Ok(Json(ReportResponse {
+        task_id,
+        status: "failed".to_string(),
+    }))
+}
+
+/// The `error:{id}` record of a failure, as agents write it
+fn error_record(error: &str, code: Option<&str>) -> Value {
+    let mut record = json!({ "error": error });
+    if let Some(code) = code.filter(|c| !c.is_empty()) {
+        record["code"] = json!(code);
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_records_match_the_agents() {
+        assert_eq!(
+            error_record("model unavailable", Some("agent_error")),
+            json!({"error": "model unavailable", "code": "agent_error"})
+        );
+        assert_eq!(error_record("boom", None), json!({"error": "boom"}));
+        assert_eq!(error_record("boom", Some("")), json!({"error": "boom"}));
+    }
+}
@@ -0,0 +1,416 @@
+//! Versioned management of the default agent config.
+//!
+//! `config:default` holds the config merged under every task's own config.
+//! Changes made through the admin API are validated against the bundled JSON
+//! Schema and recorded as immutable versions under `config:default:v{n}`,
+//! each naming who made it and when. A rollback re-applies an old version as
+//! a new one, so the history only ever grows and stays auditable.
+//!
+//! Each channel tasks arrive through may add a default of its own under
+//! `config:default:{channel}`, merged over the shared default and under the
+//! task's config, so Telegram conversations can default to a chattier
+//! persona than API calls. Channel defaults are managed at
+//! `/admin/config/channel/:channel` and validated like the shared one, but
+//! not versioned.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tracing::{info, Instrument};
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::merge::{self, MergeError};
+use crate::validation::{self, ValidationError};
+use crate::{redis_connection, request_id, telemetry, AppState};
+
+/// Key holding the config the gateway merges into submissions
+pub const DEFAULT_CONFIG_KEY: &str = "config:default";
+
+/// Channels tasks arrive through, each with an optional default of its own;
+/// `http` covers the REST and gRPC APIs
+pub const CHANNELS: [&str; 4] = ["http", "telegram", "email", "twilio"];
+
+/// Counter holding the newest version number
+const VERSION_KEY: &str = "config:default:version";
+
+/// Default and maximum entries returned by the history endpoint
+const DEFAULT_HISTORY_LIMIT: u64 = 20;
+const MAX_HISTORY_LIMIT: u64 = 100;
+
+/// Allocate the next version and store it as both the record and the live
+/// config in one step, so concurrent writers cannot interleave. ARGV[1] is
+/// the record without its version, ARGV[2] the bare config.
+const PUBLISH_SCRIPT: &str = r#"
+local n = redis.call('INCR', KEYS[1])
+local record = '{"version":' .. n .. ',' .. string.sub(ARGV[1], 2)
+redis.call('SET', KEYS[2] .. ':v' .. n, record)
+redis.call('SET', KEYS[2], ARGV[2])
+return record
+"#;
+
+/// One recorded version of the default config
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigVersion {
+    /// 0 for a config written before versioning, straight into Redis
+    pub version: u64,
+    pub config: serde_json::Value,
+    pub updated_by: Option<String>,
+    pub updated_at: Option<String>,
+    pub request_id: Option<String>,
+    /// Version this one restored, for rollbacks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back_from: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    /// Only return versions older than this one
+    before: Option<u64>,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryResponse {
+    versions: Vec<ConfigVersion>,
+    /// Pass as `before` to fetch older versions
+    next_before: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RollbackRequest {
+    version: u64,
+}
+
+fn version_key(version: u64) -> String {
+    format!("{}:v{}", DEFAULT_CONFIG_KEY, version)
+}
+
+fn channel_key(channel: &str) -> String {
+    format!("{}:{}", DEFAULT_CONFIG_KEY, channel)
+}
+
+/// Refuse channels without a default of their own
+fn check_channel(channel: &str) -> Result<(), ApiError> {
+    if CHANNELS.contains(&channel) {
+        return Ok(());
+    }
+    Err(ApiError::not_found(format!(
+        "Unknown channel {} (expected one of {})",
+        channel,
+        CHANNELS.join(", ")
+    )))
+}
+
+/// The default config of tasks arriving through `channel`: the shared
+/// default with the channel's merged over it. Missing or unreadable
+/// defaults count as empty.
+pub async fn defaults<C: ConnectionLike + Send>(
+    conn: &mut C,
+    channel: &str,
+) -> Result<serde_json::Value, MergeError> {
+    let keys = [DEFAULT_CONFIG_KEY.to_string(), channel_key(channel)];
+    let stored: Vec<Option<String>> = conn
+        .mget(&keys)
+        .instrument(telemetry::redis_span("MGET", DEFAULT_CONFIG_KEY))
+        .await
+        .unwrap_or_default();
+    let mut layers = stored
+        .into_iter()
+        .flatten()
+        .filter_map(|raw| serde_json::from_str::<serde_json::Value>(&raw).ok());
+    let mut config = layers.next().unwrap_or_else(|| serde_json::json!({}));
+    for layer in layers {
+        merge::merge(&mut config, &layer)?;
+    }
+    Ok(config)
+}
+
+/// The default config version that was live at `at`: the newest published
+/// by then, looking back [`MAX_HISTORY_LIMIT`] versions at most, or 0 when
+/// there is none
+pub async fn version_at(
+    conn: &mut redis::aio::Connection,
+    at: DateTime<Utc>,
+) -> Result<u64, ApiError> {
+    let latest: u64 = conn.get::<_, Option<u64>>(VERSION_KEY).await?.unwrap_or(0);
+    let oldest = latest.saturating_sub(MAX_HISTORY_LIMIT - 1).max(1);
+    for version in (oldest..=latest).rev() {
+        let Some(record) = load_version(conn, version).await? else {
+            continue;
+        };
+        let published = record.updated_at.as_deref().map(DateTime::parse_from_rfc3339);
+        if published.is_some_and(|p| p.is_ok_and(|p| p <= at)) {
+            return Ok(version);
+        }
+    }
+    Ok(0)
+}
+
+/// Current default config and the version that set it (admin only)
+pub async fn get_default_config(
+    State(state): State<AppState>,
+) -> Result<Json<ConfigVersion>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let version: Option<u64> = conn.get(VERSION_KEY).await?;
+
+    if let Some(version) = version {
+        if let Some(record) = load_version(&mut conn, version).await? {
+            return Ok(Json(record));
+        }
+    }
+
+    // Unversioned config set by hand, or nothing set yet
+    let raw: Option<String> = conn.get(DEFAULT_CONFIG_KEY).await?;
+    let config = match raw {
+        Some(raw) => serde_json::from_str(&raw)?,
+        None => serde_json::json!({}),
+    };
+    Ok(Json(ConfigVersion {
+        version: 0,
+        config,
+        updated_by: None,
+        updated_at: None,
+        request_id: None,
+        rolled_back_from: None,
+    }))
+}
+
+/// Validate and publish a new default config (admin only)
+pub async fn put_default_config(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    body: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<Json<ConfigVersion>, ApiError> {
+    let Json(config) = body.map_err(ValidationError::from)?;
+    validation::validate_default_config(&config, &state.config.current().limits)?;
+
+    let record = publish(&state, &principal, config, None).await?;
+    info!(
+        "Default config version {} published by {}",
+        record.version, principal.key_id
+    );
+    Ok(Json(record))
+}
+
+/// Recorded versions, newest first (admin only)
+pub async fn default_config_history(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let mut conn = redis_connection(&state).await?;
+    let latest: u64 = conn.get::<_, Option<u64>>(VERSION_KEY).await?.unwrap_or(0);
+    let newest = query
+        .before
+        .map_or(latest, |b| b.saturating_sub(1).min(latest));
+    let oldest = newest.saturating_sub(limit - 1).max(1);
+
+    let mut versions = Vec::new();
+    if newest > 0 {
+        let keys: Vec<String> = (oldest..=newest).rev().map(version_key).collect();
+        let records: Vec<Option<String>> = conn.mget(&keys).await?;
+        for record in records.into_iter().flatten() {
+            versions.push(serde_json::from_str(&record)?);
+        }
+    }
+
+    Ok(Json(HistoryResponse {
+        versions,
+        next_before: (oldest > 1).then_some(oldest),
+    }))
+}
+
+/// Re-publish an earlier version as the newest one (admin only)
+pub async fn rollback_default_config(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    body: Result<Json<RollbackRequest>, JsonRejection>,
+) -> Result<Json<ConfigVersion>, ApiError> {
+    let Json(req) = body.map_err(ValidationError::from)?;
+
+    let mut conn = redis_connection(&state).await?;
+    let target = load_version(&mut conn, req.version)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("No config version {}", req.version)))?;
+    // The schema may have tightened since the old version was written
+    validation::validate_default_config(&target.config, &state.config.current().limits)?;
+
+    let record = publish(&state, &principal, target.config, Some(req.version)).await?;
+    info!(
+        "Default config rolled back to version {} as version {} by {}",
+        req.version, record.version, principal.key_id
+    );
+    Ok(Json(record))
+}
+
+/// Put the default config of tasks arriving through `channel` under `config`
+pub async fn apply_defaults<C: ConnectionLike + Send>(
+    conn: &mut C,
+    channel: &str,
+    config: &mut serde_json::Value,
+) -> Result<(), MergeError> {
+    let mut merged = defaults(conn, channel).await?;
+    merge::merge(&mut merged, config)?;
+    *config = merged;
+    Ok(())
+}
+
+/// Default config of a channel, `{}` when it has none (admin only)
+pub async fn get_channel_config(
+    State(state): State<AppState>,
+    Path(channel): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    check_channel(&channel)?;
+    let mut conn = redis_connection(&state).await?;
+    let raw: Option<String> = conn.get(channel_key(&channel)).await?;
+    let config = match raw {
+        Some(raw) => serde_json::from_str(&raw)?,
+        None => serde_json::json!({}),
+    };
+    Ok(Json(serde_json::json!({"channel": channel, "config": config})))
+}
+
+/// Validate and store the default config of a channel (admin only)
+pub async fn put_channel_config(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(channel): Path<String>,
+    body: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    check_channel(&channel)?;
+    let Json(config) = body.map_err(ValidationError::from)?;
+    validation::validate_default_config(&config, &state.config.current().limits)?;
+
+    let mut conn = redis_connection(&state).await?;
+    conn.set::<_, _, ()>(channel_key(&channel), serde_json::to_string(&config)?)
+        .await?;
+    info!("{} default config updated by {}", channel, principal.key_id);
+    Ok(Json(serde_json::json!({"channel": channel, "config": config})))
+}
+
+/// Drop the default config of a channel (admin only)
+pub async fn delete_channel_config(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(channel): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_channel(&channel)?;
+    let mut conn = redis_connection(&state).await?;
+    let removed: i64 = conn.del(channel_key(&channel)).await?;
+    if removed == 0 {
+        return Err(ApiError::not_found(format!(
+            "No default config for {}",
+            channel
+        )));
+    }
+    info!("{} default config removed by {}", channel, principal.key_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn load_version(
+    conn: &mut redis::aio::Connection,
+    version: u64,
+) -> Result<Option<ConfigVersion>, ApiError> {
+    let record: Option<String> = conn.get(version_key(version)).await?;
+    Ok(record.map(|r| serde_json::from_str(&r)).transpose()?)
+}
+
+/// Record `config` as a new version and make it live
+async fn publish(
+    state: &AppState,
+    principal: &Principal,
+    config: serde_json::Value,
+    rolled_back_from: Option<u64>,
+) -> Result<ConfigVersion, ApiError> {
+    let mut record = serde_json::json!({
+        "config": config,
+        "updated_by": principal.key_id,
+        "updated_at": chrono::Utc::now().to_rfc3339(),
+        "request_id": request_id::current().map(|id| id.0),
+    });
+    if let Some(from) = rolled_back_from {
+        record["rolled_back_from"] = from.into();
+    }
+
+    let mut conn = redis_connection(state).await?;
+    let stored: String = redis::Script::new(PUBLISH_SCRIPT)
+        .key(VERSION_KEY)
+        .key(DEFAULT_CONFIG_KEY)
+        .arg(serde_json::to_string(&record)?)
+        .arg(serde_json::to_string(&config)?)
+        .invoke_async(&mut conn)
+        .instrument(telemetry::redis_span("EVALSHA", DEFAULT_CONFIG_KEY))
+        .await?;
+
+    Ok(serde_json::from_str(&stored)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn channels_merge_between_default_and_task() {
+        let mut redis = FakeRedis::new();
+        redis.set(
+            DEFAULT_CONFIG_KEY,
+            r#"{"model": "small", "persona": {"tone": "terse"}}"#,
+        );
+        redis.set(
+            &channel_key("telegram"),
+            r#"{"persona": {"tone": "chatty"}}"#,
+        );
+        redis.set(&channel_key("http"), r#"{"model": "large"}"#);
+
+        let telegram = defaults(&mut redis, "telegram").await.unwrap();
+        assert_eq!(
+            telegram,
+            json!({"model": "small", "persona": {"tone": "chatty"}})
+        );
+
+        let mut config = json!({"model": "tiny", "persona": {"emoji": true}});
+        apply_defaults(&mut redis, "telegram", &mut config)
+            .await
+            .unwrap();
+        assert_eq!(
+            config,
+            json!({"model": "tiny", "persona": {"tone": "chatty", "emoji": true}})
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_or_unreadable_defaults_count_as_empty() {
+        let mut redis = FakeRedis::new();
+        assert_eq!(defaults(&mut redis, "email").await.unwrap(), json!({}));
+
+        redis.set(&channel_key("email"), r#"{"persona": "formal"}"#);
+        let email = defaults(&mut redis, "email").await.unwrap();
+        assert_eq!(email, json!({"persona": "formal"}));
+
+        redis.set(DEFAULT_CONFIG_KEY, "not json");
+        let email = defaults(&mut redis, "email").await.unwrap();
+        assert_eq!(email, json!({"persona": "formal"}));
+    }
+
+    #[test]
+    fn only_known_channels_have_defaults() {
+        for channel in CHANNELS {
+            assert!(check_channel(channel).is_ok());
+        }
+        assert!(check_channel("slack").is_err());
+        assert_eq!(channel_key("twilio"), "config:default:twilio");
+    }
+}
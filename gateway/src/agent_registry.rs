@@ -0,0 +1,430 @@
+//! Agent worker registry.
+//!
+//! Agents register through `POST /agents/register` and then keep their
+//! `agent:hb:{id}` record alive with `POST /agents/{id}/heartbeat`; a record
+//! that is not refreshed within `agents.heartbeat_ttl_secs` expires, and the
+//! agent counts as gone. While processing a task an agent names itself in the
+//! task record and adds the task to its `agent:tasks:{id}` set. A sweep finds
+//! sets whose agent has vanished and marks their unfinished tasks `orphaned`,
+//! so clients stop waiting on work nobody is doing.
+//!
+//! Agents also advertise capabilities such as `code` or `search`. Each one
+//! is a sorted set `agent:capability:{name}` of agent ids scored by when
+//! their registration lapses, so any gateway can tell in one call whether a
+//! live agent can take a task for `agent:queue:{name}`.
+//!
+//! Tasks of one conversation may carry an affinity token, e.g. one per
+//! Telegram chat. The agent that takes such a task records itself under
+//! `affinity:{token}` for the TTL the task names, and while it stays alive
+//! the conversation's next tasks go to its own queue `agent:direct:{id}`,
+//! where its context and caches are, instead of the shared one.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    Extension, Json,
+};
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn, Instrument};
+
+use crate::auth::{Principal, Role};
+use crate::config::AgentRegistrySettings;
+use crate::error::ApiError;
+use crate::metrics::{Counter, Gauge};
+use crate::task_state::{self, TransitionError};
+use crate::validation::{self, ValidationError};
+use crate::{redis_connection, telemetry, AppState};
+
+use claw_core::keys::AGENT_TASKS_PREFIX;
+pub use claw_core::keys::{agent_tasks_key as tasks_key, direct_queue, DIRECT_QUEUE_PREFIX};
+
+const HEARTBEAT_PREFIX: &str = "agent:hb:";
+const CAPABILITY_PREFIX: &str = "agent:capability:";
+const AFFINITY_PREFIX: &str = "affinity:";
+
+/// Longest agent id accepted at registration
+const MAX_AGENT_ID_LEN: usize = 128;
+
+fn heartbeat_key(agent_id: &str) -> String {
+    format!("{}{}", HEARTBEAT_PREFIX, agent_id)
+}
+
+fn capability_key(capability: &str) -> String {
+    format!("{}{}", CAPABILITY_PREFIX, capability)
+}
+
+/// Affinity token of conversation `conversation`, e.g. `telegram:{chat id}`,
+/// hashed so the id stays out of Redis key names
+pub fn affinity_token(conversation: &str) -> String {
+    let hash = ring::digest::digest(&ring::digest::SHA256, conversation.as_bytes());
+    format!("chat:{}", hex::encode(&hash.as_ref()[..16]))
+}
+
+/// Send the next tasks with affinity `token` to `agent_id` for `ttl_secs`
+pub async fn hold_affinity(
+    conn: &mut redis::aio::Connection,
+    token: &str,
+    agent_id: &str,
+    ttl_secs: u64,
+) -> redis::RedisResult<()> {
+    conn.set_ex(format!("{}{}", AFFINITY_PREFIX, token), agent_id, ttl_secs)
+        .await
+}
+
+/// Queue for a task with affinity `token`: the direct queue of the live
+/// agent that took the conversation's last task, or else `fallback`
+pub async fn affinity_queue(
+    conn: &mut redis::aio::Connection,
+    token: &str,
+    fallback: &str,
+) -> redis::RedisResult<String> {
+    let agent_id: Option<String> = conn.get(format!("{}{}", AFFINITY_PREFIX, token)).await?;
+    let Some(agent_id) = agent_id else {
+        return Ok(fallback.to_string());
+    };
+    let alive: bool = conn.exists(heartbeat_key(&agent_id)).await?;
+    Ok(if alive {
+        direct_queue(&agent_id)
+    } else {
+        fallback.to_string()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    /// Generated when omitted
+    agent_id: Option<String>,
+    hostname: Option<String>,
+    version: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+/// A live agent, as stored under `agent:hb:{id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentRecord {
+    agent_id: String,
+    hostname: Option<String>,
+    version: Option<String>,
+    capabilities: Vec<String>,
+    /// Key id of the API key that registered the agent
+    registered_by: String,
+    registered_at: String,
+    last_heartbeat_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisterResponse {
+    agent_id: String,
+    /// Heartbeats must arrive more often than this to stay registered
+    heartbeat_ttl_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentsResponse {
+    alive: usize,
+    agents: Vec<AgentRecord>,
+}
+
+/// Registry counters maintained by the orphan sweep
+#[derive(Debug, Default)]
+pub struct AgentMetrics {
+    /// Agents with a live heartbeat at the last sweep
+    pub alive: Gauge,
+    /// Tasks marked orphaned since startup
+    pub orphaned: Counter,
+    /// Tasks requeued by the lease sweep since startup
+    pub leases_lapsed: Counter,
+}
+
+/// The caller, if it holds a role in `allowed`
+pub fn require_role(
+    principal: Option<Extension<Principal>>,
+    allowed: &[Role],
+) -> Result<Principal, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    if !allowed.contains(&principal.role) {
+        return Err(ApiError::forbidden("Role not allowed for agent registry"));
+    }
+    Ok(principal)
+}
+
+/// Register an agent, or refresh an existing registration (agent role)
+pub async fn register_agent(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    body: Result<Json<RegisterRequest>, JsonRejection>,
+) -> Result<Json<RegisterResponse>, ApiError> {
+    let principal = require_role(principal, &[Role::Agent, Role::Admin])?;
+    let Json(req) = body.map_err(ValidationError::from)?;
+
+    let agent_id = req
+        .agent_id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if agent_id.is_empty()
+        || agent_id.len() > MAX_AGENT_ID_LEN
+        || !agent_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(ApiError::bad_request(
+            "invalid_agent_id",
+            format!(
+                "agent_id must be 1-{} letters, digits, '-', '_' or '.'",
+                MAX_AGENT_ID_LEN
+            ),
+        ));
+    }
+    if !req
+        .capabilities
+        .iter()
+        .all(|c| validation::is_valid_capability(c))
+    {
+        return Err(ApiError::bad_request(
+            "invalid_capability",
+            validation::CAPABILITY_RULE,
+        ));
+    }
+
+    let key = heartbeat_key(&agent_id);
+    let ttl = state.config.current().agents.heartbeat_ttl_secs;
+    let mut conn = redis_connection(&state).await?;
+    let existing: Option<String> = conn.get(&key).await?;
+    let now = chrono::Utc::now().to_rfc3339();
+    let registered_at = existing
+        .and_then(|r| serde_json::from_str::<AgentRecord>(&r).ok())
+        .map_or_else(|| now.clone(), |r| r.registered_at);
+
+    let record = AgentRecord {
+        agent_id: agent_id.clone(),
+        hostname: req.hostname,
+        version: req.version,
+        capabilities: req.capabilities,
+        registered_by: principal.key_id,
+        registered_at,
+        last_heartbeat_at: now,
+    };
+    conn.set_ex::<_, _, ()>(&key, serde_json::to_string(&record)?, ttl)
+        .instrument(telemetry::redis_span("SET", &key))
+        .await?;
+    advertise(&mut conn, &record, ttl).await?;
+
+    info!("Agent {} registered", agent_id);
+    Ok(Json(RegisterResponse {
+        agent_id,
+        heartbeat_ttl_secs: ttl,
+    }))
+}
+
+/// Keep a registration alive (agent role)
+pub async fn agent_heartbeat(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<RegisterResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+
+    let key = heartbeat_key(&agent_id);
+    let ttl = state.config.current().agents.heartbeat_ttl_secs;
+    let mut conn = redis_connection(&state).await?;
+    let existing: Option<String> = conn.get(&key).await?;
+    // An expired agent may have had its tasks orphaned; make it say hello again
+    let Some(existing) = existing else {
+        return Err(ApiError::not_found(format!(
+            "Agent {} is not registered",
+            agent_id
+        )));
+    };
+
+    let mut record: AgentRecord = serde_json::from_str(&existing)?;
+    record.last_heartbeat_at = chrono::Utc::now().to_rfc3339();
+    conn.set_ex::<_, _, ()>(&key, serde_json::to_string(&record)?, ttl)
+        .instrument(telemetry::redis_span("SET", &key))
+        .await?;
+    advertise(&mut conn, &record, ttl).await?;
+
+    Ok(Json(RegisterResponse {
+        agent_id,
+        heartbeat_ttl_secs: ttl,
+    }))
+}
+
+/// Agents with a live heartbeat (operator, viewer or admin)
+pub async fn list_agents(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+) -> Result<Json<AgentsResponse>, ApiError> {
+    require_role(principal, &[Role::Operator, Role::Viewer, Role::Admin])?;
+
+    let mut conn = redis_connection(&state).await?;
+    let keys = scan_keys(&mut conn, HEARTBEAT_PREFIX).await?;
+    let mut agents: Vec<AgentRecord> = if keys.is_empty() {
+        Vec::new()
+    } else {
+        let records: Vec<Option<String>> = conn.mget(&keys).await?;
+        records
+            .into_iter()
+            .flatten()
+            .filter_map(|r| serde_json::from_str(&r).ok())
+            .collect()
+    };
+    agents.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+
+    Ok(Json(AgentsResponse {
+        alive: agents.len(),
+        agents,
+    }))
+}
+
+/// List the agent under each of its capabilities until its registration lapses
+async fn advertise(
+    conn: &mut redis::aio::Connection,
+    record: &AgentRecord,
+    ttl: u64,
+) -> redis::RedisResult<()> {
+    let expires_at = chrono::Utc::now().timestamp() + ttl as i64;
+    let mut pipe = redis::pipe();
+    for capability in &record.capabilities {
+        pipe.zadd(capability_key(capability), &record.agent_id, expires_at)
+            .ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+/// Whether a live agent has advertised `capability`
+pub async fn capability_available(
+    conn: &mut redis::aio::Connection,
+    capability: &str,
+) -> redis::RedisResult<bool> {
+    let now = chrono::Utc::now().timestamp();
+    let live: u64 = conn
+        .zcount(capability_key(capability), format!("({}", now), "+inf")
+        .await?;
+    Ok(live > 0)
+}
+
+/// Every capability some agent has advertised and not yet lapsed from
+pub async fn registered_capabilities(
+    conn: &mut redis::aio::Connection,
+) -> redis::RedisResult<Vec<String>> {
+    let keys = scan_keys(conn, CAPABILITY_PREFIX).await?;
+    Ok(keys
+        .into_iter()
+        .map(|key| key[CAPABILITY_PREFIX.len()..].to_string())
+        .collect())
+}
+
+/// Version `agent_id` registered with, while its registration is alive
+pub async fn agent_version(
+    conn: &mut redis::aio::Connection,
+    agent_id: &str,
+) -> redis::RedisResult<Option<String>> {
+    let record: Option<String> = conn.get(heartbeat_key(agent_id)).await?;
+    Ok(record
+        .and_then(|r| serde_json::from_str::<AgentRecord>(&r).ok())
+        .and_then(|r| r.version))
+}
+
+/// Every key starting with `prefix`
+pub async fn scan_keys(
+    conn: &mut redis::aio::Connection,
+    prefix: &str,
+) -> redis::RedisResult<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor = 0u64;
+    loop {
+        let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", prefix))
+            .arg("COUNT")
+            .arg(200)
+            .query_async(conn)
+            .await?;
+        keys.extend(batch);
+        cursor = next;
+        if cursor == 0 {
+            // SCAN may return a key more than once
+            keys.sort();
+            keys.dedup();
+            return Ok(keys);
+        }
+    }
+}
+
+/// Mark unfinished tasks of vanished agents as orphaned
+async fn sweep(redis_client: &Client, metrics: &AgentMetrics) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    metrics
+        .alive
+        .set(scan_keys(&mut conn, HEARTBEAT_PREFIX).await?.len() as i64);
+
+    // Forget capability listings of agents that have lapsed
+    let now = chrono::Utc::now().timestamp();
+    for key in scan_keys(&mut conn, CAPABILITY_PREFIX).await? {
+        conn.zrembyscore::<_, _, _, ()>(&key, "-inf", now).await?;
+    }
+
+    for key in scan_keys(&mut conn, AGENT_TASKS_PREFIX).await? {
+        let agent_id = &key[AGENT_TASKS_PREFIX.len()..];
+        let alive: bool = conn.exists(heartbeat_key(agent_id)).await?;
+        if alive {
+            continue;
+        }
+
+        let task_ids: Vec<String> = conn.smembers(&key).await?;
+        for task_id in task_ids {
+            if orphan_task(&mut conn, &task_id, agent_id).await? {
+                metrics.orphaned.inc();
+                warn!("Task {} orphaned: agent {} disappeared", task_id, agent_id);
+            }
+        }
+        conn.del::<_, ()>(&key).await?;
+    }
+
+    Ok(())
+}
+
+/// Mark `task_id` orphaned if `agent_id` still holds it unfinished
+async fn orphan_task(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    agent_id: &str,
+) -> anyhow::Result<bool> {
+    let result_exists: bool = conn.exists(format!("result:{}", task_id)).await?;
+    if result_exists {
+        return Ok(false);
+    }
+
+    let orphaned = task_state::transition(conn, task_id, "orphaned", |task| {
+        task["orphaned_at"] = chrono::Utc::now().to_rfc3339().into();
+        task["status"] == "processing" && task["agent_id"] == agent_id
+    })
+    .await;
+    match orphaned {
+        Ok(orphaned) => Ok(orphaned.is_some()),
+        Err(TransitionError::NotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Start the orphan sweep in a background task
+pub fn start_orphan_sweep(
+    redis_client: Arc<Client>,
+    settings: &AgentRegistrySettings,
+    metrics: Arc<AgentMetrics>,
+) {
+    let interval = Duration::from_secs(settings.orphan_check_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = sweep(&redis_client, &metrics).await {
+                error!("Agent orphan sweep error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
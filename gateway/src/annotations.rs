@@ -0,0 +1,237 @@
+//! Task annotations.
+//!
+//! People and agents attach notes to a task with `POST
+//! /task/:id/annotations` and `{"text": "...", "kind": "..."}`, `kind` being
+//! `note` (the default), `triage` or `resolution`. `GET
+//! /task/:id/annotations` lists them oldest first, and `GET /task/:id`
+//! includes them as `annotations`. Each records its author's key id and
+//! role. Annotations are kept in the Redis list `annotations:{task_id}`,
+//! stored like task records, and outlive a task's deletion as part of its
+//! audit trail until the task is purged.
+//!
+//! Annotating needs an API key. Annotations are as open as the task's
+//! result: on tasks submitted with an API key, only the submitter and the
+//! roles that may read every task may read or add them.
+//! A task takes up to `limits.max_annotations` annotations of up to
+//! `limits.max_annotation_chars` characters each.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::Utc;
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::attachments;
+use crate::auth::{Principal, Role};
+use crate::error::ApiError;
+use crate::validation::ValidationError;
+use crate::{envelope, redis_connection, schema, AppState};
+
+/// Kinds of annotation
+pub const KINDS: [&str; 3] = ["note", "triage", "resolution"];
+
+/// Redis list of a task's annotations
+pub fn annotations_key(task_id: &str) -> String {
+    format!("annotations:{}", task_id)
+}
+
+/// Body of `POST /task/:id/annotations`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnnotationRequest {
+    text: String,
+    kind: Option<String>,
+}
+
+/// A stored annotation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Annotation {
+    pub id: String,
+    pub kind: String,
+    pub text: String,
+    pub author: String,
+    pub role: Role,
+    pub created_at: String,
+}
+
+/// A task's annotations, oldest first
+pub async fn list(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+) -> Result<Vec<Annotation>, ApiError> {
+    let entries: Vec<String> = conn.lrange(annotations_key(task_id), 0, -1).await?;
+    entries
+        .iter()
+        .map(|entry| {
+            let value = envelope::decode(entry)?;
+            Ok(serde_json::from_value(value)?)
+        })
+        .collect()
+}
+
+/// Refuse `principal` access to the annotations (or logs) of `task_id`, or
+/// report the task missing
+pub async fn check_access<C: ConnectionLike + Send>(
+    conn: &mut C,
+    principal: Option<&Principal>,
+    task_id: &str,
+) -> Result<(), ApiError> {
+    if check_reader(conn, principal, task_id).await? {
+        return Ok(());
+    }
+    Err(ApiError::not_found(format!("Task {} not found", task_id)))
+}
+
+/// Refuse `principal` access to `task_id` if it may not read the task's
+/// result, and tell whether the task record is still in Redis. Tasks Redis
+/// has let go of pass, for the lookup to report.
+pub async fn check_reader<C: ConnectionLike + Send>(
+    conn: &mut C,
+    principal: Option<&Principal>,
+    task_id: &str,
+) -> Result<bool, ApiError> {
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    let Some(task) = task.map(|t| schema::decode_task(&t)).transpose()? else {
+        return Ok(false);
+    };
+    if attachments::may_read(principal, task["submitted_by"].as_str()) {
+        return Ok(true);
+    }
+    Err(match principal {
+        Some(_) => ApiError::forbidden("Task belongs to another caller"),
+        None => ApiError::unauthorized("Missing bearer token"),
+    })
+}
+
+// Attach a note to a task
+pub async fn add_annotation(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<AnnotationRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<Annotation>), ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let Json(req) = body.map_err(ValidationError::from)?;
+    let limits = state.config.current().limits.clone();
+
+    let text = req.text.trim();
+    let chars = text.chars().count();
+    if chars == 0 || chars > limits.max_annotation_chars {
+        return Err(ApiError::bad_request(
+            "invalid_annotation",
+            format!("text must be 1-{} characters", limits.max_annotation_chars),
+        ));
+    }
+    let kind = req.kind.unwrap_or_else(|| KINDS[0].to_string());
+    if !KINDS.contains(&kind.as_str()) {
+        return Err(ApiError::bad_request(
+            "invalid_annotation",
+            format!("kind must be one of {}", KINDS.join(", ")),
+        ));
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    check_access(&mut conn, Some(&principal), &task_id).await?;
+    let key = annotations_key(&task_id);
+    let count: usize = conn.llen(&key).await?;
+    if count >= limits.max_annotations {
+        return Err(ApiError::unprocessable(
+            "too_many_annotations",
+            format!(
+                "Task {} already has {} annotations",
+                task_id, limits.max_annotations
+            ),
+        ));
+    }
+
+    let annotation = Annotation {
+        id: uuid::Uuid::new_v4().to_string(),
+        kind,
+        text: text.to_string(),
+        author: principal.key_id.clone(),
+        role: principal.role,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    let entry = envelope::encode(&serde_json::to_value(&annotation)?)?;
+    conn.rpush::<_, _, ()>(&key, entry).await?;
+    info!(
+        "Task {} annotated ({}) by {}",
+        task_id, annotation.kind, annotation.author
+    );
+    Ok((StatusCode::CREATED, Json(annotation)))
+}
+
+// List a task's annotations
+pub async fn list_annotations(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    check_access(&mut conn, principal, &task_id).await?;
+    let annotations = list(&mut conn, &task_id).await?;
+    Ok(Json(serde_json::json!({
+        "task_id": task_id,
+        "annotations": annotations,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+
+    #[test]
+    fn annotations_round_trip_through_records() {
+        let annotation = Annotation {
+            id: "a1".to_string(),
+            kind: "triage".to_string(),
+            text: "Agent ran out of quota".to_string(),
+            author: "oncall".to_string(),
+            role: Role::Operator,
+            created_at: "2026-10-14T15:20:00+00:00".to_string(),
+        };
+        let entry = envelope::encode(&serde_json::to_value(&annotation).unwrap()).unwrap();
+        let value = envelope::decode(&entry).unwrap();
+        assert_eq!(value["role"], "operator");
+        let read: Annotation = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            (read.kind.as_str(), read.text.as_str()),
+            ("triage", annotation.text.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn owned_tasks_are_refused_to_other_callers() {
+        let mut redis = FakeRedis::new();
+        let task = serde_json::json!({"status": "completed", "submitted_by": "alice"});
+        redis.set("task:t1", &envelope::encode(&task).unwrap());
+        let caller = |key_id: &str, role| Principal {
+            key_id: key_id.to_string(),
+            role,
+        };
+
+        let other = caller("bob", Role::Submitter);
+        let refused = check_reader(&mut redis, Some(&other), "t1").await.unwrap_err();
+        assert_eq!(refused.status, StatusCode::FORBIDDEN);
+        let refused = check_reader(&mut redis, None, "t1").await.unwrap_err();
+        assert_eq!(refused.status, StatusCode::UNAUTHORIZED);
+
+        let owner = caller("alice", Role::Submitter);
+        assert!(check_reader(&mut redis, Some(&owner), "t1").await.unwrap());
+        let viewer = caller("carol", Role::Viewer);
+        assert!(check_reader(&mut redis, Some(&viewer), "t1").await.unwrap());
+        assert!(!check_reader(&mut redis, Some(&other), "t2").await.unwrap());
+        let missing = check_access(&mut redis, Some(&other), "t2").await.unwrap_err();
+        assert_eq!(missing.status, StatusCode::NOT_FOUND);
+    }
+}
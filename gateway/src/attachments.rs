@@ -0,0 +1,634 @@
+//! Task attachments.
+//!
+//! `POST /task/:id/attachments` stores files for a task before it is
+//! submitted. A `multipart/form-data` body stores one attachment per part;
+//! any other body is streamed as a single attachment named by `?name=`.
+//! Contents go to Redis under `attachment:{task_id}:{attachment_id}` or, with
+//! `attachments.backend = "s3"`, are streamed to an S3-compatible bucket in
+//! multipart uploads. Each attachment is capped at `attachments.max_bytes`,
+//! enforced as it arrives, and a task at `attachments.max_per_task` of them.
+//! The first upload claims the task id for its API key in
+//! `attachments:{task_id}:claim`, which also counts reserved slots; uploads
+//! by other keys are refused unless they are operators or admins.
+//!
+//! Metadata lives in the Redis hash `attachments:{task_id}`, and everything
+//! expires after `attachments.ttl_secs`. Submitting the task copies the
+//! metadata into the task record's `attachments` list, where agents find the
+//! `url` to fetch each one from `GET /task/:id/attachments/:attachment_id`.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use redis::AsyncCommands;
+use ring::digest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::auth::Principal;
+use crate::config::{AttachmentBackend, AttachmentSettings};
+use crate::error::ApiError;
+use crate::validation::{self, ValidationError};
+use crate::AppState;
+
+/// Longest kept attachment name
+const MAX_NAME_LEN: usize = 255;
+
+/// Redis hash of a task's attachment metadata, by attachment id
+fn meta_key(task_id: &str) -> String {
+    format!("attachments:{}", task_id)
+}
+
+/// Redis hash holding the uploader that claimed a task's attachments and the
+/// number of slots reserved
+fn claim_key(task_id: &str) -> String {
+    format!("attachments:{}:claim", task_id)
+}
+
+/// Reserve an attachment slot for ARGV[1] (empty for anonymous uploads),
+/// claiming the task for it unless another uploader has; ARGV[2] is the
+/// slot limit, ARGV[3] the TTL and ARGV[4] "1" when the uploader may attach
+/// to claimed tasks. Returns the slots now reserved, -1 when another
+/// uploader holds the claim and -2 when every slot is taken.
+const RESERVE_SCRIPT: &str = r#"
+local owner = redis.call('HGET', KEYS[1], 'owner')
+if owner and owner ~= ARGV[1] and ARGV[4] ~= '1' then
+  return -1
+end
+local reserved = redis.call('HINCRBY', KEYS[1], 'reserved', 1)
+if reserved > tonumber(ARGV[2]) then
+  redis.call('HINCRBY', KEYS[1], 'reserved', -1)
+  return -2
+end
+if not owner then
+  redis.call('HSET', KEYS[1], 'owner', ARGV[1])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return reserved
+"#;
+
+/// Redis key of an attachment's contents with the Redis backend
+fn blob_key(task_id: &str, attachment_id: &str) -> String {
+    format!("attachment:{}:{}", task_id, attachment_id)
+}
+
+/// Error answered when attachment contents cannot be stored or read
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn storage_error(e: impl std::fmt::Display) -> ApiError {
+    error!("Attachment storage error: {}", e);
+    ApiError::unavailable(
+        "attachment_storage_unavailable",
+        "Attachment storage is unavailable",
+    )
+}
+
+/// Where attachment contents are kept
+#[derive(Clone)]
+pub enum AttachmentStore {
+    Redis,
+    #[cfg(feature = "s3")]
+    S3 {
+        store: std::sync::Arc<object_store::aws::AmazonS3>,
+        prefix: String,
+    },
+}
+
+impl AttachmentStore {
+    pub fn from_config(settings: &AttachmentSettings) -> anyhow::Result<Self> {
+        match settings.backend {
+            AttachmentBackend::Redis => Ok(AttachmentStore::Redis),
+            #[cfg(feature = "s3")]
+            AttachmentBackend::S3 => {
+                let s3 = &settings.s3;
+                let mut builder = object_store::aws::AmazonS3Builder::from_env()
+                    .with_bucket_name(s3.bucket.clone().unwrap_or_default())
+                    .with_region(&s3.region);
+                if let Some(endpoint) = &s3.endpoint {
+                    builder = builder
+                        .with_endpoint(endpoint)
+                        .with_allow_http(endpoint.starts_with("http://"));
+                }
+                Ok(AttachmentStore::S3 {
+                    store: std::sync::Arc::new(builder.build()?),
+                    prefix: s3.prefix.clone(),
+                })
+            }
+            #[cfg(not(feature = "s3"))]
+            AttachmentBackend::S3 => anyhow::bail!("gateway built without the s3 feature"),
+        }
+    }
+
+    pub fn backend(&self) -> AttachmentBackend {
+        match self {
+            AttachmentStore::Redis => AttachmentBackend::Redis,
+            #[cfg(feature = "s3")]
+            AttachmentStore::S3 { .. } => AttachmentBackend::S3,
+        }
+    }
+
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    async fn writer(&self, task_id: &str, attachment_id: &str) -> Result<BlobWriter, ApiError> {
+        match self {
+            AttachmentStore::Redis => Ok(BlobWriter::Redis(Vec::new())),
+            #[cfg(feature = "s3")]
+            AttachmentStore::S3 { store, prefix } => {
+                use object_store::ObjectStore;
+
+                let path = object_path(prefix, task_id, attachment_id);
+                let upload = store.put_multipart(&path).await.map_err(storage_error)?;
+                Ok(BlobWriter::S3(object_store::WriteMultipart::new(upload)))
+            }
+        }
+    }
+
+    /// The stored contents of an attachment, streamed
+    async fn open(
+        &self,
+        conn: &mut redis::aio::Connection,
+        task_id: &str,
+        attachment_id: &str,
+    ) -> Result<Option<Body>, ApiError> {
+        match self {
+            AttachmentStore::Redis => {
+                let blob: Option<Vec<u8>> = conn.get(blob_key(task_id, attachment_id)).await?;
+                Ok(blob.map(Body::from))
+            }
+            #[cfg(feature = "s3")]
+            AttachmentStore::S3 { store, prefix } => {
+                use object_store::ObjectStore;
+
+                match store
+                    .get(&object_path(prefix, task_id, attachment_id))
+                    .await
+                {
+                    Ok(object) => Ok(Some(Body::from_stream(object.into_stream()))),
+                    Err(object_store::Error::NotFound { .. }) => Ok(None),
+                    Err(e) => Err(storage_error(e)),
+                }
+            }
+        }
+    }
+}
+
+impl AttachmentStore {
+    /// Drop every attachment of a task, contents and metadata
+    pub async fn remove(
+        &self,
+        conn: &mut redis::aio::Connection,
+        task_id: &str,
+    ) -> Result<(), ApiError> {
+        let ids: Vec<String> = conn.hkeys(meta_key(task_id)).await?;
+        #[cfg(feature = "s3")]
+        if let AttachmentStore::S3 { store, prefix } = self {
+            use object_store::ObjectStore;
+
+            for id in &ids {
+                match store.delete(&object_path(prefix, task_id, id)).await {
+                    Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                    Err(e) => return Err(storage_error(e)),
+                }
+            }
+        }
+        let mut keys: Vec<String> = ids.iter().map(|id| blob_key(task_id, id)).collect();
+        keys.push(meta_key(task_id));
+        keys.push(claim_key(task_id));
+        conn.del::<_, ()>(keys).await?;
+        Ok(())
+    }
+
+    /// A raw task result kept in the bucket as `name`, streamed
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    pub async fn open_result(&self, task_id: &str, name: &str) -> Result<Option<Body>, ApiError> {
+        match self {
+            AttachmentStore::Redis => Err(no_result_bucket()),
+            #[cfg(feature = "s3")]
+            AttachmentStore::S3 { store, prefix } => {
+                use object_store::ObjectStore;
+
+                match store.get(&result_path(prefix, task_id, name)).await {
+                    Ok(object) => Ok(Some(Body::from_stream(object.into_stream()))),
+                    Err(object_store::Error::NotFound { .. }) => Ok(None),
+                    Err(e) => Err(storage_error(e)),
+                }
+            }
+        }
+    }
+
+    /// Drop a raw task result kept in the bucket as `name`
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    pub async fn remove_result(&self, task_id: &str, name: &str) -> Result<(), ApiError> {
+        match self {
+            AttachmentStore::Redis => Err(no_result_bucket()),
+            #[cfg(feature = "s3")]
+            AttachmentStore::S3 { store, prefix } => {
+                use object_store::ObjectStore;
+
+                match store.delete(&result_path(prefix, task_id, name)).await {
+                    Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+                    Err(e) => Err(storage_error(e)),
+                }
+            }
+        }
+    }
+}
+
+/// Error answered for results in object storage without a bucket
+fn no_result_bucket() -> ApiError {
+    ApiError::unavailable(
+        "object_storage_unavailable",
+        "Results in object storage need attachments.backend = \"s3\"",
+    )
+}
+
+#[cfg(feature = "s3")]
+fn object_path(prefix: &str, task_id: &str, attachment_id: &str) -> object_store::path::Path {
+    object_store::path::Path::from(format!("{}{}/{}", prefix, task_id, attachment_id))
+}
+
+#[cfg(feature = "s3")]
+fn result_path(prefix: &str, task_id: &str, name: &str) -> object_store::path::Path {
+    object_store::path::Path::from(format!("{}{}/result/{}", prefix, task_id, name))
+}
+
+/// Contents on their way to the store
+enum BlobWriter {
+    Redis(Vec<u8>),
+    #[cfg(feature = "s3")]
+    S3(object_store::WriteMultipart),
+}
+
+impl BlobWriter {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), ApiError> {
+        match self {
+            BlobWriter::Redis(buffer) => buffer.extend_from_slice(chunk),
+            #[cfg(feature = "s3")]
+            BlobWriter::S3(upload) => {
+                // Hold back while a few parts are already in flight
+                upload.wait_for_capacity(4).await.map_err(storage_error)?;
+                upload.write(chunk);
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(
+        self,
+        conn: &mut redis::aio::Connection,
+        key: &str,
+        ttl_secs: u64,
+    ) -> Result<(), ApiError> {
+        match self {
+            BlobWriter::Redis(buffer) => conn.set_ex(key, buffer, ttl_secs).await?,
+            #[cfg(feature = "s3")]
+            BlobWriter::S3(upload) => {
+                upload.finish().await.map_err(storage_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn abort(self) {
+        match self {
+            BlobWriter::Redis(_) => {}
+            #[cfg(feature = "s3")]
+            BlobWriter::S3(upload) => {
+                if let Err(e) = upload.abort().await {
+                    warn!("Failed to abort attachment upload: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Metadata of a task's attachments, as listed in its task record
+pub async fn list(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+) -> Result<Vec<Value>, ApiError> {
+    let stored: Vec<String> = conn.hvals(meta_key(task_id)).await?;
+    let mut attachments = stored
+        .iter()
+        .map(|m| serde_json::from_str::<Value>(m))
+        .collect::<Result<Vec<_>, _>>()?;
+    attachments.sort_by(|a, b| a["uploaded_at"].as_str().cmp(&b["uploaded_at"].as_str()));
+    Ok(attachments)
+}
+
+/// The final path component of a client-supplied file name
+fn clean_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    name.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect()
+}
+
+/// Reserve a slot for one attachment and store it
+#[allow(clippy::too_many_arguments)]
+async fn store_attachment<S>(
+    state: &AppState,
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    name: String,
+    content_type: String,
+    uploader: Option<&Principal>,
+    settings: &AttachmentSettings,
+    chunks: S,
+) -> Result<Value, ApiError>
+where
+    S: Stream<Item = Result<Bytes, ApiError>> + Unpin,
+{
+    let privileged = uploader.is_some_and(|p| p.role.is_privileged());
+    let uploaded_by = uploader.map(|p| p.key_id.as_str());
+    let reserved: i64 = redis::Script::new(RESERVE_SCRIPT)
+        .key(claim_key(task_id))
+        .arg(uploaded_by.unwrap_or_default())
+        .arg(settings.max_per_task)
+        .arg(settings.ttl_secs)
+        .arg(if privileged { "1" } else { "0" })
+        .invoke_async(conn)
+        .await?;
+    match reserved {
+        -1 => {
+            return Err(ApiError::forbidden(
+                "Attachments of this task belong to another caller",
+            ))
+        }
+        -2 => {
+            return Err(ApiError::unprocessable(
+                "too_many_attachments",
+                format!(
+                    "A task may have at most {} attachments",
+                    settings.max_per_task
+                ),
+            ))
+        }
+        _ => {}
+    }
+
+    let stored = write_attachment(
+        state,
+        conn,
+        task_id,
+        name,
+        content_type,
+        uploaded_by,
+        settings,
+        chunks,
+    )
+    .await;
+    if stored.is_err() {
+        // Give the slot back to the next upload
+        let released: redis::RedisResult<()> =
+            conn.hincr(claim_key(task_id), "reserved", -1).await;
+        if let Err(e) = released {
+            error!("Failed to release attachment slot of task {}: {}", task_id, e);
+        }
+    }
+    stored
+}
+
+/// Stream one attachment to the store and record its metadata
+#[allow(clippy::too_many_arguments)]
+async fn write_attachment<S>(
+    state: &AppState,
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    name: String,
+    content_type: String,
+    uploaded_by: Option<&str>,
+    settings: &AttachmentSettings,
+    mut chunks: S,
+) -> Result<Value, ApiError>
+where
+    S: Stream<Item = Result<Bytes, ApiError>> + Unpin,
+{
+    let attachment_id = uuid::Uuid::new_v4().simple().to_string();
+    let mut writer = state.attachments.writer(task_id, &attachment_id).await?;
+    let mut sha256 = digest::Context::new(&digest::SHA256);
+    let mut size = 0u64;
+    let written = async {
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            if size > settings.max_bytes {
+                return Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "attachment_too_large",
+                    format!("Attachments may be at most {} bytes", settings.max_bytes),
+                ));
+            }
+            sha256.update(&chunk);
+            writer.write(&chunk).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = written {
+        writer.abort().await;
+        return Err(e);
+    }
+    writer
+        .finish(conn, &blob_key(task_id, &attachment_id), settings.ttl_secs)
+        .await?;
+
+    let meta = json!({
+        "id": attachment_id,
+        "name": name,
+        "content_type": content_type,
+        "size": size,
+        "sha256": hex::encode(sha256.finish().as_ref()),
+        "uploaded_by": uploaded_by,
+        "uploaded_at": chrono::Utc::now().to_rfc3339(),
+        "url": format!("/task/{}/attachments/{}", task_id, attachment_id),
+    });
+    let key = meta_key(task_id);
+    conn.hset::<_, _, _, ()>(&key, &attachment_id, meta.to_string())
+        .await?;
+    conn.expire::<_, ()>(&key, settings.ttl_secs as i64).await?;
+    info!(
+        "Stored attachment {} of task {} ({} bytes)",
+        attachment_id, task_id, size
+    );
+    Ok(meta)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    /// Name of a non-multipart upload
+    name: Option<String>,
+}
+
+/// Upload attachments for a task that has not been submitted yet
+pub async fn upload_attachments(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    Query(query): Query<UploadQuery>,
+    request: Request,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    if !validation::is_valid_task_id(&task_id) {
+        return Err(ApiError::bad_request(
+            "invalid_task_id",
+            "task_id may only contain letters, digits, '-', '_' and '.'",
+        ));
+    }
+    let priority = principal
+        .as_ref()
+        .is_some_and(|Extension(p)| p.role.is_privileged());
+    if state.attachments.backend() == AttachmentBackend::Redis
+        && !state.memory_guard.admits(priority)
+    {
+        warn!("Attachment for task {} rejected by memory guard", task_id);
+        return Err(ApiError::unavailable(
+            "overloaded",
+            "The gateway is shedding load, retry later",
+        )
+        .with_retry_after(30));
+    }
+
+    let mut conn = state.redis_client.get_async_connection().await?;
+    let submitted: bool = conn.exists(format!("task:{}", task_id)).await?;
+    if submitted {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "task_exists",
+            format!("Task {} was already submitted", task_id),
+        ));
+    }
+
+    let settings = state.config.current().attachments.clone();
+    let uploader = principal.as_ref().map(|Extension(p)| p);
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let mut stored = Vec::new();
+    if content_type.starts_with("multipart/form-data") {
+        let body_error = |e: axum::extract::multipart::MultipartError| {
+            ApiError::from(ValidationError::body(e.status(), e.body_text()))
+        };
+        let mut multipart = Multipart::from_request(request, &state)
+            .await
+            .map_err(|e| ValidationError::body(e.status(), e.body_text()))?;
+        while let Some(field) = multipart.next_field().await.map_err(body_error)? {
+            let name = field.file_name().or(field.name()).map(clean_name);
+            let field_type = field
+                .content_type()
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let meta = store_attachment(
+                &state,
+                &mut conn,
+                &task_id,
+                name.unwrap_or_default(),
+                field_type,
+                uploader,
+                &settings,
+                field.map_err(body_error),
+            )
+            .await?;
+            stored.push(meta);
+        }
+    } else {
+        let chunks = request.into_body().into_data_stream().map_err(|e| {
+            ApiError::bad_request("invalid_body", format!("Failed to read body: {}", e))
+        });
+        let meta = store_attachment(
+            &state,
+            &mut conn,
+            &task_id,
+            query.name.as_deref().map(clean_name).unwrap_or_default(),
+            content_type,
+            uploader,
+            &settings,
+            chunks,
+        )
+        .await?;
+        stored.push(meta);
+    }
+
+    if stored.is_empty() {
+        return Err(ApiError::bad_request(
+            "no_attachments",
+            "The multipart body has no parts",
+        ));
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "task_id": task_id, "attachments": stored })),
+    ))
+}
+
+/// Whether `principal` may read an attachment uploaded, or a result
+/// submitted, by `uploaded_by`. Agents running the task, operators and
+/// viewers may read any; anonymous uploads are as open as the tasks they
+/// belong to.
+pub fn may_read(principal: Option<&Principal>, uploaded_by: Option<&str>) -> bool {
+    match (principal, uploaded_by) {
+        (_, None) => true,
+        (Some(p), _) if p.role.reads_all() => true,
+        (Some(p), Some(owner)) => p.key_id == owner,
+        (None, Some(_)) => false,
+    }
+}
+
+/// Download an attachment's contents
+pub async fn get_attachment(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path((task_id, attachment_id)): Path<(String, String)>,
+) -> Result<Response, ApiError> {
+    let not_found = || ApiError::not_found(format!("Attachment {} not found", attachment_id));
+
+    let mut conn = state.redis_client.get_async_connection().await?;
+    let meta: Option<String> = conn.hget(meta_key(&task_id), &attachment_id).await?;
+    let meta: Value = serde_json::from_str(&meta.ok_or_else(not_found)?)?;
+
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    if !may_read(principal, meta["uploaded_by"].as_str()) {
+        return Err(match principal {
+            Some(_) => ApiError::forbidden("Attachment belongs to another caller"),
+            None => ApiError::unauthorized("Missing bearer token"),
+        });
+    }
+
+    let body = state
+        .attachments
+        .open(&mut conn, &task_id, &attachment_id)
+        .await?
+        .ok_or_else(not_found)?;
+
+    let mut headers = HeaderMap::new();
+    let content_type = meta["content_type"].as_str().unwrap_or_default();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type)
+            .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+    );
+    if let Some(size) = meta["size"].as_u64() {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    }
+    if let Some(disposition) = disposition(meta["name"].as_str().unwrap_or_default()) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok((headers, body).into_response())
+}
+
+/// `Content-Disposition` offering a download as `name`, reduced to the
+/// characters a quoted filename may hold
+pub fn disposition(name: &str) -> Option<HeaderValue> {
+    let filename: String = name
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\\') || *c == ' ')
+        .collect();
+    HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).ok()
+}
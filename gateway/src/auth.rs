@@ -0,0 +1,299 @@
+//! Authentication and authorization for gateway routes.
+//!
+//! Callers present `Authorization: Bearer <token>`. The `ADMIN_TOKEN` secret
+//! maps to an admin principal. With `auth.jwt_secret` set, a JWT signed
+//! with it (HS256) maps to the principal its claims name: `sub` as the key
+//! id and `role`, with `exp` required and `iss` matching `auth.jwt_issuer`
+//! when that is set. Any other token is looked up in Redis under
+//! `apikey:{sha256(token)}`, whose value is `{"id": "...", "role": "..."}`
+//! with role `admin`, `operator`, `submitter`, `viewer` or `agent`.
+//! Requests without a token are anonymous.
+//!
+//! Each role is held to a route policy on top of the checks handlers make:
+//! only admins reach `/admin/*`, and viewers may only `GET`.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use redis::AsyncCommands;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::ApiError;
+use crate::AppState;
+
+/// Privilege level attached to an API key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Operator,
+    Submitter,
+    /// Read-only access to tasks, results and agents
+    Viewer,
+    /// Agent workers, which may register and heartbeat
+    Agent,
+}
+
+impl Role {
+    /// Roles whose tasks run in the reserved priority lane
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Role::Admin | Role::Operator)
+    }
+
+    /// Roles that may read every caller's tasks, results and attachments
+    pub fn reads_all(&self) -> bool {
+        matches!(
+            self,
+            Role::Agent | Role::Operator | Role::Admin | Role::Viewer
+        )
+    }
+
+    /// Whether the route policy lets the role call `method` on `path`
+    pub fn permits(&self, method: &Method, path: &str) -> bool {
+        let admin_route = path == "/admin" || path.starts_with("/admin/");
+        match self {
+            Role::Admin => true,
+            _ if admin_route => false,
+            Role::Viewer => matches!(*method, Method::GET | Method::HEAD),
+            Role::Operator | Role::Submitter | Role::Agent => true,
+        }
+    }
+}
+
+/// Authenticated caller
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Principal {
+    pub key_id: String,
+    pub role: Role,
+}
+
+/// Stored API key record
+#[derive(Debug, Deserialize)]
+struct ApiKeyRecord {
+    id: String,
+    role: Role,
+}
+
+/// Claims of a JWT bearer token; `exp` and `iss` are checked on decoding
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    sub: String,
+    role: Role,
+}
+
+/// Redis key for an API key, addressed by the SHA-256 of the raw token
+pub fn api_key_redis_key(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    format!("apikey:{}", hex::encode(hash.as_ref()))
+}
+
+/// Resolve a bearer token to a principal, or `None` if it is unknown
+pub async fn resolve_token(state: &AppState, token: &str) -> Result<Option<Principal>, ApiError> {
+    let config = state.config.current();
+    if let Some(admin) = config.auth.admin_token.as_deref() {
+        if constant_time_eq(token.as_bytes(), admin.as_bytes()) {
+            return Ok(Some(Principal {
+                key_id: "admin".to_string(),
+                role: Role::Admin,
+            }));
+        }
+    }
+
+    if let (Some(secret), 3) = (&config.auth.jwt_secret, token.split('.').count()) {
+        let claims = verify_jwt(token, secret, config.auth.jwt_issuer.as_deref())
+            .map_err(|e| ApiError::unauthorized(format!("Invalid JWT: {}", e)))?;
+        return Ok(Some(Principal {
+            key_id: claims.sub,
+            role: claims.role,
+        }));
+    }
+
+    let mut conn = state.redis_client.get_async_connection().await?;
+    let record: Option<String> = conn.get(api_key_redis_key(token)).await?;
+
+    Ok(record
+        .and_then(|r| serde_json::from_str::<ApiKeyRecord>(&r).ok())
+        .map(|r| Principal {
+            key_id: r.id,
+            role: r.role,
+        }))
+}
+
+/// The claims of an HS256 `token` signed with `secret`, if it is current
+fn verify_jwt(token: &str, secret: &str, issuer: Option<&str>) -> Result<JwtClaims, String> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    if let Some(issuer) = issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    let key = DecodingKey::from_secret(secret.as_bytes());
+    match jsonwebtoken::decode::<JwtClaims>(token, &key, &validation) {
+        Ok(data) => Ok(data.claims),
+        Err(e) => Err(match e.kind() {
+            ErrorKind::InvalidSignature => "bad signature".to_string(),
+            ErrorKind::ExpiredSignature => "token expired".to_string(),
+            ErrorKind::InvalidIssuer => "wrong issuer".to_string(),
+            ErrorKind::InvalidAlgorithm => "only HS256 tokens are accepted".to_string(),
+            _ => e.to_string(),
+        }),
+    }
+}
+
+/// Refuse `principal` calls to `method` on `path` the route policy forbids
+pub fn authorize(principal: &Principal, method: &Method, path: &str) -> Result<(), ApiError> {
+    if principal.role.permits(method, path) {
+        return Ok(());
+    }
+    warn!(
+        "Refused {} {} to {} ({:?})",
+        method, path, principal.key_id, principal.role
+    );
+    Err(if path.starts_with("/admin") {
+        ApiError::forbidden("Admin role required")
+    } else {
+        ApiError::forbidden(format!("Role may not call {} {}", method, path))
+    })
+}
+
+/// Middleware attaching a [`Principal`] extension for authenticated callers.
+///
+/// Anonymous requests pass through; a token that does not resolve, or whose
+/// role the route policy refuses, is rejected. The principal is attached to
+/// the response as well, for the [access log](crate::access_log).
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(token) = bearer_token(&req) else {
+        return Ok(next.run(req).await);
+    };
+    let principal = resolve_token(&state, token)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("Unknown API key"))?;
+    authorize(&principal, req.method(), req.uri().path())?;
+    req.extensions_mut().insert(principal.clone());
+    let mut response = next.run(req).await;
+    response.extensions_mut().insert(principal);
+    Ok(response)
+}
+
+/// Middleware for `/admin` routes, admitting the principals the route policy
+/// lets through (admins) attached as a [`Principal`]
+pub async fn require_admin(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let token = bearer_token(&req).ok_or_else(|| ApiError::unauthorized("Missing bearer token"))?;
+    match resolve_token(&state, token).await? {
+        Some(principal) => {
+            authorize(&principal, req.method(), req.uri().path())?;
+            req.extensions_mut().insert(principal.clone());
+            let mut response = next.run(req).await;
+            response.extensions_mut().insert(principal);
+            Ok(response)
+        }
+        None => {
+            warn!("Rejected admin request to {}", req.uri().path());
+            Err(ApiError::unauthorized("Unknown API key"))
+        }
+    }
+}
+
+/// Extract the token from an `Authorization: Bearer` header
+pub fn bearer_token(req: &Request) -> Option<&str> {
+    bearer_token_from(req.headers())
+}
+
+/// Like [`bearer_token`], for headers outside an HTTP request (gRPC metadata)
+pub fn bearer_token_from(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Compare secrets without short-circuiting on the first mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn jwt(claims: serde_json::Value, secret: &str) -> String {
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn route_policy_by_role() {
+        let cases = [
+            (Role::Admin, Method::POST, "/admin/purge", true),
+            (Role::Operator, Method::GET, "/admin/purge", false),
+            (Role::Operator, Method::DELETE, "/task/t1", true),
+            (Role::Submitter, Method::POST, "/task", true),
+            (Role::Viewer, Method::GET, "/task/t1", true),
+            (Role::Viewer, Method::GET, "/agents", true),
+            (Role::Viewer, Method::POST, "/task", false),
+            (Role::Viewer, Method::DELETE, "/task/t1", false),
+            (Role::Viewer, Method::GET, "/admin/telegram/health", false),
+            (Role::Agent, Method::POST, "/agents/register", true),
+        ];
+        for (role, method, path, permitted) in cases {
+            assert_eq!(
+                role.permits(&method, path),
+                permitted,
+                "{:?} {} {}",
+                role,
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_current_jwts() {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let token = jwt(
+            serde_json::json!({"sub": "dash", "role": "viewer", "exp": exp, "iss": "idp"}),
+            SECRET,
+        );
+        let claims = verify_jwt(&token, SECRET, Some("idp")).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.role), ("dash", Role::Viewer));
+        assert_eq!(
+            verify_jwt(&token, SECRET, Some("other")).unwrap_err(),
+            "wrong issuer"
+        );
+    }
+
+    #[test]
+    fn refuses_forged_and_expired_jwts() {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let claims = serde_json::json!({"sub": "x", "role": "admin", "exp": exp});
+        let forged = jwt(claims, "another secret, just as long......");
+        assert_eq!(
+            verify_jwt(&forged, SECRET, None).unwrap_err(),
+            "bad signature"
+        );
+
+        let expired = serde_json::json!({"sub": "x", "role": "admin", "exp": exp - 600});
+        let expired = jwt(expired, SECRET);
+        assert_eq!(
+            verify_jwt(&expired, SECRET, None).unwrap_err(),
+            "token expired"
+        );
+    }
+}
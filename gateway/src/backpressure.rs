@@ -0,0 +1,152 @@
+//! Queue backpressure.
+//!
+//! A background loop samples the depth of the agent queues. While
+//! `agent:queue` and the queues of every registered capability,
+//! `agent:queue:{capability}`, hold more than `max_depth` tasks between them
+//! the gateway sheds new submissions: regular ones only by default, so
+//! operators can still get work through the priority lanes, or every one
+//! with `shed = "all"`. Shed requests
+//! are answered with 503 and `Retry-After`; work that could not be picked up
+//! in time is better refused at the door than left to rot in the queue.
+
+use redis::Client;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{BackpressureSettings, ShedMode};
+use crate::memory_guard::AdmissionLevel;
+use crate::metrics::{Counter, Gauge};
+use crate::queue::TaskQueue;
+use crate::{agent_registry, queue_for};
+
+/// Shared admission state updated by the sampling loop
+#[derive(Debug)]
+pub struct QueueGuard {
+    level: AtomicU8,
+    /// Regular queue depth past which submissions are shed; off when unset
+    max_depth: Option<u64>,
+    mode: ShedMode,
+    retry_after_secs: u64,
+    interval: Duration,
+    pub depth: Gauge,
+    pub priority_depth: Gauge,
+    pub shed: Counter,
+}
+
+impl QueueGuard {
+    pub fn from_config(settings: &BackpressureSettings) -> Self {
+        Self {
+            level: AtomicU8::new(AdmissionLevel::Open as u8),
+            max_depth: settings.max_depth,
+            mode: settings.shed,
+            retry_after_secs: settings.retry_after_secs,
+            interval: Duration::from_secs(settings.interval_secs),
+            depth: Gauge::default(),
+            priority_depth: Gauge::default(),
+            shed: Counter::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_depth.is_some()
+    }
+
+    pub fn level(&self) -> AdmissionLevel {
+        AdmissionLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    /// Seconds shed clients are told to wait before retrying
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after_secs
+    }
+
+    /// Whether a submission may be accepted, counting shed ones
+    pub fn admits(&self, priority: bool) -> bool {
+        let admitted = match self.level() {
+            AdmissionLevel::Open => true,
+            AdmissionLevel::PriorityOnly => priority,
+            AdmissionLevel::Closed => false,
+        };
+        if !admitted {
+            self.shed.inc();
+        }
+        admitted
+    }
+
+    /// Sample queue depths once and update the admission level
+    async fn sample(&self, redis_client: &Client, task_queue: &TaskQueue) -> anyhow::Result<()> {
+        let Some(max_depth) = self.max_depth else {
+            return Ok(());
+        };
+
+        let mut conn = redis_client.get_async_connection().await?;
+        let (depth, priority_depth) = depths(&mut conn, task_queue).await?;
+        self.depth.set(depth as i64);
+        self.priority_depth.set(priority_depth as i64);
+
+        let level = if depth <= max_depth {
+            AdmissionLevel::Open
+        } else {
+            match self.mode {
+                ShedMode::LowPriority => AdmissionLevel::PriorityOnly,
+                ShedMode::All => AdmissionLevel::Closed,
+            }
+        };
+
+        let previous = AdmissionLevel::from_u8(self.level.swap(level as u8, Ordering::Relaxed));
+        if previous != level {
+            match level {
+                AdmissionLevel::Open => {
+                    info!("Agent queues back to {} tasks, admission reopened", depth)
+                }
+                _ => warn!(
+                    "Agent queues at {} tasks (limit {}), shedding {} submissions",
+                    depth,
+                    max_depth,
+                    self.mode.as_str()
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The agent queues, regular and priority lanes alternating
+pub async fn agent_queues(conn: &mut redis::aio::Connection) -> redis::RedisResult<Vec<String>> {
+    let capabilities = agent_registry::registered_capabilities(conn).await?;
+    let lanes = std::iter::once(None).chain(capabilities.iter().map(|c| Some(c.as_str())));
+    Ok(lanes
+        .flat_map(|lane| [queue_for(lane, false), queue_for(lane, true)])
+        .collect())
+}
+
+/// Tasks waiting in the regular and the priority lanes
+pub async fn depths(
+    conn: &mut redis::aio::Connection,
+    task_queue: &TaskQueue,
+) -> anyhow::Result<(u64, u64)> {
+    let queues = agent_queues(conn).await?;
+    let queues: Vec<&str> = queues.iter().map(String::as_str).collect();
+    let depths = task_queue.depths(conn, &queues).await?;
+    let depth = depths.iter().step_by(2).sum::<u64>();
+    let priority_depth = depths.iter().skip(1).step_by(2).sum::<u64>();
+    Ok((depth, priority_depth))
+}
+
+/// Start the queue sampling loop in a background task
+pub fn start_queue_guard(redis_client: Arc<Client>, task_queue: TaskQueue, guard: Arc<QueueGuard>) {
+    if !guard.is_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = guard.sample(&redis_client, &task_queue).await {
+                warn!("Agent queue sampling failed: {}", e);
+            }
+            tokio::time::sleep(guard.interval).await;
+        }
+    });
+}
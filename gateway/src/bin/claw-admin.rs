@@ -0,0 +1,752 @@
+//! `claw-admin`: operations CLI for the secure gateway.
+//!
+//! Reads the same `REDIS_HOST`/`REDIS_PORT`/`REDIS_PASSWORD` variables as the
+//! gateway, and `CLAW_GATEWAY_URL` with `ADMIN_TOKEN` for the HTTP API, so it
+//! runs unchanged from CI jobs and from an on-call laptop with the
+//! deployment's `.env` loaded. `--json` prints machine-readable output.
+//! With `ENCRYPTION_SECRET` (and `ENCRYPTION_KEY_ID`) it reads and writes
+//! encrypted task records like the gateway.
+//!
+//! Requeueing and draining work on the Redis task queues; with
+//! `QUEUE_BACKEND=nats` they refuse to run.
+
+use anyhow::{bail, Context};
+use clap::{Args, Parser, Subcommand};
+use claw_core::keys::{self, queue_for};
+use claw_core::task_state::{self, TransitionError};
+use claw_core::{envelope, schema, store};
+use redis::AsyncCommands;
+use ring::digest;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+// Keep in step with the gateway's Redis layout (config/redis/README.md)
+const DEADLINES_KEY: &str = "watchdog:deadlines";
+
+/// Statuses of tasks that ended without a result, eligible for requeueing
+const DEAD_STATUSES: [&str; 3] = ["failed", "timed_out", "orphaned"];
+
+/// Statuses after which a task record no longer changes
+const FINAL_STATUSES: [&str; 5] = ["completed", "failed", "timed_out", "orphaned", "deleted"];
+
+/// Roles an API key may hold
+const ROLES: [&str; 5] = ["admin", "operator", "submitter", "viewer", "agent"];
+
+#[derive(Parser)]
+#[command(name = "claw-admin", about = "Operate a secure gateway deployment")]
+struct Cli {
+    #[command(flatten)]
+    conn: Connection,
+    /// Print JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct Connection {
+    #[arg(long, env = "REDIS_HOST", default_value = "localhost")]
+    redis_host: String,
+    #[arg(long, env = "REDIS_PORT", default_value_t = 6379)]
+    redis_port: u16,
+    #[arg(
+        long,
+        env = "REDIS_PASSWORD",
+        default_value = "",
+        hide_env_values = true
+    )]
+    redis_password: String,
+    #[arg(
+        long,
+        env = "CLAW_GATEWAY_URL",
+        default_value = "http://localhost:8080"
+    )]
+    gateway_url: String,
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    #[arg(long, env = "QUEUE_BACKEND", default_value = "redis")]
+    queue_backend: String,
+    /// Set when the gateway mirrors tasks into PostgreSQL
+    #[arg(long, env = "HISTORY_DATABASE_URL", hide = true)]
+    history_database_url: Option<String>,
+    /// Set when the gateway exports task events to Kafka
+    #[arg(long, env = "EVENTS_KAFKA_BROKERS", hide = true)]
+    events_kafka_brokers: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List tasks through the gateway API
+    Tasks {
+        /// Only tasks with this status (needs task history)
+        #[arg(long)]
+        status: Option<String>,
+        /// Only tasks created at or after this RFC 3339 time (needs task history)
+        #[arg(long)]
+        from: Option<String>,
+        /// Only tasks created before this RFC 3339 time (needs task history)
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Show a task's record, result and attachments
+    Task { task_id: String },
+    /// Put failed, timed out or orphaned tasks back on their queue
+    Requeue {
+        task_ids: Vec<String>,
+        /// Requeue every task with this status instead of naming them
+        #[arg(long, conflicts_with = "task_ids")]
+        status: Option<String>,
+        /// Show what would be requeued without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Delete settled tasks older than a cutoff, and index entries left behind
+    Purge {
+        #[arg(long)]
+        older_than_days: u64,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manage API keys
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Upgrade task records written by older gateways to the current schema
+    Migrate {
+        /// Count what would be upgraded without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Wait for the agent queues to empty, or discard what is queued
+    Drain {
+        /// Give up, exiting non-zero, after this many seconds
+        #[arg(long, default_value_t = 600)]
+        timeout_secs: u64,
+        /// Remove queued tasks and mark them failed instead of waiting
+        #[arg(long)]
+        discard: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// List API keys by id and role
+    List,
+    /// Create an API key and print its token, which is not stored
+    Create {
+        #[arg(long)]
+        id: String,
+        #[arg(long, value_parser = ROLES)]
+        role: String,
+    },
+    /// Delete every API key with this id
+    Revoke {
+        #[arg(long)]
+        id: String,
+    },
+}
+
+type Conn = redis::aio::Connection;
+
+/// Redis key for an API key, addressed by the SHA-256 of the raw token
+fn api_key_redis_key(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    format!("apikey:{}", hex::encode(hash.as_ref()))
+}
+
+/// Every key matching `pattern`, without blocking Redis like `KEYS` would
+async fn scan(conn: &mut Conn, pattern: &str) -> anyhow::Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut iter: redis::AsyncIter<String> = conn.scan_match(pattern).await?;
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+async fn load_task(conn: &mut Conn, task_id: &str) -> anyhow::Result<Option<Value>> {
+    store::get_record(conn, task_id)
+        .await
+        .with_context(|| format!("task {} is not a readable record", task_id))
+}
+
+/// The agent queue lists, priority lanes included
+async fn queue_keys(conn: &mut Conn) -> anyhow::Result<Vec<String>> {
+    let mut queues = Vec::new();
+    for key in scan(conn, "agent:queue*").await? {
+        let kind: String = redis::cmd("TYPE").arg(&key).query_async(conn).await?;
+        if kind == "list" {
+            queues.push(key);
+        }
+    }
+    queues.sort();
+    Ok(queues)
+}
+
+fn print(json: bool, value: &Value, text: impl FnOnce() -> String) {
+    if json {
+        println!("{}", value);
+    } else {
+        println!("{}", text());
+    }
+}
+
+async fn list_tasks(
+    cli: &Cli,
+    status: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<()> {
+    let mut query = vec![("limit", limit.to_string())];
+    for (name, value) in [("status", status), ("from", from), ("to", to)] {
+        if let Some(value) = value {
+            query.push((name, value.to_string()));
+        }
+    }
+    let url = format!("{}/tasks", cli.conn.gateway_url.trim_end_matches('/'));
+    let mut request = reqwest::Client::new().get(&url).query(&query);
+    if let Some(token) = &cli.conn.admin_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.context("gateway unreachable")?;
+    let status_code = response.status();
+    let body: Value = response.json().await?;
+    if !status_code.is_success() {
+        bail!("gateway answered {}: {}", status_code, body["message"]);
+    }
+
+    print(cli.json, &body, || {
+        let tasks = body["tasks"].as_array().cloned().unwrap_or_default();
+        tasks
+            .iter()
+            .map(|t| {
+                format!(
+                    "{}\t{}\t{}",
+                    t["task_id"].as_str().unwrap_or_default(),
+                    t["status"].as_str().unwrap_or_default(),
+                    t["created_at"].as_str().unwrap_or("-"),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    });
+    Ok(())
+}
+
+async fn inspect_task(cli: &Cli, conn: &mut Conn, task_id: &str) -> anyhow::Result<()> {
+    let Some(task) = load_task(conn, task_id).await? else {
+        bail!("task {} not found in Redis", task_id);
+    };
+    let result = store::get_result(conn, task_id).await?;
+    let error = store::get_error(conn, task_id).await?;
+    let attachments: Vec<String> = conn.hvals(format!("attachments:{}", task_id)).await?;
+    let attachments = attachments
+        .iter()
+        .map(|a| serde_json::from_str(a))
+        .collect::<Result<Vec<Value>, _>>()?;
+    let deadline: Option<i64> = conn.zscore(DEADLINES_KEY, task_id).await?;
+
+    let details = json!({
+        "task_id": task_id,
+        "task": task,
+        "result": result,
+        "error": error,
+        "attachments": attachments,
+        "deadline": deadline,
+    });
+    print(cli.json, &details, || {
+        serde_json::to_string_pretty(&details).unwrap_or_default()
+    });
+    Ok(())
+}
+
+async fn requeue(
+    cli: &Cli,
+    conn: &mut Conn,
+    task_ids: &[String],
+    status: Option<&str>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    if cli.conn.queue_backend != "redis" {
+        bail!("requeue only supports QUEUE_BACKEND=redis");
+    }
+    let task_ids = match status {
+        Some(status) => {
+            if !DEAD_STATUSES.contains(&status) {
+                bail!("--status must be one of {}", DEAD_STATUSES.join(", "));
+            }
+            let mut matching = Vec::new();
+            for key in scan(conn, "task:*").await? {
+                let task_id = key.trim_start_matches("task:");
+                if let Some(task) = load_task(conn, task_id).await? {
+                    if task["status"] == status {
+                        matching.push(task_id.to_string());
+                    }
+                }
+            }
+            matching
+        }
+        None if task_ids.is_empty() => bail!("name task ids or pass --status"),
+        None => task_ids.to_vec(),
+    };
+
+    let mut requeued = Vec::new();
+    for task_id in &task_ids {
+        let Some(task) = load_task(conn, task_id).await? else {
+            eprintln!("{}: not found, skipped", task_id);
+            continue;
+        };
+        let status = task["status"].as_str().unwrap_or("unknown").to_string();
+        if !DEAD_STATUSES.contains(&status.as_str()) {
+            eprintln!("{}: status {}, skipped", task_id, status);
+            continue;
+        }
+        let queue = queue_for(
+            task["capability"].as_str(),
+            task["priority"].as_bool().unwrap_or(false),
+        );
+        requeued.push(json!({ "task_id": task_id, "was": status, "queue": queue }));
+        if dry_run {
+            continue;
+        }
+
+        let reset = task_state::transition(conn, task_id, "pending", |task| {
+            if let Some(record) = task.as_object_mut() {
+                for field in [
+                    "started_at",
+                    "agent_id",
+                    "error",
+                    "timed_out_at",
+                    "orphaned_at",
+                ] {
+                    record.remove(field);
+                }
+            }
+            task["requeued_at"] = chrono::Utc::now().to_rfc3339().into();
+            task["requeue_count"] = (task["requeue_count"].as_u64().unwrap_or(0) + 1).into();
+            if let Some(timeout) = task["timeout_secs"].as_i64() {
+                let deadline = chrono::Utc::now() + chrono::Duration::seconds(timeout);
+                task["deadline"] = deadline.to_rfc3339().into();
+            }
+            DEAD_STATUSES.contains(&task["status"].as_str().unwrap_or_default())
+        })
+        .await?;
+        let Some(task) = reset else {
+            eprintln!("{}: status changed meanwhile, skipped", task_id);
+            requeued.pop();
+            continue;
+        };
+        conn.del::<_, ()>(keys::error_key(task_id)).await?;
+        conn.lpush::<_, _, ()>(&queue, task_id).await?;
+        if let Some(timeout) = task["timeout_secs"].as_i64() {
+            let deadline = chrono::Utc::now().timestamp() + timeout;
+            conn.zadd::<_, _, _, ()>(DEADLINES_KEY, task_id, deadline)
+                .await?;
+        }
+        // Settled tasks are no longer synced or reported; pick them up again
+        if cli
+            .conn
+            .history_database_url
+            .as_deref()
+            .is_some_and(|u| !u.is_empty())
+        {
+            conn.sadd::<_, _, ()>("history:pending", task_id).await?;
+        }
+        if cli
+            .conn
+            .events_kafka_brokers
+            .as_deref()
+            .is_some_and(|b| !b.is_empty())
+        {
+            conn.hset::<_, _, _, ()>("events:tracked", task_id, "created")
+                .await?;
+        }
+    }
+
+    let summary = json!({ "dry_run": dry_run, "requeued": requeued });
+    print(cli.json, &summary, || {
+        let verb = if dry_run { "would requeue" } else { "requeued" };
+        let lines: Vec<String> = requeued
+            .iter()
+            .map(|r| format!("{} {} ({}) -> {}", verb, r["task_id"], r["was"], r["queue"]))
+            .collect();
+        lines
+            .into_iter()
+            .chain([format!("{} task(s)", requeued.len())])
+            .collect::<Vec<_>>()
+            .join("\n")
+    });
+    Ok(())
+}
+
+/// When a settled task last changed, falling back to its creation time
+fn settled_at(task: &Value) -> Option<chrono::DateTime<chrono::Utc>> {
+    [
+        "deleted_at",
+        "completed_at",
+        "timed_out_at",
+        "orphaned_at",
+        "created_at",
+    ]
+    .iter()
+    .find_map(|field| task[*field].as_str())
+    .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+    .map(|t| t.with_timezone(&chrono::Utc))
+}
+
+async fn purge(
+    cli: &Cli,
+    conn: &mut Conn,
+    older_than_days: u64,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(older_than_days as i64);
+
+    let mut purged = Vec::new();
+    for key in scan(conn, "task:*").await? {
+        let task_id = key.trim_start_matches("task:").to_string();
+        let Some(task) = load_task(conn, &task_id).await? else {
+            continue;
+        };
+        let has_result: bool = conn.exists(keys::result_key(&task_id)).await?;
+        let settled =
+            has_result || FINAL_STATUSES.contains(&task["status"].as_str().unwrap_or_default());
+        if !settled || settled_at(&task).is_none_or(|at| at >= cutoff) {
+            continue;
+        }
+        if !dry_run {
+            let mut keys = vec![
+                key.clone(),
+                keys::result_key(&task_id),
+                keys::error_key(&task_id),
+                format!("cache:pending:{}", task_id),
+                format!("attachments:{}", task_id),
+            ];
+            keys.extend(scan(conn, &format!("attachment:{}:*", task_id)).await?);
+            conn.del::<_, ()>(keys).await?;
+            if let Some(labels) = task["labels"].as_object() {
+                for (name, value) in labels {
+                    let set = format!("label:{}:{}", name, value.as_str().unwrap_or_default());
+                    conn.srem::<_, _, ()>(set, &task_id).await?;
+                }
+            }
+        }
+        purged.push(task_id);
+    }
+
+    // Index entries whose task is gone, whether purged here or expired
+    let mut dangling = 0;
+    let indexed: Vec<String> = conn.zrange(DEADLINES_KEY, 0, -1).await?;
+    let pending: Vec<String> = conn.smembers("history:pending").await?;
+    let tracked: Vec<String> = conn.hkeys("events:tracked").await?;
+    let forwarded: Vec<String> = conn.hkeys("federation:forwarded").await?;
+    for (index, ids) in [
+        (DEADLINES_KEY, indexed),
+        ("history:pending", pending),
+        ("events:tracked", tracked),
+        ("federation:forwarded", forwarded),
+    ] {
+        for task_id in ids {
+            let task_key = keys::task_key(&task_id);
+            let exists: bool = conn.exists(&task_key).await?;
+            if exists && !purged.contains(&task_id) {
+                continue;
+            }
+            dangling += 1;
+            if dry_run {
+                continue;
+            }
+            let mut cmd = match index {
+                DEADLINES_KEY => redis::cmd("ZREM"),
+                "history:pending" => redis::cmd("SREM"),
+                _ => redis::cmd("HDEL"),
+            };
+            cmd.arg(index)
+                .arg(&task_id)
+                .query_async::<_, ()>(conn)
+                .await?;
+        }
+    }
+
+    let summary = json!({
+        "dry_run": dry_run,
+        "cutoff": cutoff.to_rfc3339(),
+        "tasks": purged,
+        "index_entries": dangling,
+    });
+    print(cli.json, &summary, || {
+        let verb = if dry_run { "would purge" } else { "purged" };
+        format!(
+            "{} {} task(s) settled before {} and {} index entr(ies)",
+            verb,
+            purged.len(),
+            cutoff.to_rfc3339(),
+            dangling
+        )
+    });
+    Ok(())
+}
+
+async fn migrate(cli: &Cli, conn: &mut Conn, dry_run: bool) -> anyhow::Result<()> {
+    let mut migrated = Vec::new();
+    let mut current = 0;
+    let mut unreadable = 0;
+    for key in scan(conn, "task:*").await? {
+        let task_id = key.trim_start_matches("task:").to_string();
+        let raw: Option<String> = conn.get(&key).await?;
+        let Some(raw) = raw else {
+            continue;
+        };
+        let task = match envelope::decode(&raw) {
+            Ok(task) => task,
+            Err(e) => {
+                eprintln!("{}: unreadable, skipped: {}", task_id, e);
+                unreadable += 1;
+                continue;
+            }
+        };
+        if schema::version(&task) >= schema::CURRENT_VERSION {
+            current += 1;
+            continue;
+        }
+        if !dry_run {
+            // Written back in the current schema, without touching the status
+            match task_state::rewrite(conn, &task_id, |_| true).await {
+                Ok(_) => {}
+                Err(TransitionError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        migrated.push(task_id);
+    }
+
+    let summary = json!({
+        "dry_run": dry_run,
+        "schema_version": schema::CURRENT_VERSION,
+        "migrated": migrated,
+        "current": current,
+        "unreadable": unreadable,
+    });
+    print(cli.json, &summary, || {
+        let verb = if dry_run { "would migrate" } else { "migrated" };
+        format!(
+            "{} {} task(s) to schema version {}; {} already current, {} unreadable",
+            verb,
+            migrated.len(),
+            schema::CURRENT_VERSION,
+            current,
+            unreadable
+        )
+    });
+    Ok(())
+}
+
+async fn api_keys(conn: &mut Conn) -> anyhow::Result<Vec<(String, Value)>> {
+    let mut keys = Vec::new();
+    for key in scan(conn, "apikey:*").await? {
+        let record: Option<String> = conn.get(&key).await?;
+        if let Some(record) = record.and_then(|r| serde_json::from_str::<Value>(&r).ok()) {
+            keys.push((key, record));
+        }
+    }
+    keys.sort_by(|(_, a), (_, b)| a["id"].as_str().cmp(&b["id"].as_str()));
+    Ok(keys)
+}
+
+async fn manage_keys(cli: &Cli, conn: &mut Conn, command: &KeysCommand) -> anyhow::Result<()> {
+    match command {
+        KeysCommand::List => {
+            let keys = api_keys(conn).await?;
+            let listed: Vec<Value> = keys
+                .iter()
+                .map(|(key, record)| {
+                    json!({
+                        "id": record["id"],
+                        "role": record["role"],
+                        "hash": key.trim_start_matches("apikey:"),
+                    })
+                })
+                .collect();
+            print(cli.json, &Value::from(listed.clone()), || {
+                listed
+                    .iter()
+                    .map(|k| {
+                        format!(
+                            "{}\t{}",
+                            k["id"].as_str().unwrap_or_default(),
+                            k["role"].as_str().unwrap_or_default()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        }
+        KeysCommand::Create { id, role } => {
+            if api_keys(conn)
+                .await?
+                .iter()
+                .any(|(_, r)| r["id"] == id.as_str())
+            {
+                bail!("an API key with id {} already exists", id);
+            }
+            let token = format!("claw_{}", hex::encode(rand::random::<[u8; 24]>()));
+            let record = json!({ "id": id, "role": role });
+            conn.set::<_, _, ()>(api_key_redis_key(&token), record.to_string())
+                .await?;
+            let created = json!({ "id": id, "role": role, "token": token });
+            print(cli.json, &created, || {
+                format!("{}\n(store it now; only its hash is kept)", token)
+            });
+        }
+        KeysCommand::Revoke { id } => {
+            let mut revoked = 0;
+            for (key, record) in api_keys(conn).await? {
+                if record["id"] == id.as_str() {
+                    conn.del::<_, ()>(&key).await?;
+                    revoked += 1;
+                }
+            }
+            if revoked == 0 {
+                bail!("no API key with id {}", id);
+            }
+            let summary = json!({ "id": id, "revoked": revoked });
+            print(cli.json, &summary, || {
+                format!("revoked {} key(s) for {}", revoked, id)
+            });
+        }
+    }
+    Ok(())
+}
+
+async fn drain(cli: &Cli, conn: &mut Conn, timeout_secs: u64, discard: bool) -> anyhow::Result<()> {
+    if cli.conn.queue_backend != "redis" {
+        bail!("drain only supports QUEUE_BACKEND=redis");
+    }
+
+    if discard {
+        let mut discarded = Vec::new();
+        for queue in queue_keys(conn).await? {
+            while let Some(task_id) = conn.rpop::<_, Option<String>>(&queue, None).await? {
+                let discard = task_state::transition(conn, &task_id, "failed", |task| {
+                    task["error"] = "Discarded from the queue by claw-admin drain".into();
+                    true
+                })
+                .await;
+                match discard {
+                    Ok(_) | Err(TransitionError::NotFound) => {}
+                    Err(TransitionError::Illegal { from, .. }) => {
+                        eprintln!("{}: status {}, left as is", task_id, from);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                conn.zrem::<_, _, ()>(DEADLINES_KEY, &task_id).await?;
+                discarded.push(task_id);
+            }
+        }
+        let summary = json!({ "discarded": discarded });
+        print(cli.json, &summary, || {
+            format!("discarded {} queued task(s)", discarded.len())
+        });
+        return Ok(());
+    }
+
+    // Queued tasks plus those agents are still working on
+    let started = std::time::Instant::now();
+    loop {
+        let mut queued = 0;
+        for queue in queue_keys(conn).await? {
+            queued += conn.llen::<_, u64>(&queue).await?;
+        }
+        let mut in_flight = 0;
+        for key in scan(conn, "agent:tasks:*").await? {
+            in_flight += conn.scard::<_, u64>(&key).await?;
+        }
+        if queued + in_flight == 0 {
+            let summary = json!({ "drained": true, "waited_secs": started.elapsed().as_secs() });
+            print(cli.json, &summary, || "queues drained".to_string());
+            return Ok(());
+        }
+        if started.elapsed() >= Duration::from_secs(timeout_secs) {
+            bail!(
+                "{} queued and {} in-flight task(s) left after {}s",
+                queued,
+                in_flight,
+                timeout_secs
+            );
+        }
+        if !cli.json {
+            eprintln!("waiting: {} queued, {} in flight", queued, in_flight);
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    if let Some(version) = std::env::var("TASK_ENVELOPE_VERSION")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        envelope::set_write_version(version);
+    }
+    if let Some(secret) = std::env::var("ENCRYPTION_SECRET")
+        .ok()
+        .filter(|s| !s.is_empty())
+    {
+        let key_id = std::env::var("ENCRYPTION_KEY_ID").unwrap_or_else(|_| "1".to_string());
+        envelope::set_keys(&key_id, &secret, &Default::default())
+            .map_err(anyhow::Error::msg)
+            .context("ENCRYPTION_SECRET")?;
+    }
+
+    // The task listing goes through the API; everything else reads Redis
+    if let Command::Tasks {
+        status,
+        from,
+        to,
+        limit,
+    } = &cli.command
+    {
+        return list_tasks(
+            &cli,
+            status.as_deref(),
+            from.as_deref(),
+            to.as_deref(),
+            *limit,
+        )
+        .await;
+    }
+
+    let url = format!(
+        "redis://:{}@{}:{}",
+        cli.conn.redis_password, cli.conn.redis_host, cli.conn.redis_port
+    );
+    let mut conn = redis::Client::open(url)?
+        .get_async_connection()
+        .await
+        .context("Redis unreachable")?;
+
+    match &cli.command {
+        Command::Tasks { .. } => unreachable!("handled above"),
+        Command::Task { task_id } => inspect_task(&cli, &mut conn, task_id).await,
+        Command::Requeue {
+            task_ids,
+            status,
+            dry_run,
+        } => requeue(&cli, &mut conn, task_ids, status.as_deref(), *dry_run).await,
+        Command::Purge {
+            older_than_days,
+            dry_run,
+        } => purge(&cli, &mut conn, *older_than_days, *dry_run).await,
+        Command::Keys(command) => manage_keys(&cli, &mut conn, command).await,
+        Command::Migrate { dry_run } => migrate(&cli, &mut conn, *dry_run).await,
+        Command::Drain {
+            timeout_secs,
+            discard,
+        } => drain(&cli, &mut conn, *timeout_secs, *discard).await,
+    }
+}
@@ -0,0 +1,348 @@
+//! Announcements to every chat.
+//!
+//! `POST /admin/broadcast` sends `{"text"}` to every chat the channel
+//! adaptors have heard from: the chats of each Telegram bot and the numbers
+//! that wrote in over Twilio. `channels` (`telegram`, `twilio`) narrows it
+//! to some channels and `chat_types` (`private`, `group`, `supergroup`,
+//! `channel`) to some kinds of chat, Twilio conversations being private
+//! ones; `dry_run` only counts the recipients. Email is not broadcast to.
+//!
+//! Sends go out in the background, Telegram's through each bot's send
+//! throttle alongside its replies and Twilio's at `twilio.broadcast_per_sec`.
+//! `GET /admin/broadcast/:id` reports how many went out and which failed,
+//! for a week.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    Extension,
+};
+use chrono::Utc;
+use redis::AsyncCommands;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::telegram::{self, Announcer, Bot};
+use crate::throttle::{Rate, Throttle};
+use crate::{redis_connection, twilio, AppState};
+
+/// Prefix of the Redis keys holding broadcast reports, by broadcast id
+const REPORT_PREFIX: &str = "broadcast:";
+
+/// How long a broadcast report is kept
+const REPORT_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Sends between updates of the stored report
+const REPORT_EVERY: usize = 25;
+
+/// Failed recipients listed in a report; the rest are only counted
+const MAX_FAILURES_LISTED: usize = 1000;
+
+/// Longest announcement, the Bot API limit in UTF-16 code units
+const MAX_TEXT_UNITS: usize = 4096;
+
+const CHANNELS: &[&str] = &["telegram", "twilio"];
+
+const CHAT_TYPES: &[&str] = &["private", "group", "supergroup", "channel"];
+
+/// Body of `POST /admin/broadcast`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BroadcastRequest {
+    text: String,
+    /// Only these channels; every one when empty
+    #[serde(default)]
+    channels: Vec<String>,
+    /// Only chats of these types; every type when empty
+    #[serde(default)]
+    chat_types: Vec<String>,
+    /// Count the recipients without sending anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl BroadcastRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.text.trim().is_empty() {
+            return Err(ApiError::bad_request(
+                "invalid_broadcast",
+                "text is required",
+            ));
+        }
+        if self.text.encode_utf16().count() > MAX_TEXT_UNITS {
+            return Err(ApiError::bad_request(
+                "invalid_broadcast",
+                format!("text must be at most {} characters", MAX_TEXT_UNITS),
+            ));
+        }
+        if let Some(channel) = self
+            .channels
+            .iter()
+            .find(|c| !CHANNELS.contains(&c.as_str()))
+        {
+            return Err(ApiError::bad_request(
+                "invalid_broadcast",
+                format!(
+                    "Unknown channel {}, expected one of {:?}",
+                    channel, CHANNELS
+                ),
+            ));
+        }
+        if let Some(kind) = self
+            .chat_types
+            .iter()
+            .find(|t| !CHAT_TYPES.contains(&t.as_str()))
+        {
+            return Err(ApiError::bad_request(
+                "invalid_broadcast",
+                format!(
+                    "Unknown chat type {}, expected one of {:?}",
+                    kind, CHAT_TYPES
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn wants(&self, channel: &str, chat_type: &str) -> bool {
+        let listed =
+            |list: &[String], item: &str| list.is_empty() || list.iter().any(|i| i == item);
+        listed(&self.channels, channel) && listed(&self.chat_types, chat_type)
+    }
+}
+
+/// Someone a broadcast goes to
+#[derive(Debug, PartialEq)]
+enum Recipient {
+    /// A chat of the bot at this index of the gateway's bots
+    Telegram { bot: usize, chat_id: i64 },
+    /// A sender, written to from the gateway number they wrote to
+    Twilio { number: String, to: String },
+}
+
+impl Recipient {
+    fn describe(&self, bots: &[Bot]) -> Value {
+        match self {
+            Recipient::Telegram { bot, chat_id } => json!({
+                "channel": "telegram",
+                "bot": bots[*bot].label(),
+                "chat_id": chat_id,
+            }),
+            Recipient::Twilio { to, .. } => json!({"channel": "twilio", "to": to}),
+        }
+    }
+}
+
+/// Every chat `request` is for
+async fn recipients(
+    state: &AppState,
+    request: &BroadcastRequest,
+) -> Result<Vec<Recipient>, ApiError> {
+    let mut conn = redis_connection(state).await?;
+    let mut recipients = Vec::new();
+    for (bot, settings) in state.telegram_bots.iter().enumerate() {
+        for (chat_id, chat_type) in telegram::known_chats(&mut conn, settings).await? {
+            if request.wants("telegram", &chat_type) {
+                recipients.push(Recipient::Telegram { bot, chat_id });
+            }
+        }
+    }
+    if state.twilio.is_enabled() && request.wants("twilio", "private") {
+        for (to, number) in twilio::known_contacts(&mut conn).await? {
+            recipients.push(Recipient::Twilio { number, to });
+        }
+    }
+    Ok(recipients)
+}
+
+fn report_key(broadcast_id: &str) -> String {
+    format!("{}{}", REPORT_PREFIX, broadcast_id)
+}
+
+async fn store_report(conn: &mut redis::aio::Connection, report: &Value) -> redis::RedisResult<()> {
+    let key = report_key(report["broadcast_id"].as_str().unwrap_or_default());
+    conn.set_ex(key, report.to_string(), REPORT_TTL_SECS).await
+}
+
+// Send an announcement to every known chat, or those asked for (admin only)
+pub async fn start_broadcast(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<BroadcastRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    request.validate()?;
+    let recipients = recipients(&state, &request).await?;
+    let mut report = json!({
+        "broadcast_id": Uuid::new_v4().to_string(),
+        "state": "running",
+        "started_by": principal.key_id,
+        "started_at": Utc::now().to_rfc3339(),
+        "recipients": recipients.len(),
+        "sent": 0,
+        "failed": 0,
+        "failures": [],
+    });
+    if request.dry_run {
+        report["state"] = "dry_run".into();
+        return Ok((StatusCode::OK, Json(report)));
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    store_report(&mut conn, &report).await?;
+    info!(
+        "Broadcast {} to {} chats started by {}",
+        report["broadcast_id"],
+        recipients.len(),
+        principal.key_id
+    );
+    let broadcast = Broadcast {
+        state,
+        text: request.text,
+        recipients,
+    };
+    let started = report.clone();
+    tokio::spawn(async move { broadcast.run(started).await });
+    Ok((StatusCode::ACCEPTED, Json(report)))
+}
+
+// How a broadcast went (admin only)
+pub async fn broadcast_status(
+    State(state): State<AppState>,
+    Path(broadcast_id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let report: Option<String> = conn.get(report_key(&broadcast_id)).await?;
+    match report {
+        Some(report) => Ok(Json(serde_json::from_str(&report)?)),
+        None => Err(ApiError::not_found("Broadcast not found")),
+    }
+}
+
+struct Broadcast {
+    state: AppState,
+    text: String,
+    recipients: Vec<Recipient>,
+}
+
+impl Broadcast {
+    /// Send to every recipient in turn, recording progress as it goes
+    async fn run(self, mut report: Value) {
+        let config = self.state.config.current();
+        let bots = &self.state.telegram_bots;
+        let announcers: Vec<Option<Announcer>> = bots
+            .iter()
+            .map(|bot| {
+                let retry = self.state.retry.telegram.clone();
+                Announcer::new(bot, &config.telegram, retry)
+                    .map_err(|e| {
+                        error!(
+                            "Failed to build the Bot API client of bot {}: {}",
+                            bot.label(),
+                            e
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+        let twilio_rate = Rate::per_second(config.twilio.broadcast_per_sec);
+        let twilio_throttle = Throttle::new(twilio_rate);
+
+        let (mut sent, mut failed) = (0u64, 0u64);
+        let mut failures = Vec::new();
+        for (index, recipient) in self.recipients.iter().enumerate() {
+            let outcome = match recipient {
+                Recipient::Telegram { bot, chat_id } => match &announcers[*bot] {
+                    Some(announcer) => announcer.send(*chat_id, &self.text).await,
+                    None => Err(anyhow::anyhow!("the Bot API client could not be built")),
+                },
+                Recipient::Twilio { number, to } => {
+                    twilio_throttle.acquire((), twilio_rate).await;
+                    let twilio = &self.state.twilio;
+                    twilio
+                        .announce(&config.twilio, number, to, &self.text)
+                        .await
+                }
+            };
+            match outcome {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    failed += 1;
+                    warn!("Broadcast {} failed: {}", report["broadcast_id"], e);
+                    if failures.len() < MAX_FAILURES_LISTED {
+                        let mut failure = recipient.describe(bots);
+                        failure["error"] = e.to_string().into();
+                        failures.push(failure);
+                    }
+                }
+            }
+            if (index + 1) % REPORT_EVERY == 0 {
+                tally(&mut report, sent, failed, &failures);
+                self.record(&report).await;
+            }
+        }
+
+        tally(&mut report, sent, failed, &failures);
+        report["state"] = "finished".into();
+        report["finished_at"] = Utc::now().to_rfc3339().into();
+        self.record(&report).await;
+        info!(
+            "Broadcast {} finished: {} sent, {} failed",
+            report["broadcast_id"], sent, failed
+        );
+    }
+
+    async fn record(&self, report: &Value) {
+        let stored = async {
+            let mut conn = self.state.redis_client.get_async_connection().await?;
+            store_report(&mut conn, report).await
+        };
+        if let Err(e) = stored.await {
+            error!(
+                "Failed to record broadcast {}: {}",
+                report["broadcast_id"], e
+            );
+        }
+    }
+}
+
+fn tally(report: &mut Value, sent: u64, failed: u64, failures: &[Value]) {
+    report["sent"] = sent.into();
+    report["failed"] = failed.into();
+    report["failures"] = failures.into();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Value) -> BroadcastRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn filters_by_channel_and_chat_type() {
+        let everyone = request(json!({"text": "Maintenance at 22:00 UTC"}));
+        assert!(everyone.validate().is_ok());
+        assert!(everyone.wants("telegram", "group"));
+        assert!(everyone.wants("twilio", "private"));
+
+        let groups =
+            request(json!({"text": "hi", "channels": ["telegram"], "chat_types": ["group"]}));
+        assert!(groups.wants("telegram", "group"));
+        assert!(!groups.wants("telegram", "private"));
+        assert!(!groups.wants("twilio", "private"));
+
+        assert!(request(json!({"text": " "})).validate().is_err());
+        assert!(request(json!({"text": "hi", "channels": ["email"]}))
+            .validate()
+            .is_err());
+        assert!(request(json!({"text": "hi", "chat_types": ["dm"]}))
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Local buffering of submissions during Redis outages.
+//!
+//! With `buffer.enabled`, a `POST /task` that fails because Redis cannot be
+//! reached is kept on the gateway instead, up to `buffer.max_tasks` of them,
+//! and answered `202` with status `buffered`; once the buffer is full,
+//! submissions are refused with a 503 (`buffer_full`). Every
+//! `buffer.flush_interval_secs` the gateway submits buffered tasks in the
+//! order they arrived, as their callers would have, for as long as Redis
+//! answers. A task whose id was stored meanwhile is dropped; one refused
+//! when it is finally submitted, by a quota say, is stored as `failed` with
+//! the reason.
+//!
+//! The buffer is kept in memory, or with `buffer.path` set in that file as
+//! well, one JSON submission per line, so a restart during the outage loses
+//! nothing. Only REST submissions are buffered, and only those the gateway
+//! could authenticate: the admin token and JWTs work without Redis, API
+//! keys stored in it do not.
+
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth::Principal;
+use crate::cache::CacheControl;
+use crate::config::BufferSettings;
+use crate::error::ApiError;
+use crate::request_id::{self, RequestId};
+use crate::{
+    enqueue_task, envelope, failures, schema, AgentRequest, AgentResponse, AppState, Submitted,
+};
+
+/// Code of the errors telling Redis could not be reached
+const STORAGE_ERROR: &str = "storage_error";
+
+/// A submission waiting for Redis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Buffered {
+    request: AgentRequest,
+    principal: Option<Principal>,
+    cache_control: CacheControl,
+    request_id: Option<String>,
+    buffered_at: String,
+}
+
+impl Buffered {
+    pub fn new(
+        request: &AgentRequest,
+        principal: Option<&Principal>,
+        cache_control: CacheControl,
+    ) -> Self {
+        Self {
+            request: request.clone(),
+            principal: principal.cloned(),
+            cache_control,
+            request_id: request_id::current().map(|id| id.0),
+            buffered_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Submissions kept while Redis is unreachable, oldest first
+pub struct SubmissionBuffer {
+    entries: Mutex<VecDeque<Buffered>>,
+    max_tasks: usize,
+    path: Option<PathBuf>,
+}
+
+impl SubmissionBuffer {
+    /// An empty buffer, or the one left in `settings.path`
+    pub fn open(settings: &BufferSettings) -> anyhow::Result<Self> {
+        let mut entries = VecDeque::new();
+        if let Some(path) = settings.path.as_ref().filter(|path| path.exists()) {
+            for line in std::fs::read_to_string(path)?.lines() {
+                match serde_json::from_str(line) {
+                    Ok(entry) => entries.push_back(entry),
+                    Err(e) => warn!("Dropping unreadable buffered task {}: {}", line, e),
+                }
+            }
+            if !entries.is_empty() {
+                info!(
+                    "{} buffered tasks found in {}",
+                    entries.len(),
+                    path.display()
+                );
+            }
+        }
+        Ok(Self {
+            entries: Mutex::new(entries),
+            max_tasks: settings.max_tasks,
+            path: settings.path.clone(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Buffered>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Keep `entry`, which failed with `reason`, unless the buffer is full
+    pub fn admit(&self, entry: Buffered, reason: ApiError) -> Result<Submitted, ApiError> {
+        let mut entries = self.lock();
+        if entries.len() >= self.max_tasks {
+            warn!("Buffer full, refusing task {}", entry.request.task_id);
+            return Err(ApiError::unavailable(
+                "buffer_full",
+                "Task storage is unavailable and the gateway's buffer is full",
+            )
+            .with_retry_after(30));
+        }
+        if let Some(path) = &self.path {
+            let appended = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| {
+                    let line = serde_json::to_string(&entry).unwrap_or_default();
+                    writeln!(file, "{}", line)?;
+                    file.sync_data()
+                });
+            if let Err(e) = appended {
+                error!("Failed to buffer task {}: {}", entry.request.task_id, e);
+                return Err(reason);
+            }
+        }
+        warn!(
+            "Task {} buffered while Redis is unreachable ({} buffered)",
+            entry.request.task_id,
+            entries.len() + 1
+        );
+        let task_id = entry.request.task_id.clone();
+        entries.push_back(entry);
+        Ok(Submitted {
+            response: AgentResponse {
+                task_id,
+                status: "buffered".to_string(),
+                result: None,
+                error: None,
+                signature: None,
+            },
+            cache_status: None,
+            age: None,
+        })
+    }
+
+    fn front(&self) -> Option<Buffered> {
+        self.lock().front().cloned()
+    }
+
+    fn pop_front(&self) {
+        self.lock().pop_front();
+    }
+
+    /// Write what is left in the buffer back to its file
+    fn persist(&self) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let entries = self.lock();
+        let mut contents = String::new();
+        for entry in entries.iter() {
+            contents.push_str(&serde_json::to_string(entry).unwrap_or_default());
+            contents.push('\n');
+        }
+        let staged = path.with_extension("tmp");
+        std::fs::write(&staged, contents)?;
+        std::fs::rename(&staged, path)
+    }
+}
+
+/// Store `entry` as failed because submitting it was refused with `e`
+async fn reject(
+    conn: &mut redis::aio::Connection,
+    entry: &Buffered,
+    e: &ApiError,
+) -> anyhow::Result<()> {
+    let task_id = &entry.request.task_id;
+    let task = json!({
+        "input": entry.request.input,
+        "status": "failed",
+        "error": e.message,
+        "submitted_by": entry.principal.as_ref().map(|p| p.key_id.clone()),
+        "request_id": entry.request_id,
+        "labels": entry.request.labels,
+        "created_at": entry.buffered_at,
+        "schema_version": schema::CURRENT_VERSION,
+    });
+    let report = json!({ "error": e.message, "code": e.code });
+    redis::pipe()
+        .set(format!("task:{}", task_id), envelope::encode(&task)?)
+        .ignore()
+        .set(failures::error_key(task_id), envelope::encode(&report)?)
+        .ignore()
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+/// Submit buffered tasks until the buffer is empty or Redis fails again,
+/// returning how many were submitted
+async fn flush(state: &AppState, buffer: &SubmissionBuffer) -> anyhow::Result<usize> {
+    let mut flushed = 0;
+    while let Some(entry) = buffer.front() {
+        let mut conn = state.redis_client.get_async_connection().await?;
+        let task_id = entry.request.task_id.clone();
+        if conn.exists(format!("task:{}", task_id)).await? {
+            warn!(
+                "Buffered task {} was stored meanwhile, dropping it",
+                task_id
+            );
+            buffer.pop_front();
+            continue;
+        }
+
+        let submission = enqueue_task(
+            state.clone(),
+            None,
+            entry.principal.clone().map(axum::Extension),
+            entry.cache_control,
+            entry.request.clone(),
+        );
+        let request_id = entry
+            .request_id
+            .clone()
+            .map_or_else(RequestId::generate, RequestId);
+        match request_id::scope(request_id, submission).await {
+            Ok(_) => {
+                info!("Buffered task {} submitted", task_id);
+                flushed += 1;
+            }
+            Err(e) if redis_unreachable(&e) => anyhow::bail!("{}", e.message),
+            Err(e) if e.code == "duplicate_task" => {
+                warn!(
+                    "Buffered task {} was stored meanwhile, dropping it",
+                    task_id
+                );
+            }
+            Err(e) => {
+                warn!("Buffered task {} refused: {}", task_id, e.message);
+                reject(&mut conn, &entry, &e).await?;
+            }
+        }
+        buffer.pop_front();
+    }
+    Ok(flushed)
+}
+
+/// Whether a submission failed for want of Redis
+pub fn redis_unreachable(e: &ApiError) -> bool {
+    e.code == STORAGE_ERROR
+}
+
+/// Flush the buffer into Redis in a background task
+pub fn start_buffer_flush(state: AppState, buffer: Arc<SubmissionBuffer>, interval_secs: u64) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval_secs)).await;
+            if buffer.len() == 0 {
+                continue;
+            }
+            match flush(&state, &buffer).await {
+                Ok(flushed) => info!("Flushed {} buffered tasks", flushed),
+                Err(e) => warn!("Buffer not flushed, {} left: {}", buffer.len(), e),
+            }
+            if let Err(e) = buffer.persist() {
+                error!("Failed to write the task buffer: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(task_id: &str) -> AgentRequest {
+        serde_json::from_value(json!({"task_id": task_id, "input": "hi", "config": null})).unwrap()
+    }
+
+    #[test]
+    fn buffers_up_to_max_tasks_and_survives_restarts() {
+        let path = std::env::temp_dir().join(format!("buffer-{}.jsonl", uuid::Uuid::new_v4()));
+        let settings = BufferSettings {
+            max_tasks: 2,
+            path: Some(path.clone()),
+            ..BufferSettings::default()
+        };
+        let buffer = SubmissionBuffer::open(&settings).unwrap();
+        let admit = |task_id| {
+            let entry = Buffered::new(&request(task_id), None, CacheControl::default());
+            buffer.admit(entry, ApiError::internal("down"))
+        };
+        assert_eq!(admit("t1").unwrap().response.status, "buffered");
+        admit("t2").unwrap();
+        assert!(matches!(admit("t3"), Err(e) if e.code == "buffer_full"));
+
+        let reopened = SubmissionBuffer::open(&settings).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.front().unwrap().request.task_id, "t1");
+        reopened.pop_front();
+        reopened.persist().unwrap();
+        assert_eq!(SubmissionBuffer::open(&settings).unwrap().len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_unreachable_redis_is_buffered() {
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(redis_unreachable(&redis::RedisError::from(refused).into()));
+        let dropped = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(redis_unreachable(&redis::RedisError::from(dropped).into()));
+
+        let oom = (redis::ErrorKind::ResponseError, "OOM", "command not allowed".to_string());
+        let oom: ApiError = redis::RedisError::from(oom).into();
+        assert!(!redis_unreachable(&oom));
+        assert_eq!(oom.status, axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
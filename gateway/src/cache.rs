@@ -0,0 +1,358 @@
+//! Response cache.
+//!
+//! A task whose effective config sets `cache: {"enabled": true, "ttl": secs}`
+//! is looked up by a hash of its input and config before it reaches the
+//! queue. Because the knob is part of the config, the default config can
+//! switch caching on with its own TTL while individual submissions override
+//! it. A hit is answered from the cached result at once; a miss runs as usual
+//! and its result is cached the first time it is read back, unless the task
+//! failed: a result turning up after its error report is never cached. Like
+//! deduplication, entries are scoped to the submitter.
+//!
+//! Clients refine this per request with `Cache-Control`: `no-cache` skips the
+//! lookup, `no-store` keeps the result out of the cache and `max-age=N`
+//! rejects entries cached more than N seconds ago.
+
+use axum::http::{header, HeaderMap};
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{warn, Instrument};
+
+use crate::metrics::Counter;
+use crate::{dedupe, failures, telemetry};
+
+/// Config key carrying the cache knob
+pub const CONFIG_KEY: &str = "cache";
+
+/// Response header reporting how the cache handled a submission
+pub const CACHE_STATUS_HEADER: &str = "x-cache";
+
+/// TTL used when the knob enables caching without naming one
+const DEFAULT_TTL_SECS: u64 = 300;
+const MAX_TTL_SECS: u64 = 7 * 24 * 3600;
+
+/// How long a submitted task may take to produce a result worth caching
+const PENDING_TTL_SECS: u64 = 24 * 3600;
+
+fn entry_key(key: &str) -> String {
+    format!("cache:{}", key)
+}
+
+fn pending_key(task_id: &str) -> String {
+    format!("cache:pending:{}", task_id)
+}
+
+/// The `cache` knob as written in a config
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheKnob {
+    #[serde(default)]
+    enabled: bool,
+    ttl: Option<u64>,
+}
+
+/// Caching requested by an effective config
+#[derive(Debug, Clone, Copy)]
+pub struct CachePolicy {
+    pub ttl: u64,
+}
+
+/// The caching `config` asks for, or `None` when it is off
+pub fn policy(config: &Value) -> Result<Option<CachePolicy>, String> {
+    let Some(knob) = config.get(CONFIG_KEY) else {
+        return Ok(None);
+    };
+    let knob: CacheKnob = serde_json::from_value(knob.clone())
+        .map_err(|e| format!("config.{}: {}", CONFIG_KEY, e))?;
+    if !knob.enabled {
+        return Ok(None);
+    }
+
+    let ttl = knob.ttl.unwrap_or(DEFAULT_TTL_SECS);
+    if !(1..=MAX_TTL_SECS).contains(&ttl) {
+        return Err(format!(
+            "config.{}.ttl: must be between 1 and {} seconds",
+            CONFIG_KEY, MAX_TTL_SECS
+        ));
+    }
+    Ok(Some(CachePolicy { ttl }))
+}
+
+/// Cache directives taken from the request's `Cache-Control` header
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct CacheControl {
+    pub no_cache: bool,
+    pub no_store: bool,
+    pub max_age: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut control = Self::default();
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in directives {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => control.max_age = secs.trim().parse().ok(),
+                _ if directive == "no-cache" => control.no_cache = true,
+                _ if directive == "no-store" => control.no_store = true,
+                _ => {}
+            }
+        }
+        control
+    }
+}
+
+/// How the cache handled a submission, as reported in [`CACHE_STATUS_HEADER`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+            Self::Bypass => "BYPASS",
+        }
+    }
+}
+
+/// Cache key for `input` and `config` submitted within `scope`. The knob
+/// itself is left out so changing a TTL keeps existing entries.
+pub fn key(scope: &str, input: &Value, config: &Value) -> String {
+    let mut config = config.clone();
+    if let Some(map) = config.as_object_mut() {
+        map.remove(CONFIG_KEY);
+    }
+    dedupe::fingerprint(scope, input, &config)
+}
+
+/// A stored result and the task that produced it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedResult {
+    pub task_id: String,
+    /// Unix time the entry was written
+    pub cached_at: i64,
+    /// Raw stored result, still in its envelope
+    pub result: String,
+}
+
+impl CachedResult {
+    /// Seconds since the entry was written
+    pub fn age(&self) -> u64 {
+        (chrono::Utc::now().timestamp() - self.cached_at).max(0) as u64
+    }
+}
+
+/// The entry for `key`, unless it is missing or older than `max_age`
+pub async fn lookup<C: ConnectionLike + Send>(
+    conn: &mut C,
+    key: &str,
+    max_age: Option<u64>,
+) -> redis::RedisResult<Option<CachedResult>> {
+    let entry_key = entry_key(key);
+    let raw: Option<String> = conn
+        .get(&entry_key)
+        .instrument(telemetry::redis_span("GET", &entry_key))
+        .await?;
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+
+    let entry: CachedResult = match serde_json::from_str(&raw) {
+        Ok(entry) => entry,
+        Err(e) => {
+            warn!("Ignoring unreadable cache entry {}: {}", entry_key, e);
+            return Ok(None);
+        }
+    };
+    if max_age.is_some_and(|max_age| entry.age() > max_age) {
+        return Ok(None);
+    }
+    Ok(Some(entry))
+}
+
+/// Remember that `task_id`'s result should be cached under `key` for `ttl`
+pub async fn mark_pending<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+    key: &str,
+    ttl: u64,
+) -> redis::RedisResult<()> {
+    let pending = serde_json::json!({ "key": key, "ttl": ttl }).to_string();
+    conn.set_ex(pending_key(task_id), pending, PENDING_TTL_SECS)
+        .await
+}
+
+/// Whether the result of `task_id` is waiting to be cached
+pub async fn is_pending<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+) -> redis::RedisResult<bool> {
+    conn.exists(pending_key(task_id)).await
+}
+
+/// Queue the check of [`is_pending`] on `pipe`
+pub fn is_pending_in(pipe: &mut redis::Pipeline, task_id: &str) {
+    pipe.exists(pending_key(task_id));
+}
+
+/// Cache `result` if `task_id` was marked as cacheable and did not fail,
+/// returning whether an entry was written. Each task populates the cache at
+/// most once.
+pub async fn populate<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+    result: &str,
+) -> anyhow::Result<bool> {
+    let pending: Option<String> = redis::cmd("GETDEL")
+        .arg(pending_key(task_id))
+        .query_async(conn)
+        .await?;
+    let Some(pending) = pending else {
+        return Ok(false);
+    };
+    let pending: Value = serde_json::from_str(&pending)?;
+    let (Some(key), Some(ttl)) = (pending["key"].as_str(), pending["ttl"].as_u64()) else {
+        anyhow::bail!("malformed pending cache marker for task {}", task_id);
+    };
+    let failed: bool = conn.exists(failures::error_key(task_id)).await?;
+    if failed {
+        return Ok(false);
+    }
+
+    let entry = serde_json::to_string(&CachedResult {
+        task_id: task_id.to_string(),
+        cached_at: chrono::Utc::now().timestamp(),
+        result: result.to_string(),
+    })?;
+    let entry_key = entry_key(key);
+    let stored: Option<String> = redis::cmd("SET")
+        .arg(&entry_key)
+        .arg(entry)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(conn)
+        .instrument(telemetry::redis_span("SET", &entry_key))
+        .await?;
+    Ok(stored.is_some())
+}
+
+/// Counters describing cache effectiveness
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    pub hits: Counter,
+    pub misses: Counter,
+    /// Lookups skipped because the request sent `no-cache`
+    pub bypassed: Counter,
+    pub stored: Counter,
+    /// Submissions answered from a recent duplicate's result
+    pub deduplicated: Counter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+    use serde_json::json;
+
+    #[test]
+    fn keys_ignore_key_order_and_the_knob() {
+        let config = json!({"model": "m", "temperature": 0.2, "cache": {"enabled": true}});
+        let reordered =
+            json!({"cache": {"enabled": true, "ttl": 60}, "temperature": 0.2, "model": "m"});
+        let stable = key("k1", &json!("2+2"), &config);
+        assert_eq!(stable, key("k1", &json!("2+2"), &reordered));
+        assert_eq!(
+            stable,
+            key(
+                "k1",
+                &json!("2+2"),
+                &json!({"model": "m", "temperature": 0.2})
+            )
+        );
+
+        assert_ne!(stable, key("k2", &json!("2+2"), &config));
+        assert_ne!(stable, key("k1", &json!("2+3"), &config));
+        assert_ne!(
+            stable,
+            key(
+                "k1",
+                &json!("2+2"),
+                &json!({"model": "n", "temperature": 0.2})
+            )
+        );
+    }
+
+    #[test]
+    fn reads_the_knob() {
+        let ttl = |config: Value| policy(&config).map(|p| p.map(|p| p.ttl));
+        assert_eq!(ttl(json!({})), Ok(None));
+        assert_eq!(
+            ttl(json!({"cache": {"enabled": false, "ttl": 60}})),
+            Ok(None)
+        );
+        assert_eq!(
+            ttl(json!({"cache": {"enabled": true}})),
+            Ok(Some(DEFAULT_TTL_SECS))
+        );
+        assert_eq!(
+            ttl(json!({"cache": {"enabled": true, "ttl": 60}})),
+            Ok(Some(60))
+        );
+        assert!(ttl(json!({"cache": {"enabled": true, "ttl": 0}})).is_err());
+        assert!(ttl(json!({"cache": {"enabled": true, "size": 1}})).is_err());
+    }
+
+    #[tokio::test]
+    async fn entries_expire_with_their_ttl() {
+        let mut redis = FakeRedis::new();
+        mark_pending(&mut redis, "t1", "abc", 60).await.unwrap();
+        assert!(is_pending(&mut redis, "t1").await.unwrap());
+        assert!(populate(&mut redis, "t1", "4").await.unwrap());
+        assert_eq!(redis.ttl(&entry_key("abc")), Some(60));
+        // Populated once
+        assert!(!is_pending(&mut redis, "t1").await.unwrap());
+        assert!(!populate(&mut redis, "t1", "5").await.unwrap());
+
+        let hit = lookup(&mut redis, "abc", None).await.unwrap().unwrap();
+        assert_eq!((hit.task_id.as_str(), hit.result.as_str()), ("t1", "4"));
+        assert!(lookup(&mut redis, "abc", Some(60)).await.unwrap().is_some());
+
+        // max-age turns away entries cached longer ago
+        let old = CachedResult {
+            cached_at: chrono::Utc::now().timestamp() - 120,
+            ..hit
+        };
+        redis.set(&entry_key("abc"), &serde_json::to_string(&old).unwrap());
+        assert!(lookup(&mut redis, "abc", Some(60)).await.unwrap().is_none());
+        assert!(lookup(&mut redis, "abc", Some(300))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(lookup(&mut redis, "xyz", None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_tasks_are_not_cached() {
+        let mut redis = FakeRedis::new();
+        mark_pending(&mut redis, "t1", "abc", 60).await.unwrap();
+        redis.set(&failures::error_key("t1"), r#"{"error": "boom"}"#);
+
+        // A late result of a task that already failed
+        assert!(!populate(&mut redis, "t1", "4").await.unwrap());
+        assert!(lookup(&mut redis, "abc", None).await.unwrap().is_none());
+        assert!(!is_pending(&mut redis, "t1").await.unwrap());
+    }
+}
@@ -0,0 +1,194 @@
+//! Synthetic canary tasks.
+//!
+//! With `canary.enabled`, every gateway submits a task marked `"canary":
+//! true` each `canary.interval_secs`, which agents complete without calling
+//! the model, and times it from submission to result. A canary without a
+//! result after `canary.timeout_secs` failed, and so has the agent fleet:
+//! it is wedged, gone or not keeping up. The latest run shows at
+//! `GET /health/detailed`, and runs, failures and latency are exported as
+//! `gateway.canary.*` metrics. Canaries stay out of the history table and
+//! send no lifecycle events. Completed canaries are removed again; those
+//! that fail are left to the watchdog. No canaries are sent during
+//! maintenance.
+
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, Client};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::config::CanarySettings;
+use crate::metrics::{Counter, Gauge};
+use crate::queue::{NewTask, TaskQueue};
+use crate::{envelope, failures, maintenance, queue_for, schema, task_logs, watchdog};
+
+/// How often a canary's result is looked for
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+pub struct CanaryMetrics {
+    pub runs: Counter,
+    pub failures: Counter,
+    /// Milliseconds the latest completed canary took
+    pub latency_ms: Gauge,
+    last: Mutex<Option<CanaryRun>>,
+}
+
+/// How a canary went
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryRun {
+    pub task_id: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub finished_at: String,
+}
+
+impl CanaryMetrics {
+    /// The latest run, if there was one
+    pub fn last(&self) -> Option<CanaryRun> {
+        self.last.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn record(&self, run: CanaryRun) {
+        self.runs.inc();
+        if run.ok {
+            self.latency_ms.set(run.latency_ms as i64);
+        } else {
+            self.failures.inc();
+        }
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Some(run);
+    }
+}
+
+/// The record of a canary task due by `deadline`
+fn canary_task(timeout_secs: u64, deadline: DateTime<Utc>) -> serde_json::Value {
+    json!({
+        "input": "canary",
+        "config": {},
+        "status": "pending",
+        "priority": false,
+        "capability": null,
+        "timeout_secs": timeout_secs,
+        "deadline": deadline.to_rfc3339(),
+        "submitted_by": null,
+        "canary": true,
+        "created_at": Utc::now().to_rfc3339(),
+        "schema_version": schema::CURRENT_VERSION,
+    })
+}
+
+/// Submit a canary and wait for its result, cleaning up after it if it came
+async fn run_once(
+    conn: &mut redis::aio::Connection,
+    task_queue: &TaskQueue,
+    task_id: &str,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let deadline = watchdog::deadline(timeout.as_secs());
+    let task = canary_task(timeout.as_secs(), deadline);
+    let queue = queue_for(None, false);
+    let labels = BTreeMap::new();
+    let new_task = NewTask {
+        task_id,
+        record: envelope::encode(&task)?,
+        labels: &labels,
+        queue: Some((&queue, deadline)),
+        tracked: false,
+    };
+    task_queue.submit(conn, new_task).await?;
+
+    let started = Instant::now();
+    let result_key = format!("result:{}", task_id);
+    let error_key = failures::error_key(task_id);
+    loop {
+        if conn.exists(&result_key).await? {
+            break;
+        }
+        let report: Option<String> = conn.get(&error_key).await?;
+        if let Some(report) = report {
+            let report = envelope::decode(&report)?;
+            anyhow::bail!("canary failed: {}", failures::message(&report));
+        }
+        if started.elapsed() >= timeout {
+            anyhow::bail!("no result within {}s", timeout.as_secs());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    watchdog::untrack(conn, task_id).await?;
+    conn.del::<_, ()>(&[
+        format!("task:{}", task_id),
+        result_key,
+        task_logs::logs_key(task_id),
+    ])
+    .await?;
+    Ok(())
+}
+
+/// Send canaries in a background task
+pub fn start_canary(
+    redis_client: Arc<Client>,
+    task_queue: TaskQueue,
+    settings: &CanarySettings,
+    metrics: Arc<CanaryMetrics>,
+) {
+    let interval = Duration::from_secs(settings.interval_secs);
+    let timeout = Duration::from_secs(settings.timeout_secs);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if maintenance::current().is_some() {
+                continue;
+            }
+
+            let task_id = format!("canary-{}", uuid::Uuid::new_v4().simple());
+            let started = Instant::now();
+            let outcome = match redis_client.get_async_connection().await {
+                Ok(mut conn) => run_once(&mut conn, &task_queue, &task_id, timeout).await,
+                Err(e) => Err(e.into()),
+            };
+            let latency_ms = started.elapsed().as_millis() as u64;
+            match &outcome {
+                Ok(()) => debug!("Canary {} completed in {}ms", task_id, latency_ms),
+                Err(e) => warn!("Canary {} failed: {}", task_id, e),
+            }
+            metrics.record(CanaryRun {
+                task_id,
+                ok: outcome.is_ok(),
+                latency_ms,
+                error: outcome.err().map(|e| e.to_string()),
+                finished_at: Utc::now().to_rfc3339(),
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_canaries_keep_the_last_latency() {
+        let metrics = CanaryMetrics::default();
+        let run = |ok, latency_ms| CanaryRun {
+            task_id: "canary-1".to_string(),
+            ok,
+            latency_ms,
+            error: (!ok).then(|| "no result within 30s".to_string()),
+            finished_at: Utc::now().to_rfc3339(),
+        };
+        metrics.record(run(true, 120));
+        metrics.record(run(false, 30_000));
+        assert_eq!((metrics.runs.get(), metrics.failures.get()), (2, 1));
+        assert_eq!(metrics.latency_ms.get(), 120);
+        assert!(!metrics.last().unwrap().ok);
+        let task = canary_task(30, Utc::now());
+        assert_eq!(task["canary"], true);
+        assert_eq!(task["schema_version"], schema::CURRENT_VERSION);
+    }
+}
@@ -0,0 +1,272 @@
+//! MessagePack and CBOR encodings for task routes.
+//!
+//! `POST /task` reads `application/msgpack` (or `application/x-msgpack`) and
+//! `application/cbor` bodies as well as JSON, into the same request model,
+//! so validation and storage do not change. `POST /task` and
+//! `GET /task/:id` answer in the encoding the `Accept` header prefers,
+//! defaulting to JSON. Error bodies are always JSON.
+//!
+//! `GET /task/:id` carries an `ETag` naming the task's status and a digest
+//! of the answer in its encoding; polling clients sending it back in
+//! `If-None-Match` get `304 Not Modified` until either changes.
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use ring::digest;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::ApiError;
+use crate::validation::ValidationError;
+
+/// Encodings the task routes speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Format::Json),
+            json if json.starts_with("application/") && json.ends_with("+json") => {
+                Some(Format::Json)
+            }
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MsgPack)
+            }
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    /// The encoding `Accept` rates highest, JSON when it names none we speak
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        preferred(headers, Format::from_media_type).map_or(Format::Json, |(format, _)| format)
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// The type `Accept` rates highest of those `parse` knows, and its quality;
+/// ties go to the first listed
+pub fn preferred<T>(headers: &HeaderMap, parse: impl Fn(&str) -> Option<T>) -> Option<(T, f32)> {
+    let mut best: Option<(T, f32)> = None;
+    let ranges = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+    for range in ranges {
+        let Some(value) = parse(range) else {
+            continue;
+        };
+        let quality = range
+            .split(';')
+            .skip(1)
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality > best.as_ref().map_or(0.0, |(_, q)| *q) {
+            best = Some((value, quality));
+        }
+    }
+    best
+}
+
+/// Request body in any [`Format`], chosen by `Content-Type`
+pub struct Body<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Body<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Format::from_media_type);
+        match format {
+            // Keep axum's JSON rejections, which clients already rely on
+            Some(Format::Json) => Json::<T>::from_request(req, state)
+                .await
+                .map(|Json(value)| Body(value))
+                .map_err(|e| ValidationError::from(e).into()),
+            Some(format) => {
+                let bytes = Bytes::from_request(req, state)
+                    .await
+                    .map_err(|e| ValidationError::body(e.status(), e.body_text()))?;
+                let value = format.decode(&bytes).map_err(|e| {
+                    ValidationError::body(
+                        StatusCode::BAD_REQUEST,
+                        format!("Failed to decode {} body: {}", format.content_type(), e),
+                    )
+                })?;
+                Ok(Body(value))
+            }
+            None => Err(ValidationError::body(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Content-Type must be application/json, application/msgpack or application/cbor",
+            )
+            .into()),
+        }
+    }
+}
+
+/// Entity tag of `value` served as `content_type`, for a task with `status`
+pub fn etag<T: Serialize>(value: &T, status: &str, content_type: &str) -> String {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(content_type.as_bytes());
+    ctx.update(b"\n");
+    ctx.update(&body);
+    let hash = hex::encode(&ctx.finish().as_ref()[..12]);
+    format!("\"{}-{}\"", status, hash)
+}
+
+/// Whether `If-None-Match` names `etag`, weakly compared
+pub fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Response body encoded as `format`
+pub struct Encoded<T>(pub T, pub Format);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(value, format) = self;
+        let bytes = match format {
+            Format::Json => return Json(value).into_response(),
+            // Named fields keep maps shaped like the JSON objects
+            Format::MsgPack => rmp_serde::to_vec_named(&value).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&value, &mut bytes)
+                    .map(|()| bytes)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        match bytes {
+            Ok(bytes) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(format.content_type()),
+                )],
+                bytes,
+            )
+                .into_response(),
+            Err(e) => {
+                ApiError::internal(format!("Failed to encode response: {}", e)).into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn defaults_to_json() {
+        assert_eq!(Format::accepted(&HeaderMap::new()), Format::Json);
+        assert_eq!(Format::accepted(&accept(&["text/html, */*"])), Format::Json);
+        assert_eq!(
+            Format::accepted(&accept(&["application/cbor;q=0"])),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn names_the_encoding() {
+        assert_eq!(
+            Format::accepted(&accept(&["application/msgpack"])),
+            Format::MsgPack
+        );
+        assert_eq!(
+            Format::accepted(&accept(&["application/x-msgpack"])),
+            Format::MsgPack
+        );
+        assert_eq!(
+            Format::accepted(&accept(&["Application/CBOR"])),
+            Format::Cbor
+        );
+        assert_eq!(
+            Format::accepted(&accept(&["application/problem+json"])),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn prefers_the_highest_quality() {
+        let headers = accept(&["application/json;q=0.5, application/cbor;q=0.9"]);
+        assert_eq!(Format::accepted(&headers), Format::Cbor);
+        let headers = accept(&["application/msgpack; q=0.2", "application/json"]);
+        assert_eq!(Format::accepted(&headers), Format::Json);
+        // Ties go to the first listed
+        let headers = accept(&["application/cbor;q=0.8, application/msgpack;q=0.8"]);
+        assert_eq!(Format::accepted(&headers), Format::Cbor);
+    }
+
+    #[test]
+    fn tags_each_status_and_encoding() {
+        let value = serde_json::json!({"task_id": "t1", "status": "pending"});
+        let json = Format::Json.content_type();
+        let tag = etag(&value, "pending", json);
+        assert!(tag.starts_with("\"pending-") && tag.ends_with('"'));
+        assert_eq!(tag, etag(&value, "pending", json));
+        assert_ne!(tag, etag(&value, "pending", Format::Cbor.content_type()));
+        assert_ne!(tag, etag(&value, "processing", json));
+
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified(&headers, &tag));
+        let sent = format!("\"other\", W/{}", tag);
+        headers.insert(header::IF_NONE_MATCH, sent.parse().unwrap());
+        assert!(not_modified(&headers, &tag));
+    }
+
+    #[test]
+    fn ignores_unreadable_qualities() {
+        let headers = accept(&["application/json;q=0.1, application/msgpack;q=high"]);
+        assert_eq!(Format::accepted(&headers), Format::MsgPack);
+    }
+}
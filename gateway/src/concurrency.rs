@@ -0,0 +1,162 @@
+//! Concurrency limits.
+//!
+//! `concurrency.max_in_flight` caps the requests handled at once, except
+//! on `concurrency.exempt_paths`, so a burst of submissions holding Redis
+//! connections cannot starve the health probes Kubernetes relies on.
+//! `[concurrency.routes]` caps requests by path pattern as well, the longest
+//! pattern covering a path applying (see [`timeouts`](crate::timeouts)). A
+//! request waits up to `concurrency.queue_timeout_ms` for its turn and is
+//! then refused with a 503 (`too_busy`), counted in
+//! `gateway.http.concurrency_rejected` by the limit it hit. Limits are set
+//! at startup.
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::config::ConcurrencySettings;
+use crate::error::ApiError;
+use crate::metrics::{CounterVec, Gauge};
+use crate::timeouts;
+
+#[derive(Debug, Default)]
+pub struct ConcurrencyMetrics {
+    /// Requests being handled under the global limit
+    pub in_flight: Gauge,
+    /// Requests refused, by the limit they hit: `global` or the pattern
+    pub rejected: CounterVec,
+}
+
+/// The configured limits
+pub struct ConcurrencyLimits {
+    global: Option<Arc<Semaphore>>,
+    max_in_flight: usize,
+    routes: BTreeMap<String, Arc<Semaphore>>,
+    exempt_paths: Vec<String>,
+    queue_timeout: Duration,
+    metrics: Arc<ConcurrencyMetrics>,
+}
+
+impl ConcurrencyLimits {
+    pub fn from_config(settings: &ConcurrencySettings, metrics: Arc<ConcurrencyMetrics>) -> Self {
+        Self {
+            global: settings.max_in_flight.map(|n| Arc::new(Semaphore::new(n))),
+            max_in_flight: settings.max_in_flight.unwrap_or(0),
+            routes: settings
+                .routes
+                .iter()
+                .map(|(pattern, n)| (pattern.clone(), Arc::new(Semaphore::new(*n))))
+                .collect(),
+            exempt_paths: settings.exempt_paths.clone(),
+            queue_timeout: Duration::from_millis(settings.queue_timeout_ms),
+            metrics,
+        }
+    }
+
+    /// Publish how many requests hold a global slot
+    fn record_in_flight(&self) {
+        if let Some(semaphore) = &self.global {
+            let in_flight = self.max_in_flight - semaphore.available_permits();
+            self.metrics.in_flight.set(in_flight as i64);
+        }
+    }
+
+    /// A slot of `semaphore`, within the queue timeout
+    async fn acquire(
+        &self,
+        semaphore: &Arc<Semaphore>,
+        limit: &str,
+    ) -> Result<OwnedSemaphorePermit, ApiError> {
+        let acquired = tokio::time::timeout(self.queue_timeout, semaphore.clone().acquire_owned());
+        match acquired.await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                self.metrics.rejected.inc(limit);
+                Err(ApiError::unavailable(
+                    "too_busy",
+                    "The gateway is handling too many requests, retry later",
+                )
+                .with_retry_after(1))
+            }
+        }
+    }
+}
+
+/// Middleware holding each request to the limits covering it
+pub async fn limit(
+    State(limits): State<Arc<ConcurrencyLimits>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    let global = limits
+        .global
+        .as_ref()
+        .filter(|_| !limits.exempt_paths.iter().any(|exempt| exempt == path));
+    let route = timeouts::route_for(&limits.routes, path);
+
+    let mut permits = Vec::new();
+    if let Some(semaphore) = global {
+        match limits.acquire(semaphore, "global").await {
+            Ok(permit) => permits.push(permit),
+            Err(e) => {
+                warn!(
+                    "Refused {} {}: global concurrency limit",
+                    req.method(),
+                    path
+                );
+                return e.into_response();
+            }
+        }
+    }
+    if let Some((pattern, semaphore)) = route {
+        match limits.acquire(semaphore, pattern).await {
+            Ok(permit) => permits.push(permit),
+            Err(e) => {
+                warn!(
+                    "Refused {} {}: concurrency limit of {}",
+                    req.method(),
+                    path,
+                    pattern
+                );
+                return e.into_response();
+            }
+        }
+    }
+
+    limits.record_in_flight();
+    let response = next.run(req).await;
+    drop(permits);
+    limits.record_in_flight();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_over_the_limit_are_refused() {
+        let settings = ConcurrencySettings {
+            max_in_flight: Some(1),
+            queue_timeout_ms: 10,
+            ..ConcurrencySettings::default()
+        };
+        let metrics = Arc::new(ConcurrencyMetrics::default());
+        let limits = ConcurrencyLimits::from_config(&settings, metrics.clone());
+        let semaphore = limits.global.as_ref().unwrap();
+        let held = limits.acquire(semaphore, "global").await.unwrap();
+        let refused = limits.acquire(semaphore, "global").await.unwrap_err();
+        assert_eq!(refused.code, "too_busy");
+        assert_eq!(metrics.rejected.total(), 1);
+        drop(held);
+        assert!(limits.acquire(semaphore, "global").await.is_ok());
+    }
+}
@@ -0,0 +1,2620 @@
+//! Typed gateway configuration.
+//!
+//! Settings are layered, later sources winning:
+//!
+//! 1. built-in defaults;
+//! 2. the file named by `CLAW_CONFIG` (TOML, or YAML for `.yaml`/`.yml`);
+//! 3. the established environment variables (`REDIS_HOST`, `ADMIN_TOKEN`,
+//!    `RETRY_REDIS_MAX_ATTEMPTS`, ...);
+//! 4. `CLAW_`-prefixed variables addressing any key, with `__` between
+//!    sections (`CLAW_REDIS__HOST`, `CLAW_TELEGRAM__BREAKER__THRESHOLD`).
+//!
+//! The merged result is validated once at startup and every problem is
+//! reported together. OpenTelemetry export keeps the standard `OTEL_*`
+//! variables, since it is set up before the configuration is loaded.
+
+use figment::{
+    providers::{Env, Format, Serialized, Toml, Yaml},
+    Figment,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::envelope;
+use crate::ip_filter;
+use crate::redaction;
+use crate::validation;
+
+/// Variable naming the configuration file
+const CONFIG_PATH_VAR: &str = "CLAW_CONFIG";
+
+/// Prefix for variables that address configuration keys directly
+const ENV_PREFIX: &str = "CLAW_";
+
+/// Established variable names and the keys they set
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("BIND_ADDR", "server.bind_addr"),
+    ("PORT", "server.port"),
+    ("GATEWAY_MODE", "server.mode"),
+    ("SIMULATION_DELAY_MS", "simulation.delay_ms"),
+    ("SIMULATION_REPLY", "simulation.reply"),
+    ("TLS_CERT_PATH", "server.tls_cert_path"),
+    ("TLS_KEY_PATH", "server.tls_key_path"),
+    ("RESULT_SIGNING_KEY_PATH", "result_signing.key_path"),
+    ("RESULT_SIGNING_KEY_ID", "result_signing.key_id"),
+    (
+        "TLS_RELOAD_INTERVAL_SECS",
+        "server.tls_reload_interval_secs",
+    ),
+    ("HTTP2_ENABLED", "server.http2"),
+    (
+        "HTTP2_MAX_CONCURRENT_STREAMS",
+        "server.http2_max_concurrent_streams",
+    ),
+    (
+        "HTTP2_KEEP_ALIVE_INTERVAL_SECS",
+        "server.http2_keep_alive_interval_secs",
+    ),
+    (
+        "HTTP2_KEEP_ALIVE_TIMEOUT_SECS",
+        "server.http2_keep_alive_timeout_secs",
+    ),
+    ("HTTP_KEEP_ALIVE", "server.keep_alive"),
+    ("HTTP_KEEP_ALIVE_TIMEOUT_SECS", "server.keep_alive_timeout_secs"),
+    ("MAX_CONNECTIONS", "server.max_connections"),
+    ("REDIS_HOST", "redis.host"),
+    ("REDIS_PORT", "redis.port"),
+    ("REDIS_PASSWORD", "redis.password"),
+    ("REDIS_REPLICA_HOST", "redis.replica_host"),
+    ("REDIS_REPLICA_PORT", "redis.replica_port"),
+    ("ADMIN_TOKEN", "auth.admin_token"),
+    ("CORS_ALLOWED_ORIGINS", "cors.allowed_origins"),
+    ("CORS_ALLOW_CREDENTIALS", "cors.allow_credentials"),
+    ("CORS_MAX_AGE_SECS", "cors.max_age_secs"),
+    ("MAX_IN_FLIGHT_REQUESTS", "concurrency.max_in_flight"),
+    ("REQUEST_TIMEOUT_READ_SECS", "timeouts.read_secs"),
+    ("REQUEST_TIMEOUT_WRITE_SECS", "timeouts.write_secs"),
+    ("ACCESS_LOG_ENABLED", "access_log.enabled"),
+    ("ACCESS_LOG_FORMAT", "access_log.format"),
+    ("ACCESS_LOG_SAMPLE_RATE", "access_log.sample_rate"),
+    ("ACCESS_LOG_BODY_SAMPLE_RATE", "access_log.body_sample_rate"),
+    ("IP_ALLOWLIST", "ip_filter.allow"),
+    ("IP_DENYLIST", "ip_filter.deny"),
+    ("ADMIN_IP_ALLOWLIST", "ip_filter.admin_allow"),
+    ("TRUSTED_PROXIES", "ip_filter.trusted_proxies"),
+    ("SIGNATURE_WINDOW_SECS", "auth.signature_window_secs"),
+    ("JWT_SECRET", "auth.jwt_secret"),
+    ("JWT_ISSUER", "auth.jwt_issuer"),
+    ("TASK_PREVIEW_CHARS", "tasks.preview_chars"),
+    ("TASK_MAX_WAIT_SECS", "tasks.max_wait_secs"),
+    ("TASK_ENVELOPE_VERSION", "tasks.envelope_version"),
+    ("ENCRYPTION_SECRET", "encryption.secret"),
+    ("ENCRYPTION_KEY_ID", "encryption.key_id"),
+    ("REDACTION_ENABLED", "redaction.enabled"),
+    ("REDACTION_DETECTORS", "redaction.detectors"),
+    ("REDACTION_LOGS", "redaction.logs"),
+    ("MODERATION_ACTION", "moderation.action"),
+    ("MODERATION_MAX_INPUT_CHARS", "moderation.max_input_chars"),
+    ("MODERATION_MAX_INPUT_TOKENS", "moderation.max_input_tokens"),
+    ("MODERATION_API_URL", "moderation.api_url"),
+    ("MODERATION_API_TOKEN", "moderation.api_token"),
+    ("MODERATION_API_TIMEOUT_SECS", "moderation.api_timeout_secs"),
+    ("MODERATION_FAIL_CLOSED", "moderation.fail_closed"),
+    ("TEMPLATES_TASK_URL", "templates.task_url"),
+    ("TASK_DEDUPE_WINDOW_SECS", "tasks.dedupe_window_secs"),
+    ("TASK_PURGE_BATCH_SIZE", "tasks.purge_batch_size"),
+    ("TASK_PURGE_PAUSE_MS", "tasks.purge_pause_ms"),
+    ("TASK_DEPENDENCY_INTERVAL_SECS", "tasks.dependency_interval_secs"),
+    ("TASK_PIPELINE_INTERVAL_SECS", "tasks.pipeline_interval_secs"),
+    ("TASK_MAP_INTERVAL_SECS", "tasks.map_interval_secs"),
+    ("MAX_BODY_BYTES", "limits.max_body_bytes"),
+    ("MAX_INPUT_BYTES", "limits.max_input_bytes"),
+    ("MAX_CONFIG_BYTES", "limits.max_config_bytes"),
+    ("MAX_CONFIG_DEPTH", "limits.max_config_depth"),
+    ("MAX_DEPENDENCIES", "limits.max_dependencies"),
+    ("MAX_MAP_ITEMS", "limits.max_map_items"),
+    ("MAX_ANNOTATIONS", "limits.max_annotations"),
+    ("MAX_ANNOTATION_CHARS", "limits.max_annotation_chars"),
+    ("MAX_BULK_RESULTS", "limits.max_bulk_results"),
+    ("REDIS_MEMORY_CEILING_BYTES", "memory_guard.ceiling_bytes"),
+    ("MEMORY_GUARD_SOFT_RATIO", "memory_guard.soft_ratio"),
+    ("MEMORY_GUARD_HARD_RATIO", "memory_guard.hard_ratio"),
+    ("MEMORY_GUARD_INTERVAL_SECS", "memory_guard.interval_secs"),
+    ("QUEUE_BACKEND", "queue.backend"),
+    ("NATS_URL", "queue.nats.url"),
+    ("NATS_STREAM", "queue.nats.stream"),
+    ("NATS_SUBJECT_PREFIX", "queue.nats.subject_prefix"),
+    ("ATTACHMENT_BACKEND", "attachments.backend"),
+    ("ATTACHMENT_MAX_BYTES", "attachments.max_bytes"),
+    ("ATTACHMENT_MAX_PER_TASK", "attachments.max_per_task"),
+    ("ATTACHMENT_TTL_SECS", "attachments.ttl_secs"),
+    ("ATTACHMENT_S3_BUCKET", "attachments.s3.bucket"),
+    ("ATTACHMENT_S3_REGION", "attachments.s3.region"),
+    ("ATTACHMENT_S3_ENDPOINT", "attachments.s3.endpoint"),
+    ("QUEUE_MAX_DEPTH", "backpressure.max_depth"),
+    ("QUEUE_SHED", "backpressure.shed"),
+    ("QUEUE_RETRY_AFTER_SECS", "backpressure.retry_after_secs"),
+    ("QUEUE_SAMPLE_INTERVAL_SECS", "backpressure.interval_secs"),
+    ("QUOTA_DAILY_TASKS", "quotas.default.daily_tasks"),
+    ("QUOTA_DAILY_AGENT_SECS", "quotas.default.daily_agent_secs"),
+    ("QUOTA_DAILY_RESULT_BYTES", "quotas.default.daily_result_bytes"),
+    ("USAGE_RETENTION_DAYS", "quotas.retention_days"),
+    ("AGENT_HEARTBEAT_TTL_SECS", "agents.heartbeat_ttl_secs"),
+    ("AGENT_ORPHAN_CHECK_INTERVAL_SECS", "agents.orphan_check_interval_secs"),
+    ("AGENT_LEASE_SECS", "agents.lease_secs"),
+    ("HISTORY_DATABASE_URL", "history.database_url"),
+    ("HISTORY_SYNC_INTERVAL_SECS", "history.interval_secs"),
+    ("GRPC_PORT", "grpc.port"),
+    ("GRPC_WATCH_INTERVAL_MS", "grpc.watch_interval_ms"),
+    ("EVENTS_KAFKA_BROKERS", "events.brokers"),
+    ("EVENTS_KAFKA_TOPIC", "events.topic"),
+    ("EVENTS_BUFFER_SIZE", "events.buffer_size"),
+    ("EVENTS_INTERVAL_SECS", "events.interval_secs"),
+    ("SUBSCRIPTIONS_MAX_PER_OWNER", "subscriptions.max_per_owner"),
+    ("SUBSCRIPTIONS_TIMEOUT_SECS", "subscriptions.timeout_secs"),
+    ("SUBSCRIPTIONS_BUFFER_SIZE", "subscriptions.buffer_size"),
+    ("TASK_TIMEOUT_SECS", "watchdog.default_timeout_secs"),
+    ("TASK_MAX_TIMEOUT_SECS", "watchdog.max_timeout_secs"),
+    ("TASK_MAX_REQUEUES", "watchdog.max_requeues"),
+    ("WATCHDOG_INTERVAL_SECS", "watchdog.interval_secs"),
+    ("CANARY_ENABLED", "canary.enabled"),
+    ("CANARY_INTERVAL_SECS", "canary.interval_secs"),
+    ("CANARY_TIMEOUT_SECS", "canary.timeout_secs"),
+    ("SUPERVISOR_MAX_RESTARTS", "supervisor.max_restarts"),
+    ("SUPERVISOR_RESTART_WINDOW_SECS", "supervisor.restart_window_secs"),
+    ("BUFFER_ENABLED", "buffer.enabled"),
+    ("BUFFER_MAX_TASKS", "buffer.max_tasks"),
+    ("BUFFER_PATH", "buffer.path"),
+    ("TIMEOUT_WEBHOOK_URL", "watchdog.webhook_url"),
+    ("TELEGRAM_BOT_TOKEN", "telegram.bot_token"),
+    ("TELEGRAM_BREAKER_THRESHOLD", "telegram.breaker.threshold"),
+    (
+        "TELEGRAM_BREAKER_COOLDOWN_SECS",
+        "telegram.breaker.cooldown_secs",
+    ),
+    (
+        "READYZ_TELEGRAM_MAX_AGE_SECS",
+        "telegram.max_heartbeat_age_secs",
+    ),
+    ("TELEGRAM_PROXY_URL", "telegram.proxy_url"),
+    ("TELEGRAM_API_BASE", "telegram.api_base"),
+    (
+        "TELEGRAM_REQUEST_TIMEOUT_SECS",
+        "telegram.request_timeout_secs",
+    ),
+    (
+        "TELEGRAM_CONNECT_TIMEOUT_SECS",
+        "telegram.connect_timeout_secs",
+    ),
+    ("TELEGRAM_POLL_TIMEOUT_SECS", "telegram.poll_timeout_secs"),
+    ("TELEGRAM_BUSY_POLL_TIMEOUT_SECS", "telegram.busy_poll_timeout_secs"),
+    ("TELEGRAM_POLL_INTERVAL_MS", "telegram.poll_interval_ms"),
+    ("TELEGRAM_AFFINITY_TTL_SECS", "telegram.affinity_ttl_secs"),
+    ("TELEGRAM_MAX_IN_FLIGHT_PER_CHAT", "telegram.max_in_flight_per_chat"),
+    (
+        "TELEGRAM_MAX_DELIVERY_ATTEMPTS",
+        "telegram.max_delivery_attempts",
+    ),
+    ("TELEGRAM_MESSAGES_PER_SEC", "telegram.messages_per_sec"),
+    ("TELEGRAM_CHAT_MESSAGES_PER_MIN", "telegram.chat_messages_per_min"),
+    ("TELEGRAM_GROUP_MESSAGES_PER_MIN", "telegram.group_messages_per_min"),
+    ("TELEGRAM_FEEDBACK_BUTTONS", "telegram.feedback_buttons"),
+    ("TELEGRAM_TTS_URL", "telegram.tts_url"),
+    ("TELEGRAM_TTS_TOKEN", "telegram.tts_token"),
+    ("TELEGRAM_TTS_TIMEOUT_SECS", "telegram.tts_timeout_secs"),
+    ("EMAIL_IMAP_HOST", "email.imap_host"),
+    ("EMAIL_IMAP_PORT", "email.imap_port"),
+    ("EMAIL_MAILBOX", "email.mailbox"),
+    ("EMAIL_SMTP_HOST", "email.smtp_host"),
+    ("EMAIL_SMTP_PORT", "email.smtp_port"),
+    ("EMAIL_SMTP_STARTTLS", "email.smtp_starttls"),
+    ("EMAIL_USERNAME", "email.username"),
+    ("EMAIL_PASSWORD", "email.password"),
+    ("EMAIL_FROM_ADDRESS", "email.from_address"),
+    ("EMAIL_ALLOWED_SENDERS", "email.allowed_senders"),
+    ("EMAIL_POLL_INTERVAL_SECS", "email.poll_interval_secs"),
+    ("EMAIL_TIMEOUT_SECS", "email.timeout_secs"),
+    ("TWILIO_ACCOUNT_SID", "twilio.account_sid"),
+    ("TWILIO_AUTH_TOKEN", "twilio.auth_token"),
+    ("TWILIO_WEBHOOK_URL", "twilio.webhook_url"),
+    ("TWILIO_ALLOWED_SENDERS", "twilio.allowed_senders"),
+    ("TWILIO_MAX_MESSAGE_CHARS", "twilio.max_message_chars"),
+    ("TWILIO_MAX_PARTS", "twilio.max_parts"),
+    ("TWILIO_INTERVAL_SECS", "twilio.interval_secs"),
+    ("TWILIO_BROADCAST_PER_SEC", "twilio.broadcast_per_sec"),
+    ("TWILIO_API_BASE", "twilio.api_base"),
+    ("FEDERATION_NAME", "federation.name"),
+    ("FEDERATION_SECRET", "federation.secret"),
+    ("FEDERATION_PEERS", "federation.peers"),
+];
+
+/// String keys whose variables are taken verbatim, so `REDIS_PASSWORD=007`
+/// is not read as the number 7
+const TEXT_KEYS: &[&str] = &[
+    "server.bind_addr",
+    "redis.host",
+    "redis.password",
+    "redis.replica_host",
+    "auth.admin_token",
+    "auth.jwt_secret",
+    "auth.jwt_issuer",
+    "simulation.reply",
+    "telegram.bot_token",
+    "telegram.proxy_url",
+    "telegram.api_base",
+    "telegram.tts_url",
+    "telegram.tts_token",
+    "email.imap_host",
+    "email.mailbox",
+    "email.smtp_host",
+    "email.username",
+    "email.password",
+    "email.from_address",
+    "email.allowed_senders",
+    "twilio.account_sid",
+    "twilio.auth_token",
+    "twilio.webhook_url",
+    "twilio.allowed_senders",
+    "twilio.api_base",
+    "federation.name",
+    "federation.secret",
+    "queue.nats.url",
+    "queue.nats.stream",
+    "queue.nats.subject_prefix",
+    "history.database_url",
+    "events.brokers",
+    "events.topic",
+    "cors.allowed_origins",
+    "ip_filter.allow",
+    "ip_filter.deny",
+    "ip_filter.admin_allow",
+    "ip_filter.trusted_proxies",
+    "attachments.s3.bucket",
+    "attachments.s3.region",
+    "attachments.s3.endpoint",
+    "templates.task_url",
+    "buffer.path",
+];
+
+/// Subsystems with their own `RETRY_<NAME>_*` overrides
+const RETRY_SUBSYSTEMS: &[&str] = &["REDIS", "DELIVERY", "TELEGRAM", "TASK", "ADAPTOR"];
+
+/// Retry knobs shared by the global and per-subsystem variables
+const RETRY_FIELDS: &[&str] = &[
+    "MAX_ATTEMPTS",
+    "BASE_DELAY_MS",
+    "MAX_DELAY_MS",
+    "MULTIPLIER",
+    "JITTER",
+];
+
+/// Keys that may change at runtime without restarting the gateway
+pub const RELOADABLE_KEYS: &[&str] = &[
+    "limits.max_input_bytes",
+    "limits.max_config_bytes",
+    "limits.max_config_depth",
+    "limits.max_dependencies",
+    "limits.max_map_items",
+    "limits.max_annotations",
+    "limits.max_annotation_chars",
+    "limits.max_bulk_results",
+    "tasks.preview_chars",
+    "tasks.max_wait_secs",
+    "tasks.dedupe_window_secs",
+    "telegram.allowed_chats",
+    "telegram.max_heartbeat_age_secs",
+    "telegram.affinity_ttl_secs",
+    "telegram.max_in_flight_per_chat",
+    "telegram.max_delivery_attempts",
+    "telegram.feedback_buttons",
+    "email.allowed_senders",
+    "twilio.allowed_senders",
+    "subscriptions.max_per_owner",
+    "timeouts.read_secs",
+    "timeouts.write_secs",
+    "access_log.enabled",
+    "access_log.format",
+    "access_log.sample_rate",
+    "access_log.exclude_paths",
+    "access_log.body_sample_rate",
+    "access_log.max_body_bytes",
+    "access_log.redact_fields",
+];
+
+/// Key fragments whose values are never shown in full
+const SECRET_KEYS: &[&str] = &["token", "secret", "password", "database_url", "proxy_url"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSettings,
+    pub grpc: GrpcSettings,
+    pub redis: RedisSettings,
+    pub auth: AuthSettings,
+    pub ip_filter: IpFilterSettings,
+    pub cors: CorsSettings,
+    pub timeouts: TimeoutSettings,
+    pub concurrency: ConcurrencySettings,
+    pub access_log: AccessLogSettings,
+    pub tasks: TaskSettings,
+    pub limits: LimitSettings,
+    pub memory_guard: MemoryGuardSettings,
+    pub queue: QueueSettings,
+    pub attachments: AttachmentSettings,
+    pub backpressure: BackpressureSettings,
+    pub buffer: BufferSettings,
+    pub quotas: QuotaSettings,
+    pub agents: AgentRegistrySettings,
+    pub watchdog: WatchdogSettings,
+    pub canary: CanarySettings,
+    pub supervisor: SupervisorSettings,
+    pub history: HistorySettings,
+    pub events: EventSettings,
+    pub subscriptions: SubscriptionSettings,
+    pub telegram: TelegramSettings,
+    pub email: EmailSettings,
+    pub twilio: TwilioSettings,
+    pub federation: FederationSettings,
+    pub retry: RetrySettings,
+    pub result_signing: ResultSigningSettings,
+    pub encryption: EncryptionSettings,
+    pub redaction: RedactionSettings,
+    pub moderation: ModerationSettings,
+    pub templates: TemplateSettings,
+    pub simulation: SimulationSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// IP address, or `IP:port` to override `port`
+    pub bind_addr: String,
+    pub port: u16,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub tls_reload_interval_secs: u64,
+    /// Serve HTTP/2 next to HTTP/1.1 (ALPN with TLS, prior knowledge without)
+    pub http2: bool,
+    /// Requests a client may have in flight on one HTTP/2 connection
+    pub http2_max_concurrent_streams: u32,
+    /// Interval of HTTP/2 keep-alive pings to idle clients; 0 disables
+    pub http2_keep_alive_interval_secs: u64,
+    /// How long a keep-alive ping may go unanswered before the connection closes
+    pub http2_keep_alive_timeout_secs: u64,
+    /// Reuse HTTP/1.1 connections for further requests
+    pub keep_alive: bool,
+    /// How long an HTTP/1.1 connection may wait for the headers of its next
+    /// request, idle keep-alive time included
+    pub keep_alive_timeout_secs: u64,
+    /// Open connections beyond which new ones are refused; unlimited when unset
+    pub max_connections: Option<usize>,
+    /// Whether tasks go to agents or to the built-in simulator
+    pub mode: GatewayMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GatewayMode {
+    /// Agents take the queued tasks
+    #[default]
+    Normal,
+    /// The gateway answers queued tasks itself, see [`crate::simulation`]
+    Simulate,
+}
+
+impl GatewayMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GatewayMode::Normal => "normal",
+            GatewayMode::Simulate => "simulate",
+        }
+    }
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0".to_string(),
+            port: 8080,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_reload_interval_secs: 60,
+            http2: true,
+            http2_max_concurrent_streams: 200,
+            http2_keep_alive_interval_secs: 20,
+            http2_keep_alive_timeout_secs: 20,
+            keep_alive: true,
+            keep_alive_timeout_secs: 60,
+            max_connections: None,
+            mode: GatewayMode::Normal,
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        if let Ok(addr) = self.bind_addr.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        self.bind_addr
+            .parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .map_err(|_| format!("must be an IP address or IP:port, got {:?}", self.bind_addr))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisSettings {
+    pub host: String,
+    pub port: u16,
+    pub password: String,
+    /// Read replica serving result reads, with the same password
+    pub replica_host: Option<String>,
+    /// Port of `replica_host`; `port` when unset
+    pub replica_port: Option<u16>,
+}
+
+impl Default for RedisSettings {
+    fn default() -> Self {
+        Self {
+            host: "redis".to_string(),
+            port: 6379,
+            password: "default".to_string(),
+            replica_host: None,
+            replica_port: None,
+        }
+    }
+}
+
+impl RedisSettings {
+    pub fn url(&self) -> String {
+        format!("redis://:{}@{}:{}", self.password, self.host, self.port)
+    }
+
+    /// URL of the read replica, if one is configured
+    pub fn replica_url(&self) -> Option<String> {
+        let host = self.replica_host.as_ref()?;
+        let port = self.replica_port.unwrap_or(self.port);
+        Some(format!("redis://:{}@{}:{}", self.password, host, port))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Bearer token granting the admin role; admin routes are disabled without it
+    pub admin_token: Option<String>,
+    /// Largest accepted age of an HMAC-signed request, in seconds
+    pub signature_window_secs: u64,
+    /// HS256 secret JWT bearer tokens are signed with; JWTs are not
+    /// accepted without it
+    pub jwt_secret: Option<String>,
+    /// Issuer JWTs must name in `iss`, if any
+    pub jwt_issuer: Option<String>,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            admin_token: None,
+            signature_window_secs: 300,
+            jwt_secret: None,
+            jwt_issuer: None,
+        }
+    }
+}
+
+/// Client address rules, as CIDRs or single addresses
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterSettings {
+    /// Only these clients are served; empty serves everyone not denied
+    #[serde(deserialize_with = "list_from_spec")]
+    pub allow: Vec<String>,
+    /// Clients refused on every route, even when allowed
+    #[serde(deserialize_with = "list_from_spec")]
+    pub deny: Vec<String>,
+    /// Only these clients reach `/admin` routes; empty leaves them to `allow`
+    #[serde(deserialize_with = "list_from_spec")]
+    pub admin_allow: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` is believed
+    #[serde(deserialize_with = "list_from_spec")]
+    pub trusted_proxies: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSettings {
+    /// Origins browsers may call from, like `https://app.example.com`, with
+    /// `*` standing for any run of characters; empty allows none
+    #[serde(deserialize_with = "list_from_spec")]
+    pub allowed_origins: Vec<String>,
+    /// Request methods allowed cross-origin, or `*`
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed cross-origin, or `*`
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer
+    pub max_age_secs: u64,
+    /// Policies for paths under a prefix; unset fields use the values above
+    pub routes: BTreeMap<String, CorsOverrides>,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"]
+                .map(str::to_string)
+                .to_vec(),
+            allowed_headers: [
+                "authorization",
+                "content-type",
+                "accept",
+                "cache-control",
+                "x-request-id",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            allow_credentials: false,
+            max_age_secs: 600,
+            routes: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutSettings {
+    /// Seconds GET and HEAD requests may take to answer
+    pub read_secs: u64,
+    /// Seconds other requests may take to answer
+    pub write_secs: u64,
+    /// Budgets by path pattern, whose `*` segments match any one segment;
+    /// the longest pattern a path starts with wins
+    pub routes: BTreeMap<String, u64>,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        Self {
+            read_secs: 10,
+            write_secs: 30,
+            routes: BTreeMap::from([
+                ("/task/*/attachments".to_string(), 300),
+                ("/admin/support-bundle".to_string(), 60),
+            ]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencySettings {
+    /// Requests handled at once outside `exempt_paths`; unset is unlimited
+    pub max_in_flight: Option<usize>,
+    /// Requests handled at once by path pattern, like `[timeouts.routes]`
+    pub routes: BTreeMap<String, usize>,
+    /// Paths the global limit leaves alone, so probes answer under load
+    #[serde(deserialize_with = "list_from_spec")]
+    pub exempt_paths: Vec<String>,
+    /// Milliseconds a request waits for its turn before it is refused
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for ConcurrencySettings {
+    fn default() -> Self {
+        Self {
+            max_in_flight: None,
+            routes: BTreeMap::new(),
+            exempt_paths: ["/health", "/healthz", "/readyz", "/version"]
+                .map(str::to_string)
+                .to_vec(),
+            queue_timeout_ms: 1000,
+        }
+    }
+}
+
+/// How access log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// A log line like any other
+    #[default]
+    Text,
+    /// One JSON object per line on stdout
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogSettings {
+    /// Whether to log every HTTP request
+    pub enabled: bool,
+    pub format: AccessLogFormat,
+    /// Fraction of requests logged, 0.0 to 1.0; server errors always are
+    pub sample_rate: f64,
+    /// Paths never logged
+    #[serde(deserialize_with = "list_from_spec")]
+    pub exclude_paths: Vec<String>,
+    /// Fraction of logged requests whose bodies are captured too
+    pub body_sample_rate: f64,
+    /// Bodies larger than this, or of unknown length, are not captured
+    pub max_body_bytes: usize,
+    /// JSON and form fields whose values are masked in captured bodies
+    #[serde(deserialize_with = "list_from_spec")]
+    pub redact_fields: Vec<String>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: AccessLogFormat::Text,
+            sample_rate: 1.0,
+            exclude_paths: ["/health", "/healthz", "/readyz"]
+                .map(str::to_string)
+                .to_vec(),
+            body_sample_rate: 0.0,
+            max_body_bytes: 4096,
+            redact_fields: ["password", "token", "secret", "api_key", "authorization"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+/// Per-route CORS settings; unset fields use the global values
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsOverrides {
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskSettings {
+    /// Default number of result characters included in task list previews
+    pub preview_chars: usize,
+    /// Longest `GET /task/:id?wait=N` may hold a request, in seconds
+    pub max_wait_secs: u64,
+    /// Envelope version used when writing task and result records; 2
+    /// encrypts them when `encryption.secret` is set
+    pub envelope_version: u32,
+    /// How long a completed task answers identical re-submissions; 0 disables
+    pub dedupe_window_secs: u64,
+    /// Task keys an admin purge examines per SCAN batch
+    pub purge_batch_size: u64,
+    /// Pause between admin purge batches, keeping Redis responsive
+    pub purge_pause_ms: u64,
+    /// Interval between checks of waiting tasks for settled dependencies
+    pub dependency_interval_secs: u64,
+    /// Interval between checks of running pipelines for completed steps
+    pub pipeline_interval_secs: u64,
+    /// Interval between checks of map tasks for settled children
+    pub map_interval_secs: u64,
+}
+
+impl Default for TaskSettings {
+    fn default() -> Self {
+        Self {
+            preview_chars: 200,
+            max_wait_secs: 60,
+            envelope_version: envelope::CURRENT_VERSION,
+            dedupe_window_secs: 0,
+            purge_batch_size: 100,
+            purge_pause_ms: 200,
+            dependency_interval_secs: 1,
+            pipeline_interval_secs: 1,
+            map_interval_secs: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Hard cap on the raw request body, enforced before parsing
+    pub max_body_bytes: usize,
+    /// Cap on the serialized `input` field
+    pub max_input_bytes: usize,
+    /// Cap on the serialized `config` field
+    pub max_config_bytes: usize,
+    /// Maximum nesting depth of `config`
+    pub max_config_depth: usize,
+    /// Most tasks a submission may list in `depends_on`; 0 refuses dependencies
+    pub max_dependencies: usize,
+    /// Most elements a map task may fan out to; 0 refuses map tasks
+    pub max_map_items: usize,
+    /// Most annotations one task may carry
+    pub max_annotations: usize,
+    /// Longest annotation text, in characters
+    pub max_annotation_chars: usize,
+    /// Most tasks one `POST /tasks/results` may ask for
+    pub max_bulk_results: usize,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 1024 * 1024,
+            max_input_bytes: 256 * 1024,
+            max_config_bytes: 16 * 1024,
+            max_config_depth: 8,
+            max_dependencies: 32,
+            max_map_items: 100,
+            max_annotations: 100,
+            max_annotation_chars: 4000,
+            max_bulk_results: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryGuardSettings {
+    /// Explicit ceiling in bytes; Redis `maxmemory` is used when unset
+    pub ceiling_bytes: Option<u64>,
+    pub soft_ratio: f64,
+    pub hard_ratio: f64,
+    pub interval_secs: u64,
+}
+
+impl Default for MemoryGuardSettings {
+    fn default() -> Self {
+        Self {
+            ceiling_bytes: None,
+            soft_ratio: 0.80,
+            hard_ratio: 0.95,
+            interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSettings {
+    pub backend: QueueBackend,
+    /// Used when `backend = "nats"`
+    pub nats: NatsSettings,
+}
+
+/// Transport handing task ids to agents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueBackend {
+    /// `agent:queue*` lists in Redis
+    #[default]
+    Redis,
+    /// A JetStream work-queue stream
+    Nats,
+}
+
+impl QueueBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueBackend::Redis => "redis",
+            QueueBackend::Nats => "nats",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatsSettings {
+    pub url: String,
+    /// Work-queue stream, created if missing
+    pub stream: String,
+    /// Queues are published to subjects below this prefix
+    pub subject_prefix: String,
+}
+
+impl Default for NatsSettings {
+    fn default() -> Self {
+        Self {
+            url: "nats://nats:4222".to_string(),
+            stream: "CLAW_TASKS".to_string(),
+            subject_prefix: "claw.tasks".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentSettings {
+    pub backend: AttachmentBackend,
+    /// Largest accepted attachment in bytes
+    pub max_bytes: u64,
+    pub max_per_task: usize,
+    /// Attachments expire this long after upload
+    pub ttl_secs: u64,
+    pub s3: S3Settings,
+}
+
+impl Default for AttachmentSettings {
+    fn default() -> Self {
+        Self {
+            backend: AttachmentBackend::default(),
+            max_bytes: 25 * 1024 * 1024,
+            max_per_task: 10,
+            ttl_secs: 86_400,
+            s3: S3Settings::default(),
+        }
+    }
+}
+
+/// Where attachment contents are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentBackend {
+    /// Redis strings next to the task
+    #[default]
+    Redis,
+    /// An S3-compatible bucket
+    S3,
+}
+
+impl AttachmentBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttachmentBackend::Redis => "redis",
+            AttachmentBackend::S3 => "s3",
+        }
+    }
+}
+
+/// Bucket for attachments; credentials come from the usual `AWS_*` variables
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Settings {
+    pub bucket: Option<String>,
+    pub region: String,
+    /// Endpoint of an S3-compatible service such as MinIO
+    pub endpoint: Option<String>,
+    /// Object keys are this followed by `{task_id}/{attachment_id}`, or
+    /// `{task_id}/result/{name}` for raw results
+    pub prefix: String,
+}
+
+impl Default for S3Settings {
+    fn default() -> Self {
+        Self {
+            bucket: None,
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            prefix: "attachments/".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackpressureSettings {
+    /// Regular queue depth past which submissions are shed; unset disables
+    pub max_depth: Option<u64>,
+    pub shed: ShedMode,
+    pub retry_after_secs: u64,
+    pub interval_secs: u64,
+}
+
+impl Default for BackpressureSettings {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            shed: ShedMode::LowPriority,
+            retry_after_secs: 10,
+            interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferSettings {
+    /// Whether to buffer submissions locally while Redis is unreachable
+    pub enabled: bool,
+    /// Submissions buffered at most; further ones are refused
+    pub max_tasks: usize,
+    /// File keeping the buffer across restarts; unset keeps it in memory
+    pub path: Option<PathBuf>,
+    /// Interval between attempts to flush the buffer into Redis
+    pub flush_interval_secs: u64,
+}
+
+impl Default for BufferSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_tasks: 1000,
+            path: None,
+            flush_interval_secs: 5,
+        }
+    }
+}
+
+/// Which submissions are refused while the queue is over its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShedMode {
+    /// Regular submissions only; the priority lane stays open
+    LowPriority,
+    All,
+}
+
+impl ShedMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShedMode::LowPriority => "low_priority",
+            ShedMode::All => "all",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaSettings {
+    /// Limits for every API key without its own entry in `keys`
+    pub default: QuotaLimits,
+    /// Per-key limits by key id, overriding `default` field by field
+    pub keys: BTreeMap<String, QuotaLimits>,
+    /// Days of daily usage rollups kept in Redis
+    pub retention_days: u64,
+    /// Interval between sweeps accounting finished tasks
+    pub interval_secs: u64,
+}
+
+impl Default for QuotaSettings {
+    fn default() -> Self {
+        Self {
+            default: QuotaLimits::default(),
+            keys: BTreeMap::new(),
+            retention_days: 90,
+            interval_secs: 10,
+        }
+    }
+}
+
+impl QuotaSettings {
+    /// Effective limits for `key_id`
+    pub fn limits_for(&self, key_id: &str) -> QuotaLimits {
+        let Some(own) = self.keys.get(key_id) else {
+            return self.default.clone();
+        };
+        QuotaLimits {
+            daily_tasks: own.daily_tasks.or(self.default.daily_tasks),
+            daily_agent_secs: own.daily_agent_secs.or(self.default.daily_agent_secs),
+            daily_result_bytes: own.daily_result_bytes.or(self.default.daily_result_bytes),
+        }
+    }
+}
+
+/// Daily consumption limits; unset fields are unlimited
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaLimits {
+    pub daily_tasks: Option<u64>,
+    pub daily_agent_secs: Option<u64>,
+    pub daily_result_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentRegistrySettings {
+    /// Seconds an agent stays registered without a heartbeat
+    pub heartbeat_ttl_secs: u64,
+    /// Interval between sweeps for tasks held by vanished agents
+    pub orphan_check_interval_secs: u64,
+    /// Seconds a work claim's lease lasts unless the claim asks otherwise
+    pub lease_secs: u64,
+    /// Longest lease a claim or extension may ask for
+    pub max_lease_secs: u64,
+    /// Interval between sweeps for lapsed leases
+    pub lease_check_interval_secs: u64,
+}
+
+impl Default for AgentRegistrySettings {
+    fn default() -> Self {
+        Self {
+            heartbeat_ttl_secs: 30,
+            orphan_check_interval_secs: 15,
+            lease_secs: 60,
+            max_lease_secs: 900,
+            lease_check_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogSettings {
+    /// Seconds a task may take from submission when it names no timeout
+    pub default_timeout_secs: u64,
+    /// Largest `timeout_seconds` a submission may ask for
+    pub max_timeout_secs: u64,
+    /// Times a timed-out task is queued again before it is given up on
+    pub max_requeues: u32,
+    /// Interval between checks for overdue tasks
+    pub interval_secs: u64,
+    /// Notified with a JSON POST when an HTTP-submitted task times out
+    pub webhook_url: Option<String>,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        Self {
+            default_timeout_secs: 300,
+            max_timeout_secs: 24 * 3600,
+            max_requeues: 0,
+            interval_secs: 5,
+            webhook_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanarySettings {
+    /// Whether to send synthetic canary tasks through the agents
+    pub enabled: bool,
+    /// Interval between canaries
+    pub interval_secs: u64,
+    /// Seconds a canary may take before it counts as failed
+    pub timeout_secs: u64,
+}
+
+impl Default for CanarySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 60,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorSettings {
+    /// Restarts of a crashed channel adaptor allowed within
+    /// `restart_window_secs` before it is left down; 0 never restarts
+    pub max_restarts: u32,
+    pub restart_window_secs: u64,
+}
+
+impl Default for SupervisorSettings {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            restart_window_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySettings {
+    /// PostgreSQL connection URL; task history is off when unset
+    pub database_url: Option<String>,
+    pub max_connections: u32,
+    /// Interval between copies of pending tasks into the table
+    pub interval_secs: u64,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            database_url: None,
+            max_connections: 5,
+            interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcSettings {
+    /// Port of the gRPC API, on the `server.bind_addr` address; off when unset
+    pub port: Option<u16>,
+    /// How often `WatchTask` checks a task for status changes
+    pub watch_interval_ms: u64,
+}
+
+impl Default for GrpcSettings {
+    fn default() -> Self {
+        Self {
+            port: None,
+            watch_interval_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventSettings {
+    /// Kafka bootstrap brokers (`host:port`); event export is off when empty
+    #[serde(deserialize_with = "list_from_spec")]
+    pub brokers: Vec<String>,
+    pub topic: String,
+    /// Events held for the producer before tracking pauses
+    pub buffer_size: usize,
+    /// Most events sent to Kafka in one request
+    pub batch_size: usize,
+    /// Interval between checks of tracked tasks for new events
+    pub interval_secs: u64,
+}
+
+impl Default for EventSettings {
+    fn default() -> Self {
+        Self {
+            brokers: Vec::new(),
+            topic: "claw.task-events".to_string(),
+            buffer_size: 10_000,
+            batch_size: 100,
+            interval_secs: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionSettings {
+    /// Most subscriptions one API key may hold
+    pub max_per_owner: usize,
+    /// Timeout of each webhook delivery attempt
+    pub timeout_secs: u64,
+    /// Events held for delivery before tracking pauses
+    pub buffer_size: usize,
+}
+
+impl Default for SubscriptionSettings {
+    fn default() -> Self {
+        Self {
+            max_per_owner: 20,
+            timeout_secs: 10,
+            buffer_size: 1_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramSettings {
+    /// Bot API token; the adaptor is disabled without it
+    pub bot_token: Option<String>,
+    /// Oldest adaptor loop heartbeat `/readyz` still accepts
+    pub max_heartbeat_age_secs: u64,
+    /// Chats the bot answers; every chat is served when empty
+    pub allowed_chats: Vec<i64>,
+    /// Proxy for Bot API calls (`http://`, `https://` or `socks5://`, with
+    /// credentials in the URL if needed)
+    pub proxy_url: Option<String>,
+    /// Bot API server, e.g. a local Bot API server or a test fake
+    pub api_base: String,
+    /// Limit on a whole Bot API call other than long polls
+    pub request_timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    /// How long a getUpdates long poll waits for messages
+    pub poll_timeout_secs: u64,
+    /// How long it waits instead while replies are awaited, so results go
+    /// out without waiting for the next message
+    pub busy_poll_timeout_secs: u64,
+    /// Pause after a long poll that brought no updates; there is none
+    /// after one that did
+    pub poll_interval_ms: u64,
+    /// How long a chat's tasks keep going to the agent that took its last
+    /// one; 0 spreads them over every agent
+    pub affinity_ttl_secs: u64,
+    /// Tasks a chat may have awaiting an answer at once; further messages
+    /// are asked to wait. 0 for no limit
+    pub max_in_flight_per_chat: usize,
+    /// Passes of the response loop a reply is tried on before its result
+    /// is given up as undeliverable
+    pub max_delivery_attempts: u32,
+    /// Messages a bot sends a second across its chats
+    pub messages_per_sec: u32,
+    /// Messages a bot sends a minute to one private chat
+    pub chat_messages_per_min: u32,
+    /// Messages a bot sends a minute to one group or channel
+    pub group_messages_per_min: u32,
+    /// Put 👍/👎 buttons under text replies for rating the result
+    pub feedback_buttons: bool,
+    /// Text-to-speech service voice replies are made with, given
+    /// `{"text", "language"}` and answering OGG/Opus audio; voice replies
+    /// are off without it
+    pub tts_url: Option<String>,
+    /// Bearer token for `tts_url`
+    pub tts_token: Option<String>,
+    pub tts_timeout_secs: u64,
+    /// Further bots run beside `bot_token`'s, each with its own state
+    pub bots: Vec<BotSettings>,
+    pub breaker: BreakerSettings,
+}
+
+/// A further Telegram bot, for a team or an agent persona
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotSettings {
+    /// Names the bot in logs, health reports and metrics, and its state in
+    /// Redis, under `telegram:bots:{name}:`; `default` is
+    /// `telegram.bot_token`'s
+    pub name: String,
+    pub token: String,
+    /// Capability the bot's tasks ask for, so they go to the agents that
+    /// have it; any agent takes them when unset
+    pub capability: Option<String>,
+}
+
+impl Default for TelegramSettings {
+    fn default() -> Self {
+        Self {
+            bot_token: None,
+            max_heartbeat_age_secs: 120,
+            allowed_chats: Vec::new(),
+            proxy_url: None,
+            api_base: "https://api.telegram.org".to_string(),
+            request_timeout_secs: 10,
+            connect_timeout_secs: 5,
+            poll_timeout_secs: 30,
+            busy_poll_timeout_secs: 1,
+            poll_interval_ms: 0,
+            affinity_ttl_secs: 1800,
+            max_in_flight_per_chat: 1,
+            max_delivery_attempts: 5,
+            messages_per_sec: 30,
+            chat_messages_per_min: 60,
+            group_messages_per_min: 20,
+            feedback_buttons: true,
+            tts_url: None,
+            tts_token: None,
+            tts_timeout_secs: 30,
+            bots: Vec::new(),
+            breaker: BreakerSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailSettings {
+    /// IMAP server polled for requests, over TLS; the adaptor is disabled
+    /// without it
+    pub imap_host: Option<String>,
+    pub imap_port: u16,
+    /// Mailbox whose unseen messages become tasks
+    pub mailbox: String,
+    /// SMTP server replies are sent through
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    /// Upgrade a plain SMTP connection with STARTTLS (port 587) rather than
+    /// speaking TLS from the start (port 465)
+    pub smtp_starttls: bool,
+    /// Login for both IMAP and SMTP
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender of replies; `username` when unset
+    pub from_address: Option<String>,
+    /// Addresses (`alice@example.com`) or domains (`@example.com`) whose
+    /// mail is answered; every sender is served when empty
+    #[serde(deserialize_with = "list_from_spec")]
+    pub allowed_senders: Vec<String>,
+    /// Pause between mailbox checks
+    pub poll_interval_secs: u64,
+    /// Limit on connecting to and each exchange with either server
+    pub timeout_secs: u64,
+}
+
+impl Default for EmailSettings {
+    fn default() -> Self {
+        Self {
+            imap_host: None,
+            imap_port: 993,
+            mailbox: "INBOX".to_string(),
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_starttls: true,
+            username: None,
+            password: None,
+            from_address: None,
+            allowed_senders: Vec::new(),
+            poll_interval_secs: 30,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwilioSettings {
+    /// Account the SMS/WhatsApp numbers belong to; the adaptor is disabled
+    /// without it
+    pub account_sid: Option<String>,
+    /// Signs inbound webhooks and authenticates REST API calls
+    pub auth_token: Option<String>,
+    /// Public URL of `POST /twilio/webhook` exactly as configured in
+    /// Twilio, which the webhook signature covers
+    pub webhook_url: Option<String>,
+    /// Numbers (`+15551234567`) whose messages are answered, on SMS and
+    /// WhatsApp alike; every sender is served when empty
+    #[serde(deserialize_with = "list_from_spec")]
+    pub allowed_senders: Vec<String>,
+    /// Longest message body sent; longer results are split
+    pub max_message_chars: usize,
+    /// Most messages a result is split into; the rest is cut off
+    pub max_parts: usize,
+    /// Interval between checks of pending tasks for results to deliver
+    pub interval_secs: u64,
+    /// Messages a second a broadcast sends, Twilio queueing anything
+    /// faster than a number may send
+    pub broadcast_per_sec: u32,
+    pub api_base: String,
+}
+
+impl Default for TwilioSettings {
+    fn default() -> Self {
+        Self {
+            account_sid: None,
+            auth_token: None,
+            webhook_url: None,
+            allowed_senders: Vec::new(),
+            max_message_chars: 1600,
+            max_parts: 10,
+            interval_secs: 2,
+            broadcast_per_sec: 1,
+            api_base: "https://api.twilio.com".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerSettings {
+    /// Consecutive failures that open the circuit
+    pub threshold: u32,
+    pub cooldown_secs: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            cooldown_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationSettings {
+    /// Name this gateway signs forwarded requests with
+    pub name: String,
+    pub secret: Option<String>,
+    /// Peer name to base URL; also accepts the `name=url,...` string form
+    #[serde(deserialize_with = "peers_from_spec")]
+    pub peers: BTreeMap<String, String>,
+}
+
+impl Default for FederationSettings {
+    fn default() -> Self {
+        Self {
+            name: "gateway".to_string(),
+            secret: None,
+            peers: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySettings {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    /// Fraction of each delay randomised, 0.0 (none) to 1.0 (full jitter)
+    pub jitter: f64,
+    pub redis: RetryOverrides,
+    /// Outbound deliveries (webhooks, federation peers, Kafka event export)
+    pub delivery: RetryOverrides,
+    pub telegram: RetryOverrides,
+    /// Delay before the watchdog queues an overdue task again
+    pub task: RetryOverrides,
+    /// Delay before a crashed channel adaptor is started again
+    pub adaptor: RetryOverrides,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 100,
+            max_delay_ms: 10_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            redis: RetryOverrides::default(),
+            delivery: RetryOverrides::default(),
+            telegram: RetryOverrides::default(),
+            task: RetryOverrides {
+                base_delay_ms: Some(1_000),
+                max_delay_ms: Some(60_000),
+                ..RetryOverrides::default()
+            },
+            adaptor: RetryOverrides {
+                base_delay_ms: Some(1_000),
+                max_delay_ms: Some(60_000),
+                ..RetryOverrides::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionSettings {
+    /// Base64 AES-256 key task, result and error records are encrypted
+    /// with; records are stored in the clear without it
+    pub secret: Option<String>,
+    /// Id of `secret`, stored with each record it encrypts
+    pub key_id: String,
+    /// Earlier secrets by key id, still read after a key rotation
+    pub previous_secrets: BTreeMap<String, String>,
+}
+
+impl Default for EncryptionSettings {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            key_id: "1".to_string(),
+            previous_secrets: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactionSettings {
+    /// Redact the task inputs of tenants without an entry in `tenants`
+    pub enabled: bool,
+    /// Built-in detectors applied: `email`, `phone`, `credit_card`
+    #[serde(deserialize_with = "list_from_spec")]
+    pub detectors: Vec<String>,
+    /// Further regular expressions whose matches are redacted
+    pub patterns: Vec<String>,
+    /// Redaction on or off by tenant (API key id, `anonymous`, or the
+    /// adaptors `telegram`, `email` and `twilio`)
+    pub tenants: BTreeMap<String, bool>,
+    /// Redact log output as well
+    pub logs: bool,
+}
+
+impl Default for RedactionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            detectors: redaction::DETECTORS.iter().map(|d| d.to_string()).collect(),
+            patterns: Vec::new(),
+            tenants: BTreeMap::new(),
+            logs: false,
+        }
+    }
+}
+
+/// What a task failing content moderation comes to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Refuse the submission
+    #[default]
+    Reject,
+    /// Queue the task with the findings recorded on it
+    Flag,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationSettings {
+    /// Regular expressions no string in a task input may match
+    pub blocklist: Vec<String>,
+    /// Most characters in a task input's strings; 0 for no limit
+    pub max_input_chars: usize,
+    /// Most tokens in a task input, estimated at four characters each; 0 for
+    /// no limit
+    pub max_input_tokens: usize,
+    pub action: ModerationAction,
+    /// Moderation endpoint task inputs are posted to, answering like the
+    /// OpenAI moderation API
+    pub api_url: Option<String>,
+    /// Bearer token for `api_url`
+    pub api_token: Option<String>,
+    pub api_timeout_secs: u64,
+    /// Treat inputs the API could not check as failing it
+    pub fail_closed: bool,
+}
+
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        Self {
+            blocklist: Vec::new(),
+            max_input_chars: 0,
+            max_input_tokens: 0,
+            action: ModerationAction::Reject,
+            api_url: None,
+            api_token: None,
+            api_timeout_secs: 5,
+            fail_closed: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateSettings {
+    /// Link to a task offered to reply templates as `link`, with `{task_id}`
+    /// standing for the task id
+    pub task_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationSettings {
+    /// How long the simulator takes over a task before answering it
+    pub delay_ms: u64,
+    /// Text every task is answered with; tasks are echoed when unset
+    pub reply: Option<String>,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        Self {
+            delay_ms: 1000,
+            reply: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResultSigningSettings {
+    /// Ed25519 private key (PKCS#8, PEM or DER) task results are signed
+    /// with; results go unsigned without it
+    pub key_path: Option<PathBuf>,
+    /// Key id reported with signatures; derived from the public key when unset
+    pub key_id: Option<String>,
+}
+
+/// Per-subsystem retry settings; unset fields use the global values
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryOverrides {
+    pub max_attempts: Option<u32>,
+    pub base_delay_ms: Option<u64>,
+    pub max_delay_ms: Option<u64>,
+    pub multiplier: Option<f64>,
+    pub jitter: Option<f64>,
+}
+
+impl Config {
+    /// Load and validate the configuration from all sources
+    pub fn load() -> anyhow::Result<Self> {
+        let mut figment = Figment::from(Serialized::defaults(Config::default()));
+
+        if let Some(path) = std::env::var_os(CONFIG_PATH_VAR) {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                anyhow::bail!(
+                    "{} points to {}, which is not a file",
+                    CONFIG_PATH_VAR,
+                    path.display()
+                );
+            }
+            let yaml = path
+                .extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml");
+            figment = if yaml {
+                figment.merge(Yaml::file(&path))
+            } else {
+                figment.merge(Toml::file(&path))
+            };
+        }
+
+        figment = figment
+            .merge(Env::raw().filter_map(|key| {
+                let key = key.as_str();
+                is_set(key)
+                    .then(|| legacy_key(key))
+                    .flatten()
+                    .map(Into::into)
+            }))
+            .merge(
+                Env::prefixed(ENV_PREFIX)
+                    .ignore(&["CONFIG"])
+                    .filter(|key| is_set(&format!("{}{}", ENV_PREFIX, key)))
+                    .split("__"),
+            );
+        for (key, value) in text_overrides() {
+            figment = figment.merge(Serialized::default(key, value));
+        }
+
+        let config: Config = figment.extract().map_err(|e| {
+            let problems: Vec<String> = e.into_iter().map(|e| e.to_string()).collect();
+            anyhow::anyhow!("Invalid configuration:\n  {}", problems.join("\n  "))
+        })?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check cross-field constraints, reporting every violation at once
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, key: &str, message: &str| {
+            if !ok {
+                problems.push(format!("{}: {}", key, message));
+            }
+        };
+
+        if let Err(e) = self.server.socket_addr() {
+            check(false, "server.bind_addr", &e);
+        }
+        check(
+            self.server.tls_cert_path.is_some() == self.server.tls_key_path.is_some(),
+            "server.tls_cert_path",
+            "tls_cert_path and tls_key_path must be set together",
+        );
+        check(
+            self.result_signing.key_id.as_ref().is_none_or(|id| !id.is_empty()),
+            "result_signing.key_id",
+            "must not be empty",
+        );
+        let server = &self.server;
+        for (key, value) in [
+            (
+                "server.tls_reload_interval_secs",
+                server.tls_reload_interval_secs,
+            ),
+            (
+                "server.http2_max_concurrent_streams",
+                u64::from(server.http2_max_concurrent_streams),
+            ),
+            (
+                "server.http2_keep_alive_timeout_secs",
+                server.http2_keep_alive_timeout_secs,
+            ),
+            ("server.keep_alive_timeout_secs", server.keep_alive_timeout_secs),
+            (
+                "server.max_connections",
+                server.max_connections.unwrap_or(1) as u64,
+            ),
+        ] {
+            check(value > 0, key, "must be greater than zero");
+        }
+        check(
+            !self.redis.host.is_empty(),
+            "redis.host",
+            "must not be empty",
+        );
+        check(
+            self.redis.replica_host.as_ref().is_none_or(|h| !h.is_empty()),
+            "redis.replica_host",
+            "must not be empty",
+        );
+        check(
+            self.tasks.envelope_version <= envelope::CURRENT_VERSION,
+            "tasks.envelope_version",
+            &format!("newest supported version is {}", envelope::CURRENT_VERSION),
+        );
+        let encryption = &self.encryption;
+        if let Some(secret) = &encryption.secret {
+            if let Err(e) = envelope::parse_secret(secret) {
+                let message = format!("must be 32 bytes of base64 ({})", e);
+                check(false, "encryption.secret", &message);
+            }
+            check(
+                self.tasks.envelope_version >= 2,
+                "tasks.envelope_version",
+                "must be 2 for records to be encrypted",
+            );
+        }
+        check(!encryption.key_id.is_empty(), "encryption.key_id", "must not be empty");
+        check(
+            self.timeouts.read_secs > 0 && self.timeouts.write_secs > 0,
+            "timeouts",
+            "read_secs and write_secs must be greater than zero",
+        );
+        check(
+            self.concurrency.max_in_flight != Some(0),
+            "concurrency.max_in_flight",
+            "must be greater than zero",
+        );
+        for (pattern, limit) in &self.concurrency.routes {
+            check(
+                pattern.starts_with('/') && *limit > 0,
+                &format!("concurrency.routes.{}", pattern),
+                "must be a path with a limit greater than zero",
+            );
+        }
+        for (pattern, secs) in &self.timeouts.routes {
+            check(
+                pattern.starts_with('/') && *secs > 0,
+                &format!("timeouts.routes.{}", pattern),
+                "must be a path with a budget greater than zero",
+            );
+        }
+        let access_log = &self.access_log;
+        for (key, rate) in [
+            ("access_log.sample_rate", access_log.sample_rate),
+            ("access_log.body_sample_rate", access_log.body_sample_rate),
+        ] {
+            check(
+                (0.0..=1.0).contains(&rate),
+                key,
+                "must be between 0.0 and 1.0",
+            );
+        }
+        check(
+            access_log.max_body_bytes > 0,
+            "access_log.max_body_bytes",
+            "must be greater than zero",
+        );
+        check(
+            access_log.exclude_paths.iter().all(|p| p.starts_with('/')),
+            "access_log.exclude_paths",
+            "paths must start with /",
+        );
+        for detector in &self.redaction.detectors {
+            let message = format!(
+                "unknown detector {}; known are {}",
+                detector,
+                redaction::DETECTORS.join(", ")
+            );
+            check(
+                redaction::DETECTORS.contains(&detector.as_str()),
+                "redaction.detectors",
+                &message,
+            );
+        }
+        for pattern in &self.redaction.patterns {
+            if let Err(e) = regex::Regex::new(pattern) {
+                check(false, "redaction.patterns", &e.to_string());
+            }
+        }
+        for pattern in &self.moderation.blocklist {
+            if let Err(e) = regex::Regex::new(pattern) {
+                check(false, "moderation.blocklist", &e.to_string());
+            }
+        }
+        if let Some(url) = &self.moderation.api_url {
+            check(
+                reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+                "moderation.api_url",
+                "must be an http(s) URL",
+            );
+        }
+        check(
+            self.moderation.api_timeout_secs > 0,
+            "moderation.api_timeout_secs",
+            "must be greater than zero",
+        );
+        if let Some(url) = &self.templates.task_url {
+            check(
+                reqwest::Url::parse(&url.replace("{task_id}", "0"))
+                    .is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+                "templates.task_url",
+                "must be an http(s) URL",
+            );
+        }
+        for (key_id, secret) in &encryption.previous_secrets {
+            check(
+                key_id != &encryption.key_id && envelope::parse_secret(secret).is_ok(),
+                "encryption.previous_secrets",
+                &format!("{} must be another key id with 32 bytes of base64", key_id),
+            );
+        }
+
+        let limits = &self.limits;
+        for (key, value) in [
+            ("limits.max_body_bytes", limits.max_body_bytes),
+            ("limits.max_input_bytes", limits.max_input_bytes),
+            ("limits.max_config_bytes", limits.max_config_bytes),
+            ("limits.max_config_depth", limits.max_config_depth),
+            ("limits.max_annotation_chars", limits.max_annotation_chars),
+            ("limits.max_bulk_results", limits.max_bulk_results),
+        ] {
+            check(value > 0, key, "must be greater than zero");
+        }
+
+        let guard = &self.memory_guard;
+        check(
+            0.0 < guard.soft_ratio
+                && guard.soft_ratio <= guard.hard_ratio
+                && guard.hard_ratio <= 1.0,
+            "memory_guard",
+            "ratios must satisfy 0 < soft_ratio <= hard_ratio <= 1",
+        );
+        check(
+            guard.interval_secs > 0,
+            "memory_guard.interval_secs",
+            "must be greater than zero",
+        );
+
+        let queue = &self.queue;
+        check(
+            queue.backend != QueueBackend::Nats || cfg!(feature = "nats"),
+            "queue.backend",
+            "nats requires a gateway built with the `nats` feature",
+        );
+        check(
+            queue.backend != QueueBackend::Nats || !queue.nats.url.is_empty(),
+            "queue.nats.url",
+            "must be set when queue.backend is nats",
+        );
+        check(
+            self.server.mode != GatewayMode::Simulate || queue.backend == QueueBackend::Redis,
+            "server.mode",
+            "simulate requires the redis queue backend",
+        );
+        check(
+            !queue.nats.stream.is_empty()
+                && !queue.nats.stream.contains(['.', '*', '>', ' ']),
+            "queue.nats.stream",
+            "must be a non-empty name without '.', '*', '>' or spaces",
+        );
+        check(
+            !queue.nats.subject_prefix.is_empty()
+                && queue.nats.subject_prefix.split('.').all(|token| {
+                    !token.is_empty() && !token.contains(['*', '>', ' '])
+                }),
+            "queue.nats.subject_prefix",
+            "must be dot-separated tokens without wildcards or spaces",
+        );
+
+        let ip_filter = &self.ip_filter;
+        for (key, entries) in [
+            ("ip_filter.allow", &ip_filter.allow),
+            ("ip_filter.deny", &ip_filter.deny),
+            ("ip_filter.admin_allow", &ip_filter.admin_allow),
+            ("ip_filter.trusted_proxies", &ip_filter.trusted_proxies),
+        ] {
+            for entry in entries {
+                check(
+                    ip_filter::parse_net(entry).is_some(),
+                    key,
+                    &format!("{:?} is not an IP address or CIDR", entry),
+                );
+            }
+        }
+
+        let cors = &self.cors;
+        let global = CorsOverrides {
+            allowed_origins: Some(cors.allowed_origins.clone()),
+            allowed_methods: Some(cors.allowed_methods.clone()),
+            allowed_headers: Some(cors.allowed_headers.clone()),
+            allow_credentials: Some(cors.allow_credentials),
+            max_age_secs: Some(cors.max_age_secs),
+        };
+        let policies = std::iter::once(("cors".to_string(), &global)).chain(
+            cors.routes
+                .iter()
+                .map(|(prefix, overrides)| (format!("cors.routes.{:?}", prefix), overrides)),
+        );
+        for (key, policy) in policies {
+            if let Some(prefix) = key.strip_prefix("cors.routes.") {
+                check(
+                    prefix.starts_with("\"/"),
+                    &key,
+                    "route prefixes must start with '/'",
+                );
+            }
+            let origins = policy.allowed_origins.as_ref().unwrap_or(&cors.allowed_origins);
+            let credentials = policy.allow_credentials.unwrap_or(cors.allow_credentials);
+            for origin in origins {
+                check(
+                    origin == "*"
+                        || (origin.starts_with("http://") || origin.starts_with("https://"))
+                            && origin.trim_end_matches('/') == origin,
+                    &format!("{}.allowed_origins", key),
+                    &format!("{:?} is not * or an http(s) origin", origin),
+                );
+            }
+            check(
+                !(credentials && origins.iter().any(|o| o == "*")),
+                &format!("{}.allow_credentials", key),
+                "cannot be combined with allowed_origins = [\"*\"]",
+            );
+            for method in policy.allowed_methods.iter().flatten() {
+                check(
+                    method == "*" || method.parse::<http::Method>().is_ok(),
+                    &format!("{}.allowed_methods", key),
+                    &format!("{:?} is not an HTTP method", method),
+                );
+            }
+            for name in policy.allowed_headers.iter().flatten() {
+                check(
+                    name == "*" || name.parse::<http::HeaderName>().is_ok(),
+                    &format!("{}.allowed_headers", key),
+                    &format!("{:?} is not a header name", name),
+                );
+            }
+        }
+
+        let attachments = &self.attachments;
+        check(
+            attachments.backend != AttachmentBackend::S3 || cfg!(feature = "s3"),
+            "attachments.backend",
+            "s3 requires a gateway built with the `s3` feature",
+        );
+        check(
+            attachments.backend != AttachmentBackend::S3
+                || attachments.s3.bucket.as_deref().is_some_and(|b| !b.is_empty()),
+            "attachments.s3.bucket",
+            "must be set when attachments.backend is s3",
+        );
+        check(
+            attachments.s3.endpoint.as_deref().is_none_or(|e| {
+                reqwest::Url::parse(e).is_ok_and(|u| matches!(u.scheme(), "http" | "https"))
+            }),
+            "attachments.s3.endpoint",
+            "must be an http(s) URL",
+        );
+
+        check(
+            self.auth.jwt_secret.as_ref().is_none_or(|s| s.len() >= 32),
+            "auth.jwt_secret",
+            "must be at least 32 bytes",
+        );
+
+        let backpressure = &self.backpressure;
+        let telegram = &self.telegram;
+        for (key, value) in [
+            ("backpressure.max_depth", backpressure.max_depth.unwrap_or(1)),
+            ("auth.signature_window_secs", self.auth.signature_window_secs),
+            ("tasks.purge_batch_size", self.tasks.purge_batch_size),
+            (
+                "tasks.dependency_interval_secs",
+                self.tasks.dependency_interval_secs,
+            ),
+            (
+                "tasks.pipeline_interval_secs",
+                self.tasks.pipeline_interval_secs,
+            ),
+            ("tasks.map_interval_secs", self.tasks.map_interval_secs),
+            ("attachments.max_bytes", attachments.max_bytes),
+            ("attachments.max_per_task", attachments.max_per_task as u64),
+            ("attachments.ttl_secs", attachments.ttl_secs),
+            ("backpressure.retry_after_secs", backpressure.retry_after_secs),
+            ("backpressure.interval_secs", backpressure.interval_secs),
+            ("buffer.max_tasks", self.buffer.max_tasks as u64),
+            ("buffer.flush_interval_secs", self.buffer.flush_interval_secs),
+            ("quotas.retention_days", self.quotas.retention_days),
+            ("quotas.interval_secs", self.quotas.interval_secs),
+            ("agents.heartbeat_ttl_secs", self.agents.heartbeat_ttl_secs),
+            ("telegram.request_timeout_secs", telegram.request_timeout_secs),
+            ("telegram.connect_timeout_secs", telegram.connect_timeout_secs),
+            ("telegram.poll_timeout_secs", telegram.poll_timeout_secs),
+            (
+                "telegram.busy_poll_timeout_secs",
+                telegram.busy_poll_timeout_secs,
+            ),
+            (
+                "telegram.max_delivery_attempts",
+                telegram.max_delivery_attempts as u64,
+            ),
+            (
+                "telegram.messages_per_sec",
+                u64::from(telegram.messages_per_sec),
+            ),
+            (
+                "telegram.chat_messages_per_min",
+                u64::from(telegram.chat_messages_per_min),
+            ),
+            (
+                "telegram.group_messages_per_min",
+                u64::from(telegram.group_messages_per_min),
+            ),
+            ("telegram.tts_timeout_secs", telegram.tts_timeout_secs),
+            ("email.poll_interval_secs", self.email.poll_interval_secs),
+            ("email.timeout_secs", self.email.timeout_secs),
+            ("twilio.max_parts", self.twilio.max_parts as u64),
+            ("twilio.interval_secs", self.twilio.interval_secs),
+            (
+                "twilio.broadcast_per_sec",
+                u64::from(self.twilio.broadcast_per_sec),
+            ),
+            (
+                "agents.orphan_check_interval_secs",
+                self.agents.orphan_check_interval_secs,
+            ),
+            ("agents.lease_secs", self.agents.lease_secs),
+            (
+                "agents.lease_check_interval_secs",
+                self.agents.lease_check_interval_secs,
+            ),
+            (
+                "watchdog.default_timeout_secs",
+                self.watchdog.default_timeout_secs,
+            ),
+            ("watchdog.interval_secs", self.watchdog.interval_secs),
+            ("canary.interval_secs", self.canary.interval_secs),
+            ("canary.timeout_secs", self.canary.timeout_secs),
+            (
+                "supervisor.restart_window_secs",
+                self.supervisor.restart_window_secs,
+            ),
+            ("history.interval_secs", self.history.interval_secs),
+            ("grpc.watch_interval_ms", self.grpc.watch_interval_ms),
+            ("events.buffer_size", self.events.buffer_size as u64),
+            ("events.batch_size", self.events.batch_size as u64),
+            ("events.interval_secs", self.events.interval_secs),
+            (
+                "subscriptions.max_per_owner",
+                self.subscriptions.max_per_owner as u64,
+            ),
+            ("subscriptions.timeout_secs", self.subscriptions.timeout_secs),
+            (
+                "subscriptions.buffer_size",
+                self.subscriptions.buffer_size as u64,
+            ),
+            (
+                "history.max_connections",
+                u64::from(self.history.max_connections),
+            ),
+        ] {
+            check(value > 0, key, "must be greater than zero");
+        }
+        check(
+            self.watchdog.default_timeout_secs <= self.watchdog.max_timeout_secs,
+            "watchdog.max_timeout_secs",
+            "must be at least watchdog.default_timeout_secs",
+        );
+        if let Some(url) = &self.history.database_url {
+            check(
+                url.starts_with("postgres://") || url.starts_with("postgresql://"),
+                "history.database_url",
+                "must be a postgres:// URL",
+            );
+            check(
+                cfg!(feature = "postgres"),
+                "history.database_url",
+                "requires a gateway built with the `postgres` feature",
+            );
+        }
+        if let Some(port) = self.grpc.port {
+            check(
+                cfg!(feature = "grpc"),
+                "grpc.port",
+                "requires a gateway built with the `grpc` feature",
+            );
+            check(
+                self.server.socket_addr().map_or(true, |addr| addr.port() != port),
+                "grpc.port",
+                "must differ from the REST port",
+            );
+        }
+        if !self.events.brokers.is_empty() {
+            check(
+                cfg!(feature = "kafka"),
+                "events.brokers",
+                "requires a gateway built with the `kafka` feature",
+            );
+            check(
+                self.events.brokers.iter().all(|broker| {
+                    broker
+                        .rsplit_once(':')
+                        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+                }),
+                "events.brokers",
+                "must be host:port entries",
+            );
+            check(
+                !self.events.topic.is_empty(),
+                "events.topic",
+                "must be set when events.brokers is",
+            );
+        }
+        if let Some(url) = &self.watchdog.webhook_url {
+            check(
+                reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+                "watchdog.webhook_url",
+                "must be an http(s) URL",
+            );
+        }
+        if let Some(url) = &self.telegram.proxy_url {
+            check(
+                reqwest::Url::parse(url).is_ok_and(|u| {
+                    matches!(u.scheme(), "http" | "https" | "socks5" | "socks5h")
+                }) && reqwest::Proxy::all(url).is_ok(),
+                "telegram.proxy_url",
+                "must be an http(s) or socks5 URL",
+            );
+        }
+        check(
+            reqwest::Url::parse(&self.telegram.api_base)
+                .is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+            "telegram.api_base",
+            "must be an http(s) URL",
+        );
+        for (i, bot) in self.telegram.bots.iter().enumerate() {
+            let named = !bot.name.is_empty()
+                && bot.name.chars().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_')
+                });
+            check(named, "telegram.bots.name", "must be lowercase letters, digits, - or _");
+            check(
+                !self.telegram.bots[..i].iter().any(|b| b.name == bot.name),
+                "telegram.bots.name",
+                "must be unique",
+            );
+            // Health reports name telegram.bot_token's bot `default`
+            check(bot.name != "default", "telegram.bots.name", "must not be default");
+            check(!bot.token.is_empty(), "telegram.bots.token", "must be set");
+            check(
+                Some(&bot.token) != self.telegram.bot_token.as_ref()
+                    && !self.telegram.bots[..i].iter().any(|b| b.token == bot.token),
+                "telegram.bots.token",
+                "must differ from the other bots'",
+            );
+            if let Some(capability) = &bot.capability {
+                check(
+                    validation::is_valid_capability(capability),
+                    "telegram.bots.capability",
+                    "must be a valid capability name",
+                );
+            }
+        }
+        check(
+            self.telegram.busy_poll_timeout_secs <= self.telegram.poll_timeout_secs,
+            "telegram.busy_poll_timeout_secs",
+            "must be at most telegram.poll_timeout_secs",
+        );
+        if let Some(url) = &self.telegram.tts_url {
+            check(
+                reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+                "telegram.tts_url",
+                "must be an http(s) URL",
+            );
+        }
+        let email = &self.email;
+        if email.imap_host.is_some() {
+            check(
+                cfg!(feature = "email"),
+                "email.imap_host",
+                "requires a gateway built with the `email` feature",
+            );
+            for (key, value) in [
+                ("email.smtp_host", &email.smtp_host),
+                ("email.username", &email.username),
+                ("email.password", &email.password),
+            ] {
+                check(
+                    value.as_deref().is_some_and(|v| !v.is_empty()),
+                    key,
+                    "must be set when email.imap_host is",
+                );
+            }
+            check(
+                !email.mailbox.is_empty(),
+                "email.mailbox",
+                "must not be empty",
+            );
+            check(
+                email
+                    .from_address
+                    .as_ref()
+                    .or(email.username.as_ref())
+                    .and_then(|a| a.split_once('@'))
+                    .is_some_and(|(user, domain)| !user.is_empty() && !domain.is_empty()),
+                "email.from_address",
+                "must be an email address (set it when email.username is not one)",
+            );
+        }
+        for sender in &email.allowed_senders {
+            check(
+                sender.contains('@') && !sender.ends_with('@'),
+                "email.allowed_senders",
+                &format!("{:?} is neither an address nor an @domain", sender),
+            );
+        }
+
+        let twilio = &self.twilio;
+        if twilio.account_sid.is_some() {
+            check(
+                twilio.auth_token.as_deref().is_some_and(|t| !t.is_empty()),
+                "twilio.auth_token",
+                "must be set when twilio.account_sid is",
+            );
+            check(
+                twilio.webhook_url.as_deref().is_some_and(|u| {
+                    reqwest::Url::parse(u).is_ok_and(|u| matches!(u.scheme(), "http" | "https"))
+                }),
+                "twilio.webhook_url",
+                "must be an http(s) URL when twilio.account_sid is set",
+            );
+        }
+        check(
+            reqwest::Url::parse(&twilio.api_base)
+                .is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+            "twilio.api_base",
+            "must be an http(s) URL",
+        );
+        check(
+            twilio.max_message_chars >= 160,
+            "twilio.max_message_chars",
+            "must be at least 160",
+        );
+        for sender in &twilio.allowed_senders {
+            check(
+                sender
+                    .strip_prefix('+')
+                    .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+                "twilio.allowed_senders",
+                &format!("{:?} is not an E.164 number such as +15551234567", sender),
+            );
+        }
+
+        check(
+            self.federation.peers.is_empty() || self.federation.secret.is_some(),
+            "federation.secret",
+            "required when federation peers are configured",
+        );
+        for (name, url) in &self.federation.peers {
+            check(
+                reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")),
+                &format!("federation.peers.{}", name),
+                "must be an http(s) URL",
+            );
+        }
+
+        let retry = &self.retry;
+        for (name, overrides) in [
+            ("global", None),
+            ("redis", Some(&retry.redis)),
+            ("delivery", Some(&retry.delivery)),
+            ("telegram", Some(&retry.telegram)),
+            ("task", Some(&retry.task)),
+            ("adaptor", Some(&retry.adaptor)),
+        ] {
+            let attempts = overrides
+                .and_then(|o| o.max_attempts)
+                .unwrap_or(retry.max_attempts);
+            let jitter = overrides.and_then(|o| o.jitter).unwrap_or(retry.jitter);
+            let multiplier = overrides
+                .and_then(|o| o.multiplier)
+                .unwrap_or(retry.multiplier);
+            check(
+                attempts > 0,
+                &format!("retry.{}.max_attempts", name),
+                "must be at least 1",
+            );
+            check(
+                (0.0..=1.0).contains(&jitter),
+                &format!("retry.{}.jitter", name),
+                "must be between 0.0 and 1.0",
+            );
+            check(
+                multiplier >= 1.0,
+                &format!("retry.{}.multiplier", name),
+                "must be at least 1.0",
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("Invalid configuration:\n  {}", problems.join("\n  "))
+        }
+    }
+
+    /// Layer runtime `overrides` (a JSON object using the file's key layout)
+    /// on top of this configuration, rejecting keys outside
+    /// [`RELOADABLE_KEYS`]
+    pub fn with_overrides(&self, overrides: &serde_json::Value) -> anyhow::Result<Self> {
+        if !overrides.is_object() {
+            anyhow::bail!("Runtime configuration must be a JSON object");
+        }
+        let mut keys = Vec::new();
+        leaf_keys(overrides, String::new(), &mut keys);
+        let fixed: Vec<String> = keys
+            .into_iter()
+            .filter(|key| !RELOADABLE_KEYS.contains(&key.as_str()))
+            .map(|key| format!("{}: cannot be changed at runtime", key))
+            .collect();
+        if !fixed.is_empty() {
+            anyhow::bail!("Invalid runtime configuration:\n  {}", fixed.join("\n  "));
+        }
+
+        let config: Config = Figment::from(Serialized::defaults(self))
+            .merge(Serialized::defaults(overrides))
+            .extract()
+            .map_err(|e| {
+                let problems: Vec<String> = e.into_iter().map(|e| e.to_string()).collect();
+                anyhow::anyhow!(
+                    "Invalid runtime configuration:\n  {}",
+                    problems.join("\n  ")
+                )
+            })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Whether the Telegram adaptor should answer `chat_id`
+    pub fn telegram_chat_allowed(&self, chat_id: i64) -> bool {
+        let allowed = &self.telegram.allowed_chats;
+        allowed.is_empty() || allowed.contains(&chat_id)
+    }
+
+    /// The effective configuration as JSON with secrets masked
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        redact(&mut value);
+        value
+    }
+
+    /// Deduplication window, when enabled
+    pub fn dedupe_window(&self) -> Option<Duration> {
+        let secs = self.tasks.dedupe_window_secs;
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Whether the email adaptor should answer mail from `address`
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    pub fn email_sender_allowed(&self, address: &str) -> bool {
+        let allowed = &self.email.allowed_senders;
+        allowed.is_empty()
+            || allowed.iter().any(|entry| match entry.strip_prefix('@') {
+                Some(domain) => address
+                    .rsplit_once('@')
+                    .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain)),
+                None => entry.eq_ignore_ascii_case(address),
+            })
+    }
+
+    /// Whether the Twilio adaptor should answer `from`, an SMS number or a
+    /// `whatsapp:` address
+    pub fn twilio_sender_allowed(&self, from: &str) -> bool {
+        let allowed = &self.twilio.allowed_senders;
+        let number = from.strip_prefix("whatsapp:").unwrap_or(from);
+        allowed.is_empty() || allowed.iter().any(|n| n == number)
+    }
+
+    pub fn telegram_heartbeat_age(&self) -> Duration {
+        Duration::from_secs(self.telegram.max_heartbeat_age_secs)
+    }
+}
+
+/// Map an established variable name to its configuration key
+fn legacy_key(name: &str) -> Option<String> {
+    let name = name.to_ascii_uppercase();
+    if let Some((_, key)) = LEGACY_ENV.iter().find(|(var, _)| *var == name) {
+        return Some(key.to_string());
+    }
+
+    let rest = name.strip_prefix("RETRY_")?;
+    if let Some(field) = RETRY_FIELDS.iter().find(|f| rest == **f) {
+        return Some(format!("retry.{}", field.to_ascii_lowercase()));
+    }
+    RETRY_SUBSYSTEMS.iter().find_map(|subsystem| {
+        let field = rest.strip_prefix(subsystem)?.strip_prefix('_')?;
+        RETRY_FIELDS.contains(&field).then(|| {
+            format!(
+                "retry.{}.{}",
+                subsystem.to_ascii_lowercase(),
+                field.to_ascii_lowercase()
+            )
+        })
+    })
+}
+
+/// Raw values for [`TEXT_KEYS`], prefixed variables after established ones
+fn text_overrides() -> Vec<(&'static str, String)> {
+    TEXT_KEYS
+        .iter()
+        .flat_map(|key| {
+            let legacy = LEGACY_ENV
+                .iter()
+                .filter(move |(_, k)| k == key)
+                .map(|(var, _)| var.to_string());
+            let prefixed = format!("{}{}", ENV_PREFIX, key.replace('.', "__")).to_uppercase();
+            legacy
+                .chain(std::iter::once(prefixed))
+                .filter(|var| is_set(var))
+                .filter_map(|var| std::env::var(var).ok())
+                .map(move |value| (*key, value))
+        })
+        .collect()
+}
+
+/// Collect the dotted paths of every non-object value in `value`
+fn leaf_keys(value: &serde_json::Value, prefix: String, keys: &mut Vec<String>) {
+    match value.as_object() {
+        Some(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                leaf_keys(value, path, keys);
+            }
+        }
+        None => keys.push(prefix),
+    }
+}
+
+/// Empty variables (e.g. `ADMIN_TOKEN=` from compose) count as unset
+fn is_set(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| !v.is_empty())
+}
+
+fn redact(value: &mut serde_json::Value) {
+    if let serde_json::Value::Object(map) = value {
+        for (key, value) in map.iter_mut() {
+            if SECRET_KEYS.iter().any(|s| key.contains(s)) && !value.is_null() {
+                *value = serde_json::Value::String("********".to_string());
+            } else {
+                redact(value);
+            }
+        }
+    }
+}
+
+/// Accept a list of strings, or the comma-separated string form used by
+/// environment variables
+fn list_from_spec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Spec {
+        List(Vec<String>),
+        Joined(String),
+    }
+
+    Ok(match Spec::deserialize(deserializer)? {
+        Spec::List(items) => items,
+        Spec::Joined(spec) => spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+/// Accept peers as a table or as the `name=url,name=url` string form
+fn peers_from_spec<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Spec {
+        Table(BTreeMap<String, String>),
+        List(String),
+    }
+
+    match Spec::deserialize(deserializer)? {
+        Spec::Table(peers) => Ok(peers),
+        Spec::List(spec) => spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once('=')
+                    .map(|(name, url)| (name.trim().to_string(), url.trim().to_string()))
+                    .ok_or_else(|| {
+                        serde::de::Error::custom(format!("invalid peer entry {:?}", entry))
+                    })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+// `Jail` closures must return `figment::Error`
+#[allow(clippy::result_large_err)]
+mod tests {
+    use super::*;
+    use figment::Jail;
+
+    #[test]
+    fn maps_established_variables() {
+        assert_eq!(legacy_key("PORT").as_deref(), Some("server.port"));
+        assert_eq!(legacy_key("redis_host").as_deref(), Some("redis.host"));
+        assert_eq!(
+            legacy_key("RETRY_MAX_ATTEMPTS").as_deref(),
+            Some("retry.max_attempts")
+        );
+        assert_eq!(
+            legacy_key("RETRY_TASK_BASE_DELAY_MS").as_deref(),
+            Some("retry.task.base_delay_ms")
+        );
+        assert_eq!(legacy_key("RETRY_TASK_SPEED"), None);
+        assert_eq!(legacy_key("RETRY_UNKNOWN_JITTER"), None);
+        assert_eq!(legacy_key("HOME"), None);
+    }
+
+    #[test]
+    fn prefixed_variables_beat_established_ones() {
+        Jail::expect_with(|jail| {
+            jail.create_file("gateway.toml", "[server]\nport = 7000\n")?;
+            jail.set_env(CONFIG_PATH_VAR, "gateway.toml");
+            assert_eq!(Config::load().unwrap().server.port, 7000);
+
+            jail.set_env("PORT", "8000");
+            jail.set_env("REDIS_HOST", "redis.internal");
+            let config = Config::load().unwrap();
+            assert_eq!(config.server.port, 8000);
+            assert_eq!(config.redis.host, "redis.internal");
+
+            jail.set_env("CLAW_SERVER__PORT", "9000");
+            jail.set_env("CLAW_REDIS__HOST", "redis.prefixed");
+            let config = Config::load().unwrap();
+            assert_eq!(config.server.port, 9000);
+            assert_eq!(config.redis.host, "redis.prefixed");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn empty_variables_count_as_unset() {
+        Jail::expect_with(|jail| {
+            jail.set_env("PORT", "8000");
+            jail.set_env("CLAW_SERVER__PORT", "");
+            jail.set_env("ADMIN_TOKEN", "");
+            let config = Config::load().unwrap();
+            assert_eq!(config.server.port, 8000);
+            assert_eq!(config.auth.admin_token, Config::default().auth.admin_token);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn text_variables_are_taken_verbatim() {
+        Jail::expect_with(|jail| {
+            jail.set_env("REDIS_PASSWORD", "007");
+            assert_eq!(Config::load().unwrap().redis.password, "007");
+            jail.set_env("CLAW_REDIS__PASSWORD", "0042");
+            assert_eq!(Config::load().unwrap().redis.password, "0042");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let mut config = Config::default();
+        config.server.tls_cert_path = Some(PathBuf::from("cert.pem"));
+        config.redis.host = String::new();
+        config.limits.max_body_bytes = 0;
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("server.tls_cert_path"), "{}", message);
+        assert!(message.contains("redis.host: must not be empty"), "{}", message);
+        assert!(
+            message.contains("limits.max_body_bytes: must be greater than zero"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn load_refuses_invalid_values() {
+        Jail::expect_with(|jail| {
+            jail.set_env("MAX_BODY_BYTES", "0");
+            let error = Config::load().unwrap_err().to_string();
+            assert!(error.contains("limits.max_body_bytes"), "{}", error);
+            jail.set_env(CONFIG_PATH_VAR, "missing.toml");
+            let error = Config::load().unwrap_err().to_string();
+            assert!(error.contains("not a file"), "{}", error);
+            Ok(())
+        });
+    }
+}
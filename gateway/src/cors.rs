@@ -0,0 +1,202 @@
+//! Cross-origin resource sharing policy.
+//!
+//! The `[cors]` settings decide which browser origins may call the gateway,
+//! and `[cors.routes."/prefix"]` tables override them for paths under a
+//! prefix, the longest matching prefix winning. That keeps, say, `/health`
+//! open to any origin while `/task` only answers the dashboard. Origins may
+//! contain `*` wildcards (`https://*.example.com`). Requests from origins a
+//! policy does not allow get no CORS headers, so browsers refuse to expose
+//! the response.
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::config::{CorsOverrides, CorsSettings};
+
+/// Whether `origin` matches `pattern`, where each `*` stands for any run of
+/// characters
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = origin.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.len() >= part.len() && rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+fn allow_origin(origins: &[String], credentials: bool) -> AllowOrigin {
+    if origins.iter().any(|o| o == "*") && !credentials {
+        return AllowOrigin::any();
+    }
+    if origins.iter().any(|o| o.contains('*')) {
+        let patterns = origins.to_vec();
+        return AllowOrigin::predicate(move |origin, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| patterns.iter().any(|p| origin_matches(p, origin)))
+        });
+    }
+    AllowOrigin::list(origins.iter().filter_map(|o| HeaderValue::from_str(o).ok()))
+}
+
+/// Methods for `methods`; a literal `*` cannot be sent with credentials, so
+/// those echo the request instead
+fn allow_methods(methods: &[String], credentials: bool) -> AllowMethods {
+    match methods.iter().any(|m| m == "*") {
+        true if credentials => AllowMethods::mirror_request(),
+        true => AllowMethods::any(),
+        false => AllowMethods::list(methods.iter().filter_map(|m| m.parse::<Method>().ok())),
+    }
+}
+
+/// Headers for `headers`, echoing the request like [`allow_methods`]
+fn allow_headers(headers: &[String], credentials: bool) -> AllowHeaders {
+    match headers.iter().any(|h| h == "*") {
+        true if credentials => AllowHeaders::mirror_request(),
+        true => AllowHeaders::any(),
+        false => AllowHeaders::list(headers.iter().filter_map(|h| h.parse::<HeaderName>().ok())),
+    }
+}
+
+/// The layer for the global settings with `overrides` applied
+fn policy(global: &CorsSettings, overrides: &CorsOverrides) -> CorsLayer {
+    let origins = overrides
+        .allowed_origins
+        .as_ref()
+        .unwrap_or(&global.allowed_origins);
+    let methods = overrides
+        .allowed_methods
+        .as_ref()
+        .unwrap_or(&global.allowed_methods);
+    let headers = overrides
+        .allowed_headers
+        .as_ref()
+        .unwrap_or(&global.allowed_headers);
+    let credentials = overrides
+        .allow_credentials
+        .unwrap_or(global.allow_credentials);
+    let max_age = overrides.max_age_secs.unwrap_or(global.max_age_secs);
+
+    CorsLayer::new()
+        .allow_origin(allow_origin(origins, credentials))
+        .allow_methods(allow_methods(methods, credentials))
+        .allow_headers(allow_headers(headers, credentials))
+        .allow_credentials(credentials)
+        .max_age(Duration::from_secs(max_age))
+}
+
+/// The configured policies, by path prefix
+pub struct CorsPolicies {
+    default: CorsLayer,
+    /// Longest prefix first
+    routes: Vec<(String, CorsLayer)>,
+}
+
+impl CorsPolicies {
+    pub fn from_config(settings: &CorsSettings) -> Self {
+        let mut routes: Vec<_> = settings
+            .routes
+            .iter()
+            .map(|(prefix, overrides)| {
+                let prefix = prefix.trim_end_matches('/').to_string();
+                (prefix, policy(settings, overrides))
+            })
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self {
+            default: policy(settings, &CorsOverrides::default()),
+            routes,
+        }
+    }
+
+    fn for_path(&self, path: &str) -> &CorsLayer {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map_or(&self.default, |(_, layer)| layer)
+    }
+}
+
+/// Middleware applying the policy for the request's path, answering
+/// preflight requests itself
+pub async fn apply(
+    State(policies): State<Arc<CorsPolicies>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let layer = policies.for_path(req.uri().path());
+    match layer.layer(next).oneshot(req).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_origins_match_only_themselves() {
+        assert!(origin_matches(
+            "https://app.example.com",
+            "https://app.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://app.example.com",
+            "https://app.example.com.evil"
+        ));
+        assert!(!origin_matches(
+            "https://app.example.com",
+            "http://app.example.com"
+        ));
+    }
+
+    #[test]
+    fn wildcards_match_any_run() {
+        let pattern = "https://*.example.com";
+        assert!(origin_matches(pattern, "https://app.example.com"));
+        assert!(origin_matches(pattern, "https://a.b.example.com"));
+        assert!(!origin_matches(pattern, "https://example.com"));
+        assert!(!origin_matches(pattern, "https://app.example.com.evil.net"));
+        assert!(!origin_matches(pattern, "http://app.example.com"));
+        assert!(origin_matches("*", "https://anything.test"));
+    }
+
+    #[test]
+    fn wildcards_cover_ports_and_several_parts() {
+        assert!(origin_matches(
+            "http://localhost:*",
+            "http://localhost:3000"
+        ));
+        assert!(!origin_matches(
+            "http://localhost:*",
+            "http://localhost.evil:3000"
+        ));
+        let pattern = "https://*.eu.*.example.com";
+        assert!(origin_matches(pattern, "https://app.eu.cdn.example.com"));
+        assert!(!origin_matches(pattern, "https://app.us.cdn.example.com"));
+        // The prefix and suffix may not overlap
+        assert!(!origin_matches("https://a*a", "https://a"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Admin dashboard.
+//!
+//! `GET /admin/ui` serves a single self-contained HTML page for on-call
+//! triage: queue depths, recent tasks, failure rates and adaptor health,
+//! with buttons to cancel a task (`DELETE /task/:id`) or requeue a failed,
+//! timed out or orphaned one (`POST /admin/tasks/:id/requeue`). The page
+//! holds no data itself and is served without authentication; it asks for
+//! an admin token and reads everything from `GET /admin/overview` with it.
+//! Both still fall under `ip_filter.admin_allow`.
+//!
+//! The overview samples up to `overview_sample` task records (200 by
+//! default, at most 2000) and reports the newest 50 along with status
+//! counts over the whole sample.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::Utc;
+use redis::AsyncCommands;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tracing::info;
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::{events, failures, history, redis_connection, schema, summarize, task_state};
+use crate::{queue_for, telegram, watchdog, AgentResponse, AppState, AGENT_QUEUE, PRIORITY_QUEUE};
+
+/// The dashboard page
+const PAGE: &str = include_str!("dashboard.html");
+
+/// Statuses a task may be requeued from
+const REQUEUEABLE: [&str; 3] = ["failed", "timed_out", "orphaned"];
+
+/// Tasks listed as recent in the overview
+const RECENT_TASKS: usize = 50;
+
+// Serve the dashboard page
+pub async fn dashboard_page() -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'self'; script-src 'unsafe-inline'; \
+                 style-src 'unsafe-inline'; frame-ancestors 'none'",
+            ),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        PAGE,
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OverviewQuery {
+    overview_sample: Option<usize>,
+}
+
+/// Share of settled tasks in `counts` that did not complete
+fn failure_rate(counts: &BTreeMap<String, u64>) -> Option<f64> {
+    let count = |status: &str| counts.get(status).copied().unwrap_or(0);
+    let failed: u64 = REQUEUEABLE.iter().map(|s| count(s)).sum();
+    let settled = failed + count("completed");
+    (settled > 0).then(|| failed as f64 / settled as f64)
+}
+
+// Queue depths, recent tasks, failure rates and adaptor health (admin only)
+pub async fn overview(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<OverviewQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let sample = query.overview_sample.unwrap_or(200).clamp(1, 2000);
+    let mut conn = redis_connection(&state).await?;
+    let depths = state
+        .task_queue
+        .depths(&mut conn, &[AGENT_QUEUE, PRIORITY_QUEUE])
+        .await?;
+
+    let mut cursor = 0u64;
+    let mut task_ids = Vec::new();
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("task:*")
+            .arg("COUNT")
+            .arg(sample)
+            .query_async(&mut conn)
+            .await?;
+        task_ids.extend(
+            keys.iter()
+                .filter_map(|k| k.strip_prefix("task:"))
+                .filter(|id| !id.contains(':'))
+                .map(str::to_string),
+        );
+        cursor = next;
+        if cursor == 0 || task_ids.len() >= sample {
+            break;
+        }
+    }
+    task_ids.truncate(sample);
+
+    let (mut tasks, _) = summarize(&mut conn, task_ids, 0, Some(&principal)).await?;
+    let mut counts = BTreeMap::new();
+    for task in &tasks {
+        *counts.entry(task.status.clone()).or_insert(0u64) += 1;
+    }
+    tasks.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    tasks.truncate(RECENT_TASKS);
+
+    let health = crate::health_check(State(state.clone())).await.0;
+    Ok(Json(json!({
+        "generated_at": Utc::now().to_rfc3339(),
+        "queues": {
+            "backend": state.task_queue.backend().as_str(),
+            AGENT_QUEUE: depths[0],
+            PRIORITY_QUEUE: depths[1],
+        },
+        "tasks": {
+            "sampled": counts.values().sum::<u64>(),
+            "by_status": counts,
+            "failure_rate": failure_rate(&counts),
+            "recent": tasks,
+        },
+        "health": health,
+        "adaptors": {
+            "telegram": telegram::snapshots(&state.telegram_bots),
+            "twilio": state.twilio.is_enabled(),
+        },
+        "agents": {
+            "alive": state.agent_metrics.alive.get(),
+            "orphaned": state.agent_metrics.orphaned.get(),
+            "lapsed_leases": state.agent_metrics.leases_lapsed.get(),
+        },
+        "watchdog": {
+            "timed_out": state.watchdog_metrics.timed_out.get(),
+            "requeued": state.watchdog_metrics.requeued.get(),
+        },
+    })))
+}
+
+// Put a failed, timed out or orphaned task back on its queue (admin only)
+pub async fn requeue_task(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(task_id): Path<String>,
+) -> Result<Json<AgentResponse>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    let Some(current) = task.map(|t| schema::decode_task(&t)).transpose()? else {
+        return Err(ApiError::not_found(format!("Task {} not found", task_id)));
+    };
+    let timeout_secs = current["timeout_secs"]
+        .as_u64()
+        .unwrap_or(state.config.current().watchdog.default_timeout_secs);
+    let deadline = watchdog::deadline(timeout_secs);
+
+    let requeued = task_state::transition(&mut conn, &task_id, "pending", |task| {
+        if let Some(record) = task.as_object_mut() {
+            for field in [
+                "started_at",
+                "agent_id",
+                "error",
+                "timed_out_at",
+                "orphaned_at",
+            ] {
+                record.remove(field);
+            }
+        }
+        task["requeued_at"] = Utc::now().to_rfc3339().into();
+        task["requeue_count"] = (task["requeue_count"].as_u64().unwrap_or(0) + 1).into();
+        task["deadline"] = deadline.to_rfc3339().into();
+        REQUEUEABLE.contains(&task["status"].as_str().unwrap_or_default())
+    })
+    .await?;
+    let Some(task) = requeued else {
+        return Err(ApiError::unprocessable(
+            "not_requeueable",
+            format!(
+                "Task {} is {}; only {} tasks can be requeued",
+                task_id,
+                current["status"].as_str().unwrap_or("unknown"),
+                REQUEUEABLE.join(", ")
+            ),
+        ));
+    };
+
+    let queue = queue_for(
+        task["capability"].as_str(),
+        task["priority"].as_bool().unwrap_or(false),
+    );
+    conn.del::<_, ()>(failures::error_key(&task_id)).await?;
+    state
+        .task_queue
+        .push(&mut conn, &queue, &task_id, deadline)
+        .await?;
+    watchdog::track(&mut conn, &task_id, timeout_secs).await?;
+    history::track(&mut conn, &task_id).await?;
+    events::track(&mut conn, &task_id).await?;
+    info!(
+        "Task {} requeued on {} by {}",
+        task_id, queue, principal.key_id
+    );
+
+    Ok(Json(AgentResponse {
+        task_id,
+        status: "pending".to_string(),
+        result: None,
+        error: None,
+        signature: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_rate_counts_settled_tasks() {
+        let counts: BTreeMap<String, u64> = [
+            ("completed", 6),
+            ("failed", 1),
+            ("timed_out", 1),
+            ("pending", 12),
+        ]
+        .into_iter()
+        .map(|(s, n)| (s.to_string(), n))
+        .collect();
+        assert_eq!(failure_rate(&counts), Some(0.25));
+        assert_eq!(failure_rate(&BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn page_reads_the_admin_endpoints() {
+        for path in ["/admin/overview", "/admin/tasks/", "/task/"] {
+            assert!(PAGE.contains(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn page_reads_telegram_health_per_bot() {
+        // No bots is an empty map, not a missing one
+        assert_eq!(json!(telegram::snapshots(&[])), json!({}));
+        assert!(PAGE.contains("bots.length ? [] : [[\"telegram\", \"disabled\"]]"));
+
+        assert!(PAGE.contains("Object.entries(overview.adaptors.telegram)"));
+        let bots = json!({"support": telegram::TelegramMetrics::default().snapshot()});
+        for field in ["circuit", "pending_tasks", "send_failures", "last_loop_at"] {
+            assert!(bots["support"].get(field).is_some(), "{}", field);
+            assert!(PAGE.contains(&format!("bot.{}", field)), "{}", field);
+        }
+        assert_eq!(bots["support"]["circuit"], "closed");
+    }
+}
@@ -0,0 +1,102 @@
+//! Duplicate task detection.
+//!
+//! With `tasks.dedupe_window_secs` set, each submission is fingerprinted by a
+//! SHA-256 over its normalized input and effective config, scoped to the
+//! submitter so results are never shared between users. The fingerprint
+//! points at the task that carried it for the length of the window; a repeat
+//! whose original has completed by then is answered with a copy of the
+//! original's result instead of running the agent again. Channel adaptors
+//! that clear results once delivered let them expire with the window
+//! instead, so repeats sent after the reply are still caught.
+
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use ring::digest;
+use serde_json::{Map, Value};
+use std::time::Duration;
+use tracing::Instrument;
+
+use crate::{envelope, queue, telemetry};
+
+/// Redis key mapping a fingerprint to the task that first carried it
+fn dedupe_key(fingerprint: &str) -> String {
+    format!("dedupe:{}", fingerprint)
+}
+
+/// Hex SHA-256 identifying `input` and `config` submitted within `scope`
+pub fn fingerprint(scope: &str, input: &Value, config: &Value) -> String {
+    let canonical = serde_json::json!([scope, normalize(input), normalize(config)]);
+    let hash = digest::digest(&digest::SHA256, canonical.to_string().as_bytes());
+    hex::encode(hash.as_ref())
+}
+
+/// Collapse whitespace in strings and order object keys, so trivially
+/// different re-sends of the same question hash alike
+fn normalize(value: &Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(s.split_whitespace().collect::<Vec<_>>().join(" ")),
+        Value::Array(items) => Value::Array(items.iter().map(normalize).collect()),
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let sorted: Map<String, Value> = keys
+                .into_iter()
+                .map(|k| (k.clone(), normalize(&map[k])))
+                .collect();
+            Value::Object(sorted)
+        }
+        other => other.clone(),
+    }
+}
+
+/// The original task id and raw stored result for a completed duplicate
+pub async fn find_completed<C: ConnectionLike + Send>(
+    conn: &mut C,
+    fingerprint: &str,
+) -> redis::RedisResult<Option<(String, String)>> {
+    let key = dedupe_key(fingerprint);
+    let original: Option<String> = conn
+        .get(&key)
+        .instrument(telemetry::redis_span("GET", &key))
+        .await?;
+    let Some(original) = original else {
+        return Ok(None);
+    };
+
+    let result: Option<String> = conn.get(format!("result:{}", original)).await?;
+    Ok(result.map(|result| (original, result)))
+}
+
+/// Record `task` as completed with `result`, copied from an earlier task
+pub async fn store_completed(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    mut task: Value,
+    result: &str,
+) -> anyhow::Result<()> {
+    task["status"] = "completed".into();
+
+    let task_key = format!("task:{}", task_id);
+    let result_key = format!("result:{}", task_id);
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .set(&task_key, envelope::encode(&task)?)
+        .ignore()
+        .set(&result_key, result)
+        .ignore();
+    queue::create(conn, task_id, &pipe).await?;
+    Ok(())
+}
+
+/// Point `fingerprint` at `task_id` for the next `window`
+pub async fn remember<C: ConnectionLike + Send>(
+    conn: &mut C,
+    fingerprint: &str,
+    task_id: &str,
+    window: Duration,
+) -> redis::RedisResult<()> {
+    let key = dedupe_key(fingerprint);
+    conn.set_ex(&key, task_id, window.as_secs())
+        .instrument(telemetry::redis_span("SET", &key))
+        .await
+}
@@ -0,0 +1,389 @@
+//! Task dependencies.
+//!
+//! A submission may list `depends_on: [task ids]` of tasks the gateway still
+//! holds. Instead of a queue such a task goes to status `waiting` and into
+//! the Redis set `dependencies:waiting`, and a background loop checks it
+//! every `tasks.dependency_interval_secs`. Once every dependency has
+//! completed, the task moves to `pending` and is queued, its deadline
+//! counted from then. As soon as one fails, times out, is orphaned, deleted
+//! or purged, the task fails as well with an error report coded
+//! `dependency_failed`, and its own dependents follow on the next pass.
+//! Dependency cycles are refused at submission.
+
+use chrono::Utc;
+use redis::aio::ConnectionLike;
+use redis::{AsyncCommands, Client};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::error::ApiError;
+use crate::queue::TaskQueue;
+use crate::runtime::RuntimeConfig;
+use crate::{envelope, failures, queue_for, schema, task_state, watchdog};
+
+/// Redis set of the tasks waiting on dependencies
+const WAITING_KEY: &str = "dependencies:waiting";
+
+/// Dependency statuses the dependent fails with
+const FAILED_STATUSES: [&str; 4] = ["failed", "timed_out", "orphaned", "deleted"];
+
+/// Where a dependency stands
+#[derive(Debug, PartialEq)]
+enum Dependency {
+    Completed,
+    Unsettled,
+    /// Failed with the given status, or `missing`
+    Failed(String),
+}
+
+/// What became of a waiting task
+#[derive(Debug)]
+pub enum Outcome {
+    Waiting,
+    Queued,
+    Failed,
+    /// It stopped waiting meanwhile, e.g. deleted
+    Gone,
+}
+
+/// What a waiting task does next
+#[derive(Debug, PartialEq)]
+enum Step {
+    Wait,
+    Release,
+    /// Fail, dependency `dep` having ended as `status`
+    Fail {
+        dep: String,
+        status: String,
+    },
+}
+
+async fn load<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+) -> anyhow::Result<Option<Value>> {
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    Ok(task.map(|t| schema::decode_task(&t)).transpose()?)
+}
+
+/// The dependencies listed on a task record
+fn dependencies_of(task: &Value) -> Vec<String> {
+    task["depends_on"]
+        .as_array()
+        .map(|deps| {
+            deps.iter()
+                .filter_map(|d| d.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Check that every dependency of `task_id` exists and none of them waits,
+/// however indirectly, on `task_id` itself
+pub async fn check<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+    depends_on: &[String],
+) -> Result<(), ApiError> {
+    let mut seen = HashSet::new();
+    let mut unvisited: Vec<String> = depends_on.to_vec();
+    while let Some(id) = unvisited.pop() {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        let task = load(conn, &id)
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        let Some(task) = task else {
+            if depends_on.contains(&id) {
+                return Err(ApiError::unprocessable(
+                    "unknown_dependency",
+                    format!("Dependency {} not found", id),
+                ));
+            }
+            continue;
+        };
+        if task["status"] != "waiting" {
+            continue;
+        }
+        let further = dependencies_of(&task);
+        if further.iter().any(|d| d == task_id) {
+            return Err(ApiError::unprocessable(
+                "dependency_cycle",
+                format!("Task {} already depends on {}", id, task_id),
+            ));
+        }
+        unvisited.extend(further);
+    }
+    Ok(())
+}
+
+/// Hold `task_id` back until its dependencies settle
+pub async fn hold(conn: &mut redis::aio::Connection, task_id: &str) -> redis::RedisResult<()> {
+    conn.sadd(WAITING_KEY, task_id).await
+}
+
+async fn dependency<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+) -> anyhow::Result<Dependency> {
+    let has_result: bool = conn.exists(format!("result:{}", task_id)).await?;
+    if has_result {
+        return Ok(Dependency::Completed);
+    }
+    let Some(task) = load(conn, task_id).await? else {
+        return Ok(Dependency::Failed("missing".to_string()));
+    };
+    let status = task["status"].as_str().unwrap_or("unknown");
+    Ok(match status {
+        "completed" => Dependency::Completed,
+        s if FAILED_STATUSES.contains(&s) => Dependency::Failed(s.to_string()),
+        _ => Dependency::Unsettled,
+    })
+}
+
+/// Queue or fail the waiting `task_id` once its dependencies allow it
+pub async fn resolve(
+    conn: &mut redis::aio::Connection,
+    task_queue: &TaskQueue,
+    task_id: &str,
+) -> anyhow::Result<Outcome> {
+    let task = load(conn, task_id).await?;
+    let Some(task) = task.filter(|t| t["status"] == "waiting") else {
+        conn.srem::<_, _, ()>(WAITING_KEY, task_id).await?;
+        return Ok(Outcome::Gone);
+    };
+
+    let mut dependencies = Vec::new();
+    for dep in dependencies_of(&task) {
+        let standing = dependency(conn, &dep).await?;
+        dependencies.push((dep, standing));
+    }
+    match next_step(dependencies) {
+        Step::Wait => Ok(Outcome::Waiting),
+        Step::Release => {
+            release(conn, task_queue, task_id).await?;
+            Ok(Outcome::Queued)
+        }
+        Step::Fail { dep, status } => {
+            fail(conn, task_id, &dep, &status).await?;
+            Ok(Outcome::Failed)
+        }
+    }
+}
+
+/// The step a task takes given where each of its dependencies stands: the
+/// first failed one fails it, and it waits while any has yet to settle
+fn next_step(dependencies: Vec<(String, Dependency)>) -> Step {
+    let mut settled = true;
+    for (dep, standing) in dependencies {
+        match standing {
+            Dependency::Completed => {}
+            Dependency::Unsettled => settled = false,
+            Dependency::Failed(status) => return Step::Fail { dep, status },
+        }
+    }
+    if settled {
+        Step::Release
+    } else {
+        Step::Wait
+    }
+}
+
+/// Queue a task whose dependencies all completed
+async fn release(
+    conn: &mut redis::aio::Connection,
+    task_queue: &TaskQueue,
+    task_id: &str,
+) -> anyhow::Result<()> {
+    let mut timeout = 0;
+    let mut due = Utc::now();
+    let released = task_state::transition(conn, task_id, "pending", |task| {
+        timeout = task["timeout_secs"].as_u64().unwrap_or_default();
+        due = watchdog::deadline(timeout);
+        task["deadline"] = due.to_rfc3339().into();
+        task["released_at"] = Utc::now().to_rfc3339().into();
+        task["status"] == "waiting"
+    })
+    .await?;
+    // Another gateway may have got there first
+    if let Some(task) = released {
+        let priority = task["priority"].as_bool().unwrap_or(false);
+        let queue = queue_for(task["capability"].as_str(), priority);
+        task_queue.push(conn, &queue, task_id, due).await?;
+        watchdog::track(conn, task_id, timeout).await?;
+        info!(
+            "Task {} dependencies completed, queued on {}",
+            task_id, queue
+        );
+    }
+    conn.srem::<_, _, ()>(WAITING_KEY, task_id).await?;
+    Ok(())
+}
+
+/// Fail a task whose dependency `dep` ended as `status`
+async fn fail(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    dep: &str,
+    status: &str,
+) -> anyhow::Result<()> {
+    let report = failure_report(dep, status);
+    let message = report["error"].as_str().unwrap_or_default().to_string();
+    let failed = task_state::transition(conn, task_id, "failed", |task| {
+        task["error"] = message.clone().into();
+        task["completed_at"] = Utc::now().to_rfc3339().into();
+        task["status"] == "waiting"
+    })
+    .await?;
+    if failed.is_some() {
+        conn.set::<_, _, ()>(failures::error_key(task_id), envelope::encode(&report)?)
+            .await?;
+        warn!("Task {} failed: {}", task_id, message);
+    }
+    conn.srem::<_, _, ()>(WAITING_KEY, task_id).await?;
+    Ok(())
+}
+
+/// The error report of a task whose dependency `dep` ended as `status`
+fn failure_report(dep: &str, status: &str) -> Value {
+    let message = match status {
+        "missing" => format!("Dependency {} no longer exists", dep),
+        status => format!("Dependency {} ended as {}", dep, status),
+    };
+    json!({
+        "error": message,
+        "code": "dependency_failed",
+        "dependency": dep,
+        "dependency_status": status,
+    })
+}
+
+/// Check every waiting task
+async fn resolve_pass(redis_client: &Client, task_queue: &TaskQueue) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let waiting: Vec<String> = conn.smembers(WAITING_KEY).await?;
+    for task_id in waiting {
+        if let Err(e) = resolve(&mut conn, task_queue, &task_id).await {
+            error!("Failed to resolve dependencies of task {}: {}", task_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Start releasing waiting tasks in a background task
+pub fn start_dependency_resolver(
+    redis_client: Arc<Client>,
+    task_queue: TaskQueue,
+    runtime: Arc<RuntimeConfig>,
+) {
+    let interval = Duration::from_secs(runtime.current().tasks.dependency_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = resolve_pass(&redis_client, &task_queue).await {
+                error!("Dependency resolver error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+
+    fn store(redis: &FakeRedis, task_id: &str, task: Value) {
+        redis.set(
+            &format!("task:{}", task_id),
+            &envelope::encode(&task).unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_dependency_cycles() {
+        let mut redis = FakeRedis::new();
+        store(
+            &redis,
+            "a",
+            json!({"status": "waiting", "depends_on": ["b"]}),
+        );
+        store(
+            &redis,
+            "b",
+            json!({"status": "waiting", "depends_on": ["c"]}),
+        );
+
+        // c waiting on a would close a -> b -> c -> a
+        let refused = check(&mut redis, "c", &["a".to_string()])
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code, "dependency_cycle");
+        assert!(check(&mut redis, "d", &["a".to_string()]).await.is_ok());
+        let unknown = check(&mut redis, "d", &["x".to_string()])
+            .await
+            .unwrap_err();
+        assert_eq!(unknown.code, "unknown_dependency");
+    }
+
+    #[tokio::test]
+    async fn waits_for_unsettled_dependencies() {
+        let mut redis = FakeRedis::new();
+        store(&redis, "a", json!({"status": "completed"}));
+        store(&redis, "b", json!({"status": "processing"}));
+        assert_eq!(
+            dependency(&mut redis, "a").await.unwrap(),
+            Dependency::Completed
+        );
+        assert_eq!(
+            dependency(&mut redis, "b").await.unwrap(),
+            Dependency::Unsettled
+        );
+
+        let standing = |b| {
+            vec![
+                ("a".to_string(), Dependency::Completed),
+                ("b".to_string(), b),
+            ]
+        };
+        assert_eq!(next_step(standing(Dependency::Unsettled)), Step::Wait);
+
+        // Completed, with its result stored, it lets the dependent go
+        redis.set("result:b", "4");
+        assert_eq!(
+            dependency(&mut redis, "b").await.unwrap(),
+            Dependency::Completed
+        );
+        assert_eq!(next_step(standing(Dependency::Completed)), Step::Release);
+    }
+
+    #[tokio::test]
+    async fn passes_failures_on_to_dependents() {
+        let mut redis = FakeRedis::new();
+        store(&redis, "a", json!({"status": "timed_out"}));
+        let timed_out = dependency(&mut redis, "a").await.unwrap();
+        assert_eq!(timed_out, Dependency::Failed("timed_out".to_string()));
+        let missing = dependency(&mut redis, "gone").await.unwrap();
+        assert_eq!(missing, Dependency::Failed("missing".to_string()));
+
+        // A failure decides even while other dependencies are running
+        let step = next_step(vec![
+            ("b".to_string(), Dependency::Unsettled),
+            ("a".to_string(), timed_out),
+        ]);
+        let Step::Fail { dep, status } = step else {
+            panic!("expected a failure, got {:?}", step);
+        };
+        let report = failure_report(&dep, &status);
+        assert_eq!(report["code"], "dependency_failed");
+        assert_eq!(report["dependency"], "a");
+        assert_eq!(report["error"], "Dependency a ended as timed_out");
+        assert_eq!(
+            failure_report("gone", "missing")["error"],
+            "Dependency gone no longer exists"
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Result diffs.
+//!
+//! `GET /tasks/diff?a=<task id>&b=<task id>` compares the results of two
+//! tasks, typically one and its rerun (see [`rerun`](crate::rerun)), to see
+//! what a config change did to an agent's answer. By default the answer is
+//! the list of changes turning `a`'s result into `b`'s, each with the JSON
+//! pointer `path` it happens at and the values on either side: `added`,
+//! `removed` or `changed`. Objects are compared key by key and arrays
+//! element by element. With `format=unified` it is a unified diff of the
+//! results as text instead, text results compared as they are and others
+//! pretty-printed. Failed tasks are compared by their error reports; tasks
+//! without a result yet cannot be compared. Both tasks must be readable by
+//! the caller.
+
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use similar::TextDiff;
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::{attachments, lookup_result, redis_connection, schema, AgentResponse, AppState};
+
+/// Query of `GET /tasks/diff`
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    a: String,
+    b: String,
+    format: Option<String>,
+}
+
+/// One difference between two results
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+    Added { path: String, b: Value },
+    Removed { path: String, a: Value },
+    Changed { path: String, a: Value, b: Value },
+}
+
+/// `key` escaped as a JSON pointer reference token
+fn token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// The changes turning `a` into `b`, found below `path`
+fn changes(path: &str, a: &Value, b: &Value, found: &mut Vec<Change>) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let path = format!("{}/{}", path, token(key));
+                match b.get(key) {
+                    Some(other) => changes(&path, value, other, found),
+                    None => found.push(Change::Removed {
+                        path,
+                        a: value.clone(),
+                    }),
+                }
+            }
+            for (key, value) in b.iter().filter(|(key, _)| !a.contains_key(*key)) {
+                found.push(Change::Added {
+                    path: format!("{}/{}", path, token(key)),
+                    b: value.clone(),
+                });
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for (i, value) in a.iter().enumerate() {
+                let path = format!("{}/{}", path, i);
+                match b.get(i) {
+                    Some(other) => changes(&path, value, other, found),
+                    None => found.push(Change::Removed {
+                        path,
+                        a: value.clone(),
+                    }),
+                }
+            }
+            for (i, value) in b.iter().enumerate().skip(a.len()) {
+                found.push(Change::Added {
+                    path: format!("{}/{}", path, i),
+                    b: value.clone(),
+                });
+            }
+        }
+        (a, b) if a != b => found.push(Change::Changed {
+            path: path.to_string(),
+            a: a.clone(),
+            b: b.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// A result as the text a unified diff compares
+fn text(result: &Value) -> String {
+    let mut text = match result {
+        Value::String(text) => text.clone(),
+        result => serde_json::to_string_pretty(result).unwrap_or_else(|_| result.to_string()),
+    };
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+/// The result of a task to compare, if `principal` may read it
+async fn result_of(
+    state: &AppState,
+    principal: Option<&Principal>,
+    task_id: &str,
+) -> Result<(AgentResponse, Value), ApiError> {
+    let mut conn = redis_connection(state).await?;
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    if let Some(task) = task.map(|t| schema::decode_task(&t)).transpose()? {
+        if !attachments::may_read(principal, task["submitted_by"].as_str()) {
+            return Err(match principal {
+                Some(_) => ApiError::forbidden("Task belongs to another caller"),
+                None => ApiError::unauthorized("Missing bearer token"),
+            });
+        }
+    }
+    let Json(mut response) = lookup_result(state.clone(), task_id.to_string()).await?;
+    let Some(result) = response.result.take() else {
+        return Err(ApiError::unprocessable(
+            "no_result",
+            format!(
+                "Task {} is {} and has no result yet",
+                task_id, response.status
+            ),
+        ));
+    };
+    Ok((response, result))
+}
+
+// Compare the results of two tasks
+pub async fn diff_tasks(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Response, ApiError> {
+    let unified = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("unified") => true,
+        Some(other) => {
+            return Err(ApiError::bad_request(
+                "invalid_format",
+                format!("format must be json or unified, not {}", other),
+            ))
+        }
+    };
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    let (a, a_result) = result_of(&state, principal, &query.a).await?;
+    let (b, b_result) = result_of(&state, principal, &query.b).await?;
+
+    if unified {
+        let (a_text, b_text) = (text(&a_result), text(&b_result));
+        let diff = TextDiff::from_lines(&a_text, &b_text)
+            .unified_diff()
+            .header(&format!("a/{}", a.task_id), &format!("b/{}", b.task_id))
+            .to_string();
+        let content_type = [(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")];
+        return Ok((content_type, diff).into_response());
+    }
+
+    let mut found = Vec::new();
+    changes("", &a_result, &b_result, &mut found);
+    Ok(Json(json!({
+        "a": {"task_id": a.task_id, "status": a.status},
+        "b": {"task_id": b.task_id, "status": b.status},
+        "identical": found.is_empty(),
+        "changes": found,
+    }))
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(a: Value, b: Value) -> Vec<Change> {
+        let mut found = Vec::new();
+        changes("", &a, &b, &mut found);
+        found
+    }
+
+    #[test]
+    fn finds_changes_by_json_pointer() {
+        let found = diff(
+            json!({"answer": "4", "sources": ["a", "b"], "a/b": 1}),
+            json!({"answer": "5", "sources": ["a"], "tokens": 12, "a/b": 1}),
+        );
+        assert_eq!(
+            found,
+            vec![
+                Change::Changed {
+                    path: "/answer".to_string(),
+                    a: json!("4"),
+                    b: json!("5")
+                },
+                Change::Removed {
+                    path: "/sources/1".to_string(),
+                    a: json!("b")
+                },
+                Change::Added {
+                    path: "/tokens".to_string(),
+                    b: json!(12)
+                },
+            ]
+        );
+        assert!(diff(json!({"x": [1]}), json!({"x": [1]})).is_empty());
+        assert_eq!(
+            serde_json::to_value(&diff(json!(1), json!("1"))[0]).unwrap(),
+            json!({"op": "changed", "path": "", "a": 1, "b": "1"})
+        );
+    }
+
+    #[test]
+    fn unified_diffs_compare_lines() {
+        let (a, b) = (text(&json!("one\ntwo")), text(&json!("one\nthree")));
+        let diff = TextDiff::from_lines(&a, &b)
+            .unified_diff()
+            .header("a/t1", "b/t2")
+            .to_string();
+        assert!(diff.starts_with("--- a/t1\n+++ b/t2\n"));
+        assert!(diff.contains("-two\n+three\n"));
+    }
+}
@@ -0,0 +1,721 @@
+//! Email adaptor for secure gateway.
+//!
+//! Every `email.poll_interval_secs` the adaptor logs in to `email.imap_host`
+//! over TLS, turns each unseen message in `email.mailbox` from an allowed
+//! sender into a task, and mails the answers of settled tasks back through
+//! `email.smtp_host`. Replies keep the request's subject and carry
+//! `In-Reply-To` and `References`, so Outlook and other clients show them in
+//! the same conversation.
+//!
+//! Messages are fetched without being marked read and only flagged `\Seen`
+//! once handled, so a gateway that dies in between picks them up again
+//! instead of dropping them. Automatic mail (`Auto-Submitted`, bulk and list
+//! `Precedence`) and mail from the adaptor's own address are skipped, and
+//! replies are marked `Auto-Submitted: auto-replied`, so the adaptor never
+//! ends up in a conversation with an out-of-office robot.
+
+use async_imap::Session;
+use futures_util::TryStreamExt;
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use mail_parser::MessageParser;
+use redis::{AsyncCommands, Client};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, pki_types::ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, warn, Instrument};
+use uuid::Uuid;
+
+use crate::backpressure::QueueGuard;
+use crate::config::EmailSettings;
+use crate::memory_guard::MemoryGuard;
+use crate::queue::{NewTask, TaskQueue};
+use crate::request_id::{self, RequestId};
+use crate::retry::RetryPolicies;
+use crate::runtime::RuntimeConfig;
+use crate::supervisor::Supervisor;
+use crate::templates::ReplyTemplates;
+use crate::{agent_config, envelope, failures, maintenance, redaction, schema, telemetry, watchdog};
+
+type ImapSession = Session<TlsStream<TcpStream>>;
+
+/// Reply sent when the memory guard or queue backpressure refuses new work
+const OVERLOADED_REPLY: &str =
+    "The assistant is overloaded right now, please send your request again in a few minutes.";
+
+/// Reply sent when the watchdog gives up on a task
+const TIMED_OUT_REPLY: &str = "Sorry, this request took too long and was cancelled.";
+
+/// Reply sent when the agent reports that a task failed
+const FAILED_REPLY: &str =
+    "Sorry, something went wrong while handling this request. Please try again later.";
+
+/// Most messages taken from the mailbox in one poll; the rest wait for the next
+const MAX_MESSAGES_PER_POLL: usize = 50;
+
+/// Where and how to answer a request
+struct Reply {
+    /// The request's `Reply-To`, else its sender
+    to: Mailbox,
+    subject: String,
+    /// `Message-ID` of the request, without angle brackets
+    message_id: Option<String>,
+    /// The request's own `References`, without angle brackets
+    references: Vec<String>,
+}
+
+/// Pending task awaiting agent response
+struct PendingTask {
+    reply: Reply,
+    /// Request ID of the poll that created the task
+    request_id: RequestId,
+}
+
+/// A message worth turning into a task
+struct Request {
+    from: String,
+    input: String,
+    reply: Reply,
+}
+
+/// Email adaptor that polls a mailbox for requests and mails back responses
+pub struct EmailAdaptor {
+    redis_client: Arc<Client>,
+    task_queue: TaskQueue,
+    tls: TlsConnector,
+    smtp: AsyncSmtpTransport<Tokio1Executor>,
+    /// Sender of replies
+    from: Mailbox,
+    pending_tasks: HashMap<String, PendingTask>,
+    retry: RetryPolicies,
+    memory_guard: Arc<MemoryGuard>,
+    queue_guard: Arc<QueueGuard>,
+    runtime: Arc<RuntimeConfig>,
+    templates: ReplyTemplates,
+}
+
+impl EmailAdaptor {
+    /// Create a new email adaptor
+    pub fn new(
+        redis_client: Arc<Client>,
+        retry: RetryPolicies,
+        task_queue: TaskQueue,
+        memory_guard: Arc<MemoryGuard>,
+        queue_guard: Arc<QueueGuard>,
+        runtime: Arc<RuntimeConfig>,
+        templates: ReplyTemplates,
+    ) -> anyhow::Result<Self> {
+        let settings = runtime.current().email.clone();
+        let (Some(smtp_host), Some(username), Some(password)) =
+            (&settings.smtp_host, &settings.username, &settings.password)
+        else {
+            anyhow::bail!("email.smtp_host, email.username and email.password are required");
+        };
+
+        let smtp = if settings.smtp_starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(smtp_host)?
+        };
+        let smtp = smtp
+            .port(settings.smtp_port)
+            .credentials(Credentials::new(username.clone(), password.clone()))
+            .timeout(Some(Duration::from_secs(settings.timeout_secs)))
+            .build();
+
+        let roots = rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Self {
+            redis_client,
+            task_queue,
+            tls: TlsConnector::from(Arc::new(tls)),
+            smtp,
+            from: settings.from_address.as_ref().unwrap_or(username).parse()?,
+            pending_tasks: HashMap::new(),
+            retry,
+            memory_guard,
+            queue_guard,
+            runtime,
+            templates,
+        })
+    }
+
+    /// Log in to the IMAP server and select the request mailbox
+    async fn open_mailbox(&self, settings: &EmailSettings) -> anyhow::Result<ImapSession> {
+        let host = settings.imap_host.clone().unwrap_or_default();
+        let tcp = TcpStream::connect((host.as_str(), settings.imap_port)).await?;
+        let stream = self.tls.connect(ServerName::try_from(host)?, tcp).await?;
+
+        let mut client = async_imap::Client::new(stream);
+        client.read_response().await.ok_or_else(|| {
+            anyhow::anyhow!("IMAP server closed the connection before greeting")
+        })??;
+        let username = settings.username.as_deref().unwrap_or_default();
+        let password = settings.password.as_deref().unwrap_or_default();
+        let mut session = client.login(username, password).await.map_err(|(e, _)| e)?;
+        session.select(&settings.mailbox).await?;
+        Ok(session)
+    }
+
+    /// Turn unseen messages into tasks, flagging them `\Seen` once handled.
+    /// Messages whose task could not be created stay unseen for the next
+    /// poll.
+    async fn fetch_requests(&mut self) -> anyhow::Result<()> {
+        let settings = self.runtime.current().email.clone();
+        let limit = Duration::from_secs(settings.timeout_secs);
+        let mut session = within(limit, self.open_mailbox(&settings)).await??;
+
+        let mut uids: Vec<u32> = within(limit, session.uid_search("UNSEEN"))
+            .await??
+            .into_iter()
+            .collect();
+        if uids.is_empty() {
+            debug!("No new email");
+            let _ = session.logout().await;
+            return Ok(());
+        }
+        uids.sort_unstable();
+        uids.truncate(MAX_MESSAGES_PER_POLL);
+
+        let messages = within(limit, async {
+            let mut fetched = session
+                .uid_fetch(uid_set(&uids), "(UID BODY.PEEK[])")
+                .await?;
+            let mut messages = Vec::new();
+            while let Some(fetch) = fetched.try_next().await? {
+                if let (Some(uid), Some(body)) = (fetch.uid, fetch.body()) {
+                    messages.push((uid, body.to_vec()));
+                }
+            }
+            Ok::<_, async_imap::error::Error>(messages)
+        })
+        .await??;
+        info!("Received {} email messages", messages.len());
+
+        let own_address = self.from.email.to_string();
+        let mut handled = Vec::new();
+        for (uid, raw) in messages {
+            let Some(request) = parse_request(&raw, &own_address) else {
+                handled.push(uid);
+                continue;
+            };
+            if !self.runtime.current().email_sender_allowed(&request.from) {
+                debug!("Ignoring email from {} outside the allowlist", request.from);
+                handled.push(uid);
+                continue;
+            }
+
+            // Tell the sender to retry later while new work is refused
+            if !self.admits_task() {
+                if let Err(e) = self.send_reply(&request.reply, OVERLOADED_REPLY).await {
+                    warn!("Failed to send overload notice: {}", e);
+                }
+                handled.push(uid);
+                continue;
+            }
+            if let Some(notice) = maintenance::current().filter(|notice| !notice.hold) {
+                if let Err(e) = self.send_reply(&request.reply, &notice.message).await {
+                    warn!("Failed to send maintenance notice: {}", e);
+                }
+                handled.push(uid);
+                continue;
+            }
+
+            // Create task for agent processing, traced like an HTTP request
+            let task = self.create_task(request);
+            match request_id::scope(RequestId::generate(), task).await {
+                Ok(_) => handled.push(uid),
+                Err(e) => error!("Failed to create task from email: {}", e),
+            }
+        }
+
+        if !handled.is_empty() {
+            within(limit, async {
+                session
+                    .uid_store(uid_set(&handled), "+FLAGS.SILENT (\\Seen)")
+                    .await?
+                    .try_collect::<Vec<_>>()
+                    .await
+            })
+            .await??;
+        }
+        let _ = session.logout().await;
+        Ok(())
+    }
+
+    /// Whether the memory guard and queue backpressure accept a new task.
+    /// Email tasks are never priority.
+    fn admits_task(&self) -> bool {
+        self.memory_guard.admits(false) && self.queue_guard.admits(false)
+    }
+
+    /// Create task in Redis for agent processing
+    async fn create_task(&mut self, request: Request) -> anyhow::Result<String> {
+        let task_id = Uuid::new_v4().to_string();
+        let request_id = request_id::current().unwrap_or_else(RequestId::generate);
+
+        // Create task in Redis with email metadata
+        let timeout_secs = self.runtime.current().watchdog.default_timeout_secs;
+        let deadline = watchdog::deadline(timeout_secs);
+        let mut task = serde_json::json!({
+            "input": request.input,
+            "config": {
+                "email_from": request.from,
+                "email_subject": request.reply.subject,
+                "email_message_id": request.reply.message_id,
+            },
+            "status": "pending",
+            "timeout_secs": timeout_secs,
+            "deadline": deadline.to_rfc3339(),
+            "request_id": request_id.0,
+            "created_at": chrono::Utc::now().to_rfc3339(),
+            "schema_version": schema::CURRENT_VERSION,
+        });
+        redaction::input("email", &mut task["input"]);
+
+        // Store the task and push it to the agent queue
+        let mut conn = self.redis_client.get_async_connection().await?;
+        agent_config::apply_defaults(&mut conn, "email", &mut task["config"]).await?;
+        let new_task = NewTask {
+            task_id: &task_id,
+            record: envelope::encode(&task)?,
+            labels: &BTreeMap::new(),
+            queue: Some(("agent:queue", deadline)),
+            tracked: true,
+        };
+        self.task_queue.submit(&mut conn, new_task).await?;
+
+        info!("Created task {} for email from {}", task_id, request.from);
+        self.pending_tasks.insert(
+            task_id.clone(),
+            PendingTask {
+                reply: request.reply,
+                request_id,
+            },
+        );
+        Ok(task_id)
+    }
+
+    /// Mail the replies of pending tasks that have settled, the same way the
+    /// Telegram adaptor answers its chats: answers, apologies for failures
+    /// and timeouts, and nothing for tasks that were deleted or settled past
+    /// their deadline.
+    async fn send_replies(&mut self) -> anyhow::Result<()> {
+        let task_ids: Vec<String> = self.pending_tasks.keys().cloned().collect();
+        if task_ids.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.redis_client.get_async_connection().await?;
+
+        let result_keys: Vec<String> = task_ids.iter().map(|id| format!("result:{}", id)).collect();
+        let results: Vec<Option<String>> = conn
+            .mget(&result_keys)
+            .instrument(telemetry::redis_span("MGET", "result:*"))
+            .await?;
+
+        let task_keys: Vec<String> = task_ids.iter().map(|id| format!("task:{}", id)).collect();
+        let tasks: Vec<Option<String>> = conn
+            .mget(&task_keys)
+            .instrument(telemetry::redis_span("MGET", "task:*"))
+            .await?;
+
+        let mut replies = Vec::new();
+        let mut delivered = Vec::new();
+        for ((task_id, result), task) in task_ids.into_iter().zip(results).zip(tasks) {
+            let task = task.map(|t| schema::decode_task(&t)).transpose()?;
+            let task = task.unwrap_or_default();
+            let late = watchdog::past_deadline(&task);
+            match (result, task["status"].as_str()) {
+                // Nobody wants an answer to a deleted task
+                (_, Some("deleted")) => {
+                    self.pending_tasks.remove(&task_id);
+                }
+                (Some(_), _) | (None, Some("failed")) if late => {
+                    info!("Task {} settled after its deadline, reply dropped", task_id);
+                    self.pending_tasks.remove(&task_id);
+                    delivered.push(task_id);
+                }
+                (Some(result), _) => {
+                    let text = self
+                        .templates
+                        .reply("email", &task_id, &envelope::decode(&result)?);
+                    if let Some(text) = text {
+                        replies.push((task_id, text));
+                    }
+                }
+                (None, Some("failed")) => replies.push((task_id, FAILED_REPLY.to_string())),
+                (None, Some("timed_out")) => replies.push((task_id, TIMED_OUT_REPLY.to_string())),
+                _ => {}
+            }
+        }
+
+        for (task_id, text) in replies {
+            let Some(task) = self.pending_tasks.remove(&task_id) else {
+                continue;
+            };
+            let sent =
+                request_id::scope(task.request_id.clone(), self.send_reply(&task.reply, &text))
+                    .await;
+            match sent {
+                Ok(()) => {
+                    info!(
+                        "Sent response for task {} to {}",
+                        task_id, task.reply.to.email
+                    );
+                    delivered.push(task_id);
+                }
+                Err(e) => {
+                    error!("Failed to send email reply: {}", e);
+                    self.pending_tasks.insert(task_id, task);
+                }
+            }
+        }
+
+        // Clean up delivered and expired results and error reports from Redis
+        if !delivered.is_empty() {
+            let keys: Vec<String> = delivered
+                .iter()
+                .flat_map(|id| [format!("result:{}", id), failures::error_key(id)])
+                .collect();
+            let _: Result<i64, _> = conn.del(&keys).await;
+        }
+
+        Ok(())
+    }
+
+    /// Mail `text` in answer to a request, threaded under it
+    async fn send_reply(&self, reply: &Reply, text: &str) -> anyhow::Result<()> {
+        let message = reply_message(&self.from, reply, text)?;
+        self.retry
+            .delivery
+            .run("SMTP send", || self.smtp.send(message.clone()))
+            .await?;
+        Ok(())
+    }
+
+    /// Run the adaptor loop
+    pub async fn run(&mut self) {
+        info!("Email adaptor started");
+
+        let mut failures = 0u32;
+        loop {
+            if let Err(e) = self.send_replies().await {
+                warn!("Failed to send email replies: {}", e);
+            }
+
+            let interval = Duration::from_secs(self.runtime.current().email.poll_interval_secs);
+            let delay = match self.fetch_requests().await {
+                Ok(()) => {
+                    failures = 0;
+                    interval
+                }
+                Err(e) => {
+                    failures += 1;
+                    error!("Error in email adaptor loop: {}", e);
+                    self.retry.delivery.delay_for(failures).max(interval)
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Fail `operation` if it takes longer than `limit`
+async fn within<T>(limit: Duration, operation: impl Future<Output = T>) -> anyhow::Result<T> {
+    tokio::time::timeout(limit, operation)
+        .await
+        .map_err(|_| anyhow::anyhow!("mail server did not answer within {:?}", limit))
+}
+
+/// The mail from `from` answering a request with `text`, threaded under it
+fn reply_message(
+    from: &Mailbox,
+    reply: &Reply,
+    text: &str,
+) -> Result<lettre::Message, lettre::error::Error> {
+    let domain = from.email.domain();
+    let mut message = lettre::Message::builder()
+        .from(from.clone())
+        .to(reply.to.clone())
+        .subject(&reply.subject)
+        .message_id(Some(format!("<{}@{}>", Uuid::new_v4(), domain)))
+        .header(ContentType::TEXT_PLAIN)
+        .raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str("Auto-Submitted"),
+            "auto-replied".to_string(),
+        ));
+    if let Some(message_id) = &reply.message_id {
+        let references = reply
+            .references
+            .iter()
+            .chain([message_id])
+            .map(|id| format!("<{}>", id))
+            .collect::<Vec<_>>()
+            .join(" ");
+        message = message
+            .in_reply_to(format!("<{}>", message_id))
+            .references(references);
+    }
+    message.body(text.to_string())
+}
+
+/// IMAP sequence set of `uids`
+fn uid_set(uids: &[u32]) -> String {
+    uids.iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Read a request out of a raw message, or `None` if it should be ignored
+fn parse_request(raw: &[u8], own_address: &str) -> Option<Request> {
+    let message = MessageParser::default().parse(raw)?;
+    let sender = message.from()?.first()?;
+    let from = sender.address()?.to_string();
+    if from.eq_ignore_ascii_case(own_address) {
+        return None;
+    }
+
+    let auto_submitted = message
+        .header_raw("Auto-Submitted")
+        .is_some_and(|v| !v.trim().eq_ignore_ascii_case("no"));
+    let bulk = message.header_raw("Precedence").is_some_and(|v| {
+        matches!(
+            v.trim().to_ascii_lowercase().as_str(),
+            "bulk" | "list" | "junk"
+        )
+    });
+    if auto_submitted || bulk {
+        debug!("Ignoring automatic email from {}", from);
+        return None;
+    }
+
+    let subject = message.subject().unwrap_or_default().trim().to_string();
+    let body = message
+        .body_text(0)
+        .map(|b| strip_quoted(&b))
+        .unwrap_or_default();
+    // Short requests sometimes come in the subject line alone
+    let input = if body.is_empty() {
+        subject.clone()
+    } else {
+        body
+    };
+    if input.is_empty() {
+        return None;
+    }
+
+    let reply_to = message
+        .reply_to()
+        .and_then(|a| a.first())
+        .filter(|a| a.address().is_some())
+        .unwrap_or(sender);
+    let to = match reply_to.address()?.parse() {
+        Ok(address) => Mailbox::new(reply_to.name().map(str::to_string), address),
+        Err(e) => {
+            warn!(
+                "Ignoring email with unusable reply address from {}: {}",
+                from, e
+            );
+            return None;
+        }
+    };
+    let subject = match subject.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("re:") => subject,
+        _ if subject.is_empty() => "Re: your request".to_string(),
+        _ => format!("Re: {}", subject),
+    };
+    let references = message
+        .references()
+        .as_text_list()
+        .unwrap_or_default()
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+
+    Some(Request {
+        from,
+        input,
+        reply: Reply {
+            to,
+            subject,
+            message_id: message.message_id().map(str::to_string),
+            references,
+        },
+    })
+}
+
+/// The part of a body above the quoted message it replies to
+fn strip_quoted(body: &str) -> String {
+    let quote_start = |line: &str| {
+        let line = line.trim();
+        line.starts_with('>')
+            || line == "-----Original Message-----"
+            // Outlook's separator above the quoted headers
+            || (line.len() >= 10 && line.chars().all(|c| c == '_'))
+            || (line.starts_with("On ") && line.ends_with("wrote:"))
+    };
+    body.lines()
+        .take_while(|line| !quote_start(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Start the email adaptor in a background task, restarted by `supervisor`
+/// when it crashes
+#[allow(clippy::too_many_arguments)]
+pub fn start_email_adaptor(
+    supervisor: &Arc<Supervisor>,
+    redis_client: Arc<Client>,
+    retry: RetryPolicies,
+    task_queue: TaskQueue,
+    memory_guard: Arc<MemoryGuard>,
+    queue_guard: Arc<QueueGuard>,
+    runtime: Arc<RuntimeConfig>,
+    templates: ReplyTemplates,
+) {
+    supervisor.supervise("email", move || {
+        let adaptor = EmailAdaptor::new(
+            redis_client.clone(),
+            retry.clone(),
+            task_queue.clone(),
+            memory_guard.clone(),
+            queue_guard.clone(),
+            runtime.clone(),
+            templates.clone(),
+        );
+        async move {
+            let mut adaptor =
+                adaptor.map_err(|e| anyhow::anyhow!("Failed to set up the email adaptor: {}", e))?;
+            adaptor.run().await;
+            Ok(())
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const OWN: &str = "agent@example.com";
+
+    fn raw(headers: &str, body: &str) -> Vec<u8> {
+        format!("{}\r\n\r\n{}", headers.replace('\n', "\r\n"), body).into_bytes()
+    }
+
+    #[test]
+    fn requests_carry_sender_body_and_thread() {
+        let message = raw(
+            "From: Ada <ada@example.org>\n\
+             To: agent@example.com\n\
+             Subject: Summarise the report\n\
+             Message-ID: <m2@example.org>\n\
+             References: <m0@example.org> <m1@example.org>",
+            "Please keep it short.\r\n\r\nOn Monday, Bob wrote:\r\n> earlier text\r\n",
+        );
+        let request = parse_request(&message, OWN).unwrap();
+        assert_eq!(request.from, "ada@example.org");
+        assert_eq!(request.input, "Please keep it short.");
+        assert_eq!(request.reply.to.to_string(), "Ada <ada@example.org>");
+        assert_eq!(request.reply.subject, "Re: Summarise the report");
+        assert_eq!(request.reply.message_id.as_deref(), Some("m2@example.org"));
+        assert_eq!(
+            request.reply.references,
+            ["m0@example.org", "m1@example.org"]
+        );
+    }
+
+    #[test]
+    fn subjects_stand_in_for_empty_bodies() {
+        let message = raw(
+            "From: ada@example.org\nReply-To: team@example.org\nSubject: RE: status",
+            "",
+        );
+        let request = parse_request(&message, OWN).unwrap();
+        assert_eq!(request.input, "RE: status");
+        assert_eq!(request.reply.subject, "RE: status");
+        assert_eq!(request.reply.to.email.to_string(), "team@example.org");
+        assert!(request.reply.message_id.is_none());
+
+        let message = raw("From: ada@example.org", "hello");
+        assert_eq!(
+            parse_request(&message, OWN).unwrap().reply.subject,
+            "Re: your request"
+        );
+        assert!(parse_request(&raw("From: ada@example.org", ""), OWN).is_none());
+    }
+
+    #[test]
+    fn own_and_automatic_mail_is_ignored() {
+        let own = raw("From: Agent <AGENT@example.com>\nSubject: hi", "hello");
+        assert!(parse_request(&own, OWN).is_none());
+        let auto = raw(
+            "From: ada@example.org\nAuto-Submitted: auto-replied",
+            "away",
+        );
+        assert!(parse_request(&auto, OWN).is_none());
+        let list = raw("From: ada@example.org\nPrecedence: bulk", "news");
+        assert!(parse_request(&list, OWN).is_none());
+        let manual = raw("From: ada@example.org\nAuto-Submitted: no", "hello");
+        assert!(parse_request(&manual, OWN).is_some());
+    }
+
+    #[test]
+    fn allowlist_matches_addresses_and_domains() {
+        let mut config = Config::default();
+        assert!(config.email_sender_allowed("anyone@example.net"));
+
+        config.email.allowed_senders = vec!["ada@example.org".into(), "@team.example".into()];
+        assert!(config.email_sender_allowed("ADA@example.org"));
+        assert!(config.email_sender_allowed("bob@Team.Example"));
+        assert!(!config.email_sender_allowed("eve@example.org"));
+        assert!(!config.email_sender_allowed("bob@sub.team.example"));
+    }
+
+    #[test]
+    fn replies_thread_under_the_request() {
+        let message = raw(
+            "From: ada@example.org\n\
+             Subject: Summarise\n\
+             Message-ID: <m2@example.org>\n\
+             References: <m0@example.org> <m1@example.org>",
+            "Please.",
+        );
+        let request = parse_request(&message, OWN).unwrap();
+        let from: Mailbox = OWN.parse().unwrap();
+        let reply = reply_message(&from, &request.reply, "Done").unwrap();
+        let headers = reply.headers();
+        assert_eq!(headers.get_raw("In-Reply-To"), Some("<m2@example.org>"));
+        assert_eq!(
+            headers.get_raw("References"),
+            Some("<m0@example.org> <m1@example.org> <m2@example.org>")
+        );
+        assert_eq!(headers.get_raw("Auto-Submitted"), Some("auto-replied"));
+        assert_eq!(headers.get_raw("Subject"), Some("Re: Summarise"));
+
+        let unthreaded = Reply {
+            message_id: None,
+            ..request.reply
+        };
+        let reply = reply_message(&from, &unthreaded, "Done").unwrap();
+        assert!(reply.headers().get_raw("In-Reply-To").is_none());
+        assert!(reply.headers().get_raw("References").is_none());
+    }
+}
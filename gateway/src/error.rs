@@ -0,0 +1,191 @@
+//! Structured API errors.
+//!
+//! Every route reports failures as a JSON body of the form
+//! `{code, message, details, trace_id}`. The trace id is the request's
+//! `X-Request-Id` and is logged next to the error so a client's bug report can
+//! be matched against the gateway logs.
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use claw_core::store::StoreError;
+use serde::Serialize;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::request_id;
+use crate::task_state::TransitionError;
+use crate::validation::ValidationError;
+
+/// Error returned by handlers and middleware
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// Stable machine-readable error code
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+    /// Seconds clients should wait before retrying, sent as `Retry-After`
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a serde_json::Value>,
+    trace_id: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn unprocessable(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, code, message)
+    }
+
+    pub fn unavailable(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, code, message)
+    }
+
+    pub fn bad_gateway(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, "upstream_error", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+
+    pub fn timeout(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::GATEWAY_TIMEOUT, "timeout", message)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.code, self.status, self.message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let trace_id = request_id::current()
+            .map(|id| id.0)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        if self.status.is_server_error() {
+            error!(trace_id = %trace_id, "{}", self);
+        } else {
+            debug!(trace_id = %trace_id, "{}", self);
+        }
+
+        let body = ErrorBody {
+            code: self.code,
+            message: &self.message,
+            details: self.details.as_ref(),
+            trace_id,
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if let Some(seconds) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+impl From<redis::RedisError> for ApiError {
+    fn from(e: redis::RedisError) -> Self {
+        error!("Redis error: {}", e);
+        // Only failures to reach Redis are `storage_error`; replies such as
+        // OOM or WRONGTYPE would come back the same on a retry
+        let unreachable = e.is_io_error()
+            || e.is_connection_refusal()
+            || e.is_timeout()
+            || e.is_connection_dropped();
+        if unreachable {
+            return Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "storage_error",
+                "Task storage is unavailable",
+            );
+        }
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "storage_refused",
+            "Task storage refused the request",
+        )
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        error!("Serialization error: {}", e);
+        Self::internal("Stored data could not be decoded")
+    }
+}
+
+impl From<TransitionError> for ApiError {
+    fn from(e: TransitionError) -> Self {
+        match e {
+            TransitionError::NotFound => Self::not_found("Task not found"),
+            TransitionError::Illegal { .. } | TransitionError::Contended => {
+                Self::new(StatusCode::CONFLICT, "invalid_transition", e.to_string())
+            }
+            TransitionError::Redis(e) => e.into(),
+            TransitionError::Decode(e) => e.into(),
+        }
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Redis(e) => e.into(),
+            StoreError::Decode(e) => e.into(),
+        }
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(e: ValidationError) -> Self {
+        Self::new(e.status, e.code, "Request validation failed")
+            .with_details(serde_json::json!({ "violations": e.violations }))
+    }
+}
@@ -0,0 +1,334 @@
+//! Task lifecycle events and their export to Kafka.
+//!
+//! The gateway reports each task it creates as JSON records: `task.created`
+//! on submission, `task.started` once an agent picks it up, then
+//! `task.completed`, `task.failed` (failed, timed out or orphaned) or
+//! `task.deleted`. Events go to the Kafka topic `events.topic` when
+//! `events.brokers` is set, keyed by task id, and to the
+//! [`subscriptions`](crate::subscriptions) hub while anyone is subscribed.
+//! New tasks are noted in the Redis hash `events:tracked` with the last stage
+//! reported for them. A tracker loop compares that with each task record and
+//! hands the events due to a bounded buffer per sink; the Kafka producer
+//! drains its buffer in batches under the delivery retry policy. Requests
+//! never wait on Kafka; while a buffer is full, tracking pauses instead. A
+//! batch that still fails after its retries is dropped and counted.
+
+use redis::{AsyncCommands, Client};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::config::EventSettings;
+use crate::schema;
+use crate::metrics::Counter;
+use crate::retry::RetryPolicy;
+
+/// Redis hash of tracked task ids and the last stage reported for each
+const TRACKED_KEY: &str = "events:tracked";
+
+/// Stage of a tracked task nothing has been reported for yet
+const NOT_REPORTED: &str = "new";
+
+/// Every event reported
+pub const EVENTS: [&str; 5] = [
+    "task.created",
+    "task.started",
+    "task.completed",
+    "task.failed",
+    "task.deleted",
+];
+
+/// Statuses reported as `task.failed`
+const FAILED_STATUSES: [&str; 3] = ["failed", "timed_out", "orphaned"];
+
+/// Set once Kafka export has started, so tasks are only tracked when
+/// something will report them
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set while event subscriptions exist
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+/// Start reporting lifecycle events for `task_id`, if anything consumes them
+pub async fn track(conn: &mut redis::aio::Connection, task_id: &str) -> redis::RedisResult<()> {
+    if !ENABLED.load(Ordering::Relaxed) && !SUBSCRIBED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    conn.hset(TRACKED_KEY, task_id, NOT_REPORTED).await
+}
+
+/// Add tracking `task_id` to `pipe`, as [`track`] does
+pub fn track_in(pipe: &mut redis::Pipeline, task_id: &str) {
+    if ENABLED.load(Ordering::Relaxed) || SUBSCRIBED.load(Ordering::Relaxed) {
+        pipe.hset(TRACKED_KEY, task_id, NOT_REPORTED).ignore();
+    }
+}
+
+/// Note whether any subscription wants task events
+pub fn set_subscribed(subscribed: bool) {
+    SUBSCRIBED.store(subscribed, Ordering::Relaxed);
+}
+
+/// Counters maintained by the producer
+#[derive(Debug, Default)]
+pub struct EventMetrics {
+    /// Events acknowledged by Kafka
+    pub delivered: Counter,
+    /// Events given up on after their batch exhausted its retries
+    pub dropped: Counter,
+}
+
+/// Events `task` is due, and the stage to remember afterwards (`None` once
+/// it has settled)
+fn due_events(
+    task: &Value,
+    has_result: bool,
+    reported: &str,
+) -> (Vec<&'static str>, Option<&'static str>) {
+    let status = task["status"].as_str().unwrap_or("unknown");
+    let started = status == "processing" || !task["started_at"].is_null();
+    let settled = if has_result || status == "completed" {
+        Some("task.completed")
+    } else if FAILED_STATUSES.contains(&status) {
+        Some("task.failed")
+    } else if status == "deleted" {
+        Some("task.deleted")
+    } else {
+        None
+    };
+
+    let mut events = Vec::new();
+    if reported == NOT_REPORTED {
+        events.push("task.created");
+    }
+    if started && reported != "started" {
+        events.push("task.started");
+    }
+    match settled {
+        Some(event) => {
+            events.push(event);
+            (events, None)
+        }
+        // A requeued task loses started_at and may start again
+        None if started => (events, Some("started")),
+        None => (events, Some("created")),
+    }
+}
+
+/// The record sent for `event` on `task_id`
+fn event_record(event: &str, task_id: &str, task: &Value) -> Value {
+    let fields: &[&str] = match event {
+        "task.created" => &["created_at"],
+        "task.started" => &["started_at"],
+        "task.deleted" => &["deleted_at"],
+        _ => &["completed_at", "timed_out_at", "orphaned_at"],
+    };
+    let occurred_at = fields
+        .iter()
+        .find_map(|field| task[*field].as_str())
+        .map_or_else(|| chrono::Utc::now().to_rfc3339(), str::to_string);
+    json!({
+        "event": event,
+        "task_id": task_id,
+        "status": task["status"],
+        "submitted_by": task["submitted_by"],
+        "request_id": task["request_id"],
+        "capability": task["capability"],
+        "labels": task["labels"],
+        "agent_id": task["agent_id"],
+        "occurred_at": occurred_at,
+    })
+}
+
+/// Queue the events due for every tracked task with every sink
+async fn track_pass(redis_client: &Client, sinks: &[mpsc::Sender<Value>]) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let tracked: HashMap<String, String> = conn.hgetall(TRACKED_KEY).await?;
+
+    for (task_id, reported) in tracked {
+        let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+        let Some(task) = task else {
+            // Nothing left in Redis to report on
+            conn.hdel::<_, _, ()>(TRACKED_KEY, &task_id).await?;
+            continue;
+        };
+        let task = schema::decode_task(&task)?;
+        let has_result: bool = conn.exists(format!("result:{}", task_id)).await?;
+
+        let (events, next) = due_events(&task, has_result, &reported);
+        for event in events {
+            let record = event_record(event, &task_id, &task);
+            for sink in sinks {
+                // Waits while the buffer is full, leaving the rest for later
+                sink.send(record.clone()).await?;
+            }
+        }
+        match next {
+            Some(stage) if stage != reported => {
+                conn.hset::<_, _, _, ()>(TRACKED_KEY, &task_id, stage)
+                    .await?
+            }
+            Some(_) => {}
+            None => conn.hdel::<_, _, ()>(TRACKED_KEY, &task_id).await?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Send buffered events to Kafka in batches until the buffer closes
+async fn produce(
+    settings: EventSettings,
+    mut buffer: mpsc::Receiver<Value>,
+    retry: RetryPolicy,
+    metrics: Arc<EventMetrics>,
+) {
+    let sink = loop {
+        match retry
+            .run("Kafka connect", || KafkaSink::connect(&settings))
+            .await
+        {
+            Ok(sink) => break sink,
+            Err(e) => {
+                error!("Failed to connect to Kafka: {}", e);
+                tokio::time::sleep(Duration::from_secs(settings.interval_secs)).await;
+            }
+        }
+    };
+
+    let mut batch = Vec::with_capacity(settings.batch_size);
+    while buffer.recv_many(&mut batch, settings.batch_size).await > 0 {
+        match retry.run("Kafka event export", || sink.send(&batch)).await {
+            Ok(()) => metrics.delivered.add(batch.len() as u64),
+            Err(e) => {
+                error!("Dropped {} task events: {}", batch.len(), e);
+                metrics.dropped.add(batch.len() as u64);
+            }
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(feature = "kafka")]
+struct KafkaSink {
+    partitions: Vec<rskafka::client::partition::PartitionClient>,
+}
+
+/// Stand-in without the `kafka` feature; it cannot be constructed
+#[cfg(not(feature = "kafka"))]
+enum KafkaSink {}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    /// Connect to every partition of the topic, which must already exist
+    async fn connect(settings: &EventSettings) -> anyhow::Result<Self> {
+        use rskafka::client::{partition::UnknownTopicHandling, ClientBuilder};
+
+        // Bound the client's own retries so ours decide when to give up
+        let backoff = rskafka::BackoffConfig {
+            deadline: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let client = ClientBuilder::new(settings.brokers.clone())
+            .client_id("secure-gateway")
+            .backoff_config(backoff)
+            .build()
+            .await?;
+        let Some(topic) = client
+            .list_topics()
+            .await?
+            .into_iter()
+            .find(|t| t.name == settings.topic)
+        else {
+            anyhow::bail!("Kafka topic {} does not exist", settings.topic);
+        };
+
+        let mut partitions = Vec::with_capacity(topic.partitions.len());
+        for partition in topic.partitions {
+            partitions.push(
+                client
+                    .partition_client(&settings.topic, partition, UnknownTopicHandling::Retry)
+                    .await?,
+            );
+        }
+        if partitions.is_empty() {
+            anyhow::bail!("Kafka topic {} has no partitions", settings.topic);
+        }
+        tracing::info!(
+            "Exporting task events to Kafka topic {} ({} partitions)",
+            settings.topic,
+            partitions.len()
+        );
+        Ok(Self { partitions })
+    }
+
+    /// Produce `events`, each task's on one partition so they stay in order
+    async fn send(&self, events: &[Value]) -> anyhow::Result<()> {
+        use rskafka::client::partition::Compression;
+        use rskafka::record::Record;
+        use std::hash::{Hash, Hasher};
+
+        let mut by_partition: HashMap<usize, Vec<Record>> = HashMap::new();
+        for event in events {
+            let key = event["task_id"].as_str().unwrap_or_default();
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            key.hash(&mut hasher);
+            let partition = (hasher.finish() % self.partitions.len() as u64) as usize;
+            by_partition.entry(partition).or_default().push(Record {
+                key: Some(key.as_bytes().to_vec()),
+                value: Some(serde_json::to_vec(event)?),
+                headers: Default::default(),
+                timestamp: chrono::Utc::now(),
+            });
+        }
+        for (partition, records) in by_partition {
+            self.partitions[partition]
+                .produce(records, Compression::NoCompression)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+impl KafkaSink {
+    async fn connect(_settings: &EventSettings) -> anyhow::Result<Self> {
+        anyhow::bail!("gateway built without the kafka feature")
+    }
+
+    async fn send(&self, _events: &[Value]) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
+
+/// Start the Kafka producer in a background task, returning its buffer
+pub fn start_event_export(
+    settings: &EventSettings,
+    retry: RetryPolicy,
+    metrics: Arc<EventMetrics>,
+) -> mpsc::Sender<Value> {
+    ENABLED.store(true, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::channel(settings.buffer_size);
+    tokio::spawn(produce(settings.clone(), receiver, retry, metrics));
+    sender
+}
+
+/// Start the event tracker in a background task, feeding `sinks`
+pub fn start_event_tracking(
+    redis_client: Arc<Client>,
+    settings: &EventSettings,
+    sinks: Vec<mpsc::Sender<Value>>,
+) {
+    let interval = Duration::from_secs(settings.interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = track_pass(&redis_client, &sinks).await {
+                error!("Task event tracking error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
@@ -0,0 +1,86 @@
+//! Task failure reports.
+//!
+//! Agents report success by writing `result:{id}`. A task that fails instead
+//! gets an error report under `error:{id}`, in the same envelope as results:
+//! an object whose `error` field is a human-readable message, optionally
+//! with a machine-readable `code` and whatever details the agent wants to
+//! pass on. The task record goes to status `failed` and carries the message
+//! in its own `error` field, which is all older agents and `claw-admin
+//! drain` leave behind.
+//!
+//! `GET /task/:id` answers a failed task with status `failed`, the message
+//! in `error` and the report in `result`, and the Telegram adaptor tells the
+//! chat the request could not be completed. [`settlement`] tells the
+//! gateway's own orchestrators whether a task they wait on has ended.
+
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+
+use crate::{envelope, schema};
+use crate::error::ApiError;
+
+pub use claw_core::keys::error_key;
+
+/// Message for failures that did not say what went wrong
+const UNKNOWN_FAILURE: &str = "Task failed";
+
+/// The human-readable message of an error report
+pub fn message(report: &Value) -> String {
+    ["error", "message"]
+        .iter()
+        .find_map(|field| report[*field].as_str())
+        .unwrap_or(UNKNOWN_FAILURE)
+        .to_string()
+}
+
+/// The error report of a failed task without an `error:{id}` key, built
+/// from its record
+pub fn from_record(task: &Value) -> Value {
+    if let Some(message) = task["error"].as_str() {
+        return json!({ "error": message });
+    }
+    // Agents used to leave their failed output on the record
+    match &task["result"] {
+        result @ Value::Object(_) => result.clone(),
+        _ => json!({ "error": UNKNOWN_FAILURE }),
+    }
+}
+
+/// Statuses a task ends in without a result
+pub const FAILED_STATUSES: [&str; 4] = ["failed", "timed_out", "orphaned", "deleted"];
+
+/// How a task the gateway waits on stands
+pub enum Settlement {
+    Running,
+    Completed(Value),
+    /// Failed, timed out, orphaned, deleted or gone, with the reason
+    Failed(String),
+}
+
+/// Whether `task_id` has a result, an error report or a final status
+pub async fn settlement<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+) -> Result<Settlement, ApiError> {
+    let result: Option<String> = conn.get(format!("result:{}", task_id)).await?;
+    if let Some(result) = result {
+        return Ok(Settlement::Completed(envelope::decode(&result)?));
+    }
+    let report: Option<String> = conn.get(error_key(task_id)).await?;
+    if let Some(report) = report {
+        let report = envelope::decode(&report)?;
+        return Ok(Settlement::Failed(message(&report)));
+    }
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    let Some(task) = task else {
+        return Ok(Settlement::Failed(format!("task {} is gone", task_id)));
+    };
+    let task = schema::decode_task(&task)?;
+    let status = task["status"].as_str().unwrap_or("unknown");
+    Ok(if FAILED_STATUSES.contains(&status) {
+        Settlement::Failed(format!("task {} ended as {}", task_id, status))
+    } else {
+        Settlement::Running
+    })
+}
@@ -0,0 +1,436 @@
+//! Fan-out/fan-in map tasks.
+//!
+//! A submission with `"map": {}` and an array `input` is expanded into one
+//! child task per element, `{task_id}.{index}`, each with the element as its
+//! input and the parent's capability, timeout and labels, submitted like any
+//! other task. The parent's config is merged over the default agent config
+//! once, and every child and the parent's record carry the merged config. Arrays may hold up to `limits.max_map_items`
+//! elements. The parent itself never reaches an agent: its record, status
+//! `processing`, lists its `children`, and it stays in `map:running` while a
+//! background aggregator checks on them every `tasks.map_interval_secs`.
+//!
+//! The parent's result is `{"results": [...], "errors": [...], "completed":
+//! n, "failed": n}`, `results` aligned with the input (null for failed items)
+//! and `errors` listing `{"index", "task_id", "error"}`. `map.on_failure`
+//! decides what a failed child does to the parent: `fail` (the default) fails
+//! it at once with an error report coded `map_failed`, `partial` waits for
+//! every child and completes with whatever succeeded, failing only if
+//! nothing did.
+
+use axum::{http::StatusCode, Extension};
+use chrono::Utc;
+use claw_core::{MapSpec, OnFailure};
+use redis::{AsyncCommands, Client};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth::Principal;
+use crate::cache::CacheControl;
+use crate::error::ApiError;
+use crate::failures::{self, Settlement};
+use crate::federation::PeerOrigin;
+use crate::queue::NewTask;
+use crate::runtime::RuntimeConfig;
+use crate::{enqueue_task, envelope, events, get_config, history, redis_connection};
+use crate::{request_id, schema};
+use crate::{task_state, validation, AgentRequest, AgentResponse, AppState, Submitted};
+
+/// Redis set of the map tasks whose children are still running
+const RUNNING_KEY: &str = "map:running";
+
+/// Task id of the child running element `index`
+pub fn child_id(task_id: &str, index: usize) -> String {
+    format!("{}.{}", task_id, index)
+}
+
+/// Store the parent of a map task and submit one child per input element
+pub async fn submit(
+    state: AppState,
+    peer_origin: Option<Extension<PeerOrigin>>,
+    principal: Option<Extension<Principal>>,
+    cache_control: CacheControl,
+    req: AgentRequest,
+    map: MapSpec,
+) -> Result<Submitted, ApiError> {
+    let Some(items) = req.input.as_array() else {
+        return Err(ApiError::bad_request(
+            "invalid_map",
+            "A map task needs an array input",
+        ));
+    };
+    let children: Vec<String> = (0..items.len())
+        .map(|i| child_id(&req.task_id, i))
+        .collect();
+
+    // Children take the ids after the parent's, which must all be free
+    let mut conn = redis_connection(&state).await?;
+    let child_keys: Vec<String> = children.iter().map(|id| format!("task:{}", id)).collect();
+    let taken: usize = conn.exists(&child_keys).await?;
+    if taken > 0 {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "duplicate_task",
+            format!(
+                "Tasks with the ids of {}'s items already exist",
+                req.task_id
+            ),
+        ));
+    }
+
+    // Merged once, so a config the gateway cannot merge refuses the map
+    // rather than each child
+    let config = get_config(&state, &req.config).await?;
+
+    let mut task = json!({
+        "input": req.input,
+        "config": config,
+        "status": "processing",
+        "map": map,
+        "children": children,
+        "capability": req.capability,
+        "submitted_by": principal.as_ref().map(|Extension(p)| p.key_id.clone()),
+        "request_id": request_id::current().map(|id| id.0),
+        "labels": req.labels,
+        "created_at": Utc::now().to_rfc3339(),
+        "schema_version": schema::CURRENT_VERSION,
+    });
+    if let Some(parent) = &req.parent_task_id {
+        task["parent_task_id"] = parent.clone().into();
+    }
+    let parent = NewTask {
+        task_id: &req.task_id,
+        record: envelope::encode(&task)?,
+        labels: &req.labels,
+        queue: None,
+        tracked: true,
+    };
+    state.task_queue.submit(&mut conn, parent).await?;
+    conn.sadd::<_, _, ()>(RUNNING_KEY, &req.task_id).await?;
+
+    for (child, item) in children.iter().zip(items) {
+        let child_req = AgentRequest {
+            task_id: child.clone(),
+            input: item.clone(),
+            config: Some(config.clone()),
+            capability: req.capability.clone(),
+            timeout_seconds: req.timeout_seconds,
+            labels: req.labels.clone(),
+            depends_on: Vec::new(),
+            map: None,
+            parent_task_id: None,
+        };
+        let submitted =
+            match validation::validate_request(&child_req, &state.config.current().limits) {
+                Ok(()) => Box::pin(enqueue_task(
+                    state.clone(),
+                    peer_origin.clone(),
+                    principal.clone(),
+                    cache_control,
+                    child_req,
+                ))
+                .await
+                .map(|_| ()),
+                Err(e) => Err(e.into()),
+            };
+        // The aggregator finds a refused child failed; a task that took the
+        // child's id meanwhile keeps its own error report
+        if let Err(e) = submitted {
+            warn!(
+                "Map task {} item {} refused: {}",
+                req.task_id, child, e.message
+            );
+            if e.code == "duplicate_task" {
+                continue;
+            }
+            let report = json!({"error": e.message, "code": e.code});
+            conn.set::<_, _, ()>(failures::error_key(child), envelope::encode(&report)?)
+                .await?;
+        }
+    }
+    info!(
+        "Map task {} submitted with {} items",
+        req.task_id,
+        children.len()
+    );
+
+    Ok(Submitted {
+        response: AgentResponse {
+            task_id: req.task_id,
+            status: "submitted".to_string(),
+            result: None,
+            error: None,
+            signature: None,
+        },
+        cache_status: None,
+        age: None,
+    })
+}
+
+/// Complete or fail the map task `task_id` once its children allow it
+async fn aggregate(conn: &mut redis::aio::Connection, task_id: &str) -> anyhow::Result<()> {
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    let task = task.map(|t| schema::decode_task(&t)).transpose()?;
+    let Some(task) = task.filter(|t| t["status"] == "processing") else {
+        conn.srem::<_, _, ()>(RUNNING_KEY, task_id).await?;
+        return Ok(());
+    };
+    let map: MapSpec = serde_json::from_value(task["map"].clone()).unwrap_or_default();
+    let children: Vec<String> = serde_json::from_value(task["children"].clone())?;
+
+    let mut settlements = Vec::with_capacity(children.len());
+    for child in &children {
+        let settlement = failures::settlement(conn, child)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e.message))?;
+        settlements.push(settlement);
+    }
+    match aggregated(map.on_failure, &children, settlements) {
+        Some((status, record)) => settle(conn, task_id, status, record).await,
+        None => Ok(()),
+    }
+}
+
+/// The status a map task settles in and its result or error report, given
+/// how its children stand; `None` while it has to wait for some of them
+fn aggregated(
+    on_failure: OnFailure,
+    children: &[String],
+    settlements: Vec<Settlement>,
+) -> Option<(&'static str, Value)> {
+    let mut results = Vec::with_capacity(children.len());
+    let mut errors = Vec::new();
+    let mut running = false;
+    for (index, (child, settlement)) in children.iter().zip(settlements).enumerate() {
+        match settlement {
+            Settlement::Running => {
+                running = true;
+                results.push(Value::Null);
+            }
+            Settlement::Completed(result) => results.push(result),
+            Settlement::Failed(message) => {
+                if on_failure == OnFailure::Fail {
+                    let message = format!("Item {} failed: {}", index, message);
+                    let report = json!({
+                        "error": message,
+                        "code": "map_failed",
+                        "index": index,
+                        "task_id": child,
+                    });
+                    return Some(("failed", report));
+                }
+                results.push(Value::Null);
+                errors.push(json!({"index": index, "task_id": child, "error": message}));
+            }
+        }
+    }
+
+    if running {
+        return None;
+    }
+
+    let failed = errors.len();
+    let aggregated = json!({
+        "results": results,
+        "errors": errors,
+        "completed": children.len() - failed,
+        "failed": failed,
+    });
+    if failed == children.len() {
+        let mut report = aggregated;
+        report["error"] = "Every item failed".into();
+        report["code"] = "map_failed".into();
+        return Some(("failed", report));
+    }
+    Some(("completed", aggregated))
+}
+
+/// Move a map task to `status`, storing `record` as its result or report
+async fn settle(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    status: &str,
+    record: Value,
+) -> anyhow::Result<()> {
+    let failed = status == "failed";
+    let settled = task_state::transition(conn, task_id, status, |task| {
+        if failed {
+            task["error"] = failures::message(&record).into();
+        }
+        task["completed_at"] = Utc::now().to_rfc3339().into();
+        task["status"] == "processing"
+    })
+    .await?;
+    if settled.is_some() {
+        let key = if failed {
+            failures::error_key(task_id)
+        } else {
+            format!("result:{}", task_id)
+        };
+        conn.set::<_, _, ()>(key, envelope::encode(&record)?)
+            .await?;
+        history::track(conn, task_id).await?;
+        events::track(conn, task_id).await?;
+        info!("Map task {} {}", task_id, status);
+    }
+    conn.srem::<_, _, ()>(RUNNING_KEY, task_id).await?;
+    Ok(())
+}
+
+/// Check every running map task
+async fn aggregate_pass(redis_client: &Client) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let running: Vec<String> = conn.smembers(RUNNING_KEY).await?;
+    for task_id in running {
+        if let Err(e) = aggregate(&mut conn, &task_id).await {
+            error!("Failed to aggregate map task {}: {}", task_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Start settling map tasks in a background task
+pub fn start_map_aggregator(redis_client: Arc<Client>, runtime: Arc<RuntimeConfig>) {
+    let interval = Duration::from_secs(runtime.current().tasks.map_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = aggregate_pass(&redis_client).await {
+                error!("Map aggregator error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+
+    /// Settle `children` as stored in `redis` under `on_failure`
+    async fn aggregate_children(
+        redis: &mut FakeRedis,
+        on_failure: OnFailure,
+        children: &[String],
+    ) -> Option<(&'static str, Value)> {
+        let mut settlements = Vec::new();
+        for child in children {
+            settlements.push(failures::settlement(redis, child).await.unwrap());
+        }
+        aggregated(on_failure, children, settlements)
+    }
+
+    fn store(redis: &FakeRedis, key: String, value: Value) {
+        redis.set(&key, &envelope::encode(&value).unwrap());
+    }
+
+    fn running(redis: &FakeRedis, task_id: &str) {
+        let task = json!({"status": "processing", "schema_version": schema::CURRENT_VERSION});
+        store(redis, format!("task:{}", task_id), task);
+    }
+
+    fn children() -> Vec<String> {
+        (0..3).map(|i| child_id("m", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn aggregates_results_in_input_order() {
+        let mut redis = FakeRedis::new();
+        let children = children();
+        store(&redis, format!("result:{}", children[2]), json!("c"));
+        store(&redis, format!("result:{}", children[0]), json!("a"));
+        running(&redis, &children[1]);
+        assert_eq!(
+            aggregate_children(&mut redis, OnFailure::Fail, &children).await,
+            None
+        );
+
+        store(&redis, format!("result:{}", children[1]), json!({"b": 1}));
+        let (status, result) = aggregate_children(&mut redis, OnFailure::Fail, &children)
+            .await
+            .unwrap();
+        assert_eq!(status, "completed");
+        assert_eq!(
+            result,
+            json!({"results": ["a", {"b": 1}, "c"], "errors": [], "completed": 3, "failed": 0})
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_policy_fails_on_the_first_failed_item() {
+        let mut redis = FakeRedis::new();
+        let children = children();
+        running(&redis, &children[0]);
+        store(
+            &redis,
+            failures::error_key(&children[1]),
+            json!({"error": "boom"}),
+        );
+        running(&redis, &children[2]);
+
+        let (status, report) = aggregate_children(&mut redis, OnFailure::Fail, &children)
+            .await
+            .unwrap();
+        assert_eq!(status, "failed");
+        assert_eq!(report["code"], "map_failed");
+        assert_eq!(report["error"], "Item 1 failed: boom");
+        assert_eq!(report["index"], 1);
+        assert_eq!(report["task_id"], "m.1");
+    }
+
+    #[tokio::test]
+    async fn partial_policy_reports_failed_items() {
+        let mut redis = FakeRedis::new();
+        let children = children();
+        store(&redis, format!("result:{}", children[0]), json!("a"));
+        store(
+            &redis,
+            failures::error_key(&children[1]),
+            json!({"error": "boom"}),
+        );
+        running(&redis, &children[2]);
+        // Partial maps wait for every item
+        assert_eq!(
+            aggregate_children(&mut redis, OnFailure::Partial, &children).await,
+            None
+        );
+
+        // children[2] was deleted before it ran
+        redis
+            .del::<_, ()>(format!("task:{}", children[2]))
+            .await
+            .unwrap();
+        let (status, result) = aggregate_children(&mut redis, OnFailure::Partial, &children)
+            .await
+            .unwrap();
+        assert_eq!(status, "completed");
+        assert_eq!(result["results"], json!(["a", null, null]));
+        assert_eq!(result["completed"], 1);
+        assert_eq!(result["failed"], 2);
+        assert_eq!(
+            result["errors"],
+            json!([
+                {"index": 1, "task_id": "m.1", "error": "boom"},
+                {"index": 2, "task_id": "m.2", "error": "task m.2 is gone"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_policy_fails_when_every_item_failed() {
+        let mut redis = FakeRedis::new();
+        let children = children();
+        for child in &children {
+            store(&redis, failures::error_key(child), json!({"error": "boom"}));
+        }
+        let (status, report) = aggregate_children(&mut redis, OnFailure::Partial, &children)
+            .await
+            .unwrap();
+        assert_eq!(status, "failed");
+        assert_eq!(report["code"], "map_failed");
+        assert_eq!(report["error"], "Every item failed");
+        assert_eq!(report["failed"], 3);
+        assert_eq!(report["errors"].as_array().unwrap().len(), 3);
+    }
+}
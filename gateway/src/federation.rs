@@ -0,0 +1,601 @@
+//! Gateway-to-gateway federation.
+//!
+//! A task whose effective config carries a `route` naming a configured peer is
+//! forwarded to that peer's `/task` endpoint instead of the local agent queue.
+//! Forwarded submissions are signed with a shared HMAC secret, and a relay loop
+//! copies the peer's result or error report back into local Redis so
+//! `GET /task/:id` and the channel adaptors see it exactly as if the task had
+//! run locally.
+//!
+//! Every request to a peer carries `x-claw-peer`, `x-claw-timestamp`,
+//! `x-claw-nonce` and `x-claw-signature`, the hex HMAC-SHA256 of
+//! `{timestamp}.{nonce}.{from}.{to}.{METHOD}.{path and query}.{body}`, where
+//! `from` and `to` are the `federation.name` of sender and receiver; peers
+//! must be configured under those names. The nonce is fresh random hex for
+//! every request, so two requests signed within the same second still differ.
+//! Each signature is accepted once, remembered in `signature:seen:{signature}`
+//! until it leaves the replay window.
+//!
+//! Peers refusing a forwarded task with a 4xx answer are not asked again;
+//! only unreachable peers, rate limits and server errors are retried. The
+//! relay stops tracking a task once the peer reports any final status or no
+//! longer knows the task; ends other than `completed` fail it locally.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use redis::{AsyncCommands, Client};
+use ring::hmac;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+
+use crate::config::FederationSettings;
+use crate::error::ApiError;
+use crate::request_id::{self, REQUEST_ID_HEADER};
+use crate::retry::{RetryDecision, RetryPolicy};
+use crate::task_state::{self, TransitionError};
+use crate::AppState;
+use crate::{envelope, failures};
+
+/// Header naming the gateway that signed a federated request
+pub const PEER_HEADER: &str = "x-claw-peer";
+/// Header carrying the unix timestamp the signature was computed at
+pub const TIMESTAMP_HEADER: &str = "x-claw-timestamp";
+/// Header carrying the random nonce making each signature unique
+pub const NONCE_HEADER: &str = "x-claw-nonce";
+/// Header carrying the hex-encoded HMAC-SHA256 signature
+pub const SIGNATURE_HEADER: &str = "x-claw-signature";
+
+/// Redis hash mapping forwarded task ids to the peer handling them
+pub const FORWARDED_KEY: &str = "federation:forwarded";
+
+/// Maximum accepted clock skew for signed requests, in seconds
+const MAX_SKEW_SECS: i64 = 300;
+
+/// Largest federated body we are willing to buffer for verification
+const MAX_SIGNED_BODY: usize = 10 * 1024 * 1024;
+
+/// Interval between relay sweeps over forwarded tasks
+const RELAY_INTERVAL_SECS: u64 = 5;
+
+/// A remote gateway that tasks can be routed to
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub name: String,
+    pub base_url: String,
+}
+
+/// Identity of the peer a verified federated request came from
+#[derive(Debug, Clone)]
+pub struct PeerOrigin(pub String);
+
+/// A forwarded task the peer answered with an error status
+#[derive(Debug)]
+struct PeerRefused {
+    peer: String,
+    status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for PeerRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer {} rejected task: {}", self.peer, self.status)
+    }
+}
+
+impl std::error::Error for PeerRefused {}
+
+/// Federation settings and the HTTP client used to talk to peers
+#[derive(Clone)]
+pub struct Federation {
+    node_name: String,
+    key: Option<hmac::Key>,
+    peers: Arc<HashMap<String, Peer>>,
+    http: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl Federation {
+    /// Build the federation client from validated settings.
+    ///
+    /// `secret` is the shared signing secret and `name` identifies this
+    /// gateway to its peers.
+    pub fn from_config(settings: &FederationSettings, retry: RetryPolicy) -> anyhow::Result<Self> {
+        let peers = settings
+            .peers
+            .iter()
+            .map(|(name, url)| {
+                let peer = Peer {
+                    name: name.clone(),
+                    base_url: url.trim_end_matches('/').to_string(),
+                };
+                (name.clone(), peer)
+            })
+            .collect();
+
+        let http = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()?;
+
+        Ok(Self {
+            node_name: settings.name.clone(),
+            key: settings
+                .secret
+                .as_ref()
+                .map(|s| hmac::Key::new(hmac::HMAC_SHA256, s.as_bytes())),
+            peers: Arc::new(peers),
+            http,
+            retry,
+        })
+    }
+
+    /// Whether any peers are configured
+    pub fn is_enabled(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    /// Resolve the peer a task should be forwarded to, if any
+    pub fn route_for(&self, config: &serde_json::Value) -> Option<&Peer> {
+        config
+            .get("route")
+            .and_then(|r| r.as_str())
+            .and_then(|name| self.peers.get(name))
+    }
+
+    /// Sign a request to `peer` now under a fresh nonce, returning the
+    /// timestamp, nonce and hex signature headers
+    fn sign(
+        &self,
+        peer: &Peer,
+        method: &str,
+        url: &str,
+        body: &[u8],
+    ) -> anyhow::Result<[(&'static str, String); 3]> {
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("federation secret not configured"))?;
+        let url = reqwest::Url::parse(url)?;
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let timestamp = chrono::Utc::now().timestamp();
+        let nonce = hex::encode(rand::random::<[u8; 16]>());
+        let signed = Signed {
+            timestamp,
+            nonce: &nonce,
+            from: &self.node_name,
+            to: &peer.name,
+        };
+        let signature = hmac::sign(key, &signed.message(method, &path, body));
+        Ok([
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (NONCE_HEADER, nonce),
+            (SIGNATURE_HEADER, hex::encode(signature.as_ref())),
+        ])
+    }
+
+    /// Verify a signature produced by [`Federation::sign`] on a request
+    /// from `from` to this gateway
+    fn verify(
+        &self,
+        from: &str,
+        (timestamp, nonce): (i64, &str),
+        method: &str,
+        path: &str,
+        body: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        let Some(key) = self.key.as_ref() else {
+            return false;
+        };
+        let signed = Signed {
+            timestamp,
+            nonce,
+            from,
+            to: &self.node_name,
+        };
+        hmac::verify(key, &signed.message(method, path, body), signature).is_ok()
+    }
+
+    /// Forward the `/task` body `submission` for `task_id` to `peer`,
+    /// recording it for result relay
+    pub async fn forward(
+        &self,
+        redis_client: &Client,
+        peer: &Peer,
+        task_id: &str,
+        submission: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(submission)?;
+        let request_id = request_id::current();
+
+        // Each attempt is signed afresh under a new nonce so retries stay
+        // inside the replay window and are not taken for replays
+        let url = format!("{}/task", peer.base_url);
+        self.retry
+            .run_classified(
+                &format!("Forward to peer {}", peer.name),
+                || async {
+                    let mut request = self
+                        .http
+                        .post(&url)
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .header(PEER_HEADER, &self.node_name);
+                    for (name, value) in self.sign(peer, "POST", &url, &body)? {
+                        request = request.header(name, value);
+                    }
+                    // Peers adopt our request ID so the task keeps one ID end to end
+                    if let Some(id) = &request_id {
+                        request = request.header(REQUEST_ID_HEADER.as_str(), id.as_str());
+                    }
+                    let response = request.body(body.clone()).send().await?;
+
+                    if !response.status().is_success() {
+                        return Err(PeerRefused {
+                            peer: peer.name.clone(),
+                            status: response.status(),
+                        }
+                        .into());
+                    }
+                    Ok(())
+                },
+                classify_error,
+            )
+            .await?;
+
+        let mut conn = redis_client.get_async_connection().await?;
+        conn.hset::<_, _, _, ()>(FORWARDED_KEY, task_id, &peer.name)
+            .await?;
+
+        info!("Task {} forwarded to peer {}", task_id, peer.name);
+        Ok(())
+    }
+
+    /// Poll peers for forwarded tasks and copy finished results back locally
+    async fn relay_once(&self, redis_client: &Client) -> anyhow::Result<()> {
+        let mut conn = redis_client.get_async_connection().await?;
+        let forwarded: HashMap<String, String> = conn.hgetall(FORWARDED_KEY).await?;
+
+        for (task_id, peer_name) in forwarded {
+            let Some(peer) = self.peers.get(&peer_name) else {
+                warn!("Task {} forwarded to unknown peer {}", task_id, peer_name);
+                continue;
+            };
+
+            let url = format!("{}/task/{}", peer.base_url, task_id);
+            let mut request = self.http.get(&url).header(PEER_HEADER, &self.node_name);
+            for (name, value) in self.sign(peer, "GET", &url, &[])? {
+                request = request.header(name, value);
+            }
+            let response = match request.send().await {
+                Ok(r) => r,
+                Err(e) => {
+                    debug!("Peer {} unreachable: {}", peer.name, e);
+                    continue;
+                }
+            };
+            let body = if response.status() == reqwest::StatusCode::NOT_FOUND {
+                None
+            } else if !response.status().is_success() {
+                debug!(
+                    "Peer {} returned {} for task {}",
+                    peer.name,
+                    response.status(),
+                    task_id
+                );
+                continue;
+            } else {
+                match response.json::<serde_json::Value>().await {
+                    Ok(body) => Some(body),
+                    Err(e) => {
+                        warn!(
+                            "Peer {} sent an unreadable answer for task {}: {}",
+                            peer.name, task_id, e
+                        );
+                        continue;
+                    }
+                }
+            };
+            let Some((status, result)) = peer_outcome(&peer.name, body.as_ref()) else {
+                continue;
+            };
+            let key = match status {
+                "completed" => format!("result:{}", task_id),
+                _ => failures::error_key(&task_id),
+            };
+
+            // A task deleted here in the meantime keeps its status
+            match task_state::transition(&mut conn, &task_id, status, |_| true).await {
+                Ok(_) => {
+                    conn.set::<_, _, ()>(key, envelope::encode(&result)?)
+                        .await?;
+                    info!(
+                        "Relayed {} task {} from peer {}",
+                        status, task_id, peer.name
+                    );
+                }
+                Err(TransitionError::Illegal { from, .. }) => {
+                    warn!("Dropped peer result for {} task {}", from, task_id);
+                }
+                Err(TransitionError::NotFound) => {
+                    warn!("Dropped peer result for vanished task {}", task_id);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            conn.hdel::<_, _, ()>(FORWARDED_KEY, &task_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Retry unreachable peers, rate limits and server errors; a peer refusing
+/// the task with any other status will refuse it again
+fn classify_error(e: &anyhow::Error) -> RetryDecision {
+    match e.downcast_ref::<PeerRefused>() {
+        None => RetryDecision::Backoff,
+        Some(refused)
+            if refused.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                || refused.status.is_server_error() =>
+        {
+            RetryDecision::Backoff
+        }
+        Some(_) => RetryDecision::Stop,
+    }
+}
+
+/// How a forwarded task settled, judging by the peer's `GET /task/:id`
+/// answer (`None` when the peer no longer knows it): the local status and
+/// the result or error report to store, or `None` while it still runs
+fn peer_outcome(
+    peer: &str,
+    body: Option<&serde_json::Value>,
+) -> Option<(&'static str, serde_json::Value)> {
+    let Some(body) = body else {
+        return Some((
+            "failed",
+            serde_json::json!({
+                "error": format!("Peer {} no longer knows the task", peer),
+                "code": "peer_task_gone",
+            }),
+        ));
+    };
+    match body["status"].as_str().unwrap_or("unknown") {
+        "completed" => Some(("completed", body["result"].clone())),
+        // The peer answers failures with the error report as result
+        "failed" => Some(("failed", body["result"].clone())),
+        status if failures::FAILED_STATUSES.contains(&status) => Some((
+            "failed",
+            serde_json::json!({
+                "error": format!("Task ended as {} on peer {}", status, peer),
+                "code": format!("peer_{}", status),
+            }),
+        )),
+        _ => None,
+    }
+}
+
+/// What a federation signature covers besides the request itself
+struct Signed<'a> {
+    timestamp: i64,
+    nonce: &'a str,
+    from: &'a str,
+    to: &'a str,
+}
+
+impl Signed<'_> {
+    /// The bytes the signature is computed over
+    fn message(&self, method: &str, path: &str, body: &[u8]) -> Vec<u8> {
+        let mut msg = format!(
+            "{}.{}.{}.{}.{}.{}.",
+            self.timestamp, self.nonce, self.from, self.to, method, path
+        )
+        .into_bytes();
+        msg.extend_from_slice(body);
+        msg
+    }
+}
+
+/// Middleware verifying signed requests from peer gateways.
+///
+/// Requests without a peer header pass through untouched; signed requests are
+/// buffered, checked against the shared secret, the replay window and the
+/// signatures already seen, and tagged with a [`PeerOrigin`] extension so
+/// they are never forwarded again.
+pub async fn verify_peer_signature(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(peer) = req.headers().get(PEER_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+    let peer = peer
+        .to_str()
+        .map_err(|_| ApiError::bad_request("invalid_peer", "Peer header is not valid text"))?
+        .to_string();
+
+    let timestamp: i64 = req
+        .headers()
+        .get(TIMESTAMP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| ApiError::unauthorized("Missing or invalid signature timestamp"))?;
+    let nonce = req
+        .headers()
+        .get(NONCE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| ApiError::unauthorized("Missing or invalid signature nonce"))?;
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| hex::decode(v).ok())
+        .ok_or_else(|| ApiError::unauthorized("Missing or invalid peer signature"))?;
+
+    let now = chrono::Utc::now().timestamp();
+    if (now - timestamp).abs() > MAX_SKEW_SECS {
+        warn!("Rejected federated request from {}: stale timestamp", peer);
+        return Err(ApiError::unauthorized("Signature timestamp outside replay window"));
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_SIGNED_BODY).await.map_err(|_| {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "Federated request body too large",
+        )
+    })?;
+
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let method = parts.method.as_str();
+    if !state
+        .federation
+        .verify(&peer, (timestamp, &nonce), method, path, &bytes, &signature)
+    {
+        warn!("Rejected federated request from {}: bad signature", peer);
+        return Err(ApiError::unauthorized("Invalid peer signature"));
+    }
+
+    // Remember the signature until its timestamp leaves the window
+    let mut conn = state.redis_client.get_async_connection().await?;
+    let remaining = (timestamp + MAX_SKEW_SECS - now).max(1);
+    let first_use: bool = redis::cmd("SET")
+        .arg(format!("signature:seen:{}", hex::encode(&signature)))
+        .arg(&peer)
+        .arg("NX")
+        .arg("EX")
+        .arg(remaining)
+        .query_async::<_, Option<String>>(&mut conn)
+        .await?
+        .is_some();
+    if !first_use {
+        warn!("Rejected federated request from {}: replayed signature", peer);
+        return Err(ApiError::unauthorized("Peer signature already used"));
+    }
+
+    parts.extensions.insert(PeerOrigin(peer));
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+/// Start the result relay loop in a background task
+pub fn start_relay(redis_client: Arc<Client>, federation: Federation) {
+    tokio::spawn(async move {
+        info!("Federation relay started");
+        loop {
+            if let Err(e) = federation.relay_once(&redis_client).await {
+                error!("Federation relay error: {}", e);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(RELAY_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn federation(name: &str, peer: &str) -> Federation {
+        let settings = FederationSettings {
+            name: name.to_string(),
+            secret: Some("shared".to_string()),
+            peers: [(peer.to_string(), format!("http://{}.example", peer))].into(),
+        };
+        Federation::from_config(&settings, RetryPolicy::default()).unwrap()
+    }
+
+    fn headers(signed: [(&'static str, String); 3]) -> HashMap<&'static str, String> {
+        signed.into_iter().collect()
+    }
+
+    #[test]
+    fn signatures_differ_within_the_same_second() {
+        let east = federation("east", "west");
+        let peer = east.peers["west"].clone();
+        let first = headers(east.sign(&peer, "POST", "http://west.example/task", b"{}").unwrap());
+        let second = headers(east.sign(&peer, "POST", "http://west.example/task", b"{}").unwrap());
+        assert_ne!(first[NONCE_HEADER], second[NONCE_HEADER]);
+        assert_ne!(first[SIGNATURE_HEADER], second[SIGNATURE_HEADER]);
+    }
+
+    #[test]
+    fn peer_verifies_the_nonce_it_was_signed_with() {
+        let east = federation("east", "west");
+        let west = federation("west", "east");
+        let peer = east.peers["west"].clone();
+        let signed = headers(east.sign(&peer, "POST", "http://west.example/task", b"{}").unwrap());
+        let timestamp: i64 = signed[TIMESTAMP_HEADER].parse().unwrap();
+        let signature = hex::decode(&signed[SIGNATURE_HEADER]).unwrap();
+        let nonce = signed[NONCE_HEADER].as_str();
+
+        assert!(west.verify("east", (timestamp, nonce), "POST", "/task", b"{}", &signature));
+        assert!(!west.verify("east", (timestamp, "other"), "POST", "/task", b"{}", &signature));
+        assert!(!west.verify("east", (timestamp, nonce), "POST", "/task", b"[]", &signature));
+        assert!(!west.verify("north", (timestamp, nonce), "POST", "/task", b"{}", &signature));
+    }
+
+    fn refused(status: u16) -> anyhow::Error {
+        PeerRefused {
+            peer: "west".to_string(),
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        assert_eq!(classify_error(&refused(400)), RetryDecision::Stop);
+        assert_eq!(classify_error(&refused(401)), RetryDecision::Stop);
+        assert_eq!(classify_error(&refused(422)), RetryDecision::Stop);
+        assert_eq!(classify_error(&refused(429)), RetryDecision::Backoff);
+        assert_eq!(classify_error(&refused(503)), RetryDecision::Backoff);
+        // Connection failures are worth another try
+        let unreachable = anyhow::anyhow!("connection refused");
+        assert_eq!(classify_error(&unreachable), RetryDecision::Backoff);
+    }
+
+    #[test]
+    fn running_tasks_stay_tracked() {
+        for status in ["pending", "processing", "waiting"] {
+            assert!(peer_outcome("west", Some(&json!({ "status": status }))).is_none());
+        }
+    }
+
+    #[test]
+    fn settled_tasks_are_relayed() {
+        let completed = json!({ "status": "completed", "result": { "answer": 42 } });
+        assert_eq!(
+            peer_outcome("west", Some(&completed)),
+            Some(("completed", json!({ "answer": 42 })))
+        );
+        let failed = json!({ "status": "failed", "result": { "error": "boom" } });
+        assert_eq!(
+            peer_outcome("west", Some(&failed)),
+            Some(("failed", json!({ "error": "boom" })))
+        );
+    }
+
+    #[test]
+    fn every_other_end_fails_the_task_locally() {
+        for status in ["timed_out", "orphaned", "deleted"] {
+            let (local, report) = peer_outcome("west", Some(&json!({ "status": status }))).unwrap();
+            assert_eq!(local, "failed");
+            assert_eq!(report["code"], format!("peer_{}", status));
+        }
+        let (local, report) = peer_outcome("west", None).unwrap();
+        assert_eq!(local, "failed");
+        assert_eq!(report["code"], "peer_task_gone");
+    }
+}
@@ -0,0 +1,373 @@
+//! Feedback on task results.
+//!
+//! Whoever got a result can rate it: over HTTP with `POST /task/:id/feedback`
+//! and `{"rating": "up"|"down", "comment": "..."}`, or with the 👍/👎
+//! buttons under a Telegram reply. Only completed tasks can be rated. Each
+//! rater has one rating per task, a later one replacing theirs, kept in the
+//! Redis hash `feedback:{task_id}` by rater and stored like task records;
+//! `GET /task/:id/feedback` lists them. Over HTTP rating needs an API key
+//! and the same access as the task's annotations.
+//!
+//! Ratings are also counted in `feedback:totals` by the version of the agent
+//! that ran the task, as it registered, and by the default config version
+//! live when the task was submitted, so `GET /admin/feedback` shows how each
+//! version fares. The totals outlive purged tasks. A rating is stored and
+//! counted under `WATCH` on the task's hash, so when one rater's ratings race
+//! the replaced rating is taken out of the totals exactly once.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+use crate::annotations::check_access;
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::validation::ValidationError;
+use crate::{agent_config, agent_registry, envelope, redis_connection, schema, AppState};
+
+/// Redis hash counting ratings by version
+const TOTALS_KEY: &str = "feedback:totals";
+
+/// Agent version of tasks whose agent did not register one
+const UNKNOWN_VERSION: &str = "unknown";
+
+/// Redis hash of a task's ratings, by rater
+pub fn feedback_key(task_id: &str) -> String {
+    format!("feedback:{}", task_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rating::Up => "up",
+            Rating::Down => "down",
+        }
+    }
+
+    pub fn parse(rating: &str) -> Option<Self> {
+        match rating {
+            "up" => Some(Rating::Up),
+            "down" => Some(Rating::Down),
+            _ => None,
+        }
+    }
+}
+
+/// Body of `POST /task/:id/feedback`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedbackRequest {
+    rating: Rating,
+    comment: Option<String>,
+}
+
+/// A stored rating
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Feedback {
+    pub rating: Rating,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Key id of the rater, or `telegram:{user id}`
+    pub rated_by: String,
+    /// `http` or `telegram`
+    pub channel: String,
+    pub rated_at: String,
+    /// Version of the agent that ran the task, `unknown` if it gave none
+    pub agent_version: String,
+    /// Default config version live when the task was submitted, 0 if none
+    /// was published
+    pub config_version: u64,
+}
+
+impl Feedback {
+    /// Fields of `feedback:totals` this rating is counted under
+    fn totals_fields(&self) -> [String; 2] {
+        let rating = self.rating.as_str();
+        [
+            format!("agent:{}:{}", self.agent_version, rating),
+            format!("config:{}:{}", self.config_version, rating),
+        ]
+    }
+}
+
+/// The record of `task_id`, if it has a result to rate
+pub async fn rateable_task(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+) -> Result<Value, ApiError> {
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    let Some(task) = task.map(|t| schema::decode_task(&t)).transpose()? else {
+        return Err(ApiError::not_found(format!("Task {} not found", task_id)));
+    };
+    if task["status"] != "completed" {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "not_completed",
+            format!("Task {} has no result to rate", task_id),
+        ));
+    }
+    Ok(task)
+}
+
+/// Record `rated_by`'s rating of `task`, replacing theirs if they rated it
+/// before, and count it in the totals
+pub async fn rate(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    task: &Value,
+    (rated_by, channel): (&str, &str),
+    rating: Rating,
+    comment: Option<String>,
+) -> Result<Feedback, ApiError> {
+    let agent_version = match (task["agent_version"].as_str(), task["agent_id"].as_str()) {
+        (Some(version), _) => Some(version.to_string()),
+        (None, Some(agent_id)) => agent_registry::agent_version(conn, agent_id).await?,
+        (None, None) => None,
+    };
+    let submitted = task["created_at"]
+        .as_str()
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok());
+    let config_version = match submitted {
+        Some(at) => agent_config::version_at(conn, at.with_timezone(&Utc)).await?,
+        None => 0,
+    };
+    let feedback = Feedback {
+        rating,
+        comment,
+        rated_by: rated_by.to_string(),
+        channel: channel.to_string(),
+        rated_at: Utc::now().to_rfc3339(),
+        agent_version: agent_version.unwrap_or_else(|| UNKNOWN_VERSION.to_string()),
+        config_version,
+    };
+
+    record(conn, task_id, &feedback).await?;
+    info!(
+        "Task {} rated {} by {}",
+        task_id,
+        rating.as_str(),
+        feedback.rated_by
+    );
+    Ok(feedback)
+}
+
+/// Store `feedback` as its rater's rating of `task_id` and move the totals
+/// from their earlier rating to it, retrying if the hash changes meanwhile
+async fn record<C: redis::aio::ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+    feedback: &Feedback,
+) -> Result<(), ApiError> {
+    let key = feedback_key(task_id);
+    let entry = envelope::encode(&serde_json::to_value(feedback)?)?;
+    loop {
+        redis::cmd("WATCH")
+            .arg(&key)
+            .query_async::<_, ()>(conn)
+            .await?;
+        let earlier: Option<String> = conn.hget(&key, &feedback.rated_by).await?;
+        let earlier = earlier
+            .map(|e| -> Result<Feedback, ApiError> {
+                Ok(serde_json::from_value(envelope::decode(&e)?)?)
+            })
+            .transpose();
+        let earlier = match earlier {
+            Ok(earlier) => earlier,
+            Err(e) => {
+                redis::cmd("UNWATCH").query_async::<_, ()>(conn).await?;
+                return Err(e);
+            }
+        };
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(&key, &feedback.rated_by, &entry)
+            .ignore();
+        for field in earlier.iter().flat_map(Feedback::totals_fields) {
+            pipe.hincr(TOTALS_KEY, field, -1).ignore();
+        }
+        for field in feedback.totals_fields() {
+            pipe.hincr(TOTALS_KEY, field, 1).ignore();
+        }
+        // EXEC answers nil when the watched hash changed
+        let applied: Option<()> = pipe.query_async(conn).await?;
+        if applied.is_some() {
+            return Ok(());
+        }
+    }
+}
+
+// Rate a task's result
+pub async fn add_feedback(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<FeedbackRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<Feedback>), ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let Json(req) = body.map_err(ValidationError::from)?;
+    let max_chars = state.config.current().limits.max_annotation_chars;
+    let comment = req.comment.as_deref().map(str::trim).filter(|c| !c.is_empty());
+    if comment.is_some_and(|c| c.chars().count() > max_chars) {
+        return Err(ApiError::bad_request(
+            "invalid_feedback",
+            format!("comment must be at most {} characters", max_chars),
+        ));
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    check_access(&mut conn, Some(&principal), &task_id).await?;
+    let task = rateable_task(&mut conn, &task_id).await?;
+    let rater = (principal.key_id.as_str(), "http");
+    let comment = comment.map(str::to_string);
+    let feedback = rate(&mut conn, &task_id, &task, rater, req.rating, comment).await?;
+    Ok((StatusCode::CREATED, Json(feedback)))
+}
+
+// List a task's ratings
+pub async fn list_feedback(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    check_access(&mut conn, principal, &task_id).await?;
+    let entries: HashMap<String, String> = conn.hgetall(feedback_key(&task_id)).await?;
+    let mut feedback = entries
+        .values()
+        .map(|entry| Ok(serde_json::from_value(envelope::decode(entry)?)?))
+        .collect::<Result<Vec<Feedback>, ApiError>>()?;
+    feedback.sort_by(|a, b| a.rated_at.cmp(&b.rated_at));
+    let count = |rating| feedback.iter().filter(|f| f.rating == rating).count();
+    Ok(Json(json!({
+        "task_id": task_id,
+        "up": count(Rating::Up),
+        "down": count(Rating::Down),
+        "feedback": feedback,
+    })))
+}
+
+// Ratings by agent and config version (admin only)
+pub async fn feedback_totals(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let totals: HashMap<String, i64> = conn.hgetall(TOTALS_KEY).await?;
+    Ok(Json(summarize(&totals)))
+}
+
+/// Up and down counts and the share of ups, overall and by version
+fn summarize(totals: &HashMap<String, i64>) -> Value {
+    let mut by: BTreeMap<&str, BTreeMap<&str, (i64, i64)>> = BTreeMap::new();
+    for (field, count) in totals {
+        let Some((dimension, rest)) = field.split_once(':') else {
+            continue;
+        };
+        let Some((version, rating)) = rest.rsplit_once(':') else {
+            continue;
+        };
+        let tally = by.entry(dimension).or_default().entry(version).or_default();
+        match Rating::parse(rating) {
+            Some(Rating::Up) => tally.0 += count,
+            Some(Rating::Down) => tally.1 += count,
+            None => {}
+        }
+    }
+
+    let show = |(up, down): (i64, i64)| {
+        let rated = up + down;
+        let score = (rated > 0).then(|| up as f64 / rated as f64);
+        json!({"up": up, "down": down, "score": score})
+    };
+    let versions = |dimension: &str| -> BTreeMap<&str, Value> {
+        let tallies = by.get(dimension).into_iter().flatten();
+        tallies.map(|(version, tally)| (*version, show(*tally))).collect()
+    };
+    // Every rating is counted once under an agent version
+    let total = by.get("agent").into_iter().flatten().fold((0, 0), |sum, (_, tally)| {
+        (sum.0 + tally.0, sum.1 + tally.1)
+    });
+    json!({
+        "total": show(total),
+        "agent_versions": versions("agent"),
+        "config_versions": versions("config"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+
+    #[test]
+    fn totals_are_summarized_by_version() {
+        let feedback = Feedback {
+            rating: Rating::Down,
+            comment: None,
+            rated_by: "telegram:42".to_string(),
+            channel: "telegram".to_string(),
+            rated_at: "2026-10-14T15:20:00+00:00".to_string(),
+            agent_version: "1.4.0:rc1".to_string(),
+            config_version: 7,
+        };
+        let mut totals: HashMap<String, i64> = feedback
+            .totals_fields()
+            .into_iter()
+            .map(|field| (field, 1))
+            .collect();
+        totals.insert("agent:1.3.2:up".to_string(), 3);
+        totals.insert("config:7:up".to_string(), 3);
+
+        let summary = summarize(&totals);
+        assert_eq!(summary["total"], json!({"up": 3, "down": 1, "score": 0.75}));
+        assert_eq!(
+            summary["agent_versions"]["1.4.0:rc1"],
+            json!({"up": 0, "down": 1, "score": 0.0})
+        );
+        assert_eq!(summary["config_versions"]["7"]["up"], 3);
+        assert_eq!(Rating::parse("sideways"), None);
+    }
+
+    #[tokio::test]
+    async fn replaced_ratings_leave_the_totals() {
+        let mut redis = FakeRedis::new();
+        let mut feedback = Feedback {
+            rating: Rating::Up,
+            comment: None,
+            rated_by: "telegram:42".to_string(),
+            channel: "telegram".to_string(),
+            rated_at: "2026-10-14T15:20:00+00:00".to_string(),
+            agent_version: "1.4.0".to_string(),
+            config_version: 7,
+        };
+        record(&mut redis, "t1", &feedback).await.unwrap();
+        feedback.rating = Rating::Down;
+        feedback.comment = Some("wrong file".to_string());
+        record(&mut redis, "t1", &feedback).await.unwrap();
+
+        let total = |field: &str| redis.hget(TOTALS_KEY, field);
+        assert_eq!(total("agent:1.4.0:up").as_deref(), Some("0"));
+        assert_eq!(total("config:7:up").as_deref(), Some("0"));
+        assert_eq!(total("agent:1.4.0:down").as_deref(), Some("1"));
+        assert_eq!(total("config:7:down").as_deref(), Some("1"));
+        let entry = redis.hget("feedback:t1", "telegram:42").unwrap();
+        let stored: Feedback = serde_json::from_value(envelope::decode(&entry).unwrap()).unwrap();
+        assert_eq!(stored.rating, Rating::Down);
+        assert_eq!(stored.comment.as_deref(), Some("wrong file"));
+    }
+}
@@ -0,0 +1,345 @@
+//! gRPC API.
+//!
+//! With `grpc.port` set, the `claw.gateway.v1.Gateway` service defined in
+//! `proto/gateway.proto` listens on that port beside the REST API.
+//! `SubmitTask` and `GetTask` run the same code as `POST /task` and
+//! `GET /task/:id`, including validation, admission, caching and routing.
+//! `WatchTask` streams a task's state whenever its status changes, polling
+//! every `grpc.watch_interval_ms`, and ends once the task settles. Clients
+//! are held to the `ip_filter` rules of the REST API, judged by the
+//! connection's peer and `x-forwarded-for` metadata from trusted proxies.
+//!
+//! Callers authenticate as over REST, with `authorization: Bearer` metadata
+//! or HMAC signing metadata (see [`signing`]) whose signature covers
+//! `{timestamp}.{nonce}.POST.{/claw.gateway.v1.Gateway/Method}.{encoded request}`.
+//! Messages are capped at `limits.max_body_bytes`. Tasks submitted with an
+//! API key can only be read and watched by that key, agents, operators,
+//! viewers and admins, and viewers may not submit, as with `POST /task`.
+//! The listener is plaintext; terminate TLS in front of it.
+
+use axum::{
+    extract::Path,
+    extract::State,
+    http::{HeaderMap, Method, StatusCode},
+    Extension,
+};
+use futures_util::Stream;
+use prost::Message;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::service::{interceptor::InterceptedService, Interceptor};
+use tonic::{Code, Request, Response, Status};
+use tracing::{error, info, warn};
+
+use crate::auth::Principal;
+use crate::cache::CacheControl;
+use crate::error::ApiError;
+use crate::ip_filter::IpFilter;
+use crate::signing::{self, KEY_ID_HEADER};
+use crate::{
+    annotations, auth, enqueue_task, get_result, history, redis_connection, request_id,
+    validation, AgentRequest, AgentResponse, AppState,
+};
+
+mod pb {
+    tonic::include_proto!("claw.gateway.v1");
+}
+
+use pb::gateway_server::{Gateway, GatewayServer};
+use pb::{GetTaskRequest, SubmitTaskRequest, TaskReply};
+
+impl From<ApiError> for Status {
+    fn from(e: ApiError) -> Self {
+        let code = match e.status {
+            StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => Code::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::CONFLICT => Code::AlreadyExists,
+            StatusCode::UNPROCESSABLE_ENTITY => Code::FailedPrecondition,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            _ => Code::Internal,
+        };
+        Status::new(code, format!("{}: {}", e.code, e.message))
+    }
+}
+
+impl From<AgentResponse> for TaskReply {
+    fn from(response: AgentResponse) -> Self {
+        TaskReply {
+            task_id: response.task_id,
+            status: response.status,
+            result_json: response.result.map(|r| r.to_string()),
+            error: response.error,
+            signing_key_id: response.signature.as_ref().map(|s| s.key_id.clone()),
+            signature: response.signature.map(|s| s.value),
+        }
+    }
+}
+
+/// Parse a JSON document sent as text in field `field`
+fn parse_json(field: &str, text: &str) -> Result<serde_json::Value, ApiError> {
+    serde_json::from_str(text).map_err(|e| {
+        ApiError::bad_request(
+            "invalid_json",
+            format!("{} is not valid JSON: {}", field, e),
+        )
+    })
+}
+
+/// The task `submission` asks for, as `POST /task` takes it
+fn agent_request(submission: SubmitTaskRequest) -> Result<AgentRequest, ApiError> {
+    Ok(AgentRequest {
+        task_id: submission.task_id,
+        input: parse_json("input_json", &submission.input_json)?,
+        config: submission
+            .config_json
+            .map(|c| parse_json("config_json", &c))
+            .transpose()?,
+        capability: submission.capability,
+        timeout_seconds: submission.timeout_seconds,
+        labels: submission.labels.into_iter().collect(),
+        depends_on: submission.depends_on,
+        map: None,
+        parent_task_id: None,
+    })
+}
+
+/// `principal`, if the route policy of the REST call matching `method`
+/// admits it
+fn authorized(principal: Principal, method: &str) -> Result<Principal, ApiError> {
+    let http_method = match method {
+        "SubmitTask" => Method::POST,
+        _ => Method::GET,
+    };
+    auth::authorize(&principal, &http_method, "/task")?;
+    Ok(principal)
+}
+
+type TaskStream = Pin<Box<dyn Stream<Item = Result<TaskReply, Status>> + Send>>;
+
+struct GatewayService {
+    state: AppState,
+}
+
+impl GatewayService {
+    /// The caller of `method`, from a bearer token or a signature over the
+    /// encoded `message`; `None` for anonymous callers
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        method: &str,
+        message: &impl Message,
+    ) -> Result<Option<Principal>, ApiError> {
+        if let Some(key_id) = headers.get(KEY_ID_HEADER) {
+            let key_id = key_id
+                .to_str()
+                .map_err(|_| {
+                    ApiError::bad_request("invalid_key_id", "Key id header is not valid text")
+                })?
+                .to_string();
+            let path = format!("{}/{}", SERVICE_PATH, method);
+            let body = message.encode_to_vec();
+            let principal =
+                signing::verify_signature(&self.state, key_id, headers, "POST", &path, &body)
+                    .await?;
+            return authorized(principal, method).map(Some);
+        }
+        match auth::bearer_token_from(headers) {
+            Some(token) => {
+                let principal = auth::resolve_token(&self.state, token)
+                    .await?
+                    .ok_or_else(|| ApiError::unauthorized("Unknown API key"))?;
+                authorized(principal, method).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Refuse callers that may not see `task_id`, as `GET /task/:id` does
+    async fn check_reader(
+        &self,
+        principal: Option<&Principal>,
+        task_id: &str,
+    ) -> Result<(), ApiError> {
+        let mut conn = redis_connection(&self.state).await?;
+        annotations::check_reader(&mut conn, principal, task_id).await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Gateway for GatewayService {
+    async fn submit_task(
+        &self,
+        request: Request<SubmitTaskRequest>,
+    ) -> Result<Response<TaskReply>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let submission = request.into_inner();
+        let principal = self
+            .authenticate(&headers, "SubmitTask", &submission)
+            .await?;
+
+        let req = agent_request(submission)?;
+        validation::validate_request(&req, &self.state.config.current().limits)
+            .map_err(ApiError::from)?;
+
+        let cache_control = CacheControl::from_headers(&headers);
+        let submitted = request_id::scope(
+            request_id::RequestId::from_headers(&headers),
+            enqueue_task(
+                self.state.clone(),
+                None,
+                principal.map(Extension),
+                cache_control,
+                req,
+            ),
+        )
+        .await?;
+        Ok(Response::new(submitted.response.into()))
+    }
+
+    async fn get_task(
+        &self,
+        request: Request<GetTaskRequest>,
+    ) -> Result<Response<TaskReply>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let lookup = request.into_inner();
+        let principal = self.authenticate(&headers, "GetTask", &lookup).await?;
+        self.check_reader(principal.as_ref(), &lookup.task_id)
+            .await?;
+        let task_id = lookup.task_id;
+        let response = get_result(State(self.state.clone()), Path(task_id)).await?;
+        Ok(Response::new(response.0.into()))
+    }
+
+    type WatchTaskStream = TaskStream;
+
+    async fn watch_task(
+        &self,
+        request: Request<GetTaskRequest>,
+    ) -> Result<Response<Self::WatchTaskStream>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let lookup = request.into_inner();
+        let principal = self.authenticate(&headers, "WatchTask", &lookup).await?;
+        self.check_reader(principal.as_ref(), &lookup.task_id)
+            .await?;
+        let task_id = lookup.task_id;
+        let interval = Duration::from_millis(self.state.config.current().grpc.watch_interval_ms);
+
+        // Each step yields the next status change; `None` ends the stream
+        let step = (self.state.clone(), task_id, None::<String>, false);
+        let stream =
+            futures_util::stream::unfold(step, move |(state, task_id, last, done)| async move {
+                if done {
+                    return None;
+                }
+                loop {
+                    let response = get_result(State(state.clone()), Path(task_id.clone())).await;
+                    let response = match response {
+                        Ok(response) => response.0,
+                        Err(e) => return Some((Err(e.into()), (state, task_id, last, true))),
+                    };
+                    if last.as_deref() != Some(response.status.as_str()) {
+                        let status = response.status.clone();
+                        let done = history::FINAL_STATUSES.contains(&status.as_str());
+                        let next = (state, task_id, Some(status), done);
+                        return Some((Ok(response.into()), next));
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Path the address rules see for every gRPC call
+const SERVICE_PATH: &str = "/claw.gateway.v1.Gateway";
+
+/// Interceptor refusing calls from clients the address rules exclude
+#[derive(Clone)]
+struct ClientFilter(Arc<IpFilter>);
+
+impl Interceptor for ClientFilter {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        let headers = request.metadata().clone().into_headers();
+        match self.0.permits(peer, &headers, SERVICE_PATH) {
+            Ok(_) => Ok(request),
+            Err(client) => {
+                match client {
+                    Some(client) => warn!("Refused gRPC call from client {}", client),
+                    None => warn!("Refused gRPC call from a client of unknown address"),
+                }
+                Err(Status::permission_denied("Client address not allowed"))
+            }
+        }
+    }
+}
+
+/// Serve the gRPC API on `addr` in a background task
+pub fn start_grpc_server(state: AppState, addr: SocketAddr, filter: Arc<IpFilter>) {
+    let max_message_bytes = state.config.current().limits.max_body_bytes;
+    let service =
+        GatewayServer::new(GatewayService { state }).max_decoding_message_size(max_message_bytes);
+    let service = InterceptedService::new(service, ClientFilter(filter));
+    tokio::spawn(async move {
+        info!("gRPC API listening on {}", addr);
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve(addr)
+            .await
+        {
+            error!("gRPC server failed: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::envelope;
+    use crate::queue::{NewTask, TaskQueue};
+    use claw_test::FakeRedis;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn resubmitted_ids_stay_with_their_owner() {
+        let mut redis = FakeRedis::new();
+        let owned = json!({"status": "completed", "submitted_by": "alice"});
+        redis.set("task:t1", &envelope::encode(&owned).unwrap());
+        let bob = Principal {
+            key_id: "bob".to_string(),
+            role: Role::Submitter,
+        };
+
+        // SubmitTask from another key reusing the id is refused...
+        let submission = SubmitTaskRequest {
+            task_id: "t1".to_string(),
+            input_json: r#""hi""#.to_string(),
+            ..Default::default()
+        };
+        let req = agent_request(submission).unwrap();
+        let record = json!({"input": req.input, "status": "pending", "submitted_by": bob.key_id});
+        let resubmission = NewTask {
+            task_id: &req.task_id,
+            record: envelope::encode(&record).unwrap(),
+            labels: &req.labels,
+            queue: Some(("agent:queue", chrono::Utc::now())),
+            tracked: true,
+        };
+        let refused = TaskQueue::Redis.submit(&mut redis, resubmission).await;
+        let refused = Status::from(ApiError::from(refused.unwrap_err()));
+        assert_eq!(refused.code(), Code::AlreadyExists);
+
+        // ...so GetTask still refuses it
+        let refused = annotations::check_reader(&mut redis, Some(&bob), "t1").await;
+        assert_eq!(Status::from(refused.unwrap_err()).code(), Code::PermissionDenied);
+        let stored = envelope::decode(&redis.get("task:t1").unwrap()).unwrap();
+        assert_eq!(stored["submitted_by"], "alice");
+    }
+}
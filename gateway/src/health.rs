@@ -0,0 +1,269 @@
+//! Detailed health.
+//!
+//! `GET /health/detailed` answers what `/health` does plus what an operator
+//! looks for during an incident: the Redis round trip, how many tasks wait
+//! in the agent queues and for how long the oldest has, the state of each
+//! Telegram bot, whether each channel adaptor is up, the latest
+//! [`canary`](crate::canary) and the build running. It needs an API key,
+//! unlike `/health`. The oldest task's age is known with the Redis queue
+//! backend only.
+//!
+//! `GET /version` answers with the build alone, to tell which release runs
+//! where: crate version, commit, build time and enabled features, all
+//! recorded by the build script. It is open like `/health`.
+
+use axum::{extract::State, Json};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::canary::CanaryRun;
+use crate::supervisor::AdaptorStatus;
+use crate::{backpressure, health_check, schema, telegram, AppState, HealthResponse};
+
+/// The build running
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// Unix seconds, served as RFC 3339
+    #[serde(serialize_with = "rfc3339")]
+    pub built_at: &'static str,
+    /// Comma separated, served as a list
+    #[serde(serialize_with = "list")]
+    pub features: &'static str,
+}
+
+pub const BUILD: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_sha: env!("BUILD_GIT_SHA"),
+    built_at: env!("BUILD_TIMESTAMP"),
+    features: env!("BUILD_FEATURES"),
+};
+
+fn rfc3339<S: Serializer>(secs: &&str, serializer: S) -> Result<S::Ok, S::Error> {
+    let built_at = secs
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0));
+    match built_at {
+        Some(built_at) => serializer.serialize_str(&built_at.to_rfc3339()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn list<S: Serializer>(features: &&str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(features.split(',').filter(|f| !f.is_empty()))
+}
+
+// What was built, for telling releases apart
+pub async fn version() -> Json<BuildInfo> {
+    Json(BUILD)
+}
+
+#[derive(Debug, Serialize)]
+pub struct DetailedHealthResponse {
+    #[serde(flatten)]
+    health: HealthResponse,
+    detail: Detail,
+    build: BuildInfo,
+}
+
+#[derive(Debug, Serialize)]
+struct Detail {
+    /// `normal`, or `simulate` while the gateway answers tasks itself
+    mode: &'static str,
+    redis: RedisDetail,
+    /// Absent while Redis cannot be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queues: Option<QueueDetail>,
+    /// Each Telegram bot, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    telegram: BTreeMap<String, TelegramDetail>,
+    /// Channel adaptors started, by name
+    adaptors: BTreeMap<String, AdaptorStatus>,
+    canary: CanaryDetail,
+    /// Submissions held on this gateway for Redis, when buffering is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buffered: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct RedisDetail {
+    /// Milliseconds a PING took, connecting included
+    round_trip_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueDetail {
+    backend: &'static str,
+    depth: u64,
+    priority_depth: u64,
+    /// Seconds the task waiting longest has been waiting
+    oldest_pending_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct TelegramDetail {
+    /// Whether Bot API calls are getting through, by the circuit breaker
+    reachable: bool,
+    circuit: &'static str,
+    consecutive_failures: u32,
+    last_loop_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CanaryDetail {
+    enabled: bool,
+    runs: u64,
+    failures: u64,
+    /// The latest canary, when one ran
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last: Option<CanaryRun>,
+}
+
+/// Time a PING, connecting included
+async fn redis_round_trip(state: &AppState) -> RedisDetail {
+    let started = Instant::now();
+    let ping = async {
+        let mut conn = state.redis_client.get_async_connection().await?;
+        redis::cmd("PING").query_async::<_, String>(&mut conn).await
+    };
+    match ping.await {
+        Ok(_) => RedisDetail {
+            round_trip_ms: Some(started.elapsed().as_secs_f64() * 1000.0),
+            error: None,
+        },
+        Err(e) => RedisDetail {
+            round_trip_ms: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Seconds since the oldest of `records` was created
+fn oldest_age(records: &[Option<String>], now: DateTime<Utc>) -> Option<i64> {
+    records
+        .iter()
+        .flatten()
+        .filter_map(|record| schema::decode_task(record).ok())
+        .filter_map(|task| DateTime::parse_from_rfc3339(task["created_at"].as_str()?).ok())
+        .map(|created_at| (now - created_at.with_timezone(&Utc)).num_seconds())
+        .max()
+}
+
+async fn queue_detail(state: &AppState) -> anyhow::Result<QueueDetail> {
+    let mut conn = state.redis_client.get_async_connection().await?;
+    let (depth, priority_depth) = backpressure::depths(&mut conn, &state.task_queue).await?;
+    let queues = backpressure::agent_queues(&mut conn).await?;
+    let queues: Vec<&str> = queues.iter().map(String::as_str).collect();
+    let oldest: Vec<String> = state
+        .task_queue
+        .oldest(&mut conn, &queues)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+    let mut oldest_pending_secs = None;
+    if !oldest.is_empty() {
+        let keys: Vec<String> = oldest.iter().map(|id| format!("task:{}", id)).collect();
+        let records: Vec<Option<String>> = conn.mget(keys).await?;
+        oldest_pending_secs = oldest_age(&records, Utc::now());
+    }
+    Ok(QueueDetail {
+        backend: state.task_queue.backend().as_str(),
+        depth,
+        priority_depth,
+        oldest_pending_secs,
+    })
+}
+
+// Health with the detail operators need during incidents
+pub async fn health_detailed(State(state): State<AppState>) -> Json<DetailedHealthResponse> {
+    let redis = redis_round_trip(&state).await;
+    let queues = match &redis.error {
+        Some(_) => None,
+        None => queue_detail(&state)
+            .await
+            .map_err(|e| tracing::warn!("Failed to read the agent queues: {}", e))
+            .ok(),
+    };
+    let telegram = telegram::snapshots(&state.telegram_bots)
+        .into_iter()
+        .map(|(bot, snapshot)| {
+            let detail = TelegramDetail {
+                reachable: snapshot.circuit != "open",
+                circuit: snapshot.circuit,
+                consecutive_failures: snapshot.consecutive_failures,
+                last_loop_at: snapshot.last_loop_at,
+            };
+            (bot, detail)
+        })
+        .collect();
+    let detail = Detail {
+        mode: state.config.current().server.mode.as_str(),
+        redis,
+        queues,
+        telegram,
+        adaptors: state.supervisor.adaptors(),
+        canary: CanaryDetail {
+            enabled: state.config.current().canary.enabled,
+            runs: state.canary_metrics.runs.get(),
+            failures: state.canary_metrics.failures.get(),
+            last: state.canary_metrics.last(),
+        },
+        buffered: state.buffer.as_ref().map(|buffer| buffer.len()),
+    };
+
+    let Json(mut health) = health_check(State(state)).await;
+    let adaptor_down = detail.adaptors.values().any(|status| !status.is_up());
+    if adaptor_down || detail.canary.last.as_ref().is_some_and(|run| !run.ok) {
+        health.status = "degraded".to_string();
+    }
+    Json(DetailedHealthResponse {
+        health,
+        detail,
+        build: BUILD,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope;
+    use serde_json::json;
+
+    #[test]
+    fn build_info_lists_features() {
+        let build = BuildInfo {
+            built_at: "1700000000",
+            features: "grpc,nats",
+            ..BUILD
+        };
+        let build = serde_json::to_value(build).unwrap();
+        assert_eq!(build["built_at"], "2023-11-14T22:13:20+00:00");
+        assert_eq!(build["features"], json!(["grpc", "nats"]));
+        let none = serde_json::to_value(BuildInfo {
+            features: "",
+            ..BUILD
+        })
+        .unwrap();
+        assert_eq!(none["features"], json!([]));
+    }
+
+    #[test]
+    fn ages_the_oldest_waiting_task() {
+        let now = Utc::now();
+        let created = |secs: i64| {
+            let created_at = (now - chrono::Duration::seconds(secs)).to_rfc3339();
+            Some(envelope::encode(&json!({ "created_at": created_at })).unwrap())
+        };
+        let records = [created(5), None, created(42), Some("garbage".to_string())];
+        assert_eq!(oldest_age(&records, now), Some(42));
+        assert_eq!(oldest_age(&[None], now), None);
+    }
+}
@@ -0,0 +1,304 @@
+//! Durable task history in PostgreSQL.
+//!
+//! Redis only holds tasks for as long as memory allows. With
+//! `history.database_url` set, every task the gateway creates is noted in
+//! the Redis set `history:pending`, and a sync loop copies its record and
+//! result into the `task_history` table until the task settles. `GET
+//! /task/:id` falls back to the table once Redis has let a task go, and
+//! `GET /tasks?from=&to=&status=` queries it for tasks of any age.
+//!
+//! Redis stays the primary task store: agents read and update task records
+//! there directly, so the table is a history, not a replacement.
+
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, Client};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+use crate::config::HistorySettings;
+use crate::error::ApiError;
+use crate::{envelope, failures, schema};
+
+/// Redis set of task ids whose latest state has yet to reach the table
+const PENDING_KEY: &str = "history:pending";
+
+/// Statuses after which a task record no longer changes
+pub const FINAL_STATUSES: [&str; 5] = ["completed", "failed", "timed_out", "orphaned", "deleted"];
+
+/// Set once the history store is connected, so tasks are only tracked when
+/// something will sync them
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Queue `task_id` for the history table, if one is configured
+pub async fn track(conn: &mut redis::aio::Connection, task_id: &str) -> redis::RedisResult<()> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    conn.sadd(PENDING_KEY, task_id).await
+}
+
+/// Add tracking `task_id` to `pipe`, as [`track`] does
+pub fn track_in(pipe: &mut redis::Pipeline, task_id: &str) {
+    if ENABLED.load(Ordering::Relaxed) {
+        pipe.sadd(PENDING_KEY, task_id).ignore();
+    }
+}
+
+/// Whether `task_id` has changes the table has yet to receive
+pub async fn is_pending(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+) -> redis::RedisResult<bool> {
+    conn.sismember(PENDING_KEY, task_id).await
+}
+
+/// A task as recorded in the history table
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub task_id: String,
+    pub status: String,
+    pub created_at: Option<DateTime<Utc>>,
+    /// Agent result, already out of its envelope
+    pub result: Option<Value>,
+}
+
+/// Filters for [`TaskHistory::list`]
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+pub struct HistoryFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub status: Option<String>,
+    /// When set, only tasks this reader may see: anonymous ones and, for a
+    /// key, its own
+    pub reader: Option<Option<String>>,
+    pub limit: usize,
+    pub offset: u64,
+}
+
+/// Error answered when the history table cannot be reached
+pub fn unavailable(e: anyhow::Error) -> ApiError {
+    error!("Task history error: {}", e);
+    ApiError::unavailable("history_unavailable", "Task history is unavailable")
+}
+
+#[cfg(feature = "postgres")]
+pub struct TaskHistory {
+    pool: sqlx::PgPool,
+}
+
+/// Stand-in without the `postgres` feature; it cannot be constructed
+#[cfg(not(feature = "postgres"))]
+pub enum TaskHistory {}
+
+#[cfg(feature = "postgres")]
+impl TaskHistory {
+    /// Connect and create the table when missing
+    pub async fn connect(settings: &HistorySettings, url: &str) -> anyhow::Result<Self> {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .connect(url)
+            .await?;
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        tracing::info!("Task history enabled");
+        Ok(Self { pool })
+    }
+
+    pub async fn get(&self, task_id: &str) -> anyhow::Result<Option<HistoryEntry>> {
+        let row = sqlx::query_as::<_, Row>(
+            "SELECT task_id, status, created_at, result FROM task_history WHERE task_id = $1",
+        )
+        .bind(task_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Row::into_entry))
+    }
+
+    /// Tasks matching `filter`, newest first
+    pub async fn list(&self, filter: &HistoryFilter) -> anyhow::Result<Vec<HistoryEntry>> {
+        let rows = sqlx::query_as::<_, Row>(
+            "SELECT task_id, status, created_at, result FROM task_history
+             WHERE ($1::timestamptz IS NULL OR created_at >= $1)
+               AND ($2::timestamptz IS NULL OR created_at < $2)
+               AND ($3::text IS NULL OR status = $3)
+               AND (NOT $6 OR submitted_by IS NULL OR submitted_by = $7)
+             ORDER BY created_at DESC NULLS LAST, task_id
+             LIMIT $4 OFFSET $5",
+        )
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(&filter.status)
+        .bind(filter.limit as i64)
+        .bind(filter.offset as i64)
+        .bind(filter.reader.is_some())
+        .bind(filter.reader.clone().flatten())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Row::into_entry).collect())
+    }
+
+    /// Store the latest state of a task; a failed task's error report is
+    /// kept as its result
+    async fn upsert(
+        &self,
+        task_id: &str,
+        task: &Value,
+        result: Option<&Value>,
+        failure: Option<&Value>,
+    ) -> anyhow::Result<()> {
+        let status = match (result, failure) {
+            (Some(_), _) => "completed",
+            (None, Some(_)) => "failed",
+            (None, None) => task["status"].as_str().unwrap_or("unknown"),
+        };
+        let created_at = task["created_at"]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        sqlx::query(
+            "INSERT INTO task_history
+                 (task_id, status, submitted_by, request_id, created_at, task, result)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (task_id) DO UPDATE SET
+                 status = EXCLUDED.status,
+                 task = EXCLUDED.task,
+                 result = COALESCE(EXCLUDED.result, task_history.result),
+                 updated_at = now()",
+        )
+        .bind(task_id)
+        .bind(status)
+        .bind(task["submitted_by"].as_str())
+        .bind(task["request_id"].as_str())
+        .bind(created_at)
+        .bind(sqlx::types::Json(task))
+        .bind(result.or(failure).map(sqlx::types::Json))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+impl TaskHistory {
+    pub async fn connect(_settings: &HistorySettings, _url: &str) -> anyhow::Result<Self> {
+        anyhow::bail!("gateway built without the postgres feature")
+    }
+
+    pub async fn get(&self, _task_id: &str) -> anyhow::Result<Option<HistoryEntry>> {
+        match *self {}
+    }
+
+    pub async fn list(&self, _filter: &HistoryFilter) -> anyhow::Result<Vec<HistoryEntry>> {
+        match *self {}
+    }
+
+    async fn upsert(
+        &self,
+        _task_id: &str,
+        _task: &Value,
+        _result: Option<&Value>,
+        _failure: Option<&Value>,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "postgres")]
+const SCHEMA: [&str; 3] = [
+    "CREATE TABLE IF NOT EXISTS task_history (
+         task_id TEXT PRIMARY KEY,
+         status TEXT NOT NULL,
+         submitted_by TEXT,
+         request_id TEXT,
+         created_at TIMESTAMPTZ,
+         updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+         task JSONB NOT NULL,
+         result JSONB
+     )",
+    "CREATE INDEX IF NOT EXISTS task_history_created_at ON task_history (created_at)",
+    "CREATE INDEX IF NOT EXISTS task_history_status ON task_history (status, created_at)",
+];
+
+#[cfg(feature = "postgres")]
+#[derive(sqlx::FromRow)]
+struct Row {
+    task_id: String,
+    status: String,
+    created_at: Option<DateTime<Utc>>,
+    result: Option<sqlx::types::Json<Value>>,
+}
+
+#[cfg(feature = "postgres")]
+impl Row {
+    fn into_entry(self) -> HistoryEntry {
+        HistoryEntry {
+            task_id: self.task_id,
+            status: self.status,
+            created_at: self.created_at,
+            result: self.result.map(|r| r.0),
+        }
+    }
+}
+
+/// Copy the latest state of every pending task into the table
+async fn sync(redis_client: &Client, history: &TaskHistory) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let pending: HashSet<String> = conn.smembers(PENDING_KEY).await?;
+
+    for task_id in pending {
+        let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+        let result: Option<String> = conn.get(format!("result:{}", task_id)).await?;
+        let report: Option<String> = conn.get(failures::error_key(&task_id)).await?;
+        let Some(task) = task else {
+            // Nothing left in Redis to copy
+            conn.srem::<_, _, ()>(PENDING_KEY, &task_id).await?;
+            continue;
+        };
+
+        let task = schema::decode_task(&task)?;
+        let result = result.map(|r| envelope::decode(&r)).transpose()?;
+        let failure = match report {
+            Some(report) => Some(envelope::decode(&report)?),
+            None if task["status"] == "failed" => Some(failures::from_record(&task)),
+            None => None,
+        };
+        history
+            .upsert(&task_id, &task, result.as_ref(), failure.as_ref())
+            .await?;
+
+        let settled = result.is_some()
+            || failure.is_some()
+            || task["status"]
+                .as_str()
+                .is_some_and(|s| FINAL_STATUSES.contains(&s));
+        if settled {
+            conn.srem::<_, _, ()>(PENDING_KEY, &task_id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Start the history sync loop in a background task
+pub fn start_history_sync(
+    redis_client: Arc<Client>,
+    history: Arc<TaskHistory>,
+    settings: &HistorySettings,
+) {
+    let interval = Duration::from_secs(settings.interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = sync(&redis_client, &history).await {
+                error!("Task history sync error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
@@ -0,0 +1,111 @@
+//! Translations of the replies the Telegram adaptor writes itself.
+//!
+//! Help, command acknowledgments and the apologies for overloads, failures
+//! and timeouts come from the catalogs in `src/locales`, bundled into the
+//! binary: one JSON object of message keys per language, `en` holding every
+//! key and the others falling back to it. Placeholders like `{language}`
+//! are filled in by [`format`]. A chat's language is the one chosen with
+//! `/language` (kept in the Redis hash `telegram:languages`), else the
+//! sender's Telegram `language_code` when there is a catalog for it, else
+//! English.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Language used when nothing better is known, with every message
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Bundled catalogs by language code
+const CATALOGS: [(&str, &str); 4] = [
+    ("en", include_str!("locales/en.json")),
+    ("de", include_str!("locales/de.json")),
+    ("es", include_str!("locales/es.json")),
+    ("fr", include_str!("locales/fr.json")),
+];
+
+type Catalog = HashMap<String, String>;
+
+/// The parsed catalogs, on first use
+fn catalogs() -> &'static HashMap<&'static str, Catalog> {
+    static PARSED: OnceLock<HashMap<&'static str, Catalog>> = OnceLock::new();
+    PARSED.get_or_init(|| {
+        CATALOGS
+            .iter()
+            .map(|(code, source)| {
+                let catalog = serde_json::from_str(source)
+                    .unwrap_or_else(|e| panic!("locales/{}.json is invalid: {}", code, e));
+                (*code, catalog)
+            })
+            .collect()
+    })
+}
+
+/// Codes of every bundled language
+pub fn languages() -> Vec<&'static str> {
+    CATALOGS.iter().map(|(code, _)| *code).collect()
+}
+
+/// The bundled language for a code like `de` or `pt-BR`, if there is one
+pub fn resolve(code: &str) -> Option<&'static str> {
+    let primary = code.split(['-', '_']).next()?.to_ascii_lowercase();
+    CATALOGS
+        .iter()
+        .map(|(code, _)| *code)
+        .find(|code| *code == primary)
+}
+
+/// Message `key` in `language`, or in English when it has no translation
+pub fn text(language: &str, key: &str) -> &'static str {
+    let catalogs = catalogs();
+    [language, DEFAULT_LANGUAGE]
+        .iter()
+        .find_map(|code| catalogs.get(*code)?.get(key))
+        .map(String::as_str)
+        .unwrap_or_else(|| panic!("no message {} in locales/en.json", key))
+}
+
+/// Message `key` in `language` with its `{name}` placeholders filled in
+pub fn format(language: &str, key: &str, args: &[(&str, &str)]) -> String {
+    args.iter()
+        .fold(text(language, key).to_string(), |message, (name, value)| {
+            message.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_translate_only_known_keys() {
+        let english = &catalogs()[DEFAULT_LANGUAGE];
+        for (code, catalog) in catalogs() {
+            for key in catalog.keys() {
+                assert!(
+                    english.contains_key(key),
+                    "{} has unknown key {}",
+                    code,
+                    key
+                );
+            }
+            assert!(catalog.contains_key("name"), "{} has no name", code);
+        }
+    }
+
+    #[test]
+    fn resolves_telegram_language_codes() {
+        assert_eq!(resolve("de"), Some("de"));
+        assert_eq!(resolve("FR-ca"), Some("fr"));
+        assert_eq!(resolve("pt-br"), None);
+        assert_eq!(resolve(""), None);
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        assert_eq!(
+            format("de", "language_set", &[("language", text("de", "name"))]),
+            "Antworten kommen jetzt auf Deutsch."
+        );
+        assert_eq!(text("xx", "failed"), text(DEFAULT_LANGUAGE, "failed"));
+    }
+}
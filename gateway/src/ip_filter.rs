@@ -0,0 +1,245 @@
+//! Client address allow and deny lists.
+//!
+//! Every request is checked against `ip_filter.deny`, then `ip_filter.allow`,
+//! and `/admin` routes also against `ip_filter.admin_allow`, so the admin API
+//! can be kept to an office VPN while the rest stays public. The client is
+//! the connection's peer, unless that peer is one of
+//! `ip_filter.trusted_proxies`: then `X-Forwarded-For` is read from the
+//! right, skipping further trusted proxies, and the first other address is
+//! the client. Entries are CIDRs such as `10.8.0.0/16` or single addresses.
+//! The gRPC API is held to the same rules, and a request whose peer address
+//! is unknown is refused.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::config::IpFilterSettings;
+use crate::error::ApiError;
+
+/// Parse an address rule, reading a bare address as a single-host network
+pub fn parse_net(entry: &str) -> Option<IpNet> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn parse_all(entries: &[String]) -> Vec<IpNet> {
+    entries.iter().filter_map(|e| parse_net(e)).collect()
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+/// Parsed address rules
+#[derive(Debug)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    admin_allow: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn from_config(settings: &IpFilterSettings) -> Self {
+        Self {
+            allow: parse_all(&settings.allow),
+            deny: parse_all(&settings.deny),
+            admin_allow: parse_all(&settings.admin_allow),
+            trusted_proxies: parse_all(&settings.trusted_proxies),
+        }
+    }
+
+    /// The client behind `peer`, believing `X-Forwarded-For` only from
+    /// trusted proxies
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !contains(&self.trusted_proxies, peer) {
+            return peer;
+        }
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for hop in forwarded.into_iter().rev() {
+            // Anything unparsable came from beyond the proxies we trust
+            let Ok(ip) = hop.parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !contains(&self.trusted_proxies, ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Whether the request from `peer` for `path` may be served, returning
+    /// the client address it was judged by. Requests without a peer address
+    /// are refused.
+    pub fn permits(
+        &self,
+        peer: Option<IpAddr>,
+        headers: &HeaderMap,
+        path: &str,
+    ) -> Result<IpAddr, Option<IpAddr>> {
+        let Some(peer) = peer else {
+            return Err(None);
+        };
+        let client = self.client_ip(peer, headers);
+        if self.admits(client, path) {
+            Ok(client)
+        } else {
+            Err(Some(client))
+        }
+    }
+
+    fn admits(&self, client: IpAddr, path: &str) -> bool {
+        if contains(&self.deny, client) {
+            return false;
+        }
+        if !self.allow.is_empty() && !contains(&self.allow, client) {
+            return false;
+        }
+        let admin = path == "/admin" || path.starts_with("/admin/");
+        !admin || self.admin_allow.is_empty() || contains(&self.admin_allow, client)
+    }
+}
+
+/// Middleware refusing clients the address rules exclude
+pub async fn filter_clients(
+    State(filter): State<Arc<IpFilter>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+    match filter.permits(peer, req.headers(), req.uri().path()) {
+        Ok(_) => Ok(next.run(req).await),
+        Err(client) => {
+            match client {
+                Some(client) => warn!("Refused {} to client {}", req.uri().path(), client),
+                None => warn!(
+                    "Refused {} to a client of unknown address",
+                    req.uri().path()
+                ),
+            }
+            Err(ApiError::forbidden("Client address not allowed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn filter(allow: &[&str], deny: &[&str], admin: &[&str], proxies: &[&str]) -> IpFilter {
+        let list = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect();
+        IpFilter::from_config(&IpFilterSettings {
+            allow: list(allow),
+            deny: list(deny),
+            admin_allow: list(admin),
+            trusted_proxies: list(proxies),
+        })
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_networks_and_bare_addresses() {
+        assert_eq!(parse_net("10.8.0.0/16"), "10.8.0.0/16".parse().ok());
+        assert_eq!(parse_net(" 192.0.2.1 "), "192.0.2.1/32".parse().ok());
+        assert_eq!(parse_net("::1"), "::1/128".parse().ok());
+        assert_eq!(parse_net("not an address"), None);
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let filter = filter(&[], &[], &[], &["10.0.0.1"]);
+        let headers = forwarded(&["203.0.113.9"]);
+        assert_eq!(
+            filter.client_ip(ip("198.51.100.7"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_are_skipped_from_the_right() {
+        let filter = filter(&[], &[], &[], &["10.0.0.0/8"]);
+        let headers = forwarded(&["203.0.113.9, 198.51.100.7", "10.0.0.2"]);
+        assert_eq!(
+            filter.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn unparsable_hops_stop_the_walk() {
+        let filter = filter(&[], &[], &[], &["10.0.0.0/8"]);
+        let headers = forwarded(&["203.0.113.9, garbage, 10.0.0.3"]);
+        assert_eq!(filter.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+        let none = HeaderMap::new();
+        assert_eq!(filter.client_ip(ip("10.0.0.1"), &none), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = filter(&["10.0.0.0/8"], &["10.0.0.66"], &[], &[]);
+        assert!(filter.admits(ip("10.1.2.3"), "/task"));
+        assert!(!filter.admits(ip("10.0.0.66"), "/task"));
+        assert!(!filter.admits(ip("192.0.2.1"), "/task"));
+    }
+
+    #[test]
+    fn empty_rules_admit_everyone() {
+        let filter = filter(&[], &[], &[], &[]);
+        assert!(filter.admits(ip("192.0.2.1"), "/task"));
+        assert!(filter.admits(ip("::1"), "/admin/config"));
+    }
+
+    #[test]
+    fn admin_routes_need_the_admin_allowlist() {
+        let filter = filter(&[], &[], &["10.8.0.0/16"], &[]);
+        assert!(filter.admits(ip("10.8.1.1"), "/admin"));
+        assert!(filter.admits(ip("10.8.1.1"), "/admin/keys"));
+        assert!(!filter.admits(ip("192.0.2.1"), "/admin/keys"));
+        assert!(filter.admits(ip("192.0.2.1"), "/administrators"));
+        assert!(filter.admits(ip("192.0.2.1"), "/task"));
+    }
+
+    #[test]
+    fn unknown_peers_are_refused() {
+        let filter = filter(&[], &[], &[], &[]);
+        assert_eq!(filter.permits(None, &HeaderMap::new(), "/task"), Err(None));
+        assert_eq!(
+            filter.permits(Some(ip("192.0.2.1")), &HeaderMap::new(), "/task"),
+            Ok(ip("192.0.2.1"))
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Task labels.
+//!
+//! Submissions may carry `labels`, free-form `key: value` pairs such as
+//! `env: prod` or `customer: acme`. Each label is indexed in the Redis set
+//! `label:{key}:{value}` holding the ids of the tasks that carry it, so
+//! `GET /tasks?label=env:prod&label=team:ml` is an intersection of sets rather
+//! than a scan. Ids whose task record has gone are dropped from the sets when
+//! a lookup runs into them.
+
+use redis::AsyncCommands;
+use std::collections::BTreeMap;
+use tracing::Instrument;
+
+use crate::telemetry;
+
+/// Most labels a single task may carry
+pub const MAX_LABELS: usize = 16;
+
+/// Longest accepted label key
+const MAX_KEY_LEN: usize = 63;
+
+/// Longest accepted label value
+const MAX_VALUE_LEN: usize = 128;
+
+/// What [`is_valid_key`] accepts, for error messages
+pub const KEY_RULE: &str =
+    "label keys must be 1-63 lowercase letters, digits, '-', '_', '.' or '/'";
+
+/// What [`is_valid_value`] accepts, for error messages
+pub const VALUE_RULE: &str =
+    "label values must be 1-128 letters, digits, '-', '_', '.', '/', ':' or '@'";
+
+/// Redis set of the tasks carrying `key: value`
+fn label_key(key: &str, value: &str) -> String {
+    format!("label:{}:{}", key, value)
+}
+
+/// Whether `key` can name a label; keys never contain `:` so that a
+/// `key:value` filter splits unambiguously
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.' | '/')
+        })
+}
+
+/// Whether `value` can be a label value
+pub fn is_valid_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_VALUE_LEN
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':' | '@'))
+}
+
+/// Parse a `key:value` filter
+pub fn parse_filter(filter: &str) -> Option<(String, String)> {
+    let (key, value) = filter.split_once(':')?;
+    (is_valid_key(key) && is_valid_value(value)).then(|| (key.to_string(), value.to_string()))
+}
+
+/// Index a task under each of its labels
+pub async fn index(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    labels: &BTreeMap<String, String>,
+) -> redis::RedisResult<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    index_in(&mut pipe, task_id, labels);
+    pipe.query_async(conn)
+        .instrument(telemetry::redis_span("SADD", "label:*"))
+        .await
+}
+
+/// Add indexing a task under each of its labels to `pipe`
+pub fn index_in(pipe: &mut redis::Pipeline, task_id: &str, labels: &BTreeMap<String, String>) {
+    for (key, value) in labels {
+        pipe.sadd(label_key(key, value), task_id).ignore();
+    }
+}
+
+/// Remove a task from the sets of the labels its record lists
+pub async fn unindex(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    task: &serde_json::Value,
+) -> redis::RedisResult<()> {
+    let Some(labels) = task["labels"].as_object().filter(|l| !l.is_empty()) else {
+        return Ok(());
+    };
+    let mut pipe = redis::pipe();
+    for (key, value) in labels {
+        if let Some(value) = value.as_str() {
+            pipe.srem(label_key(key, value), task_id).ignore();
+        }
+    }
+    pipe.query_async(conn).await
+}
+
+/// Ids of the tasks carrying every one of `filters`, sorted
+pub async fn find(
+    conn: &mut redis::aio::Connection,
+    filters: &[(String, String)],
+) -> redis::RedisResult<Vec<String>> {
+    let keys: Vec<String> = filters.iter().map(|(k, v)| label_key(k, v)).collect();
+    let mut task_ids: Vec<String> = conn
+        .sinter(&keys)
+        .instrument(telemetry::redis_span("SINTER", "label:*"))
+        .await?;
+    task_ids.sort();
+    Ok(task_ids)
+}
+
+/// Drop ids whose task record has gone from the sets of `filters`
+pub async fn forget(
+    conn: &mut redis::aio::Connection,
+    filters: &[(String, String)],
+    task_ids: &[String],
+) -> redis::RedisResult<()> {
+    if task_ids.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    for (key, value) in filters {
+        pipe.srem(label_key(key, value), task_ids).ignore();
+    }
+    pipe.query_async(conn).await
+}
@@ -0,0 +1,432 @@
+//! Work claims with leases.
+//!
+//! Agents that cannot reach Redis, or are not written in Python, take work
+//! over HTTP: `POST /internal/work/claim` with `{"agent_id", "capabilities",
+//! "lease_secs"}` pops the next task from the queues an agent would drain,
+//! in the same order (see `agent/agent/storage.py`), marks it `processing`
+//! and answers its record with a lease on it, or `204` when there is no
+//! work. The lease lasts `lease_secs`, `agents.lease_secs` by default and at
+//! most `agents.max_lease_secs`. `POST /internal/work/:id/extend` with
+//! `{"lease_id", "lease_secs"}` renews it, and `POST /internal/work/:id/complete`
+//! with `{"lease_id"}` and either `"result"` or `"error"` (and `"code"`) ends
+//! it and settles the task as [`agent_api`](crate::agent_api) does. Both
+//! answer 409 (`lease_lost`) once the lease is gone.
+//!
+//! Leases are kept in the sorted set `lease:deadlines`, scored by when they
+//! lapse, and the hash `lease:holders` of `{"lease_id", "queue",
+//! "agent_id"}` by task id. Every `agents.lease_check_interval_secs` a sweep
+//! takes back lapsed leases and puts their tasks, if still unfinished, back
+//! at the head of the queue they came from. Claims need the Redis queue
+//! backend, and the agent or admin role.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use claw_core::keys::claim_queues;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::agent_api::{self, checked_task_id};
+use crate::agent_registry::{self, require_role, AgentMetrics};
+use crate::auth::{Principal, Role};
+use crate::config::AgentRegistrySettings;
+use crate::error::ApiError;
+use crate::queue::TaskQueue;
+use crate::task_state::{self, TransitionError};
+use crate::validation::{self, ValidationError};
+use crate::{redis_connection, AppState};
+
+/// Sorted set of leased task ids, scored by the unix time their lease lapses
+const DEADLINES_KEY: &str = "lease:deadlines";
+
+/// Hash of the lease on each leased task
+const HOLDERS_KEY: &str = "lease:holders";
+
+/// Queued tasks a claim skips, because they settled meanwhile, before it
+/// answers that there is no work
+const MAX_SKIPPED: usize = 10;
+
+/// Lapsed leases taken back per pass
+const BATCH_SIZE: isize = 100;
+
+/// Pop the first task off `KEYS[3..]` and lease it: `ARGV[1]` is when the
+/// lease lapses, `ARGV[2]` its id and `ARGV[3]` the holder
+const CLAIM_SCRIPT: &str = r#"
+for i = 3, #KEYS do
+  local task_id = redis.call('RPOP', KEYS[i])
+  if task_id then
+    redis.call('ZADD', KEYS[1], ARGV[1], task_id)
+    local lease = cjson.encode({lease_id = ARGV[2], queue = KEYS[i], agent_id = ARGV[3]})
+    redis.call('HSET', KEYS[2], task_id, lease)
+    return task_id
+  end
+end
+return false
+"#;
+
+/// Move the lapse of lease `ARGV[2]` on `ARGV[1]` to `ARGV[3]`, if it holds
+const EXTEND_SCRIPT: &str = r#"
+local lease = redis.call('HGET', KEYS[2], ARGV[1])
+if not lease or cjson.decode(lease).lease_id ~= ARGV[2] then
+  return 0
+end
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+return 1
+"#;
+
+/// End the lease on `ARGV[1]`, returning it, if it is lease `ARGV[2]` or has
+/// lapsed by `ARGV[3]`
+const END_SCRIPT: &str = r#"
+local lease = redis.call('HGET', KEYS[2], ARGV[1])
+local lapses = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not lease then
+  redis.call('ZREM', KEYS[1], ARGV[1])
+  return false
+end
+local lapsed = lapses and tonumber(lapses) <= tonumber(ARGV[3])
+if cjson.decode(lease).lease_id ~= ARGV[2] and not lapsed then
+  return false
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+redis.call('HDEL', KEYS[2], ARGV[1])
+return lease
+"#;
+
+/// A lease as kept in `lease:holders`
+#[derive(Debug, Deserialize)]
+struct Lease {
+    queue: String,
+    agent_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaimRequest {
+    /// Registered agent claiming work, whose own queue comes first
+    agent_id: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    lease_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Claim {
+    task_id: String,
+    lease_id: String,
+    lease_expires_at: String,
+    task: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtendRequest {
+    lease_id: String,
+    lease_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtendResponse {
+    task_id: String,
+    lease_id: String,
+    lease_expires_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteRequest {
+    lease_id: String,
+    result: Option<Value>,
+    error: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompleteResponse {
+    task_id: String,
+    status: &'static str,
+}
+
+/// When a lease of `requested` seconds taken now lapses
+fn lease_deadline(
+    requested: Option<u64>,
+    settings: &AgentRegistrySettings,
+) -> Result<DateTime<Utc>, ApiError> {
+    let secs = requested.unwrap_or(settings.lease_secs);
+    if !(1..=settings.max_lease_secs).contains(&secs) {
+        return Err(ApiError::bad_request(
+            "invalid_lease",
+            format!("lease_secs must be 1-{}", settings.max_lease_secs),
+        ));
+    }
+    Ok(Utc::now() + chrono::Duration::seconds(secs as i64))
+}
+
+fn lease_lost(task_id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "lease_lost",
+        format!("The lease on task {} is not held", task_id),
+    )
+}
+
+/// End the lease on `task_id` if it is `lease_id`, or if it has lapsed when
+/// `lease_id` is `None`
+async fn end_lease(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    lease_id: Option<&str>,
+) -> redis::RedisResult<Option<Lease>> {
+    let lapsed_by = match lease_id {
+        Some(_) => 0,
+        None => Utc::now().timestamp(),
+    };
+    let lease: Option<String> = redis::Script::new(END_SCRIPT)
+        .key(DEADLINES_KEY)
+        .key(HOLDERS_KEY)
+        .arg(task_id)
+        .arg(lease_id.unwrap_or_default())
+        .arg(lapsed_by)
+        .invoke_async(conn)
+        .await?;
+    Ok(lease.and_then(|lease| serde_json::from_str(&lease).ok()))
+}
+
+/// Lease the next task waiting for the caller (agent role)
+pub async fn claim_work(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    body: Result<Json<ClaimRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    let Json(req) = body.map_err(ValidationError::from)?;
+    if !matches!(state.task_queue, TaskQueue::Redis) {
+        return Err(ApiError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "unsupported_queue_backend",
+            "Work claims need the redis queue backend",
+        ));
+    }
+    if !req
+        .capabilities
+        .iter()
+        .all(|c| validation::is_valid_capability(c))
+    {
+        return Err(ApiError::bad_request(
+            "invalid_capability",
+            validation::CAPABILITY_RULE,
+        ));
+    }
+    let expires_at = lease_deadline(req.lease_secs, &state.config.current().agents)?;
+
+    let queues = claim_queues(req.agent_id.as_deref(), &req.capabilities);
+    let mut conn = redis_connection(&state).await?;
+    let script = redis::Script::new(CLAIM_SCRIPT);
+    for _ in 0..MAX_SKIPPED {
+        let lease_id = uuid::Uuid::new_v4().to_string();
+        let mut invocation = script.prepare_invoke();
+        invocation.key(DEADLINES_KEY).key(HOLDERS_KEY);
+        for queue in &queues {
+            invocation.key(queue);
+        }
+        let task_id: Option<String> = invocation
+            .arg(expires_at.timestamp())
+            .arg(&lease_id)
+            .arg(req.agent_id.as_deref().unwrap_or_default())
+            .invoke_async(&mut conn)
+            .await?;
+        let Some(task_id) = task_id else {
+            return Ok(StatusCode::NO_CONTENT.into_response());
+        };
+
+        match agent_api::start(&mut conn, &task_id, req.agent_id.as_deref()).await {
+            Ok(task) => {
+                info!("Task {} leased until {}", task_id, expires_at);
+                return Ok(Json(Claim {
+                    task_id,
+                    lease_id,
+                    lease_expires_at: expires_at.to_rfc3339(),
+                    task,
+                })
+                .into_response());
+            }
+            // Settled or deleted while it was queued
+            Err(e) if e.code == "invalid_transition" || e.status == StatusCode::NOT_FOUND => {
+                end_lease(&mut conn, &task_id, Some(&lease_id)).await?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Renew a lease (agent role)
+pub async fn extend_lease(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<ExtendRequest>, JsonRejection>,
+) -> Result<Json<ExtendResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    checked_task_id(&task_id)?;
+    let Json(req) = body.map_err(ValidationError::from)?;
+    let expires_at = lease_deadline(req.lease_secs, &state.config.current().agents)?;
+
+    let mut conn = redis_connection(&state).await?;
+    let extended: bool = redis::Script::new(EXTEND_SCRIPT)
+        .key(DEADLINES_KEY)
+        .key(HOLDERS_KEY)
+        .arg(&task_id)
+        .arg(&req.lease_id)
+        .arg(expires_at.timestamp())
+        .invoke_async(&mut conn)
+        .await?;
+    if !extended {
+        return Err(lease_lost(&task_id));
+    }
+    Ok(Json(ExtendResponse {
+        task_id,
+        lease_id: req.lease_id,
+        lease_expires_at: expires_at.to_rfc3339(),
+    }))
+}
+
+/// End a lease and settle its task (agent role)
+pub async fn complete_work(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    body: Result<Json<CompleteRequest>, JsonRejection>,
+) -> Result<Json<CompleteResponse>, ApiError> {
+    require_role(principal, &[Role::Agent, Role::Admin])?;
+    checked_task_id(&task_id)?;
+    let Json(req) = body.map_err(ValidationError::from)?;
+    if req.result.is_some() == req.error.is_some() {
+        return Err(ApiError::bad_request(
+            "invalid_outcome",
+            "Give either result or error",
+        ));
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    if end_lease(&mut conn, &task_id, Some(&req.lease_id))
+        .await?
+        .is_none()
+    {
+        return Err(lease_lost(&task_id));
+    }
+    let status = match (&req.result, &req.error) {
+        (Some(result), _) => {
+            agent_api::complete(&mut conn, &task_id, result).await?;
+            "completed"
+        }
+        (None, error) => {
+            let error = error.as_deref().unwrap_or_default();
+            agent_api::fail(&mut conn, &task_id, error, req.code.as_deref()).await?;
+            "failed"
+        }
+    };
+    Ok(Json(CompleteResponse { task_id, status }))
+}
+
+/// Put the task of a lapsed lease back at the head of its queue, unless it
+/// finished meanwhile; whether it was requeued
+async fn requeue(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    lease: &Lease,
+) -> Result<bool, TransitionError> {
+    if !lease.agent_id.is_empty() {
+        conn.srem::<_, _, ()>(agent_registry::tasks_key(&lease.agent_id), task_id)
+            .await?;
+    }
+    let requeued = task_state::transition(conn, task_id, "pending", |task| {
+        let unfinished = matches!(task["status"].as_str(), Some("processing" | "orphaned"));
+        if let Some(fields) = task.as_object_mut() {
+            fields.remove("agent_id");
+            fields.remove("agent_version");
+            fields.remove("started_at");
+        }
+        unfinished
+    });
+    match requeued.await {
+        Ok(Some(_)) => {}
+        Ok(None) | Err(TransitionError::NotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    conn.rpush::<_, _, ()>(&lease.queue, task_id).await?;
+    Ok(true)
+}
+
+/// Take back every lapsed lease
+async fn sweep(redis_client: &Client, metrics: &AgentMetrics) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let now = Utc::now().timestamp();
+    let lapsed: Vec<String> = conn
+        .zrangebyscore_limit(DEADLINES_KEY, "-inf", now, 0, BATCH_SIZE)
+        .await?;
+    for task_id in lapsed {
+        // Another gateway may have taken it back, or the agent renewed it
+        let Some(lease) = end_lease(&mut conn, &task_id, None).await? else {
+            continue;
+        };
+        if requeue(&mut conn, &task_id, &lease).await? {
+            warn!(
+                "Lease on task {} lapsed, requeued on {}",
+                task_id, lease.queue
+            );
+            metrics.leases_lapsed.inc();
+        }
+    }
+    Ok(())
+}
+
+/// Requeue the tasks of lapsed leases in a background task
+pub fn start_lease_sweep(
+    redis_client: Arc<Client>,
+    settings: &AgentRegistrySettings,
+    metrics: Arc<AgentMetrics>,
+) {
+    let interval = Duration::from_secs(settings.lease_check_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = sweep(&redis_client, &metrics).await {
+                error!("Lease sweep error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_drain_queues_as_agents_do() {
+        let capabilities = vec!["code".to_string()];
+        assert_eq!(
+            claim_queues(Some("a1"), &capabilities),
+            [
+                "agent:direct:a1",
+                "agent:queue:priority",
+                "agent:queue:code:priority",
+                "agent:queue:code",
+                "agent:queue",
+            ]
+        );
+        assert_eq!(
+            claim_queues(None, &[]),
+            ["agent:queue:priority", "agent:queue"]
+        );
+
+        let settings = AgentRegistrySettings::default();
+        assert!(lease_deadline(None, &settings).is_ok());
+        assert!(lease_deadline(Some(0), &settings).is_err());
+        assert!(lease_deadline(Some(settings.max_lease_secs + 1), &settings).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Long polling of task results.
+//!
+//! `GET /task/:id?wait=N` holds the request for up to N seconds (at most
+//! `tasks.max_wait_secs`) while the task is unsettled, answering 200 as
+//! soon as it completes, fails or is deleted and 202 with its current state
+//! once the wait runs out. Waiters are woken by Redis keyspace notifications
+//! for `result:*`, `error:*` and `task:*`, which need
+//! `notify-keyspace-events K$` in `redis.conf`; they also check again every
+//! [`RECHECK_INTERVAL`], so waits end without the notifications too, only
+//! later.
+
+use futures_util::StreamExt;
+use redis::Client;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Keyspace channels of the keys a task settles through
+const PATTERNS: [&str; 3] = [
+    "__keyspace@*__:result:*",
+    "__keyspace@*__:error:*",
+    "__keyspace@*__:task:*",
+];
+
+/// How often a waiter checks its task without being woken
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Delay before resubscribing after the notification connection drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Room for wake-ups not yet seen by every waiter
+const WAKE_BUFFER: usize = 1024;
+
+/// Hands the ids of tasks whose keys changed to local waiters
+#[derive(Clone)]
+pub struct ResultWaiters {
+    local: broadcast::Sender<String>,
+}
+
+/// The task a keyspace notification channel is about, if it is a task's
+/// record, result or error report
+fn task_of(channel: &str) -> Option<&str> {
+    let (_, key) = channel.split_once("__:")?;
+    let (_, task_id) = key.split_once(':')?;
+    (!task_id.is_empty() && !task_id.contains(':')).then_some(task_id)
+}
+
+/// Forward keyspace notifications for task keys to local waiters
+async fn relay(redis_client: &Client, local: &broadcast::Sender<String>) -> anyhow::Result<()> {
+    let mut pubsub = redis_client.get_async_connection().await?.into_pubsub();
+    for pattern in PATTERNS {
+        pubsub.psubscribe(pattern).await?;
+    }
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        if let Some(task_id) = task_of(message.get_channel_name()) {
+            // Fails only while nobody is waiting
+            let _ = local.send(task_id.to_string());
+        }
+    }
+    Ok(())
+}
+
+impl ResultWaiters {
+    /// Start relaying notifications in a background task
+    pub fn start(redis_client: Arc<Client>) -> Self {
+        let (local, _) = broadcast::channel(WAKE_BUFFER);
+        let waiters = Self {
+            local: local.clone(),
+        };
+        tokio::spawn(async move {
+            loop {
+                match relay(&redis_client, &local).await {
+                    Ok(()) => warn!("Task keyspace subscription closed"),
+                    Err(e) => warn!("Task keyspace relay error: {}", e),
+                }
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+        waiters
+    }
+
+    /// Start listening for changes; taken before reading the task, so a
+    /// change landing between the read and the wait is not missed
+    pub fn listen(&self) -> broadcast::Receiver<String> {
+        self.local.subscribe()
+    }
+}
+
+/// Wait until `task_id` may have changed: it was notified, the recheck
+/// interval passed or `until` came
+pub async fn changed(receiver: &mut broadcast::Receiver<String>, task_id: &str, until: Instant) {
+    let until = until.min(Instant::now() + RECHECK_INTERVAL);
+    let notified = async {
+        loop {
+            match receiver.recv().await {
+                Ok(changed) if changed == task_id => return,
+                Ok(_) => {}
+                // Something was missed, maybe this task
+                Err(broadcast::error::RecvError::Lagged(_)) => return,
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    };
+    let _ = tokio::time::timeout_at(until, notified).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_task_ids_from_keyspace_channels() {
+        assert_eq!(task_of("__keyspace@0__:result:t1"), Some("t1"));
+        assert_eq!(task_of("__keyspace@3__:task:t-2"), Some("t-2"));
+        assert_eq!(task_of("__keyspace@0__:result:t1:chunk:0"), None);
+        assert_eq!(task_of("__keyspace@0__:task:"), None);
+    }
+
+    #[tokio::test]
+    async fn wakes_only_for_its_task() {
+        let (local, _) = broadcast::channel(8);
+        let waiters = ResultWaiters {
+            local: local.clone(),
+        };
+        let mut receiver = waiters.listen();
+        local.send("other".to_string()).unwrap();
+        local.send("t1".to_string()).unwrap();
+        let started = Instant::now();
+        changed(&mut receiver, "t1", started + Duration::from_secs(5)).await;
+        assert!(started.elapsed() < RECHECK_INTERVAL);
+
+        let started = Instant::now();
+        changed(&mut receiver, "t1", started + Duration::from_millis(50)).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
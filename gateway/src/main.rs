@@ -1,263 +1,1923 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::Json,
-    routing::{get, post},
-    Router,
+    extract::{rejection::JsonRejection, DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
+    Extension, Router,
 };
 use redis::{AsyncCommands, Client};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tower_http::cors::CorsLayer;
-use tracing::{error, info};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::time::Duration;
+use tracing::{error, info, warn, Instrument};
 
+mod access_log;
+mod agent_api;
+mod agent_config;
+mod agent_registry;
+mod annotations;
+mod attachments;
+mod auth;
+mod broadcast;
+mod buffer;
+mod backpressure;
+mod cache;
+mod canary;
+mod codec;
+mod concurrency;
+mod config;
+mod cors;
+mod dashboard;
+mod dedupe;
+mod dependencies;
+mod diff;
+mod events;
+mod error;
+#[cfg(feature = "email")]
+mod email;
+mod failures;
+mod fanout;
+mod feedback;
+mod health;
+mod federation;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod history;
+mod i18n;
+mod ip_filter;
+mod labels;
+mod leases;
+mod long_poll;
+mod maintenance;
+mod memory_guard;
+mod merge;
+mod metrics;
+mod moderation;
+mod pipelines;
+mod queue;
+mod redaction;
+mod render;
+mod replica;
+mod request_id;
+mod rerun;
+mod result_signing;
+mod results;
+mod retention;
+mod retry;
+mod runtime;
+mod server;
+mod signing;
+mod simulation;
+mod subscriptions;
+mod supervisor;
+mod support;
+mod task_logs;
 mod telegram;
+mod templates;
+mod throttle;
+mod timeouts;
+mod tts;
+mod twilio;
+mod telemetry;
+mod usage;
+mod validation;
+mod watchdog;
+
+use agent_registry::AgentMetrics;
+use claw_core::keys::{queue_for, AGENT_QUEUE, PRIORITY_QUEUE};
+use claw_core::{envelope, schema, task_state, AgentRequest, AgentResponse};
+use attachments::AttachmentStore;
+use auth::Principal;
+use backpressure::QueueGuard;
+use buffer::SubmissionBuffer;
+use cache::{CacheControl, CacheMetrics, CacheStatus};
+use canary::CanaryMetrics;
+use codec::{Body, Encoded, Format};
+use concurrency::{ConcurrencyLimits, ConcurrencyMetrics};
+use config::Config;
+use error::ApiError;
+use events::EventMetrics;
+use federation::{Federation, PeerOrigin};
+use history::{HistoryFilter, TaskHistory};
+use ip_filter::IpFilter;
+use long_poll::ResultWaiters;
+use memory_guard::{AdmissionLevel, MemoryGuard};
+use moderation::Moderator;
+use queue::{NewTask, QueueError, TaskQueue};
+use render::{Rendered, Rendition};
+use replica::ReadReplica;
+use result_signing::ResultSigner;
+use retry::{CircuitState, RetryPolicies};
+use runtime::RuntimeConfig;
+use subscriptions::SubscriptionHub;
+use supervisor::Supervisor;
+use telegram::TelegramHealth;
+use templates::ReplyTemplates;
+use timeouts::TimeoutMetrics;
+use twilio::Twilio;
+use validation::ValidationError;
+use watchdog::WatchdogMetrics;
+
+/// Key written by `/readyz` to prove the queue namespace accepts writes
+const READINESS_PROBE_KEY: &str = "agent:readyz";
 
 // Configuration
 #[derive(Clone)]
 struct AppState {
     redis_client: Arc<Client>,
+    replica: ReadReplica,
+    task_queue: TaskQueue,
+    history: Option<Arc<TaskHistory>>,
+    /// Submissions kept while Redis is unreachable, when buffering is on
+    buffer: Option<Arc<SubmissionBuffer>>,
+    attachments: AttachmentStore,
+    federation: Federation,
+    retry: RetryPolicies,
+    config: Arc<RuntimeConfig>,
+    /// Every Telegram bot run, for broadcasts and health reports
+    telegram_bots: Vec<telegram::Bot>,
+    /// Channel adaptors started, and whether they are up
+    supervisor: Arc<Supervisor>,
+    twilio: Twilio,
+    memory_guard: Arc<MemoryGuard>,
+    queue_guard: Arc<QueueGuard>,
+    cache_metrics: Arc<CacheMetrics>,
+    agent_metrics: Arc<AgentMetrics>,
+    event_metrics: Arc<EventMetrics>,
+    subscriptions: SubscriptionHub,
+    result_waiters: ResultWaiters,
+    watchdog_metrics: Arc<WatchdogMetrics>,
+    canary_metrics: Arc<CanaryMetrics>,
+    timeout_metrics: Arc<TimeoutMetrics>,
+    result_signer: Option<ResultSigner>,
+    moderator: Arc<Moderator>,
+    templates: ReplyTemplates,
 }
 
 // Request/Response types
+/// A task's state as `GET /task/:id` answers it, with its annotations
+#[derive(Debug, Serialize)]
+struct TaskDetail {
+    #[serde(flatten)]
+    response: AgentResponse,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<annotations::Annotation>,
+}
+
+/// A submission's answer, with the cache headers REST sends alongside it
+struct Submitted {
+    response: AgentResponse,
+    cache_status: Option<CacheStatus>,
+    age: Option<u64>,
+}
+
+/// Body of `POST /tasks/results`
 #[derive(Debug, Deserialize)]
-struct AgentRequest {
-    task_id: String,
-    input: serde_json::Value,
-    config: Option<serde_json::Value>,
+#[serde(deny_unknown_fields)]
+struct BulkResultsRequest {
+    task_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkResultsResponse {
+    /// The state of every task found, in the order asked for
+    results: Vec<AgentResponse>,
+    /// Ids of the tasks not found
+    missing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetTaskQuery {
+    /// Seconds to hold the request while the task is unsettled
+    wait: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListTasksQuery {
+    /// SCAN cursor returned by the previous page
+    cursor: Option<u64>,
+    limit: Option<usize>,
+    /// Override for the preview length in characters (0 disables previews)
+    preview: Option<usize>,
+    /// Only tasks created at or after this time (task history)
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only tasks created before this time (task history)
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only tasks with this status (task history)
+    status: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
-struct AgentResponse {
+struct TaskSummary {
     task_id: String,
     status: String,
-    result: Option<serde_json::Value>,
-    error: Option<String>,
+    created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    labels: Option<serde_json::Value>,
+    preview: Option<ResultPreview>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResultPreview {
+    text: String,
+    truncated: bool,
+    /// Size of the full stored result in bytes
+    size: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ListTasksResponse {
+    tasks: Vec<TaskSummary>,
+    /// Cursor for the next page, absent once the scan is complete
+    next_cursor: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 struct HealthResponse {
     status: String,
     redis: bool,
+    /// Telegram circuit breaker state of each bot, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    telegram: BTreeMap<String, &'static str>,
+    /// Admission level set by the Redis memory guard
+    admission: &'static str,
+    /// Admission level set by agent queue backpressure
+    queue: &'static str,
+    /// Message of the maintenance in progress
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<String>,
 }
 
 // Health check endpoint
 async fn health_check(State(state): State<AppState>) -> Json<HealthResponse> {
     let redis_status = check_redis_connection(&state.redis_client).await;
+    let telegram: BTreeMap<String, CircuitState> = state
+        .telegram_bots
+        .iter()
+        .map(|bot| (bot.label().to_string(), bot.metrics().breaker.state()))
+        .collect();
+    let admission = state.memory_guard.level();
+    let queue = state.queue_guard.level();
+    let healthy = redis_status
+        && !telegram.values().any(|s| *s == CircuitState::Open)
+        && admission == AdmissionLevel::Open
+        && queue == AdmissionLevel::Open;
     Json(HealthResponse {
-        status: if redis_status { "healthy".to_string() } else { "degraded".to_string() },
+        status: if healthy { "healthy".to_string() } else { "degraded".to_string() },
         redis: redis_status,
+        telegram: telegram.into_iter().map(|(bot, s)| (bot, s.as_str())).collect(),
+        admission: admission.as_str(),
+        queue: queue.as_str(),
+        maintenance: maintenance::current().map(|notice| notice.message),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ProbeCheck {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl ProbeCheck {
+    fn from_result(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self { ok: true, detail: None },
+            Err(detail) => Self { ok: false, detail: Some(detail) },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessResponse {
+    status: &'static str,
+    checks: BTreeMap<String, ProbeCheck>,
+}
+
+// Liveness probe: the process is up and serving requests
+async fn liveness() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "alive" }))
+}
+
+// Readiness probe: dependencies are reachable and the adaptor is running
+async fn readiness(State(state): State<AppState>) -> Result<Json<ReadinessResponse>, ApiError> {
+    let mut checks = BTreeMap::new();
+
+    let redis = check_redis_connection(&state.redis_client).await;
+    checks.insert(
+        "redis".to_string(),
+        ProbeCheck::from_result(redis.then_some(()).ok_or_else(|| "unreachable".to_string())),
+    );
+
+    // A short-lived write proves Redis is neither read-only nor out of memory
+    let writable = async {
+        let mut conn = state.redis_client.get_async_connection().await?;
+        conn.set_ex::<_, _, ()>(READINESS_PROBE_KEY, chrono::Utc::now().timestamp(), 30)
+            .await
+    };
+    checks.insert(
+        "queue".to_string(),
+        ProbeCheck::from_result(writable.await.map_err(|e| e.to_string())),
+    );
+
+    // Tasks only reach agents if the queue backend is up as well
+    if state.task_queue.backend() != config::QueueBackend::Redis {
+        checks.insert(
+            state.task_queue.backend().as_str().to_string(),
+            ProbeCheck::from_result(state.task_queue.ping().await.map_err(|e| e.to_string())),
+        );
+    }
+
+    let max_age = state.config.current().telegram_heartbeat_age().as_secs() as i64;
+    for bot in &state.telegram_bots {
+        let last_loop_at = bot.metrics().last_loop_at.get();
+        let age = chrono::Utc::now().timestamp() - last_loop_at;
+        let heartbeat = match last_loop_at {
+            0 => Err("adaptor loop has not completed yet".to_string()),
+            _ if age > max_age => Err(format!("last heartbeat {}s ago", age)),
+            _ => Ok(()),
+        };
+        checks.insert(bot.adaptor_name(), ProbeCheck::from_result(heartbeat));
+    }
+
+    if checks.values().all(|c| c.ok) {
+        return Ok(Json(ReadinessResponse {
+            status: "ready",
+            checks,
+        }));
+    }
+    Err(ApiError::unavailable("not_ready", "Gateway is not ready")
+        .with_details(serde_json::to_value(checks)?))
+}
+
+#[derive(Debug, Serialize)]
+struct TelegramHealthResponse {
+    enabled: bool,
+    /// Health of each bot, by name
+    bots: BTreeMap<String, TelegramHealth>,
+}
+
+// Telegram adaptor health summary (admin only)
+async fn telegram_health(State(state): State<AppState>) -> Json<TelegramHealthResponse> {
+    Json(TelegramHealthResponse {
+        enabled: !state.telegram_bots.is_empty(),
+        bots: telegram::snapshots(&state.telegram_bots),
     })
 }
 
 // Submit task to agent
 async fn submit_task(
     State(state): State<AppState>,
-    Json(req): Json<AgentRequest>,
-) -> Result<Json<AgentResponse>, StatusCode> {
+    peer_origin: Option<Extension<PeerOrigin>>,
+    principal: Option<Extension<Principal>>,
+    headers: HeaderMap,
+    Body(req): Body<AgentRequest>,
+) -> Result<Response, ApiError> {
     // Validate request
-    if let Err(e) = validate_request(&req).await {
-        error!("Request validation failed: {}", e);
-        return Err(StatusCode::BAD_REQUEST);
+    if let Err(e) = validation::validate_request(&req, &state.config.current().limits) {
+        error!("Request validation failed: {:?}", e.violations);
+        return Err(e.into());
     }
 
-    // Get config from Redis
-    let config = match get_config(&state.redis_client, &req.config).await {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            error!("Failed to get config: {}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let cache_control = CacheControl::from_headers(&headers);
+
+    // Keep a copy to buffer should Redis turn out to be unreachable
+    let fallback = match (&state.buffer, &peer_origin) {
+        (Some(buffer), None) => {
+            let principal = principal.as_ref().map(|Extension(p)| p);
+            let entry = buffer::Buffered::new(&req, principal, cache_control);
+            Some((buffer.clone(), entry))
         }
+        _ => None,
     };
+    let submitted = enqueue_task(state, peer_origin, principal, cache_control, req).await;
+    let submitted = match (submitted, fallback) {
+        (Err(e), Some((buffer, entry))) if buffer::redis_unreachable(&e) => {
+            let mut response = buffer.admit(entry, e)?.respond(Format::accepted(&headers));
+            *response.status_mut() = StatusCode::ACCEPTED;
+            return Ok(response);
+        }
+        (submitted, _) => submitted?,
+    };
+    Ok(submitted.respond(Format::accepted(&headers)))
+}
+
+// Store a validated task and push it onto the right queue
+async fn enqueue_task(
+    state: AppState,
+    peer_origin: Option<Extension<PeerOrigin>>,
+    principal: Option<Extension<Principal>>,
+    cache_control: CacheControl,
+    mut req: AgentRequest,
+) -> Result<Submitted, ApiError> {
+    // Operator and admin submissions use the reserved priority lane
+    let priority = principal
+        .as_ref()
+        .is_some_and(|Extension(p)| p.role.is_privileged());
+
+    // Refuse work while Redis is close to its memory ceiling
+    if !state.memory_guard.admits(priority) {
+        warn!("Task {} rejected by memory guard", req.task_id);
+        return Err(ApiError::unavailable(
+            "overloaded",
+            "The gateway is shedding load, retry later",
+        )
+        .with_retry_after(30));
+    }
+
+    // Refuse work during maintenance, unless it is to be held
+    maintenance::admit()?;
 
-    // Create task in Redis
+    // Shed work the agents cannot get to in time
+    if !state.queue_guard.admits(priority) {
+        warn!("Task {} shed by queue backpressure", req.task_id);
+        return Err(ApiError::unavailable(
+            "queue_full",
+            "The agent queue is full, retry later",
+        )
+        .with_retry_after(state.queue_guard.retry_after_secs()));
+    }
+
+    // Personal data never reaches Redis for tenants that redact it
+    let tenant = usage::tenant(principal.as_ref().map(|Extension(p)| p));
+    redaction::input(tenant, &mut req.input);
+
+    // Task ids are never reused: a resubmitted id would take the task over
     let task_key = format!("task:{}", req.task_id);
-    let task_value = serde_json::to_string(&serde_json::json!({
+    if redis_connection(&state).await?.exists(task_key).await? {
+        return Err(QueueError::Duplicate(req.task_id).into());
+    }
+
+    // Map tasks run as one child task per input element
+    if let Some(map) = req.map {
+        return fanout::submit(state, peer_origin, principal, cache_control, req, map).await;
+    }
+
+    // Refuse or flag inputs failing content moderation
+    let moderation = state.moderator.review(&req.task_id, &req.input).await?;
+
+    let timeout_secs =
+        watchdog::timeout_for(req.timeout_seconds, &state.config.current().watchdog)?;
+
+    // Get config from Redis
+    let config = get_config(&state, &req.config).await?;
+
+    let scope = principal
+        .as_ref()
+        .map_or("anonymous", |Extension(p)| p.key_id.as_str());
+
+    // Serve cacheable tasks from a stored result when one is fresh enough
+    let policy = cache::policy(&config).map_err(|e| ApiError::bad_request("invalid_config", e))?;
+    let mut cache_status = None;
+    let mut cache_entry = None;
+    if let Some(policy) = policy {
+        let key = cache::key(scope, &req.input, &config);
+        if cache_control.no_cache {
+            state.cache_metrics.bypassed.inc();
+            cache_status = Some(CacheStatus::Bypass);
+        } else {
+            let mut conn = redis_connection(&state).await?;
+            if let Some(hit) = cache::lookup(&mut conn, &key, cache_control.max_age).await? {
+                state.cache_metrics.hits.inc();
+                let age = hit.age();
+                let source = ("cached_from", hit.task_id.as_str());
+                let response =
+                    answer_from(&mut conn, principal, req, config, source, &hit.result).await?;
+                return Ok(Submitted {
+                    response: result_signing::signed(response.0, state.result_signer.as_ref()),
+                    cache_status: Some(CacheStatus::Hit),
+                    age: Some(age),
+                });
+            }
+            state.cache_metrics.misses.inc();
+            cache_status = Some(CacheStatus::Miss);
+        }
+        if !cache_control.no_store {
+            cache_entry = Some((key, policy.ttl));
+        }
+    }
+
+    // Answer repeats of a recently completed task from its result
+    let dedupe_window = state
+        .config
+        .current()
+        .dedupe_window()
+        .filter(|_| !cache_control.no_cache);
+    let fingerprint = dedupe_window.map(|_| dedupe::fingerprint(scope, &req.input, &config));
+    if let Some(fingerprint) = &fingerprint {
+        let mut conn = redis_connection(&state).await?;
+        if let Some((original, result)) = dedupe::find_completed(&mut conn, fingerprint).await? {
+            state.cache_metrics.deduplicated.inc();
+            let source = ("deduplicated_from", original.as_str());
+            let response = answer_from(&mut conn, principal, req, config, source, &result).await?;
+            return Ok(Submitted {
+                response: result_signing::signed(response.0, state.result_signer.as_ref()),
+                cache_status: None,
+                age: None,
+            });
+        }
+    }
+
+    // Tasks needing a capability must have a live agent to run them
+    let forward_to = match &peer_origin {
+        Some(_) => None,
+        None => state.federation.route_for(&config),
+    };
+    let waiting = !req.depends_on.is_empty();
+    if waiting && forward_to.is_some() {
+        return Err(ApiError::unprocessable(
+            "dependencies_not_forwardable",
+            "Tasks with dependencies cannot be routed to a peer gateway",
+        ));
+    }
+    if let (Some(capability), None) = (&req.capability, forward_to) {
+        let mut conn = redis_connection(&state).await?;
+        if !agent_registry::capability_available(&mut conn, capability).await? {
+            warn!("Task {} needs capability {} with no live agent", req.task_id, capability);
+            return Err(ApiError::unprocessable(
+                "no_capable_agent",
+                format!("No live agent supports capability {}", capability),
+            ));
+        }
+    }
+
+    // Dependencies must exist and must not lead back to this task
+    if waiting {
+        let mut conn = redis_connection(&state).await?;
+        dependencies::check(&mut conn, &req.task_id, &req.depends_on).await?;
+    }
+
+    // Enforce the tenant's daily quotas; peers bill their own submitters
+    let admission = match peer_origin {
+        Some(_) => None,
+        None => {
+            let mut conn = redis_connection(&state).await?;
+            let principal = principal.as_ref().map(|Extension(p)| p);
+            let quotas = &state.config.current().quotas;
+            Some(usage::admit(&mut conn, principal, &req.task_id, quotas).await?)
+        }
+    };
+
+    let submitted = async {
+        // Have the result cached once it is first read back
+        if let Some((key, ttl)) = &cache_entry {
+            let mut conn = redis_connection(&state).await?;
+            cache::mark_pending(&mut conn, &req.task_id, key, *ttl).await?;
+        }
+
+        // Forward to a peer gateway when routing points elsewhere. Tasks that
+        // already arrived from a peer always run locally to avoid routing loops.
+        if let Some(Extension(PeerOrigin(origin))) = &peer_origin {
+            info!("Task {} received from peer {}", req.task_id, origin);
+        }
+        if let Some(peer) = forward_to {
+            let response = forward_task(&state, peer, req, config).await?;
+            return Ok(Submitted {
+                response: response.0,
+                cache_status,
+                age: None,
+            });
+        }
+
+        let queue = queue_for(req.capability.as_deref(), priority);
+        let queue = queue.as_str();
+
+        let mut conn = redis_connection(&state).await?;
+        let attachments = attachments::list(&mut conn, &req.task_id).await?;
+
+        // Create task in Redis
+        let deadline = watchdog::deadline(timeout_secs);
+        let mut task = serde_json::json!({
+            "input": req.input,
+            "config": config,
+            "status": if waiting { "waiting" } else { "pending" },
+            "priority": priority,
+            "capability": req.capability,
+            "timeout_secs": timeout_secs,
+            "deadline": deadline.to_rfc3339(),
+            "submitted_by": principal.as_ref().map(|Extension(p)| p.key_id.clone()),
+            "request_id": request_id::current().map(|id| id.0),
+            "attachments": attachments,
+            "labels": req.labels,
+            "created_at": chrono::Utc::now().to_rfc3339(),
+            "schema_version": schema::CURRENT_VERSION,
+        });
+        if waiting {
+            task["depends_on"] = req.depends_on.clone().into();
+        }
+        if let Some(parent) = &req.parent_task_id {
+            task["parent_task_id"] = parent.clone().into();
+        }
+        if let Some(moderation) = moderation {
+            task["moderation"] = moderation;
+        }
+        // Store and queue the task in one step; waiting tasks are queued by
+        // the dependency resolver
+        let new_task = NewTask {
+            task_id: &req.task_id,
+            record: envelope::encode(&task)?,
+            labels: &req.labels,
+            queue: (!waiting).then_some((queue, deadline)),
+            tracked: true,
+        };
+        state.task_queue.submit(&mut conn, new_task).await?;
+
+        let status = if waiting {
+            // Dependencies that already completed need not wait for the resolver
+            dependencies::hold(&mut conn, &req.task_id).await?;
+            let outcome = dependencies::resolve(&mut conn, &state.task_queue, &req.task_id)
+                .await
+                .map_err(|e| ApiError::internal(e.to_string()))?;
+            info!(
+                "Task {} submitted with {} dependencies: {:?}",
+                req.task_id,
+                req.depends_on.len(),
+                outcome
+            );
+            match outcome {
+                dependencies::Outcome::Waiting => "waiting",
+                dependencies::Outcome::Queued => "submitted",
+                dependencies::Outcome::Failed | dependencies::Outcome::Gone => "failed",
+            }
+        } else {
+            info!("Task {} submitted to {}", req.task_id, queue);
+            "submitted"
+        };
+
+        if let (Some(fingerprint), Some(window)) = (&fingerprint, dedupe_window) {
+            dedupe::remember(&mut conn, fingerprint, &req.task_id, window).await?;
+        }
+
+        Ok(Submitted {
+            response: AgentResponse {
+                task_id: req.task_id,
+                status: status.to_string(),
+                result: None,
+                error: None,
+                signature: None,
+            },
+            cache_status,
+            age: None,
+        })
+    }
+    .await;
+
+    // A submission that never reached an agent or peer is not billed
+    if let (Err(_), Some(admission)) = (&submitted, admission) {
+        match redis_connection(&state).await {
+            Ok(mut conn) => admission.refund(&mut conn).await,
+            Err(e) => error!("Failed to refund quota for task {}: {:?}", admission.task_id, e),
+        }
+    }
+    submitted
+}
+
+// Complete a submission with a copy of an earlier task's result. `source`
+// names the task record field pointing back at that task, and the task.
+async fn answer_from(
+    conn: &mut redis::aio::Connection,
+    principal: Option<Extension<Principal>>,
+    req: AgentRequest,
+    config: serde_json::Value,
+    source: (&str, &str),
+    result: &str,
+) -> Result<Json<AgentResponse>, ApiError> {
+    let (source_field, original) = source;
+    let mut task = serde_json::json!({
         "input": req.input,
         "config": config,
-        "status": "pending",
+        "submitted_by": principal.as_ref().map(|Extension(p)| p.key_id.clone()),
+        "request_id": request_id::current().map(|id| id.0),
+        "labels": req.labels,
         "created_at": chrono::Utc::now().to_rfc3339(),
-    }))
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let mut conn = state
-        .redis_client
-        .get_async_connection()
+        "schema_version": schema::CURRENT_VERSION,
+    });
+    task[source_field] = original.into();
+    dedupe::store_completed(conn, &req.task_id, task, result)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| match e.downcast::<QueueError>() {
+            Ok(e) => e.into(),
+            Err(e) => ApiError::internal(format!("Failed to store answered task: {}", e)),
+        })?;
+    labels::index(conn, &req.task_id, &req.labels).await?;
+    history::track(conn, &req.task_id).await?;
+    events::track(conn, &req.task_id).await?;
 
-    conn.set::<_, _, ()>(&task_key, task_value)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    info!("Task {} answered from task {}", req.task_id, original);
+
+    Ok(Json(AgentResponse {
+        task_id: req.task_id,
+        status: "completed".to_string(),
+        result: Some(envelope::decode(result)?),
+        error: None,
+        signature: None,
+    }))
+}
 
-    // Push to agent queue
-    conn.lpush::<_, _, ()>("agent:queue", req.task_id.clone())
+// Hand a task over to a peer gateway, keeping a local record for polling
+async fn forward_task(
+    state: &AppState,
+    peer: &federation::Peer,
+    req: AgentRequest,
+    config: serde_json::Value,
+) -> Result<Json<AgentResponse>, ApiError> {
+    let submission = serde_json::json!({
+        "task_id": req.task_id,
+        "input": req.input,
+        "config": config,
+        "capability": req.capability,
+        "timeout_seconds": req.timeout_seconds,
+        "labels": req.labels,
+    });
+    if let Err(e) = state
+        .federation
+        .forward(&state.redis_client, peer, &req.task_id, &submission)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    {
+        error!("Failed to forward task {} to {}: {}", req.task_id, peer.name, e);
+        return Err(ApiError::bad_gateway(format!(
+            "Peer gateway {} did not accept the task",
+            peer.name
+        )));
+    }
 
-    info!("Task {} submitted", req.task_id);
+    let task_value = envelope::encode(&serde_json::json!({
+        "input": req.input,
+        "config": config,
+        "status": "forwarded",
+        "peer": peer.name,
+        "request_id": request_id::current().map(|id| id.0),
+        "labels": req.labels,
+        "created_at": chrono::Utc::now().to_rfc3339(),
+        "schema_version": schema::CURRENT_VERSION,
+    }))?;
+
+    let mut conn = redis_connection(state).await?;
+    let new_task = NewTask {
+        task_id: &req.task_id,
+        record: task_value,
+        labels: &req.labels,
+        queue: None,
+        tracked: true,
+    };
+    state.task_queue.submit(&mut conn, new_task).await?;
 
     Ok(Json(AgentResponse {
         task_id: req.task_id,
-        status: "submitted".to_string(),
+        status: "forwarded".to_string(),
         result: None,
         error: None,
+        signature: None,
     }))
 }
 
-// Get task result
+// Get task result, encoded or rendered as the client accepts; with `wait`,
+// once it settles or the wait is over (202). Tasks submitted with an API
+// key are refused to callers that may not read them.
+async fn get_task(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    Query(query): Query<GetTaskQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let settled = |response: &AgentResponse| {
+        history::FINAL_STATUSES.contains(&response.status.as_str())
+    };
+    let format = Format::accepted(&headers);
+    let max_wait = state.config.current().tasks.max_wait_secs;
+    let wait = query.wait.map(|secs| Duration::from_secs(secs.min(max_wait)));
+    let mut receiver = wait.map(|_| state.result_waiters.listen());
+    let until = tokio::time::Instant::now() + wait.unwrap_or_default();
+
+    // Owned tasks are refused to other callers before anything of them is
+    // read, waited on or compared
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    annotations::check_reader(&mut redis_connection(&state).await?, principal, &task_id).await?;
+
+    let mut response = get_result(State(state.clone()), Path(task_id.clone())).await?.0;
+    if let Some(receiver) = &mut receiver {
+        while !settled(&response) && tokio::time::Instant::now() < until {
+            long_poll::changed(receiver, &task_id, until).await;
+            response = get_result(State(state.clone()), Path(task_id.clone())).await?.0;
+        }
+    }
+    let status = if wait.is_some() && !settled(&response) {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+
+    let mut conn = redis_connection(&state).await?;
+    let annotations = annotations::list(&mut conn, &response.task_id).await?;
+    let rendition = Rendition::accepted(&headers);
+    let content_type = rendition.map_or(format.content_type(), Rendition::content_type);
+    let etag = codec::etag(&(&response, &annotations), &response.status, content_type);
+    if codec::not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    if let Some(rendition) = rendition {
+        let body = Rendered(rendition.render(&response), rendition);
+        return Ok((status, [(header::ETAG, etag)], body).into_response());
+    }
+    let detail = TaskDetail {
+        response,
+        annotations,
+    };
+    Ok((status, [(header::ETAG, etag)], Encoded(detail, format)).into_response())
+}
+
+// Look up a task's state and result, signed when results are signed
 async fn get_result(
     State(state): State<AppState>,
     Path(task_id): Path<String>,
-) -> Result<Json<AgentResponse>, StatusCode> {
+) -> Result<Json<AgentResponse>, ApiError> {
+    let signer = state.result_signer.clone();
+    let Json(response) = lookup_result(state, task_id).await?;
+    Ok(Json(result_signing::signed(response, signer.as_ref())))
+}
+
+// Look up a task's state and result
+async fn lookup_result(state: AppState, task_id: String) -> Result<Json<AgentResponse>, ApiError> {
+    // The replica answers for what it has; anything else may have been
+    // written since it last caught up with the primary
+    if let Some(mut conn) = state.replica.connection().await {
+        match read_result(&state, &mut conn, &task_id, false).await {
+            Ok(Some(response)) => {
+                state.replica.hit();
+                return Ok(Json(response));
+            }
+            Ok(None) => state.replica.miss(&task_id),
+            Err(e) => {
+                warn!("Redis replica read of task {} failed: {}", task_id, e.message);
+                state.replica.miss(&task_id);
+            }
+        }
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    if let Some(response) = read_result(&state, &mut conn, &task_id, true).await? {
+        return Ok(Json(response));
+    }
+
+    // Tasks Redis has let go of may still be in the history table
+    if let Some(response) = read_history(&state, &task_id).await? {
+        return Ok(Json(response));
+    }
+
+    Err(ApiError::not_found(format!("Task {} not found", task_id)))
+}
+
+// Look up the states and results of many tasks in one round trip (up to
+// `limits.max_bulk_results`), signed when results are signed. Tasks the
+// caller may not read are reported missing, like unknown ones.
+async fn get_results(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    body: Result<Json<BulkResultsRequest>, JsonRejection>,
+) -> Result<Json<BulkResultsResponse>, ApiError> {
+    let Json(req) = body.map_err(ValidationError::from)?;
+    let max = state.config.current().limits.max_bulk_results;
+    if req.task_ids.is_empty() || req.task_ids.len() > max {
+        return Err(ApiError::bad_request(
+            "invalid_task_ids",
+            format!("task_ids must list 1-{} tasks", max),
+        ));
+    }
+
+    let keys = |key: fn(&str) -> String| req.task_ids.iter().map(|id| key(id)).collect::<Vec<_>>();
+    let mut pipe = redis::pipe();
+    pipe.mget(keys(|id| format!("result:{}", id)))
+        .mget(keys(failures::error_key))
+        .mget(keys(|id| format!("task:{}", id)));
+    for task_id in &req.task_ids {
+        cache::is_pending_in(&mut pipe, task_id);
+    }
+    let mut conn = redis_connection(&state).await?;
+    let (results, reports, tasks, pending): BulkRead = pipe
+        .query_async(&mut conn)
+        .instrument(telemetry::redis_span("MGET", "result:*"))
+        .await?;
+
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for (i, task_id) in req.task_ids.iter().enumerate() {
+        let task = tasks[i].as_deref().map(schema::decode_task).transpose()?;
+        if task.is_some_and(|t| !attachments::may_read(principal, t["submitted_by"].as_str())) {
+            missing.push(task_id.clone());
+            continue;
+        }
+        if let (Some(result), true) = (&results[i], pending[i]) {
+            match cache::populate(&mut conn, task_id, result).await {
+                Ok(true) => state.cache_metrics.stored.inc(),
+                Ok(false) => {}
+                Err(e) => warn!("Failed to cache result of task {}: {}", task_id, e),
+            }
+        }
+        let stored = (results[i].as_deref(), reports[i].as_deref(), tasks[i].as_deref());
+        let response = match response_from(task_id, stored.0, stored.1, stored.2)? {
+            Some(response) => Some(response),
+            None => read_history(&state, task_id).await?,
+        };
+        match response {
+            Some(response) => {
+                found.push(result_signing::signed(response, state.result_signer.as_ref()))
+            }
+            None => missing.push(task_id.clone()),
+        }
+    }
+    Ok(Json(BulkResultsResponse {
+        results: found,
+        missing,
+    }))
+}
+
+/// Stored results, error reports, task records and pending cache markers of
+/// a bulk read
+type BulkRead = (Vec<Option<String>>, Vec<Option<String>>, Vec<Option<String>>, Vec<bool>);
+
+// A task's state as the history table has it, when there is one
+async fn read_history(state: &AppState, task_id: &str) -> Result<Option<AgentResponse>, ApiError> {
+    let Some(history) = &state.history else {
+        return Ok(None);
+    };
+    let Some(entry) = history.get(task_id).await.map_err(history::unavailable)? else {
+        return Ok(None);
+    };
+    // The table keeps a failed task's error report as its result
+    let error = (entry.status == "failed")
+        .then(|| entry.result.as_ref().map(failures::message))
+        .flatten();
+    Ok(Some(AgentResponse {
+        task_id: task_id.to_string(),
+        status: entry.status,
+        result: entry.result,
+        error,
+        signature: None,
+    }))
+}
+
+// A task's state and result as Redis has them, read through `conn`; only
+// the primary's connection takes writes
+async fn read_result(
+    state: &AppState,
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    primary: bool,
+) -> Result<Option<AgentResponse>, ApiError> {
     let result_key = format!("result:{}", task_id);
     let task_key = format!("task:{}", task_id);
 
-    let mut conn = state
-        .redis_client
-        .get_async_connection()
+    // Check if result exists
+    let result = conn
+        .get::<_, String>(&result_key)
+        .instrument(telemetry::redis_span("GET", &result_key))
+        .await
+        .ok();
+    if let Some(result) = &result {
+        // The cache is written on the primary
+        let mut writer = None;
+        if !primary && cache::is_pending(conn, task_id).await? {
+            writer = Some(redis_connection(state).await?);
+        }
+        if primary || writer.is_some() {
+            match cache::populate(writer.as_mut().unwrap_or(conn), task_id, result).await {
+                Ok(true) => state.cache_metrics.stored.inc(),
+                Ok(false) => {}
+                Err(e) => warn!("Failed to cache result of task {}: {}", task_id, e),
+            }
+        }
+        return response_from(task_id, Some(result.as_str()), None, None);
+    }
+
+    // Check if the agent reported a failure
+    let error_key = failures::error_key(task_id);
+    let report: Option<String> = conn
+        .get(&error_key)
+        .instrument(telemetry::redis_span("GET", &error_key))
+        .await?;
+    if report.is_some() {
+        return response_from(task_id, None, report.as_deref(), None);
+    }
+
+    // Check if task exists
+    let task = conn
+        .get::<_, String>(&task_key)
+        .instrument(telemetry::redis_span("GET", &task_key))
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .ok();
+    response_from(task_id, None, None, task.as_deref())
+}
 
-    // Check if result exists
-    if let Some(result) = conn.get::<_, String>(&result_key).await.ok() {
-        let value: serde_json::Value =
-            serde_json::from_str(&result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        return Ok(Json(AgentResponse {
-            task_id,
+// A task's state from what Redis holds for it: its stored result, else the
+// agent's error report, else its record
+fn response_from(
+    task_id: &str,
+    result: Option<&str>,
+    report: Option<&str>,
+    task: Option<&str>,
+) -> Result<Option<AgentResponse>, ApiError> {
+    if let Some(result) = result {
+        let value = envelope::decode(result)?;
+        return Ok(Some(AgentResponse {
+            task_id: task_id.to_string(),
             status: "completed".to_string(),
             result: Some(value),
             error: None,
+            signature: None,
         }));
     }
 
-    // Check if task exists
-    if let Some(task) = conn.get::<_, String>(&task_key).await.ok() {
-        let value: serde_json::Value =
-            serde_json::from_str(&task).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(report) = report {
+        let report = envelope::decode(report)?;
+        return Ok(Some(AgentResponse {
+            task_id: task_id.to_string(),
+            status: "failed".to_string(),
+            error: Some(failures::message(&report)),
+            result: Some(report),
+            signature: None,
+        }));
+    }
+
+    if let Some(task) = task {
+        let value = schema::decode_task(task)?;
         let status = value["status"].as_str().unwrap_or("unknown").to_string();
-        return Ok(Json(AgentResponse {
-            task_id,
+        if status == "failed" {
+            let report = failures::from_record(&value);
+            return Ok(Some(AgentResponse {
+                task_id: task_id.to_string(),
+                status,
+                error: Some(failures::message(&report)),
+                result: Some(report),
+                signature: None,
+            }));
+        }
+        return Ok(Some(AgentResponse {
+            task_id: task_id.to_string(),
             status,
             result: None,
             error: None,
+            signature: None,
         }));
     }
 
-    Err(StatusCode::NOT_FOUND)
+    Ok(None)
 }
 
-// Helper functions
-async fn check_redis_connection(redis_client: &Client) -> bool {
-    match redis_client.get_async_connection().await {
-        Ok(mut conn) => redis::cmd("PING").query_async::<_, String>(&mut conn).await.is_ok(),
-        Err(_) => false,
+// List tasks with truncated result previews; keys that may not read every
+// task only see their own and anonymous ones
+async fn list_tasks(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Query(query): Query<ListTasksQuery>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<ListTasksResponse>, ApiError> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let preview_chars = query.preview.unwrap_or(state.config.current().tasks.preview_chars);
+
+    // `label` may repeat, so it is read from the raw pairs
+    let label_filters = params
+        .iter()
+        .filter(|(name, _)| name == "label")
+        .map(|(_, filter)| {
+            labels::parse_filter(filter).ok_or_else(|| {
+                ApiError::bad_request(
+                    "invalid_label",
+                    format!("label filter {} is not key:value", filter),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Filtered listings come from the history table, which keeps every task
+    if query.from.is_some() || query.to.is_some() || query.status.is_some() {
+        if !label_filters.is_empty() {
+            return Err(ApiError::bad_request(
+                "unsupported_filter",
+                "label cannot be combined with from, to or status",
+            ));
+        }
+        let Some(history) = &state.history else {
+            return Err(ApiError::bad_request(
+                "history_disabled",
+                "from, to and status need task history (history.database_url)",
+            ));
+        };
+        let reader = principal.as_ref().map(|Extension(p)| p);
+        return list_history(history, query, limit, preview_chars, reader).await;
+    }
+
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    let mut conn = redis_connection(&state).await?;
+
+    // Labelled listings page through the label sets' intersection, where
+    // `cursor` is an offset
+    if !label_filters.is_empty() {
+        let matching = labels::find(&mut conn, &label_filters).await?;
+        let offset = query.cursor.unwrap_or(0) as usize;
+        let next_cursor = (matching.len() > offset + limit).then_some((offset + limit) as u64);
+        let task_ids: Vec<String> = matching.into_iter().skip(offset).take(limit).collect();
+        let (tasks, gone) = summarize(&mut conn, task_ids, preview_chars, principal).await?;
+        labels::forget(&mut conn, &label_filters, &gone).await?;
+        return Ok(Json(ListTasksResponse { tasks, next_cursor }));
+    }
+
+    // Page through task keys; SCAN counts are hints, so keep going until we
+    // have a full page or the cursor wraps around
+    let mut cursor = query.cursor.unwrap_or(0);
+    let mut task_ids = Vec::new();
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("task:*")
+            .arg("COUNT")
+            .arg(limit * 4)
+            .query_async(&mut conn)
+            .await?;
+        task_ids.extend(
+            keys.iter()
+                .filter_map(|k| k.strip_prefix("task:"))
+                .filter(|id| !id.contains(':'))
+                .map(str::to_string),
+        );
+        cursor = next;
+        if cursor == 0 || task_ids.len() >= limit {
+            break;
+        }
+    }
+    task_ids.truncate(limit);
+
+    let (tasks, _) = summarize(&mut conn, task_ids, preview_chars, principal).await?;
+    Ok(Json(ListTasksResponse {
+        tasks,
+        next_cursor: (cursor != 0).then_some(cursor),
+    }))
+}
+
+// Summaries of the tasks with the given ids that `principal` may read, and
+// the ids whose record is gone
+async fn summarize(
+    conn: &mut redis::aio::Connection,
+    task_ids: Vec<String>,
+    preview_chars: usize,
+    principal: Option<&Principal>,
+) -> Result<(Vec<TaskSummary>, Vec<String>), ApiError> {
+    if task_ids.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
     }
+
+    let task_keys: Vec<String> = task_ids.iter().map(|id| format!("task:{}", id)).collect();
+    let result_keys: Vec<String> = task_ids.iter().map(|id| format!("result:{}", id)).collect();
+    let tasks: Vec<Option<String>> = conn.mget(&task_keys).await?;
+    let results: Vec<Option<String>> = if preview_chars > 0 {
+        conn.mget(&result_keys).await?
+    } else {
+        vec![None; task_ids.len()]
+    };
+
+    let mut gone = Vec::new();
+    let summaries = task_ids
+        .into_iter()
+        .zip(tasks)
+        .zip(results)
+        .filter_map(|((task_id, task), result)| {
+            let Some(task) = task else {
+                gone.push(task_id);
+                return None;
+            };
+            let task = schema::decode_task(&task).ok()?;
+            if !attachments::may_read(principal, task["submitted_by"].as_str()) {
+                return None;
+            }
+            let status = if result.is_some() {
+                "completed".to_string()
+            } else {
+                task["status"].as_str().unwrap_or("unknown").to_string()
+            };
+            Some(TaskSummary {
+                task_id,
+                status,
+                created_at: task["created_at"].as_str().map(str::to_string),
+                labels: task
+                    .get("labels")
+                    .filter(|l| l.as_object().is_some_and(|l| !l.is_empty()))
+                    .cloned(),
+                preview: result.map(|r| result_preview(&r, preview_chars)),
+            })
+        })
+        .collect();
+
+    Ok((summaries, gone))
+}
+
+// List tasks from the history table; `cursor` is an offset there
+async fn list_history(
+    history: &TaskHistory,
+    query: ListTasksQuery,
+    limit: usize,
+    preview_chars: usize,
+    principal: Option<&Principal>,
+) -> Result<Json<ListTasksResponse>, ApiError> {
+    let offset = query.cursor.unwrap_or(0);
+    let filter = HistoryFilter {
+        from: query.from,
+        to: query.to,
+        status: query.status,
+        // Matches `attachments::may_read`
+        reader: match principal {
+            Some(p) if p.role.reads_all() => None,
+            principal => Some(principal.map(|p| p.key_id.clone())),
+        },
+        limit,
+        offset,
+    };
+    let entries = history.list(&filter).await.map_err(history::unavailable)?;
+
+    let next_cursor = (entries.len() == limit).then_some(offset + limit as u64);
+    let tasks = entries
+        .into_iter()
+        .map(|entry| TaskSummary {
+            task_id: entry.task_id,
+            status: entry.status,
+            created_at: entry.created_at.map(|t| t.to_rfc3339()),
+            labels: None,
+            preview: entry
+                .result
+                .filter(|_| preview_chars > 0)
+                .map(|r| result_preview(&r.to_string(), preview_chars)),
+        })
+        .collect();
+    Ok(Json(ListTasksResponse { tasks, next_cursor }))
 }
 
-async fn validate_request(req: &AgentRequest) -> Result<(), String> {
-    // Validate task_id format
-    if req.task_id.is_empty() {
-        return Err("task_id cannot be empty".to_string());
+// Helper functions
+impl Submitted {
+    fn respond(self, format: Format) -> Response {
+        let mut response = Encoded(self.response, format).into_response();
+        let headers = response.headers_mut();
+        if let Some(status) = self.cache_status {
+            headers.insert(
+                cache::CACHE_STATUS_HEADER,
+                HeaderValue::from_static(status.as_str()),
+            );
+        }
+        if let Some(age) = self.age {
+            headers.insert(header::AGE, HeaderValue::from(age));
+        }
+        response
     }
+}
+
+fn result_preview(raw: &str, max_chars: usize) -> ResultPreview {
+    // Agents store `{"result": "..."}`; preview the text itself when present
+    let value =
+        envelope::decode(raw).unwrap_or_else(|_| serde_json::Value::String(raw.to_string()));
+    let text = match value.get("result").unwrap_or(&value) {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
 
-    // Validate input
-    if req.input.is_null() {
-        return Err("input cannot be null".to_string());
+    let truncated = text.chars().count() > max_chars;
+    ResultPreview {
+        text: text.chars().take(max_chars).collect(),
+        truncated,
+        size: raw.len(),
     }
+}
 
-    Ok(())
+async fn redis_connection(state: &AppState) -> Result<redis::aio::Connection, ApiError> {
+    let conn = state
+        .retry
+        .redis
+        .run("Redis connect", || state.redis_client.get_async_connection())
+        .instrument(tracing::info_span!("redis.connect"))
+        .await?;
+    Ok(conn)
+}
+
+async fn check_redis_connection(redis_client: &Client) -> bool {
+    match redis_client.get_async_connection().await {
+        Ok(mut conn) => redis::cmd("PING").query_async::<_, String>(&mut conn).await.is_ok(),
+        Err(_) => false,
+    }
 }
 
 async fn get_config(
-    redis_client: &Client,
+    state: &AppState,
     user_config: &Option<serde_json::Value>,
-) -> Result<serde_json::Value, String> {
-    let mut conn = redis_client
-        .get_async_connection()
-        .await
-        .map_err(|e| format!("Redis connection error: {}", e))?;
+) -> Result<serde_json::Value, ApiError> {
+    let mut conn = redis_connection(state).await?;
 
-    // Get default config
-    let default_config: String = conn
-        .get("config:default")
+    // Get the shared and API default configs
+    let mut config = agent_config::defaults(&mut conn, "http")
         .await
-        .unwrap_or_else(|_| "{}".to_string());
-    let mut config: serde_json::Value =
-        serde_json::from_str(&default_config).unwrap_or_else(|_| serde_json::json!({}));
+        .map_err(|e| ApiError::internal(format!("Invalid default config: {}", e)))?;
 
     // Merge with user config
     if let Some(user_cfg) = user_config {
-        merge_json(&mut config, user_cfg);
+        merge::merge(&mut config, user_cfg)
+            .map_err(|e| ApiError::bad_request("invalid_config", e.to_string()))?;
     }
 
     Ok(config)
 }
 
-fn merge_json(target: &mut serde_json::Value, source: &serde_json::Value) {
-    if let (Some(t_obj), Some(s_obj)) = (target.as_object_mut(), source.as_object()) {
-        for (key, value) in s_obj {
-            if t_obj.contains_key(key) {
-                if t_obj[key].is_object() && value.is_object() {
-                    merge_json(&mut t_obj[key], value);
-                } else {
-                    t_obj[key] = value.clone();
-                }
-            }
-        }
-    }
+// Fallback for unknown routes
+async fn route_not_found() -> ApiError {
+    ApiError::not_found("No such route")
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "secure_gateway=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    // Initialize tracing and, when configured, OTLP export
+    let telemetry = telemetry::init()?;
+
+    // Load configuration from defaults, the config file and the environment
+    let config = Arc::new(Config::load()?);
+    let server_config = server::ServerConfig::from_config(&config.server)?;
 
-    // Load environment variables
-    let redis_host = std::env::var("REDIS_HOST").unwrap_or_else(|_| "redis".to_string());
-    let redis_port = std::env::var("REDIS_PORT").unwrap_or_else(|_| "6379".to_string());
-    let redis_password = std::env::var("REDIS_PASSWORD").unwrap_or_else(|_| "default".to_string());
-    let telegram_bot_token = std::env::var("TELEGRAM_BOT_TOKEN").ok();
+    // Choose the storage envelope version before anything writes records
+    envelope::set_write_version(config.tasks.envelope_version);
+    if let Some(secret) = &config.encryption.secret {
+        let encryption = &config.encryption;
+        envelope::set_keys(&encryption.key_id, secret, &encryption.previous_secrets)
+            .map_err(anyhow::Error::msg)?;
+        info!("Encrypting task records with key {}", encryption.key_id);
+    }
+    redaction::install(&config.redaction).map_err(anyhow::Error::msg)?;
 
     // Create Redis client
-    let redis_url = format!(
-        "redis://:{}@{}:{}",
-        redis_password, redis_host, redis_port
-    );
-    let redis_client = Arc::new(Client::open(redis_url)?);
+    let redis_client = Arc::new(Client::open(config.redis.url())?);
+
+    // Serve result reads from a replica when one is configured
+    let replica = ReadReplica::from_config(&config.redis)?;
+    if replica.is_enabled() {
+        info!("Reading task results from the Redis replica");
+    }
+
+    // Layer runtime overrides from Redis and follow their changes
+    let runtime = Arc::new(RuntimeConfig::new(config.clone()));
+    runtime::start_runtime_watcher(redis_client.clone(), runtime.clone());
+
+    // Connect the backend handing tasks to agents
+    let task_queue = TaskQueue::connect(&config.queue).await?;
+    info!("Task queue backend: {}", task_queue.backend().as_str());
+
+    // Mirror tasks into PostgreSQL for long-term history
+    let history = match &config.history.database_url {
+        Some(url) => {
+            let history = Arc::new(TaskHistory::connect(&config.history, url).await?);
+            history::start_history_sync(redis_client.clone(), history.clone(), &config.history);
+            Some(history)
+        }
+        None => None,
+    };
+
+    // Hold submissions on this gateway while Redis is down
+    let buffer = match config.buffer.enabled {
+        true => Some(Arc::new(SubmissionBuffer::open(&config.buffer)?)),
+        false => None,
+    };
+
+    // Keep task attachments in Redis or object storage
+    let attachments = AttachmentStore::from_config(&config.attachments)?;
+    info!("Attachment backend: {}", attachments.backend().as_str());
+
+    // Retry policies shared by every subsystem
+    let retry = RetryPolicies::from_config(&config.retry);
+
+    // Watch Redis memory and tighten admission as it fills up
+    let memory_guard = Arc::new(MemoryGuard::from_config(&config.memory_guard));
+    memory_guard::start_memory_guard(redis_client.clone(), memory_guard.clone());
 
-    // Start Telegram adaptor if bot token is provided
-    if let Some(_) = telegram_bot_token {
-        info!("Starting Telegram adaptor");
-        telegram::start_telegram_adaptor(redis_client.clone());
+    // Shed submissions while the agent queue is backed up
+    let queue_guard = Arc::new(QueueGuard::from_config(&config.backpressure));
+    backpressure::start_queue_guard(redis_client.clone(), task_queue.clone(), queue_guard.clone());
+
+    // Render channel replies with the templates stored in Redis
+    let templates = ReplyTemplates::new(&config.templates);
+    templates::start_template_reload(redis_client.clone(), templates.clone());
+
+    // Restart channel adaptors that crash
+    let supervisor = Arc::new(Supervisor::new(retry.adaptor.clone(), &config.supervisor));
+
+    // Start a Telegram adaptor for each bot configured, each with metrics
+    // and a breaker of its own
+    let bots = telegram::Bot::all(&config.telegram);
+    if !bots.is_empty() {
+        info!("Starting Telegram adaptors for {} bots", bots.len());
+        for bot in &bots {
+            telegram::start_telegram_adaptor(
+                &supervisor,
+                redis_client.clone(),
+                replica.clone(),
+                bot.clone(),
+                retry.clone(),
+                task_queue.clone(),
+                memory_guard.clone(),
+                queue_guard.clone(),
+                runtime.clone(),
+                templates.clone(),
+            );
+        }
     } else {
-        info!("TELEGRAM_BOT_TOKEN not set, Telegram adaptor disabled");
+        info!("No Telegram bot token set, Telegram adaptor disabled");
+    }
+
+    // Start the email adaptor if an IMAP server is configured
+    #[cfg(feature = "email")]
+    if config.email.imap_host.is_some() {
+        info!("Starting email adaptor");
+        email::start_email_adaptor(
+            &supervisor,
+            redis_client.clone(),
+            retry.clone(),
+            task_queue.clone(),
+            memory_guard.clone(),
+            queue_guard.clone(),
+            runtime.clone(),
+            templates.clone(),
+        );
+    }
+
+    // Answer Twilio SMS and WhatsApp messages if an account is configured
+    let twilio = Twilio::from_config(&config.twilio, retry.delivery.clone())?;
+    if twilio.is_enabled() {
+        info!("Starting Twilio adaptor");
+        twilio::start_delivery(
+            &supervisor,
+            redis_client.clone(),
+            twilio.clone(),
+            runtime.clone(),
+            templates.clone(),
+        );
     }
 
+    // Roll up per-tenant usage as tasks finish
+    usage::start_usage_accounting(redis_client.clone(), &config.quotas);
+
+    // Orphan tasks held by agents whose heartbeats stopped
+    let agent_metrics = Arc::new(AgentMetrics::default());
+    agent_registry::start_orphan_sweep(
+        redis_client.clone(),
+        &config.agents,
+        agent_metrics.clone(),
+    );
+
+    // Requeue claimed tasks whose lease lapsed
+    leases::start_lease_sweep(
+        redis_client.clone(),
+        &config.agents,
+        agent_metrics.clone(),
+    );
+
+    // Answer queued tasks without agents
+    if config.server.mode == config::GatewayMode::Simulate {
+        simulation::start_simulator(redis_client.clone(), &config.simulation);
+    }
+
+    // Settle map tasks once their children do
+    fanout::start_map_aggregator(redis_client.clone(), runtime.clone());
+
+    // Queue waiting tasks once their dependencies complete
+    dependencies::start_dependency_resolver(
+        redis_client.clone(),
+        task_queue.clone(),
+        runtime.clone(),
+    );
+
+    // Time out tasks that outlive their deadline
+    let watchdog_metrics = Arc::new(WatchdogMetrics::default());
+    watchdog::start_watchdog(
+        redis_client.clone(),
+        task_queue.clone(),
+        runtime.clone(),
+        retry.delivery.clone(),
+        retry.task.clone(),
+        watchdog_metrics.clone(),
+    )?;
+
+    // Export task lifecycle events to Kafka and fan them out to subscribers
+    let event_metrics = Arc::new(EventMetrics::default());
+    let mut event_sinks = Vec::new();
+    if !config.events.brokers.is_empty() {
+        event_sinks.push(events::start_event_export(
+            &config.events,
+            retry.delivery.clone(),
+            event_metrics.clone(),
+        ));
+    }
+    let (subscriptions, subscription_sink) = subscriptions::start_hub(
+        redis_client.clone(),
+        &config.subscriptions,
+        retry.delivery.clone(),
+    );
+    event_sinks.push(subscription_sink);
+    events::start_event_tracking(redis_client.clone(), &config.events, event_sinks);
+
+    // Wake long-polling result reads as tasks settle
+    let result_waiters = ResultWaiters::start(redis_client.clone());
+
+    // Load federation peers and start relaying their results
+    let federation = Federation::from_config(&config.federation, retry.delivery.clone())?;
+    if federation.is_enabled() {
+        info!("Federation enabled");
+        federation::start_relay(redis_client.clone(), federation.clone());
+    }
+
+    let result_signer = ResultSigner::from_config(&config.result_signing)?;
+    if let Some(signer) = &result_signer {
+        info!("Signing task results with key {}", signer.key_id());
+    }
+
+    let moderator = Arc::new(Moderator::from_config(&config.moderation)?);
+
+    // Time no-op tasks through the agents to catch a wedged fleet
+    let canary_metrics = Arc::new(CanaryMetrics::default());
+    if config.canary.enabled {
+        canary::start_canary(
+            redis_client.clone(),
+            task_queue.clone(),
+            &config.canary,
+            canary_metrics.clone(),
+        );
+    }
+
+    let cache_metrics = Arc::new(CacheMetrics::default());
+    let timeout_metrics = Arc::new(TimeoutMetrics::default());
+    let concurrency_metrics = Arc::new(ConcurrencyMetrics::default());
+    telemetry.register_metrics(
+        memory_guard.clone(),
+        bots.iter()
+            .map(|bot| (bot.label().to_string(), bot.metrics().clone()))
+            .collect(),
+        cache_metrics.clone(),
+        queue_guard.clone(),
+        agent_metrics.clone(),
+        watchdog_metrics.clone(),
+        event_metrics.clone(),
+        replica.metrics(),
+        canary_metrics.clone(),
+        timeout_metrics.clone(),
+        concurrency_metrics.clone(),
+    );
+
     // Create app state
-    let state = AppState { redis_client };
+    let state = AppState {
+        redis_client,
+        replica,
+        task_queue,
+        history,
+        buffer,
+        attachments,
+        federation,
+        retry,
+        config: runtime,
+        telegram_bots: bots,
+        supervisor,
+        twilio,
+        memory_guard,
+        queue_guard,
+        cache_metrics,
+        agent_metrics,
+        event_metrics,
+        subscriptions,
+        result_waiters,
+        watchdog_metrics,
+        canary_metrics,
+        timeout_metrics,
+        result_signer,
+        moderator,
+        templates,
+    };
+
+    // Submit the steps of pipeline runs as the previous ones complete
+    pipelines::start_pipeline_orchestrator(state.clone());
+
+    // Submit what was buffered once Redis is back
+    if let Some(buffer) = &state.buffer {
+        let interval_secs = config.buffer.flush_interval_secs;
+        buffer::start_buffer_flush(state.clone(), buffer.clone(), interval_secs);
+    }
+
+    // Follow maintenance started on any gateway
+    maintenance::start_maintenance_sync(state.redis_client.clone(), state.task_queue.clone());
+
+    // Admin routes
+    let admin = Router::new()
+        .route("/admin/telegram/health", get(telegram_health))
+        .route(
+            "/admin/telegram/undeliverable",
+            get(telegram::list_undeliverable),
+        )
+        .route(
+            "/admin/telegram/undeliverable/:task_id",
+            delete(telegram::dismiss_undeliverable),
+        )
+        .route("/admin/support-bundle", post(support::support_bundle))
+        .route("/admin/broadcast", post(broadcast::start_broadcast))
+        .route("/admin/feedback", get(feedback::feedback_totals))
+        .route(
+            "/admin/broadcast/:broadcast_id",
+            get(broadcast::broadcast_status),
+        )
+        .route(
+            "/admin/config/default",
+            get(agent_config::get_default_config).put(agent_config::put_default_config),
+        )
+        .route(
+            "/admin/config/default/history",
+            get(agent_config::default_config_history),
+        )
+        .route(
+            "/admin/config/default/rollback",
+            post(agent_config::rollback_default_config),
+        )
+        .route(
+            "/admin/maintenance",
+            get(maintenance::get_maintenance)
+                .put(maintenance::start_maintenance)
+                .delete(maintenance::end_maintenance),
+        )
+        .route(
+            "/admin/config/channel/:channel",
+            get(agent_config::get_channel_config)
+                .put(agent_config::put_channel_config)
+                .delete(agent_config::delete_channel_config),
+        )
+        .route(
+            "/admin/purge",
+            get(retention::purge_status).post(retention::start_purge),
+        )
+        .route("/admin/overview", get(dashboard::overview))
+        .route(
+            "/admin/tasks/:task_id/requeue",
+            post(dashboard::requeue_task),
+        )
+        .route("/admin/templates", get(templates::list_templates))
+        .route(
+            "/admin/templates/:channel",
+            get(templates::get_template)
+                .put(templates::put_template)
+                .delete(templates::delete_template),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_admin,
+        ));
+
+    // Agent registry and task reporting routes, authorized per handler by role
+    let agents = Router::new()
+        .route("/agents", get(agent_registry::list_agents))
+        .route("/agents/register", post(agent_registry::register_agent))
+        .route(
+            "/agents/:agent_id/heartbeat",
+            post(agent_registry::agent_heartbeat),
+        )
+        .route("/internal/task/:task_id/start", post(agent_api::start_task))
+        .route(
+            "/internal/task/:task_id/progress",
+            post(agent_api::report_progress),
+        )
+        .route(
+            "/internal/task/:task_id/result",
+            post(agent_api::report_result),
+        )
+        .route("/internal/task/:task_id/error", post(agent_api::report_error))
+        .route("/internal/work/claim", post(leases::claim_work))
+        .route(
+            "/internal/work/:task_id/extend",
+            post(leases::extend_lease),
+        )
+        .route(
+            "/internal/work/:task_id/complete",
+            post(leases::complete_work),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ));
+
+    // Task event subscriptions, scoped to their owners
+    let subscriptions = Router::new()
+        .route(
+            "/subscriptions",
+            get(subscriptions::list_subscriptions).post(subscriptions::create_subscription),
+        )
+        .route(
+            "/subscriptions/:id",
+            delete(subscriptions::delete_subscription),
+        )
+        .route(
+            "/subscriptions/:id/events",
+            get(subscriptions::stream_events),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ));
+
+    // Pipeline definitions and runs, scoped to their owners
+    let pipelines = Router::new()
+        .route("/pipelines", post(pipelines::create_pipeline))
+        .route("/pipelines/:id", get(pipelines::get_pipeline))
+        .route("/pipelines/:id/run", post(pipelines::run_pipeline))
+        .route(
+            "/pipelines/:id/runs/:run_id",
+            get(pipelines::get_run),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ));
+
+    // Serve the gRPC API on its own port, under the same address rules
+    let client_filter = Arc::new(IpFilter::from_config(&config.ip_filter));
+    #[cfg(feature = "grpc")]
+    if let Some(port) = config.grpc.port {
+        let addr = std::net::SocketAddr::new(server_config.addr.ip(), port);
+        grpc::start_grpc_server(state.clone(), addr, client_filter.clone());
+    }
 
     // Build router
     let app = Router::new()
         .route("/health", get(health_check))
-        .route("/task", post(submit_task))
-        .route("/task/:task_id", get(get_result))
-        .layer(CorsLayer::permissive())
+        .route(
+            "/health/detailed",
+            get(health::health_detailed).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route("/healthz", get(liveness))
+        .route("/version", get(health::version))
+        .route(
+            "/.well-known/claw-signing-key",
+            get(result_signing::signing_key),
+        )
+        .route("/readyz", get(readiness))
+        // The page holds no data; it calls the admin routes with a token
+        .route("/admin/ui", get(dashboard::dashboard_page))
+        .route(
+            "/task",
+            post(submit_task)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    federation::verify_peer_signature,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    signing::verify_request_signature,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::authenticate,
+                )),
+        )
+        .route(
+            "/task/:task_id",
+            get(get_task)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::authenticate,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    federation::verify_peer_signature,
+                ))
+                .merge(delete(retention::delete_task).layer(
+                    middleware::from_fn_with_state(state.clone(), auth::authenticate),
+                )),
+        )
+        .route(
+            "/task/:task_id/annotations",
+            get(annotations::list_annotations)
+                .post(annotations::add_annotation)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::authenticate,
+                )),
+        )
+        .route(
+            "/task/:task_id/feedback",
+            get(feedback::list_feedback)
+                .post(feedback::add_feedback)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::authenticate,
+                )),
+        )
+        .route(
+            "/task/:task_id/rerun",
+            post(rerun::rerun_task).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/task/:task_id/reruns",
+            get(rerun::list_reruns).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/task/:task_id/logs",
+            get(task_logs::get_logs).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/task/:task_id/result/raw",
+            get(results::get_raw_result).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/task/:task_id/attachments",
+            post(attachments::upload_attachments)
+                .layer(DefaultBodyLimit::disable())
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::authenticate,
+                )),
+        )
+        .route(
+            "/task/:task_id/attachments/:attachment_id",
+            get(attachments::get_attachment).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/usage",
+            get(usage::get_usage).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/tasks",
+            get(list_tasks).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/tasks/diff",
+            get(diff::diff_tasks).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route(
+            "/tasks/results",
+            post(get_results).layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            )),
+        )
+        .route("/twilio/webhook", post(twilio::receive_message))
+        .merge(agents)
+        .merge(subscriptions)
+        .merge(pipelines)
+        .merge(admin)
+        .fallback(route_not_found)
+        .layer(DefaultBodyLimit::max(config.limits.max_body_bytes))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(cors::CorsPolicies::from_config(&config.cors)),
+            cors::apply,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(ConcurrencyLimits::from_config(
+                &config.concurrency,
+                concurrency_metrics,
+            )),
+            concurrency::limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            client_filter,
+            ip_filter::filter_clients,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            timeouts::enforce,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            access_log::log_requests,
+        ))
+        .layer(middleware::from_fn(request_id::propagate))
         .with_state(state);
 
     // Start server
-    let listener = TcpListener::bind("0.0.0.0:8080").await?;
-    info!("Secure Gateway listening on 0.0.0.0:8080");
-
-    axum::serve(listener, app).await?;
+    server::serve(app, server_config).await?;
 
     Ok(())
 }
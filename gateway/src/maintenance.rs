@@ -0,0 +1,265 @@
+//! Maintenance mode.
+//!
+//! `PUT /admin/maintenance` with `{"message": "...", "hold": false}` stops
+//! taking new work so agents can be upgraded: agents keep draining their
+//! queues, but `POST /task` answers 503 (`maintenance`) and the channel
+//! adaptors tell senders to come back later. With `"hold": true` new tasks
+//! are stored as usual and parked in the Redis list `maintenance:held`
+//! instead of being queued. `DELETE /admin/maintenance` resumes: held tasks
+//! are queued in the order they arrived, with their full timeout ahead of
+//! them. `GET /health` and Telegram replies carry the message meanwhile.
+//!
+//! The notice is kept in Redis under `maintenance`; gateways pick up
+//! changes made elsewhere within [`SYNC_INTERVAL`], and release tasks a
+//! gateway held after another one resumed.
+
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::queue::TaskQueue;
+use crate::validation::ValidationError;
+use crate::{redis_connection, task_state, watchdog, AppState};
+
+/// Key holding the maintenance notice while maintenance is on
+const NOTICE_KEY: &str = "maintenance";
+
+/// Redis list of tasks held back during maintenance, oldest first
+const HELD_KEY: &str = "maintenance:held";
+
+/// How often the notice is reread from Redis
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Message shown when the notice names none
+const DEFAULT_MESSAGE: &str = "The gateway is down for maintenance";
+
+/// The notice in force on this gateway
+static NOTICE: RwLock<Option<Notice>> = RwLock::new(None);
+
+/// Maintenance as announced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notice {
+    pub message: String,
+    /// Whether new tasks are held rather than refused
+    pub hold: bool,
+    pub started_by: String,
+    pub started_at: String,
+}
+
+/// Body of `PUT /admin/maintenance`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceRequest {
+    message: Option<String>,
+    #[serde(default)]
+    hold: bool,
+}
+
+/// A task waiting for maintenance to end
+#[derive(Debug, Serialize, Deserialize)]
+struct HeldTask {
+    task_id: String,
+    queue: String,
+    /// Seconds it had left to run when it was held
+    timeout_secs: u64,
+}
+
+/// The notice in force, if maintenance is on
+pub fn current() -> Option<Notice> {
+    NOTICE.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn set(notice: Option<Notice>) {
+    *NOTICE.write().unwrap_or_else(|e| e.into_inner()) = notice;
+}
+
+/// Whether new tasks are to be held rather than queued
+pub fn holding() -> bool {
+    current().is_some_and(|notice| notice.hold)
+}
+
+/// Refuse new work while maintenance is on without holding it
+pub fn admit() -> Result<(), ApiError> {
+    match current() {
+        Some(notice) if !notice.hold => {
+            Err(ApiError::unavailable("maintenance", notice.message).with_retry_after(60))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Queue the parking of `task_id`, bound for `queue` by `deadline`, on `pipe`
+pub fn hold_in(pipe: &mut redis::Pipeline, task_id: &str, queue: &str, deadline: DateTime<Utc>) {
+    let held = HeldTask {
+        task_id: task_id.to_string(),
+        queue: queue.to_string(),
+        timeout_secs: (deadline - Utc::now()).num_seconds().max(1) as u64,
+    };
+    let entry = serde_json::to_string(&held).unwrap_or_default();
+    pipe.rpush(HELD_KEY, entry).ignore();
+}
+
+/// Queue every held task, returning how many were queued
+async fn release(conn: &mut redis::aio::Connection, task_queue: &TaskQueue) -> anyhow::Result<u64> {
+    let mut released = 0;
+    loop {
+        let entry: Option<String> = conn.lpop(HELD_KEY, None).await?;
+        let Some(entry) = entry else {
+            return Ok(released);
+        };
+        let held: HeldTask = match serde_json::from_str(&entry) {
+            Ok(held) => held,
+            Err(e) => {
+                warn!("Dropping unreadable held task {}: {}", entry, e);
+                continue;
+            }
+        };
+
+        // Give the task its time again; one deleted meanwhile stays put
+        let deadline = watchdog::deadline(held.timeout_secs);
+        let updated = task_state::transition(conn, &held.task_id, "pending", |task| {
+            task["deadline"] = deadline.to_rfc3339().into();
+            task["status"] == "pending"
+        })
+        .await?;
+        if updated.is_none() {
+            continue;
+        }
+        if let Err(e) = task_queue
+            .push(conn, &held.queue, &held.task_id, deadline)
+            .await
+        {
+            conn.lpush::<_, _, ()>(HELD_KEY, entry).await?;
+            return Err(e.into());
+        }
+        watchdog::track(conn, &held.task_id, held.timeout_secs).await?;
+        released += 1;
+    }
+}
+
+/// Keep the notice in step with Redis, and release tasks held on this
+/// gateway once maintenance has ended, in a background task
+pub fn start_maintenance_sync(redis_client: Arc<Client>, task_queue: TaskQueue) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = sync(&redis_client, &task_queue).await {
+                warn!("Maintenance sync failed: {}", e);
+            }
+            tokio::time::sleep(SYNC_INTERVAL).await;
+        }
+    });
+}
+
+async fn sync(redis_client: &Client, task_queue: &TaskQueue) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let stored: Option<String> = conn.get(NOTICE_KEY).await?;
+    let notice = stored
+        .map(|n| serde_json::from_str::<Notice>(&n))
+        .transpose()?;
+    let ended = notice.is_none();
+    set(notice);
+    if ended {
+        let released = release(&mut conn, task_queue).await?;
+        if released > 0 {
+            info!("Queued {} tasks held during maintenance", released);
+        }
+    }
+    Ok(())
+}
+
+// Show whether maintenance is on, and how many tasks it holds (admin only)
+pub async fn get_maintenance(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let stored: Option<String> = conn.get(NOTICE_KEY).await?;
+    let notice = stored
+        .map(|n| serde_json::from_str::<Notice>(&n))
+        .transpose()?;
+    let held: u64 = conn.llen(HELD_KEY).await?;
+    Ok(Json(json!({"maintenance": notice, "held_tasks": held})))
+}
+
+// Start maintenance (admin only)
+pub async fn start_maintenance(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    body: Result<Json<MaintenanceRequest>, JsonRejection>,
+) -> Result<Json<Notice>, ApiError> {
+    let Json(req) = body.map_err(ValidationError::from)?;
+    let message = req.message.map(|m| m.trim().to_string());
+    let notice = Notice {
+        message: message
+            .filter(|m| !m.is_empty())
+            .unwrap_or_else(|| DEFAULT_MESSAGE.to_string()),
+        hold: req.hold,
+        started_by: principal.key_id.clone(),
+        started_at: Utc::now().to_rfc3339(),
+    };
+
+    let mut conn = redis_connection(&state).await?;
+    conn.set::<_, _, ()>(NOTICE_KEY, serde_json::to_string(&notice)?)
+        .await?;
+    set(Some(notice.clone()));
+    info!(
+        "Maintenance started by {} ({})",
+        principal.key_id,
+        if notice.hold {
+            "holding tasks"
+        } else {
+            "refusing tasks"
+        }
+    );
+    Ok(Json(notice))
+}
+
+// End maintenance and queue the tasks it held (admin only)
+pub async fn end_maintenance(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    conn.del::<_, ()>(NOTICE_KEY).await?;
+    set(None);
+    let released = release(&mut conn, &state.task_queue)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to queue held tasks: {}", e)))?;
+    info!(
+        "Maintenance ended by {}, {} held tasks queued",
+        principal.key_id, released
+    );
+    Ok(Json(json!({"released": released})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_tasks_keep_their_remaining_time() {
+        let mut pipe = redis::pipe();
+        hold_in(
+            &mut pipe,
+            "t1",
+            "agent:queue",
+            Utc::now() + chrono::Duration::seconds(300),
+        );
+        let packed = String::from_utf8_lossy(&pipe.get_packed_pipeline()).to_string();
+        assert!(packed.contains("RPUSH"));
+        let entry = &packed[packed.find('{').unwrap()..packed.rfind('}').unwrap() + 1];
+        let held: HeldTask = serde_json::from_str(entry).unwrap();
+        assert_eq!(
+            (held.task_id.as_str(), held.queue.as_str()),
+            ("t1", "agent:queue")
+        );
+        assert!((298..=300).contains(&held.timeout_secs));
+    }
+}
@@ -0,0 +1,158 @@
+//! Redis memory guard with adaptive admission control.
+//!
+//! A background loop samples `INFO memory` and compares `used_memory` against
+//! a ceiling (explicit, or Redis' own `maxmemory`). Past the soft threshold
+//! only priority-lane submissions are admitted; past the hard threshold all
+//! submissions are refused until usage drops again. We would rather turn work
+//! away than let Redis evict arbitrary task keys or run out of memory.
+
+use redis::Client;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::config::MemoryGuardSettings;
+use crate::metrics::{Counter, Gauge};
+
+/// How much new work the gateway currently accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionLevel {
+    Open = 0,
+    PriorityOnly = 1,
+    Closed = 2,
+}
+
+impl AdmissionLevel {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => AdmissionLevel::PriorityOnly,
+            2 => AdmissionLevel::Closed,
+            _ => AdmissionLevel::Open,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdmissionLevel::Open => "open",
+            AdmissionLevel::PriorityOnly => "priority_only",
+            AdmissionLevel::Closed => "closed",
+        }
+    }
+}
+
+/// Shared admission state updated by the sampling loop
+#[derive(Debug)]
+pub struct MemoryGuard {
+    level: AtomicU8,
+    /// Explicit ceiling in bytes; falls back to Redis `maxmemory` when unset
+    ceiling_override: Option<u64>,
+    soft_ratio: f64,
+    hard_ratio: f64,
+    interval: Duration,
+    pub used_bytes: Gauge,
+    pub ceiling_bytes: Gauge,
+    pub rejected: Counter,
+}
+
+impl MemoryGuard {
+    pub fn from_config(settings: &MemoryGuardSettings) -> Self {
+        Self {
+            level: AtomicU8::new(AdmissionLevel::Open as u8),
+            ceiling_override: settings.ceiling_bytes,
+            soft_ratio: settings.soft_ratio,
+            hard_ratio: settings.hard_ratio,
+            interval: Duration::from_secs(settings.interval_secs),
+            used_bytes: Gauge::default(),
+            ceiling_bytes: Gauge::default(),
+            rejected: Counter::default(),
+        }
+    }
+
+    pub fn level(&self) -> AdmissionLevel {
+        AdmissionLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    /// Whether a submission may be accepted, counting rejections
+    pub fn admits(&self, priority: bool) -> bool {
+        let admitted = match self.level() {
+            AdmissionLevel::Open => true,
+            AdmissionLevel::PriorityOnly => priority,
+            AdmissionLevel::Closed => false,
+        };
+        if !admitted {
+            self.rejected.inc();
+        }
+        admitted
+    }
+
+    /// Sample Redis memory usage once and update the admission level
+    async fn sample(&self, redis_client: &Client) -> anyhow::Result<()> {
+        let mut conn = redis_client.get_async_connection().await?;
+        let info: String = redis::cmd("INFO")
+            .arg("memory")
+            .query_async(&mut conn)
+            .await?;
+
+        let used = info_field(&info, "used_memory").unwrap_or(0);
+        let ceiling = self
+            .ceiling_override
+            .or_else(|| info_field(&info, "maxmemory").filter(|m| *m > 0));
+        self.used_bytes.set(used as i64);
+        self.ceiling_bytes.set(ceiling.unwrap_or(0) as i64);
+
+        // Without a known ceiling there is nothing to guard against
+        let level = match ceiling {
+            Some(ceiling) => {
+                let ratio = used as f64 / ceiling as f64;
+                if ratio >= self.hard_ratio {
+                    AdmissionLevel::Closed
+                } else if ratio >= self.soft_ratio {
+                    AdmissionLevel::PriorityOnly
+                } else {
+                    AdmissionLevel::Open
+                }
+            }
+            None => AdmissionLevel::Open,
+        };
+
+        let previous = AdmissionLevel::from_u8(self.level.swap(level as u8, Ordering::Relaxed));
+        if previous != level {
+            match level {
+                AdmissionLevel::Open => info!(
+                    "Redis memory back to normal ({} bytes used), admission reopened",
+                    used
+                ),
+                AdmissionLevel::PriorityOnly => warn!(
+                    "Redis memory at {} of {:?} bytes, admitting priority tasks only",
+                    used, ceiling
+                ),
+                AdmissionLevel::Closed => error!(
+                    "Redis memory at {} of {:?} bytes, refusing all submissions",
+                    used, ceiling
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read an integer field from `INFO` output
+fn info_field(info: &str, name: &str) -> Option<u64> {
+    info.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+/// Start the memory sampling loop in a background task
+pub fn start_memory_guard(redis_client: Arc<Client>, guard: Arc<MemoryGuard>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = guard.sample(&redis_client).await {
+                warn!("Redis memory sampling failed: {}", e);
+            }
+            tokio::time::sleep(guard.interval).await;
+        }
+    });
+}
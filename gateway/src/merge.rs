@@ -0,0 +1,262 @@
+//! Deep merge of task config over the default agent config.
+//!
+//! The rules, applied key by key from the overriding `source` into `target`:
+//!
+//! - objects merge recursively, and keys missing from `target` are added;
+//! - `null` deletes the key from `target`;
+//! - arrays replace the target array by default, or are appended to it when
+//!   the enclosing object (or any ancestor) sets `"$arrays": "append"`;
+//! - any other value replaces the target value.
+//!
+//! `$arrays` is a merge directive, not config: it never appears in the
+//! result, and `"$arrays": "replace"` restores the default for a subtree.
+
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Object key selecting the array strategy for its subtree
+pub const ARRAYS_DIRECTIVE: &str = "$arrays";
+
+/// How an overriding array combines with the array it overrides
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArrayStrategy {
+    #[default]
+    Replace,
+    Append,
+}
+
+impl ArrayStrategy {
+    fn parse(value: &Value) -> Option<Self> {
+        match value.as_str()? {
+            "replace" => Some(Self::Replace),
+            "append" => Some(Self::Append),
+            _ => None,
+        }
+    }
+}
+
+/// A merge directive that could not be understood
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeError {
+    /// Dotted path of the object carrying the directive
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Merge `source` into `target` following the module rules
+pub fn merge(target: &mut Value, source: &Value) -> Result<(), MergeError> {
+    merge_at(target, source, ArrayStrategy::default(), "config")
+}
+
+fn merge_at(
+    target: &mut Value,
+    source: &Value,
+    inherited: ArrayStrategy,
+    path: &str,
+) -> Result<(), MergeError> {
+    let Some(source) = source.as_object() else {
+        *target = source.clone();
+        return Ok(());
+    };
+
+    let arrays = match source.get(ARRAYS_DIRECTIVE) {
+        None => inherited,
+        Some(value) => ArrayStrategy::parse(value).ok_or_else(|| MergeError {
+            path: path.to_string(),
+            message: format!(
+                "{} must be \"replace\" or \"append\", got {}",
+                ARRAYS_DIRECTIVE, value
+            ),
+        })?,
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().expect("target is an object");
+
+    for (key, value) in source {
+        if key == ARRAYS_DIRECTIVE {
+            continue;
+        }
+        let child_path = format!("{}.{}", path, key);
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            Value::Object(_) => {
+                let slot = target.entry(key.clone()).or_insert(Value::Null);
+                merge_at(slot, value, arrays, &child_path)?;
+            }
+            Value::Array(items) => match (arrays, target.get_mut(key)) {
+                (ArrayStrategy::Append, Some(Value::Array(existing))) => {
+                    existing.extend(items.iter().cloned());
+                }
+                _ => {
+                    target.insert(key.clone(), value.clone());
+                }
+            },
+            _ => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn merged(target: Value, source: Value) -> Value {
+        let mut target = target;
+        merge(&mut target, &source).unwrap();
+        target
+    }
+
+    #[test]
+    fn overrides_existing_keys() {
+        assert_eq!(
+            merged(
+                json!({"model": "a", "temperature": 0.2}),
+                json!({"model": "b"})
+            ),
+            json!({"model": "b", "temperature": 0.2})
+        );
+    }
+
+    #[test]
+    fn adds_new_keys() {
+        assert_eq!(
+            merged(
+                json!({"model": "a"}),
+                json!({"route": "eu", "tools": {"search": true}})
+            ),
+            json!({"model": "a", "route": "eu", "tools": {"search": true}})
+        );
+    }
+
+    #[test]
+    fn merges_nested_objects() {
+        assert_eq!(
+            merged(
+                json!({"tools": {"search": true, "shell": false}}),
+                json!({"tools": {"shell": true, "browser": true}})
+            ),
+            json!({"tools": {"search": true, "shell": true, "browser": true}})
+        );
+    }
+
+    #[test]
+    fn null_deletes_keys() {
+        assert_eq!(
+            merged(
+                json!({"model": "a", "tools": {"search": true, "shell": true}}),
+                json!({"model": null, "tools": {"shell": null}})
+            ),
+            json!({"tools": {"search": true}})
+        );
+    }
+
+    #[test]
+    fn null_only_deletes_existing_keys() {
+        assert_eq!(
+            merged(
+                json!({"model": "a"}),
+                json!({"route": null, "tools": {"x": null}})
+            ),
+            json!({"model": "a", "tools": {}})
+        );
+    }
+
+    #[test]
+    fn arrays_replace_by_default() {
+        assert_eq!(
+            merged(json!({"stop": ["a", "b"]}), json!({"stop": ["c"]})),
+            json!({"stop": ["c"]})
+        );
+    }
+
+    #[test]
+    fn arrays_append_when_requested() {
+        assert_eq!(
+            merged(
+                json!({"stop": ["a"], "tools": {"allow": ["x"]}}),
+                json!({"$arrays": "append", "stop": ["b"], "tools": {"allow": ["y"]}})
+            ),
+            json!({"stop": ["a", "b"], "tools": {"allow": ["x", "y"]}})
+        );
+    }
+
+    #[test]
+    fn nested_directive_overrides_inherited_strategy() {
+        assert_eq!(
+            merged(
+                json!({"stop": ["a"], "tools": {"allow": ["x"]}}),
+                json!({
+                    "$arrays": "append",
+                    "stop": ["b"],
+                    "tools": {"$arrays": "replace", "allow": ["y"]}
+                })
+            ),
+            json!({"stop": ["a", "b"], "tools": {"allow": ["y"]}})
+        );
+    }
+
+    #[test]
+    fn append_onto_missing_or_non_array_replaces() {
+        assert_eq!(
+            merged(
+                json!({"stop": "a"}),
+                json!({"$arrays": "append", "stop": ["b"], "extra": [1]})
+            ),
+            json!({"stop": ["b"], "extra": [1]})
+        );
+    }
+
+    #[test]
+    fn object_replaces_scalar() {
+        assert_eq!(
+            merged(json!({"tools": "none"}), json!({"tools": {"search": true}})),
+            json!({"tools": {"search": true}})
+        );
+    }
+
+    #[test]
+    fn scalar_replaces_object() {
+        assert_eq!(
+            merged(json!({"tools": {"search": true}}), json!({"tools": false})),
+            json!({"tools": false})
+        );
+    }
+
+    #[test]
+    fn non_object_source_replaces_target() {
+        assert_eq!(merged(json!({"model": "a"}), json!([1, 2])), json!([1, 2]));
+    }
+
+    #[test]
+    fn directive_is_not_copied() {
+        let result = merged(
+            json!({}),
+            json!({"$arrays": "append", "tools": {"$arrays": "replace"}}),
+        );
+        assert_eq!(result, json!({"tools": {}}));
+    }
+
+    #[test]
+    fn rejects_unknown_directive() {
+        let mut target = json!({});
+        let err = merge(&mut target, &json!({"tools": {"$arrays": "zip"}})).unwrap_err();
+        assert_eq!(err.path, "config.tools");
+    }
+}
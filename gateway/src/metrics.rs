@@ -0,0 +1,60 @@
+//! Lightweight typed metrics.
+//!
+//! Subsystems declare their own metric structs out of these primitives so that
+//! every metric is a named field rather than a stringly-typed registry entry.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Monotonically increasing counter
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that can go up and down
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counter split by a single label value
+#[derive(Debug, Default)]
+pub struct CounterVec(Mutex<HashMap<String, u64>>);
+
+impl CounterVec {
+    pub fn inc(&self, label: &str) {
+        let mut values = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        *values.entry(label.to_string()).or_insert(0) += 1;
+    }
+
+    /// Sum across all label values
+    pub fn total(&self) -> u64 {
+        self.snapshot().values().sum()
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, u64> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
@@ -0,0 +1,242 @@
+//! Content moderation of task submissions.
+//!
+//! Before a task submitted over the API is stored, its input goes through
+//! the configured checks: `moderation.blocklist` patterns matched against
+//! every string in the input, `moderation.max_input_chars` and
+//! `moderation.max_input_tokens` (estimated at four characters a token)
+//! limits on their total length, and, with `moderation.api_url`, a
+//! moderation endpoint answering like the OpenAI moderation API
+//! (`{"flagged": bool, "categories": {...}}`, at the top level or as the
+//! first of `results`). Its token goes in `moderation.api_token`. An
+//! endpoint that cannot be reached lets tasks through unless
+//! `moderation.fail_closed`.
+//!
+//! With `moderation.action = "reject"` a task failing any check is refused
+//! with a 422 coded `content_rejected` whose `details.reasons` lists the
+//! findings. With `"flag"` it is queued as usual. Either way, checked
+//! tasks record the outcome as `"moderation": {"decision": "allowed" |
+//! "flagged", "reasons": [...]}`. Each finding names its `check`:
+//! `blocklist` with the `pattern`, `max_input_chars` or `max_input_tokens`
+//! with the `limit`, or `api` with the flagged `categories`. Map tasks are
+//! moderated item by item, a rejected item failing like any refused one.
+
+use regex::Regex;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::{ModerationAction, ModerationSettings};
+use crate::error::ApiError;
+
+/// Characters counted as one token by the length check
+const CHARS_PER_TOKEN: usize = 4;
+
+/// The configured checks
+pub struct Moderator {
+    blocklist: Vec<(Regex, String)>,
+    settings: ModerationSettings,
+    http: reqwest::Client,
+}
+
+/// Every string in `value`, object keys aside
+fn strings<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+    match value {
+        Value::String(text) => found.push(text),
+        Value::Array(items) => items.iter().for_each(|v| strings(v, found)),
+        Value::Object(map) => map.values().for_each(|v| strings(v, found)),
+        _ => {}
+    }
+}
+
+/// The categories a moderation API answer flags, if it flags any
+fn flagged_categories(answer: &Value) -> Option<Vec<String>> {
+    let verdict = answer
+        .get("results")
+        .and_then(|r| r.get(0))
+        .unwrap_or(answer);
+    if verdict["flagged"] != true {
+        return None;
+    }
+    let categories = verdict["categories"]
+        .as_object()
+        .map(|c| {
+            c.iter()
+                .filter(|(_, flagged)| **flagged == true)
+                .map(|(name, _)| name.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(categories)
+}
+
+impl Moderator {
+    pub fn from_config(settings: &ModerationSettings) -> anyhow::Result<Self> {
+        let blocklist = settings
+            .blocklist
+            .iter()
+            .map(|p| Ok((Regex::new(p)?, p.clone())))
+            .collect::<anyhow::Result<_>>()?;
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.api_timeout_secs))
+            .build()?;
+        Ok(Self {
+            blocklist,
+            settings: settings.clone(),
+            http,
+        })
+    }
+
+    /// Whether any check is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.blocklist.is_empty()
+            || self.settings.max_input_chars > 0
+            || self.settings.max_input_tokens > 0
+            || self.settings.api_url.is_some()
+    }
+
+    /// Findings of the local checks on `texts`
+    fn check_locally(&self, texts: &[&str]) -> Vec<Value> {
+        let mut reasons = Vec::new();
+        for (regex, pattern) in &self.blocklist {
+            if texts.iter().any(|t| regex.is_match(t)) {
+                reasons.push(json!({"check": "blocklist", "pattern": pattern}));
+            }
+        }
+        let chars: usize = texts.iter().map(|t| t.chars().count()).sum();
+        let limits = [
+            ("max_input_chars", self.settings.max_input_chars, chars),
+            (
+                "max_input_tokens",
+                self.settings.max_input_tokens,
+                chars.div_ceil(CHARS_PER_TOKEN),
+            ),
+        ];
+        for (check, limit, actual) in limits {
+            if limit > 0 && actual > limit {
+                reasons.push(json!({"check": check, "limit": limit, "actual": actual}));
+            }
+        }
+        reasons
+    }
+
+    /// Finding of the moderation API on `texts`, if it flags them
+    async fn check_remotely(&self, url: &str, texts: &[&str]) -> Option<Value> {
+        let mut request = self
+            .http
+            .post(url)
+            .json(&json!({"input": texts.join("\n")}));
+        if let Some(token) = &self.settings.api_token {
+            request = request.bearer_auth(token);
+        }
+        let answer = async {
+            let response = request.send().await?.error_for_status()?;
+            response.json::<Value>().await
+        };
+        match answer.await {
+            Ok(answer) => flagged_categories(&answer)
+                .map(|categories| json!({"check": "api", "categories": categories})),
+            Err(e) if self.settings.fail_closed => {
+                warn!("Moderation API unavailable, failing the check: {}", e);
+                Some(json!({"check": "api", "error": "moderation API unavailable"}))
+            }
+            Err(e) => {
+                warn!("Moderation API unavailable, skipping the check: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The decision to record on a task with `input`, or the rejection
+    pub async fn review(&self, task_id: &str, input: &Value) -> Result<Option<Value>, ApiError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let mut texts = Vec::new();
+        strings(input, &mut texts);
+        let mut reasons = self.check_locally(&texts);
+        if let Some(url) = &self.settings.api_url {
+            reasons.extend(self.check_remotely(url, &texts).await);
+        }
+
+        if reasons.is_empty() {
+            return Ok(Some(json!({"decision": "allowed", "reasons": reasons})));
+        }
+        match self.settings.action {
+            ModerationAction::Reject => {
+                warn!("Task {} rejected by content moderation", task_id);
+                Err(ApiError::unprocessable(
+                    "content_rejected",
+                    "The task input was rejected by content moderation",
+                )
+                .with_details(json!({"reasons": reasons})))
+            }
+            ModerationAction::Flag => {
+                warn!("Task {} flagged by content moderation", task_id);
+                Ok(Some(json!({"decision": "flagged", "reasons": reasons})))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderator(settings: ModerationSettings) -> Moderator {
+        Moderator::from_config(&settings).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unconfigured_moderation_records_nothing() {
+        let moderator = moderator(ModerationSettings::default());
+        let decision = moderator.review("t1", &json!("anything")).await.unwrap();
+        assert!(decision.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_blocked_and_oversized_inputs() {
+        let moderator = moderator(ModerationSettings {
+            blocklist: vec![r"(?i)\bforbidden\b".to_string()],
+            max_input_tokens: 3,
+            ..ModerationSettings::default()
+        });
+        let input = json!({"prompt": "A Forbidden word", "n": 1});
+        let error = moderator.review("t1", &input).await.unwrap_err();
+        assert_eq!(error.code, "content_rejected");
+        assert_eq!(
+            error.details.unwrap()["reasons"],
+            json!([
+                {"check": "blocklist", "pattern": r"(?i)\bforbidden\b"},
+                {"check": "max_input_tokens", "limit": 3, "actual": 4},
+            ])
+        );
+
+        let allowed = moderator.review("t2", &json!(["fine"])).await.unwrap();
+        assert_eq!(allowed, Some(json!({"decision": "allowed", "reasons": []})));
+    }
+
+    #[tokio::test]
+    async fn flags_instead_of_rejecting() {
+        let moderator = moderator(ModerationSettings {
+            max_input_chars: 4,
+            action: ModerationAction::Flag,
+            ..ModerationSettings::default()
+        });
+        let decision = moderator.review("t1", &json!("too long")).await.unwrap();
+        assert_eq!(decision.unwrap()["decision"], "flagged");
+    }
+
+    #[test]
+    fn reads_api_answers() {
+        let answer = json!({"results": [{
+            "flagged": true,
+            "categories": {"violence": true, "hate": false},
+        }]});
+        assert_eq!(
+            flagged_categories(&answer),
+            Some(vec!["violence".to_string()])
+        );
+        assert_eq!(flagged_categories(&json!({"flagged": true})), Some(vec![]));
+        assert_eq!(flagged_categories(&json!({"flagged": false})), None);
+    }
+}
@@ -0,0 +1,649 @@
+//! Pipeline templates for multi-step agent workflows.
+//!
+//! `POST /pipelines` stores a definition of named steps, e.g.
+//! `{"name": "brief", "steps": [{"name": "search", "input": {"query":
+//! "{{input.topic}}"}, "capability": "web"}, {"name": "summarize", "input":
+//! "Summarize: {{steps.search.text}}"}]}`. A step's `input` is a template:
+//! `{{input...}}` reads the run's input and `{{steps.<name>...}}` the result
+//! of an earlier step, following object keys and array indexes separated by
+//! dots. A string that is nothing but one placeholder takes the referenced
+//! value as is; elsewhere placeholders are replaced by the value's text.
+//!
+//! `POST /pipelines/:id/run` with `{"input": ...}` starts a run and submits
+//! its first step as an ordinary task, `{run_id}.{step}`, through the same
+//! admission, quotas and queues as `POST /task`, on behalf of the caller. A
+//! background orchestrator checks running runs every
+//! `tasks.pipeline_interval_secs` and submits each step once the one before
+//! completes; the run's `result` is the last step's. A step that fails,
+//! times out, is orphaned or deleted fails the run. `GET
+//! /pipelines/:id/runs/:run_id` reports progress.
+//!
+//! Definitions live in `pipeline:{id}` and runs in `pipeline:run:{run_id}`,
+//! the running ones listed in `pipelines:running`. Callers see and run their
+//! own pipelines; operators and admins every one.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    Extension,
+};
+use chrono::Utc;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth::{Principal, Role};
+use crate::cache::CacheControl;
+use crate::error::ApiError;
+use crate::failures::{self, Settlement};
+use crate::{enqueue_task, redaction, redis_connection, usage, validation};
+use crate::{AgentRequest, AppState};
+
+/// Redis set of the runs still in progress
+const RUNNING_KEY: &str = "pipelines:running";
+
+/// Most steps a pipeline may have
+const MAX_STEPS: usize = 20;
+
+/// Longest step name
+const MAX_STEP_NAME_LEN: usize = 64;
+
+/// Seconds a gateway may hold a run while advancing it
+const RUN_LOCK_TTL_SECS: u64 = 30;
+
+fn pipeline_key(id: &str) -> String {
+    format!("pipeline:{}", id)
+}
+
+fn run_key(run_id: &str) -> String {
+    format!("pipeline:run:{}", run_id)
+}
+
+/// One step of a pipeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub name: String,
+    /// Task input, with placeholders for the run input and earlier results
+    pub input: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capability: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreatePipelineRequest {
+    name: Option<String>,
+    steps: Vec<Step>,
+}
+
+/// A stored pipeline definition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Pipeline {
+    id: String,
+    name: Option<String>,
+    steps: Vec<Step>,
+    created_by: String,
+    created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunRequest {
+    #[serde(default)]
+    input: Value,
+}
+
+/// A pipeline run and how far it got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Run {
+    run_id: String,
+    pipeline_id: String,
+    /// `running`, `completed` or `failed`
+    status: String,
+    input: Value,
+    /// Index of the step in progress
+    step: usize,
+    /// Task ids of the steps submitted so far
+    tasks: Vec<String>,
+    /// Results of the completed steps, by step name
+    results: BTreeMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    started_by: String,
+    role: Role,
+    created_at: String,
+    updated_at: String,
+}
+
+impl Run {
+    fn principal(&self) -> Principal {
+        Principal {
+            key_id: self.started_by.clone(),
+            role: self.role,
+        }
+    }
+
+    fn fail(&mut self, message: String) {
+        warn!("Pipeline run {} failed: {}", self.run_id, message);
+        self.status = "failed".to_string();
+        self.error = Some(message);
+    }
+}
+
+/// Whether `principal` may see or run what `owner` created
+fn may_access(principal: &Principal, owner: &str) -> bool {
+    principal.key_id == owner || principal.role.is_privileged()
+}
+
+fn is_valid_step_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_STEP_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// The placeholder paths in every string inside `template`
+fn placeholders(template: &Value, paths: &mut Vec<String>) {
+    match template {
+        Value::String(text) => {
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else {
+                    break;
+                };
+                paths.push(rest[start + 2..start + end].trim().to_string());
+                rest = &rest[start + end + 2..];
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|v| placeholders(v, paths)),
+        Value::Object(map) => map.values().for_each(|v| placeholders(v, paths)),
+        _ => {}
+    }
+}
+
+/// Check names, limits and that every placeholder reads the input or an
+/// earlier step
+fn check_steps(steps: &[Step]) -> Result<(), String> {
+    if steps.is_empty() || steps.len() > MAX_STEPS {
+        return Err(format!("a pipeline needs 1-{} steps", MAX_STEPS));
+    }
+    let mut earlier = BTreeSet::new();
+    for step in steps {
+        if !is_valid_step_name(&step.name) {
+            return Err(format!(
+                "step name {:?} must be 1-{} letters, digits, '-' or '_'",
+                step.name, MAX_STEP_NAME_LEN
+            ));
+        }
+        if let Some(capability) = &step.capability {
+            if !validation::is_valid_capability(capability) {
+                return Err(format!(
+                    "step {}: {}",
+                    step.name,
+                    validation::CAPABILITY_RULE
+                ));
+            }
+        }
+        let mut paths = Vec::new();
+        placeholders(&step.input, &mut paths);
+        for path in paths {
+            let mut parts = path.split('.');
+            match (parts.next(), parts.next()) {
+                (Some("input"), _) => {}
+                (Some("steps"), Some(name)) if earlier.contains(name) => {}
+                (Some("steps"), Some(name)) => {
+                    return Err(format!(
+                        "step {} reads step {}, which does not run before it",
+                        step.name, name
+                    ));
+                }
+                _ => {
+                    return Err(format!(
+                        "step {}: placeholder {{{{{}}}}} must start with input or steps.<name>",
+                        step.name, path
+                    ));
+                }
+            }
+        }
+        if !earlier.insert(step.name.as_str()) {
+            return Err(format!("step name {} is used twice", step.name));
+        }
+    }
+    Ok(())
+}
+
+/// The value a placeholder path names
+fn lookup<'a>(
+    path: &str,
+    input: &'a Value,
+    results: &'a BTreeMap<String, Value>,
+) -> Result<&'a Value, String> {
+    let mut parts = path.split('.');
+    let mut value = match parts.next() {
+        Some("input") => input,
+        Some("steps") => {
+            let name = parts.next().unwrap_or_default();
+            results
+                .get(name)
+                .ok_or_else(|| format!("step {} has no result", name))?
+        }
+        _ => return Err(format!("unknown placeholder {}", path)),
+    };
+    for part in parts {
+        let next = match value {
+            Value::Array(items) => part.parse::<usize>().ok().and_then(|i| items.get(i)),
+            Value::Object(map) => map.get(part),
+            _ => None,
+        };
+        value = next.ok_or_else(|| format!("{} is not in the pipeline data", path))?;
+    }
+    Ok(value)
+}
+
+/// Fill in the placeholders of `template`
+fn render(
+    template: &Value,
+    input: &Value,
+    results: &BTreeMap<String, Value>,
+) -> Result<Value, String> {
+    Ok(match template {
+        Value::String(text) => {
+            let trimmed = text.trim();
+            let whole = trimmed
+                .strip_prefix("{{")
+                .and_then(|t| t.strip_suffix("}}"))
+                .filter(|path| !path.contains("{{") && !path.contains("}}"));
+            if let Some(path) = whole {
+                return lookup(path.trim(), input, results).cloned();
+            }
+            let mut rendered = String::new();
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else {
+                    break;
+                };
+                rendered.push_str(&rest[..start]);
+                match lookup(rest[start + 2..start + end].trim(), input, results)? {
+                    Value::String(s) => rendered.push_str(s),
+                    value => rendered.push_str(&value.to_string()),
+                }
+                rest = &rest[start + end + 2..];
+            }
+            rendered.push_str(rest);
+            Value::String(rendered)
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|v| render(v, input, results))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| Ok((k.clone(), render(v, input, results)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+async fn load<T: serde::de::DeserializeOwned>(
+    conn: &mut redis::aio::Connection,
+    key: &str,
+) -> Result<Option<T>, ApiError> {
+    let stored: Option<String> = conn.get(key).await?;
+    Ok(stored.map(|s| serde_json::from_str(&s)).transpose()?)
+}
+
+async fn save_run(conn: &mut redis::aio::Connection, run: &mut Run) -> Result<(), ApiError> {
+    run.updated_at = Utc::now().to_rfc3339();
+    conn.set::<_, _, ()>(run_key(&run.run_id), serde_json::to_string(run)?)
+        .await?;
+    if run.status != "running" {
+        conn.srem::<_, _, ()>(RUNNING_KEY, &run.run_id).await?;
+    }
+    Ok(())
+}
+
+/// Submit the run's current step as a task, leaving the run for the next
+/// pass when the gateway is shedding load
+async fn submit_step(state: &AppState, pipeline: &Pipeline, run: &mut Run) {
+    let step = &pipeline.steps[run.step];
+    let input = match render(&step.input, &run.input, &run.results) {
+        Ok(input) => input,
+        Err(e) => return run.fail(format!("Step {}: {}", step.name, e)),
+    };
+    let task_id = format!("{}.{}", run.run_id, run.step);
+    let req = AgentRequest {
+        task_id: task_id.clone(),
+        input,
+        config: step.config.clone(),
+        capability: step.capability.clone(),
+        timeout_seconds: step.timeout_seconds,
+        labels: BTreeMap::from([
+            ("pipeline".to_string(), pipeline.id.clone()),
+            ("pipeline_run".to_string(), run.run_id.clone()),
+        ]),
+        depends_on: Vec::new(),
+        map: None,
+        parent_task_id: None,
+    };
+    if let Err(e) = validation::validate_request(&req, &state.config.current().limits) {
+        let problems: Vec<String> = e.violations.iter().map(|v| v.message.clone()).collect();
+        return run.fail(format!("Step {}: {}", step.name, problems.join("; ")));
+    }
+
+    let principal = Some(Extension(run.principal()));
+    match enqueue_task(state.clone(), None, principal, CacheControl::default(), req).await {
+        Ok(_) => {
+            info!("Pipeline run {} submitted step {}", run.run_id, step.name);
+            run.tasks.push(task_id);
+        }
+        Err(e) if matches!(e.status, StatusCode::SERVICE_UNAVAILABLE) => {
+            warn!(
+                "Pipeline run {} step {} deferred: {}",
+                run.run_id, step.name, e.message
+            );
+        }
+        Err(e) => run.fail(format!("Step {}: {}", step.name, e.message)),
+    }
+}
+
+/// Move a run along as far as its tasks allow
+async fn advance(
+    state: &AppState,
+    conn: &mut redis::aio::Connection,
+    pipeline: &Pipeline,
+    run: &mut Run,
+) -> Result<(), ApiError> {
+    while run.status == "running" {
+        let Some(task_id) = run.tasks.get(run.step).cloned() else {
+            submit_step(state, pipeline, run).await;
+            break;
+        };
+        let name = pipeline.steps[run.step].name.clone();
+        match failures::settlement(conn, &task_id).await? {
+            Settlement::Running => break,
+            Settlement::Failed(message) => run.fail(format!("Step {}: {}", name, message)),
+            Settlement::Completed(result) => {
+                run.results.insert(name, result.clone());
+                run.step += 1;
+                if run.step == pipeline.steps.len() {
+                    info!("Pipeline run {} completed", run.run_id);
+                    run.status = "completed".to_string();
+                    run.result = Some(result);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Advance the run `run_id` unless another gateway is at it
+async fn advance_run(
+    state: &AppState,
+    conn: &mut redis::aio::Connection,
+    run_id: &str,
+) -> Result<(), ApiError> {
+    let lock = format!("{}:lock", run_key(run_id));
+    let locked: bool = redis::cmd("SET")
+        .arg(&lock)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(RUN_LOCK_TTL_SECS)
+        .query_async::<_, Option<String>>(conn)
+        .await?
+        .is_some();
+    if !locked {
+        return Ok(());
+    }
+    let advanced = async {
+        let Some(mut run) = load::<Run>(conn, &run_key(run_id)).await? else {
+            conn.srem::<_, _, ()>(RUNNING_KEY, run_id).await?;
+            return Ok(());
+        };
+        let Some(pipeline) = load::<Pipeline>(conn, &pipeline_key(&run.pipeline_id)).await? else {
+            run.fail(format!("Pipeline {} no longer exists", run.pipeline_id));
+            return save_run(conn, &mut run).await;
+        };
+        let before = serde_json::to_value(&run)?;
+        advance(state, conn, &pipeline, &mut run).await?;
+        if serde_json::to_value(&run)? != before {
+            save_run(conn, &mut run).await?;
+        }
+        Ok(())
+    }
+    .await;
+    conn.del::<_, ()>(&lock).await?;
+    advanced
+}
+
+/// Advance every running run
+async fn orchestrate_pass(state: &AppState) -> Result<(), ApiError> {
+    let mut conn = redis_connection(state).await?;
+    let running: Vec<String> = conn.smembers(RUNNING_KEY).await?;
+    for run_id in running {
+        if let Err(e) = advance_run(state, &mut conn, &run_id).await {
+            error!("Failed to advance pipeline run {}: {}", run_id, e.message);
+        }
+    }
+    Ok(())
+}
+
+/// Start advancing pipeline runs in a background task
+pub fn start_pipeline_orchestrator(state: AppState) {
+    let interval = Duration::from_secs(state.config.current().tasks.pipeline_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = orchestrate_pass(&state).await {
+                error!("Pipeline orchestrator error: {}", e.message);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+// Store a pipeline definition
+pub async fn create_pipeline(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Json(req): Json<CreatePipelineRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    check_steps(&req.steps).map_err(|e| ApiError::unprocessable("invalid_pipeline", e))?;
+
+    let pipeline = Pipeline {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        name: req.name,
+        steps: req.steps,
+        created_by: principal.key_id.clone(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    let mut conn = redis_connection(&state).await?;
+    conn.set::<_, _, ()>(
+        pipeline_key(&pipeline.id),
+        serde_json::to_string(&pipeline)?,
+    )
+    .await?;
+    info!(
+        "Pipeline {} with {} steps created by {}",
+        pipeline.id,
+        pipeline.steps.len(),
+        principal.key_id
+    );
+    Ok((StatusCode::CREATED, Json(serde_json::to_value(pipeline)?)))
+}
+
+async fn accessible_pipeline(
+    conn: &mut redis::aio::Connection,
+    principal: &Principal,
+    id: &str,
+) -> Result<Pipeline, ApiError> {
+    let pipeline = load::<Pipeline>(conn, &pipeline_key(id))
+        .await?
+        .filter(|p| may_access(principal, &p.created_by));
+    pipeline.ok_or_else(|| ApiError::not_found(format!("Pipeline {} not found", id)))
+}
+
+// Show a pipeline definition
+pub async fn get_pipeline(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let mut conn = redis_connection(&state).await?;
+    let pipeline = accessible_pipeline(&mut conn, &principal, &id).await?;
+    Ok(Json(serde_json::to_value(pipeline)?))
+}
+
+// Start a run of a pipeline, submitting its first step
+pub async fn run_pipeline(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(id): Path<String>,
+    Json(mut req): Json<RunRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    redaction::input(usage::tenant(Some(&principal)), &mut req.input);
+    let mut conn = redis_connection(&state).await?;
+    let pipeline = accessible_pipeline(&mut conn, &principal, &id).await?;
+
+    let now = Utc::now().to_rfc3339();
+    let mut run = Run {
+        run_id: uuid::Uuid::new_v4().simple().to_string(),
+        pipeline_id: pipeline.id,
+        status: "running".to_string(),
+        input: req.input,
+        step: 0,
+        tasks: Vec::new(),
+        results: BTreeMap::new(),
+        result: None,
+        error: None,
+        started_by: principal.key_id.clone(),
+        role: principal.role,
+        created_at: now.clone(),
+        updated_at: now,
+    };
+    save_run(&mut conn, &mut run).await?;
+    conn.sadd::<_, _, ()>(RUNNING_KEY, &run.run_id).await?;
+    info!(
+        "Pipeline {} run {} started by {}",
+        id, run.run_id, principal.key_id
+    );
+
+    advance_run(&state, &mut conn, &run.run_id).await?;
+    let run = load::<Run>(&mut conn, &run_key(&run.run_id))
+        .await?
+        .unwrap_or(run);
+    Ok((StatusCode::ACCEPTED, Json(serde_json::to_value(run)?)))
+}
+
+// Report how far a pipeline run got
+pub async fn get_run(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path((id, run_id)): Path<(String, String)>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let mut conn = redis_connection(&state).await?;
+    let run = load::<Run>(&mut conn, &run_key(&run_id))
+        .await?
+        .filter(|r| r.pipeline_id == id && may_access(&principal, &r.started_by))
+        .ok_or_else(|| ApiError::not_found(format!("Pipeline run {} not found", run_id)))?;
+    Ok(Json(json!(run)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(name: &str, input: Value) -> Step {
+        Step {
+            name: name.to_string(),
+            input,
+            config: None,
+            capability: None,
+            timeout_seconds: None,
+        }
+    }
+
+    #[test]
+    fn accepts_steps_reading_earlier_ones() {
+        let steps = [
+            step("search", json!({"query": "{{input.topic}}"})),
+            step("summarize", json!("Summarize {{steps.search.text}}")),
+        ];
+        assert_eq!(check_steps(&steps), Ok(()));
+    }
+
+    #[test]
+    fn refuses_bad_definitions() {
+        assert!(check_steps(&[]).is_err());
+        let later = [
+            step("a", json!("{{steps.b}}")),
+            step("b", json!("{{input}}")),
+        ];
+        assert!(check_steps(&later)
+            .unwrap_err()
+            .contains("does not run before"));
+        let own = [step("a", json!("{{steps.a}}"))];
+        assert!(check_steps(&own).is_err());
+        let twice = [step("a", json!(1)), step("a", json!(2))];
+        assert!(check_steps(&twice).unwrap_err().contains("twice"));
+        assert!(check_steps(&[step("a b", json!(1))]).is_err());
+        assert!(check_steps(&[step("a", json!("{{env.HOME}}"))]).is_err());
+    }
+
+    #[test]
+    fn renders_whole_values_and_text() {
+        let input = json!({"topic": "rust", "n": 3});
+        let results = BTreeMap::from([(
+            "search".to_string(),
+            json!({"hits": ["a", "b"], "text": "found"}),
+        )]);
+        let template = json!({
+            "hits": "{{ steps.search.hits }}",
+            "first": "{{steps.search.hits.0}}",
+            "prompt": "Top {{input.n}} on {{input.topic}}: {{steps.search.text}}",
+            "fixed": [1, true],
+        });
+        assert_eq!(
+            render(&template, &input, &results),
+            Ok(json!({
+                "hits": ["a", "b"],
+                "first": "a",
+                "prompt": "Top 3 on rust: found",
+                "fixed": [1, true],
+            }))
+        );
+    }
+
+    #[test]
+    fn rendering_missing_data_fails() {
+        let results = BTreeMap::new();
+        assert!(render(&json!("{{input.missing}}"), &json!({}), &results).is_err());
+        assert!(render(&json!("{{steps.search}}"), &json!({}), &results).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//! Task queue backends.
+//!
+//! Task records and results always live in Redis; only the hand-off of task
+//! ids to agents goes through the configured `queue.backend`. `redis` (the
+//! default) pushes onto the `agent:queue*` lists. `nats` publishes to a
+//! JetStream work-queue stream instead, one subject per queue, which agents
+//! drain through durable consumers and acknowledge once a task is processed.
+//! A task whose agent dies before acknowledging it is redelivered, which a
+//! Redis list cannot do. JetStream messages also carry the task's deadline in
+//! a `Claw-Deadline` header (unix seconds); agents on Redis read it from the
+//! task record.
+//!
+//! [`TaskQueue::submit`] stores a new task: its record, label, history and
+//! event indexes, queue entry and deadline go to Redis in one `MULTI`/`EXEC`
+//! round trip, so a task is never stored without being queued. The id is
+//! watched meanwhile, and an id already stored is refused with a 409
+//! (`duplicate_task`) rather than overwritten. On NATS the
+//! publish follows the transaction; a task whose publish fails is still
+//! watched, and times out at its deadline. During
+//! [maintenance](crate::maintenance) that holds tasks, the queue entry is
+//! replaced by one in the holding list.
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use std::collections::BTreeMap;
+use tracing::Instrument;
+
+use crate::agent_registry::DIRECT_QUEUE_PREFIX;
+use crate::config::{QueueBackend, QueueSettings};
+use crate::error::ApiError;
+use crate::{events, history, labels, maintenance, telemetry, watchdog, AGENT_QUEUE};
+
+#[derive(Debug)]
+pub enum QueueError {
+    Redis(redis::RedisError),
+    /// A task with this id is already stored
+    Duplicate(String),
+    #[cfg(feature = "nats")]
+    Nats(async_nats::Error),
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Redis(e) => write!(f, "{}", e),
+            QueueError::Duplicate(task_id) => write!(f, "Task {} already exists", task_id),
+            #[cfg(feature = "nats")]
+            QueueError::Nats(e) => write!(f, "NATS: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+impl From<redis::RedisError> for QueueError {
+    fn from(e: redis::RedisError) -> Self {
+        QueueError::Redis(e)
+    }
+}
+
+impl From<QueueError> for ApiError {
+    fn from(e: QueueError) -> Self {
+        match e {
+            QueueError::Redis(e) => e.into(),
+            QueueError::Duplicate(task_id) => ApiError::new(
+                StatusCode::CONFLICT,
+                "duplicate_task",
+                format!("Task {} already exists", task_id),
+            ),
+            #[cfg(feature = "nats")]
+            QueueError::Nats(e) => {
+                tracing::error!("NATS error: {}", e);
+                ApiError::unavailable("queue_unavailable", "Task queue is unavailable")
+            }
+        }
+    }
+}
+
+/// Run `pipe`, a transaction storing the new task `task_id`, unless a task
+/// with that id is already stored. A resubmitted id would otherwise take
+/// over the stored task, its owner included.
+pub async fn create<C: ConnectionLike + Send>(
+    conn: &mut C,
+    task_id: &str,
+    pipe: &redis::Pipeline,
+) -> Result<(), QueueError> {
+    let key = format!("task:{}", task_id);
+    redis::cmd("WATCH")
+        .arg(&key)
+        .query_async::<_, ()>(conn)
+        .await?;
+    if conn.exists(&key).await? {
+        redis::cmd("UNWATCH").query_async::<_, ()>(conn).await?;
+        return Err(QueueError::Duplicate(task_id.to_string()));
+    }
+    // EXEC answers nil when the task was stored meanwhile
+    let stored: Option<()> = pipe
+        .query_async(conn)
+        .instrument(telemetry::redis_span("MULTI", "task:*"))
+        .await?;
+    stored.ok_or_else(|| QueueError::Duplicate(task_id.to_string()))
+}
+
+/// Subject below `prefix` carrying the tasks of Redis queue `queue`
+#[cfg_attr(not(feature = "nats"), allow(dead_code))]
+fn subject(prefix: &str, queue: &str) -> String {
+    // Agent ids may hold dots, which would split the subject
+    if let Some(agent_id) = queue.strip_prefix(DIRECT_QUEUE_PREFIX) {
+        return format!("{}.agent.{}", prefix, agent_id.replace('.', "_"));
+    }
+    let lane = queue.strip_prefix(AGENT_QUEUE).unwrap_or(queue);
+    let lane = match lane.strip_prefix(':') {
+        None => "queue".to_string(),
+        Some("priority") => "priority".to_string(),
+        Some(capability) => format!("capability.{}", capability.replace(':', ".")),
+    };
+    format!("{}.{}", prefix, lane)
+}
+
+/// A task to store and, unless it waits or runs elsewhere, queue
+pub struct NewTask<'a> {
+    pub task_id: &'a str,
+    /// The task record, already in its envelope
+    pub record: String,
+    pub labels: &'a BTreeMap<String, String>,
+    /// Queue to hand the task to and its deadline
+    pub queue: Option<(&'a str, DateTime<Utc>)>,
+    /// Whether the history table and lifecycle events follow the task
+    pub tracked: bool,
+}
+
+/// Hands task ids to agents over the configured backend. Queues are named
+/// as in Redis whichever backend carries them.
+#[derive(Clone)]
+pub enum TaskQueue {
+    Redis,
+    #[cfg(feature = "nats")]
+    Nats(std::sync::Arc<nats::NatsQueue>),
+}
+
+impl TaskQueue {
+    pub async fn connect(settings: &QueueSettings) -> anyhow::Result<Self> {
+        match settings.backend {
+            QueueBackend::Redis => Ok(TaskQueue::Redis),
+            #[cfg(feature = "nats")]
+            QueueBackend::Nats => Ok(TaskQueue::Nats(std::sync::Arc::new(
+                nats::NatsQueue::connect(&settings.nats).await?,
+            ))),
+            #[cfg(not(feature = "nats"))]
+            QueueBackend::Nats => anyhow::bail!("gateway built without the nats feature"),
+        }
+    }
+
+    pub fn backend(&self) -> QueueBackend {
+        match self {
+            TaskQueue::Redis => QueueBackend::Redis,
+            #[cfg(feature = "nats")]
+            TaskQueue::Nats(_) => QueueBackend::Nats,
+        }
+    }
+
+    /// Hand `task_id`, due by `deadline`, to the agents consuming `queue`.
+    /// `conn` is only used by the Redis backend.
+    #[cfg_attr(not(feature = "nats"), allow(unused_variables))]
+    pub async fn push(
+        &self,
+        conn: &mut redis::aio::Connection,
+        queue: &str,
+        task_id: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<(), QueueError> {
+        match self {
+            TaskQueue::Redis => conn
+                .lpush(queue, task_id)
+                .instrument(telemetry::redis_span("LPUSH", queue))
+                .await
+                .map_err(QueueError::from),
+            #[cfg(feature = "nats")]
+            TaskQueue::Nats(nats) => nats.publish(queue, task_id, deadline).await,
+        }
+    }
+
+    /// The Redis transaction storing `task`, held back from its queue if
+    /// `hold` is set
+    fn submission(&self, task: &NewTask<'_>, hold: bool) -> redis::Pipeline {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set(format!("task:{}", task.task_id), &task.record)
+            .ignore();
+        labels::index_in(&mut pipe, task.task_id, task.labels);
+        if task.tracked {
+            history::track_in(&mut pipe, task.task_id);
+            events::track_in(&mut pipe, task.task_id);
+        }
+        match task.queue {
+            Some((queue, deadline)) if hold => {
+                maintenance::hold_in(&mut pipe, task.task_id, queue, deadline)
+            }
+            Some((queue, deadline)) => {
+                if matches!(self, TaskQueue::Redis) {
+                    pipe.lpush(queue, task.task_id).ignore();
+                }
+                watchdog::track_in(&mut pipe, task.task_id, deadline);
+            }
+            None => {}
+        }
+        pipe
+    }
+
+    /// Store a new task and hand it to its queue in one transaction, unless
+    /// a task with its id is already stored
+    pub async fn submit<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        task: NewTask<'_>,
+    ) -> Result<(), QueueError> {
+        let hold = maintenance::holding();
+        create(conn, task.task_id, &self.submission(&task, hold)).await?;
+
+        match (self, task.queue.filter(|_| !hold)) {
+            #[cfg(feature = "nats")]
+            (TaskQueue::Nats(nats), Some((queue, deadline))) => {
+                nats.publish(queue, task.task_id, deadline).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Take `task_id` back off `queue` if no agent has picked it up. JetStream
+    /// cannot withdraw a message by content, so there agents skip tasks that
+    /// reached a final status instead.
+    pub async fn withdraw(
+        &self,
+        conn: &mut redis::aio::Connection,
+        queue: &str,
+        task_id: &str,
+    ) -> Result<(), QueueError> {
+        match self {
+            TaskQueue::Redis => Ok(conn.lrem(queue, 0, task_id).await?),
+            #[cfg(feature = "nats")]
+            TaskQueue::Nats(_) => Ok(()),
+        }
+    }
+
+    /// Tasks waiting in each of `queues`
+    pub async fn depths(
+        &self,
+        conn: &mut redis::aio::Connection,
+        queues: &[&str],
+    ) -> Result<Vec<u64>, QueueError> {
+        match self {
+            TaskQueue::Redis => {
+                let mut pipe = redis::pipe();
+                for queue in queues {
+                    pipe.llen(*queue);
+                }
+                Ok(pipe.query_async(conn).await?)
+            }
+            #[cfg(feature = "nats")]
+            TaskQueue::Nats(nats) => nats.depths(queues).await,
+        }
+    }
+
+    /// The id of the task waiting longest in each of `queues`, where the
+    /// backend tells; NATS does not
+    pub async fn oldest(
+        &self,
+        conn: &mut redis::aio::Connection,
+        queues: &[&str],
+    ) -> Result<Vec<Option<String>>, QueueError> {
+        match self {
+            TaskQueue::Redis => {
+                // Agents pop from the right
+                let mut pipe = redis::pipe();
+                for queue in queues {
+                    pipe.lindex(*queue, -1);
+                }
+                Ok(pipe.query_async(conn).await?)
+            }
+            #[cfg(feature = "nats")]
+            TaskQueue::Nats(_) => Ok(vec![None; queues.len()]),
+        }
+    }
+
+    /// Check the backend beyond Redis is reachable
+    pub async fn ping(&self) -> Result<(), QueueError> {
+        match self {
+            TaskQueue::Redis => Ok(()),
+            #[cfg(feature = "nats")]
+            TaskQueue::Nats(nats) => nats.ping().await,
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use async_nats::jetstream::{self, stream};
+    use chrono::{DateTime, Utc};
+    use futures_util::TryStreamExt;
+    use tracing::info;
+
+    use super::{subject, QueueError};
+    use crate::config::NatsSettings;
+
+    /// Header carrying a task's deadline in unix seconds
+    const DEADLINE_HEADER: &str = "Claw-Deadline";
+
+    fn nats_error(e: impl std::error::Error + Send + Sync + 'static) -> QueueError {
+        QueueError::Nats(Box::new(e))
+    }
+
+    pub struct NatsQueue {
+        jetstream: jetstream::Context,
+        stream: String,
+        subject_prefix: String,
+    }
+
+    impl NatsQueue {
+        /// Connect and make sure the work-queue stream exists
+        pub async fn connect(settings: &NatsSettings) -> anyhow::Result<Self> {
+            let client = async_nats::connect(&settings.url).await?;
+            let jetstream = jetstream::new(client);
+            jetstream
+                .get_or_create_stream(stream::Config {
+                    name: settings.stream.clone(),
+                    subjects: vec![format!("{}.>", settings.subject_prefix)],
+                    retention: stream::RetentionPolicy::WorkQueue,
+                    ..Default::default()
+                })
+                .await?;
+            info!(
+                "Task queue on NATS stream {} at {}",
+                settings.stream, settings.url
+            );
+
+            Ok(Self {
+                jetstream,
+                stream: settings.stream.clone(),
+                subject_prefix: settings.subject_prefix.clone(),
+            })
+        }
+
+        /// Publish `task_id` and wait for the stream to store it
+        pub async fn publish(
+            &self,
+            queue: &str,
+            task_id: &str,
+            deadline: DateTime<Utc>,
+        ) -> Result<(), QueueError> {
+            let subject = subject(&self.subject_prefix, queue);
+            let mut headers = async_nats::HeaderMap::new();
+            headers.insert(DEADLINE_HEADER, deadline.timestamp().to_string().as_str());
+            self.jetstream
+                .publish_with_headers(subject, headers, task_id.to_string().into())
+                .await
+                .map_err(nats_error)?
+                .await
+                .map_err(nats_error)?;
+            Ok(())
+        }
+
+        /// Unacknowledged messages on each queue's subject
+        pub async fn depths(&self, queues: &[&str]) -> Result<Vec<u64>, QueueError> {
+            let stream = self
+                .jetstream
+                .get_stream(&self.stream)
+                .await
+                .map_err(nats_error)?;
+            let mut depths = Vec::with_capacity(queues.len());
+            for queue in queues {
+                let subjects = stream
+                    .info_with_subjects(subject(&self.subject_prefix, queue))
+                    .await
+                    .map_err(nats_error)?;
+                let counts: Vec<(String, usize)> =
+                    subjects.try_collect().await.map_err(nats_error)?;
+                depths.push(counts.iter().map(|(_, n)| *n as u64).sum());
+            }
+            Ok(depths)
+        }
+
+        pub async fn ping(&self) -> Result<(), QueueError> {
+            self.jetstream
+                .get_stream(&self.stream)
+                .await
+                .map_err(nats_error)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope;
+    use claw_test::FakeRedis;
+    use serde_json::json;
+
+    #[test]
+    fn names_a_subject_per_queue() {
+        for (queue, lane) in [
+            ("agent:queue", "queue"),
+            ("agent:queue:priority", "priority"),
+            ("agent:queue:code", "capability.code"),
+            ("agent:queue:code:priority", "capability.code.priority"),
+            ("agent:direct:worker-1", "agent.worker-1"),
+            ("agent:direct:host.example", "agent.host_example"),
+        ] {
+            assert_eq!(subject("claw.tasks", queue), format!("claw.tasks.{}", lane));
+        }
+    }
+
+    #[test]
+    fn submits_tasks_in_one_transaction() {
+        let labels = BTreeMap::from([("team".to_string(), "ops".to_string())]);
+        let task = NewTask {
+            task_id: "t1",
+            record: "{}".to_string(),
+            labels: &labels,
+            queue: Some(("agent:queue", Utc::now())),
+            tracked: true,
+        };
+        let packed = TaskQueue::Redis
+            .submission(&task, false)
+            .get_packed_pipeline();
+        let packed = String::from_utf8_lossy(&packed);
+        assert!(packed.starts_with("*1\r\n$5\r\nMULTI"));
+        assert!(packed.ends_with("*1\r\n$4\r\nEXEC\r\n"));
+        assert!(packed.contains("$5\r\nLPUSH\r\n$11\r\nagent:queue\r\n$2\r\nt1"));
+
+        // Tasks waiting on others are stored but not queued
+        let waiting = NewTask {
+            queue: None,
+            ..task
+        };
+        let packed = TaskQueue::Redis
+            .submission(&waiting, false)
+            .get_packed_pipeline();
+        assert!(!String::from_utf8_lossy(&packed).contains("LPUSH"));
+
+        // Maintenance parks queued tasks in the holding list instead
+        let held = NewTask {
+            queue: Some(("agent:queue", Utc::now())),
+            ..waiting
+        };
+        let packed = TaskQueue::Redis
+            .submission(&held, true)
+            .get_packed_pipeline();
+        let packed = String::from_utf8_lossy(&packed).to_string();
+        assert!(!packed.contains("LPUSH") && packed.contains("maintenance:held"));
+    }
+
+    #[test]
+    fn untracked_tasks_send_no_events() {
+        events::set_subscribed(true);
+        let labels = BTreeMap::new();
+        let task = NewTask {
+            task_id: "canary-1",
+            record: "{}".to_string(),
+            labels: &labels,
+            queue: Some(("agent:queue", Utc::now())),
+            tracked: true,
+        };
+        let packed = TaskQueue::Redis.submission(&task, false).get_packed_pipeline();
+        assert!(String::from_utf8_lossy(&packed).contains("events:tracked"));
+
+        let untracked = NewTask {
+            tracked: false,
+            ..task
+        };
+        let packed = TaskQueue::Redis
+            .submission(&untracked, false)
+            .get_packed_pipeline();
+        assert!(!String::from_utf8_lossy(&packed).contains("events:tracked"));
+    }
+
+    #[tokio::test]
+    async fn refuses_resubmitted_task_ids() {
+        let mut redis = FakeRedis::new();
+        let owned = json!({"status": "completed", "submitted_by": "alice"});
+        let owned = envelope::encode(&owned).unwrap();
+        redis.set("task:t1", &owned);
+
+        // Another key resubmitting the id must not take the task over
+        let labels = BTreeMap::new();
+        let takeover = json!({"status": "pending", "submitted_by": "bob"});
+        let takeover = NewTask {
+            task_id: "t1",
+            record: envelope::encode(&takeover).unwrap(),
+            labels: &labels,
+            queue: Some(("agent:queue", Utc::now())),
+            tracked: true,
+        };
+        let refused = TaskQueue::Redis.submit(&mut redis, takeover).await.unwrap_err();
+        assert!(matches!(&refused, QueueError::Duplicate(id) if id == "t1"));
+        let refused = ApiError::from(refused);
+        assert_eq!(refused.status, StatusCode::CONFLICT);
+        assert_eq!(refused.code, "duplicate_task");
+        assert_eq!(redis.get("task:t1"), Some(owned));
+    }
+}
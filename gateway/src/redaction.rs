@@ -0,0 +1,233 @@
+//! PII redaction.
+//!
+//! Detectors find personal data in text and replace it with a marker:
+//! `email` (`[email]`), `phone` (`[phone]`, international numbers with `+`
+//! or separated national ones), `credit_card` (`[card]`, 13 to 19 digits
+//! passing the Luhn check) and any regular expressions in
+//! `redaction.patterns` (`[redacted]`). Task inputs are redacted before they
+//! are stored or queued, for every tenant while `redaction.enabled` and for
+//! the tenants `redaction.tenants` switches on or off by key id, the channel
+//! adaptors counting as the tenants `telegram`, `email` and `twilio`. With
+//! `redaction.logs`, every line the gateway logs to stdout or keeps for
+//! support bundles is redacted as well; spans exported over OTLP are not.
+//!
+//! Detectors and patterns are compiled once at startup.
+
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::io::Write;
+use std::sync::OnceLock;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::RedactionSettings;
+
+/// Built-in detectors by name
+pub const DETECTORS: [&str; 3] = ["email", "phone", "credit_card"];
+
+const EMAIL: &str = r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b";
+const PHONE: &str = r"(?:\+\d[\d ().-]{6,16}\d|\(?\b\d{3}\)?[ .-]\d{3,4}[ .-]\d{4})\b";
+const CARD: &str = r"\b\d(?:[ -]?\d){12,18}\b";
+
+/// The configured redaction, once the configuration is loaded
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+/// A compiled set of detectors
+pub struct Redactor {
+    /// Patterns in the order they apply, with their markers; cards go
+    /// before phone numbers, which they would otherwise look like
+    patterns: Vec<(Regex, &'static str)>,
+    settings: RedactionSettings,
+}
+
+/// Whether `digits` pass the Luhn checksum
+fn luhn(digits: &str) -> bool {
+    let mut sum = 0;
+    for (i, d) in digits
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+    {
+        sum += match (i % 2 == 1, d * 2) {
+            (true, doubled) if doubled > 9 => doubled - 9,
+            (true, doubled) => doubled,
+            (false, _) => d,
+        };
+    }
+    sum % 10 == 0
+}
+
+impl Redactor {
+    pub fn from_config(settings: &RedactionSettings) -> Result<Self, String> {
+        let mut patterns = Vec::new();
+        for (name, pattern, marker) in [
+            ("credit_card", CARD, "[card]"),
+            ("email", EMAIL, "[email]"),
+            ("phone", PHONE, "[phone]"),
+        ] {
+            if settings.detectors.iter().any(|d| d == name) {
+                patterns.push((Regex::new(pattern).map_err(|e| e.to_string())?, marker));
+            }
+        }
+        for pattern in &settings.patterns {
+            let regex = Regex::new(pattern).map_err(|e| format!("{}: {}", pattern, e))?;
+            patterns.push((regex, "[redacted]"));
+        }
+        Ok(Self {
+            patterns,
+            settings: settings.clone(),
+        })
+    }
+
+    /// `text` with every detected item replaced by its marker
+    pub fn redact_str<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for (regex, marker) in &self.patterns {
+            if !regex.is_match(&text) {
+                continue;
+            }
+            let replaced = regex.replace_all(&text, |caps: &regex::Captures| {
+                let found = &caps[0];
+                if *marker == "[card]" && !luhn(found) {
+                    found.to_string()
+                } else {
+                    marker.to_string()
+                }
+            });
+            text = Cow::Owned(replaced.into_owned());
+        }
+        text
+    }
+
+    /// Redact every string in `value`, object keys aside
+    pub fn redact(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => {
+                if let Cow::Owned(redacted) = self.redact_str(text) {
+                    *text = redacted;
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.redact(v)),
+            _ => {}
+        }
+    }
+
+    /// Whether the inputs of `tenant` are redacted
+    fn applies_to(&self, tenant: &str) -> bool {
+        self.settings
+            .tenants
+            .get(tenant)
+            .copied()
+            .unwrap_or(self.settings.enabled)
+    }
+}
+
+/// Redact task inputs and logs as `settings` say from now on
+pub fn install(settings: &RedactionSettings) -> Result<(), String> {
+    let redactor = Redactor::from_config(settings)?;
+    let _ = REDACTOR.set(redactor);
+    Ok(())
+}
+
+/// Redact a task input submitted by `tenant`, if its inputs are redacted
+pub fn input(tenant: &str, value: &mut Value) {
+    if let Some(redactor) = REDACTOR.get().filter(|r| r.applies_to(tenant)) {
+        redactor.redact(value);
+    }
+}
+
+/// A log line, redacted if logs are
+pub fn log_line(line: &str) -> Cow<'_, str> {
+    match REDACTOR.get().filter(|r| r.settings.logs) {
+        Some(redactor) => redactor.redact_str(line),
+        None => Cow::Borrowed(line),
+    }
+}
+
+/// Writes log output to stdout, redacted if logs are
+pub struct RedactingStdout;
+
+impl<'a> MakeWriter<'a> for RedactingStdout {
+    type Writer = RedactingStdout;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingStdout
+    }
+}
+
+impl Write for RedactingStdout {
+    // The fmt layer hands over each event as one formatted line
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        std::io::stdout().write_all(log_line(&line).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn redactor(patterns: &[&str]) -> Redactor {
+        let settings = RedactionSettings {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            ..RedactionSettings::default()
+        };
+        Redactor::from_config(&settings).unwrap()
+    }
+
+    #[test]
+    fn finds_builtin_items() {
+        let redactor = redactor(&[]);
+        for (text, redacted) in [
+            ("mail jane.doe@example.co.uk", "mail [email]"),
+            ("call +44 20 7946 0958 today", "call [phone] today"),
+            ("call (415) 555-0100", "call [phone]"),
+            ("call 415-555-0100", "call [phone]"),
+            ("card 4111 1111 1111 1111 ok", "card [card] ok"),
+            ("card 4111-1111-1111-1111", "card [card]"),
+        ] {
+            assert_eq!(redactor.redact_str(text), redacted);
+        }
+    }
+
+    #[test]
+    fn leaves_lookalikes() {
+        let redactor = redactor(&[]);
+        for text in [
+            "due 2026-10-14T15:16:31Z",
+            "task 4f1c2a9e-1234-5678-9abc-def012345678",
+            "order 4111 1111 1111 1112",
+            "took 1500 ms, 42 items",
+        ] {
+            assert_eq!(redactor.redact_str(text), text);
+        }
+    }
+
+    #[test]
+    fn applies_patterns_throughout_values() {
+        let redactor = redactor(&[r"EMP-\d{6}"]);
+        let mut input = json!({"text": "EMP-123456 at bob@example.com", "n": [1, "EMP-000001"]});
+        redactor.redact(&mut input);
+        assert_eq!(
+            input,
+            json!({"text": "[redacted] at [email]", "n": [1, "[redacted]"]})
+        );
+    }
+
+    #[test]
+    fn tenants_override_the_default() {
+        let mut settings = RedactionSettings::default();
+        settings.tenants.insert("telegram".to_string(), true);
+        let redactor = Redactor::from_config(&settings).unwrap();
+        assert!(redactor.applies_to("telegram"));
+        assert!(!redactor.applies_to("anonymous"));
+    }
+}
@@ -0,0 +1,216 @@
+//! Text renditions of task results.
+//!
+//! `GET /task/:id` answers `Accept: text/plain`, `text/markdown` or
+//! `text/html`, when rated above the encodings of [`codec`](crate::codec),
+//! with the task's result alone instead of the JSON record. Text results are
+//! taken as markdown: they are served as they are as text or markdown and
+//! rendered to an HTML page, with any HTML in them escaped. Other results
+//! are pretty-printed JSON, fenced in markdown and preformatted in HTML.
+//! Unsettled and failed tasks are described in a line of text instead.
+
+use axum::{
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use pulldown_cmark::{html, Event, Options, Parser};
+use serde_json::Value;
+
+use crate::codec::{self, Format};
+use crate::AgentResponse;
+
+/// Content security policy of HTML renditions: nothing may load or run
+const HTML_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors 'none'";
+
+/// Text renditions of a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rendition {
+    Text,
+    Markdown,
+    Html,
+}
+
+impl Rendition {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "text/plain" => Some(Rendition::Text),
+            "text/markdown" | "text/x-markdown" => Some(Rendition::Markdown),
+            "text/html" => Some(Rendition::Html),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Rendition::Text => "text/plain; charset=utf-8",
+            Rendition::Markdown => "text/markdown; charset=utf-8",
+            Rendition::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    /// The rendition `Accept` rates above every encoding, if any
+    pub fn accepted(headers: &HeaderMap) -> Option<Self> {
+        let (rendition, quality) = codec::preferred(headers, Rendition::from_media_type)?;
+        let encoded = codec::preferred(headers, Format::from_media_type).map_or(0.0, |(_, q)| q);
+        (quality > encoded).then_some(rendition)
+    }
+
+    /// `response` in this rendition
+    pub fn render(self, response: &AgentResponse) -> String {
+        let result = match (&response.result, response.status.as_str()) {
+            (Some(result), "completed") => result,
+            _ => return self.describe(response),
+        };
+        match (self, result) {
+            (Rendition::Text | Rendition::Markdown, Value::String(text)) => text.clone(),
+            (Rendition::Text, result) => pretty(result),
+            (Rendition::Markdown, result) => format!("```json\n{}\n```\n", pretty(result)),
+            (Rendition::Html, Value::String(text)) => page(&response.task_id, &to_html(text)),
+            (Rendition::Html, result) => {
+                let code = format!("<pre><code>{}</code></pre>", escape(&pretty(result)));
+                page(&response.task_id, &code)
+            }
+        }
+    }
+
+    /// A line on a task without a result to show
+    fn describe(self, response: &AgentResponse) -> String {
+        let line = match &response.error {
+            Some(error) => format!("Task {} {}: {}", response.task_id, response.status, error),
+            None => format!("Task {} is {}", response.task_id, response.status),
+        };
+        match self {
+            Rendition::Text | Rendition::Markdown => line,
+            Rendition::Html => page(&response.task_id, &format!("<p>{}</p>", escape(&line))),
+        }
+    }
+}
+
+/// A rendered body, with the policy HTML is served under
+pub struct Rendered(pub String, pub Rendition);
+
+impl IntoResponse for Rendered {
+    fn into_response(self) -> Response {
+        let Rendered(body, rendition) = self;
+        let mut response =
+            ([(header::CONTENT_TYPE, rendition.content_type())], body).into_response();
+        if rendition == Rendition::Html {
+            response.headers_mut().insert(
+                header::CONTENT_SECURITY_POLICY,
+                header::HeaderValue::from_static(HTML_POLICY),
+            );
+        }
+        response
+    }
+}
+
+fn pretty(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
+
+/// `text` with the characters HTML and XML give meaning escaped
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `markdown` as HTML, showing rather than running any HTML it holds
+fn to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let events = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        event => event,
+    });
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events);
+    rendered
+}
+
+/// A standalone page around `body`
+fn page(task_id: &str, body: &str) -> String {
+    format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Task {}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(task_id),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn completed(result: Value) -> AgentResponse {
+        AgentResponse {
+            task_id: "t1".to_string(),
+            status: "completed".to_string(),
+            result: Some(result),
+            error: None,
+            signature: None,
+        }
+    }
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn renditions_yield_to_preferred_encodings() {
+        assert_eq!(Rendition::accepted(&HeaderMap::new()), None);
+        assert_eq!(Rendition::accepted(&accept("*/*")), None);
+        assert_eq!(
+            Rendition::accepted(&accept("text/html,application/xml;q=0.9,*/*;q=0.8")),
+            Some(Rendition::Html)
+        );
+        assert_eq!(
+            Rendition::accepted(&accept("text/plain;q=0.5, application/json")),
+            None
+        );
+        assert_eq!(
+            Rendition::accepted(&accept("text/markdown, application/json;q=0.5")),
+            Some(Rendition::Markdown)
+        );
+    }
+
+    #[test]
+    fn renders_markdown_without_running_html() {
+        let response = completed(json!("# Done\n\n<script>alert(1)</script>"));
+        let html = Rendition::Html.render(&response);
+        assert!(html.contains("<h1>Done</h1>"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+        assert_eq!(
+            Rendition::Markdown.render(&response),
+            "# Done\n\n<script>alert(1)</script>"
+        );
+    }
+
+    #[test]
+    fn pretty_prints_structured_results() {
+        let response = completed(json!({"total": 3}));
+        assert_eq!(Rendition::Text.render(&response), "{\n  \"total\": 3\n}");
+        assert!(Rendition::Markdown
+            .render(&response)
+            .starts_with("```json\n{"));
+
+        let pending = AgentResponse {
+            status: "pending".to_string(),
+            result: None,
+            ..completed(Value::Null)
+        };
+        assert_eq!(Rendition::Text.render(&pending), "Task t1 is pending");
+    }
+}
@@ -0,0 +1,99 @@
+//! Redis read replica.
+//!
+//! With `redis.replica_host` set, `GET /task/:id` and the Telegram result
+//! polling read from the replica so the primary is left to queue writes.
+//! A replica lags the primary: a task it has no trace of may have been
+//! submitted a moment ago, so such reads are repeated on the primary. Writes
+//! made on the way, like populating the result cache, always go to the
+//! primary. A replica that cannot be reached sends reads to the primary too.
+
+use redis::Client;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use crate::config::RedisSettings;
+use crate::metrics::Counter;
+
+/// The replica, if one is configured
+#[derive(Clone, Default)]
+pub struct ReadReplica {
+    client: Option<Arc<Client>>,
+    metrics: Arc<ReplicaMetrics>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplicaMetrics {
+    /// Reads answered by the replica
+    pub reads: Counter,
+    /// Reads the replica missed or could not serve, repeated on the primary
+    pub fallbacks: Counter,
+}
+
+impl ReadReplica {
+    pub fn from_config(settings: &RedisSettings) -> redis::RedisResult<Self> {
+        let client = settings
+            .replica_url()
+            .map(Client::open)
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self {
+            client,
+            metrics: Arc::default(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.client.is_some()
+    }
+
+    pub fn metrics(&self) -> Arc<ReplicaMetrics> {
+        self.metrics.clone()
+    }
+
+    /// A connection to the replica; none without one, or when it is down
+    pub async fn connection(&self) -> Option<redis::aio::Connection> {
+        let client = self.client.as_ref()?;
+        match client.get_async_connection().await {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                warn!("Redis replica unavailable, reading from the primary: {}", e);
+                self.metrics.fallbacks.inc();
+                None
+            }
+        }
+    }
+
+    /// Count a read the replica answered
+    pub fn hit(&self) {
+        self.metrics.reads.inc();
+    }
+
+    /// Count a read repeated on the primary
+    pub fn miss(&self, what: &str) {
+        debug!("Redis replica missed {}, reading from the primary", what);
+        self.metrics.fallbacks.inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replica_shares_the_primary_password_and_port() {
+        let mut settings = RedisSettings::default();
+        assert!(!ReadReplica::from_config(&settings).unwrap().is_enabled());
+
+        settings.replica_host = Some("redis-replica".to_string());
+        assert_eq!(
+            settings.replica_url().as_deref(),
+            Some("redis://:default@redis-replica:6379")
+        );
+        settings.replica_port = Some(6380);
+        assert!(settings
+            .replica_url()
+            .unwrap()
+            .ends_with("@redis-replica:6380"));
+        assert!(ReadReplica::from_config(&settings).unwrap().is_enabled());
+    }
+}
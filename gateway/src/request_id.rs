@@ -0,0 +1,101 @@
+//! Request ID propagation.
+//!
+//! Every HTTP request carries an `X-Request-Id`: the caller's value when it
+//! is well formed, otherwise a fresh UUID. The ID is attached to the tracing
+//! span for the request, echoed on the response, stored in the task record
+//! and forwarded to peer gateways, so one task can be followed through the
+//! gateway, Redis and the agent logs.
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::future::Future;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Header carrying the request ID
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest caller-supplied ID we accept before generating our own
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Identifier for one request, available as a request extension
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accept a caller-supplied ID made of visible ASCII only
+    fn parse(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?.trim();
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.to_string()))
+    }
+
+    /// The caller's `X-Request-Id` from `headers`, or a fresh ID
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(&REQUEST_ID_HEADER)
+            .and_then(Self::parse)
+            .unwrap_or_else(Self::generate)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// ID of the request currently being handled, if any
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// Run `fut` with `id` as the current request ID inside a tracing span
+pub async fn scope<F: Future>(id: RequestId, fut: F) -> F::Output {
+    let span = tracing::info_span!("request", request_id = %id);
+    CURRENT.scope(id, fut.instrument(span)).await
+}
+
+/// Middleware assigning a [`RequestId`] and echoing it on the response
+pub async fn propagate(mut req: Request, next: Next) -> Response {
+    let id = RequestId::from_headers(req.headers());
+    req.extensions_mut().insert(id.clone());
+
+    let span = tracing::info_span!(
+        "http",
+        otel.name = %format!("{} {}", req.method(), req.uri().path()),
+        otel.kind = "server",
+        request_id = %id,
+        method = %req.method(),
+        path = %req.uri().path(),
+    );
+    let mut response = CURRENT
+        .scope(id.clone(), next.run(req))
+        .instrument(span)
+        .await;
+
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER.clone(), value);
+    }
+    response
+}
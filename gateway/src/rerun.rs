@@ -0,0 +1,235 @@
+//! Task reruns.
+//!
+//! `POST /task/:id/rerun` submits a new task with the input, config,
+//! capability, timeout, labels and map spec of an earlier one, so callers
+//! need not rebuild the original request by hand. The body is optional:
+//! `{"task_id": "...", "config": {...}, "timeout_seconds": N}` names the new
+//! task (a UUID otherwise), merges `config` over the original config and
+//! replaces the timeout. Reruns skip the result cache and deduplication,
+//! which would answer them with the result being rerun.
+//!
+//! The new record names the task it reruns as `parent_task_id`, and the
+//! Redis list `reruns:{task_id}` keeps a task's reruns in the order they
+//! were submitted. `GET /task/:id/reruns` lists every descendant, reruns of
+//! reruns included, with their parents and statuses. Reruns are open to
+//! whoever may read the original task.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::HeaderMap,
+    response::Response,
+    Extension, Json,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use tracing::{error, info};
+
+use crate::attachments;
+use crate::auth::Principal;
+use crate::cache::CacheControl;
+use crate::codec::Format;
+use crate::error::ApiError;
+use crate::validation::{self, ValidationError};
+use crate::{enqueue_task, merge, redis_connection, schema, AgentRequest, AppState};
+
+/// Most descendants `GET /task/:id/reruns` walks
+const MAX_DESCENDANTS: usize = 1000;
+
+/// Redis list of the reruns of a task, oldest first
+pub fn reruns_key(task_id: &str) -> String {
+    format!("reruns:{}", task_id)
+}
+
+/// Body of `POST /task/:id/rerun`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RerunRequest {
+    task_id: Option<String>,
+    /// Merged over the original task's config
+    config: Option<Value>,
+    timeout_seconds: Option<u64>,
+}
+
+/// A rerun in `GET /task/:id/reruns`
+#[derive(Debug, Serialize)]
+pub struct Descendant {
+    task_id: String,
+    parent_task_id: String,
+    status: String,
+    created_at: Option<String>,
+}
+
+/// The record of `task_id`, if `principal` may read it
+async fn readable(
+    conn: &mut redis::aio::Connection,
+    principal: Option<&Principal>,
+    task_id: &str,
+) -> Result<Value, ApiError> {
+    let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+    let Some(task) = task.map(|t| schema::decode_task(&t)).transpose()? else {
+        return Err(ApiError::not_found(format!("Task {} not found", task_id)));
+    };
+    if attachments::may_read(principal, task["submitted_by"].as_str()) {
+        return Ok(task);
+    }
+    Err(match principal {
+        Some(_) => ApiError::forbidden("Task belongs to another caller"),
+        None => ApiError::unauthorized("Missing bearer token"),
+    })
+}
+
+/// The submission rerunning `task`, stored as `parent_task_id`
+fn rerun_of(
+    parent_task_id: &str,
+    task: &Value,
+    req: RerunRequest,
+) -> Result<AgentRequest, ApiError> {
+    let mut config = task["config"].clone();
+    if let Some(overrides) = &req.config {
+        if config.is_null() {
+            config = Value::Object(Default::default());
+        }
+        merge::merge(&mut config, overrides)
+            .map_err(|e| ApiError::bad_request("invalid_config", e.to_string()))?;
+    }
+    let labels: BTreeMap<String, String> =
+        serde_json::from_value(task["labels"].clone()).unwrap_or_default();
+    let map = task
+        .get("map")
+        .filter(|map| !map.is_null())
+        .map(|map| serde_json::from_value(map.clone()))
+        .transpose()?;
+    Ok(AgentRequest {
+        task_id: req
+            .task_id
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        input: task["input"].clone(),
+        config: (!config.is_null()).then_some(config),
+        capability: task["capability"].as_str().map(String::from),
+        timeout_seconds: req.timeout_seconds.or(task["timeout_secs"].as_u64()),
+        labels,
+        depends_on: Vec::new(),
+        map,
+        parent_task_id: Some(parent_task_id.to_string()),
+    })
+}
+
+// Submit a task again, linked to the original
+pub async fn rerun_task(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    headers: HeaderMap,
+    body: Result<Json<RerunRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    // A body without a JSON content type carries no overrides
+    let req = match body {
+        Ok(Json(req)) => req,
+        Err(JsonRejection::MissingJsonContentType(_)) => RerunRequest::default(),
+        Err(e) => return Err(ValidationError::from(e).into()),
+    };
+
+    let mut conn = redis_connection(&state).await?;
+    let task = readable(
+        &mut conn,
+        principal.as_ref().map(|Extension(p)| p),
+        &task_id,
+    )
+    .await?;
+    let rerun = rerun_of(&task_id, &task, req)?;
+    if let Err(e) = validation::validate_request(&rerun, &state.config.current().limits) {
+        error!("Rerun validation failed: {:?}", e.violations);
+        return Err(e.into());
+    }
+
+    let rerun_id = rerun.task_id.clone();
+    let cache_control = CacheControl {
+        no_cache: true,
+        ..CacheControl::default()
+    };
+    let submitted = enqueue_task(state, None, principal, cache_control, rerun).await?;
+    conn.rpush::<_, _, ()>(reruns_key(&task_id), &rerun_id)
+        .await?;
+    info!("Task {} rerun as {}", task_id, rerun_id);
+    Ok(submitted.respond(Format::accepted(&headers)))
+}
+
+// List a task's reruns and theirs, breadth first
+pub async fn list_reruns(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let task = readable(
+        &mut conn,
+        principal.as_ref().map(|Extension(p)| p),
+        &task_id,
+    )
+    .await?;
+
+    let mut descendants = Vec::new();
+    let mut parents = VecDeque::from([task_id.clone()]);
+    while let Some(parent) = parents.pop_front() {
+        let reruns: Vec<String> = conn.lrange(reruns_key(&parent), 0, -1).await?;
+        for rerun in reruns {
+            if descendants.len() == MAX_DESCENDANTS {
+                parents.clear();
+                break;
+            }
+            let record: Option<String> = conn.get(format!("task:{}", rerun)).await?;
+            let record = record.and_then(|r| schema::decode_task(&r).ok());
+            let Some(record) = record else {
+                // Purged since, along with any reruns of its own
+                continue;
+            };
+            descendants.push(Descendant {
+                task_id: rerun.clone(),
+                parent_task_id: parent.clone(),
+                status: record["status"].as_str().unwrap_or("unknown").to_string(),
+                created_at: record["created_at"].as_str().map(String::from),
+            });
+            parents.push_back(rerun);
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "task_id": task_id,
+        "parent_task_id": task["parent_task_id"],
+        "reruns": descendants,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reruns_keep_the_original_and_merge_overrides() {
+        let task = json!({
+            "input": "summarise",
+            "config": {"model": "small", "temperature": 0.2},
+            "capability": "search",
+            "timeout_secs": 120,
+            "labels": {"team": "ops"},
+        });
+        let req = RerunRequest {
+            config: Some(json!({"model": "large"})),
+            ..RerunRequest::default()
+        };
+        let rerun = rerun_of("t1", &task, req).unwrap();
+        assert_eq!(rerun.input, json!("summarise"));
+        assert_eq!(
+            rerun.config,
+            Some(json!({"model": "large", "temperature": 0.2}))
+        );
+        assert_eq!(rerun.capability.as_deref(), Some("search"));
+        assert_eq!(rerun.timeout_seconds, Some(120));
+        assert_eq!(rerun.labels["team"], "ops");
+        assert_eq!(rerun.parent_task_id.as_deref(), Some("t1"));
+        assert_ne!(rerun.task_id, "t1");
+    }
+}
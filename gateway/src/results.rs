@@ -0,0 +1,214 @@
+//! Raw task results.
+//!
+//! `GET /task/:id/result/raw` answers a completed task's result as bytes
+//! instead of a JSON field, for outputs too large or too binary for one.
+//! Agents store such a result as a reference under `raw`, either
+//! `{"raw": {"chunks": n, ...}}` with the contents split over the Redis keys
+//! `result:{task_id}:chunk:0` to `result:{task_id}:chunk:{n-1}`, or
+//! `{"raw": {"object": "<name>", ...}}` for an object kept in the attachment
+//! bucket (`attachments.backend = "s3"`) at
+//! `{attachments.s3.prefix}{task_id}/result/<name>`. The reference may also
+//! give `content_type`, `name` (the download's file name) and `size`.
+//! Contents are streamed a chunk at a time. Results without a reference are
+//! answered as they are, strings as text and anything else as JSON.
+//!
+//! Only the submitter, agents, operators, viewers and admins may download
+//! the result of a task submitted with an API key, as with `GET /task/:id`.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures_util::stream;
+use redis::AsyncCommands;
+use serde_json::Value;
+
+use crate::attachments::{self, AttachmentStore};
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::{annotations, envelope, redis_connection, AppState};
+
+/// Redis key of one chunk of a raw result
+fn chunk_key(task_id: &str, index: u64) -> String {
+    format!("result:{}:chunk:{}", task_id, index)
+}
+
+/// Where the contents of a raw result are kept
+enum Contents {
+    /// Redis chunks, this many
+    Chunks(u64),
+    /// An object in the attachment bucket
+    Object(String),
+}
+
+/// Where a result's contents are kept, when it is a raw result reference
+fn contents(result: &Value) -> Result<Option<Contents>, ApiError> {
+    let raw = &result["raw"];
+    if let Some(chunks) = raw["chunks"].as_u64() {
+        return Ok(Some(Contents::Chunks(chunks)));
+    }
+    let Some(name) = raw["object"].as_str() else {
+        return Ok(None);
+    };
+    // The name must keep the object under its task's directory
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(ApiError::internal(format!(
+            "Raw result names the object {:?} outside its task",
+            name
+        )));
+    }
+    Ok(Some(Contents::Object(name.to_string())))
+}
+
+/// Drop the chunks or object a task's result refers to
+pub async fn remove(
+    conn: &mut redis::aio::Connection,
+    store: &AttachmentStore,
+    task_id: &str,
+) -> Result<(), ApiError> {
+    let result: Option<String> = conn.get(format!("result:{}", task_id)).await?;
+    let Some(result) = result else {
+        return Ok(());
+    };
+    match contents(&envelope::decode(&result)?) {
+        Ok(Some(Contents::Chunks(chunks))) => {
+            let keys: Vec<String> = (0..chunks).map(|i| chunk_key(task_id, i)).collect();
+            if !keys.is_empty() {
+                conn.del::<_, ()>(keys).await?;
+            }
+        }
+        Ok(Some(Contents::Object(name))) => store.remove_result(task_id, &name).await?,
+        Ok(None) | Err(_) => {}
+    }
+    Ok(())
+}
+
+/// Stream `chunks` Redis chunks of a result, failing the body if one is gone
+fn stream_chunks(conn: redis::aio::Connection, task_id: String, chunks: u64) -> Body {
+    Body::from_stream(stream::try_unfold((conn, 0), move |(mut conn, index)| {
+        let key = chunk_key(&task_id, index);
+        async move {
+            if index == chunks {
+                return Ok(None);
+            }
+            let chunk: Option<Vec<u8>> = conn.get(&key).await?;
+            let chunk = chunk.ok_or_else(|| anyhow::anyhow!("{} is missing", key))?;
+            anyhow::Ok(Some((Bytes::from(chunk), (conn, index + 1))))
+        }
+    }))
+}
+
+// Download a task's result as bytes
+pub async fn get_raw_result(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let not_found = || ApiError::not_found(format!("No result for task {}", task_id));
+
+    let mut conn = redis_connection(&state).await?;
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    annotations::check_reader(&mut conn, principal, &task_id).await?;
+
+    let result: Option<String> = conn.get(format!("result:{}", task_id)).await?;
+    let result = envelope::decode(&result.ok_or_else(not_found)?)?;
+
+    let mut headers = HeaderMap::new();
+    let (body, content_type, name) = match contents(&result)? {
+        None => match result {
+            Value::String(text) => (
+                Body::from(text),
+                "text/plain; charset=utf-8",
+                format!("{}.txt", task_id),
+            ),
+            value => (
+                Body::from(serde_json::to_vec(&value)?),
+                "application/json",
+                format!("{}.json", task_id),
+            ),
+        },
+        Some(contents) => {
+            let body = match contents {
+                Contents::Chunks(chunks) => {
+                    let keys: Vec<String> = (0..chunks).map(|i| chunk_key(&task_id, i)).collect();
+                    let present: u64 = if keys.is_empty() {
+                        0
+                    } else {
+                        conn.exists(keys).await?
+                    };
+                    if present < chunks {
+                        return Err(ApiError::new(
+                            StatusCode::GONE,
+                            "result_incomplete",
+                            format!("Result of task {} is missing chunks", task_id),
+                        ));
+                    }
+                    stream_chunks(conn, task_id.clone(), chunks)
+                }
+                Contents::Object(object) => state
+                    .attachments
+                    .open_result(&task_id, &object)
+                    .await?
+                    .ok_or_else(not_found)?,
+            };
+            if let Some(size) = result["raw"]["size"].as_u64() {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+            }
+            let content_type = result["raw"]["content_type"]
+                .as_str()
+                .unwrap_or("application/octet-stream");
+            let name = result["raw"]["name"].as_str().map(str::to_string);
+            (body, content_type, name.unwrap_or_else(|| task_id.clone()))
+        }
+    };
+
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type)
+            .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+    );
+    if let Some(disposition) = attachments::disposition(&name) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok((headers, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_references() {
+        let chunked = json!({"raw": {"chunks": 3, "content_type": "image/png"}});
+        assert!(matches!(contents(&chunked), Ok(Some(Contents::Chunks(3)))));
+        let object = json!({"raw": {"object": "report.pdf"}});
+        assert!(matches!(
+            contents(&object),
+            Ok(Some(Contents::Object(name))) if name == "report.pdf"
+        ));
+    }
+
+    #[test]
+    fn plain_results_are_no_references() {
+        for result in [
+            json!("some text"),
+            json!({"answer": 42}),
+            json!({"raw": "model output"}),
+            json!({"raw": {"chunks": "3"}}),
+        ] {
+            assert!(matches!(contents(&result), Ok(None)), "{}", result);
+        }
+    }
+
+    #[test]
+    fn objects_stay_under_their_task() {
+        for name in ["", ".", "..", "../other/result/x", "a/b"] {
+            let result = json!({"raw": {"object": name}});
+            assert!(contents(&result).is_err(), "{}", name);
+        }
+    }
+}
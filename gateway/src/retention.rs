@@ -0,0 +1,361 @@
+//! Task deletion and purging.
+//!
+//! `DELETE /task/:id` soft-deletes a task: it leaves its queue and the
+//! watchdog, its result or error report, raw result contents, attachments,
+//! execution log and label entries are dropped, and the record stays behind
+//! with status `deleted`, `deleted_at` and `deleted_by` as the audit trail.
+//! Submitters may delete their own tasks, operators and admins any task.
+//!
+//! `POST /admin/purge?before=<timestamp>` reclaims Redis memory by removing
+//! the keys of every settled task, deleted ones included, that settled before
+//! the timestamp (RFC 3339 or unix seconds). It runs in the background,
+//! walking `task:*` with SCAN `tasks.purge_batch_size` keys at a time and
+//! sleeping `tasks.purge_pause_ms` between batches so Redis keeps serving
+//! traffic. One purge runs at a time, and `GET /admin/purge` reports the
+//! progress of the latest in `purge:status`. Tasks whose final state has yet
+//! to reach the history table are left for the next purge.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::federation::FORWARDED_KEY;
+use crate::{
+    annotations, events, failures, feedback, history, labels, queue_for, redis_connection,
+    schema,
+};
+use crate::{rerun, results, task_logs, task_state, watchdog};
+use crate::{AgentResponse, AppState};
+
+/// Redis key holding the progress of the latest purge
+const PURGE_STATUS_KEY: &str = "purge:status";
+
+/// Lock held while a purge runs, renewed after every batch
+const PURGE_LOCK_KEY: &str = "purge:lock";
+
+/// Seconds the purge lock outlives a gateway that stopped renewing it
+const PURGE_LOCK_TTL_SECS: u64 = 300;
+
+/// Keys of a task besides its attachments
+fn task_keys(task_id: &str) -> [String; 4] {
+    [
+        format!("task:{}", task_id),
+        format!("result:{}", task_id),
+        failures::error_key(task_id),
+        format!("cache:pending:{}", task_id),
+    ]
+}
+
+// Soft-delete a task, keeping its record as the audit trail
+pub async fn delete_task(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+) -> Result<Json<AgentResponse>, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+
+    let mut conn = redis_connection(&state).await?;
+    let [task_key, result_key, error_key, pending_key] = task_keys(&task_id);
+    let task: Option<String> = conn.get(&task_key).await?;
+    let Some(task) = task else {
+        return Err(ApiError::not_found(format!("Task {} not found", task_id)));
+    };
+    let task = schema::decode_task(&task)?;
+
+    let owner = task["submitted_by"].as_str() == Some(principal.key_id.as_str());
+    if !owner && !principal.role.is_privileged() {
+        return Err(ApiError::forbidden(
+            "Only the submitter, operators and admins may delete a task",
+        ));
+    }
+
+    // Deleting first stops an agent still at work from settling the task
+    let deleted = task_state::transition(&mut conn, &task_id, "deleted", |task| {
+        if task["status"] == "deleted" {
+            return false;
+        }
+        task["deleted_from_status"] = task["status"].take();
+        task["deleted_at"] = Utc::now().to_rfc3339().into();
+        task["deleted_by"] = principal.key_id.clone().into();
+        true
+    })
+    .await?;
+    if let Some(task) = deleted {
+        // A task still waiting must not reach an agent
+        let priority = task["priority"].as_bool().unwrap_or(false);
+        let queue = queue_for(task["capability"].as_str(), priority);
+        state
+            .task_queue
+            .withdraw(&mut conn, &queue, &task_id)
+            .await?;
+        watchdog::untrack(&mut conn, &task_id).await?;
+        conn.hdel::<_, _, ()>(FORWARDED_KEY, &task_id).await?;
+        labels::unindex(&mut conn, &task_id, &task).await?;
+        state.attachments.remove(&mut conn, &task_id).await?;
+        results::remove(&mut conn, &state.attachments, &task_id).await?;
+        let logs_key = task_logs::logs_key(&task_id);
+        conn.del::<_, ()>(&[result_key, error_key, pending_key, logs_key])
+            .await?;
+        history::track(&mut conn, &task_id).await?;
+        events::track(&mut conn, &task_id).await?;
+        info!("Task {} deleted by {}", task_id, principal.key_id);
+    }
+
+    Ok(Json(AgentResponse {
+        task_id,
+        status: "deleted".to_string(),
+        result: None,
+        error: None,
+        signature: None,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+    /// Purge tasks settled before this time
+    before: String,
+    /// Count what would be purged without removing anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Parse RFC 3339 or unix seconds
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    match value.parse::<i64>() {
+        Ok(secs) => DateTime::from_timestamp(secs, 0),
+        Err(_) => DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|t| t.with_timezone(&Utc)),
+    }
+}
+
+/// When a settled task reached its final state
+fn settled_at(task: &Value) -> Option<DateTime<Utc>> {
+    [
+        "deleted_at",
+        "completed_at",
+        "timed_out_at",
+        "orphaned_at",
+        "created_at",
+    ]
+    .iter()
+    .find_map(|field| task[*field].as_str())
+    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+    .map(|t| t.with_timezone(&Utc))
+}
+
+// Start purging tasks settled before a timestamp (admin only)
+pub async fn start_purge(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<PurgeQuery>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let before = parse_timestamp(&query.before).ok_or_else(|| {
+        ApiError::bad_request(
+            "invalid_timestamp",
+            "before must be an RFC 3339 timestamp or unix seconds",
+        )
+    })?;
+    if before > Utc::now() {
+        return Err(ApiError::bad_request(
+            "invalid_timestamp",
+            "before must not be in the future",
+        ));
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    let locked: Option<String> = redis::cmd("SET")
+        .arg(PURGE_LOCK_KEY)
+        .arg(&principal.key_id)
+        .arg("NX")
+        .arg("EX")
+        .arg(PURGE_LOCK_TTL_SECS)
+        .query_async(&mut conn)
+        .await?;
+    if locked.is_none() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "purge_running",
+            "A purge is already running",
+        ));
+    }
+
+    let progress = json!({
+        "state": "running",
+        "before": before.to_rfc3339(),
+        "dry_run": query.dry_run,
+        "started_by": principal.key_id,
+        "started_at": Utc::now().to_rfc3339(),
+        "scanned": 0,
+        "purged": 0,
+    });
+    conn.set::<_, _, ()>(PURGE_STATUS_KEY, progress.to_string())
+        .await?;
+    info!(
+        "Purge of tasks settled before {} started by {}",
+        before, principal.key_id
+    );
+
+    let purge = Purge {
+        state,
+        before,
+        dry_run: query.dry_run,
+    };
+    let started = progress.clone();
+    tokio::spawn(async move { purge.run(started).await });
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+// Progress of the latest purge (admin only)
+pub async fn purge_status(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let progress: Option<String> = conn.get(PURGE_STATUS_KEY).await?;
+    let progress = match progress {
+        Some(p) => serde_json::from_str(&p)?,
+        None => json!({ "state": "idle" }),
+    };
+    Ok(Json(progress))
+}
+
+struct Purge {
+    state: AppState,
+    before: DateTime<Utc>,
+    dry_run: bool,
+}
+
+impl Purge {
+    /// Walk every task, recording progress after each batch
+    async fn run(self, mut progress: Value) {
+        let outcome = self.sweep(&mut progress).await;
+        progress["finished_at"] = Utc::now().to_rfc3339().into();
+        match outcome {
+            Ok(()) => {
+                progress["state"] = "finished".into();
+                info!(
+                    "Purge finished: {} of {} tasks purged",
+                    progress["purged"], progress["scanned"]
+                );
+            }
+            Err(e) => {
+                error!("Purge failed: {}", e);
+                progress["state"] = "failed".into();
+                progress["error"] = e.to_string().into();
+            }
+        }
+        if let Err(e) = self.finish(&progress).await {
+            error!("Failed to record purge outcome: {}", e);
+        }
+    }
+
+    async fn sweep(&self, progress: &mut Value) -> anyhow::Result<()> {
+        let mut conn = self.state.redis_client.get_async_connection().await?;
+        let (mut scanned, mut purged) = (0u64, 0u64);
+        let mut cursor = 0u64;
+        loop {
+            let config = self.state.config.current();
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg("task:*")
+                .arg("COUNT")
+                .arg(config.tasks.purge_batch_size)
+                .query_async(&mut conn)
+                .await?;
+            let task_ids = keys
+                .iter()
+                .filter_map(|k| k.strip_prefix("task:"))
+                .filter(|id| !id.contains(':'));
+            for task_id in task_ids {
+                scanned += 1;
+                if self.purge(&mut conn, task_id).await? {
+                    purged += 1;
+                }
+            }
+
+            progress["scanned"] = scanned.into();
+            progress["purged"] = purged.into();
+            conn.set::<_, _, ()>(PURGE_STATUS_KEY, progress.to_string())
+                .await?;
+            conn.expire::<_, ()>(PURGE_LOCK_KEY, PURGE_LOCK_TTL_SECS as i64)
+                .await?;
+
+            cursor = next;
+            if cursor == 0 {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(config.tasks.purge_pause_ms)).await;
+        }
+    }
+
+    /// Remove `task_id` if it settled before the cutoff; whether it was (or,
+    /// in a dry run, would be)
+    async fn purge(
+        &self,
+        conn: &mut redis::aio::Connection,
+        task_id: &str,
+    ) -> anyhow::Result<bool> {
+        let [task_key, result_key, error_key, pending_key] = task_keys(task_id);
+        let task: Option<String> = conn.get(&task_key).await?;
+        let Some(task) = task.and_then(|t| schema::decode_task(&t).ok()) else {
+            return Ok(false);
+        };
+        let has_result: bool = conn.exists(&result_key).await?;
+        let status = task["status"].as_str().unwrap_or_default();
+        let settled = has_result || history::FINAL_STATUSES.contains(&status);
+        if !settled || settled_at(&task).is_none_or(|at| at >= self.before) {
+            return Ok(false);
+        }
+        if history::is_pending(conn, task_id).await? {
+            return Ok(false);
+        }
+        if self.dry_run {
+            return Ok(true);
+        }
+
+        labels::unindex(conn, task_id, &task).await?;
+        self.state
+            .attachments
+            .remove(conn, task_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        results::remove(conn, &self.state.attachments, task_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        watchdog::untrack(conn, task_id).await?;
+        conn.hdel::<_, _, ()>(FORWARDED_KEY, task_id).await?;
+        let annotations_key = annotations::annotations_key(task_id);
+        conn.del::<_, ()>(&[
+            task_key,
+            result_key,
+            error_key,
+            pending_key,
+            annotations_key,
+            feedback::feedback_key(task_id),
+            task_logs::logs_key(task_id),
+            rerun::reruns_key(task_id),
+        ])
+        .await?;
+        Ok(true)
+    }
+
+    async fn finish(&self, progress: &Value) -> anyhow::Result<()> {
+        let mut conn = self.state.redis_client.get_async_connection().await?;
+        conn.set::<_, _, ()>(PURGE_STATUS_KEY, progress.to_string())
+            .await?;
+        conn.del::<_, ()>(PURGE_LOCK_KEY).await?;
+        Ok(())
+    }
+}
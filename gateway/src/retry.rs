@@ -0,0 +1,354 @@
+//! Retry and backoff policies shared across subsystems.
+//!
+//! Each subsystem gets a named [`RetryPolicy`] built once at startup, so Redis
+//! access, outbound HTTP delivery and Telegram sends all back off the same way
+//! and can be tuned without touching code.
+
+use rand::Rng;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::{BreakerSettings, RetryOverrides, RetrySettings};
+
+/// How a failed attempt should be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Give up immediately
+    Stop,
+    /// Retry after the policy's computed backoff
+    Backoff,
+    /// Retry after a server-provided delay (e.g. `retry_after`)
+    After(Duration),
+}
+
+/// Exponential backoff with jitter
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Fraction of each delay randomised, 0.0 (none) to 1.0 (full jitter)
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Build a subsystem policy, falling back to the global retry settings
+    /// for anything the subsystem does not override
+    pub fn from_config(global: &RetrySettings, overrides: &RetryOverrides) -> Self {
+        Self {
+            max_attempts: overrides
+                .max_attempts
+                .unwrap_or(global.max_attempts)
+                .max(1),
+            base_delay: Duration::from_millis(
+                overrides.base_delay_ms.unwrap_or(global.base_delay_ms),
+            ),
+            max_delay: Duration::from_millis(overrides.max_delay_ms.unwrap_or(global.max_delay_ms)),
+            multiplier: overrides.multiplier.unwrap_or(global.multiplier),
+            jitter: overrides
+                .jitter
+                .unwrap_or(global.jitter)
+                .clamp(0.0, 1.0),
+        }
+    }
+
+    /// Delay to wait after the given (1-based) failed attempt; jitter never
+    /// takes it past `max_delay`
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let max = self.max_delay.as_secs_f64();
+        // Capped before jitter too, so huge exponents do not overflow
+        let base = (self.base_delay.as_secs_f64() * exp).min(max);
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64((base * (1.0 + jitter)).clamp(0.0, max))
+    }
+
+    /// Run `op` until it succeeds or the attempt budget is exhausted
+    pub async fn run<T, E, F, Fut>(&self, what: &str, op: F) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.run_if(what, op, |_| true).await
+    }
+
+    /// Like [`RetryPolicy::run`], but only retries errors accepted by `retryable`
+    pub async fn run_if<T, E, F, Fut, P>(&self, what: &str, op: F, retryable: P) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        P: Fn(&E) -> bool,
+    {
+        self.run_classified(what, op, |e| {
+            if retryable(e) {
+                RetryDecision::Backoff
+            } else {
+                RetryDecision::Stop
+            }
+        })
+        .await
+    }
+
+    /// Run `op`, letting `classify` decide per error whether and when to retry
+    pub async fn run_classified<T, E, F, Fut, C>(
+        &self,
+        what: &str,
+        mut op: F,
+        classify: C,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        C: Fn(&E) -> RetryDecision,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_attempts => {
+                    let delay = match classify(&e) {
+                        RetryDecision::Stop => return Err(e),
+                        RetryDecision::Backoff => self.delay_for(attempt),
+                        RetryDecision::After(delay) => delay,
+                    };
+                    warn!(
+                        "{} failed (attempt {}/{}): {}; retrying in {:?}",
+                        what, attempt, self.max_attempts, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Circuit breaker state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Consecutive-failure circuit breaker.
+///
+/// After `threshold` consecutive failures the circuit opens for `cooldown`;
+/// the first call after the cooldown is let through as a half-open probe whose
+/// outcome closes or re-opens the circuit.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(60))
+    }
+}
+
+impl CircuitBreaker {
+    pub fn from_config(settings: &BreakerSettings) -> Self {
+        Self::new(settings.threshold, Duration::from_secs(settings.cooldown_secs))
+    }
+
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Mutex::new(BreakerInner {
+                failures: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    /// Current state of the circuit
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(at) if at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Time left until an open circuit allows a probe
+    pub fn remaining_cooldown(&self) -> Duration {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .opened_at
+            .map(|at| self.cooldown.saturating_sub(at.elapsed()))
+            .unwrap_or_default()
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.failures = 0;
+        inner.opened_at = None;
+    }
+
+    /// Record a failure, returning true if this failure opened the circuit
+    pub fn record_failure(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.failures += 1;
+        let was_closed = inner.opened_at.is_none();
+        if inner.failures >= self.threshold {
+            inner.opened_at = Some(Instant::now());
+        }
+        was_closed && inner.opened_at.is_some()
+    }
+
+    /// Consecutive failures seen so far
+    pub fn failures(&self) -> u32 {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .failures
+    }
+}
+
+/// Named policies for each subsystem, loaded once at startup
+#[derive(Debug, Clone)]
+pub struct RetryPolicies {
+    pub redis: RetryPolicy,
+    /// Outbound HTTP deliveries (webhooks, federation peers)
+    pub delivery: RetryPolicy,
+    pub telegram: RetryPolicy,
+    /// Requeues of overdue tasks
+    pub task: RetryPolicy,
+    /// Restarts of crashed channel adaptors
+    pub adaptor: RetryPolicy,
+}
+
+impl RetryPolicies {
+    pub fn from_config(settings: &RetrySettings) -> Self {
+        Self {
+            redis: RetryPolicy::from_config(settings, &settings.redis),
+            delivery: RetryPolicy::from_config(settings, &settings.delivery),
+            telegram: RetryPolicy::from_config(settings, &settings.telegram),
+            task: RetryPolicy::from_config(settings, &settings.task),
+            adaptor: RetryPolicy::from_config(settings, &settings.adaptor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delay_grows_exponentially() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay_for(1), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(2), Duration::from_millis(200));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay_for(5), Duration::from_secs(1));
+        assert_eq!(policy.delay_for(50), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = policy(0.5);
+        for _ in 0..200 {
+            let delay = policy.delay_for(2).as_secs_f64();
+            assert!((0.1..=0.3).contains(&delay), "{} outside jitter range", delay);
+            let capped = policy.delay_for(10).as_secs_f64();
+            assert!((0.5..=1.0).contains(&capped), "{} outside capped range", capped);
+            assert!(policy.delay_for(50) <= policy.max_delay);
+        }
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.remaining_cooldown() > Duration::ZERO);
+        // Further failures do not report opening again
+        assert!(!breaker.record_failure());
+    }
+
+    #[test]
+    fn breaker_half_opens_after_cooldown_and_closes_on_success() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(breaker.remaining_cooldown(), Duration::ZERO);
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.failures(), 0);
+    }
+
+    #[test]
+    fn failed_probe_reopens_the_circuit() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
@@ -0,0 +1,118 @@
+//! Hot-reloadable runtime configuration.
+//!
+//! The configuration loaded at startup is the base; the JSON object stored at
+//! `config:runtime` is layered on top of it. Writers publish on the
+//! `config:runtime` channel after changing the key and the watcher re-reads,
+//! validates and swaps in the merged configuration, so request handlers and
+//! the Telegram loop see new values on their next read without a restart.
+//! Only [`RELOADABLE_KEYS`](crate::config::RELOADABLE_KEYS) may be overridden;
+//! an invalid update is logged and the previous configuration stays in force.
+
+use futures_util::StreamExt;
+use redis::{AsyncCommands, Client};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::metrics::{Counter, Gauge};
+
+/// Redis key holding runtime overrides, and the channel announcing changes
+pub const RUNTIME_CONFIG_KEY: &str = "config:runtime";
+
+/// Delay before resubscribing after the pub/sub connection drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// The startup configuration plus the currently applied runtime overrides
+#[derive(Debug)]
+pub struct RuntimeConfig {
+    base: Arc<Config>,
+    current: RwLock<Arc<Config>>,
+    pub reloads: Counter,
+    pub failures: Counter,
+    /// Unix time of the last successful reload, 0 if none yet
+    pub last_reload_at: Gauge,
+}
+
+impl RuntimeConfig {
+    pub fn new(base: Arc<Config>) -> Self {
+        Self {
+            current: RwLock::new(base.clone()),
+            base,
+            reloads: Counter::default(),
+            failures: Counter::default(),
+            last_reload_at: Gauge::default(),
+        }
+    }
+
+    /// The configuration in force right now
+    pub fn current(&self) -> Arc<Config> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Apply the raw `config:runtime` value; a missing key restores the base
+    fn apply(&self, raw: Option<&str>) -> anyhow::Result<()> {
+        let config = match raw {
+            Some(raw) => {
+                let overrides: serde_json::Value = serde_json::from_str(raw).map_err(|e| {
+                    anyhow::anyhow!("{} is not valid JSON: {}", RUNTIME_CONFIG_KEY, e)
+                })?;
+                Arc::new(self.base.with_overrides(&overrides)?)
+            }
+            None => self.base.clone(),
+        };
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = config;
+        Ok(())
+    }
+
+    /// Re-read `config:runtime` and apply it, keeping the old config on error
+    async fn reload(&self, redis_client: &Client) {
+        let result = async {
+            let mut conn = redis_client.get_async_connection().await?;
+            let raw: Option<String> = conn.get(RUNTIME_CONFIG_KEY).await?;
+            self.apply(raw.as_deref())
+        };
+        match result.await {
+            Ok(()) => {
+                self.reloads.inc();
+                self.last_reload_at.set(chrono::Utc::now().timestamp());
+                info!("Runtime configuration reloaded");
+            }
+            Err(e) => {
+                self.failures.inc();
+                warn!("Keeping previous runtime configuration: {}", e);
+            }
+        }
+    }
+
+    /// Subscribe to change notifications, reloading on each one
+    async fn watch(&self, redis_client: &Client) -> anyhow::Result<()> {
+        let mut pubsub = redis_client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(RUNTIME_CONFIG_KEY).await?;
+
+        // Pick up anything changed while we were not subscribed
+        self.reload(redis_client).await;
+
+        let mut messages = pubsub.on_message();
+        while messages.next().await.is_some() {
+            self.reload(redis_client).await;
+        }
+        Ok(())
+    }
+}
+
+/// Start the runtime configuration watcher in a background task
+pub fn start_runtime_watcher(redis_client: Arc<Client>, runtime: Arc<RuntimeConfig>) {
+    tokio::spawn(async move {
+        loop {
+            match runtime.watch(&redis_client).await {
+                Ok(()) => warn!("Runtime configuration subscription closed"),
+                Err(e) => warn!("Runtime configuration watcher error: {}", e),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    });
+}
@@ -0,0 +1,301 @@
+//! HTTP listener setup.
+//!
+//! The gateway binds to `server.bind_addr` (default `0.0.0.0`) on
+//! `server.port` (default 8080); the bind address may also carry its own
+//! port. Setting `tls_cert_path` and `tls_key_path` switches the listener to
+//! native TLS. Both PEM files are polled every `tls_reload_interval_secs`
+//! (default 60) and reloaded when they change, so renewed certificates take
+//! effect without a restart.
+//!
+//! Connections speak HTTP/1.1 or, unless `server.http2` is off, HTTP/2. The
+//! remaining `server` settings tune them for many long-lived clients: HTTP/2
+//! stream limits and keep-alive pings, how long an HTTP/1.1 connection may
+//! idle between requests, and `max_connections`, past which new connections
+//! are closed as soon as they are accepted.
+
+use axum::Router;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::RustlsConfig;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, warn};
+
+use crate::config::ServerSettings;
+
+/// Certificate and key locations for TLS termination
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub reload_interval: Duration,
+}
+
+/// Protocol and connection tuning
+#[derive(Debug, Clone)]
+pub struct HttpTuning {
+    pub http2: bool,
+    pub http2_max_concurrent_streams: u32,
+    pub http2_keep_alive_interval: Option<Duration>,
+    pub http2_keep_alive_timeout: Duration,
+    pub keep_alive: bool,
+    pub keep_alive_timeout: Duration,
+    pub max_connections: Option<usize>,
+}
+
+impl HttpTuning {
+    fn from_config(settings: &ServerSettings) -> Self {
+        Self {
+            http2: settings.http2,
+            http2_max_concurrent_streams: settings.http2_max_concurrent_streams,
+            http2_keep_alive_interval: (settings.http2_keep_alive_interval_secs > 0)
+                .then(|| Duration::from_secs(settings.http2_keep_alive_interval_secs)),
+            http2_keep_alive_timeout: Duration::from_secs(settings.http2_keep_alive_timeout_secs),
+            keep_alive: settings.keep_alive,
+            keep_alive_timeout: Duration::from_secs(settings.keep_alive_timeout_secs),
+            max_connections: settings.max_connections,
+        }
+    }
+
+    /// Apply the tuning to a connection builder
+    fn configure(&self, builder: &mut Builder<TokioExecutor>) {
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive)
+            .header_read_timeout(self.keep_alive_timeout);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.http2_max_concurrent_streams)
+            .keep_alive_interval(self.http2_keep_alive_interval)
+            .keep_alive_timeout(self.http2_keep_alive_timeout);
+    }
+}
+
+/// Listener configuration
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsPaths>,
+    pub http: HttpTuning,
+}
+
+impl ServerConfig {
+    pub fn from_config(settings: &ServerSettings) -> anyhow::Result<Self> {
+        let addr = settings
+            .socket_addr()
+            .map_err(|e| anyhow::anyhow!("server.bind_addr {}", e))?;
+
+        let tls = match (&settings.tls_cert_path, &settings.tls_key_path) {
+            (Some(cert), Some(key)) => Some(TlsPaths {
+                cert: cert.clone(),
+                key: key.clone(),
+                reload_interval: Duration::from_secs(settings.tls_reload_interval_secs),
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("server.tls_cert_path and server.tls_key_path must be set together"),
+        };
+
+        Ok(Self {
+            addr,
+            tls,
+            http: HttpTuning::from_config(settings),
+        })
+    }
+}
+
+/// Acceptor closing connections beyond the configured limit and, on
+/// cleartext listeners with HTTP/2 off, connections opening with the HTTP/2
+/// preface. Hyper serves whichever protocol a connection starts with, so
+/// this is where HTTP/2 is turned away.
+#[derive(Clone)]
+struct ConnectionGate {
+    limit: Option<Arc<Semaphore>>,
+    refuse_h2c: bool,
+    /// How long to wait for a connection's first bytes when checking them
+    first_bytes_wait: Duration,
+}
+
+impl ConnectionGate {
+    fn new(http: &HttpTuning, tls: bool) -> Self {
+        Self {
+            limit: http.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            refuse_h2c: !http.http2 && !tls,
+            first_bytes_wait: http.keep_alive_timeout,
+        }
+    }
+}
+
+/// Whether the client opened with the HTTP/2 connection preface
+async fn opens_with_h2_preface(stream: &TcpStream, wait: Duration) -> bool {
+    let mut start = [0u8; 14];
+    match tokio::time::timeout(wait, stream.peek(&mut start)).await {
+        Ok(Ok(n)) => n > 0 && b"PRI * HTTP/2.0".starts_with(&start[..n]),
+        _ => false,
+    }
+}
+
+impl<S: Send + 'static> Accept<TcpStream, S> for ConnectionGate {
+    type Stream = LimitedStream;
+    type Service = S;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(LimitedStream, S)>> + Send>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let gate = self.clone();
+        Box::pin(async move {
+            let permit = match gate.limit {
+                None => None,
+                Some(limit) => match limit.try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        warn!("Refused connection: max_connections reached");
+                        return Err(io::ErrorKind::ConnectionRefused.into());
+                    }
+                },
+            };
+            if gate.refuse_h2c && opens_with_h2_preface(&stream, gate.first_bytes_wait).await {
+                debug!("Refused HTTP/2 connection: server.http2 is off");
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            Ok((
+                LimitedStream {
+                    stream,
+                    _permit: permit,
+                },
+                service,
+            ))
+        })
+    }
+}
+
+/// A connection holding its slot under the connection limit until dropped
+struct LimitedStream {
+    stream: TcpStream,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+/// Serve `app` until the listener fails
+pub async fn serve(app: Router, config: ServerConfig) -> anyhow::Result<()> {
+    let Some(tls) = config.tls else {
+        let gate = ConnectionGate::new(&config.http, false);
+        let mut server = axum_server::bind(config.addr).acceptor(gate);
+        config.http.configure(server.http_builder());
+        info!("Secure Gateway listening on http://{}", config.addr);
+        server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+        return Ok(());
+    };
+
+    let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load TLS certificate: {}", e))?;
+    let http2 = config.http.http2;
+    if !http2 {
+        http1_alpn(&rustls);
+    }
+    tokio::spawn(watch_certificates(rustls.clone(), tls, http2));
+
+    let gate = ConnectionGate::new(&config.http, true);
+    let mut server = axum_server::bind_rustls(config.addr, rustls).map(|tls| tls.acceptor(gate));
+    config.http.configure(server.http_builder());
+    info!("Secure Gateway listening on https://{}", config.addr);
+    server
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/// Offer only HTTP/1.1 in ALPN, so clients do not negotiate HTTP/2 with a
+/// listener that will not speak it
+fn http1_alpn(rustls: &RustlsConfig) {
+    let mut inner = (*rustls.get_inner()).clone();
+    inner.alpn_protocols = vec![b"http/1.1".to_vec()];
+    rustls.reload_from_config(Arc::new(inner));
+}
+
+/// Reload the certificate whenever either PEM file's modification time changes
+async fn watch_certificates(rustls: RustlsConfig, tls: TlsPaths, http2: bool) {
+    let modified = |tls: &TlsPaths| -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&tls.cert)
+            .and_then(|m| m.modified())
+            .ok()?;
+        let key = std::fs::metadata(&tls.key)
+            .and_then(|m| m.modified())
+            .ok()?;
+        Some((cert, key))
+    };
+
+    let mut last = modified(&tls);
+    loop {
+        tokio::time::sleep(tls.reload_interval).await;
+
+        let current = modified(&tls);
+        if current.is_none() || current == last {
+            continue;
+        }
+        match rustls.reload_from_pem_file(&tls.cert, &tls.key).await {
+            Ok(()) => {
+                if !http2 {
+                    http1_alpn(&rustls);
+                }
+                info!("Reloaded TLS certificate from {}", tls.cert.display());
+                last = current;
+            }
+            // Keep serving the previous certificate; a half-written renewal
+            // will be picked up on the next poll
+            Err(e) => warn!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! HMAC-signed requests for machine-to-machine callers.
+//!
+//! Instead of a bearer token, a caller may name its signing key in
+//! `X-Claw-Key-Id` and send `X-Claw-Timestamp` (unix seconds) and
+//! `X-Claw-Nonce` with `X-Claw-Signature`, the hex HMAC-SHA256 under the
+//! key's secret of `{timestamp}.{nonce}.{METHOD}.{path and query}.{body}`.
+//! The nonce is any string of up to 128 visible ASCII characters the caller
+//! never uses twice within the window, such as random hex; it lets identical
+//! requests sent within the same second through. Keys live in Redis under
+//! `signkey:{key_id}` as `{"secret": "...", "role": "..."}`.
+//!
+//! A request is accepted while its timestamp is within
+//! `auth.signature_window_secs` of the gateway clock, and only once: each
+//! nonce is remembered in `signature:seen:{key_id}:{nonce}` until its
+//! timestamp leaves the window, so a captured request cannot be replayed.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use redis::aio::ConnectionLike;
+use redis::AsyncCommands;
+use ring::hmac;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::auth::{self, Principal, Role};
+use crate::error::ApiError;
+use crate::federation::{NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::AppState;
+
+/// Header naming the signing key of an HMAC-signed request
+pub const KEY_ID_HEADER: &str = "x-claw-key-id";
+
+/// Longest nonce accepted
+const MAX_NONCE_LEN: usize = 128;
+
+/// Stored signing key record
+#[derive(Debug, Deserialize)]
+struct SigningKeyRecord {
+    secret: String,
+    role: Role,
+}
+
+/// The signature headers of a signed request
+#[derive(Debug)]
+struct SignatureHeaders {
+    timestamp: i64,
+    nonce: String,
+    signature: Vec<u8>,
+}
+
+impl SignatureHeaders {
+    fn parse(headers: &HeaderMap) -> Result<Self, ApiError> {
+        let timestamp = headers
+            .get(TIMESTAMP_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| ApiError::unauthorized("Missing or invalid signature timestamp"))?;
+        let nonce = headers
+            .get(NONCE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| {
+                !v.is_empty()
+                    && v.len() <= MAX_NONCE_LEN
+                    && v.bytes().all(|b| b.is_ascii_graphic())
+            })
+            .ok_or_else(|| ApiError::unauthorized("Missing or invalid signature nonce"))?
+            .to_string();
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| hex::decode(v).ok())
+            .ok_or_else(|| ApiError::unauthorized("Missing or invalid request signature"))?;
+        Ok(Self {
+            timestamp,
+            nonce,
+            signature,
+        })
+    }
+
+    /// Whether the timestamp is within `window` seconds of `now`
+    fn is_fresh(&self, now: i64, window: u64) -> bool {
+        (now - self.timestamp).unsigned_abs() <= window
+    }
+
+    /// Whether the signature is `secret`'s over the request
+    fn is_signed_by(&self, secret: &str, method: &str, path: &str, body: &[u8]) -> bool {
+        let mut msg =
+            format!("{}.{}.{}.{}.", self.timestamp, self.nonce, method, path).into_bytes();
+        msg.extend_from_slice(body);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        hmac::verify(&key, &msg, &self.signature).is_ok()
+    }
+}
+
+/// The record of signing key `key_id`, if there is a readable one
+async fn signing_key<C: ConnectionLike + Send>(
+    conn: &mut C,
+    key_id: &str,
+) -> redis::RedisResult<Option<SigningKeyRecord>> {
+    let record: Option<String> = conn.get(format!("signkey:{}", key_id)).await?;
+    Ok(record.and_then(|r| serde_json::from_str(&r).ok()))
+}
+
+/// Redis key remembering a nonce `key_id` has used
+fn seen_key(key_id: &str, nonce: &str) -> String {
+    format!("signature:seen:{}:{}", key_id, nonce)
+}
+
+/// Remember `key_id`'s `nonce` for `ttl_secs`, returning false if it was
+/// already used
+async fn first_use<C: ConnectionLike>(
+    conn: &mut C,
+    key_id: &str,
+    nonce: &str,
+    ttl_secs: i64,
+) -> redis::RedisResult<bool> {
+    let set: Option<String> = redis::cmd("SET")
+        .arg(seen_key(key_id, nonce))
+        .arg(key_id)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl_secs)
+        .query_async(conn)
+        .await?;
+    Ok(set.is_some())
+}
+
+/// Middleware verifying HMAC-signed requests.
+///
+/// Requests without a key id header pass through untouched; signed requests
+/// are buffered, checked against the key's secret and the replay window, and
+/// attached as the key's [`Principal`].
+pub async fn verify_request_signature(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(key_id) = req.headers().get(KEY_ID_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+    let key_id = key_id
+        .to_str()
+        .map_err(|_| ApiError::bad_request("invalid_key_id", "Key id header is not valid text"))?
+        .to_string();
+
+    let (mut parts, body) = req.into_parts();
+    let max_body_bytes = state.config.current().limits.max_body_bytes;
+    let bytes = axum::body::to_bytes(body, max_body_bytes)
+        .await
+        .map_err(|_| {
+            ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "Signed request body too large",
+            )
+        })?;
+
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let method = parts.method.as_str();
+    let principal = verify_signature(&state, key_id, &parts.headers, method, path, &bytes).await?;
+    auth::authorize(&principal, &parts.method, parts.uri.path())?;
+    parts.extensions.insert(principal);
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+/// Check the signature headers of a request by `key_id` over `method`,
+/// `path` and `body`, returning the key's principal. Shared with the gRPC
+/// API, which signs the encoded request message.
+pub async fn verify_signature(
+    state: &AppState,
+    key_id: String,
+    headers: &HeaderMap,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Principal, ApiError> {
+    let signed = SignatureHeaders::parse(headers)?;
+
+    let window = state.config.current().auth.signature_window_secs;
+    let now = chrono::Utc::now().timestamp();
+    if !signed.is_fresh(now, window) {
+        warn!("Rejected signed request from {}: stale timestamp", key_id);
+        return Err(ApiError::unauthorized(
+            "Signature timestamp outside replay window",
+        ));
+    }
+
+    let mut conn = state.redis_client.get_async_connection().await?;
+    let Some(record) = signing_key(&mut conn, &key_id).await? else {
+        warn!("Rejected signed request: unknown key {}", key_id);
+        return Err(ApiError::unauthorized("Unknown signing key"));
+    };
+
+    if !signed.is_signed_by(&record.secret, method, path, body) {
+        warn!("Rejected signed request from {}: bad signature", key_id);
+        return Err(ApiError::unauthorized("Invalid request signature"));
+    }
+
+    // Remember the nonce until its timestamp leaves the window
+    let remaining = (signed.timestamp + window as i64 - now).max(1);
+    if !first_use(&mut conn, &key_id, &signed.nonce, remaining).await? {
+        warn!("Rejected signed request from {}: replayed nonce", key_id);
+        return Err(ApiError::unauthorized("Request nonce already used"));
+    }
+
+    Ok(Principal {
+        key_id,
+        role: record.role,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+
+    const SECRET: &str = "s3cret";
+    const NOW: i64 = 1_700_000_000;
+
+    fn signed(timestamp: i64, nonce: &str, body: &[u8]) -> SignatureHeaders {
+        let mut msg = format!("{}.{}.POST./task.", timestamp, nonce).into_bytes();
+        msg.extend_from_slice(body);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse().unwrap());
+        headers.insert(NONCE_HEADER, nonce.parse().unwrap());
+        let signature = hex::encode(hmac::sign(&key, &msg).as_ref());
+        headers.insert(SIGNATURE_HEADER, signature.parse().unwrap());
+        SignatureHeaders::parse(&headers).unwrap()
+    }
+
+    #[test]
+    fn good_signatures_verify() {
+        let request = signed(NOW, "n1", b"{}");
+        assert!(request.is_fresh(NOW + 10, 300));
+        assert!(request.is_signed_by(SECRET, "POST", "/task", b"{}"));
+    }
+
+    #[test]
+    fn stale_timestamps_are_refused() {
+        let request = signed(NOW - 301, "n1", b"{}");
+        assert!(!request.is_fresh(NOW, 300));
+        assert!(!signed(NOW + 301, "n1", b"{}").is_fresh(NOW, 300));
+    }
+
+    #[test]
+    fn bad_macs_are_refused() {
+        let request = signed(NOW, "n1", b"{}");
+        assert!(!request.is_signed_by("other", "POST", "/task", b"{}"));
+        assert!(!request.is_signed_by(SECRET, "POST", "/task", b"{\"x\":1}"));
+        assert!(!request.is_signed_by(SECRET, "PUT", "/task", b"{}"));
+        // The nonce is signed too
+        let mut renamed = signed(NOW, "n1", b"{}");
+        renamed.nonce = "n2".to_string();
+        assert!(!renamed.is_signed_by(SECRET, "POST", "/task", b"{}"));
+    }
+
+    #[test]
+    fn nonces_are_required_and_bounded() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, NOW.to_string().parse().unwrap());
+        headers.insert(SIGNATURE_HEADER, "00".parse().unwrap());
+        assert!(SignatureHeaders::parse(&headers).is_err());
+        headers.insert(NONCE_HEADER, "x".repeat(MAX_NONCE_LEN + 1).parse().unwrap());
+        assert!(SignatureHeaders::parse(&headers).is_err());
+        headers.insert(NONCE_HEADER, "a b".parse().unwrap());
+        assert!(SignatureHeaders::parse(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn replayed_nonces_are_refused() {
+        let mut redis = FakeRedis::new();
+        assert!(first_use(&mut redis, "ci", "n1", 300).await.unwrap());
+        assert!(!first_use(&mut redis, "ci", "n1", 300).await.unwrap());
+        // Identical requests in the same second differ by nonce
+        assert!(first_use(&mut redis, "ci", "n2", 300).await.unwrap());
+        // and nonces belong to their key
+        assert!(first_use(&mut redis, "deploy", "n1", 300).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unknown_keys_have_no_record() {
+        let mut redis = FakeRedis::new();
+        redis.set("signkey:ci", r#"{"secret": "s3cret", "role": "submitter"}"#);
+        let record = signing_key(&mut redis, "ci").await.unwrap().unwrap();
+        assert_eq!(record.secret, SECRET);
+        assert!(signing_key(&mut redis, "nobody").await.unwrap().is_none());
+    }
+}
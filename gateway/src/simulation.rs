@@ -0,0 +1,147 @@
+//! Simulation mode.
+//!
+//! With `server.mode = "simulate"` (`GATEWAY_MODE=simulate`) the gateway
+//! answers the tasks it queues itself, so frontends and adaptors can be
+//! worked on without the agent stack. A loop takes tasks off every agent
+//! queue, priority lanes first, marks them `processing`, and after
+//! `simulation.delay_ms` completes them as an agent would, with `{"result":
+//! <reply>, "simulated": true}`: the task's text echoed back, or
+//! `simulation.reply` when set. Everything else, from the watchdog to the
+//! chat adaptors, works as it does with agents. A task Redis fails to mark
+//! `processing` goes back at the head of its queue. Agents still running
+//! take their share of the tasks.
+
+use redis::Client;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::agent_api;
+use crate::agent_registry::{self, DIRECT_QUEUE_PREFIX};
+use crate::config::SimulationSettings;
+use crate::AGENT_QUEUE;
+
+/// Pause between looks at the queues when they are empty
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The answer to a task with `input`
+fn reply(input: &Value, settings: &SimulationSettings) -> Value {
+    let text = match (&settings.reply, input) {
+        (Some(reply), _) => reply.clone(),
+        (None, Value::String(text)) => format!("Echo: {}", text),
+        (None, Value::Object(fields)) if fields["text"].is_string() => {
+            format!("Echo: {}", fields["text"].as_str().unwrap_or_default())
+        }
+        (None, other) => format!("Echo: {}", other),
+    };
+    json!({ "result": text, "error": null, "simulated": true })
+}
+
+/// Every agent queue, priority lanes first
+async fn queues(conn: &mut redis::aio::Connection) -> redis::RedisResult<Vec<String>> {
+    let mut queues = agent_registry::scan_keys(conn, AGENT_QUEUE).await?;
+    queues.extend(agent_registry::scan_keys(conn, DIRECT_QUEUE_PREFIX).await?);
+    queues.sort_by_key(|queue| !queue.ends_with(":priority"));
+    Ok(queues)
+}
+
+/// Take the next queued task, if any, and answer it after the delay
+async fn take_one(redis_client: &Client, settings: &SimulationSettings) -> anyhow::Result<bool> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let mut taken = None;
+    for queue in queues(&mut conn).await? {
+        let task_id: Option<String> = redis::cmd("RPOP")
+            .arg(&queue)
+            .query_async(&mut conn)
+            .await?;
+        if let Some(task_id) = task_id {
+            taken = Some((queue, task_id));
+            break;
+        }
+    }
+    let Some((queue, task_id)) = taken else {
+        return Ok(false);
+    };
+
+    let task = match agent_api::start(&mut conn, &task_id, None).await {
+        Ok(task) => task,
+        // Put back at the head of its queue, for the next look to retry
+        Err(e) if matches!(e.code, "storage_error" | "storage_refused") => {
+            let requeued: redis::RedisResult<()> = redis::cmd("RPUSH")
+                .arg(&queue)
+                .arg(&task_id)
+                .query_async(&mut conn)
+                .await;
+            if let Err(requeue) = requeued {
+                error!(
+                    "Simulator could not requeue task {}, leaving it to the watchdog: {}",
+                    task_id, requeue
+                );
+            }
+            anyhow::bail!("task {} not started: {}", task_id, e.message);
+        }
+        // Settled or deleted while it was queued
+        Err(e) => {
+            warn!("Simulator skipped task {}: {}", task_id, e.message);
+            return Ok(true);
+        }
+    };
+    let result = reply(&task["input"], settings);
+    let delay = Duration::from_millis(settings.delay_ms);
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        if let Err(e) = agent_api::complete(&mut conn, &task_id, &result).await {
+            warn!(
+                "Simulator could not complete task {}: {}",
+                task_id, e.message
+            );
+        }
+    });
+    Ok(true)
+}
+
+/// Start answering queued tasks in a background task
+pub fn start_simulator(redis_client: Arc<Client>, settings: &SimulationSettings) {
+    let settings = settings.clone();
+    info!(
+        "Simulation mode: tasks are answered by the gateway after {}ms",
+        settings.delay_ms
+    );
+    tokio::spawn(async move {
+        loop {
+            match take_one(&redis_client, &settings).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => error!("Simulator error: {}", e),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echoes_the_text_of_a_task() {
+        let settings = SimulationSettings::default();
+        assert_eq!(reply(&json!("hi"), &settings)["result"], "Echo: hi");
+        assert_eq!(
+            reply(&json!({"text": "hi", "lang": "en"}), &settings)["result"],
+            "Echo: hi"
+        );
+        assert_eq!(reply(&json!([1, 2]), &settings)["result"], "Echo: [1,2]");
+
+        let fixed = SimulationSettings {
+            reply: Some("Done".to_string()),
+            ..settings
+        };
+        let answer = reply(&json!("hi"), &fixed);
+        assert_eq!(
+            answer,
+            json!({"result": "Done", "error": null, "simulated": true})
+        );
+    }
+}
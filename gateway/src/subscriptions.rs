@@ -0,0 +1,745 @@
+//! Task event subscriptions.
+//!
+//! `POST /subscriptions` registers interest in task lifecycle events (see
+//! [`events`]) matching optional filters, e.g.
+//! `{"url": "https://ops.example.com/hook", "filters": {"status": "failed",
+//! "label": "team:ml"}}`. Each filter takes one value or a list; an event
+//! must match one value of every filter given. Subscriptions are kept in the
+//! Redis hash `subscriptions`. Operators and admins see events for every
+//! task, other keys only for the tasks they submitted.
+//!
+//! With a `url`, each matching event is POSTed there as JSON under the
+//! delivery retry policy, with `x-claw-event` and `x-claw-subscription`
+//! headers and `x-claw-timestamp`/`x-claw-signature` signing `{timestamp}.{body}`
+//! with hex HMAC-SHA256 under the subscription's secret, which is returned
+//! once on creation. Every subscription can also be followed as
+//! server-sent events at `GET /subscriptions/:id/events`; events reach
+//! every gateway through the Redis channel `events:task`.
+//!
+//! Webhook URLs must resolve to public addresses only: loopback, private,
+//! link-local, shared and unspecified addresses are refused on creation and
+//! again before every delivery, and redirects are not followed. Each
+//! delivery connects only to the addresses it checked, so a name repointed
+//! in between cannot reach an internal address.
+//!
+//! Subscriptions created elsewhere are picked up within
+//! [`RELOAD_INTERVAL`]; a webhook still failing after its retries loses the
+//! event.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    response::Json,
+    Extension,
+};
+use chrono::Utc;
+use futures_util::stream::{self, Stream, StreamExt};
+use redis::{AsyncCommands, Client};
+use ring::hmac;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tracing::warn;
+
+use crate::auth::Principal;
+use crate::config::SubscriptionSettings;
+use crate::error::ApiError;
+use crate::federation::{SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::retry::RetryPolicy;
+use crate::{events, labels, redis_connection, task_state, AppState};
+
+/// Redis hash of subscriptions by id
+const SUBSCRIPTIONS_KEY: &str = "subscriptions";
+
+/// Redis channel carrying every event to every gateway
+const EVENTS_CHANNEL: &str = "events:task";
+
+/// Header naming the event of a webhook delivery
+const EVENT_HEADER: &str = "x-claw-event";
+
+/// Header naming the subscription a webhook delivery is for
+const SUBSCRIPTION_HEADER: &str = "x-claw-subscription";
+
+/// How often subscriptions are reloaded from Redis
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Webhook deliveries in flight at once
+const MAX_CONCURRENT_DELIVERIES: usize = 16;
+
+/// Events kept for slow server-sent event consumers
+const STREAM_BUFFER: usize = 1024;
+
+/// Delay before resubscribing after the pub/sub connection drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Accept a single value where a list is expected
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Which events a subscription receives; empty filters match everything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// Task statuses, e.g. `failed`
+    #[serde(deserialize_with = "one_or_many")]
+    status: Vec<String>,
+    /// Event names, e.g. `task.completed`
+    #[serde(deserialize_with = "one_or_many")]
+    event: Vec<String>,
+    /// `key:value` labels
+    #[serde(deserialize_with = "one_or_many")]
+    label: Vec<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    capability: Vec<String>,
+}
+
+impl Filters {
+    /// Reject filters no event could match
+    fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |message: String| Err(ApiError::bad_request("invalid_filter", message));
+        if let Some(status) = self
+            .status
+            .iter()
+            .find(|s| !task_state::TRANSITIONS.iter().any(|(from, _)| from == s))
+        {
+            return invalid(format!("Unknown status filter: {}", status));
+        }
+        if let Some(event) = self
+            .event
+            .iter()
+            .find(|e| !events::EVENTS.contains(&e.as_str()))
+        {
+            return invalid(format!(
+                "Unknown event filter: {} (expected one of {})",
+                event,
+                events::EVENTS.join(", ")
+            ));
+        }
+        if let Some(label) = self
+            .label
+            .iter()
+            .find(|l| labels::parse_filter(l).is_none())
+        {
+            return invalid(format!(
+                "Invalid label filter {}: expected key:value",
+                label
+            ));
+        }
+        if self.capability.iter().any(|c| c.is_empty()) {
+            return invalid("Capability filters must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn matches(&self, event: &Value) -> bool {
+        let one_of = |values: &[String], field: &str| {
+            values.is_empty()
+                || event[field]
+                    .as_str()
+                    .is_some_and(|v| values.iter().any(|value| value == v))
+        };
+        one_of(&self.status, "status")
+            && one_of(&self.event, "event")
+            && one_of(&self.capability, "capability")
+            && (self.label.is_empty()
+                || self.label.iter().any(|filter| {
+                    labels::parse_filter(filter)
+                        .is_some_and(|(key, value)| event["labels"][key] == value)
+                }))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Subscription {
+    id: String,
+    /// API key that created the subscription
+    owner: String,
+    /// Whether events of other keys' tasks are included
+    all_tasks: bool,
+    /// Webhook receiving events; stream only when unset
+    url: Option<String>,
+    /// Webhook signing secret
+    secret: Option<String>,
+    filters: Filters,
+    created_at: String,
+}
+
+impl Subscription {
+    fn wants(&self, event: &Value) -> bool {
+        (self.all_tasks || event["submitted_by"].as_str() == Some(self.owner.as_str()))
+            && self.filters.matches(event)
+    }
+
+    /// The subscription as shown to clients, without its secret
+    fn public(&self) -> Value {
+        json!({
+            "id": self.id,
+            "owner": self.owner,
+            "all_tasks": self.all_tasks,
+            "url": self.url,
+            "filters": self.filters,
+            "created_at": self.created_at,
+            "events_url": format!("/subscriptions/{}/events", self.id),
+        })
+    }
+}
+
+/// Hands events published by any gateway to local stream consumers
+#[derive(Clone)]
+pub struct SubscriptionHub {
+    local: broadcast::Sender<Value>,
+}
+
+async fn load(conn: &mut redis::aio::Connection) -> anyhow::Result<Vec<Subscription>> {
+    let stored: HashMap<String, String> = conn.hgetall(SUBSCRIPTIONS_KEY).await?;
+    Ok(stored
+        .into_values()
+        .filter_map(|s| serde_json::from_str(&s).ok())
+        .collect())
+}
+
+async fn load_one(
+    conn: &mut redis::aio::Connection,
+    id: &str,
+) -> Result<Option<Subscription>, ApiError> {
+    let stored: Option<String> = conn.hget(SUBSCRIPTIONS_KEY, id).await?;
+    Ok(stored.map(|s| serde_json::from_str(&s)).transpose()?)
+}
+
+/// The subscription `id`, if `principal` may see it
+async fn owned(
+    conn: &mut redis::aio::Connection,
+    principal: &Principal,
+    id: &str,
+) -> Result<Subscription, ApiError> {
+    match load_one(conn, id).await? {
+        Some(s) if s.owner == principal.key_id || principal.role.is_privileged() => Ok(s),
+        _ => Err(ApiError::not_found(format!(
+            "Subscription {} not found",
+            id
+        ))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateSubscription {
+    url: Option<String>,
+    #[serde(default)]
+    filters: Filters,
+}
+
+// Register a subscription to task events
+pub async fn create_subscription(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Json(req): Json<CreateSubscription>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    req.filters.validate()?;
+    if let Some(url) = &req.url {
+        check_target(url)
+            .await
+            .map_err(|e| ApiError::bad_request("invalid_url", e))?;
+    }
+
+    let mut conn = redis_connection(&state).await?;
+    let limit = state.config.current().subscriptions.max_per_owner;
+    let held = load(&mut conn)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .iter()
+        .filter(|s| s.owner == principal.key_id)
+        .count();
+    if held >= limit {
+        return Err(ApiError::unprocessable(
+            "subscription_limit",
+            format!("At most {} subscriptions per API key", limit),
+        ));
+    }
+
+    let subscription = Subscription {
+        id: uuid::Uuid::new_v4().to_string(),
+        owner: principal.key_id.clone(),
+        all_tasks: principal.role.is_privileged(),
+        secret: req
+            .url
+            .is_some()
+            .then(|| hex::encode(rand::random::<[u8; 32]>())),
+        url: req.url,
+        filters: req.filters,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    conn.hset::<_, _, _, ()>(
+        SUBSCRIPTIONS_KEY,
+        &subscription.id,
+        serde_json::to_string(&subscription)?,
+    )
+    .await?;
+    events::set_subscribed(true);
+
+    let mut body = subscription.public();
+    // Shown this once only
+    body["secret"] = subscription.secret.into();
+    Ok((StatusCode::CREATED, Json(body)))
+}
+
+// List the caller's subscriptions, or every one for operators and admins
+pub async fn list_subscriptions(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let mut conn = redis_connection(&state).await?;
+    let mut subscriptions: Vec<Subscription> = load(&mut conn)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .into_iter()
+        .filter(|s| s.owner == principal.key_id || principal.role.is_privileged())
+        .collect();
+    subscriptions.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    let subscriptions: Vec<Value> = subscriptions.iter().map(Subscription::public).collect();
+    Ok(Json(json!({ "subscriptions": subscriptions })))
+}
+
+// Remove a subscription
+pub async fn delete_subscription(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let mut conn = redis_connection(&state).await?;
+    owned(&mut conn, &principal, &id).await?;
+    conn.hdel::<_, _, ()>(SUBSCRIPTIONS_KEY, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Follow a subscription's events as server-sent events
+pub async fn stream_events(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized("Missing bearer token"));
+    };
+    let mut conn = redis_connection(&state).await?;
+    let subscription = owned(&mut conn, &principal, &id).await?;
+
+    let receiver = state.subscriptions.local.subscribe();
+    let events = stream::unfold(
+        (receiver, subscription),
+        |(mut receiver, subscription)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if subscription.wants(&event) => {
+                        let name = event["event"].as_str().unwrap_or("task.event");
+                        let sse = Event::default().event(name).data(event.to_string());
+                        return Some((Ok(sse), (receiver, subscription)));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "Event stream for subscription {} skipped {} events",
+                            subscription.id, skipped
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Ok(Sse::new(events.boxed()).keep_alive(KeepAlive::default()))
+}
+
+/// Why a webhook delivery failed
+#[derive(Debug)]
+enum DeliveryError {
+    /// The receiver answered with an error status
+    Rejected(reqwest::StatusCode),
+    /// The URL no longer resolves to a public address
+    Blocked(String),
+    Failed(reqwest::Error),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::Rejected(status) => write!(f, "webhook answered {}", status),
+            DeliveryError::Blocked(reason) => write!(f, "{}", reason),
+            DeliveryError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl DeliveryError {
+    /// Receivers refusing the event for good are not asked again
+    fn is_retryable(&self) -> bool {
+        match self {
+            DeliveryError::Rejected(status) => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            DeliveryError::Blocked(_) => false,
+            DeliveryError::Failed(_) => true,
+        }
+    }
+}
+
+/// Whether `ip` is an address webhooks must not reach: loopback, private,
+/// link-local, shared (CGNAT), unspecified, broadcast or multicast,
+/// including IPv4 addresses reached through NAT64 (`64:ff9b::/96`) or 6to4
+/// (`2002::/16`)
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(v4));
+            }
+            let segments = ip.segments();
+            let embedded = |high: u16, low: u16| {
+                let [a, b] = high.to_be_bytes();
+                let [c, d] = low.to_be_bytes();
+                is_internal(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+            };
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return embedded(segments[6], segments[7]);
+            }
+            let first = segments[0];
+            if first == 0x2002 {
+                return embedded(segments[1], segments[2]);
+            }
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// A webhook host and the public addresses it resolved to
+#[derive(Debug)]
+struct Target {
+    host: String,
+    addrs: Vec<SocketAddr>,
+}
+
+/// Check that `url` is an http(s) URL whose host resolves only to public
+/// addresses, returning them
+async fn check_target(url: &str) -> Result<Target, String> {
+    let url = reqwest::Url::parse(url).map_err(|_| "url must be an http or https URL")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("url must be an http or https URL".to_string());
+    }
+    let host = url.host_str().ok_or("url must name a host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<_> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Cannot resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("Cannot resolve {}", host));
+    }
+    if addrs.iter().any(|a| is_internal(a.ip())) {
+        return Err(format!("{} resolves to an internal address", host));
+    }
+    Ok(Target {
+        host: host.to_string(),
+        addrs,
+    })
+}
+
+/// Client for one delivery to `target`, connecting only to its checked
+/// addresses
+fn pinned_client(timeout: Duration, target: &Target) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(&target.host, &target.addrs)
+        .build()
+}
+
+/// POST `event` to the webhook of `subscription`
+async fn deliver(
+    timeout: Duration,
+    retry: &RetryPolicy,
+    subscription: &Subscription,
+    event: &Value,
+) -> Result<(), DeliveryError> {
+    let (Some(url), Some(secret)) = (&subscription.url, &subscription.secret) else {
+        return Ok(());
+    };
+    // Checked again in case the name has been pointed elsewhere since, and
+    // connected to only where it pointed then
+    let target = check_target(url).await.map_err(DeliveryError::Blocked)?;
+    let http = pinned_client(timeout, &target).map_err(DeliveryError::Failed)?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let body = event.to_string();
+    let name = event["event"].as_str().unwrap_or_default();
+
+    // Each attempt is signed afresh so retries stay inside a replay window
+    retry
+        .run_if(
+            &format!("Deliver {} to subscription {}", name, subscription.id),
+            || async {
+                let timestamp = Utc::now().timestamp().to_string();
+                let mut ctx = hmac::Context::with_key(&key);
+                ctx.update(timestamp.as_bytes());
+                ctx.update(b".");
+                ctx.update(body.as_bytes());
+                let signature = hex::encode(ctx.sign().as_ref());
+
+                let response = http
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(EVENT_HEADER, name)
+                    .header(SUBSCRIPTION_HEADER, &subscription.id)
+                    .header(TIMESTAMP_HEADER, timestamp)
+                    .header(SIGNATURE_HEADER, signature)
+                    .body(body.clone())
+                    .send()
+                    .await
+                    .map_err(DeliveryError::Failed)?;
+                if !response.status().is_success() {
+                    return Err(DeliveryError::Rejected(response.status()));
+                }
+                Ok(())
+            },
+            DeliveryError::is_retryable,
+        )
+        .await
+}
+
+/// Publish each batch of events and deliver it to matching webhooks
+async fn fan_out(
+    redis_client: Arc<Client>,
+    mut receiver: mpsc::Receiver<Value>,
+    timeout: Duration,
+    retry: RetryPolicy,
+) {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+    let mut subscriptions: Vec<Arc<Subscription>> = Vec::new();
+    let mut loaded_at: Option<Instant> = None;
+    let mut batch = Vec::new();
+    loop {
+        let received = tokio::time::timeout(RELOAD_INTERVAL, receiver.recv_many(&mut batch, 256));
+        if let Ok(0) = received.await {
+            return;
+        }
+
+        let mut conn = match redis_client.get_async_connection().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Subscription hub cannot reach Redis: {}", e);
+                batch.clear();
+                continue;
+            }
+        };
+        if loaded_at.is_none_or(|at| at.elapsed() >= RELOAD_INTERVAL) {
+            match load(&mut conn).await {
+                Ok(loaded) => {
+                    subscriptions = loaded.into_iter().map(Arc::new).collect();
+                    events::set_subscribed(!subscriptions.is_empty());
+                    loaded_at = Some(Instant::now());
+                }
+                Err(e) => warn!("Failed to load subscriptions: {}", e),
+            }
+        }
+
+        for event in batch.drain(..) {
+            if let Err(e) = conn
+                .publish::<_, _, ()>(EVENTS_CHANNEL, event.to_string())
+                .await
+            {
+                warn!("Failed to publish task event: {}", e);
+            }
+
+            let event = Arc::new(event);
+            for subscription in subscriptions.iter().filter(|s| s.url.is_some()) {
+                if !subscription.wants(&event) {
+                    continue;
+                }
+                let Ok(permit) = permits.clone().acquire_owned().await else {
+                    return;
+                };
+                let retry = retry.clone();
+                let (subscription, event) = (subscription.clone(), event.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = deliver(timeout, &retry, &subscription, &event).await {
+                        warn!(
+                            "Dropped {} for subscription {}: {}",
+                            event["event"], subscription.id, e
+                        );
+                    }
+                    drop(permit);
+                });
+            }
+        }
+    }
+}
+
+/// Forward events published by every gateway to local stream consumers
+async fn relay(redis_client: &Client, local: &broadcast::Sender<Value>) -> anyhow::Result<()> {
+    let mut pubsub = redis_client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(EVENTS_CHANNEL).await?;
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        if let Ok(event) = serde_json::from_str(&payload) {
+            // Fails only while nobody is streaming
+            let _ = local.send(event);
+        }
+    }
+    Ok(())
+}
+
+/// Start delivering task events to subscribers, returning the hub and the
+/// buffer the event tracker feeds
+pub fn start_hub(
+    redis_client: Arc<Client>,
+    settings: &SubscriptionSettings,
+    retry: RetryPolicy,
+) -> (SubscriptionHub, mpsc::Sender<Value>) {
+    let timeout = Duration::from_secs(settings.timeout_secs);
+    let (sender, receiver) = mpsc::channel(settings.buffer_size);
+    tokio::spawn(fan_out(redis_client.clone(), receiver, timeout, retry));
+
+    let (local, _) = broadcast::channel(STREAM_BUFFER);
+    let hub = SubscriptionHub {
+        local: local.clone(),
+    };
+    tokio::spawn(async move {
+        loop {
+            match relay(&redis_client, &local).await {
+                Ok(()) => warn!("Task event subscription closed"),
+                Err(e) => warn!("Task event relay error: {}", e),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    });
+    (hub, sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "2002:c0a8:101::",
+        ] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in [
+            "93.184.216.34",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ] {
+            assert!(!internal(ip), "{} should be public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_scheme_and_resolved_host() {
+        assert!(check_target("https://93.184.216.34/hook").await.is_ok());
+        assert!(check_target("ftp://93.184.216.34/hook").await.is_err());
+        assert!(check_target("http://127.0.0.1:8080/hook").await.is_err());
+        assert!(check_target("http://[::1]/hook").await.is_err());
+        assert!(check_target("not a url").await.is_err());
+
+        let target = check_target("https://93.184.216.34:8443/hook")
+            .await
+            .unwrap();
+        assert_eq!(target.host, "93.184.216.34");
+        assert_eq!(target.addrs, ["93.184.216.34:8443".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn deliveries_connect_to_the_checked_addresses() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        // The name does not resolve; only the pinned address is reachable
+        let target = Target {
+            host: "hooks.example.invalid".to_string(),
+            addrs: vec![addr],
+        };
+        let http = pinned_client(Duration::from_secs(5), &target).unwrap();
+        let url = format!("http://hooks.example.invalid:{}/hook", addr.port());
+        let response = http.post(url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+    }
+}
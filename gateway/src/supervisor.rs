@@ -0,0 +1,213 @@
+//! Channel adaptor supervision.
+//!
+//! The Telegram, email and Twilio adaptors run as background tasks for the
+//! life of the gateway. Each is started through a [`Supervisor`], which
+//! starts it again when it returns an error or panics, after the
+//! `retry.adaptor` backoff. An adaptor crashing more than
+//! `supervisor.max_restarts` times within `supervisor.restart_window_secs`
+//! is left down rather than restarted in a storm. Whether each adaptor is
+//! up shows at `GET /health/detailed`.
+
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::config::SupervisorSettings;
+use crate::retry::RetryPolicy;
+
+/// How a supervised adaptor is doing
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AdaptorStatus {
+    /// `up`, `restarting` after a crash, `failed` once restarts were given
+    /// up, or `stopped` if it returned without an error
+    pub state: &'static str,
+    /// Restarts since the gateway started
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub up_since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_crash_at: Option<String>,
+}
+
+impl AdaptorStatus {
+    pub fn is_up(&self) -> bool {
+        self.state == "up"
+    }
+}
+
+#[derive(Debug)]
+pub struct Supervisor {
+    backoff: RetryPolicy,
+    max_restarts: u32,
+    window: Duration,
+    adaptors: Mutex<BTreeMap<String, AdaptorStatus>>,
+}
+
+impl Supervisor {
+    pub fn new(backoff: RetryPolicy, settings: &SupervisorSettings) -> Self {
+        Self {
+            backoff,
+            max_restarts: settings.max_restarts,
+            window: Duration::from_secs(settings.restart_window_secs),
+            adaptors: Mutex::default(),
+        }
+    }
+
+    /// Every supervised adaptor's status, by name
+    pub fn adaptors(&self) -> BTreeMap<String, AdaptorStatus> {
+        self.adaptors.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn update(&self, name: &str, update: impl FnOnce(&mut AdaptorStatus)) {
+        let mut adaptors = self.adaptors.lock().unwrap_or_else(|e| e.into_inner());
+        update(adaptors.entry(name.to_string()).or_default());
+    }
+
+    /// Run the adaptor `start` builds in a background task under `name`,
+    /// building it again whenever it crashes
+    pub fn supervise<F, Fut>(self: &Arc<Self>, name: impl Into<String>, start: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.into();
+        supervisor.update(&name, |status| status.state = "up");
+        tokio::spawn(async move { supervisor.watch(name, start).await });
+    }
+
+    async fn watch<F, Fut>(&self, name: String, start: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let mut crashes = VecDeque::new();
+        loop {
+            self.update(&name, |status| {
+                status.state = "up";
+                status.up_since = Some(Utc::now().to_rfc3339());
+            });
+            // Run it in a task of its own so a panic ends it alone
+            let error = match tokio::spawn(start()).await {
+                Ok(Ok(())) => {
+                    info!("Adaptor {} stopped", name);
+                    self.update(&name, |status| {
+                        status.state = "stopped";
+                        status.up_since = None;
+                    });
+                    return;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+
+            let now = Instant::now();
+            crashes.push_back(now);
+            while crashes
+                .front()
+                .is_some_and(|at| now.duration_since(*at) > self.window)
+            {
+                crashes.pop_front();
+            }
+            let storm = crashes.len() as u32 > self.max_restarts;
+            self.update(&name, |status| {
+                status.state = if storm { "failed" } else { "restarting" };
+                status.up_since = None;
+                status.last_error = Some(error.clone());
+                status.last_crash_at = Some(Utc::now().to_rfc3339());
+            });
+            if storm {
+                error!(
+                    "Adaptor {} crashed {} times within {}s, leaving it down: {}",
+                    name,
+                    crashes.len(),
+                    self.window.as_secs(),
+                    error
+                );
+                return;
+            }
+
+            let delay = self.backoff.delay_for(crashes.len() as u32);
+            warn!("Adaptor {} crashed, restarting in {:?}: {}", name, delay, error);
+            tokio::time::sleep(delay).await;
+            self.update(&name, |status| status.restarts += 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn supervisor(max_restarts: u32) -> Arc<Supervisor> {
+        let backoff = RetryPolicy {
+            base_delay: Duration::ZERO,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let settings = SupervisorSettings {
+            max_restarts,
+            ..SupervisorSettings::default()
+        };
+        Arc::new(Supervisor::new(backoff, &settings))
+    }
+
+    async fn wait_for(supervisor: &Supervisor, name: &str, state: &str) -> AdaptorStatus {
+        for _ in 0..200 {
+            let status = supervisor.adaptors()[name].clone();
+            if status.state == state {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("adaptor {} never became {}", name, state);
+    }
+
+    #[tokio::test]
+    async fn restarts_crashed_adaptors_until_they_stay_up() {
+        let supervisor = supervisor(5);
+        let starts = Arc::new(AtomicU32::new(0));
+        let counted = starts.clone();
+        supervisor.supervise("telegram", move || {
+            let start = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match start {
+                    0 => anyhow::bail!("connection reset"),
+                    1 => panic!("poisoned"),
+                    _ => std::future::pending().await,
+                }
+            }
+        });
+
+        while starts.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let status = supervisor.adaptors()["telegram"].clone();
+        assert!(status.is_up());
+        assert_eq!(status.restarts, 2);
+        assert!(status.last_error.unwrap().contains("panicked"));
+    }
+
+    #[tokio::test]
+    async fn leaves_adaptors_down_after_a_restart_storm() {
+        let supervisor = supervisor(2);
+        let starts = Arc::new(AtomicU32::new(0));
+        let counted = starts.clone();
+        supervisor.supervise("email", move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async { anyhow::bail!("IMAP login refused") }
+        });
+
+        let status = wait_for(&supervisor, "email", "failed").await;
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error.as_deref(), Some("IMAP login refused"));
+    }
+}
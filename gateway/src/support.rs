@@ -0,0 +1,154 @@
+//! Support bundles for bug reports.
+//!
+//! `POST /admin/support-bundle` returns a `.tar.gz` with the gateway's
+//! version, its effective configuration with secrets masked, queue stats,
+//! health snapshots and the most recent warnings and errors. Every section
+//! is collected independently, so a bundle can still be produced while Redis
+//! is down, which is exactly when one is most useful.
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use flate2::{write::GzEncoder, Compression};
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::error::ApiError;
+use crate::health::BUILD;
+use crate::queue::QueueError;
+use crate::{envelope, federation, telegram, telemetry, AppState, AGENT_QUEUE, PRIORITY_QUEUE};
+
+/// Build and return a support bundle archive (admin only)
+pub async fn support_bundle(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let generated_at = chrono::Utc::now();
+    let name = format!("support-bundle-{}", generated_at.format("%Y%m%dT%H%M%SZ"));
+
+    let health = crate::health_check(State(state.clone())).await.0;
+    let files = [
+        ("version.json", version_info(generated_at)),
+        ("config.json", state.config.current().redacted()),
+        ("queues.json", queue_stats(&state).await),
+        (
+            "health.json",
+            json!({
+                "health": health,
+                "telegram": telegram::snapshots(&state.telegram_bots),
+                "runtime_config": {
+                    "reloads": state.config.reloads.get(),
+                    "failures": state.config.failures.get(),
+                    "last_reload_at": state.config.last_reload_at.get(),
+                },
+                "cache": {
+                    "hits": state.cache_metrics.hits.get(),
+                    "misses": state.cache_metrics.misses.get(),
+                    "bypassed": state.cache_metrics.bypassed.get(),
+                    "stored": state.cache_metrics.stored.get(),
+                    "deduplicated": state.cache_metrics.deduplicated.get(),
+                },
+                "backpressure": {
+                    "depth": state.queue_guard.depth.get(),
+                    "priority_depth": state.queue_guard.priority_depth.get(),
+                    "shed": state.queue_guard.shed.get(),
+                },
+                "agents": {
+                    "alive": state.agent_metrics.alive.get(),
+                    "orphaned": state.agent_metrics.orphaned.get(),
+                    "lapsed_leases": state.agent_metrics.leases_lapsed.get(),
+                },
+                "watchdog": {
+                    "timed_out": state.watchdog_metrics.timed_out.get(),
+                    "requeued": state.watchdog_metrics.requeued.get(),
+                },
+                "events": {
+                    "delivered": state.event_metrics.delivered.get(),
+                    "dropped": state.event_metrics.dropped.get(),
+                },
+                "memory": {
+                    "used_bytes": state.memory_guard.used_bytes.get(),
+                    "ceiling_bytes": state.memory_guard.ceiling_bytes.get(),
+                    "rejected": state.memory_guard.rejected.get(),
+                },
+            }),
+        ),
+    ];
+
+    let mut errors = telemetry::recent_errors().join("\n");
+    errors.push('\n');
+
+    let archive = build_archive(&name, &files, &errors)
+        .map_err(|e| ApiError::internal(format!("Failed to build support bundle: {}", e)))?;
+    info!(
+        "Generated support bundle {} ({} bytes)",
+        name,
+        archive.len()
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.tar.gz\"", name),
+            ),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+fn version_info(generated_at: chrono::DateTime<chrono::Utc>) -> Value {
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "build": BUILD,
+        "envelope_version": envelope::CURRENT_VERSION,
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "generated_at": generated_at.to_rfc3339(),
+    })
+}
+
+async fn queue_stats(state: &AppState) -> Value {
+    let stats = async {
+        let mut conn = state.redis_client.get_async_connection().await?;
+        let depths = state
+            .task_queue
+            .depths(&mut conn, &[AGENT_QUEUE, PRIORITY_QUEUE])
+            .await?;
+        let forwarded: u64 = conn.hlen(federation::FORWARDED_KEY).await?;
+        let keys: u64 = redis::cmd("DBSIZE").query_async(&mut conn).await?;
+        Ok::<_, QueueError>(json!({
+            "backend": state.task_queue.backend().as_str(),
+            AGENT_QUEUE: depths[0],
+            PRIORITY_QUEUE: depths[1],
+            (federation::FORWARDED_KEY): forwarded,
+            "keys": keys,
+        }))
+    };
+    stats
+        .await
+        .unwrap_or_else(|e| json!({ "error": e.to_string() }))
+}
+
+fn build_archive(name: &str, files: &[(&str, Value)], errors: &str) -> std::io::Result<Vec<u8>> {
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+
+    let mut append = |path: &str, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{}/{}", name, path), data)
+    };
+
+    for (path, value) in files {
+        append(path, &serde_json::to_vec_pretty(value)?)?;
+    }
+    append("errors.log", errors.as_bytes())?;
+
+    archive.into_inner()?.finish()
+}
@@ -0,0 +1,253 @@
+//! Task execution logs.
+//!
+//! Agents append what they log while working on a task to the Redis stream
+//! `logs:{task_id}`, keeping the latest thousand or so entries of `{"time",
+//! "level", "source", "message"}`. `GET /task/:id/logs` pages through them
+//! oldest first, `limit` at a time (default [`DEFAULT_LIMIT`], at most
+//! [`MAX_LIMIT`]), starting after the entry id given as `after`; a full page
+//! names the id to continue from as `next`. With `follow=true` the entries
+//! come as server-sent `log` events instead, old ones first and new ones as
+//! agents write them, until the task settles. Each event carries its entry
+//! id, so a client reconnecting with `Last-Event-ID` picks up where it left
+//! off. Logs are as open as the task's result, and go with the task when it
+//! is deleted or purged.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::{annotations, history, redis_connection, schema, AppState};
+
+pub use claw_core::keys::logs_key;
+
+/// Entries on a page unless `limit` says otherwise
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// Most entries on a page, and in one read while following
+pub const MAX_LIMIT: usize = 1000;
+
+/// How long a follower waits for new entries before checking on the task
+const FOLLOW_BLOCK: Duration = Duration::from_secs(5);
+
+/// Query of `GET /task/:id/logs`
+#[derive(Debug, Deserialize)]
+pub struct LogsQuery {
+    after: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    follow: bool,
+}
+
+/// A log entry with its stream id
+#[derive(Debug, Serialize)]
+pub struct LogEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Stream entries as XRANGE and XREAD answer them
+type StreamEntries = Vec<(String, BTreeMap<String, String>)>;
+
+fn entries(raw: StreamEntries) -> Vec<LogEntry> {
+    raw.into_iter()
+        .map(|(id, fields)| LogEntry { id, fields })
+        .collect()
+}
+
+/// `id` if it is a stream entry id, `<ms>` or `<ms>-<seq>`
+fn cursor(id: &str) -> Result<String, ApiError> {
+    let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+    let numeric = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if numeric(ms) && numeric(seq) {
+        Ok(id.to_string())
+    } else {
+        Err(ApiError::bad_request(
+            "invalid_cursor",
+            format!("{} is not a log entry id", id),
+        ))
+    }
+}
+
+/// Up to `count` entries of the log of `task_id`, after `after` if given
+async fn read(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    after: Option<&str>,
+    count: usize,
+) -> redis::RedisResult<Vec<LogEntry>> {
+    let start = after.map_or_else(|| "-".to_string(), |id| format!("({}", id));
+    let raw: StreamEntries = redis::cmd("XRANGE")
+        .arg(logs_key(task_id))
+        .arg(start)
+        .arg("+")
+        .arg("COUNT")
+        .arg(count)
+        .query_async(conn)
+        .await?;
+    Ok(entries(raw))
+}
+
+// Page through or follow a task's execution log
+pub async fn get_logs(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Path(task_id): Path<String>,
+    Query(query): Query<LogsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(ApiError::bad_request(
+            "invalid_limit",
+            format!("limit must be 1-{}", MAX_LIMIT),
+        ));
+    }
+    let resumed = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|_| query.follow);
+    let after = query.after.as_deref().or(resumed).map(cursor).transpose()?;
+
+    let mut conn = redis_connection(&state).await?;
+    let principal = principal.as_ref().map(|Extension(p)| p);
+    annotations::check_access(&mut conn, principal, &task_id).await?;
+    if query.follow {
+        return Ok(follow(conn, task_id, after).into_response());
+    }
+
+    let entries = read(&mut conn, &task_id, after.as_deref(), limit).await?;
+    let next = entries
+        .last()
+        .filter(|_| entries.len() == limit)
+        .map(|entry| entry.id.clone());
+    Ok(Json(json!({
+        "task_id": task_id,
+        "entries": entries,
+        "next": next,
+    }))
+    .into_response())
+}
+
+/// A client following a log, on a connection of its own
+struct Follower {
+    conn: redis::aio::Connection,
+    task_id: String,
+    /// Id of the last entry read
+    after: String,
+    unsent: VecDeque<LogEntry>,
+    /// Whether the task had settled before the last read
+    settled: bool,
+}
+
+impl Follower {
+    /// Whether the task is settled, or gone
+    async fn task_settled(&mut self) -> anyhow::Result<bool> {
+        let task: Option<String> = self.conn.get(format!("task:{}", self.task_id)).await?;
+        let Some(task) = task else {
+            return Ok(true);
+        };
+        let task = schema::decode_task(&task)?;
+        let status = task["status"].as_str().unwrap_or_default();
+        Ok(history::FINAL_STATUSES.contains(&status))
+    }
+
+    /// Read the next entries, waiting a while for some unless the task has
+    /// settled; what it logged before settling is read all the same
+    async fn read_more(&mut self) -> anyhow::Result<()> {
+        self.settled = self.task_settled().await?;
+        let mut cmd = redis::cmd("XREAD");
+        cmd.arg("COUNT").arg(MAX_LIMIT);
+        if !self.settled {
+            cmd.arg("BLOCK").arg(FOLLOW_BLOCK.as_millis() as u64);
+        }
+        cmd.arg("STREAMS")
+            .arg(logs_key(&self.task_id))
+            .arg(&self.after);
+        let reply: Option<Vec<(String, StreamEntries)>> = cmd.query_async(&mut self.conn).await?;
+        for (_, raw) in reply.unwrap_or_default() {
+            self.unsent.extend(entries(raw));
+        }
+        if let Some(last) = self.unsent.back() {
+            self.after = last.id.clone();
+            // More may follow what this read left behind
+            self.settled = false;
+        }
+        Ok(())
+    }
+}
+
+/// The log of `task_id` as server-sent events, after `after` if given
+fn follow(
+    conn: redis::aio::Connection,
+    task_id: String,
+    after: Option<String>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let follower = Follower {
+        conn,
+        task_id,
+        after: after.unwrap_or_else(|| "0-0".to_string()),
+        unsent: VecDeque::new(),
+        settled: false,
+    };
+    let events = stream::unfold(follower, |mut follower| async move {
+        loop {
+            if let Some(entry) = follower.unsent.pop_front() {
+                let data = serde_json::to_string(&entry).unwrap_or_default();
+                let sse = Event::default().event("log").id(entry.id).data(data);
+                return Some((Ok(sse), follower));
+            }
+            if follower.settled {
+                return None;
+            }
+            if let Err(e) = follower.read_more().await {
+                warn!("Log stream for task {} failed: {}", follower.task_id, e);
+                return None;
+            }
+        }
+    });
+    Sse::new(events.boxed()).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_are_stream_entry_ids() {
+        assert_eq!(cursor("1700000000000-3").unwrap(), "1700000000000-3");
+        assert_eq!(cursor("1700000000000").unwrap(), "1700000000000");
+        assert!(cursor("-").is_err());
+        assert!(cursor("1700000000000-").is_err());
+        assert!(cursor("+)").is_err());
+    }
+
+    #[test]
+    fn entries_keep_agent_fields() {
+        let fields = BTreeMap::from([
+            ("level".to_string(), "INFO".to_string()),
+            ("message".to_string(), "Planning".to_string()),
+        ]);
+        let entry = &entries(vec![("1-0".to_string(), fields)])[0];
+        assert_eq!(
+            serde_json::to_value(entry).unwrap(),
+            json!({"id": "1-0", "level": "INFO", "message": "Planning"})
+        );
+    }
+}
@@ -1,31 +1,190 @@
 //! Telegram adaptor for secure gateway.
 
+use redis::aio::ConnectionManager;
 use redis::{AsyncCommands, Client};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tracing::{debug, error, info, warn};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn, Instrument};
 use uuid::Uuid;
 
-/// Telegram bot token from environment
-const TELEGRAM_API_BASE: &str = "https://api.telegram.org/bot";
+use crate::{
+    agent_registry, dedupe, envelope, events, failures, history, redaction, schema, watchdog,
+};
+use crate::i18n;
+use crate::agent_config;
+use crate::maintenance;
+use crate::backpressure::QueueGuard;
+use crate::memory_guard::MemoryGuard;
+use crate::queue::{NewTask, TaskQueue};
+use crate::replica::ReadReplica;
+use crate::request_id::{self, RequestId};
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::feedback::{self, Rating};
+use crate::{redis_connection, task_state, telemetry, AppState};
+use crate::metrics::{Counter, CounterVec, Gauge};
+use crate::retry::{CircuitBreaker, CircuitState, RetryDecision, RetryPolicies, RetryPolicy};
+use crate::config::TelegramSettings;
+use crate::runtime::RuntimeConfig;
+use crate::supervisor::Supervisor;
+use crate::templates::ReplyTemplates;
+use crate::throttle::{Rate, Throttle};
+use crate::tts::TtsClient;
+use crate::queue_for;
+
+/// User-Agent sent with Bot API calls
+const USER_AGENT: &str = concat!("secure-gateway/", env!("CARGO_PKG_VERSION"));
+
+/// Most replies being sent to Telegram at once
+const MAX_CONCURRENT_SENDS: usize = 8;
+
+/// Redis namespace of the state of the bot set by `telegram.bot_token`
+const DEFAULT_NAMESPACE: &str = "telegram:";
+
+/// Redis namespace of the state of the bots in `telegram.bots`, followed by
+/// the bot's name
+const BOTS_NAMESPACE: &str = "telegram:bots:";
+
+// The keys below are under the namespace of each bot
+
+/// Redis key holding the next `getUpdates` offset
+const OFFSET_KEY: &str = "offset";
+
+/// Redis hash of the reply languages chosen with `/language`, by chat id
+const LANGUAGES_KEY: &str = "languages";
+
+/// Redis set of the chats that turned on voice replies with `/voice`
+const VOICE_KEY: &str = "voice";
+
+/// Redis hash of the types of the chats the bot has heard from, by chat
+/// id, for broadcasts
+const CHATS_KEY: &str = "chats";
+
+/// Prefix of the `{chat_id}:{message_id}` keys marking messages already seen
+const SEEN_PREFIX: &str = "seen:";
+
+/// How long a seen message is remembered; Telegram keeps undelivered
+/// updates for a day
+const SEEN_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Prefix of the `{chat_id}:{message_id}` keys holding edited messages a
+/// user may re-run
+const EDITED_PREFIX: &str = "edited:";
+
+/// How long the re-run button of an edit keeps working
+const EDITED_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Callback data prefix of re-run buttons, followed by the message id
+const RERUN_PREFIX: &str = "rerun:";
+
+/// Callback data prefix of rating buttons, followed by the rating and the
+/// task id
+const RATING_PREFIX: &str = "rate:";
+
+/// Longest message the Bot API accepts, in UTF-16 code units
+const MAX_MESSAGE_UNITS: usize = 4096;
+
+/// Redis hash of the replies given up on by any bot, by task id
+const UNDELIVERABLE_KEY: &str = "telegram:undeliverable";
+
+/// Store the offset only if it moves forward, returning the effective value
+const ADVANCE_OFFSET_SCRIPT: &str = r#"
+local current = tonumber(redis.call('GET', KEYS[1]) or '0')
+local proposed = tonumber(ARGV[1])
+if proposed > current then
+  redis.call('SET', KEYS[1], ARGV[1])
+  return proposed
+end
+return current
+"#;
 
 /// Telegram update response
 #[derive(Debug, Deserialize)]
 struct TelegramUpdates {
     ok: bool,
+    #[serde(default)]
     result: Vec<Update>,
+    #[serde(default)]
+    parameters: Option<ResponseParameters>,
+}
+
+/// Extra error details attached to failed Bot API responses
+#[derive(Debug, Serialize, Deserialize)]
+struct ResponseParameters {
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+/// Bot API asked us to slow down
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Telegram rate limit, retry after {:?}", self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Bot API refused a call
+#[derive(Debug)]
+struct Refused {
+    method: &'static str,
+    error_code: Option<u16>,
+    description: Option<String>,
+}
+
+impl Refused {
+    /// Whether trying again cannot help: the user blocked the bot, the bot
+    /// was removed from the chat, or the chat is gone
+    fn is_permanent(&self) -> bool {
+        match self.error_code {
+            Some(403) => true,
+            Some(400) => self
+                .description
+                .as_deref()
+                .is_some_and(|d| d.contains("chat not found")),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = self.description.as_deref().unwrap_or("no description");
+        write!(f, "Telegram {} failed: {}", self.method, description)
+    }
 }
 
+impl std::error::Error for Refused {}
+
 /// Single Telegram update
 #[derive(Debug, Deserialize)]
 struct Update {
     #[serde(rename = "update_id")]
     update_id: i64,
     message: Option<Message>,
+    /// New version of a message sent earlier
+    edited_message: Option<Message>,
+    /// Press of an inline keyboard button
+    callback_query: Option<CallbackQuery>,
 }
 
 /// Telegram message
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Message {
     #[serde(rename = "message_id")]
     message_id: i64,
@@ -35,6 +194,111 @@ struct Message {
     chat: Chat,
     #[serde(default)]
     text: String,
+    #[serde(default)]
+    entities: Vec<MessageEntity>,
+    #[serde(default)]
+    reply_to_message: Option<Box<Message>>,
+    /// Voice note, for the agent to transcribe
+    #[serde(default)]
+    voice: Option<Voice>,
+    #[serde(default)]
+    caption: String,
+    #[serde(default)]
+    location: Option<Location>,
+    #[serde(default)]
+    contact: Option<Contact>,
+}
+
+impl Message {
+    /// Whether there is anything in the message the bot can act on
+    fn has_content(&self) -> bool {
+        !self.text.is_empty()
+            || self.voice.is_some()
+            || self.location.is_some()
+            || self.contact.is_some()
+    }
+}
+
+/// Location shared in a message
+#[derive(Debug, Deserialize, Serialize)]
+struct Location {
+    latitude: f64,
+    longitude: f64,
+    /// Radius of uncertainty, in meters
+    #[serde(default)]
+    horizontal_accuracy: Option<f64>,
+}
+
+impl Location {
+    fn input(&self) -> Value {
+        serde_json::json!({
+            "type": "location",
+            "lat": self.latitude,
+            "lon": self.longitude,
+            "accuracy_m": self.horizontal_accuracy,
+        })
+    }
+}
+
+/// Phone contact shared in a message
+#[derive(Debug, Deserialize, Serialize)]
+struct Contact {
+    phone_number: String,
+    first_name: String,
+    #[serde(default)]
+    last_name: Option<String>,
+    /// Telegram user of the contact, if they have one
+    #[serde(default)]
+    user_id: Option<i64>,
+}
+
+impl Contact {
+    fn input(&self) -> Value {
+        serde_json::json!({
+            "type": "contact",
+            "phone_number": self.phone_number,
+            "first_name": self.first_name,
+            "last_name": self.last_name,
+            "user_id": self.user_id,
+        })
+    }
+}
+
+/// Voice note attached to a message
+#[derive(Debug, Deserialize, Serialize)]
+struct Voice {
+    file_id: String,
+    #[serde(default)]
+    duration: u64,
+}
+
+/// Press of an inline keyboard button under one of the bot's messages
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    id: String,
+    from: User,
+    /// Message the button was under
+    message: Option<Message>,
+    data: Option<String>,
+}
+
+/// Entity annotation inside message text (mentions, commands, links)
+#[derive(Debug, Deserialize, Serialize)]
+struct MessageEntity {
+    #[serde(rename = "type")]
+    entity_type: String,
+    offset: usize,
+    length: usize,
+    /// The user a `text_mention` names, for users without a username
+    #[serde(default)]
+    user: Option<User>,
+}
+
+/// Response of getMe
+#[derive(Debug, Deserialize)]
+struct GetMeResponse {
+    ok: bool,
+    result: Option<User>,
 }
 
 /// Telegram user
@@ -49,6 +313,9 @@ struct User {
     last_name: String,
     #[serde(default)]
     username: String,
+    /// IETF language tag of the user's Telegram client, e.g. `de` or `pt-br`
+    #[serde(default)]
+    language_code: Option<String>,
 }
 
 /// Telegram chat
@@ -60,25 +327,17 @@ struct Chat {
     chat_type: String,
 }
 
-/// Telegram API response for sendMessage
+/// Telegram API response for calls other than getMe and getUpdates
 #[derive(Debug, Serialize, Deserialize)]
 struct TelegramResponse {
     ok: bool,
-    result: Option<MessageResult>,
+    /// The message sent, or `true` for calls with nothing to return
+    result: Option<Value>,
     description: Option<String>,
-}
-
-/// Result of sendMessage
-#[derive(Debug, Deserialize, Serialize)]
-struct MessageResult {
-    #[serde(rename = "message_id")]
-    message_id: i64,
-    #[serde(rename = "from")]
-    from: User,
-    #[serde(rename = "chat")]
-    chat: Chat,
     #[serde(default)]
-    date: i64,
+    error_code: Option<u16>,
+    #[serde(default)]
+    parameters: Option<ResponseParameters>,
 }
 
 /// Payload for sendMessage
@@ -90,243 +349,2279 @@ struct SendMessagePayload {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "parse_mode")]
     parse_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to_message_id: Option<i64>,
+    /// Inline keyboard shown under the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_markup: Option<Value>,
+    /// Formatting of parts of the text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entities: Option<Value>,
 }
 
-/// Pending task awaiting agent response
-struct PendingTask {
-    chat_id: i64,
+/// A result's reply, ready to send
+#[derive(Debug, Clone, PartialEq)]
+struct Reply {
+    text: String,
+    /// Shown as a JSON code block
+    code: bool,
+    /// Task whose result can be rated with buttons under the reply
+    rated: Option<String>,
 }
 
-/// Telegram adaptor that polls for messages and handles responses
-pub struct TelegramAdaptor {
-    redis_client: Arc<Client>,
-    bot_token: String,
-    offset: i64,
-    pending_tasks: Arc<tokio::sync::Mutex<std::collections::HashMap<String, PendingTask>>>,
-}
+impl Reply {
+    fn text(text: String) -> Self {
+        Self {
+            text: truncated(text),
+            code: false,
+            rated: None,
+        }
+    }
 
-impl TelegramAdaptor {
-    /// Create a new Telegram adaptor
-    pub fn new(redis_client: Arc<Client>, bot_token: String) -> Self {
+    /// With 👍/👎 buttons rating the result of `task_id`
+    fn rated(self, task_id: &str) -> Self {
         Self {
-            redis_client,
-            bot_token,
-            offset: 0,
-            pending_tasks: Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
+            rated: Some(task_id.to_string()),
+            ..self
         }
     }
 
-    /// Get updates from Telegram API
-    async fn get_updates(&self) -> anyhow::Result<Vec<Update>> {
-        let url = format!(
-            "{}getUpdates?offset={}&timeout=30",
-            self.get_base_url(),
-            self.offset
-        );
+    /// The reply to a result without text of its own: numbers and booleans
+    /// as they are, objects and arrays pretty-printed as a code block
+    fn structured(result: &Value) -> Option<Self> {
+        match result {
+            Value::Null => None,
+            Value::Object(_) | Value::Array(_) => Some(Self {
+                text: truncated(serde_json::to_string_pretty(result).ok()?),
+                code: true,
+                rated: None,
+            }),
+            other => Some(Self::text(other.to_string())),
+        }
+    }
+}
 
-        debug!("Calling Telegram API: {}", &url);
+/// Health metrics for the Telegram adaptor
+#[derive(Debug, Default)]
+pub struct TelegramMetrics {
+    pub updates_received: Counter,
+    /// Tasks created, labelled by chat id
+    pub tasks_created: CounterVec,
+    /// Tasks answered from a recent duplicate's result
+    pub tasks_deduplicated: Counter,
+    /// Redelivered messages skipped as already handled
+    pub duplicates_suppressed: Counter,
+    pub send_failures: Counter,
+    /// Replies given up on, listed under `/admin/telegram/undeliverable`
+    pub undeliverable: Counter,
+    /// HTTP 429 responses from the Bot API
+    pub rate_limited: Counter,
+    pub loop_errors: Counter,
+    pub pending_tasks: Gauge,
+    pub last_loop_duration_ms: Gauge,
+    /// Unix timestamp of the last completed loop iteration
+    pub last_loop_at: Gauge,
+    /// Breaker guarding Bot API calls, shared so `/health` can report it
+    pub breaker: CircuitBreaker,
+}
 
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(35))
-            .build()?;
+/// Point-in-time view of [`TelegramMetrics`] for the admin API
+#[derive(Debug, Serialize)]
+pub struct TelegramHealth {
+    pub updates_received: u64,
+    pub tasks_created: u64,
+    pub tasks_created_by_chat: HashMap<String, u64>,
+    pub tasks_deduplicated: u64,
+    pub duplicates_suppressed: u64,
+    pub send_failures: u64,
+    pub undeliverable: u64,
+    pub rate_limited: u64,
+    pub loop_errors: u64,
+    pub pending_tasks: i64,
+    pub last_loop_duration_ms: i64,
+    pub last_loop_at: Option<String>,
+    pub circuit: &'static str,
+    pub consecutive_failures: u32,
+}
 
-        let response = client.get(&url).send().await?;
+impl TelegramMetrics {
+    pub fn snapshot(&self) -> TelegramHealth {
+        let last_loop_at = self.last_loop_at.get();
+        TelegramHealth {
+            updates_received: self.updates_received.get(),
+            tasks_created: self.tasks_created.total(),
+            tasks_created_by_chat: self.tasks_created.snapshot(),
+            tasks_deduplicated: self.tasks_deduplicated.get(),
+            duplicates_suppressed: self.duplicates_suppressed.get(),
+            send_failures: self.send_failures.get(),
+            undeliverable: self.undeliverable.get(),
+            rate_limited: self.rate_limited.get(),
+            loop_errors: self.loop_errors.get(),
+            pending_tasks: self.pending_tasks.get(),
+            last_loop_duration_ms: self.last_loop_duration_ms.get(),
+            last_loop_at: (last_loop_at > 0)
+                .then(|| chrono::DateTime::from_timestamp(last_loop_at, 0))
+                .flatten()
+                .map(|t| t.to_rfc3339()),
+            circuit: self.breaker.state().as_str(),
+            consecutive_failures: self.breaker.failures(),
+        }
+    }
+}
 
-        // Get response status and body for debugging
-        let status = response.status();
-        let body_text = response.text().await?;
+/// Health of each of `bots`, by name
+pub fn snapshots(bots: &[Bot]) -> BTreeMap<String, TelegramHealth> {
+    bots.iter()
+        .map(|bot| (bot.label().to_string(), bot.metrics.snapshot()))
+        .collect()
+}
 
-        debug!("Telegram API response status: {}", status);
-        debug!("Telegram API response body: {}", body_text);
+/// Pending task awaiting agent response
+struct PendingTask {
+    chat_id: i64,
+    /// Message the task was created from
+    message_id: i64,
+    /// Message to thread the reply under (set for group chats)
+    reply_to: Option<i64>,
+    /// Request ID of the update that created the task
+    request_id: RequestId,
+    /// Language of the chat's apologies
+    language: &'static str,
+    /// Whether to speak the reply, when text-to-speech is set up
+    voice: bool,
+    /// Passes the reply has failed to go out on
+    attempts: u32,
+    /// Earliest the reply is tried again after a failed pass
+    retry_at: Option<Instant>,
+}
 
-        let updates: TelegramUpdates = serde_json::from_str(&body_text)?;
+/// Bot API client for sending messages, cheap to clone into concurrent sends
+#[derive(Clone)]
+struct BotApi {
+    base_url: String,
+    http: reqwest::Client,
+    metrics: Arc<TelegramMetrics>,
+    /// Voice replies are sent only when this is set
+    tts: Option<TtsClient>,
+    /// Spaces sends out by chat, shared with the bot's other clients
+    throttle: Arc<Throttle<i64>>,
+    chat_rate: Rate,
+    group_rate: Rate,
+}
 
-        if !updates.ok {
-            return Err(anyhow::anyhow!("Telegram API returned ok=false: {}", body_text));
+impl BotApi {
+    /// One client for every call, so connections to the Bot API are reused
+    fn new(
+        bot_token: &str,
+        throttle: Arc<Throttle<i64>>,
+        settings: &TelegramSettings,
+        metrics: Arc<TelegramMetrics>,
+    ) -> reqwest::Result<Self> {
+        let mut http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(settings.request_timeout_secs))
+            .connect_timeout(Duration::from_secs(settings.connect_timeout_secs));
+        if let Some(proxy_url) = &settings.proxy_url {
+            http = http.proxy(reqwest::Proxy::all(proxy_url)?);
         }
+        Ok(Self {
+            base_url: format!("{}/bot{}/", settings.api_base.trim_end_matches('/'), bot_token),
+            http: http.build()?,
+            metrics,
+            tts: TtsClient::from_settings(settings)?,
+            throttle,
+            chat_rate: Rate::per_minute(settings.chat_messages_per_min),
+            group_rate: Rate::per_minute(settings.group_messages_per_min),
+        })
+    }
 
-        Ok(updates.result)
+    /// Wait for a message to `chat_id` to be allowed out; group and channel
+    /// ids are negative
+    async fn take_turn(&self, chat_id: i64) {
+        let rate = if chat_id < 0 { self.group_rate } else { self.chat_rate };
+        self.throttle.acquire(chat_id, rate).await;
     }
 
-    /// Send message to Telegram
-    async fn send_message(&self, chat_id: i64, text: String) -> anyhow::Result<()> {
-        let url = format!("{}sendMessage", self.get_base_url());
+    /// Count rate-limit responses from the Bot API
+    fn observe_status(&self, status: reqwest::StatusCode) {
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            self.metrics.rate_limited.inc();
+        }
+    }
+
+    /// Send message to Telegram, optionally threaded as a reply
+    async fn send_message(
+        &self,
+        chat_id: i64,
+        text: String,
+        reply_to_message_id: Option<i64>,
+    ) -> anyhow::Result<()> {
         let payload = SendMessagePayload {
             chat_id,
             text,
             parse_mode: None,
+            reply_to_message_id,
+            reply_markup: None,
+            entities: None,
         };
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&payload)
-            .send()
-            .await?;
-
-        let telegram_response: TelegramResponse = response.json().await?;
-
-        if !telegram_response.ok {
-            return Err(anyhow::anyhow!(format!(
-                "Telegram sendMessage failed: {:?}",
-                telegram_response.description
-            )));
-        }
-
-        Ok(())
+        self.take_turn(chat_id).await;
+        self.call("sendMessage", &payload).await
     }
 
-    /// Get the base URL for Telegram API
-    fn get_base_url(&self) -> String {
-        format!("{}{}/", TELEGRAM_API_BASE, self.bot_token)
+    /// Send the reply to a task, threaded as a reply if asked
+    async fn send_reply(
+        &self,
+        chat_id: i64,
+        reply: &Reply,
+        reply_to_message_id: Option<i64>,
+    ) -> anyhow::Result<()> {
+        let length = reply.text.encode_utf16().count();
+        let entities = reply.code.then(|| {
+            serde_json::json!([{"type": "pre", "offset": 0, "length": length, "language": "json"}])
+        });
+        let payload = SendMessagePayload {
+            chat_id,
+            text: reply.text.clone(),
+            parse_mode: None,
+            reply_to_message_id,
+            reply_markup: reply.rated.as_deref().map(rating_keyboard),
+            entities,
+        };
+        self.take_turn(chat_id).await;
+        self.call("sendMessage", &payload).await
     }
 
-    /// Create task in Redis for agent processing
-    async fn create_task(
+    /// Send a message with one inline button, returning `data` when pressed
+    async fn send_button(
         &self,
-        message: &Message,
-    ) -> anyhow::Result<String> {
-        let task_id = Uuid::new_v4().to_string();
-
-        // Store pending task info
-        let pending = PendingTask {
-            chat_id: message.chat.id,
+        chat_id: i64,
+        text: String,
+        reply_to_message_id: Option<i64>,
+        (label, data): (&str, String),
+    ) -> anyhow::Result<()> {
+        let button = serde_json::json!({"text": label, "callback_data": data});
+        let payload = SendMessagePayload {
+            chat_id,
+            text,
+            parse_mode: None,
+            reply_to_message_id,
+            reply_markup: Some(serde_json::json!({"inline_keyboard": [[button]]})),
+            entities: None,
         };
-        self.pending_tasks
-            .lock()
-            .await
-            .insert(task_id.clone(), pending);
-
-        // Create task in Redis with Telegram metadata
-        let task_key = format!("task:{}", task_id);
-        let task_value = serde_json::to_string(&serde_json::json!({
-            "input": message.text.clone(),
-            "config": {
-                "telegram_chat_id": message.chat.id,
-                "telegram_message_id": message.message_id,
-                "telegram_user_id": message.from.as_ref().map(|u| u.id),
-                "telegram_username": message.from.as_ref().map(|u| u.username.clone()).filter(|s| !s.is_empty()),
-            },
-            "status": "pending",
-            "created_at": chrono::Utc::now().to_rfc3339(),
-        }))?;
-
-        let mut conn = self.redis_client.get_async_connection().await?;
-        conn.set::<_, _, ()>(&task_key, task_value).await?;
-
-        // Push to agent queue
-        conn.lpush::<_, _, ()>("agent:queue", &task_id).await?;
-
-        info!("Created task {} for Telegram chat {}", task_id, message.chat.id);
-
-        Ok(task_id)
+        self.take_turn(chat_id).await;
+        self.call("sendMessage", &payload).await
     }
 
-    /// Check for agent response and send to Telegram
-    async fn check_and_send_responses(&self) -> anyhow::Result<()> {
-        let pending = self.pending_tasks.lock().await;
-        let task_ids: Vec<String> = pending.keys().cloned().collect();
-        drop(pending);
+    /// Acknowledge a button press, showing `text` to the user if given
+    async fn answer_callback(&self, query_id: &str, text: Option<&str>) -> anyhow::Result<()> {
+        let payload = serde_json::json!({"callback_query_id": query_id, "text": text});
+        self.call("answerCallbackQuery", &payload).await
+    }
 
-        for task_id in task_ids {
-            let result_key = format!("result:{}", task_id);
+    /// Speak `text` in `language` and send it as a voice message
+    async fn send_voice(
+        &self,
+        chat_id: i64,
+        text: &str,
+        language: &str,
+        reply_to_message_id: Option<i64>,
+    ) -> anyhow::Result<()> {
+        let tts = self
+            .tts
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("text-to-speech is not configured"))?;
+        let audio = tts.synthesize(text, language).await?;
 
-            let mut conn = self.redis_client.get_async_connection().await?;
+        let mut fields = vec![("chat_id", chat_id.to_string())];
+        if let Some(id) = reply_to_message_id {
+            fields.push(("reply_to_message_id", id.to_string()));
+        }
+        let boundary = Uuid::new_v4().simple().to_string();
+        let body = multipart_body(&boundary, &fields, ("voice", "reply.ogg", "audio/ogg", &audio));
+        let request = self
+            .http
+            .post(format!("{}sendVoice", self.base_url))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(body);
+        self.take_turn(chat_id).await;
+        self.finish("sendVoice", request).await
+    }
 
-            if let Some(result_json) = conn.get::<_, String>(&result_key).await.ok() {
-                let result: serde_json::Value = serde_json::from_str(&result_json)?;
+    /// Make a Bot API call, reporting rate limits and refusals as errors
+    async fn call(&self, method: &'static str, payload: &impl Serialize) -> anyhow::Result<()> {
+        let url = format!("{}{}", self.base_url, method);
+        self.finish(method, self.http.post(&url).json(payload)).await
+    }
 
-                // Get the result text
-                if let Some(result_text) = result.get("result").and_then(|r| r.as_str()) {
-                    // Get the pending task info
-                    let pending = self.pending_tasks.lock().await;
-                    if let Some(task) = pending.get(&task_id) {
-                        let chat_id = task.chat_id;
-                        drop(pending);
+    /// Send a Bot API request and read its response; a rate limit holds
+    /// back every send of the bot for its `retry_after`
+    async fn finish(
+        &self,
+        method: &'static str,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<()> {
+        let response = request
+            .send()
+            .instrument(telemetry::telegram_span(method))
+            .await?;
 
-                        // Send response to Telegram
-                        if let Err(e) = self.send_message(chat_id, result_text.to_string()).await {
-                            error!("Failed to send message to Telegram: {}", e);
-                        } else {
-                            info!("Sent response to Telegram chat {}", chat_id);
+        self.observe_status(response.status());
+        let telegram_response: TelegramResponse = response.json().await?;
 
-                            // Remove from pending tasks
-                            self.pending_tasks.lock().await.remove(&task_id);
+        if let Some(retry_after) = telegram_response
+            .parameters
+            .as_ref()
+            .and_then(|p| p.retry_after)
+        {
+            let retry_after = Duration::from_secs(retry_after);
+            self.throttle.hold(retry_after);
+            return Err(RateLimited { retry_after }.into());
+        }
 
-                            // Clean up result from Redis
-                            let _: Result<i64, _> = conn.del(&result_key).await;
-                        }
-                    } else {
-                        drop(pending);
-                    }
-                }
+        if !telegram_response.ok {
+            return Err(Refused {
+                method,
+                error_code: telegram_response.error_code,
+                description: telegram_response.description,
             }
+            .into());
         }
 
         Ok(())
     }
+}
 
-    /// Run the adaptor loop
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        info!("Telegram adaptor started");
-
-        loop {
-            match self.run_once().await {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Error in Telegram adaptor loop: {}", e);
-                }
-            }
+/// A bot for an adaptor to run as
+#[derive(Debug, Clone)]
+pub struct Bot {
+    /// Unset for the bot of `telegram.bot_token`
+    name: Option<String>,
+    token: String,
+    capability: Option<String>,
+    /// Spaces out the bot's sends, its replies and broadcasts alike
+    throttle: Arc<Throttle<i64>>,
+    /// Counted apart from the other bots', with a breaker of its own
+    metrics: Arc<TelegramMetrics>,
+}
 
-            // Sleep 15 seconds if no messages
-            tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
-        }
+impl Bot {
+    /// Every bot `settings` configure, `telegram.bot_token`'s first
+    pub fn all(settings: &TelegramSettings) -> Vec<Bot> {
+        let throttle = || Arc::new(Throttle::new(Rate::per_second(settings.messages_per_sec)));
+        let metrics = || {
+            Arc::new(TelegramMetrics {
+                breaker: CircuitBreaker::from_config(&settings.breaker),
+                ..Default::default()
+            })
+        };
+        let default = settings.bot_token.iter().map(|token| Bot {
+            name: None,
+            token: token.clone(),
+            capability: None,
+            throttle: throttle(),
+            metrics: metrics(),
+        });
+        let named = settings.bots.iter().map(|bot| Bot {
+            name: Some(bot.name.clone()),
+            token: bot.token.clone(),
+            capability: bot.capability.clone(),
+            throttle: throttle(),
+            metrics: metrics(),
+        });
+        default.chain(named).collect()
     }
 
-    /// Run one iteration of the adaptor loop
-    async fn run_once(&mut self) -> anyhow::Result<bool> {
-        // Check for agent responses and send to Telegram
-        if let Err(e) = self.check_and_send_responses().await {
-            warn!("Failed to check responses: {}", e);
-        }
-
-        // Get updates from Telegram
-        let updates = self.get_updates().await?;
-
-        if updates.is_empty() {
-            debug!("No new Telegram messages");
-            return Ok(false);
+    /// Prefix of the bot's Redis keys
+    fn namespace(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}{}:", BOTS_NAMESPACE, name),
+            None => DEFAULT_NAMESPACE.to_string(),
         }
+    }
 
-        info!("Received {} Telegram updates", updates.len());
-
-        for update in updates {
-            // Update offset to mark this update as processed
-            self.offset = update.update_id + 1;
+    /// Name of the bot in logs and reports
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
 
-            if let Some(message) = update.message {
-                if !message.text.is_empty() {
-                    // Create task for agent processing
-                    if let Err(e) = self.create_task(&message).await {
-                        error!("Failed to create task: {}", e);
-                    }
-                }
-            }
+    /// Name of the bot's adaptor under the supervisor and in readiness checks
+    pub fn adaptor_name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("telegram:{}", name),
+            None => "telegram".to_string(),
         }
+    }
 
-        Ok(true)
+    pub fn metrics(&self) -> &Arc<TelegramMetrics> {
+        &self.metrics
     }
 }
 
-/// Start the Telegram adaptor in a background task
-pub fn start_telegram_adaptor(redis_client: Arc<Client>) {
-    let bot_token = std::env::var("TELEGRAM_BOT_TOKEN")
-        .expect("TELEGRAM_BOT_TOKEN must be set");
-
-    tokio::spawn(async move {
-        let mut adaptor = TelegramAdaptor::new(redis_client, bot_token);
-        if let Err(e) = adaptor.run().await {
-            error!("Telegram adaptor crashed: {}", e);
+/// Telegram adaptor that polls for messages and handles responses
+pub struct TelegramAdaptor {
+    bot: Bot,
+    /// Prefix of the bot's Redis keys
+    namespace: String,
+    /// Queue the bot's tasks go to when no agent holds their chat
+    queue: String,
+    redis_client: Arc<Client>,
+    /// Replica the response loop polls for results, if any
+    replica: ReadReplica,
+    /// Pooled connection for the response loop, opened on first use
+    connection: OnceCell<ConnectionManager>,
+    task_queue: TaskQueue,
+    api: BotApi,
+    /// Seconds a getUpdates call waits for messages
+    poll_timeout_secs: u64,
+    /// Seconds it waits while replies are awaited
+    busy_poll_timeout_secs: u64,
+    /// Pause after a poll without updates
+    poll_interval: Duration,
+    request_timeout_secs: u64,
+    offset: i64,
+    /// Whether `offset` has been restored from Redis yet
+    offset_loaded: bool,
+    pending_tasks: Arc<tokio::sync::Mutex<HashMap<String, PendingTask>>>,
+    /// Bot identity from getMe, used for mention-gating in groups
+    me: Option<User>,
+    metrics: Arc<TelegramMetrics>,
+    retry: RetryPolicies,
+    memory_guard: Arc<MemoryGuard>,
+    queue_guard: Arc<QueueGuard>,
+    runtime: Arc<RuntimeConfig>,
+    templates: ReplyTemplates,
+}
+
+impl TelegramAdaptor {
+    /// Create a new Telegram adaptor
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        redis_client: Arc<Client>,
+        replica: ReadReplica,
+        bot: Bot,
+        retry: RetryPolicies,
+        task_queue: TaskQueue,
+        memory_guard: Arc<MemoryGuard>,
+        queue_guard: Arc<QueueGuard>,
+        runtime: Arc<RuntimeConfig>,
+        templates: ReplyTemplates,
+    ) -> reqwest::Result<Self> {
+        let settings = runtime.current().telegram.clone();
+        let metrics = bot.metrics.clone();
+        let api = BotApi::new(&bot.token, bot.throttle.clone(), &settings, metrics.clone())?;
+        Ok(Self {
+            namespace: bot.namespace(),
+            queue: queue_for(bot.capability.as_deref(), false),
+            bot,
+            redis_client,
+            replica,
+            connection: OnceCell::new(),
+            task_queue,
+            api,
+            poll_timeout_secs: settings.poll_timeout_secs,
+            busy_poll_timeout_secs: settings.busy_poll_timeout_secs,
+            poll_interval: Duration::from_millis(settings.poll_interval_ms),
+            request_timeout_secs: settings.request_timeout_secs,
+            offset: 0,
+            offset_loaded: false,
+            pending_tasks: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            me: None,
+            metrics,
+            retry,
+            memory_guard,
+            queue_guard,
+            runtime,
+            templates,
+        })
+    }
+
+    /// Redis key `name` of the bot
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.namespace, name)
+    }
+
+    /// Redis key of message `message_id` of `chat_id` under `prefix`
+    fn message_key(&self, prefix: &str, chat_id: i64, message_id: i64) -> String {
+        format!("{}{}{}:{}", self.namespace, prefix, chat_id, message_id)
+    }
+
+    /// Fetch the bot's own user record from Telegram
+    async fn get_me(&self) -> anyhow::Result<User> {
+        let url = format!("{}getMe", self.get_base_url());
+        let response: GetMeResponse = self
+            .api
+            .http
+            .get(&url)
+            .send()
+            .instrument(telemetry::telegram_span("getMe"))
+            .await?
+            .json()
+            .await?;
+
+        match response.result {
+            Some(user) if response.ok => Ok(user),
+            _ => Err(anyhow::anyhow!("Telegram getMe failed")),
+        }
+    }
+
+    /// Get updates from Telegram API
+    async fn get_updates(&self) -> anyhow::Result<Vec<Update>> {
+        let awaiting = !self.pending_tasks.lock().await.is_empty();
+        let poll_secs =
+            long_poll_secs(self.poll_timeout_secs, self.busy_poll_timeout_secs, awaiting);
+        let url = format!(
+            "{}getUpdates?offset={}&timeout={}",
+            self.get_base_url(),
+            self.offset,
+            poll_secs
+        );
+
+        debug!("Calling Telegram API: {}", &url);
+
+        // The long poll holds the request open past the usual timeout
+        let timeout = Duration::from_secs(poll_secs + self.request_timeout_secs);
+        let response = self
+            .api
+            .http
+            .get(&url)
+            .timeout(timeout)
+            .send()
+            .instrument(telemetry::telegram_span("getUpdates"))
+            .await?;
+
+        // Get response status and body for debugging
+        let status = response.status();
+        let body_text = response.text().await?;
+
+        debug!("Telegram API response status: {}", status);
+        self.api.observe_status(status);
+        debug!("Telegram API response body: {}", body_text);
+
+        let updates: TelegramUpdates = serde_json::from_str(&body_text)?;
+
+        if let Some(retry_after) = updates.parameters.and_then(|p| p.retry_after) {
+            return Err(RateLimited {
+                retry_after: Duration::from_secs(retry_after),
+            }
+            .into());
+        }
+
+        if !updates.ok {
+            return Err(anyhow::anyhow!("Telegram API returned ok=false: {}", body_text));
+        }
+
+        Ok(updates.result)
+    }
+
+    /// Get the base URL for Telegram API
+    fn get_base_url(&self) -> &str {
+        &self.api.base_url
+    }
+
+    /// Whether the memory guard and queue backpressure accept a new task.
+    /// Telegram tasks are never priority.
+    fn admits_task(&self) -> bool {
+        self.memory_guard.admits(false) && self.queue_guard.admits(false)
+    }
+
+    /// Whether this is the first delivery of a message, remembering it if
+    /// so. Updates are redelivered when the offset could not be stored; if
+    /// Redis cannot tell, the message is taken as new.
+    async fn first_delivery(&self, message: &Message) -> bool {
+        let key = self.message_key(SEEN_PREFIX, message.chat.id, message.message_id);
+        let marked = async {
+            let mut conn = self.connection().await?;
+            anyhow::Ok(mark_seen(&mut conn, &key).await?)
+        };
+        marked.await.unwrap_or_else(|e| {
+            warn!("Failed to check message {} for redelivery: {}", key, e);
+            true
+        })
+    }
+
+    /// Whether a chat already has as many tasks awaiting an answer as it may
+    async fn chat_busy(&self, chat_id: i64) -> bool {
+        let limit = self.runtime.current().telegram.max_in_flight_per_chat;
+        limit > 0 && in_flight(&*self.pending_tasks.lock().await, chat_id) >= limit
+    }
+
+    /// Work out the task input for a message, or `None` if it should be
+    /// ignored: its text, or a structured input for a shared location or
+    /// contact, which groups only send the bot in reply to it
+    fn task_input(&self, message: &Message) -> Option<Value> {
+        let shared = match (&message.location, &message.contact) {
+            (Some(location), _) => Some(location.input()),
+            (None, Some(contact)) => Some(contact.input()),
+            (None, None) => None,
+        };
+        match shared {
+            Some(input) => {
+                (!is_group_chat(&message.chat) || self.replied_to_bot(message)).then_some(input)
+            }
+            None => self.text_input(message).map(Value::String),
+        }
+    }
+
+    /// Whether a message replies to one of the bot's own
+    fn replied_to_bot(&self, message: &Message) -> bool {
+        self.me.as_ref().is_some_and(|me| replies_to(message, me))
+    }
+
+    /// Work out the text input for a message, or `None` if it should be ignored.
+    ///
+    /// Private chats always produce a task. In groups the bot only reacts when
+    /// it is mentioned or when the message replies to one of its own messages;
+    /// see [`group_text`].
+    fn text_input(&self, message: &Message) -> Option<String> {
+        // Voice notes are taken in private chats, with their caption
+        if message.voice.is_some() {
+            return (!is_group_chat(&message.chat)).then(|| message.caption.clone());
+        }
+        if !is_group_chat(&message.chat) {
+            return Some(message.text.clone());
+        }
+
+        group_text(message, self.me.as_ref()?)
+    }
+
+    /// The bot command a message starts with, as its name and arguments;
+    /// commands addressed to other bots in a group are not ours
+    fn command(&self, message: &Message) -> Option<(String, String)> {
+        let entity = message
+            .entities
+            .iter()
+            .find(|e| e.entity_type == "bot_command" && e.offset == 0)?;
+        let command = entity_text(&message.text, entity)?;
+        let (name, bot) = match command[1..].split_once('@') {
+            Some((name, bot)) => (name, Some(bot)),
+            None => (&command[1..], None),
+        };
+        if let Some(bot) = bot {
+            let me = self.me.as_ref()?;
+            if !bot.eq_ignore_ascii_case(&me.username) {
+                return None;
+            }
+        }
+        let args = utf16_slice(&message.text, entity.length, usize::MAX).unwrap_or_default();
+        Some((name.to_ascii_lowercase(), args.trim().to_string()))
+    }
+
+    /// The reply to a command the adaptor answers itself; other commands go
+    /// to the agent like any message
+    async fn run_command(&self, message: &Message, name: &str, args: &str) -> Option<String> {
+        match name {
+            "start" | "help" => {
+                let language = self.chat_language(message).await;
+                Some(i18n::text(language, "help").to_string())
+            }
+            "language" => Some(self.set_language(message, args).await),
+            "voice" => Some(self.set_voice(message, args).await),
+            _ => None,
+        }
+    }
+
+    /// Show or change the reply language of a chat
+    async fn set_language(&self, message: &Message, code: &str) -> String {
+        let available = i18n::languages().join(", ");
+        let chosen = match code.to_ascii_lowercase().as_str() {
+            "" => None,
+            "auto" => Some(None),
+            code => match i18n::resolve(code) {
+                Some(language) => Some(Some(language)),
+                None => {
+                    let language = self.chat_language(message).await;
+                    return i18n::format(
+                        language,
+                        "language_unknown",
+                        &[("code", code), ("available", &available)],
+                    );
+                }
+            },
+        };
+
+        if let Some(chosen) = chosen {
+            let stored = async {
+                let mut conn = self.connection().await?;
+                let chat = message.chat.id.to_string();
+                match chosen {
+                    Some(language) => {
+                        conn.hset::<_, _, _, ()>(self.key(LANGUAGES_KEY), chat, language)
+                            .await?
+                    }
+                    None => conn.hdel::<_, _, ()>(self.key(LANGUAGES_KEY), chat).await?,
+                }
+                anyhow::Ok(())
+            };
+            if let Err(e) = stored.await {
+                warn!("Failed to store the language of chat {}: {}", message.chat.id, e);
+            }
+        }
+
+        let language = self.chat_language(message).await;
+        let name = i18n::text(language, "name");
+        match chosen {
+            Some(_) => i18n::format(language, "language_set", &[("language", name)]),
+            None => i18n::format(
+                language,
+                "language_current",
+                &[("language", name), ("available", &available)],
+            ),
+        }
+    }
+
+    /// Show or change whether a chat's replies are spoken
+    async fn set_voice(&self, message: &Message, choice: &str) -> String {
+        let language = self.chat_language(message).await;
+        if self.api.tts.is_none() {
+            return i18n::text(language, "voice_unavailable").to_string();
         }
+        let chosen = match choice.to_ascii_lowercase().as_str() {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+        if let Some(on) = chosen {
+            let stored = async {
+                let mut conn = self.connection().await?;
+                let chat = message.chat.id.to_string();
+                if on {
+                    conn.sadd::<_, _, ()>(self.key(VOICE_KEY), chat).await?;
+                } else {
+                    conn.srem::<_, _, ()>(self.key(VOICE_KEY), chat).await?;
+                }
+                anyhow::Ok(())
+            };
+            if let Err(e) = stored.await {
+                warn!("Failed to store the voice setting of chat {}: {}", message.chat.id, e);
+            }
+        }
+        let on = self.chat_voice(message.chat.id).await;
+        i18n::text(language, if on { "voice_on" } else { "voice_off" }).to_string()
+    }
+
+    /// Note that the bot has heard from a chat, so broadcasts reach it
+    async fn remember_chat(&self, chat: &Chat) {
+        let stored = async {
+            let mut conn = self.connection().await?;
+            let key = self.key(CHATS_KEY);
+            conn.hset::<_, _, _, ()>(key, chat.id, &chat.chat_type).await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = stored.await {
+            warn!("Failed to remember chat {}: {}", chat.id, e);
+        }
+    }
+
+    /// Whether a chat turned on voice replies
+    async fn chat_voice(&self, chat_id: i64) -> bool {
+        let on = async {
+            let mut conn = self.connection().await?;
+            let on: bool = conn.sismember(self.key(VOICE_KEY), chat_id.to_string()).await?;
+            anyhow::Ok(on)
+        };
+        on.await.unwrap_or_else(|e| {
+            warn!("Failed to read the voice setting of chat {}: {}", chat_id, e);
+            false
+        })
+    }
+
+    /// The language to answer a message in: the chat's choice, else the
+    /// sender's, else the default
+    async fn chat_language(&self, message: &Message) -> &'static str {
+        let chosen = async {
+            let mut conn = self.connection().await?;
+            let chosen: Option<String> =
+                conn.hget(self.key(LANGUAGES_KEY), message.chat.id.to_string()).await?;
+            anyhow::Ok(chosen)
+        };
+        let chosen = chosen.await.unwrap_or_else(|e| {
+            warn!("Failed to read the language of chat {}: {}", message.chat.id, e);
+            None
+        });
+        chosen
+            .as_deref()
+            .or(message.from.as_ref().and_then(|u| u.language_code.as_deref()))
+            .and_then(i18n::resolve)
+            .unwrap_or(i18n::DEFAULT_LANGUAGE)
+    }
+
+    /// Answer a message straight away, threading the answer in groups
+    async fn reply(&self, message: &Message, text: String) {
+        let reply_to = is_group_chat(&message.chat).then_some(message.message_id);
+        if let Err(e) = self.api.send_message(message.chat.id, text, reply_to).await {
+            warn!("Failed to reply to chat {}: {}", message.chat.id, e);
+        }
+    }
+
+    /// Create task in Redis for agent processing
+    async fn create_task(
+        &self,
+        message: &Message,
+        input: Value,
+    ) -> anyhow::Result<String> {
+        let task_id = Uuid::new_v4().to_string();
+        let request_id = request_id::current().unwrap_or_else(RequestId::generate);
+        let language = self.chat_language(message).await;
+        // A voice note is answered in kind
+        let voice = self.api.tts.is_some()
+            && (message.voice.is_some() || self.chat_voice(message.chat.id).await);
+
+        // Store pending task info
+        let pending = PendingTask {
+            chat_id: message.chat.id,
+            message_id: message.message_id,
+            reply_to: is_group_chat(&message.chat).then_some(message.message_id),
+            request_id: request_id.clone(),
+            language,
+            voice,
+            attempts: 0,
+            retry_at: None,
+        };
+        self.pending_tasks
+            .lock()
+            .await
+            .insert(task_id.clone(), pending);
+
+        // Create task in Redis with Telegram metadata
+        let timeout_secs = self.runtime.current().watchdog.default_timeout_secs;
+        let deadline = watchdog::deadline(timeout_secs);
+        let mut task = serde_json::json!({
+            "input": input,
+            "config": {
+                "telegram_chat_id": message.chat.id,
+                "telegram_message_id": message.message_id,
+                "telegram_user_id": message.from.as_ref().map(|u| u.id),
+                "telegram_username": message.from.as_ref().map(|u| u.username.clone()).filter(|s| !s.is_empty()),
+                "telegram_language": language,
+                "telegram_voice_file_id": message.voice.as_ref().map(|v| v.file_id.clone()),
+                "telegram_bot": self.bot.name,
+            },
+            "status": "pending",
+            "timeout_secs": timeout_secs,
+            "deadline": deadline.to_rfc3339(),
+            "request_id": request_id.0,
+            "created_at": chrono::Utc::now().to_rfc3339(),
+            "schema_version": schema::CURRENT_VERSION,
+        });
+
+        if let Some(capability) = &self.bot.capability {
+            task["capability"] = capability.as_str().into();
+        }
+
+        redaction::input("telegram", &mut task["input"]);
+
+        // Follow-ups go to the agent holding the conversation's context
+        let affinity_ttl_secs = self.runtime.current().telegram.affinity_ttl_secs;
+        let conversation = format!("{}{}", self.namespace, message.chat.id);
+        let affinity =
+            (affinity_ttl_secs > 0).then(|| agent_registry::affinity_token(&conversation));
+        if let Some(token) = &affinity {
+            task["affinity"] = serde_json::json!({"token": token, "ttl_secs": affinity_ttl_secs});
+        }
+
+        let mut conn = self.redis_client.get_async_connection().await?;
+        agent_config::apply_defaults(&mut conn, "telegram", &mut task["config"]).await?;
+
+        // A repeat of a recently answered question in the same chat is
+        // delivered from the earlier result by the response loop
+        let dedupe_window = self.runtime.current().dedupe_window();
+        let fingerprint = dedupe_window.map(|_| {
+            let scope = format!("{}{}", self.namespace, message.chat.id);
+            dedupe::fingerprint(&scope, &task["input"], &serde_json::json!({}))
+        });
+        if let Some(fingerprint) = &fingerprint {
+            let completed = dedupe::find_completed(&mut conn, fingerprint).await?;
+            if let Some((original, result)) = completed {
+                let mut task = task;
+                task["deduplicated_from"] = original.as_str().into();
+                dedupe::store_completed(&mut conn, &task_id, task, &result).await?;
+                history::track(&mut conn, &task_id).await?;
+                events::track(&mut conn, &task_id).await?;
+                self.metrics.tasks_created.inc(&message.chat.id.to_string());
+                self.metrics.tasks_deduplicated.inc();
+                info!("Task {} answered from duplicate task {}", task_id, original);
+                return Ok(task_id);
+            }
+        }
+
+        // Store the task and push it to the agent queue
+        let queue = match &affinity {
+            Some(token) => agent_registry::affinity_queue(&mut conn, token, &self.queue).await?,
+            None => self.queue.clone(),
+        };
+        let new_task = NewTask {
+            task_id: &task_id,
+            record: envelope::encode(&task)?,
+            labels: &BTreeMap::new(),
+            queue: Some((&queue, deadline)),
+            tracked: true,
+        };
+        self.task_queue.submit(&mut conn, new_task).await?;
+        if let (Some(fingerprint), Some(window)) = (&fingerprint, dedupe_window) {
+            dedupe::remember(&mut conn, fingerprint, &task_id, window).await?;
+        }
+
+        self.metrics.tasks_created.inc(&message.chat.id.to_string());
+        info!("Created task {} for Telegram chat {}", task_id, message.chat.id);
+
+        Ok(task_id)
+    }
+
+    /// Redis connection shared by the response loop, reconnecting on its own
+    async fn connection(&self) -> anyhow::Result<ConnectionManager> {
+        let conn = self
+            .connection
+            .get_or_try_init(|| {
+                self.retry.redis.run("Redis connect", || {
+                    ConnectionManager::new(self.redis_client.as_ref().clone())
+                })
+            })
+            .await?;
+        Ok(conn.clone())
+    }
+
+    /// Send the replies of pending tasks that have settled.
+    ///
+    /// Results and records are fetched for every pending task in one `MGET`
+    /// each; the records tell failures, timeouts and answers that came after
+    /// the task's deadline, which nobody is waiting for any more. The replies
+    /// then go out concurrently, at most [`MAX_CONCURRENT_SENDS`] at a time.
+    /// Results without text are shown as JSON, and a task that completed
+    /// without any result gets a note saying so. A record or result that
+    /// cannot be read is logged and its task dropped, so it cannot hold up
+    /// the others. Replies
+    /// that failed to go out wait out a backoff before their next pass.
+    async fn check_and_send_responses(&self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let task_ids: Vec<String> = self
+            .pending_tasks
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, task)| task.retry_at.is_none_or(|at| at <= now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if task_ids.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.connection().await?;
+
+        // Poll the replica when there is one; a task it has not caught up on
+        // yet is still pending for this pass
+        let mut settled = None;
+        if let Some(mut replica) = self.replica.connection().await {
+            match read_settled(&mut replica, &task_ids).await {
+                Ok(read) => {
+                    self.replica.hit();
+                    settled = Some(read);
+                }
+                Err(e) => {
+                    warn!("Redis replica poll failed: {}", e);
+                    self.replica.miss("the Telegram result poll");
+                }
+            }
+        }
+        let (results, tasks) = match settled {
+            Some(read) => read,
+            None => read_settled(&mut conn, &task_ids).await?,
+        };
+
+        let mut replies = Vec::new();
+        let mut expired = Vec::new();
+        let feedback_buttons = self.runtime.current().telegram.feedback_buttons;
+        let languages: HashMap<String, &'static str> = self
+            .pending_tasks
+            .lock()
+            .await
+            .iter()
+            .map(|(id, task)| (id.clone(), task.language))
+            .collect();
+        for ((task_id, result), task) in task_ids.into_iter().zip(results).zip(tasks) {
+            let language = languages.get(&task_id).copied().unwrap_or(i18n::DEFAULT_LANGUAGE);
+            let task = match task.map(|t| schema::decode_task(&t)).transpose() {
+                Ok(task) => task.unwrap_or_default(),
+                Err(e) => {
+                    warn!("Dropping task {}: unreadable task record: {}", task_id, e);
+                    self.pending_tasks.lock().await.remove(&task_id);
+                    continue;
+                }
+            };
+            let late = watchdog::past_deadline(&task);
+            match (result, task["status"].as_str()) {
+                // Nobody wants an answer to a deleted task
+                (_, Some("deleted")) => {
+                    self.pending_tasks.lock().await.remove(&task_id);
+                }
+                (Some(_), _) | (None, Some("failed")) if late => {
+                    info!("Task {} settled after its deadline, reply dropped", task_id);
+                    self.pending_tasks.lock().await.remove(&task_id);
+                    expired.push(task_id);
+                }
+                (Some(result), _) => {
+                    let reply = envelope::decode(&result).map(|r| {
+                        match self.templates.reply("telegram", &task_id, &r) {
+                            Some(text) => Some(Reply::text(text)),
+                            None => Reply::structured(&r["result"]),
+                        }
+                    });
+                    match reply {
+                        Ok(Some(reply)) if feedback_buttons => {
+                            let reply = reply.rated(&task_id);
+                            replies.push((task_id, reply));
+                        }
+                        Ok(Some(reply)) => replies.push((task_id, reply)),
+                        Ok(None) => {
+                            let text = i18n::text(language, "no_result").to_string();
+                            replies.push((task_id, Reply::text(text)));
+                        }
+                        Err(e) => {
+                            warn!("Dropping task {}: unreadable result: {}", task_id, e);
+                            self.pending_tasks.lock().await.remove(&task_id);
+                        }
+                    }
+                }
+                // Get an apology out for tasks that failed or the watchdog
+                // gave up on
+                (None, Some("failed")) => {
+                    let text = i18n::text(language, "failed").to_string();
+                    replies.push((task_id, Reply::text(text)));
+                }
+                (None, Some("timed_out")) => {
+                    let text = i18n::text(language, "timed_out").to_string();
+                    replies.push((task_id, Reply::text(text)));
+                }
+                _ => {}
+            }
+        }
+
+        let mut sends = JoinSet::new();
+        let mut delivered = expired;
+        for (task_id, reply) in replies {
+            let Some(task) = self.pending_tasks.lock().await.remove(&task_id) else {
+                continue;
+            };
+            if sends.len() >= MAX_CONCURRENT_SENDS {
+                if let Some(outcome) = sends.join_next().await {
+                    delivered.extend(self.settle_send(outcome?).await);
+                }
+            }
+            let api = self.api.clone();
+            let retry = self.retry.telegram.clone();
+            sends.spawn(async move {
+                // Send response to Telegram under the originating request ID,
+                // spoken if the chat wants it and as text if that fails
+                let send = async {
+                    if task.voice && !reply.code {
+                        let spoken = api
+                            .send_voice(task.chat_id, &reply.text, task.language, task.reply_to)
+                            .await;
+                        match spoken {
+                            Ok(()) => return Ok(()),
+                            Err(e) => warn!(
+                                "Voice reply to chat {} failed, sending text: {}",
+                                task.chat_id, e
+                            ),
+                        }
+                    }
+                    retry
+                        .run_classified(
+                            "Telegram sendMessage",
+                            || api.send_reply(task.chat_id, &reply, task.reply_to),
+                            classify_error,
+                        )
+                        .await
+                };
+                let sent = request_id::scope(task.request_id.clone(), send).await;
+                (task_id, task, sent)
+            });
+        }
+        while let Some(outcome) = sends.join_next().await {
+            delivered.extend(self.settle_send(outcome?).await);
+        }
+
+        // Clean up delivered and expired results and error reports from Redis
+        if !delivered.is_empty() {
+            let dedupe_window = self.runtime.current().dedupe_window();
+            let cleanup = delivered_cleanup(&delivered, dedupe_window);
+            let _: Result<(), _> = cleanup.query_async(&mut conn).await;
+        }
+
+        Ok(())
+    }
+
+    /// Record how a send went, returning the task id once its reply is out.
+    /// Failed tasks go back to the pending map to be tried again after a
+    /// backoff, until the chat refuses them for good or they run out of
+    /// attempts and are listed as undeliverable.
+    async fn settle_send(
+        &self,
+        (task_id, mut task, sent): (String, PendingTask, anyhow::Result<()>),
+    ) -> Option<String> {
+        let e = match sent {
+            Ok(()) => {
+                info!("Sent response to Telegram chat {}", task.chat_id);
+                return Some(task_id);
+            }
+            Err(e) => e,
+        };
+        self.metrics.send_failures.inc();
+        task.attempts += 1;
+        let permanent = e.downcast_ref::<Refused>().is_some_and(Refused::is_permanent);
+        let max_attempts = self.runtime.current().telegram.max_delivery_attempts;
+        if permanent || task.attempts >= max_attempts {
+            error!(
+                "Giving up on the reply to task {} after {} attempts: {}",
+                task_id, task.attempts, e
+            );
+            self.give_up(&task_id, &task, &e, permanent).await;
+            return None;
+        }
+        error!("Failed to send message to Telegram: {}", e);
+        task.retry_at = Some(Instant::now() + self.retry.telegram.delay_for(task.attempts));
+        self.pending_tasks.lock().await.insert(task_id, task);
+        None
+    }
+
+    /// List a reply that cannot be delivered for the admin API, keeping its
+    /// result in Redis
+    async fn give_up(&self, task_id: &str, task: &PendingTask, e: &anyhow::Error, permanent: bool) {
+        self.metrics.undeliverable.inc();
+        let record = serde_json::json!({
+            "task_id": task_id,
+            "bot": self.bot.name,
+            "chat_id": task.chat_id,
+            "attempts": task.attempts,
+            "permanent": permanent,
+            "error": e.to_string(),
+            "failed_at": chrono::Utc::now().to_rfc3339(),
+        });
+        let stored = async {
+            let mut conn = self.connection().await?;
+            conn.hset::<_, _, _, ()>(UNDELIVERABLE_KEY, task_id, record.to_string())
+                .await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = stored.await {
+            warn!("Failed to list the reply to task {} as undeliverable: {}", task_id, e);
+        }
+    }
+
+    /// Run the adaptor loop
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        info!("Telegram adaptor started for bot {}", self.bot.label());
+
+        let metrics = self.metrics.clone();
+        let breaker = &metrics.breaker;
+        let mut failures = 0u32;
+
+        loop {
+            // While the circuit is open, hold off until the probe window
+            if breaker.state() == CircuitState::Open {
+                tokio::time::sleep(breaker.remaining_cooldown()).await;
+                continue;
+            }
+
+            let started = Instant::now();
+            let outcome = self.run_once().await;
+            self.record_iteration(started).await;
+
+            let delay = match outcome {
+                // The long poll already waited for updates: poll again
+                // straight away after some arrived, or after the pause
+                Ok(received) => {
+                    failures = 0;
+                    breaker.record_success();
+                    if received || self.poll_interval.is_zero() {
+                        continue;
+                    }
+                    self.poll_interval
+                }
+                Err(e) => {
+                    failures += 1;
+                    self.metrics.loop_errors.inc();
+                    error!("Error in Telegram adaptor loop: {}", e);
+                    if breaker.record_failure() {
+                        error!(
+                            "Telegram circuit opened after {} consecutive failures",
+                            failures
+                        );
+                    }
+                    match e.downcast_ref::<RateLimited>() {
+                        Some(limited) => limited.retry_after,
+                        None => self.retry.telegram.delay_for(failures),
+                    }
+                }
+            };
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Update loop timing and pending-map metrics after an iteration
+    async fn record_iteration(&self, started: Instant) {
+        let pending = self.pending_tasks.lock().await.len();
+        self.metrics.pending_tasks.set(pending as i64);
+        self.metrics
+            .last_loop_duration_ms
+            .set(started.elapsed().as_millis() as i64);
+        self.metrics.last_loop_at.set(chrono::Utc::now().timestamp());
+    }
+
+    /// Restore the persisted offset so a restart does not replay old updates
+    async fn load_offset(&mut self) -> anyhow::Result<()> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let stored: Option<i64> = conn.get(self.key(OFFSET_KEY)).await?;
+        if let Some(stored) = stored {
+            self.offset = self.offset.max(stored);
+        }
+        self.offset_loaded = true;
+        info!("Telegram bot {} offset restored at {}", self.bot.label(), self.offset);
+        Ok(())
+    }
+
+    /// Persist the current offset, adopting the stored one if it is further ahead
+    async fn persist_offset(&mut self) -> anyhow::Result<()> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let effective: i64 = redis::Script::new(ADVANCE_OFFSET_SCRIPT)
+            .key(self.key(OFFSET_KEY))
+            .arg(self.offset)
+            .invoke_async(&mut conn)
+            .await?;
+        if effective > self.offset {
+            warn!(
+                "Stored Telegram offset {} is ahead of local {}, adopting it",
+                effective, self.offset
+            );
+            self.offset = effective;
+        }
+        Ok(())
+    }
+
+    /// Run one iteration of the adaptor loop
+    async fn run_once(&mut self) -> anyhow::Result<bool> {
+        // Never poll before the persisted offset is known, or we would
+        // re-deliver updates that were processed before a restart
+        if !self.offset_loaded {
+            self.load_offset().await?;
+        }
+
+        // Learn our own identity so group mentions can be recognised
+        if self.me.is_none() {
+            match self.get_me().await {
+                Ok(me) => {
+                    info!("Telegram bot identity: @{}", me.username);
+                    self.me = Some(me);
+                }
+                Err(e) => warn!("Failed to fetch bot identity: {}", e),
+            }
+        }
+
+        // Check for agent responses and send to Telegram
+        if let Err(e) = self.check_and_send_responses().await {
+            warn!("Failed to check responses: {}", e);
+        }
+
+        // Get updates from Telegram
+        let updates = self.get_updates().await?;
+
+        if updates.is_empty() {
+            debug!("No new Telegram messages");
+            return Ok(false);
+        }
+
+        info!("Received {} Telegram updates", updates.len());
+        self.metrics.updates_received.add(updates.len() as u64);
+
+        for update in updates {
+            // Update offset to mark this update as processed
+            self.offset = update.update_id + 1;
+
+            if let Some(message) = update.message.filter(|m| self.accepts(m)) {
+                self.handle_message(message).await;
+            }
+            if let Some(message) = update.edited_message.filter(|m| self.accepts(m)) {
+                self.handle_edit(message).await;
+            }
+            if let Some(query) = update.callback_query {
+                self.handle_callback(query).await;
+            }
+        }
+
+        if let Err(e) = self.persist_offset().await {
+            warn!("Failed to persist Telegram offset: {}", e);
+        }
+
+        Ok(true)
+    }
+
+    /// Whether a message has content and is from a chat the bot serves
+    fn accepts(&self, message: &Message) -> bool {
+        message.has_content() && self.accepts_chat(message)
+    }
+
+    /// Whether a message is from a chat the bot serves
+    fn accepts_chat(&self, message: &Message) -> bool {
+        let allowed = self.runtime.current().telegram_chat_allowed(message.chat.id);
+        if !allowed {
+            debug!("Ignoring message from chat {} outside the allowlist", message.chat.id);
+        }
+        allowed
+    }
+
+    /// Answer a command or turn a new message into a task
+    async fn handle_message(&self, message: Message) {
+        if !self.first_delivery(&message).await {
+            info!(
+                "Skipping message {} in chat {}, already handled",
+                message.message_id, message.chat.id
+            );
+            self.metrics.duplicates_suppressed.inc();
+            return;
+        }
+        self.remember_chat(&message.chat).await;
+
+        if let Some((name, args)) = self.command(&message) {
+            if let Some(reply) = self.run_command(&message, &name, &args).await {
+                self.reply(&message, reply).await;
+                return;
+            }
+        }
+
+        match self.task_input(&message) {
+            Some(input) => self.submit(&message, input).await,
+            None => debug!(
+                "Ignoring unaddressed group message in chat {}",
+                message.chat.id
+            ),
+        }
+    }
+
+    /// Create the task of a message, unless new work is refused or the chat
+    /// must wait for its earlier questions
+    async fn submit(&self, message: &Message, input: Value) {
+        // Tell the user to retry later while new work is refused
+        if !self.admits_task() {
+            let language = self.chat_language(message).await;
+            self.reply(message, i18n::text(language, "overloaded").to_string()).await;
+            return;
+        }
+        // One question at a time rather than a pile of redundant tasks from
+        // a user repeating themselves
+        if self.chat_busy(message.chat.id).await {
+            let language = self.chat_language(message).await;
+            self.reply(message, i18n::text(language, "busy").to_string()).await;
+            return;
+        }
+        let notice = maintenance::current();
+        if let Some(notice) = notice.as_ref().filter(|notice| !notice.hold) {
+            let language = self.chat_language(message).await;
+            let args = [("message", notice.message.as_str())];
+            self.reply(message, i18n::format(language, "maintenance", &args)).await;
+            return;
+        }
+        // Create task for agent processing, traced like an HTTP request
+        let task = self.create_task(message, input);
+        if let Err(e) = request_id::scope(RequestId::generate(), task).await {
+            error!("Failed to create task: {}", e);
+            return;
+        }
+        // Held tasks are answered once maintenance is over
+        if let Some(notice) = notice {
+            let language = self.chat_language(message).await;
+            let args = [("message", notice.message.as_str())];
+            let text = i18n::format(language, "maintenance_held", &args);
+            self.reply(message, text).await;
+        }
+    }
+
+    /// Act on an edited message. While the original's task is still queued
+    /// it is withdrawn and the edited text submitted instead; once an agent
+    /// has it, the user is offered a re-run with the edited text. Edits of
+    /// commands are ignored, and so are the updates of a live location.
+    async fn handle_edit(&self, message: Message) {
+        if self.command(&message).is_some() || message.location.is_some() {
+            return;
+        }
+        let Some(input) = self.task_input(&message) else {
+            return;
+        };
+        match self.withdraw_pending(&message).await {
+            Ok(true) => {
+                info!(
+                    "Resubmitting edited message {} in chat {}",
+                    message.message_id, message.chat.id
+                );
+                self.submit(&message, input).await;
+            }
+            Ok(false) => self.offer_rerun(&message).await,
+            Err(e) => warn!(
+                "Failed to withdraw the task of edited message {} in chat {}: {}",
+                message.message_id, message.chat.id, e
+            ),
+        }
+    }
+
+    /// Delete the task of `message` if no agent has taken it yet, returning
+    /// whether it was
+    async fn withdraw_pending(&self, message: &Message) -> anyhow::Result<bool> {
+        let task_id = self
+            .pending_tasks
+            .lock()
+            .await
+            .iter()
+            .find(|(_, t)| t.chat_id == message.chat.id && t.message_id == message.message_id)
+            .map(|(id, _)| id.clone());
+        let Some(task_id) = task_id else {
+            return Ok(false);
+        };
+
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let withdrawn = task_state::transition(&mut conn, &task_id, "deleted", |task| {
+            if task["status"] != "pending" {
+                return false;
+            }
+            task["deleted_from_status"] = task["status"].take();
+            task["deleted_at"] = chrono::Utc::now().to_rfc3339().into();
+            task["deleted_by"] = "telegram".into();
+            true
+        })
+        .await?;
+        let Some(task) = withdrawn else {
+            return Ok(false);
+        };
+
+        let mut queues = vec![self.queue.clone()];
+        if let Some(token) = task["affinity"]["token"].as_str() {
+            queues.push(agent_registry::affinity_queue(&mut conn, token, &self.queue).await?);
+        }
+        queues.dedup();
+        for queue in &queues {
+            self.task_queue.withdraw(&mut conn, queue, &task_id).await?;
+        }
+        watchdog::untrack(&mut conn, &task_id).await?;
+        history::track(&mut conn, &task_id).await?;
+        events::track(&mut conn, &task_id).await?;
+        self.pending_tasks.lock().await.remove(&task_id);
+        info!("Task {} withdrawn for an edit of its message", task_id);
+        Ok(true)
+    }
+
+    /// Keep an edited message for a while and offer to run it again
+    async fn offer_rerun(&self, message: &Message) {
+        let key = self.message_key(EDITED_PREFIX, message.chat.id, message.message_id);
+        let stored = async {
+            let mut conn = self.connection().await?;
+            let edited = serde_json::to_string(message)?;
+            conn.set_ex::<_, _, ()>(&key, edited, EDITED_TTL_SECS).await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = stored.await {
+            warn!("Failed to keep edited message {}: {}", key, e);
+            return;
+        }
+
+        let language = self.chat_language(message).await;
+        let text = i18n::text(language, "rerun_offer").to_string();
+        let button = (i18n::text(language, "rerun_button"), rerun_data(message.message_id));
+        let sent = self
+            .api
+            .send_button(message.chat.id, text, Some(message.message_id), button)
+            .await;
+        if let Err(e) = sent {
+            warn!("Failed to offer a re-run in chat {}: {}", message.chat.id, e);
+        }
+    }
+
+    /// Act on a button press: a re-run submits the edited message kept for
+    /// it, if the user pressing it is the one who edited it
+    async fn handle_callback(&self, query: CallbackQuery) {
+        if let Some((rating, task_id)) = query.data.as_deref().and_then(parse_rating) {
+            return self.handle_rating(&query, task_id, rating).await;
+        }
+        let pressed = query.message.as_ref().zip(query.data.as_deref().and_then(parse_rerun));
+        let Some((under, message_id)) = pressed.filter(|(m, _)| self.accepts_chat(m)) else {
+            if let Err(e) = self.api.answer_callback(&query.id, None).await {
+                warn!("Failed to answer a button press: {}", e);
+            }
+            return;
+        };
+
+        let key = self.message_key(EDITED_PREFIX, under.chat.id, message_id);
+        let edited = async {
+            let mut conn = self.connection().await?;
+            let edited: Option<String> = conn.get(&key).await?;
+            let edited = edited.map(|e| serde_json::from_str::<Message>(&e)).transpose()?;
+            let edited = edited.filter(|m| m.from.as_ref().is_some_and(|u| u.id == query.from.id));
+            if edited.is_some() {
+                conn.del::<_, ()>(&key).await?;
+            }
+            anyhow::Ok(edited)
+        };
+        let edited = edited.await.unwrap_or_else(|e| {
+            warn!("Failed to read edited message {}: {}", key, e);
+            None
+        });
+
+        let answer = match edited {
+            Some(message) => {
+                if let Some(input) = self.task_input(&message) {
+                    self.submit(&message, input).await;
+                }
+                None
+            }
+            None => Some(i18n::text(self.chat_language(under).await, "rerun_expired")),
+        };
+        if let Err(e) = self.api.answer_callback(&query.id, answer).await {
+            warn!("Failed to answer a button press: {}", e);
+        }
+    }
+
+    /// Record a press of 👍 or 👎 under a reply as feedback on its task,
+    /// which only the chat the reply went to may give
+    async fn handle_rating(&self, query: &CallbackQuery, task_id: &str, rating: Rating) {
+        let mut answer = None;
+        if let Some(under) = query.message.as_ref().filter(|m| self.accepts_chat(m)) {
+            let rated_by = format!("telegram:{}", query.from.id);
+            let rated = async {
+                let mut conn = self.redis_client.get_async_connection().await?;
+                let task = feedback::rateable_task(&mut conn, task_id).await?;
+                if task["config"]["telegram_chat_id"] != under.chat.id {
+                    return Err(ApiError::forbidden("Task belongs to another chat"));
+                }
+                let rater = (rated_by.as_str(), "telegram");
+                feedback::rate(&mut conn, task_id, &task, rater, rating, None).await
+            };
+            let key = match rated.await {
+                Ok(_) => "feedback_thanks",
+                Err(e) => {
+                    warn!("Failed to record the rating of task {}: {}", task_id, e);
+                    "feedback_failed"
+                }
+            };
+            answer = Some(i18n::text(self.chat_language(under).await, key));
+        }
+        if let Err(e) = self.api.answer_callback(&query.id, answer).await {
+            warn!("Failed to answer a button press: {}", e);
+        }
+    }
+}
+
+/// Mark `key` seen for [`SEEN_TTL_SECS`], returning whether it was not yet
+async fn mark_seen<C: redis::aio::ConnectionLike + Send>(
+    conn: &mut C,
+    key: &str,
+) -> redis::RedisResult<bool> {
+    let marked: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(SEEN_TTL_SECS)
+        .query_async(conn)
+        .instrument(telemetry::redis_span("SET", key))
+        .await?;
+    Ok(marked.is_some())
+}
+
+/// Results and task records of `task_ids`, in order, in one `MGET` each
+async fn read_settled<C: redis::aio::ConnectionLike + Send>(
+    conn: &mut C,
+    task_ids: &[String],
+) -> redis::RedisResult<(Vec<Option<String>>, Vec<Option<String>>)> {
+    let result_keys: Vec<String> = task_ids.iter().map(|id| format!("result:{}", id)).collect();
+    let results: Vec<Option<String>> = conn
+        .mget(&result_keys)
+        .instrument(telemetry::redis_span("MGET", "result:*"))
+        .await?;
+
+    let task_keys: Vec<String> = task_ids.iter().map(|id| format!("task:{}", id)).collect();
+    let tasks: Vec<Option<String>> = conn
+        .mget(&task_keys)
+        .instrument(telemetry::redis_span("MGET", "task:*"))
+        .await?;
+    Ok((results, tasks))
+}
+
+/// A `multipart/form-data` body of text `fields` and one file, given as
+/// its field, file name, content type and contents
+fn multipart_body(
+    boundary: &str,
+    fields: &[(&str, String)],
+    (field, file_name, content_type, contents): (&str, &str, &str, &[u8]),
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+             Content-Type: {}\r\n\r\n",
+            boundary, field, file_name, content_type
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(contents);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+/// `text` cut to fit in one message
+fn truncated(text: String) -> String {
+    if text.encode_utf16().count() <= MAX_MESSAGE_UNITS {
+        return text;
+    }
+    let mut units = 0;
+    let mut cut: String = text
+        .chars()
+        .take_while(|c| {
+            units += c.len_utf16();
+            units < MAX_MESSAGE_UNITS
+        })
+        .collect();
+    cut.push('…');
+    cut
+}
+
+/// Callback data of the button re-running edited message `message_id`
+fn rerun_data(message_id: i64) -> String {
+    format!("{}{}", RERUN_PREFIX, message_id)
+}
+
+/// The message a re-run button is for
+fn parse_rerun(data: &str) -> Option<i64> {
+    data.strip_prefix(RERUN_PREFIX)?.parse().ok()
+}
+
+/// 👍 and 👎 buttons rating the result of `task_id`
+fn rating_keyboard(task_id: &str) -> Value {
+    let button = |label: &str, rating: Rating| {
+        let data = format!("{}{}:{}", RATING_PREFIX, rating.as_str(), task_id);
+        serde_json::json!({"text": label, "callback_data": data})
+    };
+    serde_json::json!({
+        "inline_keyboard": [[button("👍", Rating::Up), button("👎", Rating::Down)]],
+    })
+}
+
+/// The rating a rating button gives, and the task it is for
+fn parse_rating(data: &str) -> Option<(Rating, &str)> {
+    let (rating, task_id) = data.strip_prefix(RATING_PREFIX)?.split_once(':')?;
+    Some((Rating::parse(rating)?, task_id))
+}
+
+/// Seconds a getUpdates call may wait: the whole long poll, or less while
+/// replies are awaited since results are only looked for between polls
+fn long_poll_secs(poll_timeout_secs: u64, busy_poll_timeout_secs: u64, awaiting: bool) -> u64 {
+    if awaiting {
+        busy_poll_timeout_secs.min(poll_timeout_secs)
+    } else {
+        poll_timeout_secs
+    }
+}
+
+/// Tasks of `chat_id` awaiting an answer
+fn in_flight(pending: &HashMap<String, PendingTask>, chat_id: i64) -> usize {
+    pending.values().filter(|task| task.chat_id == chat_id).count()
+}
+
+/// Retry rate limits after the server-provided delay, permanent refusals
+/// not at all, everything else with backoff
+fn classify_error(e: &anyhow::Error) -> RetryDecision {
+    if e.downcast_ref::<Refused>().is_some_and(Refused::is_permanent) {
+        return RetryDecision::Stop;
+    }
+    match e.downcast_ref::<RateLimited>() {
+        Some(limited) => RetryDecision::After(limited.retry_after),
+        None => RetryDecision::Backoff,
+    }
+}
+
+/// Whether a chat is a group or supergroup
+fn is_group_chat(chat: &Chat) -> bool {
+    matches!(chat.chat_type.as_str(), "group" | "supergroup")
+}
+
+/// Commands dropping the results and error reports of delivered tasks.
+/// Within a dedupe window results expire with it instead, so a repeat of
+/// the question sent after the reply went out is still answered from them.
+fn delivered_cleanup(task_ids: &[String], dedupe_window: Option<Duration>) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    for task_id in task_ids {
+        let result_key = format!("result:{}", task_id);
+        match dedupe_window {
+            Some(window) => pipe.expire(result_key, window.as_secs() as i64).ignore(),
+            None => pipe.del(result_key).ignore(),
+        };
+        pipe.del(failures::error_key(task_id)).ignore();
+    }
+    pipe
+}
+
+/// Whether a message replies to one of `me`'s own
+fn replies_to(message: &Message, me: &User) -> bool {
+    message
+        .reply_to_message
+        .as_ref()
+        .and_then(|m| m.from.as_ref())
+        .is_some_and(|u| u.id == me.id)
+}
+
+/// The text input of a group message for the bot `me`, or `None` unless it
+/// @mentions the bot (or names it in a `text_mention`) or replies to it.
+/// The mention is stripped from the text handed to the agent.
+fn group_text(message: &Message, me: &User) -> Option<String> {
+    let mention = format!("@{}", me.username);
+    let mentioned = message.entities.iter().find(|e| match e.entity_type.as_str() {
+        "mention" => entity_text(&message.text, e)
+            .is_some_and(|text| text.eq_ignore_ascii_case(&mention)),
+        "text_mention" => e.user.as_ref().is_some_and(|u| u.id == me.id),
+        _ => false,
     });
+
+    match mentioned {
+        Some(entity) => {
+            let mut text = utf16_slice(&message.text, 0, entity.offset).unwrap_or_default();
+            text.push_str(
+                &utf16_slice(&message.text, entity.offset + entity.length, usize::MAX)
+                    .unwrap_or_default(),
+            );
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            (!text.is_empty()).then_some(text)
+        }
+        None if replies_to(message, me) => Some(message.text.clone()),
+        None => None,
+    }
+}
+
+/// Text covered by an entity; Telegram offsets count UTF-16 code units
+fn entity_text(text: &str, entity: &MessageEntity) -> Option<String> {
+    utf16_slice(text, entity.offset, entity.offset + entity.length)
+}
+
+/// Slice a string by UTF-16 code unit positions
+fn utf16_slice(text: &str, start: usize, end: usize) -> Option<String> {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let end = end.min(units.len());
+    if start > end {
+        return None;
+    }
+    String::from_utf16(&units[start..end]).ok()
+}
+
+/// Sends broadcasts as a bot, spaced out with the bot's replies
+pub struct Announcer {
+    api: BotApi,
+    retry: RetryPolicy,
+}
+
+impl Announcer {
+    pub fn new(
+        bot: &Bot,
+        settings: &TelegramSettings,
+        retry: RetryPolicy,
+    ) -> reqwest::Result<Self> {
+        let metrics = bot.metrics.clone();
+        let api = BotApi::new(&bot.token, bot.throttle.clone(), settings, metrics)?;
+        Ok(Self { api, retry })
+    }
+
+    pub async fn send(&self, chat_id: i64, text: &str) -> anyhow::Result<()> {
+        self.retry
+            .run_classified(
+                "Telegram broadcast",
+                || self.api.send_message(chat_id, text.to_string(), None),
+                classify_error,
+            )
+            .await
+    }
+}
+
+/// Chats `bot` has heard from, with their types
+pub async fn known_chats(
+    conn: &mut redis::aio::Connection,
+    bot: &Bot,
+) -> redis::RedisResult<Vec<(i64, String)>> {
+    let chats: HashMap<i64, String> = conn.hgetall(bot.namespace() + CHATS_KEY).await?;
+    let mut chats: Vec<(i64, String)> = chats.into_iter().collect();
+    chats.sort();
+    Ok(chats)
+}
+
+/// Replies the adaptor gave up on, oldest first (admin only)
+pub async fn list_undeliverable(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let stored: HashMap<String, String> = conn.hgetall(UNDELIVERABLE_KEY).await?;
+    let mut records: Vec<Value> = stored
+        .values()
+        .filter_map(|record| serde_json::from_str(record).ok())
+        .collect();
+    records.sort_by(|a, b| a["failed_at"].as_str().cmp(&b["failed_at"].as_str()));
+    Ok(Json(serde_json::json!({"undeliverable": records})))
+}
+
+/// Dismiss an undeliverable reply along with its result (admin only)
+pub async fn dismiss_undeliverable(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(task_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let removed: i64 = conn.hdel(UNDELIVERABLE_KEY, &task_id).await?;
+    if removed == 0 {
+        return Err(ApiError::not_found(format!(
+            "No undeliverable reply for task {}",
+            task_id
+        )));
+    }
+    let keys = [format!("result:{}", task_id), failures::error_key(&task_id)];
+    conn.del::<_, ()>(&keys).await?;
+    info!("Undeliverable reply to task {} dismissed by {}", task_id, principal.key_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Start the Telegram adaptor of `bot` in a background task, restarted by
+/// `supervisor` when it crashes
+#[allow(clippy::too_many_arguments)]
+pub fn start_telegram_adaptor(
+    supervisor: &Arc<Supervisor>,
+    redis_client: Arc<Client>,
+    replica: ReadReplica,
+    bot: Bot,
+    retry: RetryPolicies,
+    task_queue: TaskQueue,
+    memory_guard: Arc<MemoryGuard>,
+    queue_guard: Arc<QueueGuard>,
+    runtime: Arc<RuntimeConfig>,
+    templates: ReplyTemplates,
+) {
+    supervisor.supervise(bot.adaptor_name(), move || {
+        let adaptor = TelegramAdaptor::new(
+            redis_client.clone(),
+            replica.clone(),
+            bot.clone(),
+            retry.clone(),
+            task_queue.clone(),
+            memory_guard.clone(),
+            queue_guard.clone(),
+            runtime.clone(),
+            templates.clone(),
+        );
+        let label = bot.label().to_string();
+        async move {
+            let mut adaptor = adaptor.map_err(|e| {
+                anyhow::anyhow!("Failed to build the Bot API client of bot {}: {}", label, e)
+            })?;
+            adaptor.run().await
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::{FakeRedis, FakeTelegram};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn replies_reach_the_bot_api() {
+        let telegram = FakeTelegram::start().await.unwrap();
+        let settings = TelegramSettings {
+            bot_token: Some("123:abc".to_string()),
+            api_base: telegram.api_base().to_string(),
+            ..TelegramSettings::default()
+        };
+        let bot = &Bot::all(&settings)[0];
+        let api = BotApi::new(&bot.token, bot.throttle.clone(), &settings, Arc::default());
+        let api = api.unwrap();
+        api.send_message(42, "hello".to_string(), Some(7))
+            .await
+            .unwrap();
+        assert_eq!(
+            telegram.sent(),
+            [json!({"chat_id": 42, "text": "hello", "reply_to_message_id": 7})]
+        );
+    }
+
+    #[tokio::test]
+    async fn voice_replies_are_spoken() {
+        let telegram = FakeTelegram::start().await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tts_url = format!("http://{}/", listener.local_addr().unwrap());
+        let tts = axum::Router::new().route("/", axum::routing::post(|| async { "OggS" }));
+        tokio::spawn(async move { axum::serve(listener, tts).await });
+
+        let settings = TelegramSettings {
+            api_base: telegram.api_base().to_string(),
+            tts_url: Some(tts_url),
+            bot_token: Some("123:abc".to_string()),
+            ..TelegramSettings::default()
+        };
+        let bot = &Bot::all(&settings)[0];
+        let api = BotApi::new(&bot.token, bot.throttle.clone(), &settings, Arc::default());
+        let api = api.unwrap();
+        api.send_voice(42, "hello", "en", None).await.unwrap();
+        assert_eq!(telegram.calls("sendVoice").len(), 1);
+
+        let fields = [("chat_id", "42".to_string())];
+        let file = ("voice", "r.ogg", "audio/ogg", &b"OggS"[..]);
+        let body = String::from_utf8(multipart_body("b", &fields, file)).unwrap();
+        assert!(body.starts_with("--b\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n"));
+        assert!(body.ends_with("Content-Type: audio/ogg\r\n\r\nOggS\r\n--b--\r\n"));
+    }
+
+    #[test]
+    fn shared_locations_and_contacts_are_structured() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "chat": {"id": 42, "type": "private"},
+            "location": {"latitude": 51.5, "longitude": -0.12},
+        }))
+        .unwrap();
+        assert!(message.has_content());
+        assert_eq!(
+            message.location.unwrap().input(),
+            json!({"type": "location", "lat": 51.5, "lon": -0.12, "accuracy_m": null})
+        );
+
+        let contact: Contact = serde_json::from_value(json!({
+            "phone_number": "+441234567890",
+            "first_name": "Ada",
+            "user_id": 7,
+        }))
+        .unwrap();
+        assert_eq!(contact.input()["type"], "contact");
+        assert_eq!(contact.input()["phone_number"], "+441234567890");
+        assert_eq!(contact.input()["user_id"], 7);
+    }
+
+    #[test]
+    fn structured_results_are_shown_as_json() {
+        let reply = Reply::structured(&json!({"total": 4, "items": [1, 3]})).unwrap();
+        assert!(reply.code);
+        assert_eq!(
+            reply.text,
+            serde_json::to_string_pretty(&json!({"total": 4, "items": [1, 3]})).unwrap()
+        );
+        assert_eq!(Reply::structured(&json!(4)), Some(Reply::text("4".to_string())));
+        assert_eq!(Reply::structured(&Value::Null), None);
+
+        let long = Reply::text("é".repeat(5000));
+        assert_eq!(long.text.encode_utf16().count(), MAX_MESSAGE_UNITS);
+        assert!(long.text.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn redelivered_messages_are_seen_once() {
+        let mut redis = FakeRedis::new();
+        let key = format!("telegram:{}42:7", SEEN_PREFIX);
+        assert!(mark_seen(&mut redis, &key).await.unwrap());
+        assert!(!mark_seen(&mut redis, &key).await.unwrap());
+        assert_eq!(redis.ttl(&key), Some(SEEN_TTL_SECS as i64));
+
+        // Another message, or the same one reaching another bot, is new
+        let next = format!("telegram:{}42:8", SEEN_PREFIX);
+        assert!(mark_seen(&mut redis, &next).await.unwrap());
+        let other_bot = format!("telegram:bots:support:{}42:7", SEEN_PREFIX);
+        assert!(mark_seen(&mut redis, &other_bot).await.unwrap());
+    }
+
+    #[test]
+    fn bots_keep_their_state_apart() {
+        let settings = TelegramSettings {
+            bot_token: Some("1:default".to_string()),
+            bots: vec![crate::config::BotSettings {
+                name: "support".to_string(),
+                token: "2:support".to_string(),
+                capability: Some("support".to_string()),
+            }],
+            ..TelegramSettings::default()
+        };
+        let bots = Bot::all(&settings);
+        let namespaces: Vec<String> = bots.iter().map(Bot::namespace).collect();
+        assert_eq!(namespaces, ["telegram:", "telegram:bots:support:"]);
+        assert_eq!(bots[0].label(), "default");
+        assert_eq!(bots[1].capability.as_deref(), Some("support"));
+        let names: Vec<String> = bots.iter().map(Bot::adaptor_name).collect();
+        assert_eq!(names, ["telegram", "telegram:support"]);
+
+        // A bot whose Bot API calls fail is reported and held off alone
+        for _ in 0..settings.breaker.threshold {
+            bots[1].metrics().breaker.record_failure();
+        }
+        bots[0].metrics().updates_received.inc();
+        let health = snapshots(&bots);
+        assert_eq!(health.keys().collect::<Vec<_>>(), ["default", "support"]);
+        assert_eq!(health["default"].circuit, "closed");
+        assert_eq!(health["default"].updates_received, 1);
+        assert_eq!(health["support"].circuit, "open");
+        assert_eq!(health["support"].updates_received, 0);
+
+        let unnamed = TelegramSettings::default();
+        assert!(Bot::all(&unnamed).is_empty());
+    }
+
+    #[test]
+    fn polls_briefly_while_replies_are_awaited() {
+        assert_eq!(long_poll_secs(30, 1, false), 30);
+        assert_eq!(long_poll_secs(30, 1, true), 1);
+        assert_eq!(long_poll_secs(5, 10, true), 5);
+    }
+
+    #[test]
+    fn counts_in_flight_tasks_by_chat() {
+        let task = |chat_id| PendingTask {
+            chat_id,
+            message_id: 1,
+            reply_to: None,
+            request_id: RequestId::generate(),
+            language: i18n::DEFAULT_LANGUAGE,
+            voice: false,
+            attempts: 0,
+            retry_at: None,
+        };
+        let pending = HashMap::from([
+            ("t1".to_string(), task(42)),
+            ("t2".to_string(), task(42)),
+            ("t3".to_string(), task(7)),
+        ]);
+        assert_eq!(in_flight(&pending, 42), 2);
+        assert_eq!(in_flight(&pending, 7), 1);
+        assert_eq!(in_flight(&pending, 1), 0);
+    }
+
+    #[test]
+    fn blocked_chats_are_not_retried() {
+        let refused = |error_code, description: &str| Refused {
+            method: "sendMessage",
+            error_code: Some(error_code),
+            description: Some(description.to_string()),
+        };
+        let blocked = refused(403, "Forbidden: bot was blocked by the user");
+        assert!(blocked.is_permanent());
+        assert!(matches!(
+            classify_error(&blocked.into()),
+            RetryDecision::Stop
+        ));
+        assert!(refused(400, "Bad Request: chat not found").is_permanent());
+        assert!(!refused(400, "Bad Request: message text is empty").is_permanent());
+        assert!(!refused(502, "Bad Gateway").is_permanent());
+        assert!(matches!(
+            classify_error(&anyhow::anyhow!("connection reset")),
+            RetryDecision::Backoff
+        ));
+    }
+
+    #[test]
+    fn rerun_buttons_name_their_message() {
+        assert_eq!(parse_rerun(&rerun_data(1234)), Some(1234));
+        assert!(rerun_data(i64::MAX).len() <= 64, "callback data is limited to 64 bytes");
+        assert_eq!(parse_rerun("rerun:"), None);
+        assert_eq!(parse_rerun("other:12"), None);
+    }
+
+    #[test]
+    fn rating_buttons_name_their_task() {
+        let task_id = Uuid::new_v4().to_string();
+        let keyboard = rating_keyboard(&task_id);
+        let buttons = keyboard["inline_keyboard"][0].as_array().unwrap();
+        let ratings: Vec<_> = buttons
+            .iter()
+            .map(|b| b["callback_data"].as_str().unwrap())
+            .inspect(|data| assert!(data.len() <= 64, "callback data is limited to 64 bytes"))
+            .filter_map(parse_rating)
+            .collect();
+        assert_eq!(
+            ratings,
+            [(Rating::Up, task_id.as_str()), (Rating::Down, task_id.as_str())]
+        );
+        assert_eq!(parse_rating("rate:meh:abc"), None);
+        assert_eq!(parse_rating(&rerun_data(1)), None);
+    }
+
+    fn bot() -> User {
+        serde_json::from_value(json!({"id": 99, "first_name": "Claw", "username": "claw_bot"}))
+            .unwrap()
+    }
+
+    fn group_message(text: &str, entities: Value) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "chat": {"id": -5, "type": "supergroup"},
+            "text": text,
+            "entities": entities,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn group_messages_need_a_mention() {
+        let me = bot();
+        let plain = group_message("what is the weather", json!([]));
+        assert_eq!(group_text(&plain, &me), None);
+
+        let other = group_message(
+            "@other_bot what is the weather",
+            json!([{"type": "mention", "offset": 0, "length": 10}]),
+        );
+        assert_eq!(group_text(&other, &me), None);
+
+        let mentioned = group_message(
+            "@Claw_Bot what is the weather",
+            json!([{"type": "mention", "offset": 0, "length": 9}]),
+        );
+        assert_eq!(
+            group_text(&mentioned, &me).as_deref(),
+            Some("what is the weather")
+        );
+
+        // A bare mention leaves nothing to do
+        let bare = group_message(
+            "@claw_bot",
+            json!([{"type": "mention", "offset": 0, "length": 9}]),
+        );
+        assert_eq!(group_text(&bare, &me), None);
+    }
+
+    #[test]
+    fn text_mentions_name_the_bot_by_id() {
+        let me = bot();
+        let named = group_message(
+            "Claw summarise this",
+            json!([{
+                "type": "text_mention",
+                "offset": 0,
+                "length": 4,
+                "user": {"id": 99, "first_name": "Claw"},
+            }]),
+        );
+        assert_eq!(group_text(&named, &me).as_deref(), Some("summarise this"));
+
+        let someone = group_message(
+            "Ada summarise this",
+            json!([{
+                "type": "text_mention",
+                "offset": 0,
+                "length": 3,
+                "user": {"id": 7, "first_name": "Ada"},
+            }]),
+        );
+        assert_eq!(group_text(&someone, &me), None);
+    }
+
+    #[test]
+    fn replies_to_the_bot_need_no_mention() {
+        let me = bot();
+        let mut reply = group_message("and tomorrow?", json!([]));
+        reply.reply_to_message = Some(Box::new(group_message("sunny", json!([]))));
+        assert_eq!(group_text(&reply, &me), None);
+        reply.reply_to_message.as_mut().unwrap().from = Some(bot());
+        assert_eq!(group_text(&reply, &me).as_deref(), Some("and tomorrow?"));
+    }
+
+    #[test]
+    fn mention_offsets_count_utf16_units() {
+        let me = bot();
+        // Each emoji takes two UTF-16 code units, so the mention starts at 5
+        let message = group_message(
+            "😀😀 @claw_bot status please",
+            json!([{"type": "mention", "offset": 5, "length": 9}]),
+        );
+        assert_eq!(
+            group_text(&message, &me).as_deref(),
+            Some("😀😀 status please")
+        );
+    }
+
+    #[test]
+    fn utf16_slices_keep_surrogate_pairs_whole() {
+        assert_eq!(utf16_slice("a😀b", 1, 3).as_deref(), Some("😀"));
+        assert_eq!(utf16_slice("a😀b", 3, usize::MAX).as_deref(), Some("b"));
+        // Cutting a surrogate pair in half is no text
+        assert_eq!(utf16_slice("a😀b", 1, 2), None);
+        assert_eq!(utf16_slice("abc", 2, 1), None);
+    }
+
+    #[tokio::test]
+    async fn delivered_results_answer_repeats_within_the_dedupe_window() {
+        let mut redis = FakeRedis::new();
+        let window = Duration::from_secs(600);
+        dedupe::remember(&mut redis, "fp", "t1", window).await.unwrap();
+        redis.set("result:t1", r#"{"result": "42"}"#);
+        redis.set("error:t1", r#"{"error": "stale"}"#);
+
+        // The reply goes out, then the same question is sent again
+        let delivered = ["t1".to_string()];
+        let cleanup = delivered_cleanup(&delivered, Some(window));
+        cleanup.query_async::<_, ()>(&mut redis).await.unwrap();
+        let repeat = dedupe::find_completed(&mut redis, "fp").await.unwrap();
+        assert_eq!(
+            repeat,
+            Some(("t1".to_string(), r#"{"result": "42"}"#.to_string()))
+        );
+        assert_eq!(redis.ttl("result:t1"), Some(600));
+        assert_eq!(redis.get("error:t1"), None);
+
+        // Without deduplication nothing waits for a repeat
+        let cleanup = delivered_cleanup(&delivered, None);
+        cleanup.query_async::<_, ()>(&mut redis).await.unwrap();
+        assert_eq!(redis.get("result:t1"), None);
+    }
+
+    #[tokio::test]
+    async fn settled_tasks_are_read_in_order() {
+        let mut redis = FakeRedis::new();
+        redis.set("result:t2", r#"{"result": "done"}"#);
+        redis.set("task:t1", r#"{"status": "processing"}"#);
+        redis.set("task:t2", r#"{"status": "completed"}"#);
+        let task_ids = ["t1", "t2", "t3"].map(str::to_string);
+        let (results, tasks) = read_settled(&mut redis, &task_ids).await.unwrap();
+        assert_eq!(results, [None, Some(r#"{"result": "done"}"#.to_string()), None]);
+        assert_eq!(
+            tasks,
+            [
+                Some(r#"{"status": "processing"}"#.to_string()),
+                Some(r#"{"status": "completed"}"#.to_string()),
+                None,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn clones_of_the_api_send_concurrently() {
+        let telegram = FakeTelegram::start().await.unwrap();
+        let settings = TelegramSettings {
+            bot_token: Some("123:abc".to_string()),
+            api_base: telegram.api_base().to_string(),
+            ..TelegramSettings::default()
+        };
+        let bot = &Bot::all(&settings)[0];
+        let api = BotApi::new(&bot.token, bot.throttle.clone(), &settings, Arc::default());
+        let api = api.unwrap();
+
+        let mut sends = JoinSet::new();
+        for chat_id in 1..=MAX_CONCURRENT_SENDS as i64 {
+            let api = api.clone();
+            sends.spawn(async move { api.send_message(chat_id, "hi".to_string(), None).await });
+        }
+        while let Some(sent) = sends.join_next().await {
+            sent.unwrap().unwrap();
+        }
+        let mut chats: Vec<i64> = telegram
+            .sent()
+            .iter()
+            .filter_map(|m| m["chat_id"].as_i64())
+            .collect();
+        chats.sort();
+        assert_eq!(chats, (1..=MAX_CONCURRENT_SENDS as i64).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,502 @@
+//! Logging and OpenTelemetry export.
+//!
+//! Logs always go to stdout. When the `otlp` feature is built in and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans and gateway metrics are also
+//! exported over OTLP/HTTP, so a task's latency can be followed across the
+//! HTTP handler, Redis calls and Telegram API calls in Jaeger or Tempo.
+//!
+//! Export is configured with the standard variables: `OTEL_SERVICE_NAME`,
+//! `OTEL_TRACES_SAMPLER_ARG` (head sampling ratio, default 1.0) and
+//! `OTEL_METRIC_EXPORT_INTERVAL` (milliseconds, default 60000).
+//!
+//! The most recent warnings and errors are also kept in memory so they can be
+//! attached to support bundles.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use tracing::{field::Field, Event, Level, Span, Subscriber};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::agent_registry::AgentMetrics;
+use crate::backpressure::QueueGuard;
+use crate::cache::CacheMetrics;
+use crate::canary::CanaryMetrics;
+use crate::concurrency::ConcurrencyMetrics;
+use crate::events::EventMetrics;
+use crate::memory_guard::MemoryGuard;
+use crate::redaction;
+use crate::replica::ReplicaMetrics;
+use crate::telegram::TelegramMetrics;
+use crate::timeouts::TimeoutMetrics;
+use crate::watchdog::WatchdogMetrics;
+
+/// Number of warning and error lines retained for support bundles
+const RECENT_ERRORS_CAPACITY: usize = 200;
+
+static RECENT_ERRORS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Exporter state; flushes pending telemetry when dropped
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    meter_provider: Option<opentelemetry_sdk::metrics::MeterProvider>,
+}
+
+/// Export settings read from the environment
+#[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+struct ExportConfig {
+    endpoint: String,
+    service_name: String,
+    sample_ratio: f64,
+    metric_interval: std::time::Duration,
+}
+
+impl ExportConfig {
+    fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Some(Self {
+            endpoint: var("OTEL_EXPORTER_OTLP_ENDPOINT")?,
+            service_name: var("OTEL_SERVICE_NAME").unwrap_or_else(|| "secure-gateway".to_string()),
+            sample_ratio: var("OTEL_TRACES_SAMPLER_ARG")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1.0_f64)
+                .clamp(0.0, 1.0),
+            metric_interval: std::time::Duration::from_millis(
+                var("OTEL_METRIC_EXPORT_INTERVAL")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(60_000),
+            ),
+        })
+    }
+}
+
+/// Install the global subscriber, adding OTLP export when configured
+pub fn init() -> anyhow::Result<Telemetry> {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "secure_gateway=debug,tower_http=debug".into());
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(redaction::RedactingStdout))
+        .with(RecentErrors);
+    let export = ExportConfig::from_env();
+
+    #[cfg(feature = "otlp")]
+    {
+        let Some(config) = export else {
+            registry.init();
+            return Ok(Telemetry {
+                meter_provider: None,
+            });
+        };
+
+        let tracer = otlp::tracer(&config)?;
+        let meter_provider = otlp::meter_provider(&config)?;
+        registry
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+        tracing::info!(
+            "Exporting telemetry to {} (sampling ratio {})",
+            config.endpoint,
+            config.sample_ratio
+        );
+        Ok(Telemetry {
+            meter_provider: Some(meter_provider),
+        })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        if export.is_some() {
+            tracing::warn!(
+                "OTEL_EXPORTER_OTLP_ENDPOINT is set but the otlp feature is not built in"
+            );
+        }
+        Ok(Telemetry {})
+    }
+}
+
+impl Telemetry {
+    /// Publish gateway counters and gauges as observable OTLP instruments
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+    pub fn register_metrics(
+        &self,
+        memory_guard: Arc<MemoryGuard>,
+        telegram: Vec<(String, Arc<TelegramMetrics>)>,
+        cache: Arc<CacheMetrics>,
+        queue_guard: Arc<QueueGuard>,
+        agents: Arc<AgentMetrics>,
+        watchdog: Arc<WatchdogMetrics>,
+        events: Arc<EventMetrics>,
+        replica: Arc<ReplicaMetrics>,
+        canary: Arc<CanaryMetrics>,
+        timeouts: Arc<TimeoutMetrics>,
+        concurrency: Arc<ConcurrencyMetrics>,
+    ) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = &self.meter_provider {
+            otlp::register_metrics(
+                provider,
+                memory_guard,
+                telegram,
+                cache,
+                queue_guard,
+                agents,
+                watchdog,
+                events,
+                replica,
+                canary,
+                timeouts,
+                concurrency,
+            );
+        }
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.meter_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!("Failed to flush metrics: {}", e);
+            }
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Most recent warning and error log lines, oldest first
+pub fn recent_errors() -> Vec<String> {
+    let recent = RECENT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    recent.iter().cloned().collect()
+}
+
+/// Layer copying warnings and errors into [`RECENT_ERRORS`]
+struct RecentErrors;
+
+impl<S: Subscriber> Layer<S> for RecentErrors {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+
+        let mut line = format!(
+            "{} {} {}:",
+            chrono::Utc::now().to_rfc3339(),
+            metadata.level(),
+            metadata.target()
+        );
+        event.record(&mut LineVisitor(&mut line));
+
+        let line = redaction::log_line(&line).into_owned();
+        let mut recent = RECENT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_ERRORS_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+}
+
+/// Appends an event's message and fields to a log line
+struct LineVisitor<'a>(&'a mut String);
+
+impl tracing::field::Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = if field.name() == "message" {
+            write!(self.0, " {:?}", value)
+        } else {
+            write!(self.0, " {}={:?}", field.name(), value)
+        };
+    }
+}
+
+/// Span for a single Redis command, named after the OpenTelemetry conventions
+pub fn redis_span(operation: &'static str, key: &str) -> Span {
+    tracing::info_span!(
+        "redis",
+        otel.name = operation,
+        db.system = "redis",
+        db.operation = operation,
+        db.redis.key = key,
+    )
+}
+
+/// Span for a Telegram Bot API call
+pub fn telegram_span(method: &'static str) -> Span {
+    tracing::info_span!("telegram", otel.name = method, rpc.method = method)
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::{metrics::MeterProvider as _, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{metrics::MeterProvider, runtime, trace, Resource};
+    use std::sync::Arc;
+
+    use super::ExportConfig;
+    use crate::agent_registry::AgentMetrics;
+    use crate::backpressure::QueueGuard;
+    use crate::cache::CacheMetrics;
+    use crate::canary::CanaryMetrics;
+    use crate::concurrency::ConcurrencyMetrics;
+    use crate::events::EventMetrics;
+    use crate::memory_guard::MemoryGuard;
+    use crate::replica::ReplicaMetrics;
+    use crate::telegram::TelegramMetrics;
+    use crate::timeouts::TimeoutMetrics;
+    use crate::watchdog::WatchdogMetrics;
+
+    fn resource(config: &ExportConfig) -> Resource {
+        Resource::new(vec![
+            KeyValue::new("service.name", config.service_name.clone()),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])
+    }
+
+    pub(super) fn tracer(config: &ExportConfig) -> anyhow::Result<trace::Tracer> {
+        // Respect upstream sampling decisions, sample new traces by ratio
+        let sampler = trace::Sampler::ParentBased(Box::new(trace::Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        )));
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&config.endpoint),
+            )
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(sampler)
+                    .with_resource(resource(config)),
+            )
+            .install_batch(runtime::Tokio)?;
+        Ok(tracer)
+    }
+
+    pub(super) fn meter_provider(config: &ExportConfig) -> anyhow::Result<MeterProvider> {
+        let provider = opentelemetry_otlp::new_pipeline()
+            .metrics(runtime::Tokio)
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&config.endpoint),
+            )
+            .with_resource(resource(config))
+            .with_period(config.metric_interval)
+            .build()?;
+        Ok(provider)
+    }
+
+    /// Reads one Telegram counter for an observable instrument
+    type CounterReading = fn(&TelegramMetrics) -> u64;
+
+    /// Reads one cache counter for an observable instrument
+    type CacheReading = fn(&CacheMetrics) -> u64;
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn register_metrics(
+        provider: &MeterProvider,
+        memory_guard: Arc<MemoryGuard>,
+        telegram: Vec<(String, Arc<TelegramMetrics>)>,
+        cache: Arc<CacheMetrics>,
+        queue_guard: Arc<QueueGuard>,
+        agents: Arc<AgentMetrics>,
+        watchdog: Arc<WatchdogMetrics>,
+        events: Arc<EventMetrics>,
+        replica: Arc<ReplicaMetrics>,
+        canary: Arc<CanaryMetrics>,
+        timeouts: Arc<TimeoutMetrics>,
+        concurrency: Arc<ConcurrencyMetrics>,
+    ) {
+        let meter = provider.meter("secure-gateway");
+
+        let guard = memory_guard.clone();
+        meter
+            .i64_observable_gauge("gateway.redis.memory.used")
+            .with_description("Redis used_memory in bytes")
+            .with_callback(move |obs| obs.observe(guard.used_bytes.get(), &[]))
+            .init();
+        let guard = memory_guard.clone();
+        meter
+            .u64_observable_gauge("gateway.admission.level")
+            .with_description("0 = open, 1 = priority only, 2 = closed")
+            .with_callback(move |obs| obs.observe(guard.level() as u64, &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.admission.rejected")
+            .with_description("Submissions refused by the memory guard")
+            .with_callback(move |obs| obs.observe(memory_guard.rejected.get(), &[]))
+            .init();
+
+        let guard = queue_guard.clone();
+        meter
+            .i64_observable_gauge("gateway.queue.depth")
+            .with_description("Tasks waiting in agent:queue and the capability queues")
+            .with_callback(move |obs| obs.observe(guard.depth.get(), &[]))
+            .init();
+        let guard = queue_guard.clone();
+        meter
+            .i64_observable_gauge("gateway.queue.priority_depth")
+            .with_description("Tasks waiting in the priority lanes")
+            .with_callback(move |obs| obs.observe(guard.priority_depth.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.queue.shed")
+            .with_description("Submissions refused by queue backpressure")
+            .with_callback(move |obs| obs.observe(queue_guard.shed.get(), &[]))
+            .init();
+
+        let registry = agents.clone();
+        meter
+            .i64_observable_gauge("gateway.agents.alive")
+            .with_description("Agents with a live heartbeat")
+            .with_callback(move |obs| obs.observe(registry.alive.get(), &[]))
+            .init();
+        let registry = agents.clone();
+        meter
+            .u64_observable_counter("gateway.agents.orphaned_tasks")
+            .with_description("Tasks orphaned by agents that stopped heartbeating")
+            .with_callback(move |obs| obs.observe(registry.orphaned.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.agents.lapsed_leases")
+            .with_description("Claimed tasks requeued after their lease lapsed")
+            .with_callback(move |obs| obs.observe(agents.leases_lapsed.get(), &[]))
+            .init();
+
+        let metrics = watchdog.clone();
+        meter
+            .u64_observable_counter("gateway.tasks.timed_out")
+            .with_description("Tasks given up on by the timeout watchdog")
+            .with_callback(move |obs| obs.observe(metrics.timed_out.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.tasks.requeued")
+            .with_description("Overdue tasks requeued by the timeout watchdog")
+            .with_callback(move |obs| obs.observe(watchdog.requeued.get(), &[]))
+            .init();
+
+        let metrics = events.clone();
+        meter
+            .u64_observable_counter("gateway.events.delivered")
+            .with_description("Task lifecycle events delivered to Kafka")
+            .with_callback(move |obs| obs.observe(metrics.delivered.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.events.dropped")
+            .with_description("Task lifecycle events dropped after failed deliveries")
+            .with_callback(move |obs| obs.observe(events.dropped.get(), &[]))
+            .init();
+        let metrics = replica.clone();
+        meter
+            .u64_observable_counter("gateway.replica.reads")
+            .with_description("Result reads answered by the Redis replica")
+            .with_callback(move |obs| obs.observe(metrics.reads.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.replica.fallbacks")
+            .with_description("Result reads the Redis replica missed, repeated on the primary")
+            .with_callback(move |obs| obs.observe(replica.fallbacks.get(), &[]))
+            .init();
+
+        let metrics = canary.clone();
+        meter
+            .u64_observable_counter("gateway.canary.runs")
+            .with_description("Synthetic canary tasks sent through the agents")
+            .with_callback(move |obs| obs.observe(metrics.runs.get(), &[]))
+            .init();
+        let metrics = canary.clone();
+        meter
+            .u64_observable_counter("gateway.canary.failures")
+            .with_description("Canary tasks without a result in time")
+            .with_callback(move |obs| obs.observe(metrics.failures.get(), &[]))
+            .init();
+        meter
+            .i64_observable_gauge("gateway.canary.latency_ms")
+            .with_description("Milliseconds the latest completed canary took end to end")
+            .with_callback(move |obs| obs.observe(canary.latency_ms.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.http.timeouts")
+            .with_description("Requests answered 504 for running over their budget")
+            .with_callback(move |obs| {
+                for (budget, count) in timeouts.timeouts.snapshot() {
+                    obs.observe(count, &[KeyValue::new("budget", budget)]);
+                }
+            })
+            .init();
+        let metrics = concurrency.clone();
+        meter
+            .i64_observable_gauge("gateway.http.in_flight")
+            .with_description("Requests being handled under concurrency.max_in_flight")
+            .with_callback(move |obs| obs.observe(metrics.in_flight.get(), &[]))
+            .init();
+        meter
+            .u64_observable_counter("gateway.http.concurrency_rejected")
+            .with_description("Requests refused 503 by a concurrency limit")
+            .with_callback(move |obs| {
+                for (limit, count) in concurrency.rejected.snapshot() {
+                    obs.observe(count, &[KeyValue::new("limit", limit)]);
+                }
+            })
+            .init();
+
+        let counters: [(&'static str, CacheReading); 5] = [
+            ("gateway.cache.hits", |m| m.hits.get()),
+            ("gateway.cache.misses", |m| m.misses.get()),
+            ("gateway.cache.bypassed", |m| m.bypassed.get()),
+            ("gateway.cache.stored", |m| m.stored.get()),
+            ("gateway.tasks.deduplicated", |m| m.deduplicated.get()),
+        ];
+        for (name, read) in counters {
+            let metrics = cache.clone();
+            meter
+                .u64_observable_counter(name)
+                .with_callback(move |obs| obs.observe(read(&metrics), &[]))
+                .init();
+        }
+
+        // Each bot is observed under its name
+        if telegram.is_empty() {
+            return;
+        }
+        let telegram = Arc::new(telegram);
+        let counters: [(&'static str, CounterReading); 8] = [
+            ("telegram.updates_received", |m| m.updates_received.get()),
+            ("telegram.tasks_created", |m| m.tasks_created.total()),
+            ("telegram.tasks_deduplicated", |m| m.tasks_deduplicated.get()),
+            ("telegram.duplicates_suppressed", |m| m.duplicates_suppressed.get()),
+            ("telegram.send_failures", |m| m.send_failures.get()),
+            ("telegram.undeliverable", |m| m.undeliverable.get()),
+            ("telegram.rate_limited", |m| m.rate_limited.get()),
+            ("telegram.loop_errors", |m| m.loop_errors.get()),
+        ];
+        for (name, read) in counters {
+            let bots = telegram.clone();
+            meter
+                .u64_observable_counter(name)
+                .with_callback(move |obs| {
+                    for (bot, metrics) in bots.iter() {
+                        obs.observe(read(metrics), &[KeyValue::new("bot", bot.clone())]);
+                    }
+                })
+                .init();
+        }
+        meter
+            .i64_observable_gauge("telegram.pending_tasks")
+            .with_callback(move |obs| {
+                for (bot, metrics) in telegram.iter() {
+                    let pending = metrics.pending_tasks.get();
+                    obs.observe(pending, &[KeyValue::new("bot", bot.clone())]);
+                }
+            })
+            .init();
+    }
+}
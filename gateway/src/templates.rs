@@ -0,0 +1,263 @@
+//! Channel reply templates.
+//!
+//! By default the channel adaptors answer with the text of a task's result.
+//! A [MiniJinja](https://docs.rs/minijinja) template stored for a channel
+//! renders the reply instead, e.g. a short summary and a link for Telegram:
+//! `{{ text | truncate(200) }} {{ link }}`. Templates see `task_id`,
+//! `status`, `text` (the result as text), `result` (the whole result
+//! record) and `link` (`templates.task_url` with `{task_id}` filled in).
+//! A template that fails to render falls back to the plain text. Failure and
+//! timeout apologies are not templated; webhooks always receive full JSON.
+//!
+//! Templates are kept in the Redis hash `templates` by channel (`telegram`,
+//! `email` or `twilio`) and managed at `GET /admin/templates` and `GET`,
+//! `PUT` (`{"template": "..."}`) and `DELETE /admin/templates/:channel`.
+//! Changes apply at once on the gateway taking them and within
+//! [`RELOAD_INTERVAL`] on the others.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use minijinja::Environment;
+use redis::{AsyncCommands, Client};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::auth::Principal;
+use crate::config::TemplateSettings;
+use crate::error::ApiError;
+use crate::validation::ValidationError;
+use crate::{redis_connection, AppState};
+
+/// Channels a reply template can be stored for
+pub const CHANNELS: [&str; 3] = ["telegram", "email", "twilio"];
+
+/// Redis hash of templates by channel
+const TEMPLATES_KEY: &str = "templates";
+
+/// How often templates are reloaded from Redis
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest template accepted, in bytes
+const MAX_TEMPLATE_BYTES: usize = 16 * 1024;
+
+/// Body of `PUT /admin/templates/:channel`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateRequest {
+    template: String,
+}
+
+/// The reply templates in force, shared by the adaptors and the admin API
+#[derive(Debug, Clone, Default)]
+pub struct ReplyTemplates {
+    sources: Arc<RwLock<HashMap<String, String>>>,
+    task_url: Option<String>,
+}
+
+/// Refuse a template that does not compile
+fn compile(source: &str) -> Result<(), ApiError> {
+    if source.len() > MAX_TEMPLATE_BYTES {
+        return Err(ApiError::bad_request(
+            "invalid_template",
+            format!("Templates are limited to {} bytes", MAX_TEMPLATE_BYTES),
+        ));
+    }
+    Environment::new()
+        .template_from_str(source)
+        .map(|_| ())
+        .map_err(|e| ApiError::bad_request("invalid_template", e.to_string()))
+}
+
+/// Refuse channels templates cannot be stored for
+fn check_channel(channel: &str) -> Result<(), ApiError> {
+    if CHANNELS.contains(&channel) {
+        return Ok(());
+    }
+    Err(ApiError::not_found(format!(
+        "Unknown channel {} (expected one of {})",
+        channel,
+        CHANNELS.join(", ")
+    )))
+}
+
+impl ReplyTemplates {
+    pub fn new(settings: &TemplateSettings) -> Self {
+        Self {
+            sources: Arc::default(),
+            task_url: settings.task_url.clone(),
+        }
+    }
+
+    fn source(&self, channel: &str) -> Option<String> {
+        let sources = self.sources.read().unwrap_or_else(|e| e.into_inner());
+        sources.get(channel).cloned()
+    }
+
+    fn set(&self, channel: &str, source: Option<String>) {
+        let mut sources = self.sources.write().unwrap_or_else(|e| e.into_inner());
+        match source {
+            Some(source) => sources.insert(channel.to_string(), source),
+            None => sources.remove(channel),
+        };
+    }
+
+    /// The reply to send on `channel` for the result record `result` of
+    /// `task_id`, if there is anything to send
+    pub fn reply(&self, channel: &str, task_id: &str, result: &Value) -> Option<String> {
+        let text = result.get("result").and_then(|r| r.as_str());
+        let Some(source) = self.source(channel) else {
+            return text.map(str::to_string);
+        };
+        let context = json!({
+            "task_id": task_id,
+            "status": result["status"],
+            "text": text.map(str::to_string).unwrap_or_else(|| result["result"].to_string()),
+            "result": result,
+            "link": self.task_url.as_ref().map(|url| url.replace("{task_id}", task_id)),
+        });
+        match Environment::new().render_str(&source, context) {
+            Ok(reply) => Some(reply.trim().to_string()),
+            Err(e) => {
+                warn!(
+                    "Task {}: {} template failed, sending plain text: {}",
+                    task_id, channel, e
+                );
+                text.map(str::to_string)
+            }
+        }
+    }
+
+    /// Replace every template with those stored in Redis
+    async fn reload(&self, redis_client: &Client) -> anyhow::Result<()> {
+        let mut conn = redis_client.get_async_connection().await?;
+        let stored: HashMap<String, String> = conn.hgetall(TEMPLATES_KEY).await?;
+        *self.sources.write().unwrap_or_else(|e| e.into_inner()) = stored;
+        Ok(())
+    }
+}
+
+/// Keep the templates in step with Redis in a background task
+pub fn start_template_reload(redis_client: Arc<Client>, templates: ReplyTemplates) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = templates.reload(&redis_client).await {
+                warn!("Keeping previous reply templates: {}", e);
+            }
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+        }
+    });
+}
+
+// List the stored templates (admin only)
+pub async fn list_templates(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let mut conn = redis_connection(&state).await?;
+    let stored: HashMap<String, String> = conn.hgetall(TEMPLATES_KEY).await?;
+    Ok(Json(json!({"templates": stored})))
+}
+
+// Show the template of a channel (admin only)
+pub async fn get_template(
+    State(state): State<AppState>,
+    Path(channel): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    check_channel(&channel)?;
+    let mut conn = redis_connection(&state).await?;
+    let stored: Option<String> = conn.hget(TEMPLATES_KEY, &channel).await?;
+    let template =
+        stored.ok_or_else(|| ApiError::not_found(format!("No template for {}", channel)))?;
+    Ok(Json(json!({"channel": channel, "template": template})))
+}
+
+// Store the template of a channel (admin only)
+pub async fn put_template(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(channel): Path<String>,
+    body: Result<Json<TemplateRequest>, JsonRejection>,
+) -> Result<Json<Value>, ApiError> {
+    check_channel(&channel)?;
+    let Json(req) = body.map_err(ValidationError::from)?;
+    compile(&req.template)?;
+
+    let mut conn = redis_connection(&state).await?;
+    conn.hset::<_, _, _, ()>(TEMPLATES_KEY, &channel, &req.template)
+        .await?;
+    state.templates.set(&channel, Some(req.template.clone()));
+    info!("{} reply template updated by {}", channel, principal.key_id);
+    Ok(Json(json!({"channel": channel, "template": req.template})))
+}
+
+// Go back to plain text replies on a channel (admin only)
+pub async fn delete_template(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(channel): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_channel(&channel)?;
+    let mut conn = redis_connection(&state).await?;
+    let removed: i64 = conn.hdel(TEMPLATES_KEY, &channel).await?;
+    state.templates.set(&channel, None);
+    if removed == 0 {
+        return Err(ApiError::not_found(format!("No template for {}", channel)));
+    }
+    info!("{} reply template removed by {}", channel, principal.key_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(channel: &str, source: &str) -> ReplyTemplates {
+        let templates = ReplyTemplates::new(&TemplateSettings {
+            task_url: Some("https://claw.example.com/tasks/{task_id}".to_string()),
+        });
+        templates.set(channel, Some(source.to_string()));
+        templates
+    }
+
+    #[test]
+    fn renders_channel_templates() {
+        let templates = templates("telegram", "{{ text | upper }}\n{{ link }}\n");
+        let result = json!({"task_id": "t1", "status": "completed", "result": "done"});
+        assert_eq!(
+            templates.reply("telegram", "t1", &result).as_deref(),
+            Some("DONE\nhttps://claw.example.com/tasks/t1")
+        );
+        assert_eq!(
+            templates.reply("email", "t1", &result).as_deref(),
+            Some("done")
+        );
+    }
+
+    #[test]
+    fn templates_reach_structured_results() {
+        let templates = templates("twilio", "{{ result.result.total }} items");
+        let result = json!({"status": "completed", "result": {"total": 3}});
+        assert_eq!(
+            templates.reply("twilio", "t1", &result).as_deref(),
+            Some("3 items")
+        );
+        // Without a template there is no text to send
+        assert_eq!(templates.reply("telegram", "t1", &result), None);
+    }
+
+    #[test]
+    fn broken_templates_fall_back_to_text() {
+        let templates = templates("telegram", "{{ text | no_such_filter }}");
+        let result = json!({"status": "completed", "result": "done"});
+        assert_eq!(
+            templates.reply("telegram", "t1", &result).as_deref(),
+            Some("done")
+        );
+        assert!(compile("{% if %}").is_err());
+        assert!(compile("{{ text }}").is_ok());
+    }
+}
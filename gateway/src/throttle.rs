@@ -0,0 +1,183 @@
+//! Outbound send throttling.
+//!
+//! Chat channels cap how fast a bot may send: Telegram takes about 30
+//! messages a second in all, one a second in a chat and 20 a minute in a
+//! group, and answers 429 with a `retry_after` past that, banning bots that
+//! keep going. A [`Throttle`] spaces sends out with a token bucket for the
+//! whole bot and one per chat, and holds every send back while the channel
+//! has asked to slow down.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets untouched for this long are full again and can be dropped, at
+/// the slowest rate a minute allows
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Chats tracked before idle buckets are dropped
+const MAX_KEYS: usize = 10_000;
+
+/// How fast sends may go, and how many may go at once
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    per_sec: f64,
+    burst: f64,
+}
+
+impl Rate {
+    /// `count` a second, all of them at once if need be
+    pub fn per_second(count: u32) -> Self {
+        Self {
+            per_sec: f64::from(count),
+            burst: f64::from(count.max(1)),
+        }
+    }
+
+    /// `count` a minute, evenly spaced
+    pub fn per_minute(count: u32) -> Self {
+        Self {
+            per_sec: f64::from(count) / 60.0,
+            burst: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst);
+        self.updated = now;
+    }
+
+    /// How long until a send may go
+    fn wait(&self, rate: Rate) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / rate.per_sec.max(f64::EPSILON))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner<K> {
+    global: Bucket,
+    keys: HashMap<K, Bucket>,
+    /// Sends are held back until then, as the channel asked
+    held_until: Option<Instant>,
+}
+
+/// Send throttle of one bot, by chat `K`
+#[derive(Debug)]
+pub struct Throttle<K> {
+    global: Rate,
+    inner: Mutex<Inner<K>>,
+}
+
+impl<K: Hash + Eq> Throttle<K> {
+    pub fn new(global: Rate) -> Self {
+        Self {
+            global,
+            inner: Mutex::new(Inner {
+                global: Bucket::full(global, Instant::now()),
+                keys: HashMap::new(),
+                held_until: None,
+            }),
+        }
+    }
+
+    /// Wait until a send to `key`, allowed `rate`, may go, and take its turn
+    pub async fn acquire(&self, key: K, rate: Rate)
+    where
+        K: Clone,
+    {
+        loop {
+            let wait = self.try_acquire(key.clone(), rate, Instant::now());
+            if wait.is_zero() {
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a turn for `key` at `now` if one is free, or say how long until
+    /// one might be
+    fn try_acquire(&self, key: K, rate: Rate, now: Instant) -> Duration {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(until) = inner.held_until {
+            if until > now {
+                return until - now;
+            }
+            inner.held_until = None;
+        }
+        if inner.keys.len() >= MAX_KEYS {
+            inner
+                .keys
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE);
+        }
+
+        let Inner { global, keys, .. } = &mut *inner;
+        global.refill(self.global, now);
+        let bucket = keys.entry(key).or_insert_with(|| Bucket::full(rate, now));
+        bucket.refill(rate, now);
+        let wait = bucket.wait(rate).max(global.wait(self.global));
+        if wait.is_zero() {
+            bucket.tokens -= 1.0;
+            global.tokens -= 1.0;
+        }
+        wait
+    }
+
+    /// Hold every send back for `delay`
+    pub fn hold(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if inner.held_until.is_none_or(|held| held < until) {
+            inner.held_until = Some(until);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_out_sends_per_chat_and_in_all() {
+        let throttle = Throttle::new(Rate::per_second(2));
+        let group = Rate::per_minute(20);
+        let now = Instant::now();
+
+        assert_eq!(throttle.try_acquire(-1, group, now), Duration::ZERO);
+        let wait = throttle.try_acquire(-1, group, now);
+        assert!(wait > Duration::from_millis(2_900) && wait <= Duration::from_secs(3));
+        // Another chat has its own turn, until the bot's run out
+        assert_eq!(throttle.try_acquire(2, group, now), Duration::ZERO);
+        assert!(!throttle.try_acquire(3, group, now).is_zero());
+        let later = now + Duration::from_secs(3);
+        assert_eq!(throttle.try_acquire(-1, group, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn holds_every_send_when_asked_to_slow_down() {
+        let throttle = Throttle::new(Rate::per_second(30));
+        throttle.hold(Duration::from_secs(5));
+        let wait = throttle.try_acquire(1, Rate::per_minute(60), Instant::now());
+        assert!(wait > Duration::from_secs(4));
+    }
+}
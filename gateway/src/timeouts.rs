@@ -0,0 +1,119 @@
+//! Request timeouts.
+//!
+//! Every request has a budget to answer in: `timeouts.read_secs` for `GET`
+//! and `HEAD`, `timeouts.write_secs` for the rest, or the budget of the
+//! longest pattern in `[timeouts.routes]` its path starts with, where a `*`
+//! segment matches any one segment (`/task/*/attachments`). A request over
+//! budget, stuck on a slow Redis say, is dropped and answered 504
+//! (`timeout`), and counted in `gateway.http.timeouts` by budget: the
+//! pattern, `read` or `write`. Long polls (`?wait=N`) get their wait on top
+//! of the budget; streamed responses only need to start within it.
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::TimeoutSettings;
+use crate::error::ApiError;
+use crate::metrics::CounterVec;
+use crate::AppState;
+
+#[derive(Debug, Default)]
+pub struct TimeoutMetrics {
+    /// Requests answered 504, by budget
+    pub timeouts: CounterVec,
+}
+
+/// Whether `path` starts with the segments of `pattern`
+pub fn covers(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    pattern
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .all(|expected| {
+            segments
+                .next()
+                .is_some_and(|s| expected == "*" || expected == s)
+        })
+}
+
+/// The entry of `routes` with the longest pattern covering `path`
+pub fn route_for<'a, V>(
+    routes: &'a BTreeMap<String, V>,
+    path: &str,
+) -> Option<(&'a String, &'a V)> {
+    routes
+        .iter()
+        .filter(|(pattern, _)| covers(pattern, path))
+        .max_by_key(|(pattern, _)| pattern.split('/').filter(|s| !s.is_empty()).count())
+}
+
+/// The budget for `method` on `path` in seconds, with its name
+fn budget<'a>(settings: &'a TimeoutSettings, method: &Method, path: &str) -> (&'a str, u64) {
+    match route_for(&settings.routes, path) {
+        Some((pattern, secs)) => (pattern.as_str(), *secs),
+        None if matches!(*method, Method::GET | Method::HEAD) => ("read", settings.read_secs),
+        None => ("write", settings.write_secs),
+    }
+}
+
+/// Seconds a long poll asks to wait, from its `wait` query parameter
+fn wait_secs(query: Option<&str>) -> u64 {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("wait=")?.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Middleware answering 504 to requests over their budget
+pub async fn enforce(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let config = state.config.current();
+    let (name, secs) = budget(&config.timeouts, req.method(), req.uri().path());
+    let wait = wait_secs(req.uri().query()).min(config.tasks.max_wait_secs);
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+
+    let limit = Duration::from_secs(secs + wait);
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("{} {} timed out after {}s", method, path, limit.as_secs());
+            state.timeout_metrics.timeouts.inc(name);
+            ApiError::timeout(format!("The request took longer than {}s", limit.as_secs()))
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_matching_pattern_wins() {
+        let settings = TimeoutSettings {
+            read_secs: 2,
+            write_secs: 5,
+            routes: BTreeMap::from([
+                ("/task".to_string(), 8),
+                ("/task/*/attachments".to_string(), 300),
+            ]),
+        };
+        let budget = |method, path| budget(&settings, &method, path);
+        assert_eq!(budget(Method::GET, "/tasks"), ("read", 2));
+        assert_eq!(budget(Method::POST, "/tasks/results"), ("write", 5));
+        assert_eq!(budget(Method::GET, "/task/t1"), ("/task", 8));
+        assert_eq!(
+            budget(Method::POST, "/task/t1/attachments"),
+            ("/task/*/attachments", 300)
+        );
+        assert_eq!(wait_secs(Some("preview=0&wait=30")), 30);
+        assert_eq!(wait_secs(None), 0);
+    }
+}
@@ -0,0 +1,89 @@
+//! Text-to-speech over HTTP.
+//!
+//! Voice replies are synthesized by an external service rather than in the
+//! gateway, so any engine can be put behind it. The service is POSTed
+//! `{"text", "language"}`, with a bearer token if one is set, and answers
+//! the audio as the response body, OGG/Opus for Telegram voice messages.
+
+use std::time::Duration;
+use tracing::Instrument;
+
+use crate::config::TelegramSettings;
+
+/// Client of the text-to-speech service
+#[derive(Debug, Clone)]
+pub struct TtsClient {
+    url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl TtsClient {
+    /// The client `settings` configure, if a service is set
+    pub fn from_settings(settings: &TelegramSettings) -> reqwest::Result<Option<Self>> {
+        let Some(url) = settings.tts_url.clone() else {
+            return Ok(None);
+        };
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.tts_timeout_secs))
+            .build()?;
+        Ok(Some(Self {
+            url,
+            token: settings.tts_token.clone(),
+            http,
+        }))
+    }
+
+    /// Audio of `text` spoken in `language`
+    pub async fn synthesize(&self, text: &str, language: &str) -> anyhow::Result<Vec<u8>> {
+        let mut request = self
+            .http
+            .post(&self.url)
+            .json(&serde_json::json!({"text": text, "language": language}));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .instrument(tracing::info_span!("tts", otel.name = "synthesize"))
+            .await?
+            .error_for_status()?;
+        let audio = response.bytes().await?;
+        if audio.is_empty() {
+            anyhow::bail!("text-to-speech service answered no audio");
+        }
+        Ok(audio.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Json, Router};
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn posts_the_text_and_returns_the_audio() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/synthesize", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/synthesize",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                assert_eq!(headers["authorization"], "Bearer s3cret");
+                format!("OggS:{}:{}", body["language"], body["text"])
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let settings = TelegramSettings {
+            tts_url: Some(url),
+            tts_token: Some("s3cret".to_string()),
+            ..TelegramSettings::default()
+        };
+        let tts = TtsClient::from_settings(&settings).unwrap().unwrap();
+        let audio = tts.synthesize("hello", "en").await.unwrap();
+        assert_eq!(audio, br#"OggS:"en":"hello""#);
+        let unset = TelegramSettings::default();
+        assert!(TtsClient::from_settings(&unset).unwrap().is_none());
+    }
+}
@@ -0,0 +1,584 @@
+//! Twilio SMS and WhatsApp adaptor.
+//!
+//! Twilio posts each inbound message to `POST /twilio/webhook`, signed with
+//! the account's auth token in `X-Twilio-Signature` (base64 HMAC-SHA1 of
+//! `twilio.webhook_url` followed by every form parameter, `name` then
+//! `value`, sorted by name). The message body becomes a task whose config
+//! carries the sender's number, and the task id joins `twilio:pending`.
+//!
+//! A background loop delivers the results of pending tasks through the
+//! Messages REST API, from the number the request was sent to, so WhatsApp
+//! requests are answered on WhatsApp. Results longer than
+//! `twilio.max_message_chars` are split at word breaks into numbered parts,
+//! at most `twilio.max_parts` of them. Removing the id from `twilio:pending`
+//! claims a reply, so with several gateways each result goes out once.
+
+use axum::{
+    extract::{Form, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use redis::AsyncCommands;
+use ring::hmac;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn, Instrument};
+use uuid::Uuid;
+
+use crate::config::TwilioSettings;
+use crate::error::ApiError;
+use crate::queue::NewTask;
+use crate::request_id::{self, RequestId};
+use crate::retry::{RetryDecision, RetryPolicy};
+use crate::runtime::RuntimeConfig;
+use crate::supervisor::Supervisor;
+use crate::templates::ReplyTemplates;
+use crate::{agent_config, envelope, failures, queue_for, redis_connection, schema, telemetry};
+use crate::{maintenance, redaction, render, watchdog, AppState};
+
+/// Header carrying the webhook signature
+const SIGNATURE_HEADER: &str = "x-twilio-signature";
+
+/// Redis set of the tasks whose results still have to be sent
+const PENDING_KEY: &str = "twilio:pending";
+
+/// Redis hash of the gateway number each sender last wrote to, by sender,
+/// for broadcasts
+const CONTACTS_KEY: &str = "twilio:contacts";
+
+/// Reply sent when the memory guard or queue backpressure refuses new work
+const OVERLOADED_REPLY: &str =
+    "The assistant is overloaded right now, please try again in a few minutes.";
+
+/// Reply sent when the watchdog gives up on a task
+const TIMED_OUT_REPLY: &str = "Sorry, this request took too long and was cancelled.";
+
+/// Reply sent when the agent reports that a task failed
+const FAILED_REPLY: &str =
+    "Sorry, something went wrong while handling this request. Please try again later.";
+
+/// Room kept at the end of each part for its ` (i/n)` counter
+const PART_COUNTER_CHARS: usize = 8;
+
+/// Twilio account settings and the client used for the REST API
+#[derive(Clone)]
+pub struct Twilio {
+    account_sid: Option<String>,
+    auth_token: String,
+    key: Option<hmac::Key>,
+    webhook_url: String,
+    messages_url: String,
+    http: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+/// Error response of the REST API
+#[derive(Debug, Deserialize)]
+struct ApiErrorBody {
+    #[serde(default)]
+    code: Option<u64>,
+    #[serde(default)]
+    message: String,
+}
+
+/// A message the REST API refused
+#[derive(Debug)]
+struct SendRefused {
+    status: reqwest::StatusCode,
+    retry_after: Option<Duration>,
+    message: String,
+}
+
+impl std::fmt::Display for SendRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Twilio refused the message ({}): {}",
+            self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for SendRefused {}
+
+impl Twilio {
+    /// Build the Twilio client from validated settings
+    pub fn from_config(settings: &TwilioSettings, retry: RetryPolicy) -> anyhow::Result<Self> {
+        let auth_token = settings.auth_token.clone().unwrap_or_default();
+        let account_sid = settings.account_sid.clone().unwrap_or_default();
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(Self {
+            key: settings.account_sid.as_ref().map(|_| {
+                hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, auth_token.as_bytes())
+            }),
+            account_sid: settings.account_sid.clone(),
+            auth_token,
+            webhook_url: settings.webhook_url.clone().unwrap_or_default(),
+            messages_url: format!(
+                "{}/2010-04-01/Accounts/{}/Messages.json",
+                settings.api_base.trim_end_matches('/'),
+                account_sid
+            ),
+            http,
+            retry,
+        })
+    }
+
+    /// Whether an account is configured
+    pub fn is_enabled(&self) -> bool {
+        self.account_sid.is_some()
+    }
+
+    /// Check the signature Twilio computed over the webhook URL and form
+    fn verify(&self, params: &[(String, String)], signature: &str) -> bool {
+        let (Some(key), Ok(tag)) = (
+            self.key.as_ref(),
+            base64::engine::general_purpose::STANDARD.decode(signature),
+        ) else {
+            return false;
+        };
+        let mut sorted: Vec<&(String, String)> = params.iter().collect();
+        sorted.sort();
+        let mut msg = self.webhook_url.clone();
+        for (name, value) in sorted {
+            msg.push_str(name);
+            msg.push_str(value);
+        }
+        hmac::verify(key, msg.as_bytes(), &tag).is_ok()
+    }
+
+    /// Send one message body from `from` to `to`
+    async fn send_message(&self, from: &str, to: &str, body: &str) -> anyhow::Result<()> {
+        let response = self
+            .http
+            .post(&self.messages_url)
+            .basic_auth(
+                self.account_sid.as_deref().unwrap_or_default(),
+                Some(&self.auth_token),
+            )
+            .form(&[("From", from), ("To", to), ("Body", body)])
+            .send()
+            .instrument(tracing::info_span!("twilio", otel.name = "Messages.create"))
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        let body: Option<ApiErrorBody> = response.json().await.ok();
+        let message = match body {
+            Some(ApiErrorBody {
+                code: Some(code),
+                message,
+            }) => format!("{} (error {})", message, code),
+            Some(body) => body.message,
+            None => "no details".to_string(),
+        };
+        Err(SendRefused {
+            status,
+            retry_after,
+            message,
+        }
+        .into())
+    }
+
+    /// Send a broadcast `text` to `to` from the gateway number `from`
+    pub async fn announce(
+        &self,
+        settings: &TwilioSettings,
+        from: &str,
+        to: &str,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        self.send_reply(settings, from, to, text).await
+    }
+
+    /// Send `text` to `to`, split into as many messages as it takes
+    async fn send_reply(
+        &self,
+        settings: &TwilioSettings,
+        from: &str,
+        to: &str,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        for part in split_message(text, settings.max_message_chars, settings.max_parts) {
+            self.retry
+                .run_classified(
+                    "Twilio send",
+                    || self.send_message(from, to, &part),
+                    classify_error,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Retry rate limits when Twilio says to and server errors with backoff;
+/// anything else, like an invalid number, will not get better
+fn classify_error(e: &anyhow::Error) -> RetryDecision {
+    match e.downcast_ref::<SendRefused>() {
+        None => RetryDecision::Backoff,
+        Some(refused) if refused.status == reqwest::StatusCode::TOO_MANY_REQUESTS => refused
+            .retry_after
+            .map_or(RetryDecision::Backoff, RetryDecision::After),
+        Some(refused) if refused.status.is_server_error() => RetryDecision::Backoff,
+        Some(_) => RetryDecision::Stop,
+    }
+}
+
+/// TwiML answer to a webhook, replying with `message` if given
+fn twiml(message: Option<&str>) -> Response {
+    let body = match message {
+        Some(message) => format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response><Message>{}</Message></Response>",
+            render::escape(message)
+        ),
+        None => "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response/>".to_string(),
+    };
+    ([(header::CONTENT_TYPE, "text/xml")], body).into_response()
+}
+
+// Receive an inbound SMS or WhatsApp message from Twilio
+pub async fn receive_message(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(params): Form<Vec<(String, String)>>,
+) -> Result<Response, ApiError> {
+    let twilio = &state.twilio;
+    if !twilio.is_enabled() {
+        return Err(ApiError::not_found("The Twilio adaptor is not enabled"));
+    }
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !twilio.verify(&params, signature) {
+        warn!("Rejected Twilio webhook with a bad signature");
+        return Err(ApiError::forbidden("Invalid Twilio signature"));
+    }
+
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let (Some(from), Some(to)) = (param("From"), param("To")) else {
+        return Err(ApiError::bad_request(
+            "invalid_webhook",
+            "From and To are required",
+        ));
+    };
+    let config = state.config.current();
+    if !config.twilio_sender_allowed(&from) {
+        debug!(
+            "Ignoring Twilio message from {} outside the allowlist",
+            from
+        );
+        return Ok(twiml(None));
+    }
+    // Media-only messages carry nothing for the agent to work on
+    let Some(input) = param("Body") else {
+        return Ok(twiml(None));
+    };
+
+    // Tell the sender to retry later while new work is refused
+    if !state.memory_guard.admits(false) || !state.queue_guard.admits(false) {
+        return Ok(twiml(Some(OVERLOADED_REPLY)));
+    }
+    if let Some(notice) = maintenance::current().filter(|notice| !notice.hold) {
+        return Ok(twiml(Some(&notice.message)));
+    }
+
+    let task_id = Uuid::new_v4().to_string();
+    let request_id = request_id::current().unwrap_or_else(RequestId::generate);
+    let timeout_secs = config.watchdog.default_timeout_secs;
+    let deadline = watchdog::deadline(timeout_secs);
+    let channel = if from.starts_with("whatsapp:") {
+        "whatsapp"
+    } else {
+        "sms"
+    };
+    let mut task = serde_json::json!({
+        "input": input,
+        "config": {
+            "twilio_from": from,
+            "twilio_to": to,
+            "twilio_message_sid": param("MessageSid"),
+            "twilio_channel": channel,
+        },
+        "status": "pending",
+        "timeout_secs": timeout_secs,
+        "deadline": deadline.to_rfc3339(),
+        "request_id": request_id.0,
+        "created_at": chrono::Utc::now().to_rfc3339(),
+        "schema_version": schema::CURRENT_VERSION,
+    });
+    redaction::input("twilio", &mut task["input"]);
+
+    let mut conn = redis_connection(&state).await?;
+    agent_config::apply_defaults(&mut conn, "twilio", &mut task["config"])
+        .await
+        .map_err(|e| ApiError::internal(format!("Invalid default config: {}", e)))?;
+    let queue = queue_for(None, false);
+    let new_task = NewTask {
+        task_id: &task_id,
+        record: envelope::encode(&task)?,
+        labels: &BTreeMap::new(),
+        queue: Some((&queue, deadline)),
+        tracked: true,
+    };
+    state.task_queue.submit(&mut conn, new_task).await?;
+    conn.sadd::<_, _, ()>(PENDING_KEY, &task_id).await?;
+    conn.hset::<_, _, _, ()>(CONTACTS_KEY, &from, &to).await?;
+
+    info!("Created task {} for Twilio message from {}", task_id, from);
+    Ok(twiml(None))
+}
+
+/// Senders that wrote in, with the gateway number each wrote to
+pub async fn known_contacts(
+    conn: &mut redis::aio::Connection,
+) -> redis::RedisResult<Vec<(String, String)>> {
+    let contacts: BTreeMap<String, String> = conn.hgetall(CONTACTS_KEY).await?;
+    Ok(contacts.into_iter().collect())
+}
+
+/// Deliver the replies of pending tasks that have settled: answers,
+/// apologies for failures and timeouts, and nothing for tasks that were
+/// deleted or settled past their deadline
+async fn deliver_replies(
+    conn: &mut redis::aio::Connection,
+    twilio: &Twilio,
+    settings: &TwilioSettings,
+    templates: &ReplyTemplates,
+) -> anyhow::Result<()> {
+    let task_ids: Vec<String> = conn.smembers(PENDING_KEY).await?;
+    if task_ids.is_empty() {
+        return Ok(());
+    }
+
+    let result_keys: Vec<String> = task_ids.iter().map(|id| format!("result:{}", id)).collect();
+    let results: Vec<Option<String>> = conn
+        .mget(&result_keys)
+        .instrument(telemetry::redis_span("MGET", "result:*"))
+        .await?;
+    let task_keys: Vec<String> = task_ids.iter().map(|id| format!("task:{}", id)).collect();
+    let tasks: Vec<Option<String>> = conn
+        .mget(&task_keys)
+        .instrument(telemetry::redis_span("MGET", "task:*"))
+        .await?;
+
+    for ((task_id, result), task) in task_ids.into_iter().zip(results).zip(tasks) {
+        let Some(task) = task.map(|t| schema::decode_task(&t)).transpose()? else {
+            // The record is gone, and with it the number to answer
+            conn.srem::<_, _, ()>(PENDING_KEY, &task_id).await?;
+            continue;
+        };
+        let late = watchdog::past_deadline(&task);
+        let text = match (result, task["status"].as_str()) {
+            // Nobody wants an answer to a deleted task
+            (_, Some("deleted")) => None,
+            (Some(_), _) | (None, Some("failed")) if late => {
+                info!("Task {} settled after its deadline, reply dropped", task_id);
+                None
+            }
+            (Some(result), _) => templates.reply("twilio", &task_id, &envelope::decode(&result)?),
+            (None, Some("failed")) => Some(FAILED_REPLY.to_string()),
+            (None, Some("timed_out")) => Some(TIMED_OUT_REPLY.to_string()),
+            // Still running
+            _ => continue,
+        };
+
+        // Whoever removes the task from the pending set sends its reply
+        let claimed: i64 = conn.srem(PENDING_KEY, &task_id).await?;
+        if claimed == 0 {
+            continue;
+        }
+        if let Some(text) = text.filter(|t| !t.is_empty()) {
+            // Answer from the number the request was sent to
+            let to = task["config"]["twilio_from"].as_str().unwrap_or_default();
+            let from = task["config"]["twilio_to"].as_str().unwrap_or_default();
+            let request_id = RequestId(task["request_id"].as_str().unwrap_or_default().to_string());
+            let sent =
+                request_id::scope(request_id, twilio.send_reply(settings, from, to, &text)).await;
+            match sent {
+                Ok(()) => info!("Sent response for task {} to {}", task_id, to),
+                Err(e) if matches!(classify_error(&e), RetryDecision::Stop) => {
+                    error!("Dropped Twilio reply for task {}: {}", task_id, e);
+                }
+                Err(e) => {
+                    error!("Failed to send Twilio reply for task {}: {}", task_id, e);
+                    conn.sadd::<_, _, ()>(PENDING_KEY, &task_id).await?;
+                    continue;
+                }
+            }
+        }
+        let keys = [format!("result:{}", task_id), failures::error_key(&task_id)];
+        conn.del::<_, ()>(&keys).await?;
+    }
+    Ok(())
+}
+
+/// Split `text` into parts of at most `max_chars` characters, breaking at
+/// whitespace where possible and numbering the parts when there are several.
+/// Text beyond `max_parts` parts is cut off with an ellipsis.
+fn split_message(text: &str, max_chars: usize, max_parts: usize) -> Vec<String> {
+    let text = text.trim();
+    if text.chars().count() <= max_chars {
+        return vec![text.to_string()];
+    }
+
+    let limit = max_chars - PART_COUNTER_CHARS;
+    let mut parts: Vec<String> = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if parts.len() == max_parts {
+            if let Some(last) = parts.last_mut() {
+                // Make room for the ellipsis within the limit
+                let keep: String = last.chars().take(limit - 1).collect();
+                *last = format!("{}…", keep.trim_end());
+            }
+            break;
+        }
+        let end = rest
+            .char_indices()
+            .nth(limit)
+            .map_or(rest.len(), |(index, _)| index);
+        let split = if end == rest.len() {
+            end
+        } else {
+            // Prefer the last line break or space in the second half
+            rest[..end]
+                .rfind(['\n', ' '])
+                .filter(|&at| at >= end / 2)
+                .unwrap_or(end)
+        };
+        parts.push(rest[..split].trim_end().to_string());
+        rest = rest[split..].trim_start();
+    }
+
+    let count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| format!("{} ({}/{})", part, i + 1, count))
+        .collect()
+}
+
+/// Start delivering Twilio replies in a background task
+pub fn start_delivery(
+    supervisor: &Arc<Supervisor>,
+    redis_client: Arc<redis::Client>,
+    twilio: Twilio,
+    runtime: Arc<RuntimeConfig>,
+    templates: ReplyTemplates,
+) {
+    supervisor.supervise("twilio", move || {
+        let (redis_client, twilio) = (redis_client.clone(), twilio.clone());
+        let (runtime, templates) = (runtime.clone(), templates.clone());
+        async move {
+            info!("Twilio adaptor started");
+            loop {
+                let settings = runtime.current().twilio.clone();
+                let pass = async {
+                    let mut conn = redis_client.get_async_connection().await?;
+                    deliver_replies(&mut conn, &twilio, &settings, &templates).await
+                };
+                if let Err(e) = pass.await {
+                    error!("Twilio delivery error: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(settings.interval_secs)).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example from Twilio's webhook security guide
+    const URL: &str = "https://mycompany.com/myapp.php?foo=1&bar=2";
+    const SIGNATURE: &str = "0/KCTR6DLpKmkAf8muzZqo1nDgQ=";
+
+    fn twilio() -> Twilio {
+        let settings = TwilioSettings {
+            account_sid: Some("AC123".to_string()),
+            auth_token: Some("12345".to_string()),
+            webhook_url: Some(URL.to_string()),
+            ..TwilioSettings::default()
+        };
+        Twilio::from_config(&settings, RetryPolicy::default()).unwrap()
+    }
+
+    fn params() -> Vec<(String, String)> {
+        [
+            ("Digits", "1234"),
+            ("To", "+18005551212"),
+            ("From", "+12349013030"),
+            ("Caller", "+12349013030"),
+            ("CallSid", "CA1234567890ABCDE"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn known_signatures_verify() {
+        assert!(twilio().verify(&params(), SIGNATURE));
+    }
+
+    #[test]
+    fn tampered_requests_fail() {
+        let twilio = twilio();
+        let mut changed = params();
+        changed[0].1 = "1235".to_string();
+        assert!(!twilio.verify(&changed, SIGNATURE));
+
+        let mut added = params();
+        added.push(("Body".to_string(), "hello".to_string()));
+        assert!(!twilio.verify(&added, SIGNATURE));
+
+        let moved = Twilio {
+            webhook_url: "https://mycompany.com/myapp.php".to_string(),
+            ..twilio.clone()
+        };
+        assert!(!moved.verify(&params(), SIGNATURE));
+        assert!(!twilio.verify(&params(), "not base64!"));
+    }
+
+    #[tokio::test]
+    async fn replies_are_escaped() {
+        let response = twiml(Some("Back at 5 <UTC> & \"soon\""));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response><Message>\
+             Back at 5 &lt;UTC&gt; &amp; &quot;soon&quot;</Message></Response>"
+        );
+    }
+
+    #[test]
+    fn unconfigured_accounts_verify_nothing() {
+        let twilio = Twilio::from_config(&TwilioSettings::default(), RetryPolicy::default());
+        assert!(!twilio.unwrap().verify(&params(), SIGNATURE));
+    }
+}
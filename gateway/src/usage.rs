@@ -0,0 +1,415 @@
+//! Per-tenant usage accounting and quotas.
+//!
+//! Every API key is a tenant, and unauthenticated callers share the
+//! `anonymous` tenant. Usage is rolled up per UTC day in the hash
+//! `usage:{key_id}:{YYYY-MM-DD}`, with fields `tasks` (submissions handed to
+//! an agent), `agent_ms` (time agents spent on them) and `result_bytes` (size
+//! of their stored results). A submission is checked against the tenant's
+//! daily quotas and counted in one atomic step before it is queued, and
+//! refunded if queueing then fails; a background sweep adds agent time and
+//! result size once its result appears.
+//! Rollups expire after `quotas.retention_days`. Admins are never limited.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use redis::aio::ConnectionLike;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn, Instrument};
+
+use crate::auth::{Principal, Role};
+use crate::config::{QuotaLimits, QuotaSettings};
+use crate::error::ApiError;
+use crate::{redis_connection, schema, telemetry, AppState};
+
+/// Redis hash mapping queued task ids to the tenant to bill when they finish
+const PENDING_KEY: &str = "usage:pending";
+
+/// Tenant for submissions without an API key
+const ANONYMOUS_TENANT: &str = "anonymous";
+
+/// Rollup fields, in the order quotas are checked
+const FIELDS: [&str; 3] = ["tasks", "agent_ms", "result_bytes"];
+
+/// Default and maximum days returned by `GET /usage`
+const DEFAULT_USAGE_DAYS: u64 = 7;
+const MAX_USAGE_DAYS: u64 = 90;
+
+fn rollup_key(tenant: &str, date: NaiveDate) -> String {
+    format!("usage:{}:{}", tenant, date.format("%Y-%m-%d"))
+}
+
+/// Tenant billed for a submission by `principal`
+pub fn tenant(principal: Option<&Principal>) -> &str {
+    principal.map_or(ANONYMOUS_TENANT, |p| p.key_id.as_str())
+}
+
+/// The tenant's quotas in `FIELDS` order, with agent time in milliseconds
+fn limits_in_units(limits: &QuotaLimits) -> [Option<u64>; 3] {
+    [
+        limits.daily_tasks,
+        limits
+            .daily_agent_secs
+            .map(|secs| secs.saturating_mul(1000)),
+        limits.daily_result_bytes,
+    ]
+}
+
+/// A submission counted against its tenant's quotas
+#[derive(Debug)]
+pub struct Admission {
+    pub task_id: String,
+    key: String,
+}
+
+impl Admission {
+    /// Take the submission back off the tenant's usage
+    pub async fn refund<C: ConnectionLike + Send>(self, conn: &mut C) {
+        let refunded = redis::pipe()
+            .atomic()
+            .hincr(&self.key, "tasks", -1)
+            .ignore()
+            .hdel(PENDING_KEY, &self.task_id)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await;
+        if let Err(e) = refunded {
+            error!("Failed to refund quota for task {}: {}", self.task_id, e);
+        }
+    }
+}
+
+/// The first quota, as an index into `FIELDS`, that `used` has reached
+fn used_up(used: &[Option<u64>], limits: &[Option<u64>; 3]) -> Option<usize> {
+    limits
+        .iter()
+        .zip(used)
+        .position(|(limit, used)| limit.is_some_and(|limit| used.unwrap_or(0) >= limit))
+}
+
+/// Check today's quotas for the submitting tenant and count the task,
+/// remembering it for the sweep; retried if the rollup changes meanwhile
+pub async fn admit<C: ConnectionLike + Send>(
+    conn: &mut C,
+    principal: Option<&Principal>,
+    task_id: &str,
+    quotas: &QuotaSettings,
+) -> Result<Admission, ApiError> {
+    let tenant = tenant(principal);
+    let exempt = principal.is_some_and(|p| p.role == Role::Admin);
+    let limits = if exempt {
+        [None; 3]
+    } else {
+        limits_in_units(&quotas.limits_for(tenant))
+    };
+
+    let now = Utc::now();
+    let key = rollup_key(tenant, now.date_naive());
+    let refused = loop {
+        redis::cmd("WATCH")
+            .arg(&key)
+            .query_async::<_, ()>(conn)
+            .await?;
+        let used: Vec<Option<u64>> = redis::cmd("HMGET")
+            .arg(&key)
+            .arg(&FIELDS)
+            .query_async(conn)
+            .await?;
+        if let Some(refused) = used_up(&used, &limits) {
+            redis::cmd("UNWATCH").query_async::<_, ()>(conn).await?;
+            break refused;
+        }
+        // EXEC answers nil when the rollup changed
+        let counted: Option<()> = redis::pipe()
+            .atomic()
+            .hincr(&key, "tasks", 1)
+            .ignore()
+            .expire(&key, (quotas.retention_days * 86400) as i64)
+            .ignore()
+            .hset(PENDING_KEY, task_id, tenant)
+            .ignore()
+            .query_async(conn)
+            .instrument(telemetry::redis_span("MULTI", &key))
+            .await?;
+        if counted.is_some() {
+            return Ok(Admission {
+                task_id: task_id.to_string(),
+                key,
+            });
+        }
+    };
+    let quota = ["daily_tasks", "daily_agent_secs", "daily_result_bytes"][refused];
+    let resets_at = next_midnight(now);
+    warn!(
+        "Task {} from {} refused: {} quota used up",
+        task_id, tenant, quota
+    );
+    Err(ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "quota_exceeded",
+        format!("Daily {} quota used up", quota.trim_start_matches("daily_")),
+    )
+    .with_details(serde_json::json!({
+        "quota": quota,
+        "resets_at": resets_at.to_rfc3339(),
+    }))
+    .with_retry_after((resets_at - now).num_seconds().max(1) as u64))
+}
+
+fn next_midnight(now: DateTime<Utc>) -> DateTime<Utc> {
+    let tomorrow = now.date_naive() + Days::new(1);
+    tomorrow
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight exists")
+        .and_utc()
+}
+
+/// Account agent time and result size for every queued task that finished
+async fn sweep(redis_client: &Client, retention_secs: u64) -> anyhow::Result<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let pending: HashMap<String, String> = conn.hgetall(PENDING_KEY).await?;
+
+    for (task_id, tenant) in pending {
+        let result: Option<String> = conn.get(format!("result:{}", task_id)).await?;
+        let Some(result) = result else {
+            // Tasks that expired without a result have nothing left to bill
+            let exists: bool = conn.exists(format!("task:{}", task_id)).await?;
+            if !exists {
+                conn.hdel::<_, _, ()>(PENDING_KEY, &task_id).await?;
+            }
+            continue;
+        };
+
+        let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+        let task = task.and_then(|t| schema::decode_task(&t).ok());
+        let timestamp = |field: &str| {
+            let task = task.as_ref()?;
+            DateTime::parse_from_rfc3339(task[field].as_str()?)
+                .ok()
+                .map(|t| t.with_timezone(&Utc))
+        };
+        let completed_at = timestamp("completed_at");
+        let agent_ms = match (timestamp("started_at"), completed_at) {
+            (Some(start), Some(end)) => (end - start).num_milliseconds().max(0),
+            _ => 0,
+        };
+
+        let day = completed_at.unwrap_or_else(Utc::now).date_naive();
+        let key = rollup_key(&tenant, day);
+        redis::pipe()
+            .atomic()
+            .hincr(&key, "agent_ms", agent_ms)
+            .ignore()
+            .hincr(&key, "result_bytes", result.len())
+            .ignore()
+            .expire(&key, retention_secs as i64)
+            .ignore()
+            .hdel(PENDING_KEY, &task_id)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Start the usage accounting sweep in a background task
+pub fn start_usage_accounting(redis_client: Arc<Client>, quotas: &QuotaSettings) {
+    let retention_secs = quotas.retention_days * 86400;
+    let interval = Duration::from_secs(quotas.interval_secs);
+    tokio::spawn(async move {
+        info!("Usage accounting started");
+        loop {
+            if let Err(e) = sweep(&redis_client, retention_secs).await {
+                error!("Usage accounting error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// Number of days to return, today included
+    days: Option<u64>,
+    /// Tenant to report on; only admins may name another tenant
+    key_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyUsage {
+    date: String,
+    tasks: u64,
+    agent_ms: u64,
+    result_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    key_id: String,
+    /// Daily limits in force for this tenant
+    limits: QuotaLimits,
+    /// Newest first, starting with today
+    days: Vec<DailyUsage>,
+}
+
+/// Consumption of the calling tenant, per day
+pub async fn get_usage(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageResponse>, ApiError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(ApiError::unauthorized(
+            "An API key is required to view usage",
+        ));
+    };
+    let key_id = match query.key_id {
+        Some(key_id) if key_id != principal.key_id && principal.role != Role::Admin => {
+            return Err(ApiError::forbidden(
+                "Admin role required to view other tenants",
+            ));
+        }
+        Some(key_id) => key_id,
+        None => principal.key_id.clone(),
+    };
+    let days = query
+        .days
+        .unwrap_or(DEFAULT_USAGE_DAYS)
+        .clamp(1, MAX_USAGE_DAYS);
+
+    let today = Utc::now().date_naive();
+    let dates: Vec<NaiveDate> = (0..days)
+        .filter_map(|n| today.checked_sub_days(Days::new(n)))
+        .collect();
+
+    let mut pipe = redis::pipe();
+    for date in &dates {
+        pipe.cmd("HMGET")
+            .arg(rollup_key(&key_id, *date))
+            .arg(&FIELDS);
+    }
+    let mut conn = redis_connection(&state).await?;
+    let rows: Vec<Vec<Option<u64>>> = pipe.query_async(&mut conn).await?;
+
+    let days = dates
+        .iter()
+        .zip(rows)
+        .map(|(date, row)| {
+            let field = |i: usize| row.get(i).copied().flatten().unwrap_or(0);
+            DailyUsage {
+                date: date.format("%Y-%m-%d").to_string(),
+                tasks: field(0),
+                agent_ms: field(1),
+                result_bytes: field(2),
+            }
+        })
+        .collect();
+
+    let limits = if key_id == principal.key_id && principal.role == Role::Admin {
+        QuotaLimits::default()
+    } else {
+        state.config.current().quotas.limits_for(&key_id)
+    };
+    Ok(Json(UsageResponse {
+        key_id,
+        limits,
+        days,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claw_test::FakeRedis;
+
+    fn quotas(daily_tasks: u64) -> QuotaSettings {
+        QuotaSettings {
+            default: QuotaLimits {
+                daily_tasks: Some(daily_tasks),
+                ..QuotaLimits::default()
+            },
+            ..QuotaSettings::default()
+        }
+    }
+
+    fn principal(role: Role) -> Principal {
+        Principal {
+            key_id: "k1".to_string(),
+            role,
+        }
+    }
+
+    fn used_today(redis: &FakeRedis) -> Option<String> {
+        redis.hget(&rollup_key("k1", Utc::now().date_naive()), "tasks")
+    }
+
+    #[tokio::test]
+    async fn admits_up_to_the_limit() {
+        let mut redis = FakeRedis::new();
+        let submitter = principal(Role::Submitter);
+        let quotas = quotas(2);
+        for task_id in ["t1", "t2"] {
+            admit(&mut redis, Some(&submitter), task_id, &quotas)
+                .await
+                .unwrap();
+        }
+        assert_eq!(used_today(&redis).as_deref(), Some("2"));
+        assert_eq!(redis.hget(PENDING_KEY, "t2").as_deref(), Some("k1"));
+
+        let refused = admit(&mut redis, Some(&submitter), "t3", &quotas)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.code, "quota_exceeded");
+        assert_eq!(refused.details.unwrap()["quota"], "daily_tasks");
+        assert!(refused.retry_after.is_some());
+        // Refusals count nothing
+        assert_eq!(used_today(&redis).as_deref(), Some("2"));
+        assert_eq!(redis.hget(PENDING_KEY, "t3"), None);
+
+        // Admins are never limited
+        let admin = principal(Role::Admin);
+        admit(&mut redis, Some(&admin), "t4", &quotas)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refunds_rejected_submissions() {
+        let mut redis = FakeRedis::new();
+        let submitter = principal(Role::Submitter);
+        let quotas = quotas(1);
+        let admission = admit(&mut redis, Some(&submitter), "t1", &quotas)
+            .await
+            .unwrap();
+        assert!(admit(&mut redis, Some(&submitter), "t2", &quotas)
+            .await
+            .is_err());
+
+        // Queueing t1 failed: the quota it took is free again
+        admission.refund(&mut redis).await;
+        assert_eq!(used_today(&redis).as_deref(), Some("0"));
+        assert_eq!(redis.hget(PENDING_KEY, "t1"), None);
+        admit(&mut redis, Some(&submitter), "t2", &quotas)
+            .await
+            .unwrap();
+        assert_eq!(used_today(&redis).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn finds_the_first_quota_used_up() {
+        let limits = [Some(10), None, Some(100)];
+        assert_eq!(used_up(&[Some(9), Some(5), None], &limits), None);
+        assert_eq!(used_up(&[Some(10), None, None], &limits), Some(0));
+        assert_eq!(used_up(&[None, Some(1 << 40), Some(100)], &limits), Some(2));
+        assert_eq!(used_up(&[], &limits), None);
+    }
+}
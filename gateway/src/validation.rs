@@ -0,0 +1,514 @@
+//! Request size limits and payload validation.
+//!
+//! Validation collects every violation instead of stopping at the first one,
+//! so clients get a single 400 body listing everything wrong with a request.
+//! Oversized inputs are reported as 413 to distinguish them from bad shapes.
+//! The shape of a task submission is checked against
+//! `schemas/agent_request.schema.json`; the configured limits and label
+//! rules on top of it.
+
+use axum::{extract::rejection::JsonRejection, http::StatusCode};
+use serde::Serialize;
+use std::sync::LazyLock;
+
+use crate::config::LimitSettings;
+use crate::{fanout, labels, AgentRequest};
+
+/// Longest accepted task id
+const MAX_TASK_ID_LEN: usize = 128;
+
+/// Longest accepted capability name
+const MAX_CAPABILITY_LEN: usize = 32;
+
+/// Schema every task submission must satisfy
+static AGENT_REQUEST_SCHEMA: LazyLock<jsonschema::Validator> = LazyLock::new(|| {
+    let schema = serde_json::from_str(include_str!("../schemas/agent_request.schema.json"))
+        .expect("agent request schema is valid JSON");
+    jsonschema::validator_for(&schema).expect("agent request schema compiles")
+});
+
+/// Schema every stored default agent config must satisfy
+static DEFAULT_CONFIG_SCHEMA: LazyLock<jsonschema::Validator> = LazyLock::new(|| {
+    let schema = serde_json::from_str(include_str!("../schemas/default_config.schema.json"))
+        .expect("default config schema is valid JSON");
+    jsonschema::validator_for(&schema).expect("default config schema compiles")
+});
+
+/// A single problem with a request field
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub field: String,
+    pub message: String,
+}
+
+/// Rejected request with every violation found
+#[derive(Debug)]
+pub struct ValidationError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub violations: Vec<Violation>,
+}
+
+impl ValidationError {
+    fn new(status: StatusCode, violations: Vec<Violation>) -> Self {
+        Self {
+            status,
+            code: if status == StatusCode::PAYLOAD_TOO_LARGE {
+                "payload_too_large"
+            } else {
+                "validation_failed"
+            },
+            violations,
+        }
+    }
+
+    /// A body that could not be read or decoded; statuses other than 413
+    /// and 415 are reported as 400
+    pub fn body(status: StatusCode, message: impl Into<String>) -> Self {
+        let status = match status {
+            StatusCode::PAYLOAD_TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        Self::new(status, vec![violation("body", message)])
+    }
+}
+
+impl From<JsonRejection> for ValidationError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::body(rejection.status(), rejection.body_text())
+    }
+}
+
+/// Check an `AgentRequest` against its schema and the configured limits
+pub fn validate_request(req: &AgentRequest, limits: &LimitSettings) -> Result<(), ValidationError> {
+    let request = serde_json::to_value(req).map_err(|e| {
+        ValidationError::body(
+            StatusCode::BAD_REQUEST,
+            format!("Unreadable request: {}", e),
+        )
+    })?;
+    let mut violations = schema_violations(&AGENT_REQUEST_SCHEMA, &request, None);
+    // Schema messages quote the rule; say it in words for the common fields
+    for v in &mut violations {
+        let rule = match v.field.as_str() {
+            "task_id" => TASK_ID_RULE,
+            "capability" => CAPABILITY_RULE,
+            "input" => "input cannot be null",
+            f if f.starts_with("depends_on.") => "dependencies must be valid task ids",
+            _ => continue,
+        };
+        v.message = rule.to_string();
+    }
+    let mut too_large = false;
+
+    if req.labels.len() > labels::MAX_LABELS {
+        violations.push(violation(
+            "labels",
+            format!("at most {} labels are allowed", labels::MAX_LABELS),
+        ));
+    }
+    for (key, value) in &req.labels {
+        if !labels::is_valid_key(key) {
+            violations.push(violation(format!("labels.{}", key), labels::KEY_RULE));
+        } else if !labels::is_valid_value(value) {
+            violations.push(violation(format!("labels.{}", key), labels::VALUE_RULE));
+        }
+    }
+
+    if req.depends_on.len() > limits.max_dependencies {
+        violations.push(violation(
+            "depends_on",
+            format!(
+                "at most {} dependencies are allowed",
+                limits.max_dependencies
+            ),
+        ));
+    }
+    if req.map.is_some() {
+        match req.input.as_array() {
+            Some(items) if items.is_empty() || items.len() > limits.max_map_items => {
+                violations.push(violation(
+                    "input",
+                    format!("a map task needs 1-{} input elements", limits.max_map_items),
+                ));
+            }
+            Some(items) => {
+                if fanout::child_id(&req.task_id, items.len() - 1).len() > MAX_TASK_ID_LEN {
+                    violations.push(violation(
+                        "task_id",
+                        "task_id leaves no room for the ids of the map items",
+                    ));
+                }
+            }
+            None => violations.push(violation("input", "a map task needs an array input")),
+        }
+        if !req.depends_on.is_empty() {
+            violations.push(violation(
+                "depends_on",
+                "map tasks cannot have dependencies",
+            ));
+        }
+    }
+
+    for (i, dependency) in req.depends_on.iter().enumerate() {
+        if *dependency == req.task_id {
+            violations.push(violation(
+                format!("depends_on.{}", i),
+                "a task cannot depend on itself",
+            ));
+        }
+    }
+
+    if !req.input.is_null() {
+        let size = json_size(&req.input);
+        if size > limits.max_input_bytes {
+            too_large = true;
+            violations.push(violation(
+                "input",
+                format!(
+                    "input is {} bytes, limit is {}",
+                    size, limits.max_input_bytes
+                ),
+            ));
+        }
+    }
+
+    if let Some(config) = &req.config {
+        let depth = json_depth(config);
+        if depth > limits.max_config_depth {
+            violations.push(violation(
+                "config",
+                format!(
+                    "config nesting depth is {}, limit is {}",
+                    depth, limits.max_config_depth
+                ),
+            ));
+        }
+        let size = json_size(config);
+        if size > limits.max_config_bytes {
+            too_large = true;
+            violations.push(violation(
+                "config",
+                format!(
+                    "config is {} bytes, limit is {}",
+                    size, limits.max_config_bytes
+                ),
+            ));
+        }
+    }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let status = if too_large {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    Err(ValidationError::new(status, violations))
+}
+
+/// What [`is_valid_task_id`] accepts, for error messages
+const TASK_ID_RULE: &str = "task_id must be 1-128 letters, digits, '-', '_' or '.'";
+
+/// What [`is_valid_capability`] accepts, for error messages
+pub const CAPABILITY_RULE: &str =
+    "capability must be 1-32 lowercase letters, digits, '-' or '_', and not \"priority\"";
+
+/// Whether `id` would pass the task id checks of [`validate_request`]
+pub fn is_valid_task_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_TASK_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Whether `name` can be used as a capability and in its queue name. The
+/// name `priority` is reserved since `agent:queue:priority` is the priority lane.
+pub fn is_valid_capability(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_CAPABILITY_LEN
+        && name != "priority"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_'))
+}
+
+/// Check a default agent config against its JSON Schema and the size limits
+pub fn validate_default_config(
+    config: &serde_json::Value,
+    limits: &LimitSettings,
+) -> Result<(), ValidationError> {
+    let mut violations = schema_violations(&DEFAULT_CONFIG_SCHEMA, config, Some("config"));
+
+    let depth = json_depth(config);
+    if depth > limits.max_config_depth {
+        violations.push(violation(
+            "config",
+            format!(
+                "config nesting depth is {}, limit is {}",
+                depth, limits.max_config_depth
+            ),
+        ));
+    }
+    let size = json_size(config);
+    let too_large = size > limits.max_config_bytes;
+    if too_large {
+        violations.push(violation(
+            "config",
+            format!(
+                "config is {} bytes, limit is {}",
+                size, limits.max_config_bytes
+            ),
+        ));
+    }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let status = if too_large {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    Err(ValidationError::new(status, violations))
+}
+
+/// Every way `value` breaks `schema`, with fields named by their path below
+/// `root`; errors on the value itself are reported against `root`, or
+/// `body` without one
+fn schema_violations(
+    schema: &jsonschema::Validator,
+    value: &serde_json::Value,
+    root: Option<&str>,
+) -> Vec<Violation> {
+    schema
+        .iter_errors(value)
+        .map(|e| {
+            let path = e.instance_path().to_string();
+            let path = path.trim_start_matches('/').replace('/', ".");
+            let field = match (root, path.is_empty()) {
+                (Some(root), true) => root.to_string(),
+                (Some(root), false) => format!("{}.{}", root, path),
+                (None, true) => "body".to_string(),
+                (None, false) => path,
+            };
+            violation(field, e.to_string())
+        })
+        .collect()
+}
+
+fn violation(field: impl Into<String>, message: impl Into<String>) -> Violation {
+    Violation {
+        field: field.into(),
+        message: message.into(),
+    }
+}
+
+/// Serialized size of a JSON value in bytes
+fn json_size(value: &serde_json::Value) -> usize {
+    serde_json::to_vec(value)
+        .map(|v| v.len())
+        .unwrap_or(usize::MAX)
+}
+
+/// Nesting depth of a JSON value; scalars have depth 0
+fn json_depth(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Object(map) => 1 + map.values().map(json_depth).max().unwrap_or(0),
+        serde_json::Value::Array(items) => 1 + items.iter().map(json_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(value: serde_json::Value) -> AgentRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Fields of every violation `value` produces under the default limits
+    fn rejected(value: serde_json::Value, limits: &LimitSettings) -> Vec<String> {
+        match validate_request(&request(value), limits) {
+            Ok(()) => Vec::new(),
+            Err(e) => e.violations.into_iter().map(|v| v.field).collect(),
+        }
+    }
+
+    #[test]
+    fn task_ids() {
+        assert!(is_valid_task_id("task-1.retry_2"));
+        assert!(is_valid_task_id(&"a".repeat(MAX_TASK_ID_LEN)));
+        assert!(!is_valid_task_id(""));
+        assert!(!is_valid_task_id(&"a".repeat(MAX_TASK_ID_LEN + 1)));
+        assert!(!is_valid_task_id("task 1"));
+        assert!(!is_valid_task_id("task/1"));
+    }
+
+    #[test]
+    fn schema_agrees_with_task_id_rule() {
+        let limits = LimitSettings::default();
+        for id in [
+            "ok-1",
+            "",
+            "with space",
+            "ünïcode",
+            &"a".repeat(MAX_TASK_ID_LEN + 1),
+        ] {
+            let fields = rejected(json!({"task_id": id, "input": "x"}), &limits);
+            assert_eq!(
+                fields.is_empty(),
+                is_valid_task_id(id),
+                "{:?} gave {:?}",
+                id,
+                fields
+            );
+        }
+    }
+
+    #[test]
+    fn schema_agrees_with_capability_rule() {
+        let limits = LimitSettings::default();
+        for capability in [
+            "gpu",
+            "web-search_2",
+            "GPU",
+            "priority",
+            "",
+            &"a".repeat(33),
+        ] {
+            let fields = rejected(
+                json!({"task_id": "t", "input": "x", "capability": capability}),
+                &limits,
+            );
+            assert_eq!(
+                fields.is_empty(),
+                is_valid_capability(capability),
+                "{:?}",
+                capability
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_a_plain_request() {
+        let limits = LimitSettings::default();
+        let req = json!({
+            "task_id": "t1",
+            "input": {"text": "hello"},
+            "config": {"model": "m"},
+            "labels": {"team": "ml"},
+            "depends_on": ["t0"],
+        });
+        assert!(rejected(req, &limits).is_empty());
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let limits = LimitSettings::default();
+        let fields = rejected(
+            json!({
+                "task_id": "bad id",
+                "input": null,
+                "config": [1],
+                "depends_on": ["t0", "t0", "bad id"],
+            }),
+            &limits,
+        );
+        for field in ["task_id", "input", "config", "depends_on", "depends_on.2"] {
+            assert!(
+                fields.iter().any(|f| f == field),
+                "{} missing in {:?}",
+                field,
+                fields
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_self_dependency_and_too_many_dependencies() {
+        let limits = LimitSettings {
+            max_dependencies: 1,
+            ..LimitSettings::default()
+        };
+        let fields = rejected(
+            json!({"task_id": "t1", "input": "x", "depends_on": ["t1", "t2"]}),
+            &limits,
+        );
+        assert!(fields.contains(&"depends_on".to_string()));
+        assert!(fields.contains(&"depends_on.0".to_string()));
+    }
+
+    #[test]
+    fn oversized_input_is_payload_too_large() {
+        let limits = LimitSettings {
+            max_input_bytes: 10,
+            ..LimitSettings::default()
+        };
+        let req = request(json!({"task_id": "t", "input": "x".repeat(20)}));
+        let err = validate_request(&req, &limits).unwrap_err();
+        assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(err.code, "payload_too_large");
+        assert_eq!(err.violations[0].field, "input");
+    }
+
+    #[test]
+    fn config_depth_and_size_limits() {
+        let limits = LimitSettings {
+            max_config_depth: 2,
+            max_config_bytes: 1_000,
+            ..LimitSettings::default()
+        };
+        let deep = request(json!({"task_id": "t", "input": "x", "config": {"a": {"b": {}}}}));
+        let err = validate_request(&deep, &limits).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        let big =
+            request(json!({"task_id": "t", "input": "x", "config": {"a": "x".repeat(2_000)}}));
+        let err = validate_request(&big, &limits).unwrap_err();
+        assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn labels_follow_label_rules() {
+        let limits = LimitSettings::default();
+        let fields = rejected(
+            json!({"task_id": "t", "input": "x", "labels": {"Bad Key": "v", "ok": "bad value"}}),
+            &limits,
+        );
+        assert_eq!(fields, ["labels.Bad Key", "labels.ok"]);
+    }
+
+    #[test]
+    fn map_tasks_need_an_array_within_limits() {
+        let limits = LimitSettings {
+            max_map_items: 2,
+            ..LimitSettings::default()
+        };
+        let map = |input: serde_json::Value| json!({"task_id": "t", "input": input, "map": {}});
+        assert!(rejected(map(json!(["a", "b"])), &limits).is_empty());
+        assert_eq!(rejected(map(json!("a")), &limits), ["input"]);
+        assert_eq!(rejected(map(json!([])), &limits), ["input"]);
+        assert_eq!(rejected(map(json!([1, 2, 3])), &limits), ["input"]);
+
+        let long_id = json!({"task_id": "a".repeat(MAX_TASK_ID_LEN), "input": [1], "map": {}});
+        assert_eq!(rejected(long_id, &limits), ["task_id"]);
+        let waiting = json!({"task_id": "t", "input": [1], "map": {}, "depends_on": ["t0"]});
+        assert_eq!(rejected(waiting, &limits), ["depends_on"]);
+        let policy = json!({"task_id": "t", "input": [1], "map": {"on_failure": "ignore"}});
+        assert!(serde_json::from_value::<AgentRequest>(policy).is_err());
+    }
+
+    #[test]
+    fn json_measures() {
+        assert_eq!(json_depth(&json!(1)), 0);
+        assert_eq!(json_depth(&json!({"a": [1, {"b": 2}]})), 3);
+        assert_eq!(json_size(&json!({"a": 1})), 7);
+    }
+}
@@ -0,0 +1,331 @@
+//! Task timeout watchdog.
+//!
+//! Every queued task gets a deadline `timeout_seconds` after submission, or
+//! `watchdog.default_timeout_secs` when the request names none, kept in the
+//! sorted set `watchdog:deadlines` and in the record's `deadline` field for
+//! agents to give up on work nobody waits for any more. A background loop picks up tasks past
+//! their deadline that still have no result. Each is queued again with a
+//! fresh deadline up to `watchdog.max_requeues` times and then marked
+//! `timed_out`, so clients stop polling for an answer that is not coming.
+//! Requeues wait out the `retry.task` backoff for their attempt in the
+//! sorted set `watchdog:requeue` before they reach the queue again.
+//! The Telegram adaptor tells the chat a task came from; any other task is
+//! reported to `watchdog.webhook_url`.
+
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, Client};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::config::WatchdogSettings;
+use crate::error::ApiError;
+use crate::metrics::Counter;
+use crate::queue::TaskQueue;
+use crate::retry::RetryPolicy;
+use crate::runtime::RuntimeConfig;
+use crate::{queue_for, schema, task_state};
+
+/// Sorted set of tracked task ids, scored by their unix deadline
+const DEADLINES_KEY: &str = "watchdog:deadlines";
+
+/// Sorted set of requeued task ids, scored by the unix time they are queued
+const REQUEUE_KEY: &str = "watchdog:requeue";
+
+/// Overdue tasks handled per pass
+const BATCH_SIZE: isize = 100;
+
+/// Timeout for a submission asking for `requested` seconds
+pub fn timeout_for(requested: Option<u64>, settings: &WatchdogSettings) -> Result<u64, ApiError> {
+    match requested {
+        None => Ok(settings.default_timeout_secs),
+        Some(secs) if (1..=settings.max_timeout_secs).contains(&secs) => Ok(secs),
+        Some(_) => Err(ApiError::bad_request(
+            "invalid_timeout",
+            format!(
+                "timeout_seconds must be between 1 and {}",
+                settings.max_timeout_secs
+            ),
+        )),
+    }
+}
+
+/// The deadline of a task queued now with `timeout_secs`
+pub fn deadline(timeout_secs: u64) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(timeout_secs as i64)
+}
+
+/// Whether the record's `deadline` has passed
+pub fn past_deadline(task: &Value) -> bool {
+    task["deadline"]
+        .as_str()
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+        .is_some_and(|d| d < Utc::now())
+}
+
+/// Start watching `task_id`, due `timeout_secs` from now
+pub async fn track(
+    conn: &mut redis::aio::Connection,
+    task_id: &str,
+    timeout_secs: u64,
+) -> redis::RedisResult<()> {
+    conn.zadd(DEADLINES_KEY, task_id, deadline(timeout_secs).timestamp())
+        .await
+}
+
+/// Add watching `task_id`, due by `deadline`, to `pipe`
+pub fn track_in(pipe: &mut redis::Pipeline, task_id: &str, deadline: DateTime<Utc>) {
+    pipe.zadd(DEADLINES_KEY, task_id, deadline.timestamp())
+        .ignore();
+}
+
+/// Stop watching `task_id`
+pub async fn untrack(conn: &mut redis::aio::Connection, task_id: &str) -> redis::RedisResult<()> {
+    conn.zrem(DEADLINES_KEY, task_id).await
+}
+
+/// Whether `task` is still waiting or being worked on
+fn unfinished(task: &Value) -> bool {
+    matches!(task["status"].as_str(), Some("pending" | "processing"))
+}
+
+/// Counters maintained by the watchdog
+#[derive(Debug, Default)]
+pub struct WatchdogMetrics {
+    /// Tasks given up on after their last deadline
+    pub timed_out: Counter,
+    /// Overdue tasks put back on their queue
+    pub requeued: Counter,
+}
+
+struct Watchdog {
+    task_queue: TaskQueue,
+    runtime: Arc<RuntimeConfig>,
+    http: reqwest::Client,
+    retry: RetryPolicy,
+    /// Backoff before each requeue
+    requeue_retry: RetryPolicy,
+    metrics: Arc<WatchdogMetrics>,
+}
+
+impl Watchdog {
+    /// Handle every task whose deadline has passed
+    async fn sweep(&self, redis_client: &Client) -> anyhow::Result<()> {
+        let mut conn = redis_client.get_async_connection().await?;
+        let now = Utc::now().timestamp();
+
+        let due: Vec<String> = conn
+            .zrangebyscore_limit(REQUEUE_KEY, "-inf", now, 0, BATCH_SIZE)
+            .await?;
+        for task_id in due {
+            if let Err(e) = self.release(&mut conn, &task_id).await {
+                error!("Failed to requeue task {}: {}", task_id, e);
+            }
+        }
+
+        let overdue: Vec<String> = conn
+            .zrangebyscore_limit(DEADLINES_KEY, "-inf", now, 0, BATCH_SIZE)
+            .await?;
+
+        let settings = self.runtime.current().watchdog.clone();
+        for task_id in overdue {
+            if let Err(e) = self.expire(&mut conn, &task_id, &settings).await {
+                error!("Failed to time out task {}: {}", task_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Requeue or time out `task_id` unless it finished in the meantime
+    async fn expire(
+        &self,
+        conn: &mut redis::aio::Connection,
+        task_id: &str,
+        settings: &WatchdogSettings,
+    ) -> anyhow::Result<()> {
+        let result_exists: bool = conn.exists(format!("result:{}", task_id)).await?;
+        let task_key = format!("task:{}", task_id);
+        let task: Option<String> = conn.get(&task_key).await?;
+        let task = match (result_exists, task) {
+            (false, Some(task)) => schema::decode_task(&task)?,
+            _ => return Ok(untrack(conn, task_id).await?),
+        };
+        if !unfinished(&task) {
+            return Ok(conn.zrem(DEADLINES_KEY, task_id).await?);
+        }
+
+        // A task still waiting in its queue must not be picked up twice
+        let priority = task["priority"].as_bool().unwrap_or(false);
+        let queue = queue_for(task["capability"].as_str(), priority);
+        self.task_queue.withdraw(conn, &queue, task_id).await?;
+
+        let requeues = task["requeues"].as_u64().unwrap_or(0);
+        if requeues < u64::from(settings.max_requeues) {
+            if self.requeue(conn, task_id, &queue, settings).await? {
+                warn!("Task {} overdue, requeued on {}", task_id, queue);
+            } else {
+                conn.zrem::<_, _, ()>(DEADLINES_KEY, task_id).await?;
+            }
+            return Ok(());
+        }
+
+        // The agent may have finished it since it was read
+        let timed_out = task_state::transition(conn, task_id, "timed_out", |task| {
+            task["timed_out_at"] = Utc::now().to_rfc3339().into();
+            unfinished(task)
+        })
+        .await?;
+        conn.zrem::<_, _, ()>(DEADLINES_KEY, task_id).await?;
+        let Some(task) = timed_out else {
+            return Ok(());
+        };
+        self.metrics.timed_out.inc();
+        warn!("Task {} timed out after {} requeues", task_id, requeues);
+
+        // Telegram tasks are reported by the adaptor in their chat
+        if task["config"]["telegram_chat_id"].is_null() {
+            if let Some(url) = &settings.webhook_url {
+                self.notify(url, task_id, &task).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedule an overdue task to go back on `queue` with a fresh deadline
+    /// once its backoff has passed, unless it finished in the meantime;
+    /// whether it was requeued
+    async fn requeue(
+        &self,
+        conn: &mut redis::aio::Connection,
+        task_id: &str,
+        queue: &str,
+        settings: &WatchdogSettings,
+    ) -> anyhow::Result<bool> {
+        let mut timeout = settings.default_timeout_secs;
+        let mut backoff = 0;
+        let mut due = Utc::now();
+        let requeued = task_state::transition(conn, task_id, "pending", |task| {
+            if let Some(fields) = task.as_object_mut() {
+                fields.remove("agent_id");
+                fields.remove("agent_version");
+                fields.remove("started_at");
+            }
+            timeout = task["timeout_secs"].as_u64().unwrap_or(timeout);
+            let requeues = task["requeues"].as_u64().unwrap_or(0) + 1;
+            backoff = self
+                .requeue_retry
+                .delay_for(requeues as u32)
+                .as_secs_f64()
+                .ceil() as u64;
+            task["requeues"] = requeues.into();
+            task["requeued_at"] = Utc::now().to_rfc3339().into();
+            due = deadline(backoff + timeout);
+            task["deadline"] = due.to_rfc3339().into();
+            unfinished(task)
+        })
+        .await?;
+        if requeued.is_none() {
+            return Ok(false);
+        }
+
+        track(conn, task_id, backoff + timeout).await?;
+        if backoff == 0 {
+            self.task_queue.push(conn, queue, task_id, due).await?;
+        } else {
+            let at = Utc::now().timestamp() + backoff as i64;
+            conn.zadd::<_, _, _, ()>(REQUEUE_KEY, task_id, at).await?;
+            info!("Task {} goes back on {} in {}s", task_id, queue, backoff);
+        }
+        self.metrics.requeued.inc();
+        Ok(true)
+    }
+
+    /// Queue a requeued task whose backoff has passed
+    async fn release(
+        &self,
+        conn: &mut redis::aio::Connection,
+        task_id: &str,
+    ) -> anyhow::Result<()> {
+        // Only the gateway that takes it off the set queues it
+        let taken: u32 = conn.zrem(REQUEUE_KEY, task_id).await?;
+        if taken == 0 {
+            return Ok(());
+        }
+        let task: Option<String> = conn.get(format!("task:{}", task_id)).await?;
+        let Some(task) = task.map(|t| schema::decode_task(&t)).transpose()? else {
+            return Ok(());
+        };
+        if task["status"] != "pending" {
+            return Ok(());
+        }
+        let due = task["deadline"]
+            .as_str()
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+            .map_or_else(Utc::now, |d| d.with_timezone(&Utc));
+        let priority = task["priority"].as_bool().unwrap_or(false);
+        let queue = queue_for(task["capability"].as_str(), priority);
+        self.task_queue.push(conn, &queue, task_id, due).await?;
+        Ok(())
+    }
+
+    /// Tell the configured webhook that `task_id` timed out
+    async fn notify(&self, url: &str, task_id: &str, task: &Value) {
+        let payload = serde_json::json!({
+            "event": "task.timed_out",
+            "task_id": task_id,
+            "submitted_by": task["submitted_by"],
+            "request_id": task["request_id"],
+            "created_at": task["created_at"],
+            "timed_out_at": task["timed_out_at"],
+            "requeues": task["requeues"].as_u64().unwrap_or(0),
+        });
+        let delivered = self
+            .retry
+            .run("Timeout webhook", || async {
+                self.http
+                    .post(url)
+                    .json(&payload)
+                    .send()
+                    .await?
+                    .error_for_status()
+                    .map(|_| ())
+            })
+            .await;
+        if let Err(e) = delivered {
+            error!("Failed to report timed out task {}: {}", task_id, e);
+        }
+    }
+}
+
+/// Start the timeout watchdog in a background task
+pub fn start_watchdog(
+    redis_client: Arc<Client>,
+    task_queue: TaskQueue,
+    runtime: Arc<RuntimeConfig>,
+    retry: RetryPolicy,
+    requeue_retry: RetryPolicy,
+    metrics: Arc<WatchdogMetrics>,
+) -> anyhow::Result<()> {
+    let interval = Duration::from_secs(runtime.current().watchdog.interval_secs);
+    let watchdog = Watchdog {
+        task_queue,
+        runtime,
+        http: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?,
+        retry,
+        requeue_retry,
+        metrics,
+    };
+    tokio::spawn(async move {
+        info!("Task timeout watchdog started");
+        loop {
+            if let Err(e) = watchdog.sweep(&redis_client).await {
+                error!("Task watchdog error: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+    Ok(())
+}
@@ -87,10 +87,10 @@ When the user says hello or asks for a greeting.
                     logger.info("="*50)
                     break
             elif status == "failed":
-                result = await r.get(f"result:{task_id}")
-                if result:
-                    result_obj = json.loads(result)
-                    logger.error(f"Task failed: {result_obj.get('error', 'Unknown error')}")
+                report = await r.get(f"error:{task_id}")
+                if report:
+                    report_obj = json.loads(report)
+                    logger.error(f"Task failed: {report_obj.get('error', 'Unknown error')}")
                 break
 
     # Cleanup
@@ -98,6 +98,7 @@ When the user says hello or asks for a greeting.
     await r.srem("skills:index", "test_hello")
     await r.delete(f"task:{task_id}")
     await r.delete(f"result:{task_id}")
+    await r.delete(f"error:{task_id}")
     await r.close()
     logger.info("Test complete")
 